@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use clap::Parser;
 
 #[derive(Parser, Debug, Default)]
@@ -14,4 +16,8 @@ pub(crate) struct CliAS {
     #[clap(short, long)]
     /// Skip storing persistent states entirely
     pub non_persistent: bool,
+
+    /// Path to the config
+    #[clap(long, default_value = "./AuthServiceConfig.yaml")]
+    pub config: PathBuf,
 }
@@ -1,3 +1,8 @@
+use std::net::Ipv4Addr;
+use std::path::Path;
+
+use corelib::{check_writable_path, ConfigError};
+
 use serde_derive::{Deserialize, Serialize};
 
 /// This struct contains information pertaining to the authentication
@@ -7,4 +12,44 @@ pub(crate) struct AuthServiceConfig {
     pub data_path: String,
     pub ip_address: String,
     pub port: u16,
+    /// The credential types users may register with, e.g. `Basic`. Any if
+    /// not set
+    #[serde(default)]
+    pub allowed_credential_types: Option<Vec<String>>,
+    /// The signature schemes users may register with, e.g. `ED25519`. Any
+    /// if not set
+    #[serde(default)]
+    pub allowed_signature_schemes: Option<Vec<String>>,
+    /// The credential operators present to revoke the credentials of users.
+    /// Nobody may revoke any if not set
+    #[serde(default)]
+    pub admin_credential: Option<String>,
+}
+
+impl AuthServiceConfig {
+    /// Loads the config at `path`, with a relative data path taken relative
+    /// to the directory of the config
+    pub(crate) fn load(path: &Path) -> Result<Self, confy::ConfyError> {
+        let mut config: Self = confy::load_path(path)?;
+        if let Some(config_dir) = path.parent() {
+            if !config.data_path.is_empty() && Path::new(&config.data_path).is_relative() {
+                config.data_path = config_dir
+                    .join(&config.data_path)
+                    .to_string_lossy()
+                    .to_string();
+            }
+        }
+        Ok(config)
+    }
+
+    /// Checks that the IP address parses and that the data file can be written
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        self.ip_address
+            .parse::<Ipv4Addr>()
+            .map_err(|_| ConfigError::InvalidIpAddress {
+                field: "ip_address",
+                value: self.ip_address.clone(),
+            })?;
+        check_writable_path("data_path", &self.data_path)
+    }
 }
@@ -1,20 +1,27 @@
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::ops::Deref;
 use std::sync::Arc;
+use std::time::Instant;
 
 use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
-use axum::{extract::Extension, response::IntoResponse, routing::get, Router};
+use axum::{extract::Extension, response::IntoResponse, routing::get, Json, Router};
 use axum_macros::debug_handler;
 use clap::Parser;
 use futures::lock::Mutex;
 use futures::stream::SplitSink;
 use futures::stream::StreamExt;
 use log::*;
+use serde_derive::Serialize;
 
 use config::AuthServiceConfig;
 use corelib::messages::OnWireMessage;
-use corelib::servers_api::network_helpers::{parse_wrapped_ws_msg, send_enum_app_message};
+use corelib::servers_api::network_helpers::{
+    parse_wrapped_ws_msg, send_enum_app_message, SendError,
+};
 use corelib::servers_api::{as_struct::*, handle_onwire_msg_as_local};
+use corelib::state_store::{decode_versioned, restore_state, FileStore, StateStore};
+use corelib::TimerType::SingleUserRequestASProcessTime;
+use corelib::{encode_public_key, SingleTimeMeasurement};
 
 use crate::cli_struct::CliAS;
 
@@ -30,48 +37,84 @@ async fn main() {
         persistent_state: !local_cli_param.non_persistent,
     };
 
-    let as_config: AuthServiceConfig =
-        confy::load_path("./AuthServiceConfig.yaml").expect("Could not parse AS config.");
+    let as_config = match AuthServiceConfig::load(&local_cli_param.config) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Could not parse AS config: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = as_config.validate() {
+        eprintln!("Invalid AS config: {}", e);
+        std::process::exit(1);
+    }
 
-    let ip_addr: Ipv4Addr = as_config
-        .ip_address
-        .parse()
-        .expect("Could not parse IP address.");
+    let ip_addr: Ipv4Addr = as_config.ip_address.parse().expect("Validated IP address");
     let port: u16 = as_config.port;
 
     let sock_addr = SocketAddr::from((IpAddr::V4(ip_addr), port));
 
-    let mut logger = env_logger::Builder::new();
-    match local_cli_param.verbose {
-        0 => logger.filter_level(LevelFilter::Info),
-        1 => logger.filter_level(LevelFilter::Debug),
-        _ => logger.filter_level(LevelFilter::Trace),
-    };
-    logger.init();
-
-    let server_state: Arc<SharedAuthServiceState> = match &local_cli_param.fresh_start {
-        false => Arc::new({
-            if let Ok(state) = confy::load_path(&as_config.data_path) {
-                state
-            } else {
-                warn!("Starting fresh (cannot find or restore states)");
+    #[cfg(not(feature = "request-spans"))]
+    {
+        let mut logger = env_logger::Builder::new();
+        match local_cli_param.verbose {
+            0 => logger.filter_level(LevelFilter::Info),
+            1 => logger.filter_level(LevelFilter::Debug),
+            _ => logger.filter_level(LevelFilter::Trace),
+        };
+        logger.init();
+    }
+    // Also turns log records into events within the span of their request
+    #[cfg(feature = "request-spans")]
+    tracing_subscriber::fmt()
+        .with_max_level(match local_cli_param.verbose {
+            0 => tracing::Level::INFO,
+            1 => tracing::Level::DEBUG,
+            _ => tracing::Level::TRACE,
+        })
+        .init();
+
+    let mut server_state: AuthServiceState = match &local_cli_param.fresh_start {
+        false => match restore_state(&FileStore, &as_config.data_path, decode_versioned) {
+            Ok(Some(state)) => state,
+            Ok(None) => {
+                warn!("Starting fresh (no local record found)");
                 store_state(AuthServiceState::new(), &as_config).await;
                 AuthServiceState::new()
             }
-        }),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
 
         true => {
             info!("Starting fresh as requested");
             // Remove cached result
             store_state(AuthServiceState::new(), &as_config).await;
             // Return a fresh result
-            Arc::new(AuthServiceState::new())
+            AuthServiceState::new()
         }
     };
+    server_state.allowed_credential_types = as_config.allowed_credential_types.clone();
+    server_state.allowed_signature_schemes = as_config.allowed_signature_schemes.clone();
+    server_state.admin_credential = as_config.admin_credential.clone();
+    let server_state: Arc<SharedAuthServiceState> = Arc::new(server_state);
+
+    // For the `as_public_key` of the DS config, to require the tokens issued here
+    info!(
+        "Public key: {}",
+        encode_public_key(&server_state.public_key())
+    );
 
     let app: Router = Router::new()
         .route("/", get(websocket_handler))
-        .layer(Extension((server_state, as_config, as_param)));
+        .route("/health", get(health_handler));
+    #[cfg(feature = "metrics")]
+    let app = app.route("/metrics", get(metrics_handler));
+    let app = app
+        .layer(Extension((server_state, as_config, as_param)))
+        .layer(Extension(Instant::now()));
 
     tracing::debug!("listening on {}", sock_addr);
     axum::Server::bind(&sock_addr)
@@ -92,6 +135,36 @@ async fn websocket_handler(
     ws.on_upgrade(|socket| websocket(socket, state, as_config, as_param))
 }
 
+/// Reported by `GET /health`
+#[derive(Serialize, Debug)]
+struct HealthReport {
+    uptime_secs: u64,
+    n_users: usize,
+}
+
+/// Reports the uptime and the number of registered users, without a
+/// websocket upgrade
+#[debug_handler]
+async fn health_handler(
+    Extension((state, _, _)): Extension<(
+        Arc<SharedAuthServiceState>,
+        AuthServiceConfig,
+        AuthServiceParam,
+    )>,
+    Extension(started_at): Extension<Instant>,
+) -> Json<HealthReport> {
+    Json(HealthReport {
+        uptime_secs: started_at.elapsed().as_secs(),
+        n_users: state.credential_entries.len(),
+    })
+}
+
+/// Reports the recorded measurements in the Prometheus text format
+#[cfg(feature = "metrics")]
+async fn metrics_handler() -> String {
+    corelib::metrics::gather_text()
+}
+
 async fn websocket(
     stream: WebSocket,
     state: Arc<SharedAuthServiceState>,
@@ -107,7 +180,18 @@ async fn websocket(
         match parse_wrapped_ws_msg(received_message).await {
             None => {}
             Some(app_msg_w_meta) => {
-                respond_onwire_msg(app_msg_w_meta.onwire_msg, &mut shared_sender, &state).await;
+                if let Err(e) = respond_onwire_msg(
+                    app_msg_w_meta.onwire_msg,
+                    app_msg_w_meta.compress,
+                    &mut shared_sender,
+                    &state,
+                )
+                .await
+                {
+                    // The client is gone, or does not read its replies
+                    warn!("Dropping the connection: {}", e);
+                    break;
+                }
             }
         }
     }
@@ -119,7 +203,7 @@ async fn websocket(
 }
 
 async fn store_state(state: AuthServiceState, as_config: &AuthServiceConfig) {
-    match confy::store_path(&as_config.data_path, state) {
+    match FileStore.store(&as_config.data_path, &state) {
         Ok(_) => {
             info!("Server state save successfully\n");
         }
@@ -129,15 +213,51 @@ async fn store_state(state: AuthServiceState, as_config: &AuthServiceConfig) {
     }
 }
 
+/// Replies are compressed if `compress` is set, i.e., if the request was
 async fn respond_onwire_msg(
     onwire_msg: OnWireMessage,
+    compress: bool,
     sender: &mut Mutex<SplitSink<WebSocket, Message>>,
     shared_state: &Arc<SharedAuthServiceState>,
-) {
+) -> Result<(), SendError> {
     debug!("Decoded: {:?}", onwire_msg);
 
+    let begin_timestamp = Instant::now();
     let reply_msg_queue = handle_onwire_msg_as_local(onwire_msg, shared_state).await;
+    SingleTimeMeasurement::new(SingleUserRequestASProcessTime, begin_timestamp.elapsed());
     for reply_msg in reply_msg_queue {
-        send_enum_app_message(&reply_msg, sender).await;
+        send_enum_app_message(&reply_msg, compress, sender).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use corelib::servers_api::ds_structs::DeliveryServiceState;
+    use corelib::test_helpers::TestClientBundle;
+
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_health_reports_seeded_state() {
+        let as_state = Arc::new(AuthServiceState::new());
+        let ds_state = Arc::new(DeliveryServiceState::new());
+
+        for name in ["alice", "bob", "charlie"] {
+            TestClientBundle::new(name)
+                .register_assert_ok(&as_state, &ds_state)
+                .await;
+        }
+
+        let Json(report) = health_handler(
+            Extension((
+                as_state,
+                AuthServiceConfig::default(),
+                AuthServiceParam::default(),
+            )),
+            Extension(Instant::now()),
+        )
+        .await;
+        assert_eq!(report.n_users, 3);
     }
 }
@@ -1,4 +1,14 @@
+use std::path::{Path, PathBuf};
+
+use corelib::client_api::client_crypto_impl::DEFAULT_KEY_PACKAGE_LIFETIME;
+use corelib::{check_writable_path, ConfigError};
 use serde_derive::{Deserialize, Serialize};
+use url::Url;
+
+/// The config used when neither `--config` nor `MLSGOV_CLIENT_CONFIG` is set
+pub(crate) const DEFAULT_CONFIG_PATH: &str = "./CliClientConfig.yaml";
+/// The environment variable giving the path of the config
+pub(crate) const CONFIG_PATH_ENV: &str = "MLSGOV_CLIENT_CONFIG";
 
 /// This struct contains configuration information for the client
 #[derive(Serialize, Deserialize, Default)]
@@ -9,8 +19,137 @@ pub(crate) struct ClientConfig {
     pub as_url_str: String,
     /// The number of new key packages to send with every sync
     pub new_key_packages_per_sync: usize,
+    /// If set, only top up the key packages held by the DS to this number on
+    /// sync, rather than always sending `new_key_packages_per_sync`
+    #[serde(default)]
+    pub key_packages_target: Option<usize>,
+    /// Seconds the generated key packages are valid for, 90 days by default
+    #[serde(default = "default_key_package_lifetime_secs")]
+    pub key_package_lifetime_secs: u64,
+    /// Whether to propose removing group members whose credentials the AS revoked
+    #[serde(default)]
+    pub auto_remove_revoked_members: bool,
+    /// Whether to forget the revoked credentials of users who are not members
+    /// of any group of this client when syncing with the AS
+    #[serde(default)]
+    pub drop_revoked_credentials: bool,
+    /// A secret set on every device of the user, to share their notification
+    /// preferences (e.g., mutes) through the AS encrypted with it. Not shared
+    /// if unset
+    #[serde(default)]
+    pub prefs_secret: Option<String>,
+    /// Whether to broadcast the hash of each group state after syncing, for
+    /// members to detect diverging group states
+    #[serde(default)]
+    pub broadcast_gov_state_hashes: bool,
+    /// Whether to refuse creating or renaming a group to a name already used
+    /// by another group of the same community this client is in
+    #[serde(default)]
+    pub enforce_unique_group_names: bool,
+    /// The role given to the creator of a new group, `Mod` if unset
+    #[serde(default)]
+    pub creator_role: Option<String>,
+    /// The role given to users invited to a new group, `BaseUser` if unset
+    #[serde(default)]
+    pub invitee_role: Option<String>,
+    /// Whether to send ordered messages without a sender field, leaving the
+    /// attribution to the signature within the MLS message
+    #[serde(default)]
+    pub sealed_sender: bool,
+    /// Whether to compress the messages sent to the servers, which then
+    /// compress their replies too
+    #[serde(default)]
+    pub compress_msgs: bool,
+    /// What to do with `--skip-store` commands that advance a group's epoch,
+    /// which includes every command that syncs
+    #[serde(default)]
+    pub skip_store_hazard: SkipStoreHazard,
+    /// Seconds to wait for a server reply before giving up on it, unless
+    /// `--timeout` is given. 0 waits forever
+    #[serde(default = "default_read_timeout_secs")]
+    pub read_timeout_secs: u64,
     /// Path to the file containing this client's data
     pub data_path: String,
     /// Path to the file containing this client's keystore
     pub keystore_path: String,
 }
+
+fn default_read_timeout_secs() -> u64 {
+    30
+}
+
+fn default_key_package_lifetime_secs() -> u64 {
+    DEFAULT_KEY_PACKAGE_LIFETIME.as_secs()
+}
+
+/// How to handle a command advancing the MLS epoch of a group under
+/// `--skip-store`, as the stored group state would then branch from the
+/// group's actual state
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SkipStoreHazard {
+    /// Refuse to run the command
+    #[default]
+    Refuse,
+    /// Run the command, but mark the stored state as branched, so that later
+    /// runs refuse to use it
+    MarkBranched,
+}
+
+/// The path of the config: `cli_path` (from `--config`) if given, else
+/// `env_path` (from `MLSGOV_CLIENT_CONFIG`) if set, else the default
+pub(crate) fn config_path(cli_path: Option<&Path>, env_path: Option<String>) -> PathBuf {
+    match (cli_path, env_path) {
+        (Some(cli_path), _) => cli_path.to_path_buf(),
+        (None, Some(env_path)) => PathBuf::from(env_path),
+        (None, None) => PathBuf::from(DEFAULT_CONFIG_PATH),
+    }
+}
+
+impl ClientConfig {
+    /// Loads the config at `path`, with relative data and keystore paths
+    /// taken relative to the directory of the config
+    pub(crate) fn load(path: &Path) -> Result<Self, confy::ConfyError> {
+        let mut config: Self = confy::load_path(path)?;
+        config.data_path = relative_to_config(path, &config.data_path);
+        config.keystore_path = relative_to_config(path, &config.keystore_path);
+        Ok(config)
+    }
+
+    /// Checks that the server URLs parse, that key packages are sent on
+    /// sync and valid for some time, and that the data and keystore files can be written
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        for (field, value) in [
+            ("ds_url_str", &self.ds_url_str),
+            ("as_url_str", &self.as_url_str),
+        ] {
+            Url::parse(value).map_err(|e| ConfigError::InvalidUrl {
+                field,
+                value: value.to_string(),
+                reason: e.to_string(),
+            })?;
+        }
+        if self.new_key_packages_per_sync == 0 {
+            return Err(ConfigError::NotPositive {
+                field: "new_key_packages_per_sync",
+            });
+        }
+        if self.key_package_lifetime_secs == 0 {
+            return Err(ConfigError::NotPositive {
+                field: "key_package_lifetime_secs",
+            });
+        }
+        check_writable_path("data_path", &self.data_path)?;
+        check_writable_path("keystore_path", &self.keystore_path)
+    }
+}
+
+/// Resolves `file_path` against the directory of the config at `config_path`
+/// if it is relative
+pub(crate) fn relative_to_config(config_path: &Path, file_path: &str) -> String {
+    match config_path.parent() {
+        Some(config_dir) if !file_path.is_empty() && Path::new(file_path).is_relative() => {
+            config_dir.join(file_path).to_string_lossy().to_string()
+        }
+        _ => file_path.to_string(),
+    }
+}
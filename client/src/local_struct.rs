@@ -1,3 +1,7 @@
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use chrono::{DateTime, NaiveDate, Utc};
 use clap::Parser;
 use clap::Subcommand;
 
@@ -48,6 +52,34 @@ pub(crate) struct ClientInput {
     /// Delay = min(randint(0, 2^#trial)*window_size, max_delay)
     #[arg(short, long, default_value_t = 0f32)]
     pub(crate) window_size: f32,
+
+    /// The most times a failed command is retried under `--auto-retry`.
+    /// Unlimited if not set
+    #[clap(long)]
+    pub(crate) max_retries: Option<u32>,
+
+    /// Path to the client config, overriding `MLSGOV_CLIENT_CONFIG` and the
+    /// default `./CliClientConfig.yaml`
+    #[clap(long)]
+    pub(crate) config: Option<PathBuf>,
+
+    /// Seconds to wait for a server reply before giving up on it, overriding
+    /// `read_timeout_secs` of the config. 0 waits forever
+    #[clap(long)]
+    pub(crate) timeout: Option<u64>,
+}
+
+impl ClientInput {
+    /// Whether the command syncs with the DS before running
+    pub(crate) fn pre_syncs(&self) -> bool {
+        !self.no_sync && self.command.needs_pre_sync()
+    }
+
+    /// Whether running the command may advance the MLS epoch of a local
+    /// group state, either by itself or by processing the commits it syncs
+    pub(crate) fn advances_epoch(&self) -> bool {
+        self.command.advances_epoch() || self.pre_syncs()
+    }
 }
 
 #[derive(Subcommand, PartialEq, Debug)]
@@ -57,6 +89,25 @@ pub(crate) enum ClientInputCommand {
     Register {
         #[clap(value_parser)]
         name: String,
+        /// a name to show other users instead of the unique `name`
+        #[clap(long, value_parser)]
+        display_name: Option<String>,
+        /// writes the identity of this new device to a file, for another
+        /// registered device of the user to endorse, and does not register yet
+        #[clap(long, value_parser, conflicts_with = "endorsement")]
+        endorsement_request: Option<PathBuf>,
+        /// registers the identity written with `--endorsement-request`,
+        /// endorsed by the file `endorse-device` wrote
+        #[clap(long, value_parser)]
+        endorsement: Option<PathBuf>,
+    },
+    /// endorses a new device of this user, so that it can register
+    EndorseDevice {
+        /// the file the new device wrote with `register --endorsement-request`
+        #[clap(value_parser)]
+        request: PathBuf,
+        #[clap(long, value_parser)]
+        out: PathBuf,
     },
     /// creates a new MLS group
     Create {
@@ -67,6 +118,18 @@ pub(crate) enum ClientInputCommand {
     },
     /// syncs the client and prints updates. note sync is called automatically before all commands
     Sync,
+    /// asks the authentication service whether it stores the credential of
+    /// this user, and which verification key was registered with it
+    WhoAmI,
+    /// as an operator, has the authentication service revoke the credential
+    /// of a user, e.g., of a compromised device
+    RevokeCredential {
+        #[clap(value_parser)]
+        user_name: String,
+        /// the `admin_credential` set in the configuration of the AS
+        #[clap(long, value_parser)]
+        admin_credential: String,
+    },
     /// pre-authoring an invite of a user to a group, not informing the invitee.
     Invite {
         #[clap(value_parser)]
@@ -93,6 +156,20 @@ pub(crate) enum ClientInputCommand {
         group_id: String,
         #[clap(value_parser)]
         message: String,
+        /// makes the message disappear from every history this many seconds
+        /// after it was received
+        #[clap(long, value_parser)]
+        expires_after_secs: Option<u64>,
+        /// the action id of the message this one replies to
+        #[clap(long, value_parser)]
+        in_reply_to: Option<String>,
+    },
+    /// send a direct message to a user, opening the conversation if needed
+    Dm {
+        #[clap(value_parser)]
+        peer_name: String,
+        #[clap(value_parser)]
+        message: String,
     },
     /// read a message from a group
     Read {
@@ -119,12 +196,32 @@ pub(crate) enum ClientInputCommand {
         #[clap(value_parser)]
         group_id: String,
     },
+    /// discard the local state of a group and ask its mods to invite you again
+    RejoinGroup {
+        #[clap(value_parser)]
+        community_id: String,
+        #[clap(value_parser)]
+        group_id: String,
+    },
+    /// ask a member of a group you were invited to for its group state directly,
+    /// instead of waiting for it to be broadcast
+    RequestGroupState {
+        #[clap(value_parser)]
+        community_id: String,
+        #[clap(value_parser)]
+        group_id: String,
+        #[clap(value_parser)]
+        member: String,
+    },
     /// pre-reject a group invite. Serve as pre-authorization for `Remove` self
     Decline {
         #[clap(value_parser)]
         community_id: String,
         #[clap(value_parser)]
         group_id: String,
+        /// Also remove yourself from the group once the decline is committed
+        #[clap(long)]
+        remove_self: bool,
     },
     /// pre-kick a user from a group. Serve as pre-authorization for `Remove` the user
     Kick {
@@ -135,6 +232,24 @@ pub(crate) enum ClientInputCommand {
         #[clap(value_parser)]
         member_name: String,
     },
+    /// ban a user from a group, so that they cannot be invited to it
+    Ban {
+        #[clap(value_parser)]
+        community_id: String,
+        #[clap(value_parser)]
+        group_id: String,
+        #[clap(value_parser)]
+        member_name: String,
+    },
+    /// lift the ban of a user from a group
+    Unban {
+        #[clap(value_parser)]
+        community_id: String,
+        #[clap(value_parser)]
+        group_id: String,
+        #[clap(value_parser)]
+        member_name: String,
+    },
     /// The actual (credential-level) removal of a user (or self) from a group
     Remove {
         #[clap(value_parser)]
@@ -144,6 +259,16 @@ pub(crate) enum ClientInputCommand {
         #[clap(value_parser)]
         member_name: String,
     },
+    /// The actual (credential-level) removal of several users from a group,
+    /// in a single commit
+    RemoveMembers {
+        #[clap(value_parser)]
+        community_id: String,
+        #[clap(value_parser)]
+        group_id: String,
+        #[clap(value_parser, required = true)]
+        member_names: Vec<String>,
+    },
     /// promote a user to a higher role in a group
     SetRole {
         #[clap(value_parser)]
@@ -155,6 +280,47 @@ pub(crate) enum ClientInputCommand {
         #[clap(value_parser)]
         new_role: String,
     },
+    /// restore for every member the group state this client had at a previous epoch
+    Revert {
+        #[clap(value_parser)]
+        community_id: String,
+        #[clap(value_parser)]
+        group_id: String,
+        #[clap(value_parser)]
+        target_epoch: u64,
+    },
+    /// give a role to a user in a group, in addition to the roles they hold
+    AddRole {
+        #[clap(value_parser)]
+        community_id: String,
+        #[clap(value_parser)]
+        group_id: String,
+        #[clap(value_parser)]
+        member_name: String,
+        #[clap(value_parser)]
+        role: String,
+    },
+    /// take a role away from a user in a group, keeping their other roles
+    RemoveRole {
+        #[clap(value_parser)]
+        community_id: String,
+        #[clap(value_parser)]
+        group_id: String,
+        #[clap(value_parser)]
+        member_name: String,
+        #[clap(value_parser)]
+        role: String,
+    },
+    /// assign roles to several users in a single epoch change
+    SetRoles {
+        #[clap(value_parser)]
+        community_id: String,
+        #[clap(value_parser)]
+        group_id: String,
+        /// assignments in the form `member_name:role`
+        #[clap(value_parser = parse_role_assignment, required = true)]
+        assignments: Vec<(String, String)>,
+    },
     /// rename a group within a community
     RenameGroup {
         #[clap(value_parser)]
@@ -173,6 +339,25 @@ pub(crate) enum ClientInputCommand {
         #[clap(value_parser)]
         new_group_topic: String,
     },
+    /// caps the number of members of a group, or lifts the cap if no size is given
+    SetMaxGroupSize {
+        #[clap(value_parser)]
+        community_id: String,
+        #[clap(value_parser)]
+        group_id: String,
+        #[clap(value_parser)]
+        max_group_size: Option<usize>,
+    },
+    /// sets how many members must flag a message before a takedown of it is
+    /// proposed, or never proposes one if no threshold is given
+    SetFlagThreshold {
+        #[clap(value_parser)]
+        community_id: String,
+        #[clap(value_parser)]
+        group_id: String,
+        #[clap(value_parser)]
+        threshold: Option<usize>,
+    },
     /// braodcast new update to group state
     UpdateGroupState {
         #[clap(value_parser)]
@@ -180,6 +365,83 @@ pub(crate) enum ClientInputCommand {
         #[clap(value_parser)]
         group_id: String,
     },
+    /// writes the group's governance state (name, topic, roles, policies, ...) to a JSON file
+    ExportGov {
+        #[clap(value_parser)]
+        community_id: String,
+        #[clap(value_parser)]
+        group_id: String,
+        #[clap(long, value_parser)]
+        out: PathBuf,
+    },
+    /// writes the group's message history to a JSON file, hash chained and signed
+    ExportHistory {
+        #[clap(value_parser)]
+        community_id: String,
+        #[clap(value_parser)]
+        group_id: String,
+        #[clap(long, value_parser)]
+        out: PathBuf,
+    },
+    /// checks that a message history written by `ExportHistory` was not altered
+    VerifyHistory {
+        #[clap(value_parser)]
+        file: PathBuf,
+    },
+    /// prints the messages of a captured websocket exchange, one encoded
+    /// message per line or each prefixed by its length, key material redacted
+    DumpWire {
+        #[clap(value_parser)]
+        path: PathBuf,
+    },
+    /// stops counting the messages of the group, or only of the user if given,
+    /// as unread. Only affects this client
+    Mute {
+        #[clap(value_parser)]
+        community_id: String,
+        #[clap(value_parser)]
+        group_id: String,
+        #[clap(value_parser)]
+        user: Option<String>,
+    },
+    /// undoes a `mute` of the group, or of the user if given
+    Unmute {
+        #[clap(value_parser)]
+        community_id: String,
+        #[clap(value_parser)]
+        group_id: String,
+        #[clap(value_parser)]
+        user: Option<String>,
+    },
+    /// replaces the group's governance state with one written by `export-gov`,
+    /// and broadcasts it, if the current role permits reverting the group state
+    ImportGov {
+        #[clap(value_parser)]
+        community_id: String,
+        #[clap(value_parser)]
+        group_id: String,
+        #[clap(value_parser)]
+        file: PathBuf,
+    },
+    /// displays the group's MLS epoch, name, topic, and members with their roles
+    GroupInfo {
+        #[clap(value_parser)]
+        community_id: String,
+        #[clap(value_parser)]
+        group_id: String,
+        /// also display the fingerprint of each member's credential, to
+        /// compare out of band
+        #[clap(long)]
+        with_keys: bool,
+    },
+    /// checks the local state of the group for inconsistencies, exiting
+    /// with a non-zero code if any is found
+    Verify {
+        #[clap(value_parser)]
+        community_id: String,
+        #[clap(value_parser)]
+        group_id: String,
+    },
     /// displays the group state
     ShowGroupState {
         #[clap(value_parser)]
@@ -187,6 +449,43 @@ pub(crate) enum ClientInputCommand {
         #[clap(value_parser)]
         group_id: String,
     },
+    /// replays the group's policy audit log against the current policies
+    /// and group state, and lists the actions that would not pass under them
+    ReplayPolicies {
+        #[clap(value_parser)]
+        community_id: String,
+        #[clap(value_parser)]
+        group_id: String,
+    },
+    /// lists the reports awaiting review, if the current role permits it
+    ListReports {
+        #[clap(value_parser)]
+        community_id: String,
+        #[clap(value_parser)]
+        group_id: String,
+    },
+    /// lists the flagged messages by number of flags, if the current role
+    /// permits reviewing reports
+    Flagged {
+        #[clap(value_parser)]
+        community_id: String,
+        #[clap(value_parser)]
+        group_id: String,
+    },
+    /// lists the pending proposals and the yes votes each still needs
+    Proposals {
+        #[clap(value_parser)]
+        community_id: String,
+        #[clap(value_parser)]
+        group_id: String,
+    },
+    /// shows the latest decisions of the policy engine, and why they were taken
+    ShowPolicyLog {
+        #[clap(value_parser)]
+        community_id: String,
+        #[clap(value_parser)]
+        group_id: String,
+    },
     /// votes on a proposed action
     Vote {
         #[clap(value_parser)]
@@ -200,6 +499,18 @@ pub(crate) enum ClientInputCommand {
         #[clap(value_parser)]
         proposed_action_type: ActionType,
     },
+    /// closes the vote on a proposed action once its voting period elapsed.
+    /// Sent automatically for the actions this user proposed
+    ClosePoll {
+        #[clap(value_parser)]
+        community_id: String,
+        #[clap(value_parser)]
+        group_id: String,
+        #[clap(value_parser)]
+        proposed_action_id: String,
+        #[clap(value_parser)]
+        proposed_action_type: ActionType,
+    },
     /// define a new role as a set of `ActionType`s
     DefRole {
         #[clap(value_parser)]
@@ -210,6 +521,9 @@ pub(crate) enum ClientInputCommand {
         role_name: String,
         #[clap(value_parser)]
         action_types: Vec<ActionType>,
+        /// roles whose action types the new role also permits
+        #[clap(long, value_parser)]
+        inherits_from: Vec<String>,
     },
     Report {
         #[clap(value_parser)]
@@ -229,6 +543,15 @@ pub(crate) enum ClientInputCommand {
         #[clap(value_parser)]
         data: String,
     },
+    /// flags a message for review by moderators
+    Flag {
+        #[clap(value_parser)]
+        community_id: String,
+        #[clap(value_parser)]
+        group_id: String,
+        #[clap(value_parser)]
+        message_id: String,
+    },
     TakedownText {
         #[clap(value_parser)]
         community_id: String,
@@ -261,6 +584,28 @@ pub(crate) enum ClientInputCommand {
     },
 }
 
+/// Parses a `member_name:role` assignment of [ClientInputCommand::SetRoles]
+fn parse_role_assignment(s: &str) -> Result<(String, String), String> {
+    match s.split_once(':') {
+        Some((member_name, role)) if !member_name.is_empty() && !role.is_empty() => {
+            Ok((member_name.to_string(), role.to_string()))
+        }
+        _ => Err(format!("expected `member_name:role`, got `{}`", s)),
+    }
+}
+
+/// Parses the date of [ReadOption::Since], either as an RFC 3339 date and
+/// time, e.g. `2023-03-01T12:00:00+01:00`, or a `YYYY-MM-DD` date at 00:00 UTC
+fn parse_iso8601(s: &str) -> Result<SystemTime, String> {
+    if let Ok(date_time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(date_time.into());
+    }
+    match NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        Ok(date) => Ok(DateTime::<Utc>::from_utc(date.and_hms_opt(0, 0, 0).unwrap(), Utc).into()),
+        Err(_) => Err(format!("expected an ISO 8601 date (and time), got `{}`", s)),
+    }
+}
+
 #[derive(Subcommand, Eq, PartialEq, Debug, Clone)]
 #[clap(args_conflicts_with_subcommands = false)] //If this is true, [Read] is [group_id] XOR [option]
 pub enum ReadOption {
@@ -270,6 +615,26 @@ pub enum ReadOption {
         n_message: usize,
     },
     All,
+    /// Print the messages received since the given date
+    Since {
+        #[clap(value_parser = parse_iso8601)]
+        iso8601: SystemTime,
+    },
+    /// Print the messages of the thread of the given message: the message
+    /// its reply chain starts from and all the replies to it
+    #[clap(long_flag = "thread")]
+    Thread {
+        #[clap(value_parser)]
+        message_id: String,
+    },
+    /// Print the unread messages, then keep printing new ones as they
+    /// arrive until interrupted with Ctrl-C
+    #[clap(long_flag = "follow")]
+    Follow {
+        /// How long to wait between syncs
+        #[clap(long, default_value_t = 1000)]
+        poll_interval_ms: u64,
+    },
 }
 
 impl Default for ReadOption {
@@ -279,30 +644,126 @@ impl Default for ReadOption {
 }
 
 impl ClientInputCommand {
+    /// Whether the command commits to (or joins) a group, advancing the MLS
+    /// epoch of the local group state
+    pub(crate) fn advances_epoch(&self) -> bool {
+        match self {
+            ClientInputCommand::Invite { .. }
+            | ClientInputCommand::Add { .. }
+            | ClientInputCommand::Remove { .. }
+            | ClientInputCommand::RemoveMembers { .. }
+            | ClientInputCommand::Leave { .. }
+            | ClientInputCommand::Accept { .. }
+            | ClientInputCommand::Decline { .. }
+            | ClientInputCommand::Kick { .. }
+            | ClientInputCommand::Ban { .. }
+            | ClientInputCommand::Unban { .. }
+            | ClientInputCommand::Revert { .. }
+            | ClientInputCommand::ImportGov { .. }
+            | ClientInputCommand::SetRole { .. }
+            | ClientInputCommand::AddRole { .. }
+            | ClientInputCommand::RemoveRole { .. }
+            | ClientInputCommand::SetRoles { .. }
+            | ClientInputCommand::DefRole { .. }
+            | ClientInputCommand::RenameGroup { .. }
+            | ClientInputCommand::ChangeGroupTopic { .. }
+            | ClientInputCommand::SetMaxGroupSize { .. }
+            | ClientInputCommand::SetFlagThreshold { .. }
+            | ClientInputCommand::Custom { .. }
+            | ClientInputCommand::Vote { .. }
+            | ClientInputCommand::ClosePoll { .. }
+            | ClientInputCommand::CommitPendingVotes { .. }
+            // Opening a conversation invites and adds the peer
+            | ClientInputCommand::Dm { .. } => true,
+            ClientInputCommand::Register { .. }
+            | ClientInputCommand::Create { .. }
+            | ClientInputCommand::Sync
+            | ClientInputCommand::WhoAmI
+            | ClientInputCommand::RevokeCredential { .. }
+            | ClientInputCommand::EndorseDevice { .. }
+            | ClientInputCommand::Send { .. }
+            | ClientInputCommand::Read { .. }
+            | ClientInputCommand::RequestGroupState { .. }
+            | ClientInputCommand::RejoinGroup { .. }
+            | ClientInputCommand::ShowGroupState { .. }
+            | ClientInputCommand::ExportGov { .. }
+            | ClientInputCommand::ExportHistory { .. }
+            | ClientInputCommand::VerifyHistory { .. }
+            | ClientInputCommand::DumpWire { .. }
+            | ClientInputCommand::Mute { .. }
+            | ClientInputCommand::Unmute { .. }
+            | ClientInputCommand::GroupInfo { .. }
+            | ClientInputCommand::Verify { .. }
+            | ClientInputCommand::ReplayPolicies { .. }
+            | ClientInputCommand::ListReports { .. }
+            | ClientInputCommand::Flagged { .. }
+            | ClientInputCommand::Proposals { .. }
+            | ClientInputCommand::ShowPolicyLog { .. }
+            | ClientInputCommand::UpdateGroupState { .. }
+            | ClientInputCommand::Report { .. }
+            | ClientInputCommand::Flag { .. }
+            | ClientInputCommand::TakedownText { .. }
+            | ClientInputCommand::ProposeVote { .. } => false,
+        }
+    }
+
     pub(crate) fn needs_pre_sync(&self) -> bool {
         match self {
-            ClientInputCommand::Register { .. } => false,
+            // Only reads the local state, or only talks to the AS
+            ClientInputCommand::Register { .. }
+            | ClientInputCommand::WhoAmI
+            | ClientInputCommand::RevokeCredential { .. }
+            | ClientInputCommand::EndorseDevice { .. }
+            | ClientInputCommand::GroupInfo { .. }
+            | ClientInputCommand::Mute { .. }
+            | ClientInputCommand::Unmute { .. }
+            | ClientInputCommand::Verify { .. }
+            | ClientInputCommand::VerifyHistory { .. }
+            | ClientInputCommand::DumpWire { .. } => false,
+            // The local state of the group is discarded, and may not process new messages
+            ClientInputCommand::RejoinGroup { .. } => false,
             ClientInputCommand::Create { .. }
             | ClientInputCommand::Sync
             | ClientInputCommand::Invite { .. }
             | ClientInputCommand::Send { .. }
+            | ClientInputCommand::Dm { .. }
             | ClientInputCommand::Read { .. }
             | ClientInputCommand::Leave { .. }
             | ClientInputCommand::Accept { .. }
+            | ClientInputCommand::RequestGroupState { .. }
             | ClientInputCommand::Decline { .. }
             | ClientInputCommand::Kick { .. }
+            | ClientInputCommand::Ban { .. }
+            | ClientInputCommand::Unban { .. }
+            | ClientInputCommand::Revert { .. }
             | ClientInputCommand::SetRole { .. }
+            | ClientInputCommand::AddRole { .. }
+            | ClientInputCommand::RemoveRole { .. }
+            | ClientInputCommand::SetRoles { .. }
             | ClientInputCommand::ShowGroupState { .. }
+            | ClientInputCommand::ExportGov { .. }
+            | ClientInputCommand::ExportHistory { .. }
+            | ClientInputCommand::ImportGov { .. }
+            | ClientInputCommand::ReplayPolicies { .. }
+            | ClientInputCommand::ListReports { .. }
+            | ClientInputCommand::Flagged { .. }
+            | ClientInputCommand::Proposals { .. }
+            | ClientInputCommand::ShowPolicyLog { .. }
             | ClientInputCommand::UpdateGroupState { .. }
             | ClientInputCommand::RenameGroup { .. }
             | ClientInputCommand::Add { .. }
             | ClientInputCommand::Vote { .. }
+            | ClientInputCommand::ClosePoll { .. }
             | ClientInputCommand::Remove { .. }
+            | ClientInputCommand::RemoveMembers { .. }
             | ClientInputCommand::DefRole { .. }
             | ClientInputCommand::Report { .. }
             | ClientInputCommand::Custom { .. }
+            | ClientInputCommand::Flag { .. }
             | ClientInputCommand::TakedownText { .. }
             | ClientInputCommand::ChangeGroupTopic { .. }
+            | ClientInputCommand::SetMaxGroupSize { .. }
+            | ClientInputCommand::SetFlagThreshold { .. }
             | ClientInputCommand::ProposeVote { .. }
             | ClientInputCommand::CommitPendingVotes { .. } => true,
         }
@@ -4,11 +4,12 @@ extern crate ed25519_dalek;
 
 use core::panic;
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
-use std::net::TcpStream;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::ops::{Deref, DerefMut};
 use std::path::Path;
 use std::str::from_utf8;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, Instant, SystemTime};
 use std::vec;
@@ -21,27 +22,37 @@ use clap::Parser;
 use colored::Colorize;
 use ed25519_dalek::Keypair;
 use log::*;
-use openmls::prelude::{Credential, KeyPackage};
+use openmls::prelude::KeyPackage;
 use rand::Rng;
 use rand_07::rngs::OsRng;
-use tungstenite::{stream::MaybeTlsStream, WebSocket};
+use serde_derive::Serialize;
 use url::Url;
-use versions::Versioning;
 
-use config::ClientConfig;
+use config::{config_path, ClientConfig, SkipStoreHazard, CONFIG_PATH_ENV};
+use corelib::client_api::actions::Action;
 use corelib::client_api::client_crypto_impl::{CryptoBackend, KeyStoreType};
 use corelib::client_api::client_struct::{ClientDataProvider, ClientParsedMsg};
-use corelib::client_api::{self, create_group_msg, show_group_state};
-use corelib::client_api::{register_msg_as, register_msg_ds, sync_msg};
-use corelib::messages::{OnWireMessage, OnWireMessageWithMetaData, UnorderedMsgContent};
+use corelib::client_api::{self, create_group_msg, show_group_state, OnWireTransport};
+use corelib::client_api::{
+    endorse_device, key_packages_to_replenish, register_msg_as_with_endorsement, register_msg_ds,
+    sync_msg, whoami_msg,
+};
+use corelib::messages::{
+    decode_wire_dump, redact_key_material, AuthToken, DeviceEndorsement, DeviceEndorsementRequest,
+    DumpedFrame, OnWireMessage, OnWireMessageWithMetaData, UnorderedMsgContent,
+};
 use corelib::policyengine::policies::VoteOnNameChangePolicy;
+use corelib::policyengine::VOTES_UNREACHABLE;
+use corelib::state_store::{restore_state, FileStore, StateStore};
 use corelib::MsgSizeType::{IncomingMsg, OutgoingMsg};
 use corelib::TimerType::*;
-use corelib::{identity_to_str, CommGroupId, SingleMsgSizeMeasurement, SingleTimeMeasurement};
+use corelib::{
+    verification_key_fingerprint, CommGroupId, SingleMsgSizeMeasurement, SingleTimeMeasurement,
+};
 use local_struct::{ClientInput, ClientInputCommand, ReadOption};
 use networking::finish_websocket;
-use networking::get_websocket;
-use process::group_onwire_msgs_for_ds;
+use networking::{is_read_timeout, ReconnectingWs};
+use process::{group_onwire_msgs_for_ds, warn_left_out_invitees};
 
 mod config;
 mod local_struct;
@@ -56,14 +67,33 @@ fn main() {
     // Client input parsing and validation
     let cli = ClientInput::parse();
 
-    let cli_config: ClientConfig =
-        confy::load_path("./CliClientConfig.yaml").expect("Could not parse client config.");
+    let cli_config = match ClientConfig::load(&config_path(
+        cli.config.as_deref(),
+        std::env::var(CONFIG_PATH_ENV).ok(),
+    )) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Could not parse client config: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = cli_config.validate() {
+        eprintln!("Invalid client config: {}", e);
+        std::process::exit(1);
+    }
 
-    let ds_url = Url::parse(&cli_config.ds_url_str).unwrap();
-    let as_url = Url::parse(&cli_config.as_url_str).unwrap();
+    let ds_url = Url::parse(&cli_config.ds_url_str).expect("Validated DS URL");
+    let as_url = Url::parse(&cli_config.as_url_str).expect("Validated AS URL");
 
     let mut backend = CryptoBackend::default();
-    let (client_data_opt, key_storage_opt) = read_local_saved_states(&cli_config);
+    backend.key_package_lifetime = Duration::from_secs(cli_config.key_package_lifetime_secs);
+    // The stored states are left unread, so that a fresh start works over
+    // states that cannot be read
+    let (client_data_opt, key_storage_opt) = if cli.fresh_start {
+        (None, None)
+    } else {
+        read_local_saved_states(&cli_config)
+    };
 
     // Ensure initialized logger and valid command combination, and return initialized config
     let mut client_data = Box::new(validated_config(
@@ -72,23 +102,70 @@ fn main() {
         key_storage_opt,
         &mut backend,
     )) as Box<dyn ClientDataProvider>;
+    if !skip_store_allows(&cli, cli_config.skip_store_hazard) {
+        error!("Refusing to run a command that advances the group epoch, or syncs, with `--skip-store`, as it would branch the stored group state. Set `skip_store_hazard: MarkBranched` to run it anyway");
+        return;
+    }
+    client_data.set_enforce_unique_group_names(cli_config.enforce_unique_group_names);
+    client_data.set_sealed_sender(cli_config.sealed_sender);
+    if cli_config.creator_role.is_some() || cli_config.invitee_role.is_some() {
+        client_data.set_default_group_roles(
+            cli_config
+                .creator_role
+                .clone()
+                .unwrap_or_else(|| "Mod".to_string()),
+            cli_config
+                .invitee_role
+                .clone()
+                .unwrap_or_else(|| "BaseUser".to_string()),
+        );
+    }
 
     // Now that the client finished cold-starting, we start our process timer
     let client_begin_timestamp = Instant::now();
 
     // Ready the websockets
-    let mut ws_ds = get_websocket(ds_url);
-    let mut ws_as = get_websocket(as_url);
+    let mut ws_ds = ReconnectingWs::new(ds_url);
+    let mut ws_as = ReconnectingWs::new(as_url);
+    ws_ds.set_compress(cli_config.compress_msgs);
+    ws_as.set_compress(cli_config.compress_msgs);
+    let read_timeout = match cli.timeout.unwrap_or(cli_config.read_timeout_secs) {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    ws_ds.set_read_timeout(read_timeout);
+    ws_as.set_read_timeout(read_timeout);
+    // Notification preferences are only shared if a secret is configured
+    let prefs_key = cli_config
+        .prefs_secret
+        .as_deref()
+        .map(client_api::prefs_key);
+    if !matches!(cli.command, ClientInputCommand::Register { .. }) {
+        // Required by a DS that knows the public key of the AS
+        ws_ds.set_token(client_api::fresh_ds_token(
+            client_data.deref_mut(),
+            &mut ws_as,
+            SystemTime::now(),
+        ));
+        // Ask the DS again for what a dropped connection may have lost
+        ws_ds.set_resume_msg(tungstenite::Message::Binary(encode_onwire_msg(
+            &sync_msg(client_data.deref(), vec![]),
+            cli_config.compress_msgs,
+            ws_ds.token(),
+        )));
+    }
 
     SingleTimeMeasurement::new(EstablishWebsockets, client_begin_timestamp.elapsed());
 
-    let mut can_retry = false;
+    let mut retry_reason: Option<String> = None;
     let mut n_trial = 0;
+    // Cleared if `Verify` finds an inconsistency
+    let mut integrity_ok = true;
     let mut rng = rand::thread_rng();
 
-    while (n_trial == 0) || (cli.auto_retry && can_retry) {
+    loop {
         if n_trial > 0 {
-            let range = 0..(2_u32.pow(n_trial));
+            let range = 0..(2_u32.saturating_pow(n_trial));
             let rand_slot = if !range.is_empty() {
                 rng.gen_range(range)
             } else {
@@ -102,86 +179,69 @@ fn main() {
         }
         // Perform default pre-command sync
         let presync_begin_timestamp = Instant::now();
-        if (!cli.no_sync) && cli.command.needs_pre_sync() {
+        if cli.pre_syncs() {
             handle_sync_ds(client_data.deref(), &mut backend, &mut ws_ds, &cli_config);
             let msgs = read_ws_messages(&mut ws_ds);
 
+            // Before parsing, so that the tags of messages from members who
+            // just joined can be checked against their verification keys
+            handle_sync_as(
+                client_data.deref_mut(),
+                &mut ws_as,
+                cli_config.drop_revoked_credentials,
+            )
+            .unwrap();
+
             let parse_begin_timestamp = Instant::now();
             let local_plain_msgs =
                 client_api::parse_incoming_onwire_msgs(msgs, &mut client_data, &mut backend);
             SingleTimeMeasurement::new(ParseIncomingMsgsPreSync, parse_begin_timestamp.elapsed());
 
-            handle_sync_as(client_data.deref_mut(), &mut ws_as).unwrap();
-            // Avoid printing non-json things when JSON output is expected
-            if !cli.json {
-                print_out_parsed_msgs(&local_plain_msgs);
+            client_api::queue_expired_poll_closes(&mut client_data);
+            if let Some(prefs_key) = &prefs_key {
+                handle_load_prefs(prefs_key, client_data.deref_mut(), &backend, &mut ws_as);
+            }
+            print_out_parsed_msgs(&local_plain_msgs, cli.json, client_data.deref());
+
+            handle_gov_state_requests(
+                &local_plain_msgs,
+                &mut client_data,
+                &mut backend,
+                &mut ws_ds,
+                cli.json,
+            );
+
+            if cli_config.auto_remove_revoked_members {
+                handle_revoked_members(&mut client_data, &mut backend, &mut ws_ds, cli.json);
+            }
+
+            if cli_config.broadcast_gov_state_hashes {
+                handle_gov_state_hashes(&mut client_data, &mut backend, &mut ws_ds, cli.json);
             }
+
+            handle_outbox(&mut client_data, &mut backend, &mut ws_ds, cli.json);
+        }
+        if n_trial == 0 {
+            handle_unsent_msgs(&mut client_data, &mut backend, &mut ws_ds, cli.json);
         }
         SingleTimeMeasurement::new(PreSyncTurnaround, presync_begin_timestamp.elapsed());
 
         // Perform default pre-group-operation Key package fetch
         let key_package_begin_timestamp = Instant::now();
         let external_key_packages_opt = match &cli.command {
-            ClientInputCommand::Invite { invitee_names, .. } => {
-                send_onwire_msg(
-                    OnWireMessage::UserCredentialLookup {
-                        user_name: client_data.get_user_id(),
-                        queried_users: invitee_names.to_owned(),
-                    },
+            // Only the first message to a peer opens the conversation
+            ClientInputCommand::Dm { peer_name, .. }
+                if client_data
+                    .get_ref_group(&CommGroupId::dm(&client_data.get_user_id(), peer_name))
+                    .is_none() =>
+            {
+                Some(fetch_key_packages(
+                    &[peer_name.to_owned()],
+                    &mut client_data,
+                    &backend,
                     &mut ws_as,
-                );
-                send_onwire_msg(
-                    OnWireMessage::UserKeyPackageLookup {
-                        user_name: client_data.get_user_id(),
-                        queried_users: invitee_names.to_owned(),
-                    },
                     &mut ws_ds,
-                );
-                let as_msgs = read_ws_messages(&mut ws_as);
-                let ds_msgs = read_ws_messages(&mut ws_ds);
-
-                let parse_kp_begin_timestamp = Instant::now();
-
-                let (credentials, key_packages) = (
-                    credentials_or_panic(as_msgs),
-                    key_packages_or_panic(ds_msgs),
-                );
-
-                let credential_map: HashMap<String, Credential> = credentials
-                    .iter()
-                    .map(|credential| {
-                        (
-                            identity_to_str(credential.identity())
-                                .expect("Cannot convert identity to string"),
-                            credential.clone(),
-                        )
-                    })
-                    .collect();
-
-                for key_package in key_packages.iter() {
-                    let key_package_identity = identity_to_str(key_package.credential().identity())
-                        .expect("Cannot convert key package identity to string");
-
-                    let credential = credential_map.get(&key_package_identity).expect("Assertion failed: No matching credential found for identity: {key_package_identity}");
-
-                    assert_eq!(
-                        credential,
-                        key_package.credential(),
-                        "Assertion failed for identity: {}",
-                        key_package_identity
-                    );
-
-                    key_package.verify(&backend).expect(
-                        "Cannot continue: Cannot verify signature for {key_package_identity}",
-                    );
-                }
-
-                SingleTimeMeasurement::new(
-                    ParseIncomingMsgsKeyPackage,
-                    parse_kp_begin_timestamp.elapsed(),
-                );
-
-                Some(key_packages)
+                ))
             }
             _ => None,
         };
@@ -194,7 +254,12 @@ fn main() {
         let handle_non_sync_command_start = Instant::now();
         match &cli.command {
             ClientInputCommand::Sync => (), //Because already Synced (unless `sync --no-sync`, which is undefined)
-            ClientInputCommand::Register { ref name } => {
+            ClientInputCommand::Register {
+                ref name,
+                ref display_name,
+                ref endorsement_request,
+                ref endorsement,
+            } => {
                 assert_eq!(name.to_string(), client_data.get_user_id());
                 assert_eq!(
                     name.to_string(),
@@ -202,14 +267,44 @@ fn main() {
                         .unwrap()
                         .to_string()
                 );
-                handle_register(
-                    &mut backend,
-                    &mut ws_as,
-                    &mut ws_ds,
-                    client_data.deref(),
-                    &cli_config,
-                );
+                match endorsement_request {
+                    Some(out) => handle_endorsement_request(out, client_data.deref()),
+                    None => handle_register(
+                        &mut backend,
+                        &mut ws_as,
+                        &mut ws_ds,
+                        client_data.deref_mut(),
+                        &cli_config,
+                        display_name.clone().unwrap_or_default(),
+                        endorsement.as_deref().map(read_endorsement),
+                    ),
+                }
             }
+            ClientInputCommand::EndorseDevice { request, out } => {
+                handle_endorse_device(request, out, client_data.deref())
+            }
+            ClientInputCommand::WhoAmI => {
+                handle_whoami(client_data.deref(), &mut ws_as, cli.json);
+            }
+            ClientInputCommand::RevokeCredential {
+                user_name,
+                admin_credential,
+            } => handle_revoke_credential(admin_credential, user_name, &mut ws_as),
+            ClientInputCommand::Read {
+                community_id,
+                group_id,
+                option: Some(ReadOption::Follow { poll_interval_ms }),
+            } => handle_read_follow(
+                &mut client_data,
+                &mut backend,
+                &mut ws_ds,
+                &mut ws_as,
+                &cli_config,
+                community_id,
+                group_id,
+                Duration::from_millis(*poll_interval_ms),
+                cli.json,
+            ),
             ClientInputCommand::Read {
                 community_id,
                 group_id,
@@ -224,6 +319,26 @@ fn main() {
                 option,
                 cli.json,
             ),
+            ClientInputCommand::GroupInfo {
+                community_id,
+                group_id,
+                with_keys,
+            } => handle_group_info(
+                &CommGroupId::new(community_id, group_id),
+                *with_keys,
+                client_data.deref_mut(),
+                cli.json,
+            ),
+            ClientInputCommand::Verify {
+                community_id,
+                group_id,
+            } => {
+                integrity_ok = handle_verify(
+                    &CommGroupId::new(community_id, group_id),
+                    client_data.deref_mut(),
+                    cli.json,
+                );
+            }
             ClientInputCommand::ShowGroupState {
                 community_id,
                 group_id,
@@ -231,32 +346,222 @@ fn main() {
                 &CommGroupId::new(community_id, group_id),
                 client_data.deref_mut(),
             ),
+            ClientInputCommand::ReplayPolicies {
+                community_id,
+                group_id,
+            } => handle_replay_policies(
+                &CommGroupId::new(community_id, group_id),
+                client_data.deref(),
+                cli.json,
+            ),
+            ClientInputCommand::ListReports {
+                community_id,
+                group_id,
+            } => handle_list_reports(
+                &CommGroupId::new(community_id, group_id),
+                client_data.deref(),
+                cli.json,
+            ),
+            ClientInputCommand::Flagged {
+                community_id,
+                group_id,
+            } => handle_flagged(
+                &CommGroupId::new(community_id, group_id),
+                client_data.deref(),
+                cli.json,
+            ),
+            ClientInputCommand::Proposals {
+                community_id,
+                group_id,
+            } => handle_proposals(
+                &CommGroupId::new(community_id, group_id),
+                client_data.deref(),
+                cli.json,
+            ),
+            ClientInputCommand::ShowPolicyLog {
+                community_id,
+                group_id,
+            } => handle_show_policy_log(
+                &CommGroupId::new(community_id, group_id),
+                client_data.deref(),
+                cli.json,
+            ),
             ClientInputCommand::Create {
                 group_id,
                 community_id,
             } => {
-                create_group_msg(
-                    &client_data.deref_mut().get_user_id(),
+                let created = CommGroupId::try_new(community_id, group_id).and_then(|comm_grp| {
+                    create_group_msg(
+                        &client_data.deref_mut().get_user_id(),
+                        &comm_grp,
+                        &mut backend,
+                        client_data.deref_mut(),
+                    )
+                });
+                if let Err(e) = created {
+                    error!("Cannot create the group: {}", e);
+                }
+            }
+            ClientInputCommand::Invite {
+                community_id,
+                group_id,
+                invitee_names,
+            } => {
+                retry_reason = handle_invite(
                     &CommGroupId::new(community_id, group_id),
+                    invitee_names,
+                    &mut client_data,
                     &mut backend,
-                    client_data.deref_mut(),
+                    &mut ws_as,
+                    &mut ws_ds,
+                    cli.json,
                 );
             }
-            _ => {
-                // group-related command case
-                let create_msg_begin_timestamp = Instant::now();
-                let onwire_msgs = group_onwire_msgs_for_ds(
+            ClientInputCommand::Dm { peer_name, message } => {
+                retry_reason = handle_dm(
+                    peer_name,
+                    message,
+                    external_key_packages_opt,
+                    &mut client_data,
+                    &mut backend,
+                    &mut ws_ds,
+                    cli.json,
+                );
+            }
+            ClientInputCommand::Revert {
+                community_id,
+                group_id,
+                ..
+            } => {
+                retry_reason = handle_revert(
                     &cli.command,
+                    &CommGroupId::new(community_id, group_id),
+                    &mut client_data,
                     &mut backend,
+                    &mut ws_ds,
+                    cli.json,
+                );
+            }
+            ClientInputCommand::Accept {
+                community_id,
+                group_id,
+            } => {
+                retry_reason = handle_accept(
+                    &cli.command,
+                    &CommGroupId::new(community_id, group_id),
                     &mut client_data,
-                    external_key_packages_opt,
+                    &mut backend,
+                    &mut ws_ds,
+                    cli.json,
+                );
+            }
+            ClientInputCommand::Decline {
+                community_id,
+                group_id,
+                remove_self: true,
+            } => {
+                retry_reason = handle_decline_and_remove_self(
+                    &cli.command,
+                    &CommGroupId::new(community_id, group_id),
+                    &mut client_data,
+                    &mut backend,
+                    &mut ws_ds,
+                    cli.json,
+                );
+            }
+            ClientInputCommand::ExportHistory {
+                community_id,
+                group_id,
+                out,
+            } => handle_export_history(
+                &CommGroupId::new(community_id, group_id),
+                out,
+                client_data.as_any().downcast_ref::<ClientData>().unwrap(),
+            ),
+            ClientInputCommand::VerifyHistory { file } => {
+                integrity_ok = handle_verify_history(file, cli.json);
+            }
+            ClientInputCommand::DumpWire { path } => handle_dump_wire(path, cli.json),
+            ClientInputCommand::ExportGov {
+                community_id,
+                group_id,
+                out,
+            } => handle_export_gov(
+                &CommGroupId::new(community_id, group_id),
+                out,
+                client_data.deref(),
+            ),
+            ClientInputCommand::Mute {
+                community_id,
+                group_id,
+                user,
+            } => {
+                // Starting from the preferences of the other devices, so that
+                // storing them does not undo their changes
+                if let Some(prefs_key) = &prefs_key {
+                    handle_load_prefs(prefs_key, client_data.deref_mut(), &backend, &mut ws_as);
+                }
+                client_api::mute(
+                    &CommGroupId::new(community_id, group_id),
+                    user.clone(),
+                    client_data.deref_mut(),
+                );
+                info!("Muted {}", user.as_deref().unwrap_or(group_id));
+                if let Some(prefs_key) = &prefs_key {
+                    handle_store_prefs(prefs_key, client_data.deref(), &backend, &mut ws_as);
+                }
+            }
+            ClientInputCommand::Unmute {
+                community_id,
+                group_id,
+                user,
+            } => {
+                // Starting from the preferences of the other devices, so that
+                // storing them does not undo their changes
+                if let Some(prefs_key) = &prefs_key {
+                    handle_load_prefs(prefs_key, client_data.deref_mut(), &backend, &mut ws_as);
+                }
+                client_api::unmute(
+                    &CommGroupId::new(community_id, group_id),
+                    user.clone(),
+                    client_data.deref_mut(),
+                );
+                info!("Unmuted {}", user.as_deref().unwrap_or(group_id));
+                if let Some(prefs_key) = &prefs_key {
+                    handle_store_prefs(prefs_key, client_data.deref(), &backend, &mut ws_as);
+                }
+            }
+            ClientInputCommand::ImportGov {
+                community_id,
+                group_id,
+                file,
+            } => {
+                retry_reason = handle_import_gov(
+                    &CommGroupId::new(community_id, group_id),
+                    file,
+                    &mut client_data,
+                    &mut backend,
+                    &mut ws_ds,
+                    cli.json,
                 );
+            }
+            _ => {
+                // group-related command case
+                let create_msg_begin_timestamp = Instant::now();
+                let onwire_msgs =
+                    group_onwire_msgs_for_ds(&cli.command, &mut backend, &mut client_data);
                 SingleTimeMeasurement::new(
                     MlsGovNonSyncKpFetchRequestGeneration,
                     create_msg_begin_timestamp.elapsed(),
                 );
 
                 if !onwire_msgs.is_empty() {
+                    // Stored before sending, to be resent if the client
+                    // stops before the DS confirms them
+                    client_api::record_unsent_msgs(&onwire_msgs, client_data.deref_mut());
+                    if !cli.skip_store {
+                        store_states(&client_data, &backend, &cli_config);
+                    }
                     send_onwire_msgs(onwire_msgs, &mut ws_ds);
                 }
 
@@ -266,9 +571,9 @@ fn main() {
                     &mut backend,
                 );
 
-                print_out_parsed_msgs(&parsed_msgs);
+                print_out_parsed_msgs(&parsed_msgs, cli.json, client_data.deref());
                 debug!("parsed_msgs: {:?}", parsed_msgs);
-                can_retry = determine_if_retry(&parsed_msgs);
+                retry_reason = retryable_error(&parsed_msgs);
             }
         }
         SingleTimeMeasurement::new(
@@ -276,6 +581,29 @@ fn main() {
             handle_non_sync_command_start.elapsed(),
         );
         n_trial += 1;
+        match retry_decision(
+            n_trial,
+            cli.auto_retry,
+            cli.max_retries,
+            retry_reason.take(),
+        ) {
+            RetryDecision::Done => break,
+            RetryDecision::Retry { retries_left } => match retries_left {
+                Some(retries_left) => info!(
+                    "Retrying, attempt {} ({} more retries left after it)",
+                    n_trial + 1,
+                    retries_left
+                ),
+                None => info!("Retrying, attempt {}", n_trial + 1),
+            },
+            RetryDecision::GiveUp {
+                attempts,
+                last_error,
+            } => {
+                print_out_retries_exhausted(attempts, &last_error, cli.json);
+                break;
+            }
+        }
     }
 
     // Close WSs
@@ -291,31 +619,55 @@ fn main() {
     // store states before exit
     if !cli.skip_store {
         store_states(&client_data, &backend, &cli_config);
+    } else if cli.advances_epoch() {
+        warn!("Skipping storing local states, and marking them as branched as the group state was updated");
+        mark_stored_state_branched(&cli_config);
     } else {
         warn!("Skipping storing local states. Dev only. If MLS group state updated, this will cause branch group states");
     }
+    if !integrity_ok {
+        std::process::exit(1);
+    }
+}
+
+/// Whether the command may run with the given `--skip-store` setting.
+/// Commands that advance a group's epoch, including every command that
+/// syncs, only run if their stored state is to be marked as branched
+/// afterwards.
+fn skip_store_allows(cli: &ClientInput, skip_store_hazard: SkipStoreHazard) -> bool {
+    !cli.skip_store || !cli.advances_epoch() || skip_store_hazard == SkipStoreHazard::MarkBranched
+}
+
+/// Flags the stored client data as branched, leaving the rest of it as is
+fn mark_stored_state_branched(client_config: &ClientConfig) {
+    if let (Some(mut stored_data), _) = read_local_saved_states(client_config) {
+        stored_data.branched_state = true;
+        FileStore
+            .store(&client_config.data_path, &stored_data.to_string())
+            .expect("Client data saving failed.");
+    }
 }
 
 pub(crate) fn read_local_saved_states(
     client_config: &ClientConfig,
 ) -> (Option<ClientData>, Option<KeyStoreType>) {
-    let mut client_config_opt: Option<ClientData> = None;
-    let client_str: String = confy::load_path(&client_config.data_path).expect("could not decode");
-    if Path::new(&client_config.data_path).exists() {
-        client_config_opt = match serde_json::from_str(&client_str) {
-            Ok(config) => Some(config),
-            Err(e) => {
-                error!("Cannot load ClientData using confy:{:?}", e);
-                None
-            }
-        };
-    } else {
+    let client_config_opt: Option<ClientData> = match restore_state(
+        &FileStore,
+        &client_config.data_path,
+        |client_str: String| ClientData::from_versioned_str(&client_str),
+    ) {
+        Ok(client_config_opt) => client_config_opt,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if client_config_opt.is_none() {
         info!("Starting Client Data fresh as no local saved states found");
     }
 
-    let key_storage_opt: Option<KeyStoreType> = match confy::load_path(&client_config.keystore_path)
-    {
-        Ok(keystore) => Some(keystore),
+    let key_storage_opt: Option<KeyStoreType> = match FileStore.load(&client_config.keystore_path) {
+        Ok(keystore) => keystore,
         Err(e) => {
             error!("Cannot decode keystore: {:?}", e);
             None
@@ -333,13 +685,15 @@ fn store_states(
         .as_any()
         .downcast_ref::<ClientData>()
         .expect("could not properly convert client box");
-    confy::store_path(&client_config.data_path, client_ref.to_string())
+    FileStore
+        .store(&client_config.data_path, &client_ref.to_string())
         .expect("Client data saving failed.");
-    confy::store_path(
-        &client_config.keystore_path,
-        backend.key_store.get_key_store_copy(),
-    )
-    .expect("Client KeyStore saving failed.");
+    FileStore
+        .store(
+            &client_config.keystore_path,
+            &backend.key_store.get_key_store_copy(),
+        )
+        .expect("Client KeyStore saving failed.");
 }
 
 pub(crate) fn validated_config(
@@ -364,7 +718,8 @@ pub(crate) fn validated_config(
             .filter_level(LevelFilter::Info),
     };
 
-    logger.init();
+    // Tests may validate several configs within one process
+    let _ = logger.try_init();
 
     if cli.no_sync && cli.command == ClientInputCommand::Sync {
         panic!("Cannot sync with no sync");
@@ -372,7 +727,20 @@ pub(crate) fn validated_config(
 
     // Must register first for all non-register commands
     match &cli.command {
-        ClientInputCommand::Register { name } => {
+        // Registers the identity an endorsement was requested for
+        ClientInputCommand::Register {
+            name,
+            endorsement: Some(_),
+            ..
+        } if client_config_opt
+            .as_ref()
+            .map_or(false, |config| &config.user_name == name) =>
+        {
+            if let Some(key_storage) = key_storage_opt {
+                backend.key_store.replace_from(key_storage);
+            }
+        }
+        ClientInputCommand::Register { name, .. } => {
             let credential_bundle =
                 backend.generate_credential_bundle(name.to_owned().into_bytes(), None, None);
             backend.store_credential_bundle(&credential_bundle);
@@ -387,6 +755,9 @@ pub(crate) fn validated_config(
         }
         _ => match client_config_opt {
             Some(ref config) => {
+                if config.branched_state {
+                    panic!("The stored group states branched in a run with `--skip-store`. Please start over with `--fresh-start`.");
+                }
                 match key_storage_opt {
                     None => {
                         error!("Cannot find corresponding UserKeyStore. Local key database reset.")
@@ -411,11 +782,15 @@ pub(crate) fn validated_config(
 fn handle_sync_ds(
     client_data: &(impl ClientDataProvider + ?Sized),
     backend: &mut CryptoBackend,
-    websocket: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+    websocket: &mut ReconnectingWs,
     client_config: &ClientConfig,
 ) {
+    let n_key_packages = match client_config.key_packages_target {
+        Some(target) => key_packages_to_replenish(client_data, target),
+        None => client_config.new_key_packages_per_sync,
+    };
     let mut new_key_packages = vec![];
-    for _ in 0..client_config.new_key_packages_per_sync {
+    for _ in 0..n_key_packages {
         new_key_packages.push(
             backend.generate_default_key_package_and_store_bundle(&client_data.get_credential()),
         )
@@ -423,77 +798,735 @@ fn handle_sync_ds(
     send_onwire_msg(sync_msg(client_data, new_key_packages), websocket);
 }
 
+/// Looks up the credentials and key packages of `invitee_names`, checking that
+/// they match and that the key packages which did not expire are valid
+fn fetch_key_packages(
+    invitee_names: &[String],
+    client_data: &mut Box<dyn ClientDataProvider>,
+    backend: &CryptoBackend,
+    ws_as: &mut ReconnectingWs,
+    ws_ds: &mut ReconnectingWs,
+) -> Vec<KeyPackage> {
+    let (key_packages, failed_invitees) = client_api::fetch_verified_key_packages(
+        invitee_names,
+        backend,
+        client_data.deref_mut(),
+        ws_as,
+        ws_ds,
+    );
+    warn_left_out_invitees(
+        &failed_invitees,
+        "no valid key package matching their credential was found",
+    );
+    key_packages
+}
+
+/// Invites the users to `comm_grp` after looking up and verifying their key
+/// packages. Returns the error the invite can be retried after, if any
+fn handle_invite(
+    comm_grp: &CommGroupId,
+    invitee_names: &[String],
+    client_data: &mut Box<dyn ClientDataProvider>,
+    backend: &mut CryptoBackend,
+    ws_as: &mut ReconnectingWs,
+    ws_ds: &mut ReconnectingWs,
+    print_json: bool,
+) -> Option<String> {
+    let (invite_msgs, failed_invitees) = client_api::invite_users(
+        comm_grp,
+        invitee_names,
+        backend,
+        client_data.deref_mut(),
+        ws_as,
+        ws_ds,
+    );
+    warn_left_out_invitees(
+        &failed_invitees,
+        "no valid key package matching their credential was found",
+    );
+    if invite_msgs.is_empty() {
+        return None;
+    }
+    send_onwire_msgs(invite_msgs, ws_ds);
+    let parsed_msgs =
+        client_api::parse_incoming_onwire_msgs(read_ws_messages(ws_ds), client_data, backend);
+    print_out_parsed_msgs(&parsed_msgs, print_json, &**client_data);
+    retryable_error(&parsed_msgs)
+}
+
 /// Send a sync message to the AS, which responds with a list of every
-/// CredentialEntry it currently stores. This function returns that list
-/// as as a Vec.
+/// CredentialEntry it currently stores. This function merges that list into
+/// the known entries, keeping those of group members the AS left out.
+/// Revoked entries of other users are dropped if `drop_revoked` is set
 fn handle_sync_as(
     config: &mut (impl ClientDataProvider + ?Sized),
-    ws_as: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+    ws_as: &mut ReconnectingWs,
+    drop_revoked: bool,
 ) -> Result<(), ()> {
     let sync_credentials_msg = OnWireMessage::UserSyncCredentials;
     send_onwire_msg(sync_credentials_msg, ws_as);
     let mut resp = read_ws_messages(ws_as);
     if let OnWireMessage::ASCredentialSyncResponse { credentials } = &mut resp[0] {
-        config.set_credential_entries(std::mem::take(credentials));
+        config.merge_credential_entries(std::mem::take(credentials), drop_revoked);
         Ok(())
     } else {
         Err(())
     }
 }
 
-fn handle_register(
-    backend: &mut CryptoBackend,
-    ws_as: &mut WebSocket<MaybeTlsStream<TcpStream>>,
-    ws_ds: &mut WebSocket<MaybeTlsStream<TcpStream>>,
-    client_data: &(impl ClientDataProvider + ?Sized),
-    client_config: &ClientConfig,
+/// Replaces the notification preferences with the ones stored at the AS by
+/// any device of the user, if any
+fn handle_load_prefs(
+    prefs_key: &[u8],
+    client_data: &mut (impl ClientDataProvider + ?Sized),
+    backend: &CryptoBackend,
+    ws_as: &mut ReconnectingWs,
 ) {
-    let credential_bundle = backend.read_credential_bundle(&client_data.get_credential());
+    send_onwire_msg(client_api::load_prefs_msg(client_data), ws_as);
+    for msg in read_ws_messages(ws_as) {
+        if let OnWireMessage::ASPrefsResponse { prefs: Some(prefs) } = msg {
+            if let Err(e) = client_api::apply_loaded_prefs(&prefs, prefs_key, backend, client_data)
+            {
+                warn!("Keeping the local preferences: {}", e);
+            }
+        }
+    }
+}
 
-    //AS Registration
-    send_onwire_msgs(
-        register_msg_as(
-            credential_bundle.credential().to_owned(),
-            client_data.get_keypair().public_key(),
-        ),
+/// Stores the notification preferences at the AS for the other devices of
+/// the user
+fn handle_store_prefs(
+    prefs_key: &[u8],
+    client_data: &(impl ClientDataProvider + ?Sized),
+    backend: &CryptoBackend,
+    ws_as: &mut ReconnectingWs,
+) {
+    send_onwire_msg(
+        client_api::store_prefs_msg(prefs_key, backend, client_data),
         ws_as,
     );
-    oks_or_panic(read_ws_messages(ws_as));
-
-    //DS Registration
-    let mut new_key_packages = vec![];
-    for _ in 0..client_config.new_key_packages_per_sync {
-        new_key_packages.push(
-            backend.generate_default_key_package_and_store_bundle(credential_bundle.credential()),
-        )
+    for msg in read_ws_messages(ws_as) {
+        if let OnWireMessage::ASResult {
+            request_valid: false,
+            explanation,
+            ..
+        } = msg
+        {
+            warn!(
+                "Cannot share the preferences with the other devices: {}",
+                explanation.unwrap_or_default()
+            );
+        }
     }
-    send_onwire_msgs(register_msg_ds(new_key_packages), ws_ds);
-    oks_or_panic(read_ws_messages(ws_ds));
 }
 
-fn handle_read(
-    client_config: &mut ClientData,
-    community_id: &String,
-    group_id: &String,
-    read_option_input: &Option<ReadOption>,
+/// Propose removals of group members with revoked credentials, one DS round trip each
+fn handle_revoked_members(
+    client_data: &mut Box<dyn ClientDataProvider>,
+    backend: &mut CryptoBackend,
+    ws_ds: &mut ReconnectingWs,
     print_json: bool,
 ) {
-    let read_option = read_option_input.clone().unwrap_or_default();
+    let removal_msgs = client_api::remove_revoked_members_msgs(backend, client_data.deref_mut());
+    for removal_msg in removal_msgs {
+        send_onwire_msg(removal_msg, ws_ds);
+        let parsed_msgs =
+            client_api::parse_incoming_onwire_msgs(read_ws_messages(ws_ds), client_data, backend);
+        print_out_parsed_msgs(&parsed_msgs, print_json, &**client_data);
+    }
+}
 
-    let user_id = client_config.get_user_id();
-    let group = client_config.get_mut_group_state(&CommGroupId::new(community_id, group_id));
-    let mut messages = vec![];
+/// Broadcast the hash of every group state, one DS round trip each
+fn handle_gov_state_hashes(
+    client_data: &mut Box<dyn ClientDataProvider>,
+    backend: &mut CryptoBackend,
+    ws_ds: &mut ReconnectingWs,
+    print_json: bool,
+) {
+    let hash_msgs = client_api::gov_state_hash_msgs(backend, client_data.deref_mut());
+    for hash_msg in hash_msgs {
+        send_onwire_msg(hash_msg, ws_ds);
+        let parsed_msgs =
+            client_api::parse_incoming_onwire_msgs(read_ws_messages(ws_ds), client_data, backend);
+        print_out_parsed_msgs(&parsed_msgs, print_json, &**client_data);
+    }
+}
+
+/// Send the actions queued by policies, one DS round trip each
+fn handle_outbox(
+    client_data: &mut Box<dyn ClientDataProvider>,
+    backend: &mut CryptoBackend,
+    ws_ds: &mut ReconnectingWs,
+    print_json: bool,
+) {
+    let outbox_msgs = client_api::outbox_msgs(backend, client_data.deref_mut());
+    for outbox_msg in outbox_msgs {
+        send_onwire_msg(outbox_msg, ws_ds);
+        let parsed_msgs =
+            client_api::parse_incoming_onwire_msgs(read_ws_messages(ws_ds), client_data, backend);
+        print_out_parsed_msgs(&parsed_msgs, print_json, &**client_data);
+    }
+}
+
+/// Resend the group messages of previous runs the DS did not confirm, one
+/// DS round trip each
+fn handle_unsent_msgs(
+    client_data: &mut Box<dyn ClientDataProvider>,
+    backend: &mut CryptoBackend,
+    ws_ds: &mut ReconnectingWs,
+    print_json: bool,
+) {
+    for unsent_msg in client_data.get_unsent_msgs() {
+        send_onwire_msg(unsent_msg, ws_ds);
+        let parsed_msgs =
+            client_api::parse_incoming_onwire_msgs(read_ws_messages(ws_ds), client_data, backend);
+        print_out_parsed_msgs(&parsed_msgs, print_json, &**client_data);
+    }
+}
+
+/// Sends a direct message to `peer_name`. With `peer_key_packages_opt`, the
+/// conversation is opened first: the peer is invited, added and sent the group
+/// state, each step once the previous one went through the DS. Returns the
+/// error the command can be retried after, if any
+fn handle_dm(
+    peer_name: &String,
+    message: &str,
+    peer_key_packages_opt: Option<Vec<KeyPackage>>,
+    client_data: &mut Box<dyn ClientDataProvider>,
+    backend: &mut CryptoBackend,
+    ws_ds: &mut ReconnectingWs,
+    print_json: bool,
+) -> Option<String> {
+    let comm_grp = CommGroupId::dm(&client_data.get_user_id(), peer_name);
+    if let Some(peer_key_packages) = peer_key_packages_opt {
+        if !peer_key_packages
+            .iter()
+            .any(|kp| backend.key_package_is_valid_at(kp, client_data.now()))
+        {
+            error!(
+                "Cannot open a conversation with {}: none of their key packages is valid",
+                peer_name
+            );
+            return None;
+        }
+        let (dm_msgs, _) = client_api::create_dm(
+            peer_name,
+            peer_key_packages,
+            backend,
+            client_data.deref_mut(),
+        );
+        send_onwire_msgs(dm_msgs, ws_ds);
+        let parsed_msgs =
+            client_api::parse_incoming_onwire_msgs(read_ws_messages(ws_ds), client_data, backend);
+        print_out_parsed_msgs(&parsed_msgs, print_json, &**client_data);
+
+        let (add_msgs, _) = client_api::add_msg(
+            &comm_grp,
+            &vec![peer_name.to_owned()],
+            client_data.deref_mut(),
+            backend,
+        );
+        send_onwire_msgs(add_msgs, ws_ds);
+        let parsed_msgs =
+            client_api::parse_incoming_onwire_msgs(read_ws_messages(ws_ds), client_data, backend);
+        print_out_parsed_msgs(&parsed_msgs, print_json, &**client_data);
+
+        let state_msgs = client_api::send_group_state_update(
+            &client_data.get_user_id(),
+            &comm_grp,
+            backend,
+            client_data.deref_mut(),
+        );
+        send_onwire_msgs(state_msgs, ws_ds);
+        let parsed_msgs =
+            client_api::parse_incoming_onwire_msgs(read_ws_messages(ws_ds), client_data, backend);
+        print_out_parsed_msgs(&parsed_msgs, print_json, &**client_data);
+    }
+
+    let text_msgs = client_api::send_dm(
+        peer_name,
+        message.to_owned(),
+        backend,
+        client_data.deref_mut(),
+    );
+    send_onwire_msgs(text_msgs, ws_ds);
+    let parsed_msgs =
+        client_api::parse_incoming_onwire_msgs(read_ws_messages(ws_ds), client_data, backend);
+    print_out_parsed_msgs(&parsed_msgs, print_json, &**client_data);
+    retryable_error(&parsed_msgs)
+}
+
+/// Accepts the invite to `comm_grp`, then tells the DS to stop delivering
+/// its welcome again
+fn handle_accept(
+    command: &ClientInputCommand,
+    comm_grp: &CommGroupId,
+    client_data: &mut Box<dyn ClientDataProvider>,
+    backend: &mut CryptoBackend,
+    ws_ds: &mut ReconnectingWs,
+    print_json: bool,
+) -> Option<String> {
+    let accept_msgs = group_onwire_msgs_for_ds(command, backend, client_data);
+    send_onwire_msgs(accept_msgs, ws_ds);
+    let parsed_msgs =
+        client_api::parse_incoming_onwire_msgs(read_ws_messages(ws_ds), client_data, backend);
+    print_out_parsed_msgs(&parsed_msgs, print_json, &**client_data);
+    let accepted = parsed_msgs.iter().any(|msg| {
+        matches!(
+            msg,
+            ClientParsedMsg::DSFeedback {
+                request_valid: true,
+                ..
+            }
+        )
+    });
+    if !accepted {
+        return retryable_error(&parsed_msgs);
+    }
+    send_onwire_msg(
+        client_api::accepted_invite_msg(comm_grp, client_data.deref()),
+        ws_ds,
+    );
+    let parsed_msgs =
+        client_api::parse_incoming_onwire_msgs(read_ws_messages(ws_ds), client_data, backend);
+    print_out_parsed_msgs(&parsed_msgs, print_json, &**client_data);
+    None
+}
+
+/// Declines the invite to `comm_grp`, then removes the client from the group
+/// once the DS accepted the decline
+fn handle_decline_and_remove_self(
+    command: &ClientInputCommand,
+    comm_grp: &CommGroupId,
+    client_data: &mut Box<dyn ClientDataProvider>,
+    backend: &mut CryptoBackend,
+    ws_ds: &mut ReconnectingWs,
+    print_json: bool,
+) -> Option<String> {
+    let decline_msgs = group_onwire_msgs_for_ds(command, backend, client_data);
+    if decline_msgs.is_empty() {
+        // Only a pending welcome, which was dropped locally
+        return None;
+    }
+    send_onwire_msgs(decline_msgs, ws_ds);
+    let parsed_msgs =
+        client_api::parse_incoming_onwire_msgs(read_ws_messages(ws_ds), client_data, backend);
+    print_out_parsed_msgs(&parsed_msgs, print_json, &**client_data);
+    let declined = parsed_msgs.iter().any(|msg| {
+        matches!(
+            msg,
+            ClientParsedMsg::DSFeedback {
+                request_valid: true,
+                ..
+            }
+        )
+    });
+    if !declined {
+        return retryable_error(&parsed_msgs);
+    }
+    send_onwire_msgs(
+        client_api::remove_self_on_decline(comm_grp, backend, client_data),
+        ws_ds,
+    );
+    let parsed_msgs =
+        client_api::parse_incoming_onwire_msgs(read_ws_messages(ws_ds), client_data, backend);
+    print_out_parsed_msgs(&parsed_msgs, print_json, &**client_data);
+    None
+}
+
+/// Revert the group state, then broadcast the restored state for the members
+/// without a snapshot of it. Returns the error the revert can be retried
+/// after, if any
+fn handle_revert(
+    command: &ClientInputCommand,
+    comm_grp: &CommGroupId,
+    client_data: &mut Box<dyn ClientDataProvider>,
+    backend: &mut CryptoBackend,
+    ws_ds: &mut ReconnectingWs,
+    print_json: bool,
+) -> Option<String> {
+    let epoch_before = client_api::group_info(comm_grp, client_data.deref_mut())
+        .expect("Cannot find that group")
+        .epoch;
+    let revert_msgs = group_onwire_msgs_for_ds(command, backend, client_data);
+    if revert_msgs.is_empty() {
+        error!("No snapshot of the group state at that epoch to revert to");
+        return None;
+    }
+    send_onwire_msgs(revert_msgs, ws_ds);
+    let parsed_msgs =
+        client_api::parse_incoming_onwire_msgs(read_ws_messages(ws_ds), client_data, backend);
+    print_out_parsed_msgs(&parsed_msgs, print_json, &**client_data);
+
+    let epoch_after = client_api::group_info(comm_grp, client_data.deref_mut())
+        .expect("Cannot find that group")
+        .epoch;
+    if epoch_after == epoch_before {
+        return retryable_error(&parsed_msgs);
+    }
+    None
+}
+
+fn handle_export_gov(
+    comm_grp: &CommGroupId,
+    out: &Path,
+    client_data: &(impl ClientDataProvider + ?Sized),
+) {
+    match std::fs::write(out, client_api::export_gov_state(comm_grp, client_data)) {
+        Ok(()) => info!("Exported the group state to {}", out.display()),
+        Err(e) => error!("Cannot write the group state to {}: {}", out.display(), e),
+    }
+}
+
+fn handle_export_history(comm_grp: &CommGroupId, out: &Path, client_data: &ClientData) {
+    match client_data.export_history(comm_grp, out) {
+        Ok(()) => info!("Exported the message history to {}", out.display()),
+        Err(e) => error!(
+            "Cannot write the message history to {}: {}",
+            out.display(),
+            e
+        ),
+    }
+}
+
+/// Prints whether the history export in `file` is intact, and if so who
+/// exported it with which key. Returns whether it is intact
+fn handle_verify_history(file: &Path, print_json: bool) -> bool {
+    let result = client_api::verify_history_export(file);
+    if print_json {
+        let report = match &result {
+            Ok(export) => serde_json::json!({
+                "intact": true,
+                "exporter": export.exporter,
+                "verification_key_fingerprint":
+                    verification_key_fingerprint(&export.verification_key),
+                "n_msgs": export.entries.len(),
+            }),
+            Err(e) => serde_json::json!({ "intact": false, "error": e.to_string() }),
+        };
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    } else {
+        match &result {
+            Ok(export) => println!(
+                "{} {} messages exported by {} with key {}",
+                "Intact:".color(SUCCESS_COLOR),
+                export.entries.len(),
+                export.exporter,
+                verification_key_fingerprint(&export.verification_key)
+            ),
+            Err(e) => println!("{} {}", "Altered:".color(FAILED_COLOR), e),
+        }
+    }
+    result.is_ok()
+}
+
+/// Prints the decoded messages of a captured websocket exchange in `path`,
+/// with their key material redacted, and the offsets of malformed frames
+fn handle_dump_wire(path: &Path, print_json: bool) {
+    let dump = match std::fs::read(path) {
+        Ok(dump) => dump,
+        Err(e) => {
+            error!("Cannot read {}: {}", path.display(), e);
+            return;
+        }
+    };
+    for frame in decode_wire_dump(&dump) {
+        match frame {
+            DumpedFrame::Decoded { offset, msg } => {
+                let mut value = serde_json::to_value(&msg).expect("Cannot serialize message");
+                redact_key_material(&mut value);
+                if print_json {
+                    println!("{}", value);
+                } else {
+                    println!(
+                        "{} {}\n{}",
+                        format!("@{}", offset).color(SUCCESS_COLOR),
+                        msg.onwire_msg.variant_name(),
+                        serde_json::to_string_pretty(&value).unwrap()
+                    );
+                }
+            }
+            DumpedFrame::Malformed { offset, error } => {
+                if print_json {
+                    println!(
+                        "{}",
+                        serde_json::json!({ "offset": offset, "error": error })
+                    );
+                } else {
+                    println!("{} {}", format!("@{}", offset).color(FAILED_COLOR), error);
+                }
+            }
+        }
+    }
+}
+
+/// Replaces the local group state with the exported one in `file`, and
+/// broadcasts it. Returns the error the command can be retried after, if any
+fn handle_import_gov(
+    comm_grp: &CommGroupId,
+    file: &Path,
+    client_data: &mut Box<dyn ClientDataProvider>,
+    backend: &mut CryptoBackend,
+    ws_ds: &mut ReconnectingWs,
+    print_json: bool,
+) -> Option<String> {
+    let exported = match std::fs::read_to_string(file) {
+        Ok(exported) => exported,
+        Err(e) => {
+            error!("Cannot read the group state from {}: {}", file.display(), e);
+            return None;
+        }
+    };
+    let import_msgs =
+        match client_api::import_gov_state(comm_grp, &exported, backend, client_data.deref_mut()) {
+            Ok(import_msgs) => import_msgs,
+            Err(e) => {
+                error!("{}", e);
+                return None;
+            }
+        };
+    send_onwire_msgs(import_msgs, ws_ds);
+    let parsed_msgs =
+        client_api::parse_incoming_onwire_msgs(read_ws_messages(ws_ds), client_data, backend);
+    print_out_parsed_msgs(&parsed_msgs, print_json, &**client_data);
+    retryable_error(&parsed_msgs)
+}
+
+/// Send our group state directly to every member who requested it
+fn handle_gov_state_requests(
+    parsed_msgs: &[ClientParsedMsg],
+    client_data: &mut Box<dyn ClientDataProvider>,
+    backend: &mut CryptoBackend,
+    ws_ds: &mut ReconnectingWs,
+    print_json: bool,
+) {
+    let transfer_msgs =
+        client_api::answer_gov_state_requests(parsed_msgs, backend, client_data.deref_mut());
+    for transfer_msg in transfer_msgs {
+        send_onwire_msg(transfer_msg, ws_ds);
+        let parsed_msgs =
+            client_api::parse_incoming_onwire_msgs(read_ws_messages(ws_ds), client_data, backend);
+        print_out_parsed_msgs(&parsed_msgs, print_json, &**client_data);
+    }
+}
+
+fn handle_register(
+    backend: &mut CryptoBackend,
+    ws_as: &mut ReconnectingWs,
+    ws_ds: &mut ReconnectingWs,
+    client_data: &mut (impl ClientDataProvider + ?Sized),
+    client_config: &ClientConfig,
+    display_name: String,
+    endorsement: Option<DeviceEndorsement>,
+) {
+    let credential_bundle = backend.read_credential_bundle(&client_data.get_credential());
+
+    //AS Registration
+    send_onwire_msgs(
+        register_msg_as_with_endorsement(
+            credential_bundle.credential().to_owned(),
+            client_data.get_keypair().public_key(),
+            display_name,
+            endorsement,
+        ),
+        ws_as,
+    );
+    oks_or_panic(read_ws_messages(ws_as));
+
+    //DS Registration, with a token if the DS requires one
+    let now = client_data.now();
+    ws_ds.set_token(client_api::fresh_ds_token(client_data, ws_as, now));
+    let mut new_key_packages = vec![];
+    for _ in 0..client_config.new_key_packages_per_sync {
+        new_key_packages.push(
+            backend.generate_default_key_package_and_store_bundle(credential_bundle.credential()),
+        )
+    }
+    send_onwire_msgs(register_msg_ds(new_key_packages), ws_ds);
+    oks_or_panic(read_ws_messages(ws_ds));
+}
+
+/// Writes the identity of this new device for another device of the user to
+/// endorse. The identity is kept to register once endorsed
+fn handle_endorsement_request(out: &Path, client_data: &(impl ClientDataProvider + ?Sized)) {
+    let request = DeviceEndorsementRequest {
+        credential: client_data.get_credential(),
+        verification_key: client_data.get_keypair().public_key(),
+    };
+    match std::fs::write(out, serde_json::to_vec_pretty(&request).unwrap()) {
+        Ok(()) => info!(
+            "Wrote the endorsement request to {}. Run `endorse-device` with it on a registered device, then `register {} --endorsement <file>` here",
+            out.display(),
+            client_data.get_user_id()
+        ),
+        Err(e) => error!(
+            "Cannot write the endorsement request to {}: {}",
+            out.display(),
+            e
+        ),
+    }
+}
+
+/// Endorses the new device of this user that wrote `request`
+fn handle_endorse_device(
+    request: &Path,
+    out: &Path,
+    client_data: &(impl ClientDataProvider + ?Sized),
+) {
+    let request: DeviceEndorsementRequest = std::fs::read(request)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_else(|| panic!("Cannot read the endorsement request {}", request.display()));
+    let endorsement = endorse_device(&request.credential, &request.verification_key, client_data);
+    match std::fs::write(out, serde_json::to_vec_pretty(&endorsement).unwrap()) {
+        Ok(()) => info!("Wrote the endorsement to {}", out.display()),
+        Err(e) => error!("Cannot write the endorsement to {}: {}", out.display(), e),
+    }
+}
+
+fn read_endorsement(path: &Path) -> DeviceEndorsement {
+    std::fs::read(path)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_else(|| panic!("Cannot read the endorsement {}", path.display()))
+}
+
+/// Has the AS revoke the credential of `user_name`, as an operator
+fn handle_revoke_credential(admin_credential: &str, user_name: &str, ws_as: &mut ReconnectingWs) {
+    send_onwire_msg(
+        client_api::revoke_credential_msg(admin_credential, user_name),
+        ws_as,
+    );
+    for msg in read_ws_messages(ws_as) {
+        if let OnWireMessage::ASResult {
+            request_valid,
+            explanation,
+            ..
+        } = msg
+        {
+            match request_valid {
+                true => info!("Revoked the credential of [{}]", user_name),
+                false => error!(
+                    "Cannot revoke the credential of [{}]: {}",
+                    user_name,
+                    explanation.unwrap_or_default()
+                ),
+            }
+        }
+    }
+}
+
+/// Asks the AS whether it stores the credential of this user, and whether
+/// the verification key registered with it is the local one
+fn handle_whoami(
+    client_data: &(impl ClientDataProvider + ?Sized),
+    ws_as: &mut ReconnectingWs,
+    print_json: bool,
+) {
+    send_onwire_msg(whoami_msg(client_data), ws_as);
+    let response = read_ws_messages(ws_as)
+        .into_iter()
+        .find_map(|msg| match msg {
+            OnWireMessage::ASWhoAmIResponse {
+                user_name,
+                registered,
+                revoked,
+                verification_key_fingerprint: registered_fingerprint,
+            } => Some((user_name, registered, revoked, registered_fingerprint)),
+            _ => None,
+        });
+    let (user_name, registered, revoked, registered_fingerprint) = match response {
+        Some(response) => response,
+        None => {
+            error!("No answer from AS to the whoami request");
+            return;
+        }
+    };
+    let local_fingerprint = verification_key_fingerprint(&client_data.get_keypair().public_key());
+    let key_matches = registered_fingerprint.as_ref() == Some(&local_fingerprint);
+    if print_json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&serde_json::json!({
+                "user_name": user_name,
+                "registered": registered,
+                "revoked": revoked,
+                "verification_key_fingerprint": registered_fingerprint,
+                "local_key_matches": key_matches,
+            }))
+            .unwrap()
+        );
+    } else if !registered {
+        println!(
+            "The AS has no credential for {}. Please register first",
+            user_name
+        );
+    } else {
+        println!("The AS has a credential for {}", user_name);
+        println!(
+            "Registered verification key: {}",
+            registered_fingerprint.unwrap_or_default()
+        );
+        if !key_matches {
+            println!(
+                "It differs from the local verification key: {}",
+                local_fingerprint
+            );
+        }
+        if revoked {
+            println!("The credential was revoked");
+        }
+    }
+}
+
+fn handle_read(
+    client_config: &mut ClientData,
+    community_id: &String,
+    group_id: &String,
+    read_option_input: &Option<ReadOption>,
+    print_json: bool,
+) {
+    let read_option = read_option_input.clone().unwrap_or_default();
+    let comm_grp = CommGroupId::new(community_id, group_id);
+    client_config.purge_expired_msgs(&comm_grp, SystemTime::now());
+    let display_names = client_config.get_display_names();
+    if let ReadOption::Since { iso8601 } = read_option {
+        let messages = client_config.get_history_since(&comm_grp, iso8601);
+        print_out_history_msgs(messages, community_id, group_id, &display_names, print_json);
+        return;
+    }
+    if let ReadOption::Thread { message_id } = &read_option {
+        let messages = client_config.get_thread(&comm_grp, message_id);
+        print_out_history_msgs(messages, community_id, group_id, &display_names, print_json);
+        return;
+    }
+
+    let user_id = client_config.get_user_id();
+    // Muted messages were not counted as unread, so are not shown as such
+    let muted_senders: BTreeSet<String> = client_config
+        .get_group_state(&comm_grp)
+        .history
+        .iter()
+        .map(|history_msg| &history_msg.sender)
+        .filter(|sender| client_config.is_muted(&comm_grp, sender))
+        .cloned()
+        .collect();
+    let group = client_config.get_mut_group_state(&comm_grp);
+    let mut messages = vec![];
     match read_option {
-        ReadOption::Unread => {
+        ReadOption::Unread | ReadOption::Follow { .. } => {
             let mut remaining_other_msg_count = group.unread_msgs_count;
             for history_msg in &group.history {
+                if remaining_other_msg_count == 0 {
+                    break;
+                }
+                if muted_senders.contains(&history_msg.sender) {
+                    continue;
+                }
                 messages.push(history_msg);
                 if history_msg.sender != user_id {
                     remaining_other_msg_count -= 1;
                 }
-                if remaining_other_msg_count == 0 {
-                    break;
-                }
             }
             group.unread_msgs_count = 0;
         }
@@ -515,37 +1548,370 @@ fn handle_read(
                 messages.push(history_msg);
             }
         }
+        ReadOption::Since { .. } | ReadOption::Thread { .. } => unreachable!("Handled above"),
     };
+    print_out_history_msgs(messages, community_id, group_id, &display_names, print_json);
+}
+
+fn print_out_history_msgs(
+    mut messages: Vec<&LocalHistoryMessage>,
+    community_id: &String,
+    group_id: &String,
+    display_names: &BTreeMap<String, String>,
+    print_json: bool,
+) {
     // Message are from new to old. reverse and print
     messages.reverse();
     if print_json {
-        print_out_local_history_msg_json(messages);
-        warn!("`nano_since_epoch` is incorrect in the JSON output. Use `sec_since_epoch` instead");
+        print_out_local_history_msg_json(messages);
+        warn!("`nano_since_epoch` is incorrect in the JSON output. Use `sec_since_epoch` instead");
+    } else {
+        print_out_local_history_msg_plaintext(messages, community_id, group_id, display_names);
+    }
+}
+
+/// Prints the unread messages of a group, then keeps syncing every
+/// `poll_interval` and prints the messages that arrived since, until Ctrl-C
+#[allow(clippy::too_many_arguments)]
+fn handle_read_follow(
+    client_data: &mut Box<dyn ClientDataProvider>,
+    backend: &mut CryptoBackend,
+    ws_ds: &mut ReconnectingWs,
+    ws_as: &mut ReconnectingWs,
+    client_config: &ClientConfig,
+    community_id: &String,
+    group_id: &String,
+    poll_interval: Duration,
+    print_json: bool,
+) {
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let handler_interrupted = interrupted.clone();
+    ctrlc::set_handler(move || handler_interrupted.store(true, Ordering::SeqCst))
+        .expect("Cannot set the Ctrl-C handler");
+
+    let comm_grp = CommGroupId::new(community_id, group_id);
+    let mut followed = FollowedMessages::default();
+    let client_ref = client_data
+        .as_any_mut()
+        .downcast_mut::<ClientData>()
+        .unwrap();
+    handle_read(
+        client_ref,
+        community_id,
+        group_id,
+        &Some(ReadOption::Unread),
+        print_json,
+    );
+    followed.unseen(&client_ref.get_group_state(&comm_grp).history);
+
+    while !interrupted.load(Ordering::SeqCst) {
+        thread::sleep(poll_interval);
+        if interrupted.load(Ordering::SeqCst) {
+            break;
+        }
+        // Following may outlast the token
+        if ws_ds.token().is_some() {
+            ws_ds.set_token(client_api::fresh_ds_token(
+                client_data.deref_mut(),
+                ws_as,
+                SystemTime::now(),
+            ));
+        }
+        // Only sync if the DS holds anything
+        let parsed_msgs = if client_api::poll_pending(client_data.deref(), ws_ds)
+            .map_or(false, |counts| counts.is_empty())
+        {
+            vec![]
+        } else {
+            handle_sync_ds(client_data.deref(), backend, ws_ds, client_config);
+            let msgs = read_ws_messages(ws_ds);
+            // For the verification keys of members who joined meanwhile
+            let _ = handle_sync_as(
+                client_data.deref_mut(),
+                ws_as,
+                client_config.drop_revoked_credentials,
+            );
+            client_api::parse_incoming_onwire_msgs(msgs, client_data, backend)
+        };
+        handle_gov_state_requests(&parsed_msgs, client_data, backend, ws_ds, print_json);
+
+        let client_ref = client_data
+            .as_any_mut()
+            .downcast_mut::<ClientData>()
+            .unwrap();
+        client_ref.purge_expired_msgs(&comm_grp, SystemTime::now());
+        let display_names = client_ref.get_display_names();
+        let group = client_ref.get_mut_group_state(&comm_grp);
+        group.unread_msgs_count = 0;
+        let messages = followed.unseen(&group.history);
+        if messages.is_empty() {
+            continue;
+        }
+        if print_json {
+            print_out_local_history_msg_json(messages);
+        } else {
+            print_out_local_history_msg_plaintext(messages, community_id, group_id, &display_names);
+        }
+    }
+    info!("Stopped following community [{community_id}] group [{group_id}]");
+}
+
+/// The history messages `read --follow` has printed so far. History messages
+/// carry no id of their own, so they are told apart by sender and send time.
+#[derive(Default)]
+struct FollowedMessages {
+    seen: HashSet<(String, SystemTime)>,
+}
+
+impl FollowedMessages {
+    /// The messages of `history` (newest first) not returned by an earlier
+    /// call, oldest first
+    fn unseen<'a>(&mut self, history: &'a [LocalHistoryMessage]) -> Vec<&'a LocalHistoryMessage> {
+        let mut messages: Vec<_> = history
+            .iter()
+            .filter(|msg| {
+                self.seen
+                    .insert((msg.sender.clone(), msg.message.sender_timestamp))
+            })
+            .collect();
+        messages.reverse();
+        messages
+    }
+}
+
+fn handle_replay_policies(
+    comm_grp: &CommGroupId,
+    client_data: &(impl ClientDataProvider + ?Sized),
+    print_json: bool,
+) {
+    let flagged_actions = match client_api::replay_policies(comm_grp, client_data) {
+        Ok(flagged_actions) => flagged_actions,
+        Err(err) => {
+            error!("Cannot replay the policies: {}", err);
+            return;
+        }
+    };
+    if print_json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&flagged_actions).unwrap()
+        );
+    } else if flagged_actions.is_empty() {
+        info!("All recorded actions would pass under the current policies");
+    } else {
+        warn!(
+            "{} recorded action(s) would not pass under the current policies:",
+            flagged_actions.len()
+        );
+        for action in flagged_actions {
+            println!(
+                "[{:?}] by [{}]: {:?}",
+                action.action_type(),
+                action.get_metadata().sender,
+                action
+            );
+        }
+    }
+}
+
+fn handle_group_info(
+    comm_grp: &CommGroupId,
+    with_keys: bool,
+    client_data: &mut (impl ClientDataProvider + ?Sized),
+    print_json: bool,
+) {
+    let mut group_info = match client_api::group_info(comm_grp, client_data) {
+        Some(group_info) => group_info,
+        None => {
+            error!("Cannot find group {}", comm_grp.group_id());
+            return;
+        }
+    };
+    if !with_keys {
+        group_info.key_fingerprints.clear();
+    }
+    if print_json {
+        println!("{}", serde_json::to_string_pretty(&group_info).unwrap());
+    } else {
+        println!(
+            "Group [{}] of community [{}]",
+            comm_grp.group_id(),
+            comm_grp.community_id()
+        );
+        println!("Name: {}", group_info.name);
+        println!("Topic: {}", group_info.topic);
+        println!("MLS epoch: {}", group_info.epoch);
+        println!("Members:");
+        for (member, role) in group_info.members {
+            match group_info.key_fingerprints.get(&member) {
+                Some(fingerprint) => println!("  {} ({}) {}", member, role, fingerprint),
+                None => println!("  {} ({})", member, role),
+            }
+        }
+    }
+}
+
+/// Prints the inconsistencies found in the local state of the group, and
+/// returns whether there were none
+fn handle_verify(
+    comm_grp: &CommGroupId,
+    client_data: &mut (impl ClientDataProvider + ?Sized),
+    print_json: bool,
+) -> bool {
+    let discrepancies = match client_api::verify_group_integrity(comm_grp, client_data) {
+        Some(discrepancies) => discrepancies,
+        None => {
+            error!("Cannot find group {}", comm_grp.group_id());
+            return false;
+        }
+    };
+    if print_json {
+        println!("{}", serde_json::to_string_pretty(&discrepancies).unwrap());
+    } else if discrepancies.is_empty() {
+        println!("{}", "No inconsistency found".color(SUCCESS_COLOR));
+    } else {
+        println!(
+            "{}",
+            format!("{} inconsistencies found:", discrepancies.len()).color(FAILED_COLOR)
+        );
+        for discrepancy in &discrepancies {
+            println!("  {}", discrepancy);
+        }
+    }
+    discrepancies.is_empty()
+}
+
+fn handle_list_reports(
+    comm_grp: &CommGroupId,
+    client_data: &(impl ClientDataProvider + ?Sized),
+    print_json: bool,
+) {
+    let reports = match client_api::list_reports(comm_grp, client_data) {
+        Ok(reports) => reports,
+        Err(err) => {
+            error!("Cannot list reports: {}", err);
+            return;
+        }
+    };
+    if print_json {
+        println!("{}", serde_json::to_string_pretty(&reports).unwrap());
+    } else if reports.is_empty() {
+        info!("No reports to review");
+    } else {
+        for report in reports {
+            let datetime: DateTime<Local> = report.received_timestamp.into();
+            println!(
+                "[Report {}] by [{}]: {}\n  Reported action: {}",
+                datetime.format("%d/%m/%Y %T"),
+                report.reporter,
+                report.reason,
+                report.reported_action
+            );
+        }
+    }
+}
+
+fn handle_flagged(
+    comm_grp: &CommGroupId,
+    client_data: &(impl ClientDataProvider + ?Sized),
+    print_json: bool,
+) {
+    let flagged = match client_api::list_flagged(comm_grp, client_data) {
+        Ok(flagged) => flagged,
+        Err(err) => {
+            error!("Cannot list flagged messages: {}", err);
+            return;
+        }
+    };
+    if print_json {
+        println!("{}", serde_json::to_string_pretty(&flagged).unwrap());
+    } else if flagged.is_empty() {
+        info!("No flagged messages to review");
+    } else {
+        for (message_id, flag_count) in flagged {
+            println!("[{} flags] {}", flag_count, message_id);
+        }
+    }
+}
+
+fn handle_proposals(
+    comm_grp: &CommGroupId,
+    client_data: &(impl ClientDataProvider + ?Sized),
+    print_json: bool,
+) {
+    let proposals = client_api::list_proposals(comm_grp, client_data);
+    if print_json {
+        println!("{}", serde_json::to_string_pretty(&proposals).unwrap());
+    } else if proposals.is_empty() {
+        info!("No pending proposals");
+    } else {
+        for (action, votes_needed) in proposals {
+            let metadata = action.get_metadata();
+            let votes_needed_str = if votes_needed == VOTES_UNREACHABLE {
+                "can no longer pass".to_string()
+            } else {
+                format!("{} more yes vote(s) needed", votes_needed)
+            };
+            println!(
+                "[Proposal {}] {:?} by [{}]: {}",
+                metadata.action_id,
+                action.action_type(),
+                metadata.sender,
+                votes_needed_str
+            );
+        }
+    }
+}
+
+/// Prints the latest decisions of the policy engine of the group
+fn handle_show_policy_log(
+    comm_grp: &CommGroupId,
+    client_data: &(impl ClientDataProvider + ?Sized),
+    print_json: bool,
+) {
+    let decisions = client_api::policy_log(comm_grp, client_data);
+    if print_json {
+        println!("{}", serde_json::to_string_pretty(&decisions).unwrap());
+    } else if decisions.is_empty() {
+        info!("No policy decision yet");
     } else {
-        print_out_local_history_msg_plaintext(messages, community_id, group_id);
+        for decision in decisions {
+            println!(
+                "[Action {}] {:?} by {}: {}",
+                decision.action_id, decision.status, decision.policy_name, decision.reason
+            );
+        }
     }
 }
 
-fn send_onwire_msgs(
-    on_wire_msgs: Vec<OnWireMessage>,
-    websocket: &mut WebSocket<MaybeTlsStream<TcpStream>>,
-) {
+impl OnWireTransport for ReconnectingWs {
+    fn request(&mut self, onwire_msg: OnWireMessage) -> Vec<OnWireMessage> {
+        send_onwire_msg(onwire_msg, self);
+        read_ws_messages(self)
+    }
+}
+
+fn send_onwire_msgs(on_wire_msgs: Vec<OnWireMessage>, websocket: &mut ReconnectingWs) {
     for on_wire_msg in on_wire_msgs {
         send_onwire_msg(on_wire_msg, websocket);
         debug!("A message was sent to DS")
     }
 }
 
-fn send_onwire_msg(
-    onwire_msg: OnWireMessage,
-    websocket: &mut WebSocket<MaybeTlsStream<TcpStream>>,
-) {
-    let onwire_msg_w_meta = OnWireMessageWithMetaData {
-        onwire_msg: onwire_msg.to_owned(),
-        sender_timestamp: SystemTime::now(),
-        version: Versioning::new("0.3.0").unwrap().to_string(),
-    };
-    let encoded = serde_json::to_vec(&onwire_msg_w_meta).expect("Cannot encode app msg");
+fn encode_onwire_msg(
+    onwire_msg: &OnWireMessage,
+    compress: bool,
+    token: Option<AuthToken>,
+) -> Vec<u8> {
+    OnWireMessageWithMetaData::new(onwire_msg.to_owned(), compress)
+        .with_token(token)
+        .encode()
+}
+
+fn send_onwire_msg(onwire_msg: OnWireMessage, websocket: &mut ReconnectingWs) {
+    let onwire_msg_w_meta = OnWireMessageWithMetaData::new(onwire_msg, websocket.compress())
+        .with_token(websocket.token());
+    let encoded = onwire_msg_w_meta.encode();
 
     let encoded_size = encoded.len();
 
@@ -553,30 +1919,59 @@ fn send_onwire_msg(
 
     match websocket.write_message(tungstenite::Message::Binary(encoded)) {
         Ok(_) => {
-            trace!("Sent message via websocket: [{:?}]", onwire_msg);
+            trace!(
+                "Sent message via websocket: [{:?}]",
+                onwire_msg_w_meta.onwire_msg
+            );
             let duration = pre_send_timestamp.elapsed();
             SingleTimeMeasurement::new(SingleSendMessageDelay, duration);
-            SingleMsgSizeMeasurement::new(OutgoingMsg, encoded_size);
+            if onwire_msg_w_meta.compress {
+                SingleMsgSizeMeasurement::new_compressed(
+                    OutgoingMsg,
+                    encoded_size,
+                    onwire_msg_w_meta.uncompressed_len(),
+                );
+            } else {
+                SingleMsgSizeMeasurement::new(OutgoingMsg, encoded_size);
+            }
             return;
         }
         Err(e) => error!(
             "Unable to send message via websocket:  {:?} [{:?}]",
-            e, &onwire_msg
+            e, &onwire_msg_w_meta.onwire_msg
         ),
     }
     websocket.write_pending().unwrap();
 }
 
-fn read_ws_messages(websocket: &mut WebSocket<MaybeTlsStream<TcpStream>>) -> Vec<OnWireMessage> {
+fn read_ws_messages(websocket: &mut ReconnectingWs) -> Vec<OnWireMessage> {
     let pre_read_timestamp = Instant::now();
     let mut onwire_msgs = vec![];
-    'ws_reading: while let Ok(msg) = websocket.read_message() {
+    'ws_reading: loop {
+        let msg = match websocket.read_message() {
+            Ok(msg) => msg,
+            Err(e) if is_read_timeout(&e) => {
+                warn!(
+                    "Timed out waiting for the server result; proceeding with the {} message(s) received",
+                    onwire_msgs.len()
+                );
+                break 'ws_reading;
+            }
+            Err(_) => break 'ws_reading,
+        };
         match msg {
             tungstenite::Message::Binary(encoded) => {
-                SingleMsgSizeMeasurement::new(IncomingMsg, encoded.len());
-                let decode_result: serde_json::Result<OnWireMessageWithMetaData> =
-                    serde_json::from_slice(&encoded);
+                let decode_result = OnWireMessageWithMetaData::decode(&encoded);
                 if let Ok(decoded) = decode_result {
+                    if decoded.compress {
+                        SingleMsgSizeMeasurement::new_compressed(
+                            IncomingMsg,
+                            encoded.len(),
+                            decoded.uncompressed_len(),
+                        );
+                    } else {
+                        SingleMsgSizeMeasurement::new(IncomingMsg, encoded.len());
+                    }
                     onwire_msgs.push(decoded.onwire_msg.to_owned());
                     match &decoded.onwire_msg {
                         OnWireMessage::DSResult { .. } | OnWireMessage::ASResult { .. } => {
@@ -603,8 +1998,10 @@ fn read_ws_messages(websocket: &mut WebSocket<MaybeTlsStream<TcpStream>>) -> Vec
     onwire_msgs
 }
 
-fn determine_if_retry(msgs: &Vec<ClientParsedMsg>) -> bool {
-    let mut result = false;
+/// Logs the errors among `msgs`, and returns the last of those the command
+/// can be retried after, if any
+fn retryable_error(msgs: &Vec<ClientParsedMsg>) -> Option<String> {
+    let mut result = None;
     for msg in msgs {
         match msg {
             ClientParsedMsg::Invalid {
@@ -619,10 +2016,24 @@ fn determine_if_retry(msgs: &Vec<ClientParsedMsg>) -> bool {
                 }
                 if *retry_possible {
                     info!("Please retry, or would be auto retried.");
-                    result = true;
+                    result = Some(description.clone());
+                }
+            }
+            ClientParsedMsg::EpochMismatch { self_sent, .. } => {
+                let description = msg.error_description().unwrap_or_default();
+                if *self_sent {
+                    error!("{}", description);
+                    info!("Please retry, or would be auto retried.");
+                    result = Some(description);
+                } else {
+                    warn!("{}", description);
                 }
             }
+            ClientParsedMsg::DecryptionFailed { .. } => {
+                warn!("{}", msg.error_description().unwrap_or_default());
+            }
             ClientParsedMsg::NewInvite { .. }
+            | ClientParsedMsg::ReinviteRequest { .. }
             | ClientParsedMsg::NewMsg { .. }
             | ClientParsedMsg::NewOrdMsg { .. }
             | ClientParsedMsg::ASFeedback { .. }
@@ -632,10 +2043,157 @@ fn determine_if_retry(msgs: &Vec<ClientParsedMsg>) -> bool {
     result
 }
 
-fn print_out_parsed_msgs(msgs: &Vec<ClientParsedMsg>) {
+/// What to do once a command was attempted
+#[derive(Debug, PartialEq)]
+enum RetryDecision {
+    /// The command went through, or cannot be retried
+    Done,
+    /// Retry the command, with that many retries left after this one, if
+    /// capped by `--max-retries`
+    Retry { retries_left: Option<u32> },
+    /// Stop retrying, the retry budget being exhausted
+    GiveUp { attempts: u32, last_error: String },
+}
+
+/// Decides whether to retry a command after `attempts` attempts of it, the
+/// last one failing with `retry_reason` if it can be retried
+fn retry_decision(
+    attempts: u32,
+    auto_retry: bool,
+    max_retries: Option<u32>,
+    retry_reason: Option<String>,
+) -> RetryDecision {
+    let last_error = match retry_reason {
+        Some(last_error) if auto_retry => last_error,
+        _ => return RetryDecision::Done,
+    };
+    let retries_done = attempts.saturating_sub(1);
+    match max_retries {
+        Some(max_retries) if retries_done >= max_retries => RetryDecision::GiveUp {
+            attempts,
+            last_error,
+        },
+        Some(max_retries) => RetryDecision::Retry {
+            retries_left: Some(max_retries - retries_done - 1),
+        },
+        None => RetryDecision::Retry { retries_left: None },
+    }
+}
+
+/// Tells the user that the command failed after all the retries allowed
+fn print_out_retries_exhausted(attempts: u32, last_error: &str, print_json: bool) {
+    let description = format!(
+        "Gave up after {} attempts. Last error: {}",
+        attempts, last_error
+    );
+    if print_json {
+        let feedback = JsonFeedback {
+            kind: "RetriesExhausted",
+            valid: false,
+            retry_possible: false,
+            description,
+            process_time_ns: None,
+        };
+        println!("{}", serde_json::to_string(&feedback).unwrap());
+    } else {
+        error!("{}", description);
+    }
+}
+
+/// A server feedback or a client error, printed as a single line under `--json`
+#[derive(Serialize, Debug)]
+struct JsonFeedback {
+    kind: &'static str,
+    valid: bool,
+    retry_possible: bool,
+    description: String,
+    process_time_ns: Option<u128>,
+}
+
+impl JsonFeedback {
+    /// The feedback carried by `msg`, if any
+    fn from_parsed_msg(msg: &ClientParsedMsg) -> Option<Self> {
+        match msg {
+            ClientParsedMsg::ASFeedback {
+                request_valid,
+                explanation,
+                process_time,
+            } => Some(Self::server_feedback(
+                "ASFeedback",
+                *request_valid,
+                explanation,
+                process_time,
+            )),
+            ClientParsedMsg::DSFeedback {
+                request_valid,
+                explanation,
+                process_time,
+            } => Some(Self::server_feedback(
+                "DSFeedback",
+                *request_valid,
+                explanation,
+                process_time,
+            )),
+            ClientParsedMsg::Invalid {
+                retry_possible,
+                description,
+                ..
+            } => Some(JsonFeedback {
+                kind: "Invalid",
+                valid: false,
+                retry_possible: *retry_possible,
+                description: description.clone(),
+                process_time_ns: None,
+            }),
+            ClientParsedMsg::EpochMismatch { self_sent, .. } => Some(JsonFeedback {
+                kind: "EpochMismatch",
+                valid: false,
+                retry_possible: *self_sent,
+                description: msg.error_description().unwrap_or_default(),
+                process_time_ns: None,
+            }),
+            ClientParsedMsg::DecryptionFailed { .. } => Some(JsonFeedback {
+                kind: "DecryptionFailed",
+                valid: false,
+                retry_possible: false,
+                description: msg.error_description().unwrap_or_default(),
+                process_time_ns: None,
+            }),
+            ClientParsedMsg::NewInvite { .. }
+            | ClientParsedMsg::ReinviteRequest { .. }
+            | ClientParsedMsg::NewMsg { .. }
+            | ClientParsedMsg::NewOrdMsg { .. } => None,
+        }
+    }
+
+    fn server_feedback(
+        kind: &'static str,
+        request_valid: bool,
+        explanation: &Option<String>,
+        process_time: &Duration,
+    ) -> Self {
+        JsonFeedback {
+            kind,
+            valid: request_valid,
+            retry_possible: false,
+            description: explanation.clone().unwrap_or_default(),
+            process_time_ns: Some(process_time.as_nanos()),
+        }
+    }
+}
+
+fn print_out_parsed_msgs(
+    msgs: &Vec<ClientParsedMsg>,
+    print_json: bool,
+    client_data: &(impl ClientDataProvider + ?Sized),
+) {
     let mut message_group_to_count: HashMap<(String, String), u64> = HashMap::new();
     for msg in msgs {
         match msg {
+            ClientParsedMsg::NewMsg {
+                private_msg,
+                comm_grp,
+            } if client_data.is_muted(comm_grp, &private_msg.sender) => {}
             ClientParsedMsg::NewMsg {
                 private_msg: _,
                 comm_grp,
@@ -658,12 +2216,22 @@ fn print_out_parsed_msgs(msgs: &Vec<ClientParsedMsg>) {
                     comm_grp
                 )
             }
+            ClientParsedMsg::ReinviteRequest {
+                requester,
+                comm_grp,
+            } => {
+                info!(
+                    "{} User [{requester}] asked to be invited to {:?} again. Remove then Invite them",
+                    "Invite:".color(INVITE_COLOR),
+                    comm_grp
+                )
+            }
             ClientParsedMsg::ASFeedback {
                 request_valid,
                 explanation,
                 process_time,
             } => {
-                print_out_feedback(request_valid, explanation);
+                print_out_feedback(msg, request_valid, explanation, print_json);
                 SingleTimeMeasurement::new(SingleUserRequestASProcessTime, *process_time);
                 // Same as in ok_or_panic()
             }
@@ -672,7 +2240,7 @@ fn print_out_parsed_msgs(msgs: &Vec<ClientParsedMsg>) {
                 explanation,
                 process_time,
             } => {
-                print_out_feedback(request_valid, explanation);
+                print_out_feedback(msg, request_valid, explanation, print_json);
                 SingleTimeMeasurement::new(SingleUserRequestDSProcessTime, *process_time);
                 // Same as in ok_or_panic()
             }
@@ -681,7 +2249,9 @@ fn print_out_parsed_msgs(msgs: &Vec<ClientParsedMsg>) {
                 retry_possible,
                 description,
             } => {
-                if !external_error {
+                if print_json {
+                    print_out_feedback_json(msg);
+                } else if !external_error {
                     error!("{}", description);
                     if *retry_possible {
                         info!("Please retry, or would be auto retried.");
@@ -690,8 +2260,33 @@ fn print_out_parsed_msgs(msgs: &Vec<ClientParsedMsg>) {
                     warn!("External message errorL {}", description);
                 }
             }
+            ClientParsedMsg::EpochMismatch { self_sent, .. } => {
+                let description = msg.error_description().unwrap_or_default();
+                if print_json {
+                    print_out_feedback_json(msg);
+                } else if *self_sent {
+                    error!("{}", description);
+                    info!("Please retry, or would be auto retried.");
+                } else {
+                    warn!("External message errorL {}", description);
+                }
+            }
+            ClientParsedMsg::DecryptionFailed { .. } => {
+                if print_json {
+                    print_out_feedback_json(msg);
+                } else {
+                    warn!(
+                        "External message errorL {}",
+                        msg.error_description().unwrap_or_default()
+                    );
+                }
+            }
         }
     }
+    // Only feedback is printed to stdout when JSON output is expected
+    if print_json {
+        return;
+    }
     let empty = message_group_to_count.is_empty();
     for ((community_id, group_id), count) in message_group_to_count {
         println!("You have {count} messages from community[{community_id}] group[{group_id}]");
@@ -701,7 +2296,16 @@ fn print_out_parsed_msgs(msgs: &Vec<ClientParsedMsg>) {
     }
 }
 
-fn print_out_feedback(request_valid: &bool, explanation: &Option<String>) {
+fn print_out_feedback(
+    msg: &ClientParsedMsg,
+    request_valid: &bool,
+    explanation: &Option<String>,
+    print_json: bool,
+) {
+    if print_json {
+        print_out_feedback_json(msg);
+        return;
+    }
     let result = match request_valid {
         true => "Success: ",
         false => "Failed: ",
@@ -721,23 +2325,39 @@ fn print_out_feedback(request_valid: &bool, explanation: &Option<String>) {
     }
 }
 
+fn print_out_feedback_json(msg: &ClientParsedMsg) {
+    if let Some(feedback) = JsonFeedback::from_parsed_msg(msg) {
+        println!("{}", serde_json::to_string(&feedback).unwrap());
+    }
+}
+
 fn print_out_local_history_msg_plaintext(
     messages: Vec<&LocalHistoryMessage>,
     community_id: &String,
     group_id: &String,
+    display_names: &BTreeMap<String, String>,
 ) {
     println!("Messages from community [{community_id}] group [{group_id}]");
     for msg in messages {
-        let datetime: DateTime<Local> = msg.received_timestamp.into();
-        match &msg.message.content {
-            UnorderedMsgContent::Text { text_content } => println!(
-                "[Msg {}] [{}]: {}",
-                datetime.format("%d/%m/%Y %T"),
-                msg.sender,
-                text_content,
-            ),
-            _ => println!("Content: {:?}", msg.message.content),
-        }
+        println!("{}", local_history_msg_plaintext(msg, display_names));
+    }
+}
+
+/// Formats `msg` for plaintext output, naming the sender by their display
+/// name, if they registered one
+fn local_history_msg_plaintext(
+    msg: &LocalHistoryMessage,
+    display_names: &BTreeMap<String, String>,
+) -> String {
+    let datetime: DateTime<Local> = msg.received_timestamp.into();
+    match &msg.message.content {
+        UnorderedMsgContent::Text { text_content } => format!(
+            "[Msg {}] [{}]: {}",
+            datetime.format("%d/%m/%Y %T"),
+            display_names.get(&msg.sender).unwrap_or(&msg.sender),
+            text_content,
+        ),
+        _ => format!("Content: {:?}", msg.message.content),
     }
 }
 
@@ -791,76 +2411,6 @@ fn oks_or_panic(msgs: Vec<OnWireMessage>) {
     }
 }
 
-fn credentials_or_panic(msgs: Vec<OnWireMessage>) -> Vec<Credential> {
-    for msg in msgs {
-        if msg.is_user_msg() {
-            panic!("Received user message from servers");
-        }
-        match msg {
-            OnWireMessage::ASResult {
-                request_valid,
-                explanation,
-                ..
-            }
-            | OnWireMessage::DSResult {
-                request_valid,
-                explanation,
-                ..
-            } => {
-                if !request_valid {
-                    panic!("Failed operation. Explanation: {:?}", explanation);
-                } else {
-                    info!("Operation success {:?}", explanation);
-                }
-            }
-            OnWireMessage::ASCredentialResponse {
-                queried_user_credentials,
-            } => {
-                return queried_user_credentials;
-            }
-            unk => {
-                debug!("Unexpected app msg: {:?}", unk);
-            }
-        }
-    }
-    panic!("No credential response from AS");
-}
-
-fn key_packages_or_panic(msgs: Vec<OnWireMessage>) -> Vec<KeyPackage> {
-    for msg in msgs {
-        if msg.is_user_msg() {
-            panic!("Received user message from servers");
-        }
-        match msg {
-            OnWireMessage::ASResult {
-                request_valid,
-                explanation,
-                ..
-            }
-            | OnWireMessage::DSResult {
-                request_valid,
-                explanation,
-                ..
-            } => {
-                if !request_valid {
-                    panic!("Failed operation. Explanation: {:?}", explanation);
-                } else {
-                    info!("Operation success {:?}", explanation);
-                }
-            }
-            OnWireMessage::DSKeyPackageResponse {
-                queried_user_key_packages,
-            } => {
-                return queried_user_key_packages;
-            }
-            unk => {
-                debug!("Unexpected app msg: {:?}", unk);
-            }
-        }
-    }
-    panic!("No credential response from AS");
-}
-
 #[cfg(test)]
 mod client_tests {
     use ed25519_dalek::Keypair;
@@ -870,10 +2420,30 @@ mod client_tests {
     };
     use rand_07::rngs::OsRng;
 
-    use crate::client_api::client_struct_impl::ClientData;
+    use std::collections::BTreeMap;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::{Duration, SystemTime};
+
+    use tungstenite::Message;
+    use url::Url;
+
+    use crate::client_api::client_struct_impl::{ClientData, LocalHistoryMessage};
+    use crate::networking::ReconnectingWs;
+    use crate::{encode_onwire_msg, read_ws_messages, send_onwire_msg};
     use corelib::client_api::client_crypto_impl::CryptoBackend;
+    use corelib::messages::{
+        OnWireMessage, OnWireMessageWithMetaData, UnorderedMsgContent, UnorderedPrivateMessage,
+    };
 
-    use crate::{validated_config, ClientInput};
+    use crate::config::{config_path, ClientConfig, DEFAULT_CONFIG_PATH};
+    use crate::{
+        local_history_msg_plaintext, retry_decision, skip_store_allows, validated_config,
+        ClientInput, FollowedMessages, JsonFeedback, RetryDecision, SkipStoreHazard,
+    };
+    use corelib::client_api::client_struct::ClientParsedMsg;
+    use corelib::ConfigError;
 
     fn get_test_config(user_name: &String, backend: &mut CryptoBackend) -> Option<ClientData> {
         let mut csprng = OsRng {};
@@ -905,6 +2475,9 @@ mod client_tests {
             auto_retry: false,
             max_delay: 0.0,
             window_size: 0.0,
+            config: None,
+            timeout: None,
+            max_retries: None,
         };
 
         let _ = validated_config(
@@ -932,7 +2505,429 @@ mod client_tests {
             auto_retry: false,
             max_delay: 0.0,
             window_size: 0.0,
+            config: None,
+            timeout: None,
+            max_retries: None,
         };
         let _ = validated_config(&input, None, None, &mut backend);
     }
+
+    #[test]
+    #[should_panic(expected = "branched")]
+    fn branched_state_panic() {
+        // The stored state was marked as branched by a `--skip-store` run
+        let mut backend = CryptoBackend::default();
+        let random_id = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
+        let mut client_data = get_test_config(&random_id, &mut backend).unwrap();
+        client_data.branched_state = true;
+        let input = ClientInput {
+            command: crate::ClientInputCommand::Sync,
+            verbose: 0,
+            json: false,
+            no_sync: false,
+            skip_store: false,
+            skip_history_msg_update: false,
+            fresh_start: false,
+            auto_retry: false,
+            max_delay: 0.0,
+            window_size: 0.0,
+            config: None,
+            timeout: None,
+            max_retries: None,
+        };
+        let _ = validated_config(&input, Some(client_data), None, &mut backend);
+    }
+
+    #[test]
+    fn skip_store_epoch_advancing_commands() {
+        let input = |command, skip_store| ClientInput {
+            command,
+            verbose: 0,
+            json: false,
+            no_sync: false,
+            skip_store,
+            skip_history_msg_update: false,
+            fresh_start: false,
+            auto_retry: false,
+            max_delay: 0.0,
+            window_size: 0.0,
+            config: None,
+            timeout: None,
+            max_retries: None,
+        };
+        let rename = || crate::ClientInputCommand::RenameGroup {
+            community_id: "community".to_string(),
+            group_id: "group".to_string(),
+            new_group_id: "new name".to_string(),
+        };
+
+        // Only refused when the stored state would branch
+        assert!(!skip_store_allows(
+            &input(rename(), true),
+            SkipStoreHazard::Refuse
+        ));
+        assert!(skip_store_allows(
+            &input(rename(), true),
+            SkipStoreHazard::MarkBranched
+        ));
+        assert!(skip_store_allows(
+            &input(rename(), false),
+            SkipStoreHazard::Refuse
+        ));
+        // Syncing processes the commits of others
+        assert!(!skip_store_allows(
+            &input(crate::ClientInputCommand::Sync, true),
+            SkipStoreHazard::Refuse
+        ));
+        let mut no_sync_send = input(
+            crate::ClientInputCommand::Send {
+                community_id: "community".to_string(),
+                group_id: "group".to_string(),
+                message: "hi".to_string(),
+                expires_after_secs: None,
+                in_reply_to: None,
+            },
+            true,
+        );
+        assert!(!skip_store_allows(&no_sync_send, SkipStoreHazard::Refuse));
+        no_sync_send.no_sync = true;
+        assert!(skip_store_allows(&no_sync_send, SkipStoreHazard::Refuse));
+    }
+
+    fn history_msg(sender: &str, sent_secs: u64) -> LocalHistoryMessage {
+        let sender_timestamp = SystemTime::UNIX_EPOCH + Duration::from_secs(sent_secs);
+        LocalHistoryMessage {
+            message: UnorderedPrivateMessage {
+                content: UnorderedMsgContent::Text {
+                    text_content: format!("sent at {sent_secs}"),
+                },
+                sender_timestamp,
+                sender: sender.to_string(),
+                seq: 0,
+            },
+            sender: sender.to_string(),
+            received_timestamp: SystemTime::now(),
+            expires_at: None,
+            in_reply_to: None,
+        }
+    }
+
+    #[test]
+    /// Following a group over overlapping histories should return each
+    /// message once, oldest first
+    fn follow_dedups_overlapping_syncs() {
+        let mut followed = FollowedMessages::default();
+        let first_sync = vec![history_msg("bob", 2), history_msg("alice", 1)];
+        let second_sync = vec![
+            history_msg("alice", 3),
+            history_msg("bob", 2),
+            history_msg("bob", 2),
+            history_msg("alice", 1),
+        ];
+
+        let sent = |messages: Vec<&LocalHistoryMessage>| {
+            messages
+                .iter()
+                .map(|msg| (msg.sender.clone(), msg.message.sender_timestamp))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            sent(followed.unseen(&first_sync)),
+            sent(vec![&first_sync[1], &first_sync[0]])
+        );
+        assert_eq!(
+            sent(followed.unseen(&second_sync)),
+            sent(vec![&second_sync[0]])
+        );
+        assert!(followed.unseen(&second_sync).is_empty());
+    }
+
+    #[test]
+    /// A sender with a registered display name should be shown by it, and
+    /// others by their unique id
+    fn read_output_shows_display_names() {
+        let display_names = BTreeMap::from([("u123".to_string(), "Alice".to_string())]);
+        let named = local_history_msg_plaintext(&history_msg("u123", 1), &display_names);
+        assert!(named.contains("[Alice]: sent at 1"));
+        assert!(!named.contains("u123"));
+        let unnamed = local_history_msg_plaintext(&history_msg("bob", 2), &display_names);
+        assert!(unnamed.contains("[bob]: sent at 2"));
+    }
+
+    #[test]
+    /// A failed DS result should be printed as one flat JSON object
+    fn failed_ds_result_json_feedback() {
+        let msg = ClientParsedMsg::DSFeedback {
+            request_valid: false,
+            explanation: Some("Group not found".to_string()),
+            process_time: Duration::from_nanos(1500),
+        };
+        let line = serde_json::to_string(&JsonFeedback::from_parsed_msg(&msg).unwrap()).unwrap();
+        assert!(!line.contains('\n'));
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&line).unwrap(),
+            serde_json::json!({
+                "kind": "DSFeedback",
+                "valid": false,
+                "retry_possible": false,
+                "description": "Group not found",
+                "process_time_ns": 1500,
+            })
+        );
+    }
+
+    #[test]
+    /// When the DS never sends the result of a request, reading should give
+    /// up after the read timeout with what was received, without reconnecting
+    fn read_times_out_without_result() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("ws://{}", listener.local_addr().unwrap())).unwrap();
+        let relayed = Message::Binary(encode_onwire_msg(
+            &OnWireMessage::DSKeyPackageResponse {
+                queried_user_key_packages: vec![],
+                chunk_index: 0,
+                total_chunks: 1,
+            },
+            false,
+            None,
+        ));
+        let (done_sender, done_receiver) = std::sync::mpsc::channel::<()>();
+        let server = thread::spawn(move || {
+            let mut ws = tungstenite::accept(listener.accept().unwrap().0).unwrap();
+            ws.read_message().unwrap();
+            ws.write_message(relayed).unwrap();
+            // Keep the connection open, without a result, until the client is done
+            done_receiver.recv().unwrap();
+        });
+
+        let mut ws = ReconnectingWs::with_backoff(url, 3, Duration::from_millis(10));
+        ws.set_read_timeout(Some(Duration::from_millis(200)));
+        send_onwire_msg(
+            OnWireMessage::UserSync {
+                user_name: "alice".to_string(),
+                new_key_packages: vec![],
+                known_epochs: vec![],
+                acked_until: None,
+            },
+            &mut ws,
+        );
+        let msgs = read_ws_messages(&mut ws);
+        done_sender.send(()).unwrap();
+        server.join().unwrap();
+        assert_eq!(msgs.len(), 1);
+        assert!(matches!(
+            msgs[0],
+            OnWireMessage::DSKeyPackageResponse { .. }
+        ));
+    }
+
+    #[test]
+    /// When the DS drops the connection during a sync, the client should
+    /// reconnect, sync again, and skip what it already received, even though
+    /// it is sent again with a new timestamp
+    fn reconnects_and_completes_sync() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("ws://{}", listener.local_addr().unwrap())).unwrap();
+        let sync = OnWireMessage::UserSync {
+            user_name: "alice".to_string(),
+            new_key_packages: vec![],
+            known_epochs: vec![],
+            acked_until: None,
+        };
+        let relayed = OnWireMessage::DSKeyPackageResponse {
+            queried_user_key_packages: vec![],
+            chunk_index: 0,
+            total_chunks: 1,
+        };
+        let result = OnWireMessage::DSResult {
+            request_valid: true,
+            explanation: None,
+            identifier: None,
+            preceding_and_sent_ordered_msgs: vec![],
+            remaining_key_packages: Some(0),
+            process_time_used: Duration::ZERO,
+        };
+
+        let server = thread::spawn(move || {
+            // The first connection drops before the sync result is sent
+            let mut first = tungstenite::accept(listener.accept().unwrap().0).unwrap();
+            first.read_message().unwrap();
+            let first_relayed = encode_onwire_msg(&relayed, false, None);
+            first
+                .write_message(Message::Binary(first_relayed.clone()))
+                .unwrap();
+            drop(first);
+
+            let mut second = tungstenite::accept(listener.accept().unwrap().0).unwrap();
+            let resumed = second.read_message().unwrap();
+            thread::sleep(Duration::from_millis(1));
+            let second_relayed = encode_onwire_msg(&relayed, false, None);
+            assert_ne!(first_relayed, second_relayed);
+            second
+                .write_message(Message::Binary(second_relayed))
+                .unwrap();
+            second
+                .write_message(Message::Binary(encode_onwire_msg(&result, false, None)))
+                .unwrap();
+            let next = second.read_message().unwrap();
+            (resumed, next)
+        });
+
+        let mut ws = ReconnectingWs::with_backoff(url, 3, Duration::from_millis(10));
+        ws.set_resume_msg(Message::Binary(encode_onwire_msg(&sync, false, None)));
+        send_onwire_msg(sync.clone(), &mut ws);
+        let msgs = read_ws_messages(&mut ws);
+        assert_eq!(msgs.len(), 2);
+        assert!(matches!(
+            msgs[0],
+            OnWireMessage::DSKeyPackageResponse { .. }
+        ));
+        assert!(matches!(
+            msgs[1],
+            OnWireMessage::DSResult {
+                request_valid: true,
+                ..
+            }
+        ));
+        // The new connection is usable afterwards
+        send_onwire_msg(sync, &mut ws);
+
+        let (resumed, next) = server.join().unwrap();
+        for msg in [resumed, next] {
+            let decoded = OnWireMessageWithMetaData::decode(&msg.into_data()).unwrap();
+            assert!(matches!(decoded.onwire_msg, OnWireMessage::UserSync { .. }));
+        }
+    }
+
+    #[test]
+    /// An explicit config path should be used over the environment and the
+    /// default, with the relative paths in it resolved against its directory
+    fn explicit_config_path_is_honored() {
+        let config_dir = std::env::temp_dir().join(format!(
+            "mlsgov-config-{}",
+            Alphanumeric.sample_string(&mut rand::thread_rng(), 16)
+        ));
+        let cli_path = config_dir.join("CliClientConfig.yaml");
+        let env_path = Some("/from/env/CliClientConfig.yaml".to_string());
+        assert_eq!(config_path(Some(&cli_path), env_path.clone()), cli_path);
+        assert_eq!(
+            config_path(None, env_path),
+            PathBuf::from("/from/env/CliClientConfig.yaml")
+        );
+        assert_eq!(config_path(None, None), PathBuf::from(DEFAULT_CONFIG_PATH));
+
+        std::fs::create_dir_all(&config_dir).unwrap();
+        confy::store_path(
+            &cli_path,
+            ClientConfig {
+                ds_url_str: "ws://ds.example:8000".to_string(),
+                data_path: "client_data.json".to_string(),
+                keystore_path: "/absolute/keystore.json".to_string(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let loaded = ClientConfig::load(&cli_path).unwrap();
+        assert_eq!(loaded.ds_url_str, "ws://ds.example:8000");
+        assert_eq!(
+            PathBuf::from(loaded.data_path),
+            config_dir.join("client_data.json")
+        );
+        assert_eq!(loaded.keystore_path, "/absolute/keystore.json");
+        let _ = std::fs::remove_dir_all(config_dir);
+    }
+
+    fn valid_test_config() -> ClientConfig {
+        ClientConfig {
+            ds_url_str: "ws://127.0.0.1:8000".to_string(),
+            as_url_str: "ws://127.0.0.1:9000".to_string(),
+            new_key_packages_per_sync: 10,
+            key_package_lifetime_secs: 60 * 60,
+            data_path: std::env::temp_dir()
+                .join("client_data.json")
+                .to_string_lossy()
+                .to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    /// A server URL that does not parse should be reported with its field
+    fn config_with_bad_url_is_rejected() {
+        assert_eq!(valid_test_config().validate(), Ok(()));
+        let config = ClientConfig {
+            as_url_str: "not a url".to_string(),
+            ..valid_test_config()
+        };
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidUrl {
+                field: "as_url_str",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    /// Syncs must send at least one new key package
+    fn config_with_zero_key_packages_is_rejected() {
+        let config = ClientConfig {
+            new_key_packages_per_sync: 0,
+            ..valid_test_config()
+        };
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::NotPositive {
+                field: "new_key_packages_per_sync"
+            })
+        );
+        assert!(config
+            .validate()
+            .unwrap_err()
+            .to_string()
+            .contains("new_key_packages_per_sync"));
+    }
+
+    #[test]
+    /// Retryable failures should be retried until `--max-retries` retries
+    /// were made, then given up on with the last error
+    fn retries_stop_once_budget_exhausted() {
+        let failure = |n: u32| Some(format!("failure {}", n));
+        let decisions: Vec<RetryDecision> = (1..=3)
+            .map(|attempts| retry_decision(attempts, true, Some(2), failure(attempts)))
+            .collect();
+        assert_eq!(
+            decisions,
+            vec![
+                RetryDecision::Retry {
+                    retries_left: Some(1)
+                },
+                RetryDecision::Retry {
+                    retries_left: Some(0)
+                },
+                RetryDecision::GiveUp {
+                    attempts: 3,
+                    last_error: "failure 3".to_string()
+                },
+            ]
+        );
+
+        // Done on success, without auto retry, or with no retry allowed
+        assert_eq!(retry_decision(2, true, Some(2), None), RetryDecision::Done);
+        assert_eq!(
+            retry_decision(1, false, Some(2), failure(1)),
+            RetryDecision::Done
+        );
+        assert_eq!(
+            retry_decision(1, true, Some(0), failure(1)),
+            RetryDecision::GiveUp {
+                attempts: 1,
+                last_error: "failure 1".to_string()
+            }
+        );
+        assert_eq!(
+            retry_decision(100, true, None, failure(100)),
+            RetryDecision::Retry { retries_left: None }
+        );
+    }
 }
@@ -1,27 +1,263 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::io;
 use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
 
+use corelib::messages::{AuthToken, OnWireMessageWithMetaData};
 use log::*;
 use tungstenite::{
     connect,
+    error::ProtocolError,
     protocol::{frame::coding::CloseCode, CloseFrame},
     stream::MaybeTlsStream,
-    WebSocket,
+    Message, WebSocket,
 };
 use url::Url;
 
-pub(crate) fn get_websocket(url: Url) -> WebSocket<MaybeTlsStream<TcpStream>> {
-    let (websocket, initial_response) = connect(url).expect("Can't connect");
+/// How many times a dropped connection is re-established before giving up
+pub(crate) const MAX_RECONNECT_ATTEMPTS: u32 = 5;
+/// The delay before the first reconnection attempt, doubled at every attempt
+pub(crate) const INITIAL_RECONNECT_BACKOFF: Duration = Duration::from_millis(250);
+
+pub(crate) fn get_websocket(
+    url: Url,
+    read_timeout: Option<Duration>,
+) -> WebSocket<MaybeTlsStream<TcpStream>> {
+    let (mut websocket, initial_response) = connect(url).expect("Can't connect");
     for (ref header, _value) in initial_response.headers() {
         trace!("Websocket Header: * {}", header);
     }
+    set_read_timeout(&mut websocket, read_timeout);
     websocket
 }
 
-pub(crate) fn finish_websocket(websocket: &mut WebSocket<MaybeTlsStream<TcpStream>>) {
-    websocket
-        .close(Some(CloseFrame {
-            code: CloseCode::Normal,
-            reason: std::borrow::Cow::Borrowed("Client finishes"),
-        }))
-        .unwrap();
+/// Makes reads on `websocket` fail once no data arrived for `read_timeout`,
+/// rather than block forever if `None`.
+///
+/// For `wss://` URLs, the timeout is set on the TCP stream under the TLS one.
+fn set_read_timeout(
+    websocket: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+    read_timeout: Option<Duration>,
+) {
+    let result = match websocket.get_mut() {
+        MaybeTlsStream::Plain(stream) => stream.set_read_timeout(read_timeout),
+        #[cfg(feature = "native-tls")]
+        MaybeTlsStream::NativeTls(stream) => stream.get_ref().set_read_timeout(read_timeout),
+        #[cfg(feature = "rustls-tls")]
+        MaybeTlsStream::Rustls(stream) => stream.sock.set_read_timeout(read_timeout),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "unknown websocket stream",
+        )),
+    };
+    if let Err(e) = result {
+        warn!("Cannot set the websocket read timeout: {:?}", e);
+    }
+}
+
+pub(crate) fn finish_websocket(websocket: &mut ReconnectingWs) {
+    if let Err(e) = websocket.websocket.close(Some(CloseFrame {
+        code: CloseCode::Normal,
+        reason: std::borrow::Cow::Borrowed("Client finishes"),
+    })) {
+        warn!("Cannot close the websocket to {}: {:?}", websocket.url, e);
+    }
+}
+
+/// A websocket that connects again when the server drops the connection,
+/// e.g., because it restarted.
+///
+/// A message that could not be sent is sent again on the new connection.
+/// When the connection drops while reading, the `resume_msg` is sent on the
+/// new connection, if any, so that the server sends again what may have been
+/// lost. It should thus be idempotent, like a sync without key packages.
+/// As the server may then send messages received before the connection
+/// dropped, messages received again after a reconnection are skipped. They
+/// are told apart by their content, as the server sends them again with a
+/// new timestamp.
+pub(crate) struct ReconnectingWs {
+    url: Url,
+    websocket: WebSocket<MaybeTlsStream<TcpStream>>,
+    max_attempts: u32,
+    initial_backoff: Duration,
+    resume_msg: Option<Message>,
+    /// Whether messages sent on this websocket are compressed
+    compress: bool,
+    /// Hashes of the messages received so far, without their metadata
+    received_msg_hashes: HashSet<u64>,
+    reconnected: bool,
+    /// How long a read waits for data, forever if not set
+    read_timeout: Option<Duration>,
+    /// The token the AS issued, sent along with every message if set
+    token: Option<AuthToken>,
+}
+
+impl ReconnectingWs {
+    pub(crate) fn new(url: Url) -> Self {
+        Self::with_backoff(url, MAX_RECONNECT_ATTEMPTS, INITIAL_RECONNECT_BACKOFF)
+    }
+
+    pub(crate) fn with_backoff(url: Url, max_attempts: u32, initial_backoff: Duration) -> Self {
+        ReconnectingWs {
+            websocket: get_websocket(url.clone(), None),
+            url,
+            max_attempts,
+            initial_backoff,
+            resume_msg: None,
+            compress: false,
+            received_msg_hashes: HashSet::new(),
+            reconnected: false,
+            read_timeout: None,
+            token: None,
+        }
+    }
+
+    /// Sets how long a read waits for data before failing, on this
+    /// connection and those re-established later
+    pub(crate) fn set_read_timeout(&mut self, read_timeout: Option<Duration>) {
+        self.read_timeout = read_timeout;
+        set_read_timeout(&mut self.websocket, read_timeout);
+    }
+
+    /// Sets the message to send after reconnecting while reading
+    pub(crate) fn set_resume_msg(&mut self, resume_msg: Message) {
+        self.resume_msg = Some(resume_msg);
+    }
+
+    pub(crate) fn set_compress(&mut self, compress: bool) {
+        self.compress = compress;
+    }
+
+    pub(crate) fn compress(&self) -> bool {
+        self.compress
+    }
+
+    pub(crate) fn set_token(&mut self, token: Option<AuthToken>) {
+        self.token = token;
+    }
+
+    pub(crate) fn token(&self) -> Option<AuthToken> {
+        self.token.clone()
+    }
+
+    pub(crate) fn write_message(&mut self, msg: Message) -> tungstenite::Result<()> {
+        match self.websocket.write_message(msg.clone()) {
+            Err(e) if is_connection_lost(&e) => {
+                warn!("Connection to {} lost while sending: {:?}", self.url, e);
+                if self.reconnect() {
+                    self.websocket.write_message(msg)
+                } else {
+                    Err(e)
+                }
+            }
+            result => result,
+        }
+    }
+
+    pub(crate) fn write_pending(&mut self) -> tungstenite::Result<()> {
+        self.websocket.write_pending()
+    }
+
+    pub(crate) fn read_message(&mut self) -> tungstenite::Result<Message> {
+        loop {
+            let result = match self.websocket.read_message() {
+                // A server that goes down may still close the connection properly
+                Ok(Message::Close(_)) => Err(tungstenite::Error::ConnectionClosed),
+                result => result,
+            };
+            match result {
+                // Not a lost connection, just a silent server
+                Err(e) if is_read_timeout(&e) => return Err(e),
+                Ok(Message::Binary(encoded)) => {
+                    if !self.received_msg_hashes.insert(content_hash(&encoded)) && self.reconnected
+                    {
+                        debug!("Skipping a message received before reconnecting");
+                        continue;
+                    }
+                    return Ok(Message::Binary(encoded));
+                }
+                Err(e) if is_connection_lost(&e) => {
+                    warn!("Connection to {} lost while reading: {:?}", self.url, e);
+                    if !self.reconnect() {
+                        return Err(e);
+                    }
+                    match self.resume_msg.clone() {
+                        Some(resume_msg) => self.websocket.write_message(resume_msg)?,
+                        // Nothing would answer the read on the new connection
+                        None => return Err(e),
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Tries to connect again, with an exponential backoff. Returns whether
+    /// it succeeded
+    fn reconnect(&mut self) -> bool {
+        for attempt in 0..self.max_attempts {
+            thread::sleep(self.initial_backoff * 2_u32.pow(attempt));
+            match connect(self.url.clone()) {
+                Ok((mut websocket, _)) => {
+                    info!("Reconnected to {}", self.url);
+                    set_read_timeout(&mut websocket, self.read_timeout);
+                    self.websocket = websocket;
+                    self.reconnected = true;
+                    return true;
+                }
+                Err(e) => warn!(
+                    "Reconnection attempt {} to {} failed: {:?}",
+                    attempt + 1,
+                    self.url,
+                    e
+                ),
+            }
+        }
+        error!(
+            "Giving up on reconnecting to {} after {} attempts",
+            self.url, self.max_attempts
+        );
+        false
+    }
+}
+
+/// Hashes the message encoded in `encoded`, leaving out its metadata, e.g.,
+/// the timestamp it was sent at. Hashes the whole frame if it cannot be
+/// decoded
+fn content_hash(encoded: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    match OnWireMessageWithMetaData::decode(encoded)
+        .ok()
+        .and_then(|msg_w_meta| serde_json::to_vec(&msg_w_meta.onwire_msg).ok())
+    {
+        Some(content) => content.hash(&mut hasher),
+        None => encoded.hash(&mut hasher),
+    }
+    hasher.finish()
+}
+
+/// Whether the error means no data arrived within the read timeout
+pub(crate) fn is_read_timeout(e: &tungstenite::Error) -> bool {
+    match e {
+        tungstenite::Error::Io(io_err) => matches!(
+            io_err.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ),
+        _ => false,
+    }
+}
+
+/// Whether the error means the connection is gone, rather than a bad message
+fn is_connection_lost(e: &tungstenite::Error) -> bool {
+    matches!(
+        e,
+        tungstenite::Error::ConnectionClosed
+            | tungstenite::Error::AlreadyClosed
+            | tungstenite::Error::Io(_)
+            | tungstenite::Error::Protocol(ProtocolError::ResetWithoutClosingHandshake)
+            | tungstenite::Error::Protocol(ProtocolError::SendAfterClosing)
+    )
 }
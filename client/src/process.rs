@@ -1,17 +1,22 @@
 use core::panic;
 use std::ops::DerefMut;
+use std::time::Duration;
 
-use openmls::prelude::KeyPackage;
 use uuid::Uuid;
 
 use corelib::client_api::actions::ActionMetadata;
 use corelib::client_api::actions::ActionMsg;
+use corelib::client_api::actions::ClosePollAction;
 use corelib::client_api::actions::CustomAction;
 use corelib::client_api::actions::DefRoleAction;
+use corelib::client_api::actions::FlagMessageAction;
 use corelib::client_api::actions::ReportAction;
+use corelib::client_api::actions::SetFlagThresholdAction;
+use corelib::client_api::actions::SetMaxGroupSizeAction;
 use corelib::client_api::actions::SetTopicGroupAction;
 use corelib::client_api::actions::TakedownTextMsgAction;
-use corelib::client_api::actions::{RenameGroupAction, VoteAction};
+use corelib::client_api::actions::VoteAction;
+use corelib::client_api::actions::{BanAction, UnbanAction};
 use corelib::client_api::client_crypto_impl::CryptoBackend;
 use corelib::client_api::*;
 use corelib::messages::*;
@@ -24,47 +29,65 @@ pub(crate) fn group_onwire_msgs_for_ds(
     command: &ClientInputCommand,
     backend: &mut CryptoBackend,
     client_data: &mut ClientRef,
-    external_key_packages_opt: Option<Vec<KeyPackage>>,
 ) -> Vec<OnWireMessage> {
     let user_name = client_data.get_user_id();
     let result = match command {
         ClientInputCommand::Register { .. }
         | ClientInputCommand::Sync
+        | ClientInputCommand::WhoAmI
+        | ClientInputCommand::RevokeCredential { .. }
+        | ClientInputCommand::EndorseDevice { .. }
         | ClientInputCommand::ShowGroupState { .. }
+        | ClientInputCommand::ExportGov { .. }
+        | ClientInputCommand::ExportHistory { .. }
+        | ClientInputCommand::VerifyHistory { .. }
+        | ClientInputCommand::DumpWire { .. }
+        | ClientInputCommand::Mute { .. }
+        | ClientInputCommand::Unmute { .. }
+        | ClientInputCommand::ImportGov { .. }
+        | ClientInputCommand::GroupInfo { .. }
+        | ClientInputCommand::Verify { .. }
+        | ClientInputCommand::ReplayPolicies { .. }
+        | ClientInputCommand::ListReports { .. }
+        | ClientInputCommand::Flagged { .. }
+        | ClientInputCommand::Proposals { .. }
+        | ClientInputCommand::ShowPolicyLog { .. }
+        | ClientInputCommand::Dm { .. }
+        | ClientInputCommand::Invite { .. }
         | ClientInputCommand::Read { .. }
         | ClientInputCommand::Create { .. } => {
             panic!("Not a group command")
         }
 
-        ClientInputCommand::Invite {
-            community_id,
-            group_id,
-            invitee_names: _,
-        } => pre_add_invite_msg(
-            &user_name,
-            &CommGroupId::new(community_id, group_id),
-            backend,
-            client_data.deref_mut(),
-            external_key_packages_opt.expect("Should have looked up key package for invitee"),
-        ),
         ClientInputCommand::Add {
             community_id,
             group_id,
             invitee_names,
-        } => add_msg(
-            &CommGroupId::new(community_id, group_id),
-            invitee_names,
-            client_data.deref_mut(),
-            backend,
-        ),
+        } => {
+            let (msgs, dropped_invitees) = add_msg(
+                &CommGroupId::new(community_id, group_id),
+                invitee_names,
+                client_data.deref_mut(),
+                backend,
+            );
+            warn_left_out_invitees(
+                &dropped_invitees,
+                "they have no pre-approved key package, or it expired. They may need to sync to publish new ones",
+            );
+            msgs
+        }
         ClientInputCommand::Send {
             community_id,
             group_id,
             message,
-        } => send_text_msg_mls(
+            expires_after_secs,
+            in_reply_to,
+        } => send_reply_text_msg_mls(
             &user_name,
             &CommGroupId::new(community_id, group_id),
             message.to_string(),
+            in_reply_to.to_owned(),
+            expires_after_secs.map(Duration::from_secs),
             backend,
             client_data.deref_mut(),
         ),
@@ -72,43 +95,66 @@ pub(crate) fn group_onwire_msgs_for_ds(
             community_id,
             group_id,
             new_group_id,
+        } => rename_group_msg(
+            &CommGroupId::new(community_id, group_id),
+            new_group_id,
+            backend,
+            client_data.deref_mut(),
+        ),
+
+        ClientInputCommand::ChangeGroupTopic {
+            community_id,
+            group_id,
+            new_group_topic,
         } => {
-            let action = ActionMsg::RenameGroup(RenameGroupAction {
-                new_name: new_group_id.to_owned(),
+            let comm_grp = CommGroupId::new(community_id, group_id);
+            let action = ActionMsg::SetTopicGroup(SetTopicGroupAction {
+                new_topic: new_group_topic.to_owned(),
+                lamport_counter: client_data.get_group_topic_version(&comm_grp).0 + 1,
                 metadata: ActionMetadata::new(
                     user_name.to_owned(),
                     Uuid::new_v4().to_string(),
-                    CommGroupId::new(community_id, group_id),
+                    comm_grp.clone(),
+                    client_data.now(),
                 ),
             });
+            check_action_msg_and_get_mls(&comm_grp, action, backend, client_data.deref_mut())
+        }
 
-            check_action_msg_and_get_mls(
-                &CommGroupId::new(community_id, group_id),
-                action,
-                backend,
-                client_data.deref_mut(),
-            )
+        ClientInputCommand::SetMaxGroupSize {
+            community_id,
+            group_id,
+            max_group_size,
+        } => {
+            let comm_grp = CommGroupId::new(community_id, group_id);
+            let action = ActionMsg::SetMaxGroupSize(SetMaxGroupSizeAction {
+                max_group_size: *max_group_size,
+                metadata: ActionMetadata::new(
+                    user_name.to_owned(),
+                    Uuid::new_v4().to_string(),
+                    comm_grp.clone(),
+                    client_data.now(),
+                ),
+            });
+            check_action_msg_and_get_mls(&comm_grp, action, backend, client_data.deref_mut())
         }
 
-        ClientInputCommand::ChangeGroupTopic {
+        ClientInputCommand::SetFlagThreshold {
             community_id,
             group_id,
-            new_group_topic,
+            threshold,
         } => {
-            let action = ActionMsg::SetTopicGroup(SetTopicGroupAction {
-                new_topic: new_group_topic.to_owned(),
+            let comm_grp = CommGroupId::new(community_id, group_id);
+            let action = ActionMsg::SetFlagThreshold(SetFlagThresholdAction {
+                threshold: *threshold,
                 metadata: ActionMetadata::new(
                     user_name.to_owned(),
                     Uuid::new_v4().to_string(),
-                    CommGroupId::new(community_id, group_id),
+                    comm_grp.clone(),
+                    client_data.now(),
                 ),
             });
-            check_action_msg_and_get_mls(
-                &CommGroupId::new(community_id, group_id),
-                action,
-                backend,
-                client_data.deref_mut(),
-            )
+            check_action_msg_and_get_mls(&comm_grp, action, backend, client_data.deref_mut())
         }
 
         ClientInputCommand::Leave {
@@ -120,6 +166,25 @@ pub(crate) fn group_onwire_msgs_for_ds(
             client_data.deref_mut(),
         ),
 
+        ClientInputCommand::RejoinGroup {
+            community_id,
+            group_id,
+        } => rejoin_group_msg(
+            &CommGroupId::new(community_id, group_id),
+            client_data.deref_mut(),
+        ),
+
+        ClientInputCommand::Revert {
+            community_id,
+            group_id,
+            target_epoch,
+        } => admin_revert_msg(
+            &CommGroupId::new(community_id, group_id),
+            *target_epoch,
+            backend,
+            client_data.deref_mut(),
+        ),
+
         ClientInputCommand::Accept {
             group_id,
             community_id,
@@ -128,9 +193,20 @@ pub(crate) fn group_onwire_msgs_for_ds(
             backend,
             client_data,
         ),
+        ClientInputCommand::RequestGroupState {
+            community_id,
+            group_id,
+            member,
+        } => request_gov_state_msg(
+            &CommGroupId::new(community_id, group_id),
+            member,
+            backend,
+            client_data.deref_mut(),
+        ),
         ClientInputCommand::Decline {
             community_id,
             group_id,
+            ..
         } => pre_decline_msg(
             &CommGroupId::new(community_id, group_id),
             backend,
@@ -146,6 +222,50 @@ pub(crate) fn group_onwire_msgs_for_ds(
             backend,
             client_data.deref_mut(),
         ),
+        ClientInputCommand::Ban {
+            community_id,
+            group_id,
+            member_name,
+        } => {
+            let action = ActionMsg::Ban(BanAction {
+                target_user_id: member_name.to_owned(),
+                metadata: ActionMetadata::new(
+                    user_name.to_owned(),
+                    Uuid::new_v4().to_string(),
+                    CommGroupId::new(community_id, group_id),
+                    client_data.now(),
+                ),
+            });
+
+            check_action_msg_and_get_mls(
+                &CommGroupId::new(community_id, group_id),
+                action,
+                backend,
+                client_data.deref_mut(),
+            )
+        }
+        ClientInputCommand::Unban {
+            community_id,
+            group_id,
+            member_name,
+        } => {
+            let action = ActionMsg::Unban(UnbanAction {
+                target_user_id: member_name.to_owned(),
+                metadata: ActionMetadata::new(
+                    user_name.to_owned(),
+                    Uuid::new_v4().to_string(),
+                    CommGroupId::new(community_id, group_id),
+                    client_data.now(),
+                ),
+            });
+
+            check_action_msg_and_get_mls(
+                &CommGroupId::new(community_id, group_id),
+                action,
+                backend,
+                client_data.deref_mut(),
+            )
+        }
         ClientInputCommand::SetRole {
             community_id,
             group_id,
@@ -158,7 +278,51 @@ pub(crate) fn group_onwire_msgs_for_ds(
             backend,
             client_data.deref_mut(),
         ),
+        ClientInputCommand::AddRole {
+            community_id,
+            group_id,
+            member_name,
+            role,
+        } => add_role_msg(
+            &CommGroupId::new(community_id, group_id),
+            member_name,
+            role.to_string(),
+            backend,
+            client_data.deref_mut(),
+        ),
+        ClientInputCommand::RemoveRole {
+            community_id,
+            group_id,
+            member_name,
+            role,
+        } => remove_role_msg(
+            &CommGroupId::new(community_id, group_id),
+            member_name,
+            role.to_string(),
+            backend,
+            client_data.deref_mut(),
+        ),
+        ClientInputCommand::SetRoles {
+            community_id,
+            group_id,
+            assignments,
+        } => set_roles_bulk_msg(
+            &CommGroupId::new(community_id, group_id),
+            assignments.clone(),
+            backend,
+            client_data.deref_mut(),
+        ),
 
+        ClientInputCommand::RemoveMembers {
+            community_id,
+            group_id,
+            member_names,
+        } => remove_members_msg(
+            &CommGroupId::new(community_id, group_id),
+            member_names.clone(),
+            backend,
+            client_data.deref_mut(),
+        ),
         ClientInputCommand::Remove {
             community_id,
             group_id,
@@ -194,6 +358,31 @@ pub(crate) fn group_onwire_msgs_for_ds(
                     user_name.to_owned(),
                     Uuid::new_v4().to_string(),
                     CommGroupId::new(community_id, group_id),
+                    client_data.now(),
+                ),
+            });
+
+            check_action_msg_and_get_mls(
+                &CommGroupId::new(community_id, group_id),
+                action,
+                backend,
+                client_data.deref_mut(),
+            )
+        }
+        ClientInputCommand::ClosePoll {
+            community_id,
+            group_id,
+            proposed_action_id,
+            proposed_action_type,
+        } => {
+            let action = ActionMsg::ClosePoll(ClosePollAction {
+                proposed_action_id: proposed_action_id.to_owned(),
+                proposed_action_type: proposed_action_type.to_owned(),
+                metadata: ActionMetadata::new(
+                    user_name.to_owned(),
+                    Uuid::new_v4().to_string(),
+                    CommGroupId::new(community_id, group_id),
+                    client_data.now(),
                 ),
             });
 
@@ -209,14 +398,17 @@ pub(crate) fn group_onwire_msgs_for_ds(
             group_id,
             role_name,
             action_types,
+            inherits_from,
         } => {
             let action = ActionMsg::DefRole(DefRoleAction {
                 role_name: role_name.to_owned(),
                 action_types: action_types.to_owned(),
+                inherits_from: inherits_from.to_owned(),
                 metadata: ActionMetadata::new(
                     user_name.to_owned(),
                     Uuid::new_v4().to_string(),
                     CommGroupId::new(community_id, group_id),
+                    client_data.now(),
                 ),
             });
 
@@ -240,6 +432,7 @@ pub(crate) fn group_onwire_msgs_for_ds(
                     user_name.to_owned(),
                     Uuid::new_v4().to_string(),
                     CommGroupId::new(community_id, group_id),
+                    client_data.now(),
                 ),
             });
 
@@ -261,6 +454,29 @@ pub(crate) fn group_onwire_msgs_for_ds(
                     user_name.to_owned(),
                     Uuid::new_v4().to_string(),
                     CommGroupId::new(community_id, group_id),
+                    client_data.now(),
+                ),
+            });
+
+            check_action_msg_and_get_mls(
+                &CommGroupId::new(community_id, group_id),
+                action,
+                backend,
+                client_data.deref_mut(),
+            )
+        }
+        ClientInputCommand::Flag {
+            community_id,
+            group_id,
+            message_id,
+        } => {
+            let action = ActionMsg::FlagMessage(FlagMessageAction {
+                message_id: message_id.to_owned(),
+                metadata: ActionMetadata::new(
+                    user_name.to_owned(),
+                    Uuid::new_v4().to_string(),
+                    CommGroupId::new(community_id, group_id),
+                    client_data.now(),
                 ),
             });
 
@@ -284,6 +500,7 @@ pub(crate) fn group_onwire_msgs_for_ds(
                     user_name.to_owned(),
                     Uuid::new_v4().to_string(),
                     CommGroupId::new(community_id, group_id),
+                    client_data.now(),
                 ),
             });
 
@@ -319,3 +536,11 @@ pub(crate) fn group_onwire_msgs_for_ds(
     };
     result
 }
+
+/// Tells the user which invitees were left out, and `why`, e.g., because
+/// they have no valid key package
+pub(crate) fn warn_left_out_invitees(left_out_invitees: &[String], why: &str) {
+    if !left_out_invitees.is_empty() {
+        eprintln!("Not inviting {}: {}", left_out_invitees.join(", "), why);
+    }
+}
@@ -5,7 +5,7 @@ use corelib::messages::OnWireMessage;
 use corelib::servers_api::as_struct::{AuthServiceState, SharedAuthServiceState};
 use corelib::servers_api::ds_structs::{DeliveryServiceState, SharedDeliverServiceState};
 use corelib::servers_api::{handle_onwire_msg_as_local, handle_onwire_msg_ds_local};
-use corelib::test_helpers::{assert_all_feedback_ok, TestClientBundle};
+use corelib::test_helpers::{assert_all_feedback_ok, onwire_msgs_bandwidth, TestClientBundle};
 use openmls::key_packages::KeyPackage;
 use std::ops::DerefMut;
 use std::sync::Arc;
@@ -106,6 +106,11 @@ pub async fn as_process_all_msgs(
     result
 }
 
+/// The bandwidth of the messages sent by, or to, all clients
+pub fn all_onwire_msgs_bandwidth(msgs_list: &[Vec<OnWireMessage>]) -> usize {
+    msgs_list.iter().map(onwire_msgs_bandwidth).sum()
+}
+
 pub fn clients_process_all_msgs(
     msgss: Vec<Vec<OnWireMessage>>,
     client_bundles: &mut Vec<TestClientBundle>,
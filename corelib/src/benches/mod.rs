@@ -1,4 +1,5 @@
 use std::fs::File;
+use std::time::Duration;
 
 use futures::lock::Mutex;
 use log::debug;
@@ -22,6 +23,15 @@ pub(crate) struct Cost {
     bandwidth_in_bytes: Bandwidth,
 }
 
+impl Cost {
+    pub(crate) fn new(duration: Duration, bandwidth_in_bytes: Bandwidth) -> Self {
+        Cost {
+            duration_in_nanos: duration.as_nanos(),
+            bandwidth_in_bytes,
+        }
+    }
+}
+
 #[cfg(test)]
 mod test1 {
     use std::ops::DerefMut;
@@ -30,6 +40,7 @@ mod test1 {
 
     use futures::executor::block_on;
     use futures::lock::Mutex;
+    use log::debug;
     use rand::distributions::{Alphanumeric, DistString};
     use serde::{Deserialize, Serialize};
     use uuid::Uuid;
@@ -46,6 +57,8 @@ mod test1 {
 
     use crate::{batch_helper::*, Cost};
 
+    use self::Test1TimerConfig::*;
+
     #[derive(Debug, PartialEq, Eq, PartialOrd, Clone, Copy)]
     pub(crate) enum Test1TimerConfig {
         TimeClientsInit,
@@ -68,6 +81,9 @@ mod test1 {
 
     #[derive(Debug, Serialize, Deserialize, Default)]
     pub(crate) struct BenchmarkOutput {
+        /// The cost of the requested section, if not `All`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        section: Option<Cost>,
         admin_pre_invite_last_member: Cost,
         admin_add_last_member: Cost,
         admin_group_state_update_after_last_member: Cost,
@@ -75,7 +91,20 @@ mod test1 {
         admin_rename_group: Cost,
     }
 
-    pub(crate) fn benchmark(_section: Test1TimerConfig, group_size: usize) -> BenchmarkOutput {
+    impl BenchmarkOutput {
+        /// The output of a benchmark that stopped after `section`
+        fn for_section(cost: Cost) -> Self {
+            BenchmarkOutput {
+                section: Some(cost),
+                ..Default::default()
+            }
+        }
+    }
+
+    /// Runs the benchmark up to `section`, and returns the cost of only that
+    /// section, or runs it all and returns the cost of every measured step
+    /// if `section` is `All`
+    pub(crate) fn benchmark(section: Test1TimerConfig, group_size: usize) -> BenchmarkOutput {
         let mut output = BenchmarkOutput::default();
 
         // Initialize AS and DS.
@@ -87,94 +116,95 @@ mod test1 {
         let admin_index = 0usize;
         let enable_assert = false;
 
-        // let section_start_timestamp = Instant::now();
+        let section_start_timestamp = Instant::now();
         let mut client_bundles = create_test_bundles(group_size);
-
-        // let client_init_time = section_start_timestamp.elapsed();
-        // debug!("The client init time is {:?}", &client_init_time);
-        // if section == TimeClientsInit {
-        //     return vec![client_init_time];
-        // }
-        // times.push(client_init_time);
+        let client_init_time = section_start_timestamp.elapsed();
+        debug!("The client init time is {:?}", &client_init_time);
+        if section == TimeClientsInit {
+            return BenchmarkOutput::for_section(Cost::new(client_init_time, 0));
+        }
 
         // Register on AS
         // TimeClientAsRegister
-        // let section_start_timestamp = Instant::now();
+        let section_start_timestamp = Instant::now();
         let as_registers = register_all_as_msgs(&client_bundles);
+        let mut bandwidth = all_onwire_msgs_bandwidth(&as_registers);
 
-        // let server_timestamp = Instant::now();
+        let server_timestamp = Instant::now();
         let as_responses = block_on(as_process_all_msgs(as_registers, &as_state));
-        // let server_time_used = server_timestamp.elapsed();
+        let server_time_used = server_timestamp.elapsed();
+        bandwidth += all_onwire_msgs_bandwidth(&as_responses);
 
         let local_msgs = clients_process_all_msgs(as_responses, &mut client_bundles);
-
-        // let client_as_reg_time = section_start_timestamp.elapsed() - server_time_used;
-        // debug!(
-        //     "Time for client to register on AS is {:?}",
-        //     &client_as_reg_time
-        // );
-        // if section == TimeClientsAsRegister {
-        //     return vec![client_as_reg_time];
-        // }
-        // times.push(client_as_reg_time);
+        let client_as_reg_time = section_start_timestamp.elapsed() - server_time_used;
+        debug!(
+            "Time for client to register on AS is {:?}",
+            &client_as_reg_time
+        );
+        if section == TimeClientsAsRegister {
+            return BenchmarkOutput::for_section(Cost::new(client_as_reg_time, bandwidth));
+        }
         maybe_assert_all(local_msgs, enable_assert);
 
         // Register on DS
         // TimeClientDsRegister
-        // let section_start_timestamp = Instant::now();
+        let section_start_timestamp = Instant::now();
         let ds_registers = register_all_ds_msgs(&mut client_bundles);
+        let mut bandwidth = all_onwire_msgs_bandwidth(&ds_registers);
 
-        // let server_timestamp = Instant::now();
+        let server_timestamp = Instant::now();
         let ds_responses = block_on(ds_process_all_msgs(ds_registers, &ds_state));
-        // let server_time_used = server_timestamp.elapsed();
+        let server_time_used = server_timestamp.elapsed();
+        bandwidth += all_onwire_msgs_bandwidth(&ds_responses);
 
         let local_msgs = clients_process_all_msgs(ds_responses, &mut client_bundles);
-        // let client_ds_reg_time = section_start_timestamp.elapsed() - server_time_used;
-        // debug!(
-        //     "Time for client to register on DS is {:?}",
-        //     &client_ds_reg_time
-        // );
-        // if section == TimeClientsDsRegister {
-        //     return vec![client_ds_reg_time];
-        // }
-        // times.push(client_ds_reg_time);
+        let client_ds_reg_time = section_start_timestamp.elapsed() - server_time_used;
+        debug!(
+            "Time for client to register on DS is {:?}",
+            &client_ds_reg_time
+        );
+        if section == TimeClientsDsRegister {
+            return BenchmarkOutput::for_section(Cost::new(client_ds_reg_time, bandwidth));
+        }
         maybe_assert_all(local_msgs, enable_assert);
 
         // Ensure Key Synced
         // TimeClientASKeySync
         // Ignoring Time Client generated OnWireMessage::UserSyncCredentials because trivial
         let sync_responses = block_on(get_all_sync_as_msgs(&mut client_bundles, &as_state));
-        // let section_start_timestamp = Instant::now();
+        let bandwidth = all_onwire_msgs_bandwidth(&sync_responses);
+        let section_start_timestamp = Instant::now();
         let local_msgs = clients_process_all_msgs(sync_responses, &mut client_bundles);
-        // let client_as_sync_time = section_start_timestamp.elapsed();
-        // debug!("The client AS key sync time is {:?}", &client_as_sync_time);
-        // if section == TimeClientsASKeySync {
-        //     return vec![client_as_sync_time];
-        // }
-        // times.push(client_as_sync_time);
+        let client_as_sync_time = section_start_timestamp.elapsed();
+        debug!("The client AS key sync time is {:?}", &client_as_sync_time);
+        if section == TimeClientsASKeySync {
+            return BenchmarkOutput::for_section(Cost::new(client_as_sync_time, bandwidth));
+        }
         maybe_assert_all(local_msgs, enable_assert);
 
         // Admin creates group
         // TimeAdminCreate
-        // let section_start_timestamp = Instant::now();
+        let section_start_timestamp = Instant::now();
         let admin = client_bundles.get_mut(admin_index).unwrap();
         let create_msg = create_group_msg(
             &admin.name(),
             &comm_grp(),
             &mut admin.backend,
             admin.configs.deref_mut(),
-        );
-        // let server_timestamp = Instant::now();
+        )
+        .expect("Invalid group ID");
+        let mut bandwidth = onwire_msgs_bandwidth(&create_msg);
+        let server_timestamp = Instant::now();
         let response = block_on(ds_process_msgs(create_msg, &ds_state));
-        // let server_time_used = server_timestamp.elapsed();
+        let server_time_used = server_timestamp.elapsed();
+        bandwidth += onwire_msgs_bandwidth(&response);
         let local_msgs = admin.parse_msgs(&response);
         maybe_assert_all(vec![local_msgs], enable_assert);
-        // let client_create_time = section_start_timestamp.elapsed() - server_time_used;
-        // debug!("The client create time is {:?}", &client_create_time);
-        // if section == TimeAdminCreate {
-        //     return vec![client_create_time];
-        // }
-        // times.push(client_create_time);
+        let client_create_time = section_start_timestamp.elapsed() - server_time_used;
+        debug!("The client create time is {:?}", &client_create_time);
+        if section == TimeAdminCreate {
+            return BenchmarkOutput::for_section(Cost::new(client_create_time, bandwidth));
+        }
         let mut admin_pre_inv_time = Duration::from_micros(0);
         let mut admin_pre_inv_bandwidth = 0usize;
         let mut admin_add_time = Duration::from_micros(0);
@@ -200,7 +230,7 @@ mod test1 {
                 // ------Pre-Invite------
                 // Timer0-Start
                 let section_start_timestamp = Instant::now();
-                let msgs = pre_add_invite_msg(
+                let (msgs, _) = pre_add_invite_msg(
                     &admin_bundle.name(),
                     &comm_grp(),
                     &mut admin_bundle.backend,
@@ -225,7 +255,7 @@ mod test1 {
                 // ------Add------
                 // Timer1-Start
                 let section_start_timestamp = Instant::now();
-                let add = add_msg(
+                let (add, _) = add_msg(
                     &comm_grp(),
                     &vec![name],
                     admin_bundle.configs.deref_mut(),
@@ -295,41 +325,43 @@ mod test1 {
         //     invite_times,
         // )
         // .expect("Cannnot save invite");
-        // debug!(
-        //     "The client pre-invite message generation time is {:?}",
-        //     &client_pre_inv_time
-        // );
-        // debug!("The client add time is {:?}", &client_add_time);
-        // debug!(
-        //     "The client group state update time is {:?}",
-        //     &client_data_update_time
-        // );
-        // if section == TimeAdminPreInvites {
-        //     return vec![client_pre_inv_time];
-        // }
-        // times.push(client_pre_inv_time);
-        // if section == TimeAdminGroupStateUpdate {
-        //     return vec![client_data_update_time];
-        // }
-        // times.push(client_data_update_time);
-        // if section == TimeAdminAdds {
-        //     return vec![client_add_time];
-        // }
-        // times.push(client_add_time);
+        debug!(
+            "The client pre-invite message generation time is {:?}",
+            &admin_pre_inv_time
+        );
+        debug!("The client add time is {:?}", &admin_add_time);
+        debug!(
+            "The client group state update time is {:?}",
+            &admin_state_update_time
+        );
+        match section {
+            TimeAdminPreInvites => {
+                return BenchmarkOutput::for_section(output.admin_pre_invite_last_member)
+            }
+            TimeAdminAdds => return BenchmarkOutput::for_section(output.admin_add_last_member),
+            TimeAdminGroupStateUpdate => {
+                return BenchmarkOutput::for_section(
+                    output.admin_group_state_update_after_last_member,
+                )
+            }
+            _ => (),
+        }
 
+        // TimeClientPostAddSync
         let section_start_timestamp = Instant::now();
         let ds_syncs = sync_all_ds_msgs(&mut client_bundles);
+        let mut bandwidth = all_onwire_msgs_bandwidth(&ds_syncs);
         let server_timestamp = Instant::now();
         let ds_responses = block_on(ds_process_all_msgs(ds_syncs, &ds_state));
         let server_duration = server_timestamp.elapsed();
+        bandwidth += all_onwire_msgs_bandwidth(&ds_responses);
         let local_msgs = clients_process_all_msgs(ds_responses, &mut client_bundles);
-        let _client_sync_time = section_start_timestamp.elapsed() - server_duration;
+        let client_sync_time = section_start_timestamp.elapsed() - server_duration;
         maybe_assert_all(local_msgs, enable_assert);
-        // debug!("The client sync time is {:?}", &client_sync_time);
-        // if section == TimeClientPostAddSync {
-        //     return vec![client_sync_time];
-        // }
-        // times.push(client_sync_time);
+        debug!("The client sync time is {:?}", &client_sync_time);
+        if section == TimeClientPostAddSync {
+            return BenchmarkOutput::for_section(Cost::new(client_sync_time, bandwidth));
+        }
 
         // Invitee accepts group invitation
         // TimeClientAccepts
@@ -345,20 +377,36 @@ mod test1 {
                 }
             })
             .collect();
+        let mut bandwidth = all_onwire_msgs_bandwidth(&accepts);
         let server_timestamp = Instant::now();
         let ds_responses = block_on(ds_process_all_msgs(accepts, &ds_state));
         let server_duration = server_timestamp.elapsed();
+        bandwidth += all_onwire_msgs_bandwidth(&ds_responses);
         let _local_msgs = clients_process_all_msgs(ds_responses, &mut client_bundles);
-        let _client_accept_time = section_start_timestamp.elapsed() - server_duration;
+        let client_accept_time = section_start_timestamp.elapsed() - server_duration;
+        debug!("The client accept time is {:?}", &client_accept_time);
+        if section == TimeClientsAccept {
+            return BenchmarkOutput::for_section(Cost::new(client_accept_time, bandwidth));
+        }
 
+        // TimeClientPostAcceptSync
         let section_start_timestamp = Instant::now();
         let ds_syncs = sync_all_ds_msgs(&mut client_bundles);
+        let mut bandwidth = all_onwire_msgs_bandwidth(&ds_syncs);
         let server_timestamp = Instant::now();
         let ds_responses = block_on(ds_process_all_msgs(ds_syncs, &ds_state));
         let server_duration = server_timestamp.elapsed();
+        bandwidth += all_onwire_msgs_bandwidth(&ds_responses);
         let local_msgs = clients_process_all_msgs(ds_responses, &mut client_bundles);
-        let _client_sync2_time = section_start_timestamp.elapsed() - server_duration;
+        let client_sync2_time = section_start_timestamp.elapsed() - server_duration;
         maybe_assert_all(local_msgs, enable_assert);
+        debug!(
+            "The client sync2 (post-accept) time is {:?}",
+            &client_sync2_time
+        );
+        if section == TimeClientPostAcceptSync {
+            return BenchmarkOutput::for_section(Cost::new(client_sync2_time, bandwidth));
+        }
 
         // Admin sends a group message
         //TimeClientSendsMessage
@@ -382,13 +430,29 @@ mod test1 {
             duration_in_nanos: admin_send_msg_time.as_nanos(),
             bandwidth_in_bytes: outbound_bandwidth + inbound_bandwidth,
         };
+        if section == TimeAdminSendsMessage {
+            return BenchmarkOutput::for_section(output.admin_send_message);
+        }
 
         // Invitee receives all messages
         //     TimeClientReceivesMessageSync
+        let section_start_timestamp = Instant::now();
         let ds_syncs = sync_all_ds_msgs(&mut client_bundles);
+        let mut bandwidth = all_onwire_msgs_bandwidth(&ds_syncs);
+        let server_timestamp = Instant::now();
         let ds_responses = block_on(ds_process_all_msgs(ds_syncs, &ds_state));
+        let server_duration = server_timestamp.elapsed();
+        bandwidth += all_onwire_msgs_bandwidth(&ds_responses);
         let local_msgs = clients_process_all_msgs(ds_responses, &mut client_bundles);
+        let client_sync3_time = section_start_timestamp.elapsed() - server_duration;
         maybe_assert_all(local_msgs, enable_assert);
+        debug!(
+            "The client sync3 (post-message) time is {:?}",
+            &client_sync3_time
+        );
+        if section == TimeClientReceivesMessageSync {
+            return BenchmarkOutput::for_section(Cost::new(client_sync3_time, bandwidth));
+        }
 
         // Admin sends a rename message
         //TimeAdminRenameGroup
@@ -398,11 +462,13 @@ mod test1 {
             &comm_grp(),
             ActionMsg::RenameGroup(RenameGroupAction {
                 new_name: "new_group_name".to_owned(),
+                lamport_counter: 1,
                 metadata: ActionMetadata {
                     sender: admin_bundle.name(),
                     action_id: Uuid::new_v4().to_string(),
                     community_group_id: comm_grp(),
                     data: "".to_string(),
+                    sent_at: None,
                 },
             }),
             &mut admin_bundle.backend,
@@ -419,34 +485,44 @@ mod test1 {
             duration_in_nanos: admin_rename_time.as_nanos(),
             bandwidth_in_bytes: outbound_bandwidth + inbound_bandwidth,
         };
-        // debug!("The admin rename sending time is {:?}", &admin_rename_time);
-        // if section == TimeAdminRenameGroup {
-        //     return vec![admin_rename_time];
-        // }
-        // times.push(admin_rename_time);
+        debug!("The admin rename sending time is {:?}", &admin_rename_time);
+        if section == TimeAdminRenameGroup {
+            return BenchmarkOutput::for_section(output.admin_rename_group);
+        }
 
         // Invitee sync Renames
-        //     TimeClientReceivesMessageSync
-        // let section_start_timestamp = Instant::now();
+        //     TimeClientsPostRenameSync
+        let section_start_timestamp = Instant::now();
         let ds_syncs = sync_all_ds_msgs(&mut client_bundles);
-        // let server_timestamp = Instant::now();
+        let mut bandwidth = all_onwire_msgs_bandwidth(&ds_syncs);
+        let server_timestamp = Instant::now();
         let ds_responses = block_on(ds_process_all_msgs(ds_syncs, &ds_state));
-        // let server_duration = server_timestamp.elapsed();
+        let server_duration = server_timestamp.elapsed();
+        bandwidth += all_onwire_msgs_bandwidth(&ds_responses);
         let local_msgs = clients_process_all_msgs(ds_responses, &mut client_bundles);
-        // let client_sync4_time = section_start_timestamp.elapsed() - server_duration;
+        let client_sync4_time = section_start_timestamp.elapsed() - server_duration;
         maybe_assert_all(local_msgs, enable_assert);
-        // debug!(
-        //     "The client sync4 (post-admin-rename) time is {:?}",
-        //     &client_sync4_time
-        // );
-        // if section == TimeClientsPostRenameSync {
-        //     return vec![client_sync4_time];
-        // }
-        // times.push(client_sync4_time);
-        // times
+        debug!(
+            "The client sync4 (post-admin-rename) time is {:?}",
+            &client_sync4_time
+        );
+        if section == TimeClientsPostRenameSync {
+            return BenchmarkOutput::for_section(Cost::new(client_sync4_time, bandwidth));
+        }
 
         output
     }
+
+    #[test]
+    /// Benchmarking up to the group creation should only report its cost
+    fn test_benchmark_stops_after_section() {
+        let output = benchmark(TimeAdminCreate, 2);
+        let section = output.section.expect("No cost for the section");
+        assert!(section.duration_in_nanos > 0);
+        assert!(section.bandwidth_in_bytes > 0);
+        assert_eq!(output.admin_pre_invite_last_member.duration_in_nanos, 0);
+        assert_eq!(output.admin_rename_group.duration_in_nanos, 0);
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
@@ -129,7 +129,8 @@ fn test_vote(group_size: usize) -> BenchmarkOutput {
         &comm_grp(),
         &mut admin.backend,
         admin.configs.deref_mut(),
-    );
+    )
+    .expect("Invalid group ID");
     let server_timestamp = Instant::now();
     let response = block_on(ds_process_msgs(create_msg, &ds_state));
     let server_time_used = server_timestamp.elapsed();
@@ -153,7 +154,7 @@ fn test_vote(group_size: usize) -> BenchmarkOutput {
             // ------Pre-Invite------
             // Timer0-Start
             let section_start_timestamp = Instant::now();
-            let msgs = pre_add_invite_msg(
+            let (msgs, _) = pre_add_invite_msg(
                 &admin_bundle.name(),
                 &comm_grp(),
                 &mut admin_bundle.backend,
@@ -179,7 +180,7 @@ fn test_vote(group_size: usize) -> BenchmarkOutput {
             // ------Add------
             // Timer1-Start
             let section_start_timestamp = Instant::now();
-            let add = add_msg(
+            let (add, _) = add_msg(
                 &comm_grp(),
                 &vec![name],
                 admin_bundle.configs.deref_mut(),
@@ -441,6 +442,7 @@ fn client_cast_vote(
 
                 community_group_id: comm_grp(),
                 data: "".to_string(),
+                sent_at: None,
             },
         }),
         &mut client_bundle.backend,
@@ -496,11 +498,13 @@ fn gen_rename_message(client_bundle: &mut TestClientBundle) -> Vec<OnWireMessage
         &comm_grp(),
         ActionMsg::RenameGroup(RenameGroupAction {
             new_name: NEW_GROUP_NAME.to_owned(),
+            lamport_counter: 1,
             metadata: ActionMetadata {
                 sender: client_bundle.name(),
                 action_id: RENAME_ACTION_ID.to_string(),
 
                 data: "".to_string(),
+                sent_at: None,
                 community_group_id: comm_grp(),
             },
         }),
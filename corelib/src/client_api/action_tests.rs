@@ -3,13 +3,15 @@ mod action_tests {
     use ed25519_dalek::Keypair;
     use rand_07::rngs::OsRng;
 
-    use crate::client_api::actions::{Action, ActionMetadata, TextMsgAction};
+    use crate::client_api::actions::{Action, ActionMetadata, ActionMsg, TextMsgAction};
     use crate::CommGroupId;
 
     #[test]
     fn test_action_sign_and_verify() {
         let text_msg = TextMsgAction {
             msg: "test".to_string(),
+            expires_after: None,
+            in_reply_to: None,
             metadata: ActionMetadata {
                 sender: "alice".to_string(),
                 action_id: "id1".to_string(),
@@ -18,6 +20,7 @@ mod action_tests {
                     &"Group".to_string(),
                 ),
                 data: "".to_string(),
+                sent_at: None,
             },
         };
         let mut csprng = OsRng {};
@@ -26,4 +29,26 @@ mod action_tests {
         // Verify the signature
         assert!(text_msg.verify_sig(&text_msg_sig, keypair.public_key()));
     }
+
+    #[test]
+    /// An action of a type unknown to this client should deserialize as
+    /// `Unknown` instead of failing, while a malformed known one still fails
+    fn test_unknown_action_type_deserialized_as_fallback() {
+        let payload = r#"{"PinMessage":{"message_id":"m1","metadata":{"sender":"alice","action_id":"id2","community_group_id":{"community_id":"Community","group_id":"Group"},"data":""}}}"#;
+        let action: ActionMsg = serde_json::from_str(payload).unwrap();
+        match &action {
+            ActionMsg::Unknown(unknown) => {
+                assert_eq!(unknown.kind, "PinMessage");
+                assert!(unknown.raw.contains("m1"));
+            }
+            other => panic!("Expected an unknown action, got {:?}", other),
+        }
+        assert_eq!(action.get_metadata().sender, "alice");
+        assert_eq!(action.get_metadata().action_id, "id2");
+
+        let malformed = r#"{"TextMsg":{"metadata":{"sender":"alice","action_id":"id3","community_group_id":{"community_id":"Community","group_id":"Group"},"data":""}}}"#;
+        assert!(serde_json::from_str::<ActionMsg>(malformed).is_err());
+        let without_metadata = r#"{"PinMessage":{"message_id":"m1"}}"#;
+        assert!(serde_json::from_str::<ActionMsg>(without_metadata).is_err());
+    }
 }
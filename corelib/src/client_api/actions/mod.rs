@@ -1,15 +1,14 @@
-use std::collections::hash_map::DefaultHasher;
 use std::fmt::Debug;
-use std::hash::{Hash, Hasher};
-use std::time::Instant;
+use std::time::{Duration, Instant, SystemTime};
 
 use clap::ValueEnum;
 use ed25519_dalek::{Keypair, PublicKey, Signature, Signer, Verifier};
-use log::{debug, info};
+use log::{debug, info, warn};
 use openmls::prelude::KeyPackage;
-use serde::{Deserialize, Serialize};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-use crate::client_api::client_struct::SharedGroupState;
+use crate::client_api::client_struct::{SharedGroupState, StoredReport};
 use crate::messages::encode_to_bytes;
 use crate::TimerType::MlsGovVerifiableActionGeneration;
 use crate::{identity_to_str, CommGroupId, SingleTimeMeasurement};
@@ -46,23 +45,106 @@ pub trait Action: Serialize + Clone + Debug {
 /// An enum for supported actions (following the definition of proposal types
 /// in the MLS spec)
 /// See: https://messaginglayersecurity.rocks/mls-protocol/draft-ietf-mls-protocol.html#name-proposals
+///
+/// Actions of a type this client does not know deserialize as
+/// [ActionMsg::Unknown] rather than failing, see the `Deserialize` impl.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(remote = "Self")]
 pub enum ActionMsg {
     TextMsg(TextMsgAction),
     RenameGroup(RenameGroupAction),
     Report(ReportAction),
     SetTopicGroup(SetTopicGroupAction),
+    SetMaxGroupSize(SetMaxGroupSizeAction),
+    SetFlagThreshold(SetFlagThresholdAction),
     TakedownTextMsg(TakedownTextMsgAction),
+    FlagMessage(FlagMessageAction),
     Invite(InviteAction),
     Kick(KickAction),
+    Ban(BanAction),
+    Unban(UnbanAction),
+    AdminRevert(AdminRevertAction),
     DefRole(DefRoleAction),
     SetUserRole(SetUserRoleAction),
+    SetUserRolesBulk(SetUserRolesBulkAction),
+    AddUserRole(AddUserRoleAction),
+    RemoveUserRole(RemoveUserRoleAction),
     Accept(AcceptAction),
     Decline(DeclineAction),
     Leave(LeaveAction),
     Vote(VoteAction),
+    ClosePoll(ClosePollAction),
     GovStateAnnouncement(GovStateAnnouncementAction),
+    GovStateHash(GovStateHashAction),
     Custom(CustomAction),
+    Unknown(UnknownAction),
+}
+
+impl Serialize for ActionMsg {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        ActionMsg::serialize(self, serializer)
+    }
+}
+
+/// The tags of all variants of [ActionMsg], i.e., their names. Actions with
+/// any other tag deserialize as [ActionMsg::Unknown]
+const ACTION_MSG_TAGS: &[&str] = &[
+    "TextMsg",
+    "RenameGroup",
+    "Report",
+    "SetTopicGroup",
+    "SetMaxGroupSize",
+    "SetFlagThreshold",
+    "TakedownTextMsg",
+    "FlagMessage",
+    "Invite",
+    "Kick",
+    "Ban",
+    "Unban",
+    "AdminRevert",
+    "DefRole",
+    "SetUserRole",
+    "SetUserRolesBulk",
+    "AddUserRole",
+    "RemoveUserRole",
+    "Accept",
+    "Decline",
+    "Leave",
+    "Vote",
+    "ClosePoll",
+    "GovStateAnnouncement",
+    "GovStateHash",
+    "Custom",
+    "Unknown",
+];
+
+impl<'de> Deserialize<'de> for ActionMsg {
+    /// Falls back to [ActionMsg::Unknown] for variants this client does not
+    /// know, e.g., sent by a newer client, so that they can be skipped
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = serde_json::Value::deserialize(deserializer)?;
+        let tag = match value.as_object() {
+            Some(tagged) if tagged.len() == 1 => tagged.keys().next(),
+            _ => None,
+        };
+        match tag {
+            // Only an unknown tag falls back, not a malformed known action
+            Some(tag) if !ACTION_MSG_TAGS.contains(&tag.as_str()) => {
+                UnknownAction::from_value(&value)
+                    .map(ActionMsg::Unknown)
+                    .ok_or_else(|| {
+                        D::Error::custom(format!("action of unknown type {} without metadata", tag))
+                    })
+            }
+            _ => ActionMsg::deserialize(&value).map_err(D::Error::custom),
+        }
+    }
 }
 
 pub type VoteValue = String;
@@ -78,17 +160,29 @@ impl Action for ActionMsg {
             ActionMsg::RenameGroup(action) => action.execute(client_data),
             ActionMsg::Report(action) => action.execute(client_data),
             ActionMsg::SetTopicGroup(action) => action.execute(client_data),
+            ActionMsg::SetMaxGroupSize(action) => action.execute(client_data),
+            ActionMsg::SetFlagThreshold(action) => action.execute(client_data),
             ActionMsg::TakedownTextMsg(action) => action.execute(client_data),
+            ActionMsg::FlagMessage(action) => action.execute(client_data),
             ActionMsg::Custom(action) => action.execute(client_data),
             ActionMsg::Invite(action) => action.execute(client_data),
             ActionMsg::Kick(action) => action.execute(client_data),
+            ActionMsg::Ban(action) => action.execute(client_data),
+            ActionMsg::Unban(action) => action.execute(client_data),
+            ActionMsg::AdminRevert(action) => action.execute(client_data),
             ActionMsg::DefRole(action) => action.execute(client_data),
             ActionMsg::SetUserRole(action) => action.execute(client_data),
+            ActionMsg::SetUserRolesBulk(action) => action.execute(client_data),
+            ActionMsg::AddUserRole(action) => action.execute(client_data),
+            ActionMsg::RemoveUserRole(action) => action.execute(client_data),
             ActionMsg::Accept(action) => action.execute(client_data),
             ActionMsg::Decline(action) => action.execute(client_data),
             ActionMsg::Leave(action) => action.execute(client_data),
             ActionMsg::Vote(action) => action.execute(client_data),
+            ActionMsg::ClosePoll(action) => action.execute(client_data),
             ActionMsg::GovStateAnnouncement(action) => action.execute(client_data),
+            ActionMsg::GovStateHash(action) => action.execute(client_data),
+            ActionMsg::Unknown(action) => action.execute(client_data),
         }
     }
 
@@ -98,17 +192,29 @@ impl Action for ActionMsg {
             ActionMsg::RenameGroup(action) => action.get_metadata(),
             ActionMsg::Report(action) => action.get_metadata(),
             ActionMsg::SetTopicGroup(action) => action.get_metadata(),
+            ActionMsg::SetMaxGroupSize(action) => action.get_metadata(),
+            ActionMsg::SetFlagThreshold(action) => action.get_metadata(),
             ActionMsg::TakedownTextMsg(action) => action.get_metadata(),
+            ActionMsg::FlagMessage(action) => action.get_metadata(),
             ActionMsg::Custom(action) => action.get_metadata(),
             ActionMsg::Invite(action) => action.get_metadata(),
             ActionMsg::Kick(action) => action.get_metadata(),
+            ActionMsg::Ban(action) => action.get_metadata(),
+            ActionMsg::Unban(action) => action.get_metadata(),
+            ActionMsg::AdminRevert(action) => action.get_metadata(),
             ActionMsg::DefRole(action) => action.get_metadata(),
             ActionMsg::SetUserRole(action) => action.get_metadata(),
+            ActionMsg::SetUserRolesBulk(action) => action.get_metadata(),
+            ActionMsg::AddUserRole(action) => action.get_metadata(),
+            ActionMsg::RemoveUserRole(action) => action.get_metadata(),
             ActionMsg::Accept(action) => action.get_metadata(),
             ActionMsg::Decline(action) => action.get_metadata(),
             ActionMsg::Leave(action) => action.get_metadata(),
             ActionMsg::Vote(action) => action.get_metadata(),
+            ActionMsg::ClosePoll(action) => action.get_metadata(),
             ActionMsg::GovStateAnnouncement(action) => action.get_metadata(),
+            ActionMsg::GovStateHash(action) => action.get_metadata(),
+            ActionMsg::Unknown(action) => action.get_metadata(),
         }
     }
 
@@ -118,17 +224,29 @@ impl Action for ActionMsg {
             ActionMsg::RenameGroup(action) => action.is_ordered(),
             ActionMsg::Report(action) => action.is_ordered(),
             ActionMsg::SetTopicGroup(action) => action.is_ordered(),
+            ActionMsg::SetMaxGroupSize(action) => action.is_ordered(),
+            ActionMsg::SetFlagThreshold(action) => action.is_ordered(),
             ActionMsg::TakedownTextMsg(action) => action.is_ordered(),
+            ActionMsg::FlagMessage(action) => action.is_ordered(),
             ActionMsg::Custom(action) => action.is_ordered(),
             ActionMsg::Invite(action) => action.is_ordered(),
             ActionMsg::Kick(action) => action.is_ordered(),
+            ActionMsg::Ban(action) => action.is_ordered(),
+            ActionMsg::Unban(action) => action.is_ordered(),
+            ActionMsg::AdminRevert(action) => action.is_ordered(),
             ActionMsg::DefRole(action) => action.is_ordered(),
             ActionMsg::SetUserRole(action) => action.is_ordered(),
+            ActionMsg::SetUserRolesBulk(action) => action.is_ordered(),
+            ActionMsg::AddUserRole(action) => action.is_ordered(),
+            ActionMsg::RemoveUserRole(action) => action.is_ordered(),
             ActionMsg::Accept(action) => action.is_ordered(),
             ActionMsg::Decline(action) => action.is_ordered(),
             ActionMsg::Leave(action) => action.is_ordered(),
             ActionMsg::Vote(action) => action.is_ordered(),
+            ActionMsg::ClosePoll(action) => action.is_ordered(),
             ActionMsg::GovStateAnnouncement(action) => action.is_ordered(),
+            ActionMsg::GovStateHash(action) => action.is_ordered(),
+            ActionMsg::Unknown(action) => action.is_ordered(),
         }
     }
 }
@@ -140,17 +258,34 @@ impl ActionMsg {
             ActionMsg::RenameGroup(ref _r) => ActionType::RenameGroup,
             ActionMsg::Report(ref _r) => ActionType::Report,
             ActionMsg::SetTopicGroup(ref _s) => ActionType::SetTopicGroup,
-            ActionMsg::TakedownTextMsg(ref _t) => ActionType::TakedownTextMsg,
+            ActionMsg::SetMaxGroupSize(ref _s) => ActionType::SetMaxGroupSize,
+            // Who may take down messages may also have them taken down on flags
+            ActionMsg::TakedownTextMsg(ref _t) | ActionMsg::SetFlagThreshold(ref _t) => {
+                ActionType::TakedownTextMsg
+            }
+            ActionMsg::FlagMessage(ref _f) => ActionType::FlagMessage,
             ActionMsg::Invite(ref _i) => ActionType::Invite,
             ActionMsg::Kick(ref _k) => ActionType::Kick,
+            // Lifting a ban needs the same permission as banning
+            ActionMsg::Ban(ref _b) | ActionMsg::Unban(ref _b) => ActionType::Ban,
+            ActionMsg::AdminRevert(ref _a) => ActionType::AdminRevert,
             ActionMsg::DefRole(ref _d) => ActionType::DefRole,
             ActionMsg::SetUserRole(ref _s) => ActionType::SetUserRole,
+            // Need the same permission as assigning a single role
+            ActionMsg::SetUserRolesBulk(ref _s)
+            | ActionMsg::AddUserRole(ref _s)
+            | ActionMsg::RemoveUserRole(ref _s) => ActionType::SetUserRole,
             ActionMsg::Custom(ref _c) => ActionType::Custom,
             ActionMsg::Accept(ref _a) => ActionType::Accept,
             ActionMsg::Decline(ref _d) => ActionType::Decline,
             ActionMsg::Leave(ref _l) => ActionType::Leave,
-            ActionMsg::Vote(_) => ActionType::Vote,
-            ActionMsg::GovStateAnnouncement(_) => ActionType::UpdateGroupState,
+            // Closing a vote needs no more permission than voting
+            ActionMsg::Vote(_) | ActionMsg::ClosePoll(_) => ActionType::Vote,
+            ActionMsg::GovStateAnnouncement(_) | ActionMsg::GovStateHash(_) => {
+                ActionType::UpdateGroupState
+            }
+            // Whatever it is, it is never executed
+            ActionMsg::Unknown(ref _u) => ActionType::Custom,
         }
     }
 }
@@ -164,9 +299,14 @@ pub enum ActionType {
     RenameGroup,
     Report,
     SetTopicGroup,
+    SetMaxGroupSize,
     TakedownTextMsg,
+    FlagMessage,
     Invite,
     Kick,
+    Ban,
+    /// Restoring a previous group state
+    AdminRevert,
     DefRole,
     SetUserRole,
     Accept,
@@ -174,6 +314,8 @@ pub enum ActionType {
     Leave,
     Vote,
     UpdateGroupState,
+    /// Reading the group's report review queue and flagged messages
+    ReviewReports,
     Custom,
 }
 
@@ -187,15 +329,26 @@ pub struct ActionMetadata {
     /// The data associated with the action, which may be modified by the
     /// Policy Engine
     pub data: String,
+    /// When the sender created the action, as claimed by the sender
+    #[serde(default)]
+    pub sent_at: Option<SystemTime>,
 }
 
 impl ActionMetadata {
-    pub fn new(sender: String, action_id: String, community_group_id: CommGroupId) -> Self {
+    /// The metadata of an action created at `sent_at`, which is read from
+    /// the clock of the client, e.g., [ClientDataProvider::now](crate::client_api::client_struct::ClientDataProvider::now)
+    pub fn new(
+        sender: String,
+        action_id: String,
+        community_group_id: CommGroupId,
+        sent_at: SystemTime,
+    ) -> Self {
         ActionMetadata {
             sender,
             action_id,
             community_group_id,
             data: "".to_string(),
+            sent_at: Some(sent_at),
         }
     }
 }
@@ -227,6 +380,13 @@ impl VerifiableAction {
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct TextMsgAction {
     pub msg: String,
+    /// How long recipients keep the message after receiving it, if it
+    /// disappears. See [LocalHistoryMessage](crate::client_api::client_struct_impl::LocalHistoryMessage)
+    #[serde(default)]
+    pub expires_after: Option<Duration>,
+    /// The action id of the text message this one replies to, if any
+    #[serde(default)]
+    pub in_reply_to: Option<String>,
     pub metadata: ActionMetadata,
 }
 
@@ -244,21 +404,37 @@ impl Action for TextMsgAction {
     }
 }
 
-/// An `Action` struct for a command that renames the group
+/// An `Action` struct for a command that renames the group.
+/// Concurrent renames are resolved by `(lamport_counter, sender)`,
+/// so that every client picks the same winner
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct RenameGroupAction {
     pub new_name: String,
+    /// One more than the name's counter the sender observed
+    #[serde(default)]
+    pub lamport_counter: u64,
     pub metadata: ActionMetadata,
 }
 
 impl Action for RenameGroupAction {
     fn execute(&self, client_data: &mut (impl ClientDataProvider + ?Sized)) {
+        let comm_grp = &self.metadata.community_group_id;
+        let version = (self.lamport_counter, self.metadata.sender.to_owned());
+        if version <= client_data.get_group_name_version(comm_grp) {
+            info!(
+                "{} Ignoring RenameGroupAction to {} as it is causally older",
+                client_data.get_user_id(),
+                self.new_name
+            );
+            return;
+        }
         info!(
             "{} Executing RenameGroupAction: to {}",
             client_data.get_user_id(),
             self.new_name.to_string()
         );
-        client_data.set_group_name(&self.metadata.community_group_id, self.new_name.to_string());
+        client_data.set_group_name(comm_grp, self.new_name.to_string());
+        client_data.set_group_name_version(comm_grp, version);
     }
 
     fn get_metadata(&self) -> ActionMetadata {
@@ -281,7 +457,17 @@ pub struct ReportAction {
 }
 
 impl Action for ReportAction {
-    fn execute(&self, _client_data: &mut (impl ClientDataProvider + ?Sized)) {}
+    fn execute(&self, client_data: &mut (impl ClientDataProvider + ?Sized)) {
+        client_data.store_report(
+            &self.metadata.community_group_id,
+            StoredReport {
+                reporter: self.metadata.sender.to_owned(),
+                reason: self.reason.to_owned(),
+                reported_action: self.ver_action_str.to_owned(),
+                received_timestamp: client_data.now(),
+            },
+        );
+    }
 
     fn get_metadata(&self) -> ActionMetadata {
         self.metadata.clone()
@@ -292,19 +478,87 @@ impl Action for ReportAction {
     }
 }
 
-/// An `Action` for modifying the topic associated with a group
+/// An `Action` for modifying the topic associated with a group.
+/// Concurrent changes are resolved the same way as in [RenameGroupAction]
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct SetTopicGroupAction {
     pub new_topic: String,
+    /// One more than the topic's counter the sender observed
+    #[serde(default)]
+    pub lamport_counter: u64,
     pub metadata: ActionMetadata,
 }
 
 impl Action for SetTopicGroupAction {
     fn execute(&self, client_data: &mut (impl ClientDataProvider + ?Sized)) {
-        client_data.set_group_topic(
-            &self.metadata.community_group_id,
-            self.new_topic.to_string(),
+        let comm_grp = &self.metadata.community_group_id;
+        let version = (self.lamport_counter, self.metadata.sender.to_owned());
+        if version <= client_data.get_group_topic_version(comm_grp) {
+            info!(
+                "{} Ignoring SetTopicGroupAction to {} as it is causally older",
+                client_data.get_user_id(),
+                self.new_topic
+            );
+            return;
+        }
+        client_data.set_group_topic(comm_grp, self.new_topic.to_string());
+        client_data.set_group_topic_version(comm_grp, version);
+    }
+
+    fn get_metadata(&self) -> ActionMetadata {
+        self.metadata.clone()
+    }
+
+    fn is_ordered(&self) -> bool {
+        true
+    }
+}
+
+/// An `Action` for capping the number of members of a group, or lifting the
+/// cap if `max_group_size` is `None`. Members above a new cap are not removed,
+/// but no one can be invited until the group is below it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SetMaxGroupSizeAction {
+    pub max_group_size: Option<usize>,
+    pub metadata: ActionMetadata,
+}
+
+impl Action for SetMaxGroupSizeAction {
+    fn execute(&self, client_data: &mut (impl ClientDataProvider + ?Sized)) {
+        info!(
+            "{} Executing SetMaxGroupSizeAction: to {:?}",
+            client_data.get_user_id(),
+            self.max_group_size
         );
+        client_data.set_max_group_size(&self.metadata.community_group_id, self.max_group_size);
+    }
+
+    fn get_metadata(&self) -> ActionMetadata {
+        self.metadata.clone()
+    }
+
+    fn is_ordered(&self) -> bool {
+        true
+    }
+}
+
+/// An `Action` for setting how many members must flag a message before the
+/// member whose flag reaches the count proposes to take it down, or for
+/// turning this off if `threshold` is `None`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SetFlagThresholdAction {
+    pub threshold: Option<usize>,
+    pub metadata: ActionMetadata,
+}
+
+impl Action for SetFlagThresholdAction {
+    fn execute(&self, client_data: &mut (impl ClientDataProvider + ?Sized)) {
+        info!(
+            "{} Executing SetFlagThresholdAction: to {:?}",
+            client_data.get_user_id(),
+            self.threshold
+        );
+        client_data.set_flag_takedown_threshold(&self.metadata.community_group_id, self.threshold);
     }
 
     fn get_metadata(&self) -> ActionMetadata {
@@ -341,6 +595,34 @@ impl Action for TakedownTextMsgAction {
     }
 }
 
+/// An `Action` for flagging a message for review by moderators. Unlike a
+/// report, flags are aggregated per message, and the flag reaching the
+/// group's flag threshold leads its sender to propose a takedown.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FlagMessageAction {
+    /// The action id of the flagged message
+    pub message_id: String,
+    pub metadata: ActionMetadata,
+}
+
+impl Action for FlagMessageAction {
+    fn execute(&self, client_data: &mut (impl ClientDataProvider + ?Sized)) {
+        client_data.store_flag(
+            &self.metadata.community_group_id,
+            self.message_id.to_owned(),
+            self.metadata.sender.to_owned(),
+        );
+    }
+
+    fn get_metadata(&self) -> ActionMetadata {
+        self.metadata.clone()
+    }
+
+    fn is_ordered(&self) -> bool {
+        false
+    }
+}
+
 /// The `CustomAction` type enables those who build off of our framework
 /// to define arbitrary new action types (along with policies governing
 /// those actions) without having to modify our code.
@@ -350,8 +632,23 @@ pub struct CustomAction {
     pub metadata: ActionMetadata,
 }
 
+impl CustomAction {
+    /// Splits `data` of the form `<kind>:<payload>` into its kind and payload
+    pub fn kind_and_payload(&self) -> Option<(&str, &str)> {
+        self.data.split_once(':')
+    }
+}
+
 impl Action for CustomAction {
-    fn execute(&self, _client_data: &mut (impl ClientDataProvider + ?Sized)) {}
+    /// Dispatches the action to the handler registered for its kind, if any
+    fn execute(&self, client_data: &mut (impl ClientDataProvider + ?Sized)) {
+        if !client_data.dispatch_custom_action(self) {
+            debug!(
+                "No handler registered for custom action {}",
+                self.metadata.action_id
+            );
+        }
+    }
 
     fn get_metadata(&self) -> ActionMetadata {
         self.metadata.clone()
@@ -362,6 +659,66 @@ impl Action for CustomAction {
     }
 }
 
+/// An action of a type this client does not know, e.g., sent by a newer
+/// client. It is skipped rather than executed
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UnknownAction {
+    /// The name of the `ActionMsg` variant it was received as
+    pub kind: String,
+    /// The JSON of the action as received, without its variant name
+    pub raw: String,
+    pub metadata: ActionMetadata,
+}
+
+impl UnknownAction {
+    /// Reads an externally tagged action, as long as it has the metadata
+    /// shared by all actions. `None` otherwise
+    fn from_value(value: &serde_json::Value) -> Option<Self> {
+        let (kind, body) = match value.as_object() {
+            Some(tagged) if tagged.len() == 1 => tagged.iter().next()?,
+            _ => return None,
+        };
+        let metadata = serde_json::from_value(body.get("metadata")?.clone()).ok()?;
+        Some(UnknownAction {
+            kind: kind.clone(),
+            raw: body.to_string(),
+            metadata,
+        })
+    }
+}
+
+impl Action for UnknownAction {
+    fn execute(&self, _client_data: &mut (impl ClientDataProvider + ?Sized)) {
+        info!(
+            "Skipping action {} of unknown type {}",
+            self.metadata.action_id, self.kind
+        );
+    }
+
+    fn get_metadata(&self) -> ActionMetadata {
+        self.metadata.clone()
+    }
+
+    fn is_ordered(&self) -> bool {
+        false
+    }
+}
+
+/// Executes the `CustomAction`s of one kind, i.e., whose data is of the form
+/// `<kind>:<payload>`. Handlers are registered on the client data with
+/// `register_custom_action_handler`. They are not part of the stored client
+/// state, so they must be registered again at every start.
+pub trait CustomActionHandler: Debug {
+    /// Executes a custom action given its payload, i.e., its data without
+    /// the kind prefix
+    fn handle(
+        &self,
+        payload: &str,
+        metadata: &ActionMetadata,
+        client_data: &mut dyn ClientDataProvider,
+    );
+}
+
 /// The application-layer `Action` for inviting someone to a group
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct InviteAction {
@@ -371,12 +728,34 @@ pub struct InviteAction {
 
 impl Action for InviteAction {
     fn execute(&self, client_data: &mut (impl ClientDataProvider + ?Sized)) {
+        let invitee_role = client_data.get_invitee_role(&self.metadata.community_group_id);
         for invitee_key_package in &self.invitee_key_packages {
+            let invitee = identity_to_str(invitee_key_package.credential().identity())
+                .expect("Failed to convert identity to string");
+            if client_data.is_banned(&self.metadata.community_group_id, &invitee) {
+                info!("Not inviting {} as they are banned from the group", invitee);
+                continue;
+            }
+            let shared_state = client_data.get_shared_state(&self.metadata.community_group_id);
+            if let Some(max_group_size) = shared_state.max_group_size {
+                // Staging an invitee again replaces their key package
+                let staged = shared_state.to_add_invitees.contains_key(&invitee);
+                let group_size = client_data
+                    .get_group_members(&self.metadata.community_group_id)
+                    .len()
+                    + shared_state.to_add_invitees.len();
+                if !staged && group_size >= max_group_size {
+                    info!(
+                        "Not inviting {} as the group would exceed its maximum size of {}",
+                        invitee, max_group_size
+                    );
+                    continue;
+                }
+            }
             client_data.set_user_role(
                 &self.metadata.community_group_id,
-                identity_to_str(invitee_key_package.credential().identity())
-                    .expect("Failed to convert identity to string"),
-                "BaseUser".to_string(), //TODO create a generic method for each plugin "init_for_new_invitee"?
+                invitee,
+                invitee_role.clone(), //TODO create a generic method for each plugin "init_for_new_invitee"?
             );
             client_data.store_to_add_invitee_key_pack(
                 &self.metadata.community_group_id,
@@ -421,11 +800,174 @@ impl Action for KickAction {
     }
 }
 
+/// The application-layer `Action` for banning someone from a group, i.e.,
+/// refusing any later invite of them. It does not remove them if they are
+/// currently a member; use a [KickAction] for that.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BanAction {
+    pub target_user_id: String,
+    pub metadata: ActionMetadata,
+}
+
+impl Action for BanAction {
+    fn execute(&self, client_data: &mut (impl ClientDataProvider + ?Sized)) {
+        client_data.ban_user(
+            &self.metadata.community_group_id,
+            self.target_user_id.to_string(),
+        );
+        info!("{} is banned from the group", self.target_user_id);
+    }
+
+    fn get_metadata(&self) -> ActionMetadata {
+        self.metadata.clone()
+    }
+
+    fn is_ordered(&self) -> bool {
+        true
+    }
+}
+
+/// The application-layer `Action` for lifting a ban, so that the user can
+/// be invited again
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UnbanAction {
+    pub target_user_id: String,
+    pub metadata: ActionMetadata,
+}
+
+impl Action for UnbanAction {
+    fn execute(&self, client_data: &mut (impl ClientDataProvider + ?Sized)) {
+        client_data.unban_user(&self.metadata.community_group_id, &self.target_user_id);
+        info!("{} is no longer banned from the group", self.target_user_id);
+    }
+
+    fn get_metadata(&self) -> ActionMetadata {
+        self.metadata.clone()
+    }
+
+    fn is_ordered(&self) -> bool {
+        true
+    }
+}
+
+/// The `Action` for muting a group, or a single member of it if
+/// `target_user_id` is set. Only executed locally, and never sent to the other
+/// members, so it is not an [ActionMsg]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MuteAction {
+    pub target_user_id: Option<String>,
+    pub metadata: ActionMetadata,
+}
+
+impl Action for MuteAction {
+    fn execute(&self, client_data: &mut (impl ClientDataProvider + ?Sized)) {
+        client_data.mute(
+            &self.metadata.community_group_id,
+            self.target_user_id.as_deref(),
+        );
+    }
+
+    fn get_metadata(&self) -> ActionMetadata {
+        self.metadata.clone()
+    }
+
+    fn is_ordered(&self) -> bool {
+        false
+    }
+}
+
+/// The local `Action` undoing a [MuteAction]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UnmuteAction {
+    pub target_user_id: Option<String>,
+    pub metadata: ActionMetadata,
+}
+
+impl Action for UnmuteAction {
+    fn execute(&self, client_data: &mut (impl ClientDataProvider + ?Sized)) {
+        client_data.unmute(
+            &self.metadata.community_group_id,
+            self.target_user_id.as_deref(),
+        );
+    }
+
+    fn get_metadata(&self) -> ActionMetadata {
+        self.metadata.clone()
+    }
+
+    fn is_ordered(&self) -> bool {
+        false
+    }
+}
+
+/// The `Action` for restoring the group state the sender had at
+/// `target_epoch`, undoing the governance changes since then. The state is
+/// carried in the action, so that every member restores the same one. The
+/// MLS group itself is not rolled back.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AdminRevertAction {
+    pub target_epoch: u64,
+    /// The group state to restore. Reverts from older clients carry none, and
+    /// are ignored
+    #[serde(default)]
+    pub target_state: Option<SharedGroupState>,
+    pub metadata: ActionMetadata,
+}
+
+impl Action for AdminRevertAction {
+    fn execute(&self, client_data: &mut (impl ClientDataProvider + ?Sized)) {
+        let comm_grp = &self.metadata.community_group_id;
+        let mut target_state = match &self.target_state {
+            Some(target_state) => target_state.clone(),
+            None => {
+                warn!(
+                    "No group state in the revert to epoch {} by {}, ignoring it",
+                    self.target_epoch, self.metadata.sender
+                );
+                return;
+            }
+        };
+        let current_state = client_data.get_shared_state(comm_grp);
+        // Keep the pending proposals of the policy engine
+        target_state.policy_engine = current_state.policy_engine.clone();
+        // Membership is not rolled back: members removed since lose their
+        // roles, and members added since keep theirs
+        let members = client_data.get_group_members(comm_grp);
+        let rbac = &mut target_state.rbac;
+        rbac.user_to_roles
+            .retain(|user_id, _| members.contains(user_id));
+        rbac.left_users.retain(|user_id| members.contains(user_id));
+        for member in &members {
+            if let Some(roles) = current_state.rbac.user_to_roles.get(member) {
+                rbac.user_to_roles
+                    .entry(member.clone())
+                    .or_insert_with(|| roles.clone());
+            }
+        }
+        client_data.load_shared_state(comm_grp, target_state);
+        info!(
+            "{} reverted the group state to epoch {}",
+            self.metadata.sender, self.target_epoch
+        );
+    }
+
+    fn get_metadata(&self) -> ActionMetadata {
+        self.metadata.clone()
+    }
+
+    fn is_ordered(&self) -> bool {
+        true
+    }
+}
+
 /// The `Action` for defining a user role
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct DefRoleAction {
     pub role_name: String,
     pub action_types: Vec<ActionType>,
+    /// The roles whose action types the role also permits
+    #[serde(default)]
+    pub inherits_from: Vec<String>,
     pub metadata: ActionMetadata,
 }
 
@@ -435,6 +977,7 @@ impl Action for DefRoleAction {
             &self.metadata.community_group_id,
             self.role_name.to_string(),
             self.action_types.clone(),
+            self.inherits_from.clone(),
         )
     }
 
@@ -447,8 +990,8 @@ impl Action for DefRoleAction {
     }
 }
 
-/// The `Action` for assigning a role to a user. Currently, a user can hold
-/// exactly one role at any given time.
+/// The `Action` for assigning a role to a user, replacing all the roles they
+/// held. See [AddUserRoleAction] for giving a user several roles.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct SetUserRoleAction {
     pub user_id: String,
@@ -474,6 +1017,106 @@ impl Action for SetUserRoleAction {
     }
 }
 
+/// An `Action` for assigning roles to several users at once, in a single
+/// commit. Either all or none of the `(user_id, role_name)` assignments apply.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SetUserRolesBulkAction {
+    pub assignments: Vec<(String, String)>,
+    pub metadata: ActionMetadata,
+}
+
+impl Action for SetUserRolesBulkAction {
+    fn execute(&self, client_data: &mut (impl ClientDataProvider + ?Sized)) {
+        let comm_grp = &self.metadata.community_group_id;
+        let members = client_data.get_group_members(comm_grp);
+        let roles = client_data.get_roles(comm_grp);
+        // Validate every assignment before applying any of them
+        if let Some((user_id, role_name)) = self.assignments.iter().find(|(user_id, role_name)| {
+            !members.contains(user_id) || !roles.role_defs.contains_key(role_name)
+        }) {
+            info!(
+                "Ignoring the role assignments as {} is not a member or {} is not a role",
+                user_id, role_name
+            );
+            return;
+        }
+        for (user_id, role_name) in &self.assignments {
+            client_data.set_user_role(comm_grp, user_id.to_string(), role_name.to_string());
+        }
+    }
+
+    fn get_metadata(&self) -> ActionMetadata {
+        self.metadata.clone()
+    }
+
+    fn is_ordered(&self) -> bool {
+        true
+    }
+}
+
+/// An `Action` for giving a role to a user in addition to the roles they
+/// hold. The user is then authorized for the actions of any of their roles.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AddUserRoleAction {
+    pub user_id: String,
+    pub role_name: String,
+    pub metadata: ActionMetadata,
+}
+
+impl Action for AddUserRoleAction {
+    fn execute(&self, client_data: &mut (impl ClientDataProvider + ?Sized)) {
+        let comm_grp = &self.metadata.community_group_id;
+        if !client_data
+            .get_roles(comm_grp)
+            .role_defs
+            .contains_key(&self.role_name)
+        {
+            info!("Not adding {} as it is not a role", self.role_name);
+            return;
+        }
+        client_data.add_user_role(
+            comm_grp,
+            self.user_id.to_string(),
+            self.role_name.to_string(),
+        )
+    }
+
+    fn get_metadata(&self) -> ActionMetadata {
+        self.metadata.clone()
+    }
+
+    fn is_ordered(&self) -> bool {
+        true
+    }
+}
+
+/// An `Action` for taking a role away from a user, keeping the other roles
+/// they hold
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RemoveUserRoleAction {
+    pub user_id: String,
+    pub role_name: String,
+    pub metadata: ActionMetadata,
+}
+
+impl Action for RemoveUserRoleAction {
+    fn execute(&self, client_data: &mut (impl ClientDataProvider + ?Sized)) {
+        client_data.remove_user_role(
+            &self.metadata.community_group_id,
+            self.user_id.to_string(),
+            self.role_name.to_string(),
+        )
+    }
+
+    fn get_metadata(&self) -> ActionMetadata {
+        self.metadata.clone()
+    }
+
+    fn is_ordered(&self) -> bool {
+        true
+    }
+}
+
 /// Leave the group given in the metadata
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct LeaveAction {
@@ -484,6 +1127,7 @@ impl Action for LeaveAction {
     fn execute(&self, client_data: &mut (impl ClientDataProvider + ?Sized)) {
         client_data
             .store_to_be_removed_member(&self.metadata.community_group_id, &self.metadata.sender);
+        client_data.mark_member_left(&self.metadata.community_group_id, &self.metadata.sender);
         if self.metadata.sender == client_data.get_user_id() {
             info!("Leave Action was successful. You will still need to use Remove (Self) to remove your cryptographical info completely from the group and thus stop reiceiving messages");
         }
@@ -573,6 +1217,30 @@ impl Action for VoteAction {
     }
 }
 
+/// Closes the vote on a proposed action once its voting period elapsed, as
+/// of when this action was sent. Being ordered, every member closes the vote
+/// after the same votes
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClosePollAction {
+    /// The identifier of the proposed action whose vote closes
+    pub proposed_action_id: String,
+    /// The type of the proposed action, in order to aid with filtering
+    pub proposed_action_type: ActionType,
+    pub metadata: ActionMetadata,
+}
+
+impl Action for ClosePollAction {
+    fn execute(&self, _client_data: &mut (impl ClientDataProvider + ?Sized)) {}
+
+    fn get_metadata(&self) -> ActionMetadata {
+        self.metadata.clone()
+    }
+
+    fn is_ordered(&self) -> bool {
+        true
+    }
+}
+
 /// An action to aid with policies that deal with voting
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct GovStateAnnouncementAction {
@@ -588,33 +1256,26 @@ impl Action for GovStateAnnouncementAction {
                 "The previous group state was {:?}",
                 client_data.get_shared_state(&self.metadata.community_group_id)
             );
-            let mut hasher = DefaultHasher::new();
-            serde_json::to_string(&self.group_state)
-                .expect("Cannot serialize group state")
-                .hash(&mut hasher);
             client_data
                 .load_shared_state(&self.metadata.community_group_id, self.group_state.clone());
 
             client_data.set_shared_gov_state_init_hash(
                 &self.metadata.community_group_id,
-                Some(hasher.finish()),
+                Some(self.group_state.stable_hash()),
             );
+            client_data.store_gov_state_snapshot(&self.metadata.community_group_id);
 
             debug!(
                 "The current group state is now {:?}",
                 client_data.get_shared_state(&self.metadata.community_group_id)
             );
         } else {
-            let mut hasher = DefaultHasher::new();
-            serde_json::to_string(&self.group_state)
-                .expect("Cannot serialize group state")
-                .hash(&mut hasher);
             client_data
                 .load_shared_state(&self.metadata.community_group_id, self.group_state.clone());
             info!(
                 "Hash of the shared group state from {}: {:?}",
                 self.metadata.sender,
-                hasher.finish()
+                self.group_state.stable_hash()
             );
         }
     }
@@ -627,3 +1288,32 @@ impl Action for GovStateAnnouncementAction {
         false
     }
 }
+
+/// An action broadcasting the hash of the sender's group state, rather than
+/// the state itself, for receivers to detect a divergence from their own
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GovStateHashAction {
+    /// The `SharedGroupState::stable_hash` of the sender's group state
+    pub gov_state_hash: u64,
+    /// The MLS epoch the hash was computed at, as only states at the same
+    /// epoch are comparable
+    pub epoch: u64,
+    pub metadata: ActionMetadata,
+}
+
+impl Action for GovStateHashAction {
+    fn execute(&self, _client_data: &mut (impl ClientDataProvider + ?Sized)) {
+        debug!(
+            "{} announced the group state hash [{:?}] at epoch {}",
+            self.metadata.sender, self.gov_state_hash, self.epoch
+        );
+    }
+
+    fn get_metadata(&self) -> ActionMetadata {
+        self.metadata.clone()
+    }
+
+    fn is_ordered(&self) -> bool {
+        false
+    }
+}
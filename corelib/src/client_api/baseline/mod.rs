@@ -4,14 +4,16 @@
 //! Issue #168 documents the need to refactor this code so as not to
 //! heavily duplicate logic from client_api/mod.rs
 
-use std::ops::DerefMut;
+use std::ops::{Deref, DerefMut};
 use std::str::from_utf8;
-use std::time::SystemTime;
 
+use ed25519_dalek::Signature;
 use log::*;
 use openmls::framing::{MlsMessageIn, MlsMessageOut, ProcessedMessage};
 use openmls::prelude::StagedCommit;
 
+#[cfg(feature = "signed_baseline")]
+use crate::client_api::actions::VerifiableAction;
 use crate::client_api::actions::{Action, ActionMsg};
 use crate::client_api::client_crypto_impl::CryptoBackend;
 use crate::client_api::client_err::ClientError;
@@ -33,7 +35,7 @@ pub fn action_msg_to_group_msg_unchecked(
     client_data: &mut (impl ClientDataProvider + ?Sized),
     ordered: bool,
 ) -> GroupMessage {
-    let bytes = encode_to_bytes(&action_msg);
+    let bytes = encode_action(&action_msg, &*client_data);
 
     bytes_to_group_message(
         &client_data.get_user_id(),
@@ -57,6 +59,7 @@ pub fn accept_msg(
             client_data.get_user_id(),
             Uuid::new_v4().to_string(),
             comm_grp.clone(),
+            client_data.now(),
         ),
         received_gov_state_hash: 0,
     });
@@ -91,8 +94,9 @@ pub fn check_action_msg_and_get_mls(
     } else {
         let private_message = UnorderedPrivateMessage {
             sender: client_data.get_user_id(),
-            content: UnorderedMsgContent::UnsignedAction { action: action_msg },
-            sender_timestamp: SystemTime::now(),
+            content: unordered_action_content(action_msg, &*client_data),
+            sender_timestamp: client_data.now(),
+            seq: client_data.next_msg_seq(comm_grp),
         };
         let encoded = private_message.to_bytes();
 
@@ -113,6 +117,7 @@ pub fn check_action_msg_and_get_mls(
             user_msg: private_msg,
             recipients,
             identifier: Some(comm_grp.get_string()),
+            ephemeral: None,
         }
         .to_vec()
     }
@@ -122,9 +127,16 @@ pub fn parse_mls_message_out(
     mls_msg: MlsMessageOut,
     comm_grp: &CommGroupId,
     sender: Option<String>,
+    tag: Option<&Signature>,
     client_data: &mut ClientRef,
     backend: &mut CryptoBackend,
 ) -> Vec<ClientParsedMsg> {
+    if let Err(invalid) =
+        check_group_msg_tag(&mls_msg, comm_grp, sender.as_ref(), tag, &**client_data)
+    {
+        error!("{:?}", invalid);
+        return vec![invalid];
+    }
     let mls_msg = MlsMessageIn::from(mls_msg);
     let mut local_plain_msgs = vec![];
     let own_user_name = client_data.get_user_id();
@@ -229,7 +241,7 @@ pub fn parse_mls_message_out(
                             // Message from other group member | Correct epoch | Unordered
                             debug!("Message from other group member | Correct epoch | Unordered");
                             let bytes = app_msg_mls.into_bytes();
-                            let mut private_msg: UnorderedPrivateMessage =
+                            let private_msg: UnorderedPrivateMessage =
                                 serde_json::from_slice(&bytes).unwrap();
                             debug!("Decrypted Message from bytes: {:?}", from_utf8(&bytes));
 
@@ -238,16 +250,10 @@ pub fn parse_mls_message_out(
                                 comm_grp: comm_grp.clone(),
                             });
 
-                            //TODO use match instead
-                            if let UnorderedMsgContent::UnsignedAction { action } =
-                                &mut private_msg.content
+                            if let Some(action) =
+                                received_unordered_action(&private_msg.content, client_data.deref())
                             {
-                                policy_check_and_execute(
-                                    action.clone(),
-                                    &comm_grp,
-                                    None,
-                                    client_data,
-                                );
+                                policy_check_and_execute(action, &comm_grp, None, client_data);
                             }
                         }
                         ProcessedMessage::ProposalMessage(proposal_wrapped) => mls_group_ref
@@ -335,7 +341,7 @@ pub fn parse_mls_message_out(
                                 // Message from other group member | Correct epoch | HandShake | Exists Actions
                                 // Only process one/first ordered action at one commit to avoid committing half a commit
                                 for bytes in &ord_app_msgs {
-                                    let action_opt: Option<ActionMsg> = decode_from_bytes(bytes);
+                                    let action_opt = decode_action(bytes, client_data.deref());
                                     if let Some(action) = action_opt {
                                         policy_check_and_execute(
                                             action,
@@ -356,6 +362,123 @@ pub fn parse_mls_message_out(
     local_plain_msgs
 }
 
+/// Encodes an action sent within a commit
+#[cfg(not(feature = "signed_baseline"))]
+fn encode_action(
+    action_msg: &ActionMsg,
+    _client_data: &(impl ClientDataProvider + ?Sized),
+) -> Vec<u8> {
+    encode_to_bytes(action_msg)
+}
+
+/// Encodes an action sent within a commit, signed by this client
+#[cfg(feature = "signed_baseline")]
+fn encode_action(
+    action_msg: &ActionMsg,
+    client_data: &(impl ClientDataProvider + ?Sized),
+) -> Vec<u8> {
+    encode_to_bytes(&VerifiableAction::new(
+        action_msg.clone(),
+        client_data.get_keypair(),
+    ))
+}
+
+/// Decodes an action received within a commit
+#[cfg(not(feature = "signed_baseline"))]
+pub(crate) fn decode_action(
+    bytes: &[u8],
+    _client_data: &(impl ClientDataProvider + ?Sized),
+) -> Option<ActionMsg> {
+    decode_from_bytes(bytes)
+}
+
+/// Decodes an action received within a commit, if its signature is valid
+#[cfg(feature = "signed_baseline")]
+pub(crate) fn decode_action(
+    bytes: &[u8],
+    client_data: &(impl ClientDataProvider + ?Sized),
+) -> Option<ActionMsg> {
+    let verif_action: VerifiableAction = decode_from_bytes(bytes)?;
+    verify_action(&verif_action, client_data)
+}
+
+/// The content of an unordered message carrying an action
+#[cfg(not(feature = "signed_baseline"))]
+fn unordered_action_content(
+    action_msg: ActionMsg,
+    _client_data: &(impl ClientDataProvider + ?Sized),
+) -> UnorderedMsgContent {
+    UnorderedMsgContent::UnsignedAction { action: action_msg }
+}
+
+/// The content of an unordered message carrying an action, signed by this
+/// client as in the governance mode
+#[cfg(feature = "signed_baseline")]
+fn unordered_action_content(
+    action_msg: ActionMsg,
+    client_data: &(impl ClientDataProvider + ?Sized),
+) -> UnorderedMsgContent {
+    UnorderedMsgContent::TextAction {
+        text_action: VerifiableAction::new(action_msg, client_data.get_keypair()),
+    }
+}
+
+/// The action carried by a received unordered message, if any
+#[cfg(not(feature = "signed_baseline"))]
+fn received_unordered_action(
+    content: &UnorderedMsgContent,
+    _client_data: &(impl ClientDataProvider + ?Sized),
+) -> Option<ActionMsg> {
+    match content {
+        UnorderedMsgContent::UnsignedAction { action } => Some(action.clone()),
+        _ => None,
+    }
+}
+
+/// The action carried by a received unordered message, if any and if its
+/// signature is valid. Unsigned actions are ignored.
+#[cfg(feature = "signed_baseline")]
+fn received_unordered_action(
+    content: &UnorderedMsgContent,
+    client_data: &(impl ClientDataProvider + ?Sized),
+) -> Option<ActionMsg> {
+    match content {
+        UnorderedMsgContent::TextAction { text_action } => verify_action(text_action, client_data),
+        UnorderedMsgContent::UnsignedAction { action } => {
+            warn!(
+                "Ignoring an unsigned action of type {:?}",
+                action.action_type()
+            );
+            None
+        }
+        _ => None,
+    }
+}
+
+/// Returns the action if it is signed with the verification key the AS
+/// holds for its sender
+#[cfg(feature = "signed_baseline")]
+pub(crate) fn verify_action(
+    verif_action: &VerifiableAction,
+    client_data: &(impl ClientDataProvider + ?Sized),
+) -> Option<ActionMsg> {
+    let sender = verif_action.action.get_metadata().sender;
+    match client_data.get_user_verify_key(&sender) {
+        Some(public_key) if verif_action.verify(public_key) => Some(verif_action.action.clone()),
+        Some(_) => {
+            warn!("Ignoring an action with an invalid signature of {}", sender);
+            None
+        }
+        None => {
+            warn!(
+                "Ignoring an action of {}, whose verification key is unknown",
+                sender
+            );
+            None
+        }
+    }
+}
+
 /// Unconditionally performs action
 pub fn policy_check_and_execute(
     action: ActionMsg,
@@ -400,24 +523,28 @@ pub(crate) fn try_activate_gov_state(
 
     // Init shared gov state by execute all non-handshake, same-epoch messages.
     for msg in &unprocessed_msgs {
-        match msg {
-            OnWireMessage::DSRelayedUserMsg { user_msg, .. } => match user_msg {
+        if let OnWireMessage::DSRelayedUserMsg {
+            user_msg:
                 GroupMessage::AppMlsMessage {
-                    mls_msg, sender, ..
-                } => {
-                    if (!mls_msg.is_handshake_message()) && mls_msg.epoch() == welcome_epoch {
-                        parse_mls_message_out(
-                            mls_msg.clone(),
-                            comm_grp,
-                            sender.clone(),
-                            client_data,
-                            backend,
-                        );
-                        processed_msgs.push(msg.to_owned());
-                    }
-                }
-            },
-            _ => (),
+                    mls_msg,
+                    sender,
+                    tag,
+                    ..
+                },
+            ..
+        } = msg
+        {
+            if (!mls_msg.is_handshake_message()) && mls_msg.epoch() == welcome_epoch {
+                parse_mls_message_out(
+                    mls_msg.clone(),
+                    comm_grp,
+                    sender.clone(),
+                    tag.as_ref(),
+                    client_data,
+                    backend,
+                );
+                processed_msgs.push(msg.to_owned());
+            }
         }
     }
 
@@ -0,0 +1,4798 @@
+#[cfg(test)]
+mod client_api_tests {
+    use std::collections::BTreeMap;
+    #[cfg(feature = "gov")]
+    use std::collections::BTreeSet;
+    #[cfg(feature = "gov")]
+    use std::ops::DerefMut;
+    use std::sync::Arc;
+    #[cfg(feature = "gov")]
+    use std::time::{Duration, SystemTime};
+
+    #[cfg(feature = "gov")]
+    use crate::client_api::actions::{
+        Action, ActionMetadata, ActionMsg, ActionType, AddUserRoleAction, BanAction, CustomAction,
+        CustomActionHandler, DefRoleAction, FlagMessageAction, GovStateHashAction, KickAction,
+        RenameGroupAction, ReportAction, SetFlagThresholdAction, SetMaxGroupSizeAction,
+        SetTopicGroupAction, SetUserRoleAction, TextMsgAction, UnbanAction, VerifiableAction,
+        VoteAction,
+    };
+    #[cfg(feature = "signed_baseline")]
+    use crate::client_api::actions::{ActionMetadata, ActionMsg, TextMsgAction, VerifiableAction};
+    #[cfg(feature = "signed_baseline")]
+    use crate::client_api::baseline::{decode_action, verify_action};
+    #[cfg(feature = "gov")]
+    use crate::client_api::client_crypto_impl::DEFAULT_KEY_PACKAGE_LIFETIME;
+    #[cfg(feature = "gov")]
+    use crate::client_api::client_err::ClientError;
+    #[cfg(feature = "gov")]
+    use crate::client_api::client_err::HistoryExportError;
+    use crate::client_api::client_struct::ClientDataProvider;
+    #[cfg(feature = "gov")]
+    use crate::client_api::client_struct::{
+        ClientEventSink, ClientParsedMsg, GroupRoleConfig, IntegrityDiscrepancy, RbacState,
+        SharedGroupState,
+    };
+    #[cfg(feature = "gov")]
+    use crate::client_api::client_struct_impl::{
+        ClientData, FullAccessClientData, LocalHistoryMessage, LocalWelcome,
+        MAX_POLICY_ACTION_LOG_LEN, MAX_SEALED_SENT_MSG_IDS,
+    };
+    #[cfg(feature = "gov")]
+    use crate::client_api::{
+        accept_msg, accepted_invite_msg, add_msg, admin_revert_msg, answer_gov_state_requests,
+        apply_loaded_prefs, check_action_msg_and_get_mls, check_gov_state_hash, commit_actions,
+        create_dm, create_group_msg, create_group_with_roles_msg, ephemeral_msg, export_gov_state,
+        flag_check_and_execute, gov_state_hash_msgs, import_gov_state, invite_users, list_flagged,
+        list_reports, load_prefs_msg, mute, outbox_msgs, parse_incoming_onwire_msgs_with_sink,
+        parse_mls_message_out, policy_check_and_execute, poll_pending, pre_add_invite_msg,
+        pre_decline_msg, pre_kick_msg, pre_leave_msg, prefs_key, queue_expired_poll_closes,
+        record_unsent_msgs, rejoin_group_msg, remove_members_msg, remove_other_or_self_msg,
+        remove_revoked_members_msgs, remove_role_msg, remove_self_on_decline, rename_group_msg,
+        replay_onwire_log, replay_policies, request_gov_state_msg, rotate_key_msg, send_dm,
+        send_group_state_update, send_text_msg_mls, set_roles_bulk_msg, store_prefs_msg, try_sync,
+        unmute, verify_group_integrity, verify_history_export, votes_needed,
+    };
+    use crate::client_api::{
+        credentials_from_chunks, group_info, key_packages_from_chunks, key_packages_to_replenish,
+        register_msg_as, register_msg_as_with_display_name, register_msg_as_with_endorsement,
+        register_msg_ds, revoke_credential_msg, sync_msg, verify_as_credentials, whoami_msg,
+    };
+    #[cfg(feature = "gov")]
+    use crate::clock::{Clock, MockClock, SharedClock};
+    #[cfg(feature = "signed_baseline")]
+    use crate::messages::encode_to_bytes;
+    #[cfg(feature = "gov")]
+    use crate::messages::{
+        rotate_key_request_content, EphemeralKind, GroupMessage, OnWireMessageWithMetaData,
+        PendingCounts, UnorderedMsgContent, UnorderedPrivateMessage,
+    };
+    use crate::messages::{OnWireMessage, UserRequestErrors};
+    #[cfg(feature = "gov")]
+    use crate::policyengine::policies::{
+        AutoModerationPolicy, PassAllPolicy, RateLimitPolicy, TimedVotePolicy,
+        VoteOnNameChangePolicy,
+    };
+    #[cfg(feature = "gov")]
+    use crate::policyengine::VOTES_UNREACHABLE;
+    use crate::servers_api::as_struct::AuthServiceState;
+    #[cfg(feature = "gov")]
+    use crate::servers_api::ds_structs::local_message_struct::{
+        Invite, ProtectedMessageWithMetaData,
+    };
+    use crate::servers_api::ds_structs::DeliveryServiceState;
+    use crate::servers_api::handle_onwire_msg_as_local;
+    #[cfg(feature = "gov")]
+    use crate::servers_api::handle_onwire_msg_ds_local;
+    #[cfg(feature = "gov")]
+    use crate::servers_api::MAX_LOOKUP_RESULTS_PER_CHUNK;
+    #[cfg(feature = "gov")]
+    use crate::state_store::{decode_versioned, VersionedState};
+    use crate::test_helpers::*;
+    use crate::verification_key_fingerprint;
+    #[cfg(feature = "gov")]
+    use crate::{generate_verification_key, identity_to_str, identity_to_user_device};
+    #[cfg(feature = "gov")]
+    use crate::{CommGroupId, IdError};
+    #[cfg(feature = "gov")]
+    use ed25519_dalek::Signer;
+    #[cfg(feature = "gov")]
+    use openmls::extensions::{Extension, LifetimeExtension};
+    #[cfg(feature = "gov")]
+    use openmls::messages::Welcome;
+    use openmls::prelude::SignatureScheme;
+    #[cfg(feature = "gov")]
+    use openmls::prelude::{KeyPackage, Proposal};
+    #[cfg(feature = "gov")]
+    use tls_codec::{Deserialize as _, Serialize as _};
+
+    #[actix_rt::test]
+    /// After a sync reports a full key package store on the DS,
+    /// the next sync should not upload any new key packages
+    async fn test_sync_tops_up_key_packages_to_target() {
+        let ds_state = Arc::new(DeliveryServiceState::new());
+        let mut alice = TestClientBundle::new("alice");
+        let target = 5;
+
+        // Register on DS with a full store
+        let kps = alice.new_kps(target as u64);
+        alice
+            .send_all_assert_ok(register_msg_ds(kps), &ds_state)
+            .await;
+
+        // Nothing reported yet, so the client falls back to the full target
+        assert_eq!(
+            key_packages_to_replenish(alice.configs.as_ref(), target),
+            target
+        );
+
+        // Sync without new key packages; DS reports what it holds
+        let first_sync = sync_msg(alice.configs.as_ref(), vec![]);
+        alice.send_assert_ok(first_sync, &ds_state).await;
+        assert_eq!(alice.configs.get_remaining_key_packages(), Some(target));
+        assert_eq!(ds_state.count_key_packages(&alice.name()), target);
+
+        // The next sync uploads zero packages
+        let n_new = key_packages_to_replenish(alice.configs.as_ref(), target);
+        assert_eq!(n_new, 0);
+        let kps = alice.new_kps(n_new as u64);
+        let second_sync = sync_msg(alice.configs.as_ref(), kps);
+        alice.send_assert_ok(second_sync, &ds_state).await;
+        assert_eq!(ds_state.count_key_packages(&alice.name()), target);
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// Once an operator has the AS revoke a member's credential, an AS sync
+    /// should lead the admin to propose removing that member once, while the
+    /// revoked member itself proposes nothing
+    async fn test_revoked_member_removal_proposed() {
+        let mut as_state = AuthServiceState::new();
+        as_state.admin_credential = Some("secret".to_string());
+        let (as_state, _, mut admin, mut members) = setup_registered_group_with_assert_ok(
+            as_state,
+            DeliveryServiceState::new(),
+            TestClientBundle::new("admin"),
+            vec![TestClientBundle::new("bob")],
+        )
+        .await;
+
+        let revoke = |admin_credential: &str| revoke_credential_msg(admin_credential, "bob");
+        let responses = handle_onwire_msg_as_local(revoke("guess"), &as_state).await;
+        assert!(matches!(
+            &responses[..],
+            [OnWireMessage::ASResult { request_valid: false, explanation: Some(explanation), .. }]
+                if explanation == &UserRequestErrors::NotAdmin.to_string()
+        ));
+        admin.sync_as_assert_ok(&as_state).await;
+        assert!(!admin.configs.is_credential_revoked("bob"));
+
+        let responses = handle_onwire_msg_as_local(revoke("secret"), &as_state).await;
+        assert!(matches!(
+            &responses[..],
+            [OnWireMessage::ASResult {
+                request_valid: true,
+                ..
+            }]
+        ));
+        admin.sync_as_assert_ok(&as_state).await;
+        assert!(admin.configs.is_credential_revoked("bob"));
+
+        let removal_msgs =
+            remove_revoked_members_msgs(&mut admin.backend, admin.configs.deref_mut());
+        assert_eq!(removal_msgs.len(), 1);
+        // The removal is pending, so it is not proposed again at the next sync
+        assert!(
+            remove_revoked_members_msgs(&mut admin.backend, admin.configs.deref_mut()).is_empty()
+        );
+        match admin.configs.pop_pending_action(&comm_grp()) {
+            Some(ActionMsg::Kick(kick_action)) => assert_eq!(kick_action.target_user_id, "bob"),
+            other => panic!("Expected a pending kick of bob, found {:?}", other),
+        }
+
+        // The revoked member does not propose anything itself
+        let bob = &mut members[0];
+        bob.sync_as_assert_ok(&as_state).await;
+        assert!(remove_revoked_members_msgs(&mut bob.backend, bob.configs.deref_mut()).is_empty());
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// A credential sync missing some users should keep the credentials of
+    /// group members, but not those of other users, whose revoked credentials
+    /// are only kept if requested
+    async fn test_partial_credential_sync_keeps_members() {
+        let (as_state, ds_state, mut admin, _) =
+            setup_registered_group_assert_ok("admin", &["bob"]).await;
+        let mut dave = TestClientBundle::new("dave");
+        dave.register_assert_ok(&as_state, &ds_state).await;
+        admin.sync_as_assert_ok(&as_state).await;
+        assert!(admin.configs.get_user_verify_key("bob").is_some());
+        assert!(admin.configs.get_user_verify_key("dave").is_some());
+
+        let mut partial = admin
+            .configs
+            .as_any()
+            .downcast_ref::<ClientData>()
+            .unwrap()
+            .credential_entries
+            .clone();
+        partial.retain(|user_name, _| user_name == "admin");
+        admin.configs.merge_credential_entries(partial, false);
+        assert!(admin.configs.get_user_verify_key("admin").is_some());
+        assert!(admin.configs.get_user_verify_key("bob").is_some());
+        assert!(admin.configs.get_user_verify_key("dave").is_none());
+
+        assert!(as_state.revoke_credential("dave"));
+        let full = match &handle_onwire_msg_as_local(OnWireMessage::UserSyncCredentials, &as_state)
+            .await[0]
+        {
+            OnWireMessage::ASCredentialSyncResponse { credentials } => credentials.clone(),
+            other => panic!("Expected the credentials, found {:?}", other),
+        };
+        admin.configs.merge_credential_entries(full.clone(), false);
+        assert!(admin.configs.is_credential_revoked("dave"));
+        admin.configs.merge_credential_entries(full, true);
+        assert!(admin.configs.get_user_verify_key("dave").is_none());
+        assert!(admin.configs.get_user_verify_key("bob").is_some());
+    }
+
+    #[cfg(feature = "gov")]
+    #[test]
+    /// A rename that passed under a permissive policy should be flagged
+    /// by the replay once renames require a vote
+    fn test_replay_flags_action_under_tightened_policy() {
+        let mut alice = TestClientBundle::new("alice");
+        alice
+            .configs
+            .set_client_policies(vec![Box::new(PassAllPolicy {})]);
+        let _ = create_group_msg(
+            &alice.name(),
+            &comm_grp(),
+            &mut alice.backend,
+            alice.configs.deref_mut(),
+        );
+
+        // Bob is a BaseUser, so his rename is evaluated by the policy engine
+        let rename_action = ActionMsg::RenameGroup(RenameGroupAction {
+            new_name: "new name".to_string(),
+            lamport_counter: 1,
+            metadata: ActionMetadata::new(
+                "bob".to_string(),
+                "rename_action_id".to_string(),
+                comm_grp(),
+                SystemTime::now(),
+            ),
+        });
+        policy_check_and_execute(rename_action.clone(), &comm_grp(), None, &mut alice.configs);
+        assert_eq!(alice.configs.get_group_name(&comm_grp()), "new name");
+        assert!(replay_policies(&comm_grp(), alice.configs.as_ref())
+            .unwrap()
+            .is_empty());
+
+        // Renames now require a vote
+        alice
+            .configs
+            .set_client_policies(vec![Box::new(VoteOnNameChangePolicy::new())]);
+        assert_eq!(
+            replay_policies(&comm_grp(), alice.configs.as_ref()).unwrap(),
+            vec![rename_action]
+        );
+        // The replay leaves the actual group state untouched
+        assert_eq!(alice.configs.get_group_name(&comm_grp()), "new name");
+    }
+
+    #[cfg(feature = "gov")]
+    #[test]
+    /// The policy action log should leave out the actions the policy engine
+    /// rejected, those no policy decided on and disappearing text messages,
+    /// and only keep the latest actions
+    fn test_policy_action_log_skips_rejected_and_disappearing() {
+        let mut alice = TestClientBundle::new("alice");
+        alice
+            .configs
+            .set_client_policies(vec![Box::new(RateLimitPolicy::new(
+                vec![ActionType::TextMsg],
+                1,
+                Duration::from_secs(60),
+            ))]);
+        let _ = create_group_msg(
+            &alice.name(),
+            &comm_grp(),
+            &mut alice.backend,
+            alice.configs.deref_mut(),
+        );
+
+        let text_msg = |sender: &str, action_id: &str, expires_after: Option<Duration>| {
+            ActionMsg::TextMsg(TextMsgAction {
+                msg: "hello".to_string(),
+                expires_after,
+                in_reply_to: None,
+                metadata: ActionMetadata::new(
+                    sender.to_string(),
+                    action_id.to_string(),
+                    comm_grp(),
+                    SystemTime::now(),
+                ),
+            })
+        };
+        let first = text_msg("bob", "first", None);
+        policy_check_and_execute(first.clone(), &comm_grp(), None, &mut alice.configs);
+        // Rate limited, so rejected
+        let second = text_msg("bob", "second", None);
+        policy_check_and_execute(second, &comm_grp(), None, &mut alice.configs);
+        // Passed, but disappears
+        let disappearing = text_msg("charlie", "disappearing", Some(Duration::from_secs(60)));
+        policy_check_and_execute(disappearing, &comm_grp(), None, &mut alice.configs);
+        // Not rate limited, so no policy decides on it
+        let rename = ActionMsg::RenameGroup(RenameGroupAction {
+            new_name: "new name".to_string(),
+            lamport_counter: 1,
+            metadata: ActionMetadata::new(
+                "bob".to_string(),
+                "rename".to_string(),
+                comm_grp(),
+                SystemTime::now(),
+            ),
+        });
+        policy_check_and_execute(rename, &comm_grp(), None, &mut alice.configs);
+        assert_eq!(
+            alice.configs.get_policy_action_log(&comm_grp()),
+            vec![first.clone()]
+        );
+
+        for i in 0..MAX_POLICY_ACTION_LOG_LEN {
+            alice
+                .configs
+                .log_policy_action(&comm_grp(), text_msg("bob", &i.to_string(), None));
+        }
+        let policy_action_log = alice.configs.get_policy_action_log(&comm_grp());
+        assert_eq!(policy_action_log.len(), MAX_POLICY_ACTION_LOG_LEN);
+        assert!(!policy_action_log.contains(&first));
+    }
+
+    #[cfg(feature = "gov")]
+    #[test]
+    /// A text message matching a blocked pattern should be let through, with
+    /// a takedown of it queued and sent on behalf of the local user, if they
+    /// are the member designated to take messages down
+    fn test_auto_moderation_takes_down_blocked_msg() {
+        let mut alice = TestClientBundle::new("alice");
+        alice
+            .configs
+            .set_client_policies(vec![Box::new(AutoModerationPolicy::new(vec![
+                "(?i)\\bspam\\b".to_string(),
+                "[".to_string(),
+            ]))]);
+        let _ = create_group_msg(
+            &alice.name(),
+            &comm_grp(),
+            &mut alice.backend,
+            alice.configs.deref_mut(),
+        );
+
+        // Bob holds no role, so his messages are evaluated by the policy engine
+        for (action_id, msg) in [("clean_msg_id", "hello"), ("spam_msg_id", "Buy SPAM now")] {
+            let text_action = ActionMsg::TextMsg(TextMsgAction {
+                msg: msg.to_string(),
+                expires_after: None,
+                in_reply_to: None,
+                metadata: ActionMetadata::new(
+                    "bob".to_string(),
+                    action_id.to_string(),
+                    comm_grp(),
+                    SystemTime::now(),
+                ),
+            });
+            policy_check_and_execute(text_action, &comm_grp(), None, &mut alice.configs);
+        }
+
+        let outbox = alice.configs.take_outbox_actions();
+        match outbox.as_slice() {
+            [(outbox_grp, ActionMsg::TakedownTextMsg(takedown))] => {
+                assert_eq!(outbox_grp, &comm_grp());
+                assert_eq!(takedown.message_id, "spam_msg_id");
+                assert_eq!(takedown.metadata.sender, alice.name());
+            }
+            _ => panic!("Expected a single takedown, got {:?}", outbox),
+        }
+
+        alice
+            .configs
+            .push_outbox_action(&comm_grp(), outbox[0].1.clone());
+        assert!(!outbox_msgs(&mut alice.backend, alice.configs.deref_mut()).is_empty());
+        assert!(alice.configs.take_outbox_actions().is_empty());
+
+        // Members not allowed to take messages down leave it to another member
+        alice
+            .configs
+            .set_user_role(&comm_grp(), alice.name(), "BaseUser".to_string());
+        let text_action = ActionMsg::TextMsg(TextMsgAction {
+            msg: "more spam".to_string(),
+            expires_after: None,
+            in_reply_to: None,
+            metadata: ActionMetadata::new(
+                "bob".to_string(),
+                "spam_2".to_string(),
+                comm_grp(),
+                SystemTime::now(),
+            ),
+        });
+        policy_check_and_execute(text_action, &comm_grp(), None, &mut alice.configs);
+        assert!(alice.configs.take_outbox_actions().is_empty());
+    }
+
+    #[cfg(feature = "gov")]
+    #[test]
+    /// Empty IDs and IDs with characters that are not URL-safe should be
+    /// rejected, also when creating a group
+    fn test_comm_grp_id_validation() {
+        assert_eq!(CommGroupId::try_new("", "group"), Err(IdError::Empty));
+        assert_eq!(CommGroupId::try_new("community", ""), Err(IdError::Empty));
+        assert_eq!(
+            CommGroupId::try_new("community", " "),
+            Err(IdError::UnsafeCharacter(' '))
+        );
+        assert_eq!(
+            CommGroupId::try_new("my community", "group"),
+            Err(IdError::UnsafeCharacter(' '))
+        );
+        assert_eq!(
+            CommGroupId::try_new("community", "group\n"),
+            Err(IdError::UnsafeCharacter('\n'))
+        );
+        assert_eq!(
+            CommGroupId::try_new("community", "group/1"),
+            Err(IdError::UnsafeCharacter('/'))
+        );
+        assert_eq!(CommGroupId::try_new("community", "group"), Ok(comm_grp()));
+        assert!(CommGroupId::try_new("my-community_2", "v1.0~draft").is_ok());
+        let dm_grp = CommGroupId::dm("alice", "bob");
+        assert_eq!(
+            CommGroupId::try_new(&dm_grp.community_id(), &dm_grp.group_id()),
+            Ok(dm_grp)
+        );
+
+        // IDs stored before validation are only rejected when creating a group
+        let mut alice = TestClientBundle::new("alice");
+        let blank_grp = CommGroupId::from_string(r#"{"community_id":"community","group_id":" "}"#);
+        assert_eq!(
+            create_group_msg(
+                &alice.name(),
+                &blank_grp,
+                &mut alice.backend,
+                alice.configs.deref_mut(),
+            ),
+            Err(IdError::UnsafeCharacter(' '))
+        );
+        assert!(alice.configs.get_ref_group(&blank_grp).is_none());
+        assert!(create_group_msg(
+            &alice.name(),
+            &comm_grp(),
+            &mut alice.backend,
+            alice.configs.deref_mut(),
+        )
+        .is_ok());
+        assert!(alice.configs.get_ref_group(&comm_grp()).is_some());
+    }
+
+    #[cfg(feature = "gov")]
+    #[test]
+    /// Whether a user may perform a type of action should follow their role,
+    /// with users without a role being `BaseUser`s
+    fn test_can_perform_follows_roles() {
+        let mut admin = TestClientBundle::new("admin");
+        let _ = create_group_msg(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        admin
+            .configs
+            .set_user_role(&comm_grp(), "bob".to_string(), "BaseUser".to_string());
+
+        assert!(admin
+            .configs
+            .can_perform(&comm_grp(), ActionType::Kick, "admin"));
+        assert!(!admin
+            .configs
+            .can_perform(&comm_grp(), ActionType::Kick, "bob"));
+        assert!(admin
+            .configs
+            .can_perform(&comm_grp(), ActionType::TextMsg, "bob"));
+        assert!(!admin
+            .configs
+            .can_perform(&comm_grp(), ActionType::Kick, "eve"));
+        let unknown_grp = CommGroupId::new(&"community".to_string(), &"unknown".to_string());
+        assert!(!admin
+            .configs
+            .can_perform(&unknown_grp, ActionType::TextMsg, "admin"));
+    }
+
+    #[cfg(feature = "gov")]
+    #[test]
+    /// Concurrent renames and topic changes should converge on the same
+    /// value regardless of the order in which they are executed
+    fn test_concurrent_renames_converge() {
+        let mut alice = TestClientBundle::new("alice");
+        let mut bob = TestClientBundle::new("bob");
+        for bundle in [&mut alice, &mut bob] {
+            let _ = create_group_msg(
+                &bundle.name(),
+                &comm_grp(),
+                &mut bundle.backend,
+                bundle.configs.deref_mut(),
+            );
+        }
+
+        let rename = |sender: &str, new_name: &str, lamport_counter: u64| RenameGroupAction {
+            new_name: new_name.to_string(),
+            lamport_counter,
+            metadata: ActionMetadata::new(
+                sender.to_string(),
+                "".to_string(),
+                comm_grp(),
+                SystemTime::now(),
+            ),
+        };
+        let alice_rename = rename("alice", "alice's name", 1);
+        let bob_rename = rename("bob", "bob's name", 1);
+
+        alice_rename.execute(alice.configs.deref_mut());
+        bob_rename.execute(alice.configs.deref_mut());
+        bob_rename.execute(bob.configs.deref_mut());
+        alice_rename.execute(bob.configs.deref_mut());
+
+        for bundle in [&alice, &bob] {
+            assert_eq!(bundle.configs.get_group_name(&comm_grp()), "bob's name");
+            assert_eq!(
+                bundle.configs.get_group_name_version(&comm_grp()),
+                (1, "bob".to_string())
+            );
+        }
+
+        // A rename that did not observe the current name is ignored
+        rename("alice", "stale name", 1).execute(alice.configs.deref_mut());
+        assert_eq!(alice.configs.get_group_name(&comm_grp()), "bob's name");
+        rename("alice", "newer name", 2).execute(alice.configs.deref_mut());
+        assert_eq!(alice.configs.get_group_name(&comm_grp()), "newer name");
+
+        // Topics are versioned independently of names
+        let set_topic = SetTopicGroupAction {
+            new_topic: "topic".to_string(),
+            lamport_counter: 1,
+            metadata: ActionMetadata::new(
+                "alice".to_string(),
+                "".to_string(),
+                comm_grp(),
+                SystemTime::now(),
+            ),
+        };
+        set_topic.execute(alice.configs.deref_mut());
+        assert_eq!(alice.configs.get_group_topic(&comm_grp()), "topic");
+        set_topic.execute(alice.configs.deref_mut());
+        assert_eq!(
+            alice.configs.get_group_topic_version(&comm_grp()),
+            (1, "alice".to_string())
+        );
+    }
+
+    #[cfg(feature = "gov")]
+    #[test]
+    /// Reports should be kept for review by moderators, but base users
+    /// should not be able to list them
+    fn test_reports_reviewable_by_mods_only() {
+        let mut alice = TestClientBundle::new("alice");
+        let mut bob = TestClientBundle::new("bob");
+        for bundle in [&mut alice, &mut bob] {
+            let _ = create_group_msg(
+                &bundle.name(),
+                &comm_grp(),
+                &mut bundle.backend,
+                bundle.configs.deref_mut(),
+            );
+        }
+        // Creators are Mods by default, so demote bob
+        bob.configs
+            .set_user_role(&comm_grp(), "bob".to_string(), "BaseUser".to_string());
+
+        let report = ReportAction {
+            ver_action_str: "reported action".to_string(),
+            reason: "spam".to_string(),
+            metadata: ActionMetadata::new(
+                "charlie".to_string(),
+                "".to_string(),
+                comm_grp(),
+                SystemTime::now(),
+            ),
+        };
+        report.execute(alice.configs.deref_mut());
+        report.execute(bob.configs.deref_mut());
+
+        let reports = list_reports(&comm_grp(), alice.configs.as_ref()).unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].reporter, "charlie");
+        assert_eq!(reports[0].reason, "spam");
+        assert_eq!(reports[0].reported_action, "reported action");
+
+        assert!(matches!(
+            list_reports(&comm_grp(), bob.configs.as_ref()),
+            Err(ClientError::Unauthorized(ActionType::ReviewReports))
+        ));
+        assert_eq!(bob.configs.get_reports(&comm_grp()).len(), 1);
+    }
+
+    #[cfg(feature = "gov")]
+    #[test]
+    /// A close of the vote on an action this client proposed should be
+    /// queued once the vote ended, and not again on every later sync
+    fn test_expired_poll_close_queued_once() {
+        let clock = MockClock::new(SystemTime::now());
+        let mut admin = TestClientBundle::new("admin").with_clock(SharedClock::new(clock.clone()));
+        admin
+            .configs
+            .set_client_policies(vec![Box::new(TimedVotePolicy::new(
+                vec![ActionType::RenameGroup],
+                Duration::from_secs(3600),
+            ))]);
+        let _ = create_group_msg(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        let rename = ActionMsg::RenameGroup(RenameGroupAction {
+            new_name: "new name".to_string(),
+            lamport_counter: 1,
+            metadata: ActionMetadata::new(
+                admin.name(),
+                "rename_action_id".to_string(),
+                comm_grp(),
+                clock.now(),
+            ),
+        });
+        admin
+            .configs
+            .get_policy_engine_ref_clone(&comm_grp())
+            .borrow_mut()
+            .evaluate_action(rename, &mut admin.configs);
+        queue_expired_poll_closes(&mut admin.configs);
+        assert!(admin.configs.take_outbox_actions().is_empty());
+
+        clock.advance(Duration::from_secs(3601));
+        queue_expired_poll_closes(&mut admin.configs);
+        queue_expired_poll_closes(&mut admin.configs);
+        let outbox = admin.configs.take_outbox_actions();
+        assert_eq!(outbox.len(), 1);
+        assert!(matches!(
+            &outbox[0],
+            (_, ActionMsg::ClosePoll(close)) if close.proposed_action_id == "rename_action_id"
+        ));
+    }
+
+    #[cfg(feature = "gov")]
+    #[test]
+    /// Flags should be counted per message, and only the member whose flag
+    /// reaches the group's threshold should queue a takedown of the message,
+    /// signed as its own
+    fn test_flags_propose_takedown() {
+        let mut alice = TestClientBundle::new("alice");
+        let _ = create_group_msg(
+            &alice.name(),
+            &comm_grp(),
+            &mut alice.backend,
+            alice.configs.deref_mut(),
+        );
+        ActionMsg::SetFlagThreshold(SetFlagThresholdAction {
+            threshold: Some(2),
+            metadata: ActionMetadata::new(
+                alice.name(),
+                "".to_string(),
+                comm_grp(),
+                SystemTime::now(),
+            ),
+        })
+        .execute(alice.configs.deref_mut());
+        assert_eq!(
+            alice
+                .configs
+                .get_shared_state(&comm_grp())
+                .flag_takedown_threshold,
+            Some(2)
+        );
+
+        let flag = |sender: &str, message_id: &str| FlagMessageAction {
+            message_id: message_id.to_string(),
+            metadata: ActionMetadata::new(
+                sender.to_string(),
+                "".to_string(),
+                comm_grp(),
+                SystemTime::now(),
+            ),
+        };
+        flag_check_and_execute(flag("bob", "spam"), &comm_grp(), &mut alice.configs);
+        flag_check_and_execute(flag("bob", "spam"), &comm_grp(), &mut alice.configs);
+        flag_check_and_execute(flag("bob", "other"), &comm_grp(), &mut alice.configs);
+        // A repeated flag by the same member does not count
+        assert!(alice.configs.take_outbox_actions().is_empty());
+
+        // The threshold is reached by charlie's flag, so left to charlie
+        flag_check_and_execute(flag("charlie", "spam"), &comm_grp(), &mut alice.configs);
+        assert!(alice.configs.take_outbox_actions().is_empty());
+        flag_check_and_execute(flag("alice", "other"), &comm_grp(), &mut alice.configs);
+        assert_eq!(
+            list_flagged(&comm_grp(), alice.configs.as_ref()).unwrap(),
+            vec![("other".to_string(), 2), ("spam".to_string(), 2)]
+        );
+
+        let outbox = alice.configs.take_outbox_actions();
+        assert_eq!(outbox.len(), 1);
+        match &outbox[0] {
+            (grp, ActionMsg::TakedownTextMsg(takedown)) => {
+                assert_eq!(grp, &comm_grp());
+                assert_eq!(takedown.message_id, "other");
+                assert_eq!(takedown.metadata.sender, "alice");
+            }
+            other => panic!("Expected a takedown, got {:?}", other),
+        }
+        // Nothing is taken down before the takedown is sent and authorized
+        assert!(alice.configs.get_policy_action_log(&comm_grp()).is_empty());
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// A joiner should be able to initialize its group state from a state
+    /// sent directly by a member, without any broadcast announcement
+    async fn test_gov_state_direct_transfer() {
+        let (as_state, ds_state, [mut admin, mut invitee]) =
+            register_clients_assert_ok(["admin", "invitee"]).await;
+        for bundle in [&mut admin, &mut invitee] {
+            bundle.sync_as_assert_ok(&as_state).await;
+        }
+
+        // Add the invitee without announcing the group state
+        let _ = create_group_msg(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        let invitee_kp = invitee.new_key_package();
+        let (invite_msgs, _) = pre_add_invite_msg(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+            vec![invitee_kp],
+        );
+        admin.send_all_assert_ok(invite_msgs, &ds_state).await;
+        let (add_msgs, _) = add_msg(
+            &comm_grp(),
+            &vec![invitee.name()],
+            admin.configs.deref_mut(),
+            &mut admin.backend,
+        );
+        admin.send_all_assert_ok(add_msgs, &ds_state).await;
+
+        invitee.sync_ds_assert_ok(&ds_state).await;
+        assert!(!invitee.configs.is_shared_gov_state_initialized(&comm_grp()));
+
+        let request_msgs = request_gov_state_msg(
+            &comm_grp(),
+            &admin.name(),
+            &mut invitee.backend,
+            invitee.configs.deref_mut(),
+        );
+        invitee.send_all_assert_ok(request_msgs, &ds_state).await;
+
+        // The admin answers the request it received on sync
+        let sync_responses =
+            handle_onwire_msg_ds_local(sync_msg(admin.configs.as_ref(), vec![]), &ds_state).await;
+        let parsed_msgs = admin.parse_msgs(&sync_responses);
+        let transfer_msgs =
+            answer_gov_state_requests(&parsed_msgs, &mut admin.backend, admin.configs.deref_mut());
+        assert_eq!(transfer_msgs.len(), 1);
+        admin.send_all_assert_ok(transfer_msgs, &ds_state).await;
+
+        invitee.sync_ds_assert_ok(&ds_state).await;
+        let accept_msgs = accept_msg(&comm_grp(), &mut invitee.backend, &mut invitee.configs);
+        assert!(invitee.configs.is_shared_gov_state_initialized(&comm_grp()));
+        assert_eq!(
+            invitee.configs.get_shared_state(&comm_grp()),
+            admin.configs.get_shared_state(&comm_grp())
+        );
+        invitee.send_all_assert_ok(accept_msgs, &ds_state).await;
+    }
+
+    #[cfg(feature = "gov")]
+    #[test]
+    /// With unique group names enforced, renaming or creating a group with
+    /// the name of a sibling group, regardless of case, should be rejected
+    /// when sent, while received renames apply regardless
+    fn test_unique_group_names_enforced() {
+        let mut alice = TestClientBundle::new("alice");
+        alice.configs.set_enforce_unique_group_names(true);
+        let sibling = CommGroupId::new(&comm_grp().community_id(), &"sibling".to_string());
+        for group in [comm_grp(), sibling.clone()] {
+            let _ = create_group_msg(
+                &alice.name(),
+                &group,
+                &mut alice.backend,
+                alice.configs.deref_mut(),
+            );
+        }
+        let rename = |group: &CommGroupId, new_name: &str| RenameGroupAction {
+            new_name: new_name.to_string(),
+            lamport_counter: 1,
+            metadata: ActionMetadata::new(
+                "alice".to_string(),
+                "".to_string(),
+                group.clone(),
+                SystemTime::now(),
+            ),
+        };
+
+        rename(&sibling, "Lobby").execute(alice.configs.deref_mut());
+        assert_eq!(alice.configs.get_group_name(&sibling), "Lobby");
+
+        assert!(rename_group_msg(
+            &comm_grp(),
+            "LOBBY",
+            &mut alice.backend,
+            alice.configs.deref_mut()
+        )
+        .is_empty());
+        assert!(!rename_group_msg(
+            &comm_grp(),
+            "Unique",
+            &mut alice.backend,
+            alice.configs.deref_mut()
+        )
+        .is_empty());
+
+        // Every member applies the renames they receive, whatever groups they know of
+        rename(&comm_grp(), "LOBBY").execute(alice.configs.deref_mut());
+        assert_eq!(alice.configs.get_group_name(&comm_grp()), "LOBBY");
+
+        // Creating a group named like a sibling is rejected as well
+        let duplicate = CommGroupId::new(&comm_grp().community_id(), &"lobby".to_string());
+        let _ = create_group_msg(
+            &alice.name(),
+            &duplicate,
+            &mut alice.backend,
+            alice.configs.deref_mut(),
+        );
+        assert!(!alice.configs.get_comm_grps().contains(&duplicate));
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// Looking up many users should return their credentials and key packages
+    /// in several chunks, reassembled in the order the users were queried
+    async fn test_large_lookup_is_chunked() {
+        let names: [String; 50] = std::array::from_fn(|i| format!("user{:02}", i));
+        let (as_state, ds_state, bundles) =
+            register_clients_assert_ok(names.each_ref().map(String::as_str)).await;
+        let queried_users: Vec<String> = bundles.iter().map(|bundle| bundle.name()).collect();
+
+        let as_responses = handle_onwire_msg_as_local(
+            OnWireMessage::UserCredentialLookup {
+                user_name: bundles[0].name(),
+                queried_users: queried_users.clone(),
+            },
+            &as_state,
+        )
+        .await;
+        let ds_responses = handle_onwire_msg_ds_local(
+            OnWireMessage::UserKeyPackageLookup {
+                user_name: bundles[0].name(),
+                queried_users: queried_users.clone(),
+            },
+            &ds_state,
+        )
+        .await;
+        let n_chunks = |responses: &[OnWireMessage]| {
+            responses
+                .iter()
+                .filter(|msg| {
+                    matches!(
+                        msg,
+                        OnWireMessage::ASCredentialResponse { .. }
+                            | OnWireMessage::DSKeyPackageResponse { .. }
+                    )
+                })
+                .count()
+        };
+        let expected_chunks =
+            (50 + MAX_LOOKUP_RESULTS_PER_CHUNK - 1) / MAX_LOOKUP_RESULTS_PER_CHUNK;
+        assert!(expected_chunks > 1);
+        assert_eq!(n_chunks(&as_responses), expected_chunks);
+        assert_eq!(n_chunks(&ds_responses), expected_chunks);
+
+        // Chunks received out of order are still reassembled in order
+        let mut shuffled_ds_responses = ds_responses.clone();
+        shuffled_ds_responses.reverse();
+        let credentials = credentials_from_chunks(&as_responses, &as_state.public_key()).unwrap();
+        let key_packages = key_packages_from_chunks(&shuffled_ds_responses).unwrap();
+        let credential_names: Vec<String> = credentials
+            .iter()
+            .map(|credential| identity_to_str(credential.identity()).unwrap())
+            .collect();
+        let key_package_names: Vec<String> = key_packages
+            .iter()
+            .map(|key_package| identity_to_str(key_package.credential().identity()).unwrap())
+            .collect();
+        assert_eq!(credential_names, queried_users);
+        assert_eq!(key_package_names, queried_users);
+
+        // A missing chunk fails the reassembly
+        assert!(key_packages_from_chunks(&ds_responses[1..]).is_none());
+    }
+
+    #[actix_rt::test]
+    /// A credential response should only verify against the pinned AS public
+    /// key as long as its credentials were not tampered with
+    async fn test_tampered_credential_response_fails_verification() {
+        let (as_state, _, [mut alice, bob]) = register_clients_assert_ok(["alice", "bob"]).await;
+
+        let key_responses =
+            handle_onwire_msg_as_local(OnWireMessage::ASPublicKeyRequest, &as_state).await;
+        assert_all_feedback_ok(&alice.parse_msgs(&key_responses));
+        let as_public_key = alice.configs.get_as_public_key().unwrap();
+        assert_eq!(as_public_key, as_state.public_key());
+
+        let lookup = OnWireMessage::UserCredentialLookup {
+            user_name: alice.name(),
+            queried_users: vec![bob.name()],
+        };
+        let (credentials, signature) = match &handle_onwire_msg_as_local(lookup, &as_state).await[0]
+        {
+            OnWireMessage::ASCredentialResponse {
+                queried_user_credentials,
+                signature,
+                ..
+            } => (queried_user_credentials.clone(), *signature),
+            other => panic!("Expected a credential response, found {:?}", other),
+        };
+        assert!(verify_as_credentials(
+            &credentials,
+            &signature,
+            &as_public_key
+        ));
+
+        // Swapping bob's credential for alice's breaks the signature
+        let tampered_credentials = vec![alice.credential().clone()];
+        assert!(!verify_as_credentials(
+            &tampered_credentials,
+            &signature,
+            &as_public_key
+        ));
+        // So does checking against a key other than the AS's
+        assert!(!verify_as_credentials(
+            &credentials,
+            &signature,
+            &AuthServiceState::new().public_key()
+        ));
+    }
+
+    #[actix_rt::test]
+    /// The AS should confirm a registered user along with the fingerprint of
+    /// their verification key, and deny an unknown one
+    async fn test_whoami_reports_registration() {
+        let (as_state, _, [mut alice]) = register_clients_assert_ok(["alice"]).await;
+
+        let responses =
+            handle_onwire_msg_as_local(whoami_msg(alice.configs.as_ref()), &as_state).await;
+        assert_all_feedback_ok(&alice.parse_msgs(&responses));
+        assert_eq!(
+            responses[0],
+            OnWireMessage::ASWhoAmIResponse {
+                user_name: alice.name(),
+                registered: true,
+                revoked: false,
+                verification_key_fingerprint: Some(verification_key_fingerprint(
+                    &alice.configs.get_keypair().public_key()
+                )),
+            }
+        );
+
+        let unregistered = TestClientBundle::new("mallory");
+        let responses =
+            handle_onwire_msg_as_local(whoami_msg(unregistered.configs.as_ref()), &as_state).await;
+        assert_eq!(
+            responses[0],
+            OnWireMessage::ASWhoAmIResponse {
+                user_name: unregistered.name(),
+                registered: false,
+                revoked: false,
+                verification_key_fingerprint: None,
+            }
+        );
+    }
+
+    #[actix_rt::test]
+    /// A display name registered with the AS should reach the clients that
+    /// look up credentials, keyed by the unique id of the user
+    async fn test_display_name_registered_with_as() {
+        let as_state = Arc::new(AuthServiceState::new());
+        let mut user = TestClientBundle::new("u123");
+        let mut bob = TestClientBundle::new("bob");
+        let register_msgs = register_msg_as_with_display_name(
+            user.credential().to_owned(),
+            user.configs.get_keypair().public_key(),
+            "Alice".to_string(),
+        );
+        for register_msg in register_msgs {
+            let responses = handle_onwire_msg_as_local(register_msg, &as_state).await;
+            assert_all_feedback_ok(&user.parse_msgs(&responses));
+        }
+
+        bob.sync_as_assert_ok(&as_state).await;
+        assert_eq!(
+            bob.configs.get_display_names(),
+            BTreeMap::from([("u123".to_string(), "Alice".to_string())])
+        );
+    }
+
+    #[actix_rt::test]
+    /// A credential of an allowed type and signature scheme should register
+    /// with an AS that restricts them
+    async fn test_allowed_credential_type_registered() {
+        let mut as_state = AuthServiceState::new();
+        as_state.allowed_credential_types = Some(vec!["Basic".to_string()]);
+        as_state.allowed_signature_schemes = Some(vec!["ED25519".to_string()]);
+        let as_state = Arc::new(as_state);
+        let ds_state = Arc::new(DeliveryServiceState::new());
+
+        let mut alice = TestClientBundle::new("alice");
+        alice.register_assert_ok(&as_state, &ds_state).await;
+        assert!(as_state.get_credential_copy(alice.name()).is_some());
+    }
+
+    #[actix_rt::test]
+    /// A credential of a signature scheme the AS does not allow should be
+    /// rejected, and not registered
+    async fn test_disallowed_credential_type_rejected() {
+        let mut as_state = AuthServiceState::new();
+        as_state.allowed_signature_schemes = Some(vec!["ED25519".to_string()]);
+        let as_state = Arc::new(as_state);
+        let mut carol = TestClientBundle::new("carol");
+        let credential = carol
+            .backend
+            .generate_credential_bundle(
+                b"carol".to_vec(),
+                None,
+                Some(SignatureScheme::ECDSA_SECP256R1_SHA256),
+            )
+            .credential()
+            .to_owned();
+
+        let register_msgs = register_msg_as(credential, carol.configs.get_keypair().public_key());
+        for register_msg in register_msgs {
+            let responses = handle_onwire_msg_as_local(register_msg, &as_state).await;
+            assert!(matches!(
+                responses.as_slice(),
+                [OnWireMessage::ASResult {
+                    request_valid: false,
+                    explanation: Some(explanation),
+                    ..
+                }] if explanation == &UserRequestErrors::UnsupportedCredentialType.to_string()
+            ));
+        }
+        assert!(as_state.get_credential_copy(carol.name()).is_none());
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// Many queued typing events from one sender should reach a recipient
+    /// as only the latest one, without dropping events of another kind
+    async fn test_ephemeral_msgs_coalesced_on_ds() {
+        let (_, ds_state, mut admin, mut members) =
+            setup_registered_group_assert_ok("admin", &["bob"]).await;
+        let bob = &mut members[0];
+        bob.sync_ds_assert_ok(&ds_state).await;
+
+        for i in 0..10 {
+            let typing_msgs = ephemeral_msg(
+                &comm_grp(),
+                EphemeralKind::Typing,
+                &i.to_string(),
+                &mut admin.backend,
+                admin.configs.deref_mut(),
+            );
+            admin.send_all_assert_ok(typing_msgs, &ds_state).await;
+        }
+        let reaction_msgs = ephemeral_msg(
+            &comm_grp(),
+            EphemeralKind::Reaction,
+            "+1",
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        admin.send_all_assert_ok(reaction_msgs, &ds_state).await;
+
+        let sync_responses =
+            handle_onwire_msg_ds_local(sync_msg(bob.configs.as_ref(), vec![]), &ds_state).await;
+        let ephemeral_contents: Vec<(EphemeralKind, String)> = bob
+            .parse_msgs(&sync_responses)
+            .into_iter()
+            .filter_map(|parsed_msg| match parsed_msg {
+                ClientParsedMsg::NewMsg { private_msg, .. } => match private_msg.content {
+                    UnorderedMsgContent::Ephemeral { kind, content } => Some((kind, content)),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            ephemeral_contents,
+            vec![
+                (EphemeralKind::Typing, "9".to_string()),
+                (EphemeralKind::Reaction, "+1".to_string())
+            ]
+        );
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// The DS should reject sends to unknown users or to more recipients
+    /// than `max_recipients_per_send`, and deliver the others
+    async fn test_send_recipients_checked_on_ds() {
+        let ds_state = DeliveryServiceState {
+            max_recipients_per_send: Some(1),
+            ..DeliveryServiceState::new()
+        };
+        let (_, ds_state, mut admin, mut members) = setup_registered_group_with_assert_ok(
+            AuthServiceState::new(),
+            ds_state,
+            TestClientBundle::new("admin"),
+            vec![TestClientBundle::new("bob")],
+        )
+        .await;
+        members[0].sync_ds_assert_ok(&ds_state).await;
+
+        let text_msgs = send_text_msg_mls(
+            &admin.name(),
+            &comm_grp(),
+            "hello".to_string(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        let text_msg = text_msgs.into_iter().next().unwrap();
+        let with_recipients = |new_recipients: Vec<&str>| match text_msg.clone() {
+            OnWireMessage::UserStandardSend {
+                identifier,
+                user_msg,
+                ephemeral,
+                ..
+            } => OnWireMessage::UserStandardSend {
+                identifier,
+                user_msg,
+                recipients: new_recipients.into_iter().map(String::from).collect(),
+                ephemeral,
+            },
+            _ => panic!("Expected a standard send"),
+        };
+        let send_is_valid = |onwire_msg: OnWireMessage| {
+            let ds_state = Arc::clone(&ds_state);
+            async move {
+                handle_onwire_msg_ds_local(onwire_msg, &ds_state)
+                    .await
+                    .iter()
+                    .all(|reply| matches!(reply, OnWireMessage::DSResult { request_valid, .. } if *request_valid))
+            }
+        };
+
+        let queued_for_bob = || {
+            ds_state
+                .unordered_message_indvl_queues
+                .get("bob")
+                .map_or(0, |queue| queue.len())
+        };
+        let queued_before = queued_for_bob();
+
+        assert!(!send_is_valid(with_recipients(vec!["mallory"])).await);
+        assert!(!send_is_valid(with_recipients(vec!["bob", "admin"])).await);
+        assert_eq!(queued_for_bob(), queued_before);
+
+        assert!(send_is_valid(with_recipients(vec!["bob"])).await);
+        assert_eq!(queued_for_bob(), queued_before + 1);
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// A sync should not relay the ordered messages of epochs below the one
+    /// the client reports for the group
+    async fn test_sync_skips_ordered_msgs_below_known_epoch() {
+        let (_, ds_state, [mut bob]) = register_clients_assert_ok(["bob"]).await;
+        let mut admin = TestClientBundle::new("admin");
+        let _ = create_group_msg(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+
+        // Commits of epochs 0 to 4, then a message of epoch 5
+        let mut mls_msgs = vec![];
+        {
+            let mut mls_group = admin
+                .configs
+                .get_ref_group(&comm_grp())
+                .unwrap()
+                .borrow_mut();
+            for _ in 0..5 {
+                let (commit, _) = mls_group.self_update(&admin.backend, None).unwrap();
+                mls_group.merge_pending_commit().unwrap();
+                mls_msgs.push(commit);
+            }
+            mls_msgs.push(mls_group.create_message(&admin.backend, b"hello").unwrap());
+        }
+        for mls_msg in mls_msgs {
+            let group_msg = GroupMessage::from_mls(mls_msg, comm_grp(), Some(admin.name()))
+                .with_tag(admin.configs.get_keypair());
+            ds_state.delivery_to_recipients(
+                &vec![bob.name()],
+                ProtectedMessageWithMetaData::new(group_msg, true, SystemTime::now()),
+            );
+        }
+
+        let sync = OnWireMessage::UserSync {
+            user_name: bob.name(),
+            new_key_packages: vec![],
+            known_epochs: vec![(comm_grp(), 5)],
+            acked_until: None,
+        };
+        let relayed_epochs: Vec<u64> = handle_onwire_msg_ds_local(sync, &ds_state)
+            .await
+            .into_iter()
+            .filter_map(|reply| match reply {
+                OnWireMessage::DSRelayedUserMsg { user_msg, .. } => Some(user_msg.get_epoch()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(relayed_epochs, vec![5]);
+        // The skipped messages are not kept for a later sync either
+        assert!(ds_state.message_id_to_message.is_empty());
+    }
+
+    /// Records the fields of every span entered, to check requests are
+    /// handled within a span
+    #[cfg(feature = "tracing")]
+    #[derive(Default)]
+    struct SpanRecorder {
+        spans: std::sync::Mutex<Vec<String>>,
+        entered: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for SpanRecorder {
+        fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            struct FieldsVisitor(String);
+            impl tracing::field::Visit for FieldsVisitor {
+                fn record_debug(
+                    &mut self,
+                    field: &tracing::field::Field,
+                    value: &dyn std::fmt::Debug,
+                ) {
+                    self.0 += &format!(" {}={:?}", field.name(), value);
+                }
+            }
+            let mut visitor = FieldsVisitor(span.metadata().name().to_string());
+            span.record(&mut visitor);
+            let mut spans = self.spans.lock().unwrap();
+            spans.push(visitor.0);
+            tracing::span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, _span: &tracing::span::Id, _values: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+
+        fn event(&self, _event: &tracing::Event<'_>) {}
+
+        fn enter(&self, span: &tracing::span::Id) {
+            let fields = self.spans.lock().unwrap()[span.into_u64() as usize - 1].clone();
+            self.entered.lock().unwrap().push(fields);
+        }
+
+        fn exit(&self, _span: &tracing::span::Id) {}
+    }
+
+    #[cfg(all(feature = "gov", feature = "tracing"))]
+    #[actix_rt::test]
+    /// A request should be handled within a span naming its user and variant
+    async fn test_request_handled_within_span() {
+        let ds_state = DeliveryServiceState::new();
+        ds_state.user_key_packages.insert("bob".to_string(), vec![]);
+        let ds_state = Arc::new(ds_state);
+        let recorder = SpanRecorder::default();
+        let entered = recorder.entered.clone();
+        let _guard = tracing::subscriber::set_default(recorder);
+
+        let sync = OnWireMessage::UserSync {
+            user_name: "bob".to_string(),
+            new_key_packages: vec![],
+            known_epochs: vec![],
+            acked_until: None,
+        };
+        handle_onwire_msg_ds_local(sync, &ds_state).await;
+        assert!(entered
+            .lock()
+            .unwrap()
+            .contains(&r#"onwire_request service="ds" user="bob" variant="UserSync""#.to_string()));
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// A poll should count the ordered and unordered messages the DS holds
+    /// for the user without dequeuing them, so that only a sync retrieves them
+    async fn test_poll_counts_pending_msgs_without_dequeuing() {
+        let (as_state, ds_state, [mut admin, bob, mut charlie]) =
+            register_clients_assert_ok(["admin", "bob", "charlie"]).await;
+        let mut members = vec![bob];
+        setup_group_assert_ok(&mut admin, &mut members, &as_state, &ds_state).await;
+        let mut bob = members.remove(0);
+        assert_eq!(
+            poll_pending(bob.configs.as_ref(), &mut LocalDsTransport(&ds_state)),
+            Some(PendingCounts::default())
+        );
+        assert!(try_sync(
+            &mut bob.configs,
+            &mut bob.backend,
+            &mut LocalDsTransport(&ds_state)
+        )
+        .is_empty());
+
+        let (invite_msgs, _) = pre_add_invite_msg(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+            vec![charlie.new_key_package()],
+        );
+        admin.send_all_assert_ok(invite_msgs, &ds_state).await;
+        let text_msgs = send_text_msg_mls(
+            &admin.name(),
+            &comm_grp(),
+            "hello".to_string(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        admin.send_all_assert_ok(text_msgs, &ds_state).await;
+
+        let n_stored = ds_state.message_id_to_message.len();
+        let expected = PendingCounts {
+            invites: 0,
+            unordered: 1,
+            ordered: vec![(comm_grp(), 1)],
+        };
+        for _ in 0..2 {
+            assert_eq!(
+                poll_pending(bob.configs.as_ref(), &mut LocalDsTransport(&ds_state)),
+                Some(expected.clone())
+            );
+        }
+        assert_eq!(ds_state.message_id_to_message.len(), n_stored);
+
+        let parsed = try_sync(
+            &mut bob.configs,
+            &mut bob.backend,
+            &mut LocalDsTransport(&ds_state),
+        );
+        assert_all_feedback_ok(&parsed);
+        assert!(concat_string_in_decrypted_msgs(parsed).contains("hello"));
+        assert_eq!(
+            poll_pending(bob.configs.as_ref(), &mut LocalDsTransport(&ds_state)),
+            Some(PendingCounts::default())
+        );
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// A message recorded as unsent should survive a restart of the client
+    /// and be resent, after which it is confirmed and not recorded again
+    async fn test_unsent_msg_survives_restart_and_is_resent() {
+        let (_, ds_state, mut admin, mut members) =
+            setup_registered_group_assert_ok("admin", &["bob"]).await;
+        let bob = &mut members[0];
+
+        let text_msgs = send_text_msg_mls(
+            &admin.name(),
+            &comm_grp(),
+            "hello".to_string(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        record_unsent_msgs(&text_msgs, admin.configs.deref_mut());
+        // The client crashes after storing its state, before sending
+        let stored = admin.configs.to_string();
+        admin.configs = Box::new(ClientData::from_versioned_str(&stored).unwrap());
+
+        let unsent_msgs = admin.configs.get_unsent_msgs();
+        assert_eq!(unsent_msgs, text_msgs);
+        admin.send_all_assert_ok(unsent_msgs, &ds_state).await;
+        assert!(admin.configs.get_unsent_msgs().is_empty());
+        record_unsent_msgs(&text_msgs, admin.configs.deref_mut());
+        assert!(admin.configs.get_unsent_msgs().is_empty());
+
+        let parsed = try_sync(
+            &mut bob.configs,
+            &mut bob.backend,
+            &mut LocalDsTransport(&ds_state),
+        );
+        assert_all_feedback_ok(&parsed);
+        assert_eq!(
+            concat_string_in_decrypted_msgs(parsed)
+                .matches("hello")
+                .count(),
+            1
+        );
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// The queue gauges of the DS should count the messages delivered and yet
+    /// to be retrieved, and be recounted once the state is restored
+    async fn test_queue_metrics_follow_deliveries() {
+        let ds_state = DeliveryServiceState::new();
+        let mut admin = TestClientBundle::new("admin");
+        let _ = create_group_msg(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        let recipients = [
+            vec!["bob".to_string(), "charlie".to_string()],
+            vec!["bob".to_string()],
+        ];
+        for (text, recipients) in ["hello", "world"].iter().zip(recipients.iter()) {
+            let mls_msg = admin
+                .configs
+                .get_ref_group(&comm_grp())
+                .unwrap()
+                .borrow_mut()
+                .create_message(&admin.backend, text.as_bytes())
+                .unwrap();
+            let group_msg = GroupMessage::from_mls(mls_msg, comm_grp(), Some(admin.name()));
+            assert!(ds_state.delivery_to_recipients(
+                recipients,
+                ProtectedMessageWithMetaData::new(group_msg, false, SystemTime::now()),
+            ));
+        }
+        assert_eq!(ds_state.queue_metrics.total_queued(), 3);
+        assert_eq!(ds_state.queue_metrics.max_queue_depth(), 2);
+
+        ds_state.user_key_packages.insert("bob".to_string(), vec![]);
+        let sync = OnWireMessage::UserSync {
+            user_name: "bob".to_string(),
+            new_key_packages: vec![],
+            known_epochs: vec![],
+            acked_until: None,
+        };
+        let ds_state = Arc::new(ds_state);
+        let _ = handle_onwire_msg_ds_local(sync, &ds_state).await;
+        assert_eq!(ds_state.queue_metrics.total_queued(), 1);
+        assert_eq!(ds_state.queue_metrics.max_queue_depth(), 1);
+
+        let restored_state: DeliveryServiceState =
+            serde_yaml::from_str(&serde_yaml::to_string(ds_state.as_ref()).unwrap()).unwrap();
+        assert_eq!(restored_state.queue_metrics.total_queued(), 0);
+        restored_state.recount_queue_metrics();
+        assert_eq!(restored_state.queue_metrics.total_queued(), 1);
+        assert_eq!(restored_state.queue_metrics.max_queue_depth(), 1);
+
+        let charlie_queue = ds_state
+            .unordered_message_indvl_queues
+            .get("charlie")
+            .unwrap()
+            .clone();
+        assert!(ds_state
+            .pop_message_by_id(&charlie_queue[0], &"charlie".to_string())
+            .is_some());
+        assert_eq!(ds_state.queue_metrics.total_queued(), 0);
+        assert_eq!(ds_state.queue_metrics.max_queue_depth(), 0);
+    }
+
+    #[cfg(feature = "gov")]
+    #[test]
+    /// A self-sent commit of an epoch the group already moved past should be
+    /// reported as a retriable epoch mismatch
+    fn test_self_sent_wrong_epoch_is_epoch_mismatch() {
+        let mut admin = TestClientBundle::new("admin");
+        let _ = create_group_msg(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        let stale_commit = {
+            let mut mls_group = admin
+                .configs
+                .get_ref_group(&comm_grp())
+                .unwrap()
+                .borrow_mut();
+            let (stale_commit, _) = mls_group.self_update(&admin.backend, None).unwrap();
+            mls_group.clear_pending_commit();
+            let _ = mls_group.self_update(&admin.backend, None).unwrap();
+            mls_group.merge_pending_commit().unwrap();
+            stale_commit
+        };
+
+        let parsed = parse_mls_message_out(
+            stale_commit,
+            &comm_grp(),
+            Some(admin.name()),
+            None,
+            &mut admin.configs,
+            &mut admin.backend,
+        );
+        match parsed.as_slice() {
+            [ClientParsedMsg::EpochMismatch {
+                comm_grp: mismatched_grp,
+                expected,
+                got,
+                self_sent,
+            }] => {
+                assert_eq!(mismatched_grp, &comm_grp());
+                assert_eq!((*expected, *got), (1, 0));
+                assert!(*self_sent);
+            }
+            other => panic!("Expected a single epoch mismatch, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "gov")]
+    #[test]
+    /// A DS result for a group the client removed since should neither
+    /// panic nor execute any action
+    fn test_ds_result_for_removed_group_ignored() {
+        let mut bob = TestClientBundle::new("bob");
+        let _ = create_group_msg(
+            &bob.name(),
+            &comm_grp(),
+            &mut bob.backend,
+            bob.configs.deref_mut(),
+        );
+        let action = ActionMsg::TextMsg(TextMsgAction {
+            msg: "hello".to_string(),
+            expires_after: None,
+            in_reply_to: None,
+            metadata: ActionMetadata::new(
+                bob.name(),
+                "hello".to_string(),
+                comm_grp(),
+                SystemTime::now(),
+            ),
+        });
+        bob.configs
+            .store_pending_action(&comm_grp(), action.clone());
+        bob.configs.remove_group(&comm_grp());
+        bob.configs.store_pending_action(&comm_grp(), action);
+        assert!(bob.configs.pop_pending_action(&comm_grp()).is_none());
+
+        let ds_result = |request_valid| OnWireMessage::DSResult {
+            request_valid,
+            explanation: None,
+            identifier: Some(comm_grp().get_string()),
+            preceding_and_sent_ordered_msgs: vec![],
+            remaining_key_packages: None,
+            process_time_used: Duration::ZERO,
+        };
+        let parsed = bob.parse_msgs(&vec![ds_result(true), ds_result(false)]);
+        assert_eq!(parsed.len(), 2);
+        assert!(bob.configs.pop_pending_actions(&comm_grp()).is_empty());
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// The welcome of an invite should be delivered at every sync of the
+    /// invitee until they signal that they accepted it
+    async fn test_welcome_retained_until_accepted() {
+        let as_state = Arc::new(AuthServiceState::new());
+        let ds_state = Arc::new(DeliveryServiceState {
+            welcome_retention: Some(Duration::from_secs(3600)),
+            ..DeliveryServiceState::new()
+        });
+        let mut admin = TestClientBundle::new("admin");
+        let mut bob = TestClientBundle::new("bob");
+        admin.register_assert_ok(&as_state, &ds_state).await;
+        bob.register_assert_ok(&as_state, &ds_state).await;
+        let _ = create_group_msg(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        let (invite_msgs, _) = pre_add_invite_msg(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+            vec![bob.new_key_package()],
+        );
+        admin.send_all_assert_ok(invite_msgs, &ds_state).await;
+
+        let n_welcomes = |responses: &Vec<OnWireMessage>| {
+            responses
+                .iter()
+                .filter(|msg| {
+                    matches!(msg, OnWireMessage::DSRelayedUserMsg { user_msg, .. } if user_msg.is_welcome())
+                })
+                .count()
+        };
+        let responses =
+            handle_onwire_msg_ds_local(sync_msg(bob.configs.as_ref(), vec![]), &ds_state).await;
+        assert_eq!(n_welcomes(&responses), 1);
+        assert_all_feedback_ok(&bob.parse_msgs(&responses));
+        // Delivered again, and skipped by the client which already holds the group
+        let responses =
+            handle_onwire_msg_ds_local(sync_msg(bob.configs.as_ref(), vec![]), &ds_state).await;
+        assert_eq!(n_welcomes(&responses), 1);
+        let parsed_msgs = bob.parse_msgs(&responses);
+        assert_all_feedback_ok(&parsed_msgs);
+        assert!(!parsed_msgs
+            .iter()
+            .any(|msg| matches!(msg, ClientParsedMsg::NewInvite { .. })));
+
+        bob.send_assert_ok(
+            accepted_invite_msg(&comm_grp(), bob.configs.as_ref()),
+            &ds_state,
+        )
+        .await;
+        let responses =
+            handle_onwire_msg_ds_local(sync_msg(bob.configs.as_ref(), vec![]), &ds_state).await;
+        assert_eq!(n_welcomes(&responses), 0);
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// The welcome of an invite should be queued like any other unordered
+    /// message, and let the invitee join the group once relayed
+    async fn test_welcome_relayed_as_group_message() {
+        let (_, ds_state, [mut admin, mut bob]) =
+            register_clients_assert_ok(["admin", "bob"]).await;
+        let _ = create_group_msg(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        let (invite_msgs, _) = pre_add_invite_msg(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+            vec![bob.new_key_package()],
+        );
+        admin.send_all_assert_ok(invite_msgs, &ds_state).await;
+
+        let queued_ids = ds_state
+            .unordered_message_indvl_queues
+            .get("bob")
+            .unwrap()
+            .clone();
+        assert_eq!(queued_ids.len(), 1);
+        assert!(ds_state
+            .message_id_to_message
+            .get(&queued_ids[0])
+            .unwrap()
+            .1
+            .protected_msg
+            .is_welcome());
+
+        let responses =
+            handle_onwire_msg_ds_local(sync_msg(bob.configs.as_ref(), vec![]), &ds_state).await;
+        assert!(responses.iter().any(|msg| matches!(
+            msg,
+            OnWireMessage::DSRelayedUserMsg {
+                user_msg: GroupMessage::Welcome { comm_grp: grp, sender, .. },
+                ..
+            } if grp == &comm_grp() && sender == &admin.name()
+        )));
+        let parsed_msgs = bob.parse_msgs(&responses);
+        assert_all_feedback_ok(&parsed_msgs);
+        assert!(parsed_msgs.iter().any(|msg| matches!(
+            msg,
+            ClientParsedMsg::NewInvite { inviter, comm_grp: grp }
+                if inviter == &admin.name() && grp == &comm_grp()
+        )));
+        assert!(bob.configs.get_ref_group(&comm_grp()).is_some());
+        assert!(ds_state
+            .unordered_message_indvl_queues
+            .get("bob")
+            .map_or(true, |queue| queue.is_empty()));
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// Invites queued by a DS state stored with layout 1 should be relayed
+    /// as welcome messages once the state is migrated
+    async fn test_queued_invites_migrated_from_v1() {
+        let (_, ds_state, [mut admin, mut bob]) =
+            register_clients_assert_ok(["admin", "bob"]).await;
+        let _ = create_group_msg(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        let (invite_msgs, _) = pre_add_invite_msg(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+            vec![bob.new_key_package()],
+        );
+        let welcome = invite_msgs
+            .into_iter()
+            .find_map(|msg| match msg {
+                OnWireMessage::UserInvite { welcome, .. } => Some(welcome),
+                _ => None,
+            })
+            .unwrap();
+
+        let mut v1_value = serde_yaml::to_value(ds_state.as_ref()).unwrap();
+        let fields = v1_value.as_mapping_mut().unwrap();
+        fields.remove(&serde_yaml::Value::from("schema_version"));
+        let invite = Invite {
+            welcome_obj: welcome,
+            invitee: bob.name(),
+            inviter: admin.name(),
+            comm_grp: comm_grp(),
+            created_at: SystemTime::now(),
+        };
+        let invite_queues = BTreeMap::from([(bob.name(), vec![invite])]);
+        fields.insert(
+            serde_yaml::Value::from("invite_indvl_queues"),
+            serde_yaml::to_value(invite_queues).unwrap(),
+        );
+
+        let ds_state: Arc<DeliveryServiceState> = Arc::new(decode_versioned(v1_value).unwrap());
+        assert_eq!(
+            ds_state.schema_version,
+            DeliveryServiceState::SCHEMA_VERSION
+        );
+        assert_eq!(
+            ds_state
+                .unordered_message_indvl_queues
+                .get("bob")
+                .unwrap()
+                .len(),
+            1
+        );
+        let responses =
+            handle_onwire_msg_ds_local(sync_msg(bob.configs.as_ref(), vec![]), &ds_state).await;
+        let parsed_msgs = bob.parse_msgs(&responses);
+        assert_all_feedback_ok(&parsed_msgs);
+        assert!(parsed_msgs.iter().any(|msg| matches!(
+            msg,
+            ClientParsedMsg::NewInvite { inviter, comm_grp: grp }
+                if inviter == &admin.name() && grp == &comm_grp()
+        )));
+        assert!(bob.configs.get_ref_group(&comm_grp()).is_some());
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// Messages should be timestamped by the clocks of their sender, the DS
+    /// and their receiver
+    async fn test_timestamps_read_from_clock() {
+        let mock_clock =
+            MockClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        let (_, ds_state, mut admin, mut members) = setup_registered_group_with_assert_ok(
+            AuthServiceState::new(),
+            DeliveryServiceState::new().with_clock(SharedClock::new(mock_clock.clone())),
+            TestClientBundle::new("admin").with_clock(SharedClock::new(mock_clock.clone())),
+            vec![TestClientBundle::new("bob").with_clock(SharedClock::new(mock_clock.clone()))],
+        )
+        .await;
+        let bob = &mut members[0];
+        bob.sync_ds_assert_ok(&ds_state).await;
+
+        mock_clock.advance(Duration::from_secs(60));
+        let sent_at = mock_clock.now();
+        let text_msgs = send_text_msg_mls(
+            &admin.name(),
+            &comm_grp(),
+            "hello".to_string(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        admin.send_all_assert_ok(text_msgs, &ds_state).await;
+        let msg_id = ds_state.unordered_message_indvl_queues.get("bob").unwrap()[0].clone();
+        assert_eq!(
+            ds_state
+                .message_id_to_message
+                .get(&msg_id)
+                .unwrap()
+                .1
+                .server_timestamp,
+            sent_at
+        );
+
+        mock_clock.advance(Duration::from_secs(60));
+        bob.sync_ds_assert_ok(&ds_state).await;
+        let bob_data = bob.configs.as_any().downcast_ref::<ClientData>().unwrap();
+        let received = bob_data
+            .get_group_state(&comm_grp())
+            .history
+            .last()
+            .unwrap();
+        assert_eq!(received.message.sender_timestamp, sent_at);
+        assert_eq!(
+            received.received_timestamp,
+            sent_at + Duration::from_secs(60)
+        );
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// A retried message with the same client message ID should be
+    /// acknowledged again but only be queued once
+    async fn test_retried_msg_deduplicated_on_ds() {
+        let (_, ds_state, mut admin, mut members) =
+            setup_registered_group_assert_ok("admin", &["bob"]).await;
+        let bob = &mut members[0];
+        bob.sync_ds_assert_ok(&ds_state).await;
+
+        let text_msgs = send_text_msg_mls(
+            &admin.name(),
+            &comm_grp(),
+            "hello".to_string(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        // Both the original and the retried copy are acknowledged
+        admin.send_all_assert_ok(text_msgs.clone(), &ds_state).await;
+        admin.send_all_assert_ok(text_msgs, &ds_state).await;
+
+        assert_eq!(
+            ds_state
+                .unordered_message_indvl_queues
+                .get("bob")
+                .map(|queue| queue.len()),
+            Some(1)
+        );
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// With a sealed sender, ordered messages should not name their sender
+    /// on the wire, yet be delivered and applied like other ordered messages
+    async fn test_sealed_sender_ordered_msgs() {
+        let (_, ds_state, mut admin, mut members) =
+            setup_registered_group_assert_ok("admin", &["bob"]).await;
+        let bob = &mut members[0];
+        bob.sync_ds_assert_ok(&ds_state).await;
+
+        let mut wire_msgs = vec![];
+        for (lamport_counter, (sealed_sender, new_name)) in
+            [(false, "open name"), (true, "sealed name")]
+                .into_iter()
+                .enumerate()
+        {
+            admin.configs.set_sealed_sender(sealed_sender);
+            let rename = ActionMsg::RenameGroup(RenameGroupAction {
+                new_name: new_name.to_string(),
+                lamport_counter: lamport_counter as u64 + 1,
+                metadata: ActionMetadata::new(
+                    "admin".to_string(),
+                    new_name.to_string(),
+                    comm_grp(),
+                    SystemTime::now(),
+                ),
+            });
+            let msgs = check_action_msg_and_get_mls(
+                &comm_grp(),
+                rename,
+                &mut admin.backend,
+                admin.configs.deref_mut(),
+            );
+            match &msgs[0] {
+                OnWireMessage::UserReliableSend {
+                    user_name,
+                    user_msg,
+                    ..
+                } => {
+                    assert_eq!(user_name.is_none(), sealed_sender);
+                    wire_msgs.push(user_msg.clone())
+                }
+                other => panic!("Expected an ordered message, got {:?}", other),
+            }
+            admin.send_all_assert_ok(msgs, &ds_state).await;
+            if sealed_sender {
+                // Not echoed to an unnamed sender, until it syncs
+                assert_eq!(admin.configs.get_group_name(&comm_grp()), "open name");
+                admin.sync_ds_assert_ok(&ds_state).await;
+            }
+            bob.sync_ds_assert_ok(&ds_state).await;
+
+            assert_eq!(admin.configs.get_group_name(&comm_grp()), new_name);
+            assert_eq!(bob.configs.get_group_name(&comm_grp()), new_name);
+        }
+
+        let open_bytes = serde_json::to_string(&wire_msgs[0]).unwrap();
+        let sealed_bytes = serde_json::to_string(&wire_msgs[1]).unwrap();
+        assert!(open_bytes.contains("\"sender\":\"admin\""));
+        assert!(sealed_bytes.contains("\"sender\":null"));
+        assert!(!sealed_bytes.contains("admin"));
+        let sealed_send_bytes = serde_json::to_string(&OnWireMessage::UserReliableSend {
+            user_name: None,
+            recipients: vec![],
+            user_msg: wire_msgs[1].clone(),
+        })
+        .unwrap();
+        assert!(!sealed_send_bytes.contains("admin"));
+        // The echo of the sealed message was recognized as the admin's own
+        assert!(!admin
+            .configs
+            .pop_sealed_sent_msg_id(&wire_msgs[1].get_client_msg_id()));
+
+        // Only the latest sealed messages awaiting their echo are remembered
+        let sent_ids: Vec<uuid::Uuid> = (0..=MAX_SEALED_SENT_MSG_IDS)
+            .map(|_| uuid::Uuid::new_v4())
+            .collect();
+        for sent_id in sent_ids.iter() {
+            admin.configs.store_sealed_sent_msg_id(*sent_id);
+        }
+        assert!(!admin.configs.pop_sealed_sent_msg_id(&sent_ids[0]));
+        assert!(admin
+            .configs
+            .pop_sealed_sent_msg_id(&sent_ids[MAX_SEALED_SENT_MSG_IDS]));
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// A role definition and its assignment committed together should take
+    /// effect for both the sender and the other members
+    async fn test_commit_def_and_set_role_together() {
+        let (_, ds_state, mut admin, mut members) =
+            setup_registered_group_assert_ok("admin", &["bob"]).await;
+        let bob = &mut members[0];
+
+        let actions = vec![
+            ActionMsg::DefRole(DefRoleAction {
+                role_name: "Renamer".to_string(),
+                action_types: vec![ActionType::TextMsg, ActionType::RenameGroup],
+                inherits_from: vec![],
+                metadata: ActionMetadata::new(
+                    admin.name(),
+                    "def_role".to_string(),
+                    comm_grp(),
+                    SystemTime::now(),
+                ),
+            }),
+            ActionMsg::SetUserRole(SetUserRoleAction {
+                user_id: bob.name(),
+                role_name: "Renamer".to_string(),
+                metadata: ActionMetadata::new(
+                    admin.name(),
+                    "set_role".to_string(),
+                    comm_grp(),
+                    SystemTime::now(),
+                ),
+            }),
+        ];
+        let commit_msgs = commit_actions(
+            &comm_grp(),
+            actions,
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        assert_eq!(commit_msgs.len(), 1);
+        admin.send_all_assert_ok(commit_msgs, &ds_state).await;
+        assert!(admin.configs.pop_pending_action(&comm_grp()).is_none());
+        bob.sync_ds_assert_ok(&ds_state).await;
+
+        for bundle in [&admin, &*bob] {
+            assert!(bundle
+                .configs
+                .get_roles(&comm_grp())
+                .action_type_authorized(&bob.name(), &ActionType::RenameGroup));
+        }
+        assert_eq!(
+            admin.configs.get_shared_state(&comm_grp()),
+            bob.configs.get_shared_state(&comm_grp())
+        );
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// A user holding several roles should be authorized for the actions of
+    /// any of them, and keep the others when one is taken away
+    async fn test_user_with_multiple_roles() {
+        let (_, ds_state, mut admin, mut members) =
+            setup_registered_group_assert_ok("admin", &["bob"]).await;
+        let bob = &mut members[0];
+
+        let mut actions = vec![
+            ActionMsg::DefRole(DefRoleAction {
+                role_name: "Moderator".to_string(),
+                action_types: vec![ActionType::Kick, ActionType::TakedownTextMsg],
+                inherits_from: vec![],
+                metadata: ActionMetadata::new(
+                    admin.name(),
+                    "def_mod".to_string(),
+                    comm_grp(),
+                    SystemTime::now(),
+                ),
+            }),
+            ActionMsg::DefRole(DefRoleAction {
+                role_name: "Editor".to_string(),
+                action_types: vec![ActionType::RenameGroup, ActionType::SetTopicGroup],
+                inherits_from: vec![],
+                metadata: ActionMetadata::new(
+                    admin.name(),
+                    "def_editor".to_string(),
+                    comm_grp(),
+                    SystemTime::now(),
+                ),
+            }),
+        ];
+        for role_name in ["Moderator", "Editor"] {
+            actions.push(ActionMsg::AddUserRole(AddUserRoleAction {
+                user_id: bob.name(),
+                role_name: role_name.to_string(),
+                metadata: ActionMetadata::new(
+                    admin.name(),
+                    role_name.to_string(),
+                    comm_grp(),
+                    SystemTime::now(),
+                ),
+            }));
+        }
+        let commit_msgs = commit_actions(
+            &comm_grp(),
+            actions,
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        admin.send_all_assert_ok(commit_msgs, &ds_state).await;
+        bob.sync_ds_assert_ok(&ds_state).await;
+
+        let kick = ActionMsg::Kick(KickAction {
+            target_user_id: admin.name(),
+            metadata: ActionMetadata::new(
+                bob.name(),
+                "kick".to_string(),
+                comm_grp(),
+                SystemTime::now(),
+            ),
+        });
+        let rename = ActionMsg::RenameGroup(RenameGroupAction {
+            new_name: "renamed".to_string(),
+            lamport_counter: 1,
+            metadata: ActionMetadata::new(
+                bob.name(),
+                "rename".to_string(),
+                comm_grp(),
+                SystemTime::now(),
+            ),
+        });
+        for bundle in [&admin, &*bob] {
+            let mut rbac = bundle.configs.get_roles(&comm_grp());
+            assert_eq!(
+                rbac.get_user_roles(&bob.name()),
+                ["BaseUser", "Editor", "Moderator"]
+            );
+            assert!(rbac.action_authorized(&bob.name(), &kick));
+            assert!(rbac.action_authorized(&bob.name(), &rename));
+            assert!(!rbac.action_type_authorized(&bob.name(), &ActionType::DefRole));
+        }
+
+        let msgs = remove_role_msg(
+            &comm_grp(),
+            &bob.name(),
+            "Moderator".to_string(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        admin.send_all_assert_ok(msgs, &ds_state).await;
+        bob.sync_ds_assert_ok(&ds_state).await;
+
+        for bundle in [&admin, &*bob] {
+            let mut rbac = bundle.configs.get_roles(&comm_grp());
+            assert_eq!(rbac.get_user_roles(&bob.name()), ["BaseUser", "Editor"]);
+            assert!(!rbac.action_authorized(&bob.name(), &kick));
+            assert!(rbac.action_authorized(&bob.name(), &rename));
+        }
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// Members should be found with their roles, non-members should not, and
+    /// a member without an assigned role should have none
+    async fn test_is_member_and_member_role() {
+        let (_, _, mut admin, _) = setup_registered_group_assert_ok("admin", &["bob"]).await;
+
+        assert!(admin.configs.is_member(&comm_grp(), "bob"));
+        assert_eq!(
+            admin.configs.member_role(&comm_grp(), "bob"),
+            Some("BaseUser".to_string())
+        );
+        assert!(!admin.configs.is_member(&comm_grp(), "eve"));
+        assert_eq!(admin.configs.member_role(&comm_grp(), "eve"), None);
+
+        admin
+            .configs
+            .as_any_mut()
+            .downcast_mut::<ClientData>()
+            .unwrap()
+            .get_mut_group_state(&comm_grp())
+            .shared
+            .rbac
+            .user_to_roles
+            .remove("bob");
+        assert!(admin.configs.is_member(&comm_grp(), "bob"));
+        assert_eq!(admin.configs.member_role(&comm_grp(), "bob"), None);
+    }
+
+    #[cfg(feature = "gov")]
+    #[test]
+    /// Setting a role should replace all the roles of a user, and the single
+    /// role of a user in a state stored before users could hold several
+    /// roles should still be read
+    fn test_set_user_role_replaces_roles() {
+        let mut rbac = RbacState::new();
+        rbac.add_user_role("bob".to_string(), "Mod".to_string());
+        assert_eq!(rbac.get_user_roles("bob"), ["BaseUser", "Mod"]);
+        rbac.set_user_role("bob".to_string(), "BaseUser".to_string());
+        assert_eq!(rbac.get_user_roles("bob"), ["BaseUser"]);
+
+        let mut stored = serde_json::to_value(&rbac).unwrap();
+        let user_to_roles = stored.as_object_mut().unwrap().remove("user_to_roles");
+        assert!(user_to_roles.is_some());
+        stored["user_to_role"] = serde_json::json!({ "\"bob\"": "Mod" });
+        let legacy: RbacState = serde_json::from_value(stored).unwrap();
+        assert_eq!(legacy.get_user_roles("bob"), ["Mod"]);
+    }
+
+    #[cfg(feature = "gov")]
+    #[test]
+    /// A role should permit the action types of the role it inherits from,
+    /// and those of the roles that role inherits from in turn
+    fn test_role_inherits_two_levels() {
+        let mut rbac = RbacState::new();
+        rbac.def_role("Helper".to_string(), vec![ActionType::Report], vec![])
+            .unwrap();
+        rbac.def_role(
+            "Editor".to_string(),
+            vec![ActionType::RenameGroup],
+            vec!["Helper".to_string()],
+        )
+        .unwrap();
+        rbac.def_role(
+            "Chief".to_string(),
+            vec![ActionType::Kick],
+            vec!["Editor".to_string()],
+        )
+        .unwrap();
+        rbac.set_user_role("bob".to_string(), "Chief".to_string());
+        rbac.set_user_role("carol".to_string(), "Editor".to_string());
+
+        for action_type in [
+            ActionType::Kick,
+            ActionType::RenameGroup,
+            ActionType::Report,
+        ] {
+            assert!(rbac.action_type_authorized("bob", &action_type));
+        }
+        assert!(!rbac.action_type_authorized("carol", &ActionType::Kick));
+        assert!(rbac.action_type_authorized("carol", &ActionType::Report));
+        let kick = ActionMsg::Kick(KickAction {
+            target_user_id: "carol".to_string(),
+            metadata: ActionMetadata::new(
+                "bob".to_string(),
+                "kick".to_string(),
+                comm_grp(),
+                SystemTime::now(),
+            ),
+        });
+        assert!(rbac.action_authorized(&"bob".to_string(), &kick));
+
+        let stored: RbacState =
+            serde_json::from_value(serde_json::to_value(&rbac).unwrap()).unwrap();
+        assert_eq!(stored, rbac);
+    }
+
+    #[cfg(feature = "gov")]
+    #[test]
+    /// A role inheriting from two roles that share a parent should permit
+    /// the action types of all of them
+    fn test_role_inherits_diamond() {
+        let mut rbac = RbacState::new();
+        rbac.def_role("Base".to_string(), vec![ActionType::Report], vec![])
+            .unwrap();
+        for (role_name, action_type) in [
+            ("Renamer", ActionType::RenameGroup),
+            ("Topics", ActionType::SetTopicGroup),
+        ] {
+            rbac.def_role(
+                role_name.to_string(),
+                vec![action_type],
+                vec!["Base".to_string()],
+            )
+            .unwrap();
+        }
+        rbac.def_role(
+            "Editor".to_string(),
+            vec![],
+            vec!["Renamer".to_string(), "Topics".to_string()],
+        )
+        .unwrap();
+
+        let action_types = rbac.role_action_types("Editor").unwrap();
+        assert_eq!(action_types.len(), 3);
+        for action_type in [
+            ActionType::Report,
+            ActionType::RenameGroup,
+            ActionType::SetTopicGroup,
+        ] {
+            assert!(action_types.contains(&action_type));
+        }
+    }
+
+    #[cfg(feature = "gov")]
+    #[test]
+    /// Defining a role so that it would inherit from itself, directly or
+    /// through other roles, should be rejected and leave the roles unchanged
+    fn test_role_inheritance_cycle_rejected() {
+        let mut rbac = RbacState::new();
+        rbac.def_role("A".to_string(), vec![ActionType::Report], vec![])
+            .unwrap();
+        rbac.def_role("B".to_string(), vec![], vec!["A".to_string()])
+            .unwrap();
+        rbac.def_role("C".to_string(), vec![], vec!["B".to_string()])
+            .unwrap();
+        let before = rbac.clone();
+
+        assert!(rbac
+            .def_role("A".to_string(), vec![], vec!["C".to_string()])
+            .is_err());
+        assert!(rbac
+            .def_role("A".to_string(), vec![], vec!["A".to_string()])
+            .is_err());
+        assert!(rbac
+            .def_role("D".to_string(), vec![], vec!["Missing".to_string()])
+            .is_err());
+        assert_eq!(rbac, before);
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// Configured creator and invitee roles should replace `Mod` and
+    /// `BaseUser`, consistently across members
+    async fn test_configured_creator_and_invitee_roles() {
+        let mut admin = TestClientBundle::new("admin");
+        admin
+            .configs
+            .set_default_group_roles("Owner".to_string(), "Guest".to_string());
+        let (_, _, admin, members) = setup_registered_group_with_assert_ok(
+            AuthServiceState::new(),
+            DeliveryServiceState::new(),
+            admin,
+            vec![TestClientBundle::new("bob")],
+        )
+        .await;
+
+        for bundle in [&admin, &members[0]] {
+            let rbac = bundle.configs.get_roles(&comm_grp());
+            assert_eq!(rbac.get_user_roles("admin"), ["Owner"]);
+            assert_eq!(rbac.get_user_roles("bob"), ["Guest"]);
+            assert!(rbac.action_type_authorized("admin", &ActionType::Kick));
+            assert!(!rbac.action_type_authorized("bob", &ActionType::Kick));
+        }
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// Roles passed when creating a group should be given to its creator and
+    /// invitees, over the default roles of the client
+    async fn test_create_group_with_roles() {
+        let (as_state, ds_state, [mut admin, mut bob]) =
+            register_clients_assert_ok(["admin", "bob"]).await;
+        for bundle in [&mut admin, &mut bob] {
+            bundle.sync_as_assert_ok(&as_state).await;
+        }
+        admin
+            .configs
+            .set_default_group_roles("Mod".to_string(), "BaseUser".to_string());
+
+        let roles = GroupRoleConfig {
+            creator_role: "Owner".to_string(),
+            invitee_role: "Guest".to_string(),
+        };
+        let create_msgs = create_group_with_roles_msg(
+            &admin.name(),
+            &comm_grp(),
+            roles.clone(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        )
+        .unwrap();
+        assert!(create_msgs.is_empty());
+        let (invite_msgs, _) = pre_add_invite_msg(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+            vec![bob.new_key_package()],
+        );
+        admin.send_all_assert_ok(invite_msgs, &ds_state).await;
+        let (add_msgs, _) = add_msg(
+            &comm_grp(),
+            &vec![bob.name()],
+            admin.configs.deref_mut(),
+            &mut admin.backend,
+        );
+        admin.send_all_assert_ok(add_msgs, &ds_state).await;
+        let state_msgs = send_group_state_update(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        admin.send_all_assert_ok(state_msgs, &ds_state).await;
+        bob.sync_ds_assert_ok(&ds_state).await;
+        let accept_msgs = accept_msg(&comm_grp(), &mut bob.backend, &mut bob.configs);
+        bob.send_all_assert_ok(accept_msgs, &ds_state).await;
+
+        for bundle in [&admin, &bob] {
+            let rbac = bundle.configs.get_roles(&comm_grp());
+            assert_eq!(rbac.creator_role, roles.creator_role);
+            assert_eq!(rbac.invitee_role, roles.invitee_role);
+            assert_eq!(rbac.get_user_roles("admin"), ["Owner"]);
+            assert_eq!(rbac.get_user_roles("bob"), ["Guest"]);
+            assert!(rbac.action_type_authorized("admin", &ActionType::Invite));
+            assert!(!rbac.action_type_authorized("bob", &ActionType::Invite));
+        }
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// The yes votes a rename still needs should go down as votes arrive,
+    /// reach 0 once it passed, and be unreachable if it can no longer pass
+    async fn test_votes_needed_decrements() {
+        let mut admin = TestClientBundle::new("admin");
+        admin
+            .configs
+            .set_client_policies(vec![Box::new(VoteOnNameChangePolicy::new())]);
+        let (_, _, mut admin, _) = setup_registered_group_with_assert_ok(
+            AuthServiceState::new(),
+            DeliveryServiceState::new(),
+            admin,
+            vec![
+                TestClientBundle::new("bob"),
+                TestClientBundle::new("charlie"),
+            ],
+        )
+        .await;
+
+        // Bob is a BaseUser, so his renames are put to a vote
+        let rename = |action_id: &str| {
+            ActionMsg::RenameGroup(RenameGroupAction {
+                new_name: "new name".to_string(),
+                lamport_counter: 1,
+                metadata: ActionMetadata::new(
+                    "bob".to_string(),
+                    action_id.to_string(),
+                    comm_grp(),
+                    SystemTime::now(),
+                ),
+            })
+        };
+        let vote = |sender: &str, vote_value: &str, proposed_action_id: &str| {
+            ActionMsg::Vote(VoteAction {
+                vote_value: vote_value.to_string(),
+                proposed_action_id: proposed_action_id.to_string(),
+                proposed_action_type: ActionType::RenameGroup,
+                metadata: ActionMetadata::new(
+                    sender.to_string(),
+                    "".to_string(),
+                    comm_grp(),
+                    SystemTime::now(),
+                ),
+            })
+        };
+        policy_check_and_execute(rename("rename"), &comm_grp(), None, &mut admin.configs);
+        assert_eq!(
+            votes_needed(&comm_grp(), "rename", admin.configs.as_ref()),
+            2
+        );
+
+        policy_check_and_execute(
+            vote("admin", "yes", "rename"),
+            &comm_grp(),
+            None,
+            &mut admin.configs,
+        );
+        assert_eq!(
+            votes_needed(&comm_grp(), "rename", admin.configs.as_ref()),
+            1
+        );
+        policy_check_and_execute(
+            vote("bob", "no", "rename"),
+            &comm_grp(),
+            None,
+            &mut admin.configs,
+        );
+        assert_eq!(
+            votes_needed(&comm_grp(), "rename", admin.configs.as_ref()),
+            1
+        );
+        policy_check_and_execute(
+            vote("charlie", "yes", "rename"),
+            &comm_grp(),
+            None,
+            &mut admin.configs,
+        );
+        assert_eq!(admin.configs.get_group_name(&comm_grp()), "new name");
+        assert_eq!(
+            votes_needed(&comm_grp(), "rename", admin.configs.as_ref()),
+            0
+        );
+
+        // Two no votes out of three cannot be outweighed anymore
+        policy_check_and_execute(rename("doomed"), &comm_grp(), None, &mut admin.configs);
+        for sender in ["admin", "bob"] {
+            policy_check_and_execute(
+                vote(sender, "no", "doomed"),
+                &comm_grp(),
+                None,
+                &mut admin.configs,
+            );
+        }
+        assert_eq!(
+            votes_needed(&comm_grp(), "doomed", admin.configs.as_ref()),
+            VOTES_UNREACHABLE
+        );
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// Both members of a freshly set up group should report the same epoch
+    /// and list each other with their roles
+    async fn test_group_info_lists_members_and_epoch() {
+        let (_, _, mut admin, mut members) =
+            setup_registered_group_assert_ok("admin", &["bob"]).await;
+
+        let admin_info = group_info(&comm_grp(), admin.configs.deref_mut()).unwrap();
+        let bob_info = group_info(&comm_grp(), members[0].configs.deref_mut()).unwrap();
+        let expected_members = vec![
+            ("admin".to_string(), "Mod".to_string()),
+            ("bob".to_string(), "BaseUser".to_string()),
+        ];
+        assert_eq!(admin_info.members, expected_members);
+        assert_eq!(bob_info.members, expected_members);
+        assert!(admin_info.epoch > 0);
+        assert_eq!(admin_info.epoch, bob_info.epoch);
+
+        assert!(group_info(
+            &CommGroupId::new(&"community".to_string(), &"unknown".to_string()),
+            admin.configs.deref_mut()
+        )
+        .is_none());
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// The credential fingerprint of a member should be the same across
+    /// calls and as seen by every member, and differ between members
+    async fn test_group_info_key_fingerprints_stable() {
+        let (_, _, mut admin, mut members) =
+            setup_registered_group_assert_ok("admin", &["bob"]).await;
+
+        let first = group_info(&comm_grp(), admin.configs.deref_mut())
+            .unwrap()
+            .key_fingerprints;
+        let second = group_info(&comm_grp(), admin.configs.deref_mut())
+            .unwrap()
+            .key_fingerprints;
+        let bob_view = group_info(&comm_grp(), members[0].configs.deref_mut())
+            .unwrap()
+            .key_fingerprints;
+        assert_eq!(first.len(), 2);
+        assert_eq!(first, second);
+        assert_eq!(first, bob_view);
+        assert_ne!(first["admin"], first["bob"]);
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// A bulk role assignment should apply to all of its targets in one
+    /// commit, and not at all if one of the targets is not a member
+    async fn test_set_roles_bulk_all_or_nothing() {
+        let (_, ds_state, mut admin, mut members) =
+            setup_registered_group_assert_ok("admin", &["bob", "charlie", "dave"]).await;
+
+        let assignments = vec![
+            ("bob".to_string(), "Mod".to_string()),
+            ("charlie".to_string(), "Mod".to_string()),
+            ("dave".to_string(), "Mod".to_string()),
+        ];
+        let msgs = set_roles_bulk_msg(
+            &comm_grp(),
+            assignments.clone(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        assert_eq!(msgs.len(), 1);
+        admin.send_all_assert_ok(msgs, &ds_state).await;
+        for member in members.iter_mut() {
+            member.sync_ds_assert_ok(&ds_state).await;
+        }
+
+        let bob_roles = members[0].configs.get_roles(&comm_grp());
+        for (user_id, role_name) in assignments.iter() {
+            assert_eq!(bob_roles.get_user_roles(user_id), [role_name.as_str()]);
+        }
+
+        // "eve" is not a member, so bob should not be demoted either
+        let msgs = set_roles_bulk_msg(
+            &comm_grp(),
+            vec![
+                ("bob".to_string(), "BaseUser".to_string()),
+                ("eve".to_string(), "BaseUser".to_string()),
+            ],
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        admin.send_all_assert_ok(msgs, &ds_state).await;
+        members[1].sync_ds_assert_ok(&ds_state).await;
+        for bundle in [&admin, &members[1]] {
+            let rbac = bundle.configs.get_roles(&comm_grp());
+            assert_eq!(rbac.get_user_roles("bob"), ["Mod"]);
+            assert!(rbac.user_to_roles.get("eve").is_none());
+        }
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// Welcomes to two groups of the same community should be stored and
+    /// retrieved independently, and a welcome stored under the legacy
+    /// `(community, community)` key should still be found
+    async fn test_welcomes_keyed_by_community_and_group() {
+        let (as_state, _, [mut admin, mut bob]) =
+            register_clients_assert_ok(["admin", "bob"]).await;
+        admin.sync_as_assert_ok(&as_state).await;
+
+        let community = "community".to_string();
+        let comm_grps = [
+            CommGroupId::new(&community, &"first".to_string()),
+            CommGroupId::new(&community, &"second".to_string()),
+        ];
+        let mut welcomes = vec![];
+        for comm_grp in comm_grps.iter() {
+            let _ = create_group_msg(
+                &admin.name(),
+                comm_grp,
+                &mut admin.backend,
+                admin.configs.deref_mut(),
+            );
+            let (invite_msgs, _) = pre_add_invite_msg(
+                &admin.name(),
+                comm_grp,
+                &mut admin.backend,
+                admin.configs.deref_mut(),
+                vec![bob.new_key_package()],
+            );
+            welcomes.extend(invite_msgs.into_iter().filter_map(|msg| match msg {
+                OnWireMessage::UserInvite { welcome, .. } => Some(welcome),
+                _ => None,
+            }));
+        }
+        assert_eq!(welcomes.len(), 2);
+        let encoded = |welcome: &Welcome| welcome.tls_serialize_detached().unwrap();
+
+        for (comm_grp, welcome) in comm_grps.iter().zip(welcomes.iter()) {
+            bob.configs.store_welcome(comm_grp, welcome.clone());
+        }
+        for (comm_grp, welcome) in comm_grps.iter().zip(welcomes.iter()) {
+            assert!(bob.configs.contains_welcome(comm_grp));
+            let stored = bob.configs.get_welcome_clone(comm_grp).unwrap();
+            assert_eq!(encoded(&stored), encoded(welcome));
+        }
+        let removed = bob.configs.remove_welcome(&comm_grps[0]).unwrap();
+        assert_eq!(encoded(&removed), encoded(&welcomes[0]));
+        assert!(!bob.configs.contains_welcome(&comm_grps[0]));
+        assert!(bob.configs.contains_welcome(&comm_grps[1]));
+
+        // A state persisted before welcomes were keyed by group
+        let mut legacy = ClientData::new(
+            bob.name(),
+            bob.credential().clone(),
+            generate_verification_key(),
+        );
+        legacy.pending_welcomes.insert(
+            (community.clone(), community.clone()),
+            LocalWelcome {
+                welcome: welcomes[1].clone(),
+            },
+        );
+        let mut legacy: ClientData =
+            serde_json::from_str(&serde_json::to_string(&legacy).unwrap()).unwrap();
+        assert!(legacy.contains_welcome(&comm_grps[1]));
+        let removed = legacy.remove_welcome(&comm_grps[1]).unwrap();
+        assert_eq!(encoded(&removed), encoded(&welcomes[1]));
+        assert!(legacy.pending_welcomes.is_empty());
+    }
+
+    /// Sets the topic of the group to the payload of the custom action
+    #[cfg(feature = "gov")]
+    #[derive(Debug)]
+    struct TopicHandler;
+
+    #[cfg(feature = "gov")]
+    impl CustomActionHandler for TopicHandler {
+        fn handle(
+            &self,
+            payload: &str,
+            metadata: &ActionMetadata,
+            client_data: &mut dyn ClientDataProvider,
+        ) {
+            client_data.set_group_topic(&metadata.community_group_id, payload.to_string());
+        }
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// Executing a custom action should dispatch it to the handler registered
+    /// for its kind, every time, and do nothing for other kinds
+    async fn test_custom_action_dispatched_to_handler() {
+        let (_, _, mut admin, _) = setup_registered_group_assert_ok("admin", &["bob"]).await;
+        admin
+            .configs
+            .register_custom_action_handler("topic".to_string(), Box::new(TopicHandler));
+        let original_topic = admin.configs.get_group_topic(&comm_grp()).to_string();
+
+        let admin_name = admin.name();
+        let custom_action = |data: &str| CustomAction {
+            data: data.to_string(),
+            metadata: ActionMetadata::new(
+                admin_name.clone(),
+                data.to_string(),
+                comm_grp(),
+                SystemTime::now(),
+            ),
+        };
+        custom_action("reputation:{\"user_id\":\"bob\"}").execute(admin.configs.deref_mut());
+        assert_eq!(admin.configs.get_group_topic(&comm_grp()), original_topic);
+
+        custom_action("topic:Moderated").execute(admin.configs.deref_mut());
+        assert_eq!(admin.configs.get_group_topic(&comm_grp()), "Moderated");
+        custom_action("topic:Moderated: strictly").execute(admin.configs.deref_mut());
+        assert_eq!(
+            admin.configs.get_group_topic(&comm_grp()),
+            "Moderated: strictly"
+        );
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// Inviting a banned user should not stage them, until they are unbanned
+    async fn test_banned_user_not_invited_until_unbanned() {
+        let (as_state, ds_state, [mut admin, bob, mut charlie]) =
+            register_clients_assert_ok(["admin", "bob", "charlie"]).await;
+        let mut members = vec![bob];
+        setup_group_assert_ok(&mut admin, &mut members, &as_state, &ds_state).await;
+        admin.sync_as_assert_ok(&as_state).await;
+
+        let ban_msgs = check_action_msg_and_get_mls(
+            &comm_grp(),
+            ActionMsg::Ban(BanAction {
+                target_user_id: charlie.name(),
+                metadata: ActionMetadata::new(
+                    admin.name(),
+                    "ban".to_string(),
+                    comm_grp(),
+                    SystemTime::now(),
+                ),
+            }),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        admin.send_all_assert_ok(ban_msgs, &ds_state).await;
+        let (invite_msgs, _) = pre_add_invite_msg(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+            vec![charlie.new_key_package()],
+        );
+        admin.send_all_assert_ok(invite_msgs, &ds_state).await;
+        members[0].sync_ds_assert_ok(&ds_state).await;
+        for bundle in [&admin, &members[0]] {
+            let shared_state = bundle.configs.get_shared_state(&comm_grp());
+            assert!(shared_state.banned_users.contains("charlie"));
+            assert!(!shared_state.to_add_invitees.contains_key("charlie"));
+            assert!(!shared_state.rbac.user_to_roles.contains_key("charlie"));
+        }
+
+        let unban_msgs = check_action_msg_and_get_mls(
+            &comm_grp(),
+            ActionMsg::Unban(UnbanAction {
+                target_user_id: charlie.name(),
+                metadata: ActionMetadata::new(
+                    admin.name(),
+                    "unban".to_string(),
+                    comm_grp(),
+                    SystemTime::now(),
+                ),
+            }),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        admin.send_all_assert_ok(unban_msgs, &ds_state).await;
+        let (invite_msgs, _) = pre_add_invite_msg(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+            vec![charlie.new_key_package()],
+        );
+        admin.send_all_assert_ok(invite_msgs, &ds_state).await;
+        members[0].sync_ds_assert_ok(&ds_state).await;
+        for bundle in [&admin, &members[0]] {
+            let shared_state = bundle.configs.get_shared_state(&comm_grp());
+            assert!(shared_state.banned_users.is_empty());
+            assert!(shared_state.to_add_invitees.contains_key("charlie"));
+        }
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// Removing two of three members, along with a non-member, should remove
+    /// both of them in a single commit and keep the third
+    async fn test_remove_members_in_one_commit() {
+        let (_, ds_state, mut admin, mut members) =
+            setup_registered_group_assert_ok("admin", &["bob", "charlie", "dave"]).await;
+
+        for removed in ["bob", "charlie"] {
+            let kick_msgs = pre_kick_msg(
+                &comm_grp(),
+                &removed.to_string(),
+                &mut admin.backend,
+                admin.configs.deref_mut(),
+            );
+            admin.send_all_assert_ok(kick_msgs, &ds_state).await;
+        }
+        let dave = &mut members[2];
+        dave.sync_ds_assert_ok(&ds_state).await;
+
+        let epoch_before = admin.configs.get_group_epochs()[0].1;
+        let remove_msgs = remove_members_msg(
+            &comm_grp(),
+            vec!["bob".to_string(), "eve".to_string(), "charlie".to_string()],
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        assert_eq!(remove_msgs.len(), 1);
+        admin.send_all_assert_ok(remove_msgs, &ds_state).await;
+        dave.sync_ds_assert_ok(&ds_state).await;
+
+        assert_eq!(admin.configs.get_group_epochs()[0].1, epoch_before + 1);
+        for bundle in [&admin, &*dave] {
+            assert!(!bundle.configs.is_member(&comm_grp(), "bob"));
+            assert!(!bundle.configs.is_member(&comm_grp(), "charlie"));
+            assert!(bundle.configs.is_member(&comm_grp(), "dave"));
+        }
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// Inviting two users, one of them without any key package left on the
+    /// DS, should invite and then add the other one only
+    async fn test_invite_users_partial_success() {
+        let (as_state, ds_state, [mut admin, mut bob, charlie]) =
+            register_clients_assert_ok(["admin", "bob", "charlie"]).await;
+        setup_group_assert_ok(&mut admin, &mut [], &as_state, &ds_state).await;
+        ds_state
+            .user_key_packages
+            .get_mut("charlie")
+            .unwrap()
+            .clear();
+
+        let (invite_msgs, failed_invitees) = invite_users(
+            &comm_grp(),
+            &[bob.name(), charlie.name()],
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+            &mut LocalAsTransport(&as_state),
+            &mut LocalDsTransport(&ds_state),
+        );
+        assert_eq!(failed_invitees, vec![charlie.name()]);
+        admin.send_all_assert_ok(invite_msgs, &ds_state).await;
+        let to_add_invitees = &admin.configs.get_shared_state(&comm_grp()).to_add_invitees;
+        assert!(to_add_invitees.contains_key("bob"));
+        assert!(!to_add_invitees.contains_key("charlie"));
+
+        let (add_msgs, dropped_invitees) = add_msg(
+            &comm_grp(),
+            &vec![bob.name()],
+            admin.configs.deref_mut(),
+            &mut admin.backend,
+        );
+        assert!(dropped_invitees.is_empty());
+        admin.send_all_assert_ok(add_msgs, &ds_state).await;
+        assert!(admin.configs.is_member(&comm_grp(), &bob.name()));
+        bob.sync_ds_assert_ok(&ds_state).await;
+        assert!(bob.configs.get_ref_group(&comm_grp()).is_some());
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// Rejoining should discard the local group and relay a re-invite request
+    /// to the admin, after which the admin can remove and invite the member
+    /// again to restore its membership
+    async fn test_rejoin_group_restores_membership() {
+        let (_, ds_state, mut admin, mut members) =
+            setup_registered_group_assert_ok("admin", &["bob"]).await;
+        let mut bob = members.remove(0);
+
+        let rejoin_msgs = rejoin_group_msg(&comm_grp(), bob.configs.deref_mut());
+        assert!(bob.configs.get_ref_group(&comm_grp()).is_none());
+        match &rejoin_msgs[..] {
+            [OnWireMessage::UserRequestReinvite { recipients, .. }] => {
+                assert_eq!(recipients, &vec![admin.name()])
+            }
+            other => panic!("Expected a single re-invite request, found {:?}", other),
+        }
+        bob.send_all_assert_ok(rejoin_msgs, &ds_state).await;
+
+        let sync_responses =
+            handle_onwire_msg_ds_local(sync_msg(admin.configs.as_ref(), vec![]), &ds_state).await;
+        let parsed_msgs = admin.parse_msgs(&sync_responses);
+        assert!(parsed_msgs.iter().any(|msg| matches!(
+            msg,
+            ClientParsedMsg::ReinviteRequest { requester, comm_grp: grp }
+                if requester == "bob" && grp == &comm_grp()
+        )));
+
+        let kick_msgs = pre_kick_msg(
+            &comm_grp(),
+            &bob.name(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        admin.send_all_assert_ok(kick_msgs, &ds_state).await;
+        let remove_msgs = remove_other_or_self_msg(
+            &comm_grp(),
+            &bob.name(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        admin.send_all_assert_ok(remove_msgs, &ds_state).await;
+        assert!(!admin.configs.is_member(&comm_grp(), &bob.name()));
+        // Messages of the discarded group are dropped
+        bob.sync_ds_assert_ok(&ds_state).await;
+        assert!(bob.configs.get_ref_group(&comm_grp()).is_none());
+
+        let (invite_msgs, _) = pre_add_invite_msg(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+            vec![bob.new_key_package()],
+        );
+        admin.send_all_assert_ok(invite_msgs, &ds_state).await;
+        let (add_msgs, _) = add_msg(
+            &comm_grp(),
+            &vec![bob.name()],
+            admin.configs.deref_mut(),
+            &mut admin.backend,
+        );
+        admin.send_all_assert_ok(add_msgs, &ds_state).await;
+        let state_msgs = send_group_state_update(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        admin.send_all_assert_ok(state_msgs, &ds_state).await;
+
+        bob.sync_ds_assert_ok(&ds_state).await;
+        let accept_msgs = accept_msg(&comm_grp(), &mut bob.backend, &mut bob.configs);
+        bob.send_all_assert_ok(accept_msgs, &ds_state).await;
+        admin.sync_ds_assert_ok(&ds_state).await;
+
+        assert!(bob.configs.is_shared_gov_state_initialized(&comm_grp()));
+        for bundle in [&admin, &bob] {
+            assert!(bundle.configs.is_member(&comm_grp(), &bob.name()));
+        }
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// Declining an invite that is only a pending welcome should drop the
+    /// welcome without sending anything, while declining after being added
+    /// should also send the self-removal once the decline went through
+    async fn test_remove_self_on_decline() {
+        let (as_state, ds_state, [mut admin, charlie, mut bob]) =
+            register_clients_assert_ok(["admin", "charlie", "bob"]).await;
+        let mut members = vec![charlie];
+        setup_group_assert_ok(&mut admin, &mut members, &as_state, &ds_state).await;
+
+        // Only a pending welcome: dropped locally
+        let pending_grp = CommGroupId::new(&"community".to_string(), &"pending".to_string());
+        let _ = create_group_msg(
+            &admin.name(),
+            &pending_grp,
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        let (invite_msgs, _) = pre_add_invite_msg(
+            &admin.name(),
+            &pending_grp,
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+            vec![bob.new_key_package()],
+        );
+        let welcome = invite_msgs
+            .into_iter()
+            .find_map(|msg| match msg {
+                OnWireMessage::UserInvite { welcome, .. } => Some(welcome),
+                _ => None,
+            })
+            .unwrap();
+        bob.configs.store_welcome(&pending_grp, welcome);
+        let decline_msgs = pre_decline_msg(&pending_grp, &mut bob.backend, &mut bob.configs);
+        assert!(decline_msgs.is_empty());
+        assert!(!bob.configs.contains_welcome(&pending_grp));
+        assert!(bob.configs.get_ref_group(&pending_grp).is_none());
+        assert!(
+            remove_self_on_decline(&pending_grp, &mut bob.backend, &mut bob.configs).is_empty()
+        );
+
+        // Already added to the MLS group: the decline is followed by leaving it
+        let (invite_msgs, _) = pre_add_invite_msg(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+            vec![bob.new_key_package()],
+        );
+        admin.send_all_assert_ok(invite_msgs, &ds_state).await;
+        let (add_msgs, _) = add_msg(
+            &comm_grp(),
+            &vec![bob.name()],
+            admin.configs.deref_mut(),
+            &mut admin.backend,
+        );
+        admin.send_all_assert_ok(add_msgs, &ds_state).await;
+        let state_msgs = send_group_state_update(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        admin.send_all_assert_ok(state_msgs, &ds_state).await;
+        bob.sync_ds_assert_ok(&ds_state).await;
+        assert!(bob.configs.get_ref_group(&comm_grp()).is_some());
+
+        let decline_msgs = pre_decline_msg(&comm_grp(), &mut bob.backend, &mut bob.configs);
+        assert!(!decline_msgs.is_empty());
+        bob.send_all_assert_ok(decline_msgs, &ds_state).await;
+        let remove_msgs = remove_self_on_decline(&comm_grp(), &mut bob.backend, &mut bob.configs);
+        assert!(!remove_msgs.is_empty());
+        bob.send_all_assert_ok(remove_msgs, &ds_state).await;
+
+        admin.sync_ds_assert_ok(&ds_state).await;
+        let admin_group = admin.configs.get_ref_group(&comm_grp()).unwrap();
+        let pending_removals = admin_group
+            .borrow()
+            .pending_proposals()
+            .filter(|proposal| matches!(proposal.proposal(), Proposal::Remove(_)))
+            .count();
+        assert_eq!(pending_removals, 1);
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// A member who left should be marked as such in everyone's roles until
+    /// the removal commit, after which their entry should be gone
+    async fn test_leave_marks_member_until_removed() {
+        let (_, ds_state, mut admin, mut members) =
+            setup_registered_group_assert_ok("admin", &["bob", "charlie"]).await;
+        let mut charlie = members.remove(1);
+        let mut bob = members.remove(0);
+
+        let leave_msgs = pre_leave_msg(&comm_grp(), &mut bob.backend, bob.configs.deref_mut());
+        bob.send_all_assert_ok(leave_msgs, &ds_state).await;
+        admin.sync_ds_assert_ok(&ds_state).await;
+        charlie.sync_ds_assert_ok(&ds_state).await;
+        for bundle in [&admin, &bob, &charlie] {
+            let rbac = bundle.configs.get_roles(&comm_grp());
+            assert!(rbac.has_user_left("bob"));
+            assert!(rbac.user_to_roles.contains_key("bob"));
+            assert!(!rbac.has_user_left("charlie"));
+        }
+
+        let remove_msgs = remove_other_or_self_msg(
+            &comm_grp(),
+            &bob.name(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        admin.send_all_assert_ok(remove_msgs, &ds_state).await;
+        charlie.sync_ds_assert_ok(&ds_state).await;
+        for bundle in [&admin, &charlie] {
+            assert!(!bundle.configs.is_member(&comm_grp(), &bob.name()));
+            let rbac = bundle.configs.get_roles(&comm_grp());
+            assert!(!rbac.has_user_left("bob"));
+            assert!(!rbac.user_to_roles.contains_key("bob"));
+        }
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// Inviting a user with two devices should fetch a key package for each
+    /// device, add both, and deliver the welcome to both
+    async fn test_invite_user_with_two_devices() {
+        let (as_state, ds_state, [mut admin, mut phone]) =
+            register_clients_assert_ok(["admin", "bob#phone"]).await;
+        let mut laptop = TestClientBundle::new("bob#laptop");
+        laptop
+            .register_device_assert_ok(&phone, &as_state, &ds_state)
+            .await;
+        for bundle in [&mut admin, &mut phone, &mut laptop] {
+            bundle.sync_as_assert_ok(&as_state).await;
+        }
+        assert_eq!(
+            ds_state.device_names("bob"),
+            vec!["bob#laptop".to_string(), "bob#phone".to_string()]
+        );
+        assert_eq!(
+            identity_to_user_device(phone.credential().identity()),
+            Some(("bob".to_string(), Some("phone".to_string())))
+        );
+
+        let _ = create_group_msg(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        let lookup_responses = handle_onwire_msg_ds_local(
+            OnWireMessage::UserKeyPackageLookup {
+                user_name: admin.name(),
+                queried_users: vec!["bob".to_string()],
+            },
+            &ds_state,
+        )
+        .await;
+        let key_packages = match &lookup_responses[0] {
+            OnWireMessage::DSKeyPackageResponse {
+                queried_user_key_packages,
+                ..
+            } => queried_user_key_packages.clone(),
+            other => panic!("Expected key packages, found {:?}", other),
+        };
+        assert_eq!(key_packages.len(), 2);
+
+        let (invite_msgs, _) = pre_add_invite_msg(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+            key_packages,
+        );
+        admin.send_all_assert_ok(invite_msgs, &ds_state).await;
+        let (add_msgs, _) = add_msg(
+            &comm_grp(),
+            &vec!["bob".to_string()],
+            admin.configs.deref_mut(),
+            &mut admin.backend,
+        );
+        admin.send_all_assert_ok(add_msgs, &ds_state).await;
+        let state_msgs = send_group_state_update(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        admin.send_all_assert_ok(state_msgs, &ds_state).await;
+
+        for device in [&mut phone, &mut laptop] {
+            let sync_responses =
+                handle_onwire_msg_ds_local(sync_msg(device.configs.as_ref(), vec![]), &ds_state)
+                    .await;
+            let parsed_msgs = device.parse_msgs(&sync_responses);
+            assert!(parsed_msgs.iter().any(|msg| matches!(
+                msg,
+                ClientParsedMsg::NewInvite { inviter, comm_grp: grp }
+                    if inviter == "admin" && grp == &comm_grp()
+            )));
+            let accept_msgs = accept_msg(&comm_grp(), &mut device.backend, &mut device.configs);
+            device.send_all_assert_ok(accept_msgs, &ds_state).await;
+        }
+        admin.sync_ds_assert_ok(&ds_state).await;
+
+        let members = admin.configs.get_group_members(&comm_grp());
+        for device in [&phone, &laptop] {
+            assert!(members.contains(&device.name()));
+            assert!(device.configs.is_member(&comm_grp(), &admin.name()));
+        }
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// Once a user registered an identity, another device of theirs should
+    /// only register if endorsed by a registered identity of the same user
+    async fn test_device_needs_endorsement() {
+        let (as_state, _, [phone, mut mallory]) =
+            register_clients_assert_ok(["bob#phone", "mallory"]).await;
+        let spy = TestClientBundle::new("bob#spy");
+        let laptop = TestClientBundle::new("bob#laptop");
+        let register = |endorsement| {
+            register_msg_as_with_endorsement(
+                spy.credential().clone(),
+                spy.configs.get_keypair().public_key(),
+                String::new(),
+                endorsement,
+            )
+            .remove(0)
+        };
+        let rejection = UserRequestErrors::InvalidDeviceEndorsement.to_string();
+        let is_rejected = |responses: Vec<OnWireMessage>| {
+            matches!(
+                &responses[0],
+                OnWireMessage::ASResult {
+                    request_valid: false,
+                    explanation: Some(explanation),
+                    ..
+                } if *explanation == rejection
+            )
+        };
+
+        // Unendorsed, endorsed by another user, or with the endorsement of another device
+        for endorsement in [
+            None,
+            Some(spy.endorsement_by(&mallory)),
+            Some(laptop.endorsement_by(&phone)),
+        ] {
+            assert!(is_rejected(
+                handle_onwire_msg_as_local(register(endorsement), &as_state).await
+            ));
+        }
+        assert!(!as_state.credential_entries.contains_key("bob#spy"));
+
+        let responses =
+            handle_onwire_msg_as_local(register(Some(spy.endorsement_by(&phone))), &as_state).await;
+        assert_all_feedback_ok(&mallory.parse_msgs(&responses));
+        assert!(as_state.credential_entries.contains_key("bob#spy"));
+    }
+
+    #[cfg(feature = "gov")]
+    #[test]
+    /// Group states with the same content should have the same canonical bytes
+    /// and hash, whatever order their entries were added in
+    fn test_canonical_bytes_ignore_insertion_order() {
+        let mut admin = TestClientBundle::new("admin");
+        let _ = create_group_msg(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        let mut state_a = admin.configs.get_shared_state(&comm_grp()).clone();
+        let mut state_b = state_a.clone();
+
+        state_a.to_be_removed_members = vec!["bob".to_string(), "charlie".to_string()];
+        state_b.to_be_removed_members = vec!["charlie".to_string(), "bob".to_string()];
+        state_a.rbac.role_defs.insert(
+            "Helper".to_string(),
+            vec![ActionType::TextMsg, ActionType::Report],
+        );
+        state_b.rbac.role_defs.insert(
+            "Helper".to_string(),
+            vec![ActionType::Report, ActionType::TextMsg],
+        );
+        for user in ["bob", "charlie"] {
+            state_a.banned_users.insert(user.to_string());
+        }
+        for user in ["charlie", "bob"] {
+            state_b.banned_users.insert(user.to_string());
+        }
+
+        assert_eq!(state_a.canonical_bytes(), state_b.canonical_bytes());
+        assert_eq!(state_a.stable_hash(), state_b.stable_hash());
+        state_b.banned_users.insert("diane".to_string());
+        assert_ne!(state_a.stable_hash(), state_b.stable_hash());
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// Two registered users should be able to exchange direct messages without
+    /// setting up any community
+    async fn test_direct_messages() {
+        let (as_state, ds_state, [mut alice, mut bob]) =
+            register_clients_assert_ok(["alice", "bob"]).await;
+        for bundle in [&mut alice, &mut bob] {
+            bundle.sync_as_assert_ok(&as_state).await;
+        }
+        let dm_grp = CommGroupId::dm(&alice.name(), &bob.name());
+        assert_eq!(dm_grp, CommGroupId::dm(&bob.name(), &alice.name()));
+        assert!(dm_grp.is_dm());
+
+        // Alice opens the conversation
+        let bob_kp = bob.new_key_package();
+        let (dm_msgs, dropped_invitees) = create_dm(
+            &bob.name(),
+            vec![bob_kp],
+            &mut alice.backend,
+            alice.configs.deref_mut(),
+        );
+        assert!(dropped_invitees.is_empty());
+        alice.send_all_assert_ok(dm_msgs, &ds_state).await;
+        let (add_msgs, _) = add_msg(
+            &dm_grp,
+            &vec![bob.name()],
+            alice.configs.deref_mut(),
+            &mut alice.backend,
+        );
+        alice.send_all_assert_ok(add_msgs, &ds_state).await;
+        let state_msgs = send_group_state_update(
+            &alice.name(),
+            &dm_grp,
+            &mut alice.backend,
+            alice.configs.deref_mut(),
+        );
+        alice.send_all_assert_ok(state_msgs, &ds_state).await;
+
+        // Bob joins it and answers
+        bob.sync_ds_assert_ok(&ds_state).await;
+        let accept_msgs = accept_msg(&dm_grp, &mut bob.backend, &mut bob.configs);
+        bob.send_all_assert_ok(accept_msgs, &ds_state).await;
+        alice.sync_ds_assert_ok(&ds_state).await;
+        let text_msgs = send_dm(
+            &alice.name(),
+            "hi alice".to_string(),
+            &mut bob.backend,
+            bob.configs.deref_mut(),
+        );
+        bob.send_all_assert_ok(text_msgs, &ds_state).await;
+
+        let sync_responses =
+            handle_onwire_msg_ds_local(sync_msg(alice.configs.as_ref(), vec![]), &ds_state).await;
+        let parsed_msgs = alice.parse_msgs(&sync_responses);
+        assert!(concat_string_in_decrypted_msgs(parsed_msgs).contains("hi alice"));
+        assert_eq!(
+            alice.configs.get_group_members(&dm_grp),
+            vec![alice.name(), bob.name()]
+        );
+    }
+
+    #[cfg(feature = "gov")]
+    #[test]
+    /// Generated key packages should expire after the lifetime configured on
+    /// the backend, or the one given explicitly
+    fn test_key_package_lifetime_configured() {
+        let mut alice = TestClientBundle::new("alice");
+        let credential = alice.credential().clone();
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let week = Duration::from_secs(60 * 60 * 24 * 7);
+        alice.backend.key_package_lifetime = week;
+
+        let key_package = alice
+            .backend
+            .generate_default_key_package_and_store_bundle(&credential);
+        let (_, expiry) = alice
+            .backend
+            .key_package_lifetime_bounds(&key_package)
+            .unwrap();
+        assert!((now + week.as_secs()..now + week.as_secs() + 60).contains(&expiry));
+        assert!(alice
+            .backend
+            .key_package_is_valid_at(&key_package, alice.configs.now()));
+
+        let key_package = alice
+            .backend
+            .generate_key_package_with_lifetime(&credential, Duration::from_secs(60));
+        let (_, expiry) = alice
+            .backend
+            .key_package_lifetime_bounds(&key_package)
+            .unwrap();
+        assert!((now + 60..now + 120).contains(&expiry));
+    }
+
+    #[cfg(feature = "gov")]
+    #[test]
+    /// The timestamps of actions and the expiry of key packages should follow
+    /// the clock of the client rather than the system clock
+    fn test_client_clock_drives_action_and_key_package_times() {
+        let clock = MockClock::new(SystemTime::now());
+        let mut admin = TestClientBundle::new("admin").with_clock(SharedClock::new(clock.clone()));
+        let mut bob = TestClientBundle::new("bob");
+        let _ = create_group_msg(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+
+        clock.advance(Duration::from_secs(60));
+        let _ = rename_group_msg(
+            &comm_grp(),
+            "renamed",
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        match admin.configs.pop_pending_action(&comm_grp()) {
+            Some(ActionMsg::RenameGroup(rename)) => {
+                assert_eq!(rename.metadata.sent_at, Some(clock.now()))
+            }
+            other => panic!("Expected a pending rename, found {:?}", other),
+        }
+
+        let key_package = bob.new_kps(1).remove(0);
+        assert!(admin
+            .backend
+            .key_package_is_valid_at(&key_package, admin.configs.now()));
+        clock.advance(DEFAULT_KEY_PACKAGE_LIFETIME * 2);
+        let (_, dropped_invitees) = pre_add_invite_msg(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+            vec![key_package],
+        );
+        assert_eq!(dropped_invitees, vec![bob.name()]);
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// Inviting with an expired and a valid key package should only stage the
+    /// valid invitee, and report the other as dropped
+    async fn test_invite_drops_expired_key_package() {
+        let (as_state, ds_state, [mut admin, mut bob, mut charlie]) =
+            register_clients_assert_ok(["admin", "bob", "charlie"]).await;
+        admin.sync_as_assert_ok(&as_state).await;
+        let _ = create_group_msg(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+
+        // A lifetime from the epoch to one second after it
+        let encoded_lifetime = [0u64.to_be_bytes(), 1u64.to_be_bytes()].concat();
+        let expired_lifetime = LifetimeExtension::tls_deserialize(&mut encoded_lifetime.as_slice())
+            .expect("Cannot decode lifetime");
+        let bob_credential = bob.credential().clone();
+        let bob_credential_bundle = bob.backend.read_credential_bundle(&bob_credential);
+        let expired_kp = bob
+            .backend
+            .generate_key_package_bundle(
+                &bob_credential_bundle,
+                vec![Extension::LifeTime(expired_lifetime)],
+            )
+            .key_package()
+            .clone();
+        let valid_kp = charlie.new_kps(1).remove(0);
+        assert!(!admin
+            .backend
+            .key_package_is_valid_at(&expired_kp, admin.configs.now()));
+        assert!(admin
+            .backend
+            .key_package_is_valid_at(&valid_kp, admin.configs.now()));
+
+        let (invite_msgs, dropped_invitees) = pre_add_invite_msg(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+            vec![expired_kp, valid_kp],
+        );
+        assert_eq!(dropped_invitees, vec![bob.name()]);
+        admin.send_all_assert_ok(invite_msgs, &ds_state).await;
+
+        // Bob was never staged, so adding him would add nobody
+        let (add_msgs, unresolved_invitees) = add_msg(
+            &comm_grp(),
+            &vec![bob.name(), charlie.name()],
+            admin.configs.deref_mut(),
+            &mut admin.backend,
+        );
+        assert!(add_msgs.is_empty());
+        assert_eq!(unresolved_invitees, vec![bob.name()]);
+
+        let (add_msgs, dropped_invitees) = add_msg(
+            &comm_grp(),
+            &vec![charlie.name()],
+            admin.configs.deref_mut(),
+            &mut admin.backend,
+        );
+        assert!(dropped_invitees.is_empty());
+        assert!(add_msgs.iter().any(|msg| matches!(
+            msg,
+            OnWireMessage::UserInvite { invitee_names, .. } if invitee_names == &vec![charlie.name()]
+        )));
+    }
+
+    #[cfg(feature = "gov")]
+    #[test]
+    /// Adding nobody, or only invitees whose staged key packages expired,
+    /// should generate no message rather than fail
+    fn test_add_without_valid_key_package_adds_nobody() {
+        let mut admin = TestClientBundle::new("admin");
+        let mut bob = TestClientBundle::new("bob");
+        let _ = create_group_msg(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+
+        let (add_msgs, dropped_invitees) = add_msg(
+            &comm_grp(),
+            &vec![],
+            admin.configs.deref_mut(),
+            &mut admin.backend,
+        );
+        assert!(add_msgs.is_empty());
+        assert!(dropped_invitees.is_empty());
+
+        // A lifetime from the epoch to one second after it
+        let encoded_lifetime = [0u64.to_be_bytes(), 1u64.to_be_bytes()].concat();
+        let expired_lifetime = LifetimeExtension::tls_deserialize(&mut encoded_lifetime.as_slice())
+            .expect("Cannot decode lifetime");
+        let bob_credential = bob.credential().clone();
+        let bob_credential_bundle = bob.backend.read_credential_bundle(&bob_credential);
+        let expired_kp = bob
+            .backend
+            .generate_key_package_bundle(
+                &bob_credential_bundle,
+                vec![Extension::LifeTime(expired_lifetime)],
+            )
+            .key_package()
+            .clone();
+        admin
+            .configs
+            .store_to_add_invitee_key_pack(&comm_grp(), expired_kp);
+
+        let (add_msgs, dropped_invitees) = add_msg(
+            &comm_grp(),
+            &vec![bob.name()],
+            admin.configs.deref_mut(),
+            &mut admin.backend,
+        );
+        assert!(add_msgs.is_empty());
+        assert_eq!(dropped_invitees, vec![bob.name()]);
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// A request to join the group should be dropped if the joiner is banned,
+    /// and kept as a pending proposal if an invite pre-approved them
+    async fn test_join_proposals_checked_against_bans_and_invites() {
+        let (as_state, ds_state, [mut admin, bob, mut charlie, mut dave]) =
+            register_clients_assert_ok(["admin", "bob", "charlie", "dave"]).await;
+        let mut members = vec![bob];
+        setup_group_assert_ok(&mut admin, &mut members, &as_state, &ds_state).await;
+        admin.sync_as_assert_ok(&as_state).await;
+        let mut bob = members.remove(0);
+
+        let ban_msgs = check_action_msg_and_get_mls(
+            &comm_grp(),
+            ActionMsg::Ban(BanAction {
+                target_user_id: charlie.name(),
+                metadata: ActionMetadata::new(
+                    admin.name(),
+                    "ban".to_string(),
+                    comm_grp(),
+                    SystemTime::now(),
+                ),
+            }),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        admin.send_all_assert_ok(ban_msgs, &ds_state).await;
+        let (invite_msgs, _) = pre_add_invite_msg(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+            vec![dave.new_key_package()],
+        );
+        admin.send_all_assert_ok(invite_msgs, &ds_state).await;
+        bob.sync_ds_assert_ok(&ds_state).await;
+
+        let mut join_request = |admin: &mut TestClientBundle, joiner_key_package: KeyPackage| {
+            let proposal = bob
+                .configs
+                .get_ref_group(&comm_grp())
+                .unwrap()
+                .borrow_mut()
+                .propose_add_member(&bob.backend, &joiner_key_package)
+                .expect("Cannot propose to add a member");
+            let tag = bob.configs.get_keypair().sign(&GroupMessage::tag_content(
+                &comm_grp(),
+                &bob.name(),
+                &proposal,
+            ));
+            parse_mls_message_out(
+                proposal,
+                &comm_grp(),
+                Some(bob.name()),
+                Some(&tag),
+                &mut admin.configs,
+                &mut admin.backend,
+            )
+        };
+        let n_pending_proposals = |admin: &mut TestClientBundle| {
+            admin
+                .configs
+                .get_ref_group(&comm_grp())
+                .unwrap()
+                .borrow()
+                .pending_proposals()
+                .count()
+        };
+
+        let parsed = join_request(&mut admin, charlie.new_key_package());
+        assert!(parsed
+            .iter()
+            .any(|msg| matches!(msg, ClientParsedMsg::Invalid { .. })));
+        assert_eq!(n_pending_proposals(&mut admin), 0);
+
+        let parsed = join_request(&mut admin, dave.new_key_package());
+        assert!(!parsed
+            .iter()
+            .any(|msg| matches!(msg, ClientParsedMsg::Invalid { .. })));
+        assert_eq!(n_pending_proposals(&mut admin), 1);
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// A message whose group was altered in transit should be rejected by
+    /// its recipient before being processed, as its MLS group or its tag no
+    /// longer matches, as should untagged messages and those from senders
+    /// whose verification key is unknown
+    async fn test_group_msg_with_altered_comm_grp_rejected() {
+        let (as_state, _, mut admin, mut members) =
+            setup_registered_group_assert_ok("admin", &["bob"]).await;
+        let mut bob = members.remove(0);
+        bob.sync_as_assert_ok(&as_state).await;
+
+        let send_msgs = send_text_msg_mls(
+            &admin.name(),
+            &comm_grp(),
+            "hello".to_string(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        let (mls_msg, tag) = match send_msgs.as_slice() {
+            [OnWireMessage::UserStandardSend {
+                user_msg: GroupMessage::AppMlsMessage { mls_msg, tag, .. },
+                ..
+            }] => (mls_msg.clone(), tag.clone()),
+            _ => panic!("Expected a single message to the group"),
+        };
+        assert!(tag.is_some());
+
+        let altered_grp = CommGroupId::new(&"community".to_string(), &"other".to_string());
+        for sender in [Some(admin.name()), None] {
+            let parsed = parse_mls_message_out(
+                mls_msg.clone(),
+                &altered_grp,
+                sender,
+                tag.as_ref(),
+                &mut bob.configs,
+                &mut bob.backend,
+            );
+            assert!(matches!(
+                parsed.as_slice(),
+                [ClientParsedMsg::Invalid { description, .. }] if description.contains("another group")
+            ));
+        }
+        // The same MLS group ID, in another community
+        let altered_grp = CommGroupId::new(&"other".to_string(), &"group".to_string());
+        let parsed = parse_mls_message_out(
+            mls_msg.clone(),
+            &altered_grp,
+            Some(admin.name()),
+            tag.as_ref(),
+            &mut bob.configs,
+            &mut bob.backend,
+        );
+        assert!(matches!(
+            parsed.as_slice(),
+            [ClientParsedMsg::Invalid { description, .. }] if description.contains("tag")
+        ));
+
+        let unknown_sender = parse_mls_message_out(
+            mls_msg.clone(),
+            &comm_grp(),
+            Some("mallory".to_string()),
+            tag.as_ref(),
+            &mut bob.configs,
+            &mut bob.backend,
+        );
+        assert!(matches!(
+            unknown_sender.as_slice(),
+            [ClientParsedMsg::Invalid { description, .. }] if description.contains("Public key not found")
+        ));
+
+        let untagged = parse_mls_message_out(
+            mls_msg.clone(),
+            &comm_grp(),
+            Some(admin.name()),
+            None,
+            &mut bob.configs,
+            &mut bob.backend,
+        );
+        assert!(matches!(
+            untagged.as_slice(),
+            [ClientParsedMsg::Invalid { .. }]
+        ));
+
+        let parsed = parse_mls_message_out(
+            mls_msg,
+            &comm_grp(),
+            Some(admin.name()),
+            tag.as_ref(),
+            &mut bob.configs,
+            &mut bob.backend,
+        );
+        assert_eq!(concat_string_in_decrypted_msgs(parsed), "hello");
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// Importing an exported group state should restore it for every member,
+    /// while keeping the MLS group, and be refused to roles that cannot revert
+    async fn test_gov_state_export_import_round_trip() {
+        let (_, ds_state, mut admin, mut members) =
+            setup_registered_group_assert_ok("admin", &["bob"]).await;
+        let mut bob = members.remove(0);
+
+        let exported = export_gov_state(&comm_grp(), admin.configs.as_ref());
+        let exported_state: SharedGroupState = serde_json::from_str(&exported).unwrap();
+        assert_eq!(admin.configs.get_shared_state(&comm_grp()), &exported_state);
+
+        admin
+            .configs
+            .set_group_name(&comm_grp(), "mutated".to_string());
+        admin
+            .configs
+            .set_user_role(&comm_grp(), bob.name(), "Mod".to_string());
+        assert_ne!(admin.configs.get_shared_state(&comm_grp()), &exported_state);
+
+        // Bob is a BaseUser, who cannot restore group states
+        assert!(matches!(
+            import_gov_state(
+                &comm_grp(),
+                &exported,
+                &mut bob.backend,
+                bob.configs.deref_mut()
+            ),
+            Err(ClientError::Unauthorized(ActionType::AdminRevert))
+        ));
+
+        let members_before = admin.configs.get_group_members(&comm_grp());
+        let import_msgs = import_gov_state(
+            &comm_grp(),
+            &exported,
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        )
+        .unwrap();
+        admin.send_all_assert_ok(import_msgs, &ds_state).await;
+        bob.sync_ds_assert_ok(&ds_state).await;
+        for bundle in [&admin, &bob] {
+            assert_eq!(
+                bundle.configs.get_shared_state(&comm_grp()),
+                &exported_state
+            );
+        }
+        assert_eq!(admin.configs.get_group_members(&comm_grp()), members_before);
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// Once the group is capped, invitees should only be staged while the
+    /// members and the already staged invitees stay within the cap
+    async fn test_invite_rejected_above_max_group_size() {
+        let (as_state, ds_state, [mut admin, bob, charlie, dave]) =
+            register_clients_assert_ok(["admin", "bob", "charlie", "dave"]).await;
+        let mut invitees = vec![bob, charlie, dave];
+        admin.sync_as_assert_ok(&as_state).await;
+        let _ = create_group_msg(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+
+        // The admin is a member already, leaving room for two invitees
+        let cap_msgs = check_action_msg_and_get_mls(
+            &comm_grp(),
+            ActionMsg::SetMaxGroupSize(SetMaxGroupSizeAction {
+                max_group_size: Some(3),
+                metadata: ActionMetadata::new(
+                    admin.name(),
+                    "cap".to_string(),
+                    comm_grp(),
+                    SystemTime::now(),
+                ),
+            }),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        admin.send_all_assert_ok(cap_msgs, &ds_state).await;
+        assert_eq!(
+            admin.configs.get_shared_state(&comm_grp()).max_group_size,
+            Some(3)
+        );
+
+        for invitee in invitees.iter_mut() {
+            let (invite_msgs, _) = pre_add_invite_msg(
+                &admin.name(),
+                &comm_grp(),
+                &mut admin.backend,
+                admin.configs.deref_mut(),
+                vec![invitee.new_key_package()],
+            );
+            admin.send_all_assert_ok(invite_msgs, &ds_state).await;
+        }
+        let to_add_invitees = &admin.configs.get_shared_state(&comm_grp()).to_add_invitees;
+        assert!(to_add_invitees.contains_key("bob"));
+        assert!(to_add_invitees.contains_key("charlie"));
+        assert!(!to_add_invitees.contains_key("dave"));
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// The state of a freshly set up group should be consistent, while a
+    /// member without a role and a forged proposed action are flagged
+    async fn test_verify_group_integrity_flags_discrepancies() {
+        let (_, _, mut admin, mut members) =
+            setup_registered_group_assert_ok("admin", &["bob"]).await;
+        let mut bob = members.remove(0);
+        for bundle in [&mut admin, &mut bob] {
+            assert_eq!(
+                verify_group_integrity(&comm_grp(), bundle.configs.deref_mut()),
+                Some(vec![])
+            );
+        }
+
+        admin
+            .configs
+            .as_any_mut()
+            .downcast_mut::<ClientData>()
+            .unwrap()
+            .get_mut_group_state(&comm_grp())
+            .shared
+            .rbac
+            .user_to_roles
+            .remove(&bob.name());
+        let forged_action = ActionMsg::RenameGroup(RenameGroupAction {
+            new_name: "forged".to_string(),
+            lamport_counter: 1,
+            metadata: ActionMetadata::new(
+                bob.name(),
+                "forged_id".to_string(),
+                comm_grp(),
+                SystemTime::now(),
+            ),
+        });
+        admin.configs.store_proposed_action(
+            &comm_grp(),
+            VerifiableAction::new(forged_action, &generate_verification_key()),
+        );
+        assert_eq!(
+            verify_group_integrity(&comm_grp(), admin.configs.deref_mut()),
+            Some(vec![
+                IntegrityDiscrepancy::MemberWithoutRole(bob.name()),
+                IntegrityDiscrepancy::UnverifiedProposedAction {
+                    action_id: "forged_id".to_string(),
+                    sender: bob.name(),
+                },
+            ])
+        );
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// A broadcast group state hash should match the hash of an identical
+    /// group state, and be reported as invalid against a diverged one
+    async fn test_gov_state_hash_detects_divergence() {
+        let (_, ds_state, mut admin, mut members) =
+            setup_registered_group_assert_ok("admin", &["bob"]).await;
+        let bob = &mut members[0];
+        let is_divergence = |msg: &ClientParsedMsg| matches!(msg, ClientParsedMsg::Invalid { description, .. } if description.contains("diverged"));
+
+        assert_eq!(
+            admin.configs.get_shared_state(&comm_grp()).stable_hash(),
+            bob.configs.get_shared_state(&comm_grp()).stable_hash()
+        );
+        let hash_msgs = gov_state_hash_msgs(&mut admin.backend, admin.configs.deref_mut());
+        assert_eq!(hash_msgs.len(), 1);
+        admin.send_all_assert_ok(hash_msgs, &ds_state).await;
+        let sync_responses =
+            handle_onwire_msg_ds_local(sync_msg(bob.configs.as_ref(), vec![]), &ds_state).await;
+        assert!(!bob.parse_msgs(&sync_responses).iter().any(is_divergence));
+
+        // Bob's group state diverges without the admin knowing
+        bob.configs
+            .set_group_name(&comm_grp(), "diverged name".to_string());
+        let hash_msgs = gov_state_hash_msgs(&mut admin.backend, admin.configs.deref_mut());
+        admin.send_all_assert_ok(hash_msgs, &ds_state).await;
+        let sync_responses =
+            handle_onwire_msg_ds_local(sync_msg(bob.configs.as_ref(), vec![]), &ds_state).await;
+        assert!(bob.parse_msgs(&sync_responses).iter().any(is_divergence));
+
+        // The same comparison, without the round trip
+        let hash_action = GovStateHashAction {
+            gov_state_hash: admin.configs.get_shared_state(&comm_grp()).stable_hash(),
+            epoch: group_info(&comm_grp(), admin.configs.deref_mut())
+                .expect("Cannot find that group")
+                .epoch,
+            metadata: ActionMetadata::new(
+                admin.name(),
+                "hash".to_string(),
+                comm_grp(),
+                SystemTime::now(),
+            ),
+        };
+        assert!(check_gov_state_hash(&hash_action, admin.configs.deref_mut()).is_none());
+        assert!(check_gov_state_hash(&hash_action, bob.configs.deref_mut())
+            .as_ref()
+            .map_or(false, is_divergence));
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// Reverting to the epoch before a rename should restore the old name
+    /// for all members
+    async fn test_admin_revert_restores_previous_name() {
+        let (_, ds_state, mut admin, mut members) =
+            setup_registered_group_assert_ok("admin", &["bob"]).await;
+        let old_name = admin.configs.get_shared_state(&comm_grp()).name.clone();
+        let epoch_before_rename = group_info(&comm_grp(), admin.configs.deref_mut())
+            .expect("Cannot find that group")
+            .epoch;
+
+        let rename_msgs = check_action_msg_and_get_mls(
+            &comm_grp(),
+            ActionMsg::RenameGroup(RenameGroupAction {
+                new_name: "renamed".to_string(),
+                lamport_counter: 1,
+                metadata: ActionMetadata::new(
+                    admin.name(),
+                    "rename".to_string(),
+                    comm_grp(),
+                    SystemTime::now(),
+                ),
+            }),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        admin.send_all_assert_ok(rename_msgs, &ds_state).await;
+        members[0].sync_ds_assert_ok(&ds_state).await;
+        for bundle in [&admin, &members[0]] {
+            assert_eq!(bundle.configs.get_shared_state(&comm_grp()).name, "renamed");
+            assert!(bundle
+                .configs
+                .get_gov_state_snapshot(&comm_grp(), epoch_before_rename)
+                .is_some());
+        }
+
+        let revert_msgs = admin_revert_msg(
+            &comm_grp(),
+            epoch_before_rename,
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        admin.send_all_assert_ok(revert_msgs, &ds_state).await;
+        members[0].sync_ds_assert_ok(&ds_state).await;
+        for bundle in [&admin, &members[0]] {
+            assert_eq!(bundle.configs.get_shared_state(&comm_grp()).name, old_name);
+        }
+        assert_eq!(
+            admin.configs.get_shared_state(&comm_grp()),
+            members[0].configs.get_shared_state(&comm_grp())
+        );
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// Reverting to an epoch before a member was removed should not give them
+    /// back their roles, as the MLS group is not rolled back
+    async fn test_admin_revert_drops_roles_of_removed_members() {
+        let (_, ds_state, mut admin, mut members) =
+            setup_registered_group_assert_ok("admin", &["bob", "charlie"]).await;
+        let epoch_before_kick = group_info(&comm_grp(), admin.configs.deref_mut())
+            .expect("Cannot find that group")
+            .epoch;
+        assert!(admin
+            .configs
+            .get_roles(&comm_grp())
+            .user_to_roles
+            .contains_key("charlie"));
+
+        let kick_msgs = pre_kick_msg(
+            &comm_grp(),
+            &"charlie".to_string(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        admin.send_all_assert_ok(kick_msgs, &ds_state).await;
+        let remove_msgs = remove_other_or_self_msg(
+            &comm_grp(),
+            &"charlie".to_string(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        admin.send_all_assert_ok(remove_msgs, &ds_state).await;
+        members[0].sync_ds_assert_ok(&ds_state).await;
+
+        let revert_msgs = admin_revert_msg(
+            &comm_grp(),
+            epoch_before_kick,
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        assert!(!revert_msgs.is_empty());
+        admin.send_all_assert_ok(revert_msgs, &ds_state).await;
+        members[0].sync_ds_assert_ok(&ds_state).await;
+        for bundle in [&admin, &members[0]] {
+            let rbac = bundle.configs.get_roles(&comm_grp());
+            assert!(!rbac.user_to_roles.contains_key("charlie"));
+            assert!(rbac.user_to_roles.contains_key("bob"));
+        }
+        assert_eq!(
+            admin.configs.get_shared_state(&comm_grp()),
+            members[0].configs.get_shared_state(&comm_grp())
+        );
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// Replaying a recorded log of received frames should parse them as if
+    /// they were received, and report the lines that cannot be decoded
+    async fn test_replay_onwire_log() {
+        let (_, ds_state, mut admin, mut members) =
+            setup_registered_group_assert_ok("admin", &["bob"]).await;
+        let bob = &mut members[0];
+        bob.sync_ds_assert_ok(&ds_state).await;
+
+        for text in ["hello", "world"] {
+            let text_msgs = send_text_msg_mls(
+                &admin.name(),
+                &comm_grp(),
+                text.to_string(),
+                &mut admin.backend,
+                admin.configs.deref_mut(),
+            );
+            admin.send_all_assert_ok(text_msgs, &ds_state).await;
+        }
+
+        // Record the frames of a sync, compressing every other one
+        let sync_responses =
+            handle_onwire_msg_ds_local(sync_msg(bob.configs.as_ref(), vec![]), &ds_state).await;
+        let mut log: Vec<String> = sync_responses
+            .into_iter()
+            .enumerate()
+            .map(|(i, msg)| {
+                String::from_utf8(OnWireMessageWithMetaData::new(msg, i % 2 == 1).encode()).unwrap()
+            })
+            .collect();
+        log.push("not a frame".to_string());
+        let log_path =
+            std::env::temp_dir().join(format!("mlsgov-replay-{}.log", uuid::Uuid::new_v4()));
+        std::fs::write(&log_path, log.join("\n")).unwrap();
+
+        let parsed_msgs = replay_onwire_log(&log_path, &mut bob.configs, &mut bob.backend).unwrap();
+        std::fs::remove_file(&log_path).unwrap();
+        let texts: Vec<String> = parsed_msgs
+            .iter()
+            .filter_map(|parsed_msg| match parsed_msg {
+                ClientParsedMsg::NewMsg { private_msg, .. } => match &private_msg.content {
+                    UnorderedMsgContent::TextAction { text_action } => match &text_action.action {
+                        ActionMsg::TextMsg(TextMsgAction { msg, .. }) => Some(msg.clone()),
+                        _ => None,
+                    },
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect();
+        assert_eq!(texts, vec!["hello".to_string(), "world".to_string()]);
+        assert!(parsed_msgs.iter().any(|parsed_msg| matches!(
+            parsed_msg,
+            ClientParsedMsg::DSFeedback {
+                request_valid: true,
+                ..
+            }
+        )));
+        assert!(matches!(
+            parsed_msgs.last(),
+            Some(ClientParsedMsg::Invalid {
+                external_error: true,
+                ..
+            })
+        ));
+        assert!(replay_onwire_log(
+            log_path.with_extension("missing"),
+            &mut bob.configs,
+            &mut bob.backend
+        )
+        .is_err());
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// The messages of a sync whose results were lost should be relayed again
+    /// at the next sync, until the client acknowledges processing them
+    async fn test_unacked_msgs_relayed_again() {
+        let (_, ds_state, mut admin, mut members) =
+            setup_registered_group_assert_ok("admin", &["bob"]).await;
+        let bob = &mut members[0];
+        bob.sync_ds_assert_ok(&ds_state).await;
+
+        let text_msgs = send_text_msg_mls(
+            &admin.name(),
+            &comm_grp(),
+            "hello".to_string(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        admin.send_all_assert_ok(text_msgs, &ds_state).await;
+
+        let n_relayed = |responses: &Vec<OnWireMessage>| {
+            responses
+                .iter()
+                .filter(|msg| matches!(msg, OnWireMessage::DSRelayedUserMsg { .. }))
+                .count()
+        };
+        // The results of the first sync never reach bob
+        let lost_responses =
+            handle_onwire_msg_ds_local(sync_msg(bob.configs.as_ref(), vec![]), &ds_state).await;
+        assert_eq!(n_relayed(&lost_responses), 1);
+
+        let sync_responses =
+            handle_onwire_msg_ds_local(sync_msg(bob.configs.as_ref(), vec![]), &ds_state).await;
+        assert_eq!(n_relayed(&sync_responses), 1);
+        let parsed_msgs = bob.parse_msgs(&sync_responses);
+        assert!(concat_string_in_decrypted_msgs(parsed_msgs).contains("hello"));
+
+        // Acknowledged along with the next sync
+        let sync_responses =
+            handle_onwire_msg_ds_local(sync_msg(bob.configs.as_ref(), vec![]), &ds_state).await;
+        assert_eq!(n_relayed(&sync_responses), 0);
+        assert!(ds_state.unacked_indvl_msgs.get(&bob.name()).is_none());
+    }
+
+    /// Records the kind of the messages it is notified of
+    #[cfg(feature = "gov")]
+    #[derive(Default)]
+    struct RecordingSink {
+        events: Vec<String>,
+    }
+
+    #[cfg(feature = "gov")]
+    impl ClientEventSink for RecordingSink {
+        fn on_new_message(&mut self, _msg: &ClientParsedMsg) {
+            self.events.push("message".to_string());
+        }
+
+        fn on_invite(&mut self, inviter: &str, comm_grp: &CommGroupId) {
+            self.events
+                .push(format!("invite from {inviter} to {}", comm_grp.group_id()));
+        }
+
+        fn on_reinvite_request(&mut self, requester: &str, _comm_grp: &CommGroupId) {
+            self.events.push(format!("reinvite request of {requester}"));
+        }
+
+        fn on_feedback(&mut self, _feedback: &ClientParsedMsg) {
+            self.events.push("feedback".to_string());
+        }
+
+        fn on_invalid(&mut self, _invalid: &ClientParsedMsg) {
+            self.events.push("invalid".to_string());
+        }
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// An event sink should be notified of every parsed message of a mixed
+    /// batch, in the order of the returned messages
+    async fn test_event_sink_notified_in_order() {
+        let (_, ds_state, mut admin, mut members) =
+            setup_registered_group_assert_ok("admin", &["bob"]).await;
+        let bob = &mut members[0];
+
+        // Invite bob to another group, then send a message to the first one
+        let other_grp = CommGroupId::new(&"community".to_string(), &"other".to_string());
+        let _ = create_group_msg(
+            &admin.name(),
+            &other_grp,
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        let (invite_msgs, _) = pre_add_invite_msg(
+            &admin.name(),
+            &other_grp,
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+            vec![bob.new_key_package()],
+        );
+        admin.send_all_assert_ok(invite_msgs, &ds_state).await;
+        let text_msgs = send_text_msg_mls(
+            &admin.name(),
+            &comm_grp(),
+            "hello".to_string(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        admin.send_all_assert_ok(text_msgs, &ds_state).await;
+
+        let sync_responses =
+            handle_onwire_msg_ds_local(sync_msg(bob.configs.as_ref(), vec![]), &ds_state).await;
+        // The same message, relayed for a group bob is not a member of
+        let mut unknown_grp_msg = sync_responses
+            .iter()
+            .find(|msg| {
+                matches!(
+                    msg,
+                    OnWireMessage::DSRelayedUserMsg {
+                        user_msg: GroupMessage::AppMlsMessage { .. },
+                        ..
+                    }
+                )
+            })
+            .unwrap()
+            .clone();
+        if let OnWireMessage::DSRelayedUserMsg {
+            user_msg: GroupMessage::AppMlsMessage { comm_grp, .. },
+            ..
+        } = &mut unknown_grp_msg
+        {
+            *comm_grp = CommGroupId::new(&"community".to_string(), &"unknown".to_string());
+        }
+        let mut batch = vec![OnWireMessage::ASResult {
+            request_valid: true,
+            explanation: None,
+            process_time_used: Duration::ZERO,
+        }];
+        batch.extend(sync_responses);
+        batch.push(unknown_grp_msg);
+        batch.push(OnWireMessage::DSRelayedReinviteRequest {
+            requester: "carol".to_string(),
+            comm_grp: comm_grp(),
+        });
+
+        let mut sink = RecordingSink::default();
+        let parsed_msgs = parse_incoming_onwire_msgs_with_sink(
+            batch,
+            &mut bob.configs,
+            &mut bob.backend,
+            Some(&mut sink),
+        );
+        let expected_events: Vec<String> = parsed_msgs
+            .iter()
+            .map(|parsed_msg| match parsed_msg {
+                ClientParsedMsg::NewMsg { .. } | ClientParsedMsg::NewOrdMsg { .. } => {
+                    "message".to_string()
+                }
+                ClientParsedMsg::NewInvite { inviter, comm_grp } => {
+                    format!("invite from {inviter} to {}", comm_grp.group_id())
+                }
+                ClientParsedMsg::ReinviteRequest { requester, .. } => {
+                    format!("reinvite request of {requester}")
+                }
+                ClientParsedMsg::ASFeedback { .. } | ClientParsedMsg::DSFeedback { .. } => {
+                    "feedback".to_string()
+                }
+                _ => "invalid".to_string(),
+            })
+            .collect();
+        assert_eq!(sink.events, expected_events);
+        assert_eq!(sink.events.first().unwrap(), "feedback");
+        assert!(sink
+            .events
+            .contains(&"invite from admin to other".to_string()));
+        assert!(sink.events.contains(&"message".to_string()));
+        assert_eq!(
+            sink.events[sink.events.len() - 2..],
+            [
+                "invalid".to_string(),
+                "reinvite request of carol".to_string()
+            ]
+        );
+    }
+
+    #[cfg(feature = "gov")]
+    #[test]
+    /// The history since a date should include every message received at
+    /// that date, even several, and none received before
+    fn test_get_history_since_boundaries() {
+        let mut bob = TestClientBundle::new("bob");
+        let mut client_data = ClientData::new(
+            bob.name(),
+            bob.credential().clone(),
+            generate_verification_key(),
+        );
+        let _ = create_group_msg(&bob.name(), &comm_grp(), &mut bob.backend, &mut client_data);
+        let at_secs = |secs: u64| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let history_msg = |text: &str, received_secs: u64| LocalHistoryMessage {
+            message: UnorderedPrivateMessage {
+                content: UnorderedMsgContent::Text {
+                    text_content: text.to_string(),
+                },
+                sender_timestamp: at_secs(received_secs),
+                sender: "alice".to_string(),
+                seq: 0,
+            },
+            sender: "alice".to_string(),
+            received_timestamp: at_secs(received_secs),
+            expires_at: None,
+            in_reply_to: None,
+        };
+        // From new to old, as stored
+        client_data.get_mut_group_state(&comm_grp()).history = vec![
+            history_msg("e", 50),
+            history_msg("d", 30),
+            history_msg("c", 30),
+            history_msg("b", 30),
+            history_msg("a", 10),
+        ];
+        let texts_since = |since: SystemTime| -> Vec<String> {
+            client_data
+                .get_history_since(&comm_grp(), since)
+                .into_iter()
+                .map(|msg| match &msg.message.content {
+                    UnorderedMsgContent::Text { text_content } => text_content.clone(),
+                    _ => panic!("Unexpected message content"),
+                })
+                .collect()
+        };
+
+        assert_eq!(texts_since(at_secs(30)), vec!["e", "d", "c", "b"]);
+        assert_eq!(
+            texts_since(at_secs(30) + Duration::from_nanos(1)),
+            vec!["e"]
+        );
+        assert_eq!(
+            texts_since(at_secs(30) - Duration::from_nanos(1)),
+            vec!["e", "d", "c", "b"]
+        );
+        assert_eq!(texts_since(at_secs(0)), vec!["e", "d", "c", "b", "a"]);
+        assert!(texts_since(at_secs(51)).is_empty());
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// Replies should keep the message they reply to through the MLS
+    /// messages, and the thread of any message of a reply chain should be
+    /// the whole chain, newest first, without the other messages
+    async fn test_reply_chain_thread() {
+        let (_, ds_state, mut admin, mut members) =
+            setup_registered_group_assert_ok("admin", &["bob"]).await;
+        let bob = &mut members[0];
+
+        for (from_admin, action_id, in_reply_to) in [
+            (true, "root", None),
+            (false, "reply", Some("root")),
+            (false, "other", None),
+            (true, "reply_to_reply", Some("reply")),
+        ] {
+            let sender = if from_admin { &mut admin } else { &mut *bob };
+            let action = ActionMsg::TextMsg(TextMsgAction {
+                msg: action_id.to_string(),
+                expires_after: None,
+                in_reply_to: in_reply_to.map(str::to_string),
+                metadata: ActionMetadata::new(
+                    sender.name(),
+                    action_id.to_string(),
+                    comm_grp(),
+                    SystemTime::now(),
+                ),
+            });
+            let msgs = check_action_msg_and_get_mls(
+                &comm_grp(),
+                action,
+                &mut sender.backend,
+                sender.configs.deref_mut(),
+            );
+            sender.send_all_assert_ok(msgs, &ds_state).await;
+        }
+        bob.sync_ds_assert_ok(&ds_state).await;
+
+        let bob_data = bob.configs.as_any().downcast_ref::<ClientData>().unwrap();
+        let thread_ids = |message_id: &str| -> Vec<String> {
+            bob_data
+                .get_thread(&comm_grp(), message_id)
+                .into_iter()
+                .map(|msg| msg.message_id().unwrap())
+                .collect()
+        };
+        for message_id in ["root", "reply", "reply_to_reply"] {
+            assert_eq!(
+                thread_ids(message_id),
+                vec!["reply_to_reply", "reply", "root"]
+            );
+        }
+        assert_eq!(thread_ids("other"), vec!["other"]);
+        assert!(thread_ids("missing").is_empty());
+        let received_reply = bob_data
+            .get_thread(&comm_grp(), "reply_to_reply")
+            .into_iter()
+            .find(|msg| msg.message_id().as_deref() == Some("reply_to_reply"))
+            .unwrap();
+        assert_eq!(received_reply.sender, admin.name());
+        assert_eq!(received_reply.in_reply_to.as_deref(), Some("reply"));
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// An exported history should verify, and no longer once one of its bytes
+    /// is altered, the hash chain breaking at the altered message
+    async fn test_history_export_detects_tampering() {
+        let (_, ds_state, mut admin, mut members) =
+            setup_registered_group_assert_ok("admin", &["bob"]).await;
+        let bob = &mut members[0];
+        for text in ["first", "second"] {
+            let msgs = send_text_msg_mls(
+                &admin.name(),
+                &comm_grp(),
+                text.to_string(),
+                &mut admin.backend,
+                admin.configs.deref_mut(),
+            );
+            admin.send_all_assert_ok(msgs, &ds_state).await;
+        }
+        bob.sync_ds_assert_ok(&ds_state).await;
+
+        let path =
+            std::env::temp_dir().join(format!("mlsgov-history-{}.json", uuid::Uuid::new_v4()));
+        let bob_data = bob.configs.as_any().downcast_ref::<ClientData>().unwrap();
+        bob_data.export_history(&comm_grp(), &path).unwrap();
+        let export = verify_history_export(&path).unwrap();
+        assert_eq!(export.exporter, bob.name());
+        assert_eq!(export.entries.len(), 2);
+
+        // Flip a digit of the oldest message's timestamp
+        let mut bytes = std::fs::read(&path).unwrap();
+        let field = b"\"nanos_since_epoch\": ";
+        let position = bytes
+            .windows(field.len())
+            .position(|window| window == field)
+            .unwrap()
+            + field.len();
+        bytes[position] = if bytes[position] == b'1' { b'2' } else { b'1' };
+        std::fs::write(&path, bytes).unwrap();
+        assert!(matches!(
+            verify_history_export(&path),
+            Err(HistoryExportError::BrokenChain { index: 0 })
+        ));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// A key rotation signed with the registered and the new key should replace
+    /// the former, while a second registration, a rotation signed with another
+    /// key or without the new one, or a replayed rotation, is rejected
+    async fn test_rotate_key() {
+        let (as_state, _, [mut alice, mallory]) =
+            register_clients_assert_ok(["alice", "mallory"]).await;
+        let is_rejected = |responses: &Vec<OnWireMessage>| {
+            matches!(
+                responses[0],
+                OnWireMessage::ASResult {
+                    request_valid: false,
+                    ..
+                }
+            )
+        };
+        let registered_key = || {
+            as_state
+                .credential_entries
+                .get("alice")
+                .unwrap()
+                .verification_key
+        };
+
+        let new_keypair = generate_verification_key();
+        let new_credential = alice
+            .backend
+            .generate_credential_bundle(alice.name().into_bytes(), None, None)
+            .credential()
+            .clone();
+        let register = register_msg_as(new_credential.clone(), new_keypair.public).remove(0);
+        assert!(is_rejected(
+            &handle_onwire_msg_as_local(register, &as_state).await
+        ));
+
+        let spoofed = match rotate_key_msg(
+            new_credential.clone(),
+            &new_keypair,
+            0,
+            mallory.configs.as_ref(),
+        ) {
+            OnWireMessage::UserRotateKey {
+                new_credential,
+                new_verification_key,
+                signature,
+                new_key_signature,
+                ..
+            } => OnWireMessage::UserRotateKey {
+                user_name: alice.name(),
+                new_credential,
+                new_verification_key,
+                signature,
+                new_key_signature,
+            },
+            other => panic!("Expected a key rotation, got {:?}", other),
+        };
+        assert!(is_rejected(
+            &handle_onwire_msg_as_local(spoofed, &as_state).await
+        ));
+        assert_eq!(registered_key(), alice.configs.get_keypair().public);
+
+        let rotation = rotate_key_msg(
+            new_credential.clone(),
+            &new_keypair,
+            0,
+            alice.configs.as_ref(),
+        );
+        // Without proof of possession of the new key
+        let unproven = match rotation.clone() {
+            OnWireMessage::UserRotateKey {
+                user_name,
+                new_credential,
+                new_verification_key,
+                signature,
+                ..
+            } => OnWireMessage::UserRotateKey {
+                user_name,
+                new_credential,
+                new_verification_key,
+                new_key_signature: signature,
+                signature,
+            },
+            other => panic!("Expected a key rotation, got {:?}", other),
+        };
+        assert!(is_rejected(
+            &handle_onwire_msg_as_local(unproven, &as_state).await
+        ));
+        assert_eq!(registered_key(), alice.configs.get_keypair().public);
+
+        let responses = handle_onwire_msg_as_local(rotation.clone(), &as_state).await;
+        assert_all_feedback_ok(&alice.parse_msgs(&responses));
+        assert_eq!(registered_key(), new_keypair.public);
+        assert_eq!(
+            as_state.get_credential_copy(alice.name()),
+            Some(new_credential)
+        );
+        // The old key no longer authorizes a rotation
+        assert!(is_rejected(
+            &handle_onwire_msg_as_local(rotation.clone(), &as_state).await
+        ));
+
+        // Nor once the key is rotated back to it, as the count moved on
+        let first_key = alice.configs.get_keypair().public;
+        let first_credential = alice.credential().clone();
+        let content = rotate_key_request_content(
+            &alice.name(),
+            &new_keypair.public,
+            1,
+            &first_credential,
+            &first_key,
+        );
+        let rotate_back = OnWireMessage::UserRotateKey {
+            user_name: alice.name(),
+            new_credential: first_credential,
+            new_verification_key: first_key,
+            signature: new_keypair.sign(&content),
+            new_key_signature: alice.configs.get_keypair().sign(&content),
+        };
+        let responses = handle_onwire_msg_as_local(rotate_back, &as_state).await;
+        assert_all_feedback_ok(&alice.parse_msgs(&responses));
+        assert_eq!(registered_key(), first_key);
+        assert!(is_rejected(
+            &handle_onwire_msg_as_local(rotation, &as_state).await
+        ));
+    }
+
+    #[cfg(feature = "gov")]
+    #[actix_rt::test]
+    /// Preferences stored by one device of a user should be loaded by another
+    /// of their devices with the same secret, and not overwritten by another user
+    async fn test_prefs_synced_across_devices() {
+        let (as_state, ds_state, [mut phone, mallory]) =
+            register_clients_assert_ok(["bob#phone", "mallory"]).await;
+        let mut laptop = TestClientBundle::new("bob#laptop");
+        laptop
+            .register_device_assert_ok(&phone, &as_state, &ds_state)
+            .await;
+
+        let key = prefs_key("shared secret");
+        mute(
+            &comm_grp(),
+            Some("alice".to_string()),
+            phone.configs.deref_mut(),
+        );
+        let store = store_prefs_msg(&key, &phone.backend, phone.configs.as_ref());
+        let responses = handle_onwire_msg_as_local(store, &as_state).await;
+        assert_all_feedback_ok(&phone.parse_msgs(&responses));
+        assert!(!laptop.configs.is_muted(&comm_grp(), "alice"));
+
+        let load = load_prefs_msg(laptop.configs.as_ref());
+        let responses = handle_onwire_msg_as_local(load, &as_state).await;
+        let stored = match &responses[0] {
+            OnWireMessage::ASPrefsResponse { prefs: Some(prefs) } => prefs.clone(),
+            other => panic!("Expected stored prefs, got {:?}", other),
+        };
+        assert!(matches!(
+            apply_loaded_prefs(
+                &stored,
+                &prefs_key("wrong secret"),
+                &laptop.backend,
+                laptop.configs.deref_mut()
+            ),
+            Err(ClientError::InvalidPrefs)
+        ));
+        apply_loaded_prefs(&stored, &key, &laptop.backend, laptop.configs.deref_mut()).unwrap();
+        assert!(laptop.configs.is_muted(&comm_grp(), "alice"));
+        assert!(!laptop.configs.is_muted(&comm_grp(), "carol"));
+
+        // Mallory's signature does not verify against bob's devices
+        let forged = match store_prefs_msg(&key, &mallory.backend, mallory.configs.as_ref()) {
+            OnWireMessage::UserStorePrefs {
+                prefs, signature, ..
+            } => OnWireMessage::UserStorePrefs {
+                user_name: "bob#phone".to_string(),
+                prefs,
+                signature,
+            },
+            other => panic!("Expected a store request, got {:?}", other),
+        };
+        let responses = handle_onwire_msg_as_local(forged, &as_state).await;
+        assert!(matches!(
+            responses[0],
+            OnWireMessage::ASResult {
+                request_valid: false,
+                ..
+            }
+        ));
+        let load = load_prefs_msg(laptop.configs.as_ref());
+        let responses = handle_onwire_msg_as_local(load, &as_state).await;
+        assert!(matches!(
+            &responses[0],
+            OnWireMessage::ASPrefsResponse { prefs: Some(prefs) } if prefs == &stored
+        ));
+    }
+
+    #[cfg(feature = "gov")]
+    #[test]
+    /// Messages of a sender received out of order should be stored in
+    /// sequence order, with the skipped ones flagged until they arrive
+    fn test_store_received_msgs_in_seq_order() {
+        let mut bob = TestClientBundle::new("bob");
+        let mut client_data = ClientData::new(
+            bob.name(),
+            bob.credential().clone(),
+            generate_verification_key(),
+        );
+        let _ = create_group_msg(&bob.name(), &comm_grp(), &mut bob.backend, &mut client_data);
+        let receive = |client_data: &mut ClientData, sender: &str, seq: u64| {
+            let private_msg = UnorderedPrivateMessage {
+                content: UnorderedMsgContent::Text {
+                    text_content: format!("{} {}", sender, seq),
+                },
+                sender_timestamp: SystemTime::now(),
+                sender: sender.to_string(),
+                seq,
+            };
+            client_data.store_received_msg(&comm_grp(), sender, &private_msg);
+        };
+        let gaps = |client_data: &ClientData| {
+            client_data
+                .get_group_state(&comm_grp())
+                .msg_seq_gaps
+                .get("alice")
+                .cloned()
+                .unwrap_or_default()
+        };
+
+        receive(&mut client_data, "alice", 1);
+        receive(&mut client_data, "alice", 4);
+        assert_eq!(gaps(&client_data), BTreeSet::from([2, 3]));
+        receive(&mut client_data, "carol", 1);
+        receive(&mut client_data, "alice", 2);
+        assert_eq!(gaps(&client_data), BTreeSet::from([3]));
+        receive(&mut client_data, "alice", 3);
+        assert!(gaps(&client_data).is_empty());
+
+        // From new to old, as stored
+        let stored: Vec<(String, u64)> = client_data
+            .get_group_state(&comm_grp())
+            .history
+            .iter()
+            .map(|msg| (msg.sender.clone(), msg.message.seq))
+            .collect();
+        assert_eq!(
+            stored,
+            vec![
+                ("carol".to_string(), 1),
+                ("alice".to_string(), 4),
+                ("alice".to_string(), 3),
+                ("alice".to_string(), 2),
+                ("alice".to_string(), 1),
+            ]
+        );
+    }
+
+    #[cfg(feature = "gov")]
+    #[test]
+    /// A received message with a 0-second TTL should not be shown nor kept,
+    /// while one with a long TTL should, and still count as unread
+    fn test_disappearing_msgs_purged_once_expired() {
+        let mut bob = TestClientBundle::new("bob");
+        let mut client_data = ClientData::new(
+            bob.name(),
+            bob.credential().clone(),
+            generate_verification_key(),
+        );
+        let _ = create_group_msg(&bob.name(), &comm_grp(), &mut bob.backend, &mut client_data);
+        let alice_keypair = generate_verification_key();
+        let mut receive = |text: &str, expires_after: Duration| {
+            let action = ActionMsg::TextMsg(TextMsgAction {
+                msg: text.to_string(),
+                expires_after: Some(expires_after),
+                in_reply_to: None,
+                metadata: ActionMetadata::new(
+                    "alice".to_string(),
+                    text.to_string(),
+                    comm_grp(),
+                    SystemTime::now(),
+                ),
+            });
+            let private_msg = UnorderedPrivateMessage {
+                content: UnorderedMsgContent::TextAction {
+                    text_action: VerifiableAction::new(action, &alice_keypair),
+                },
+                sender_timestamp: SystemTime::now(),
+                sender: "alice".to_string(),
+                seq: 0,
+            };
+            client_data.store_received_msg(&comm_grp(), "alice", &private_msg);
+        };
+        receive("long", Duration::from_secs(3600));
+        receive("instant", Duration::from_secs(0));
+        assert_eq!(
+            client_data.get_group_state(&comm_grp()).unread_msgs_count,
+            2
+        );
+
+        let shown_ids = |client_data: &ClientData| -> Vec<String> {
+            client_data
+                .get_history_since(&comm_grp(), SystemTime::UNIX_EPOCH)
+                .into_iter()
+                .map(|msg| match &msg.message.content {
+                    UnorderedMsgContent::TextAction { text_action } => {
+                        text_action.action.get_metadata().action_id
+                    }
+                    _ => panic!("Unexpected message content"),
+                })
+                .collect()
+        };
+        assert_eq!(shown_ids(&client_data), vec!["long"]);
+
+        assert_eq!(
+            client_data.purge_expired_msgs(&comm_grp(), SystemTime::now()),
+            1
+        );
+        let group_state = client_data.get_group_state(&comm_grp());
+        assert_eq!(group_state.history.len(), 1);
+        assert_eq!(group_state.unread_msgs_count, 1);
+        assert_eq!(shown_ids(&client_data), vec!["long"]);
+    }
+
+    #[cfg(feature = "gov")]
+    #[test]
+    /// Messages of a muted member, or of anyone in a muted group, should still
+    /// be stored, but not counted as unread
+    fn test_muted_msgs_not_counted_as_unread() {
+        let mut bob = TestClientBundle::new("bob");
+        let mut client_data = ClientData::new(
+            bob.name(),
+            bob.credential().clone(),
+            generate_verification_key(),
+        );
+        let _ = create_group_msg(&bob.name(), &comm_grp(), &mut bob.backend, &mut client_data);
+        let keypair = generate_verification_key();
+        let receive = |client_data: &mut ClientData, sender: &str, text: &str| {
+            let action = ActionMsg::TextMsg(TextMsgAction {
+                msg: text.to_string(),
+                expires_after: None,
+                in_reply_to: None,
+                metadata: ActionMetadata::new(
+                    sender.to_string(),
+                    text.to_string(),
+                    comm_grp(),
+                    SystemTime::now(),
+                ),
+            });
+            let private_msg = UnorderedPrivateMessage {
+                content: UnorderedMsgContent::TextAction {
+                    text_action: VerifiableAction::new(action, &keypair),
+                },
+                sender_timestamp: SystemTime::now(),
+                sender: sender.to_string(),
+                seq: 0,
+            };
+            client_data.store_received_msg(&comm_grp(), sender, &private_msg);
+        };
+        let unread =
+            |client_data: &ClientData| client_data.get_group_state(&comm_grp()).unread_msgs_count;
+
+        mute(&comm_grp(), Some("alice".to_string()), &mut client_data);
+        receive(&mut client_data, "alice", "muted");
+        receive(&mut client_data, "carol", "not muted");
+        assert_eq!(unread(&client_data), 1);
+        assert!(client_data.is_muted(&comm_grp(), "alice"));
+        assert!(!client_data.is_muted(&comm_grp(), "carol"));
+
+        unmute(&comm_grp(), Some("alice".to_string()), &mut client_data);
+        mute(&comm_grp(), None, &mut client_data);
+        receive(&mut client_data, "alice", "group muted");
+        receive(&mut client_data, "carol", "group muted too");
+        assert_eq!(unread(&client_data), 1);
+        assert_eq!(client_data.get_group_state(&comm_grp()).history.len(), 4);
+
+        unmute(&comm_grp(), None, &mut client_data);
+        receive(&mut client_data, "alice", "unmuted");
+        assert_eq!(unread(&client_data), 2);
+    }
+
+    #[cfg(feature = "signed_baseline")]
+    #[actix_rt::test]
+    /// With `signed_baseline`, an action signed by someone else than its
+    /// claimed sender should be rejected, as should an unsigned one
+    async fn test_forged_baseline_action_rejected() {
+        let (as_state, _, [admin, mut bob]) = register_clients_assert_ok(["admin", "bob"]).await;
+        bob.sync_as_assert_ok(&as_state).await;
+
+        let action = ActionMsg::TextMsg(TextMsgAction {
+            msg: "hello".to_string(),
+            expires_after: None,
+            in_reply_to: None,
+            metadata: ActionMetadata::new(
+                admin.name(),
+                "text".to_string(),
+                comm_grp(),
+                admin.configs.now(),
+            ),
+        });
+        let genuine = VerifiableAction::new(action.clone(), admin.configs.get_keypair());
+        assert_eq!(
+            verify_action(&genuine, bob.configs.as_ref()),
+            Some(action.clone())
+        );
+        assert_eq!(
+            decode_action(&encode_to_bytes(&genuine), bob.configs.as_ref()),
+            Some(action.clone())
+        );
+
+        // Signed by bob, claiming to be sent by admin
+        let forged = VerifiableAction::new(action.clone(), bob.configs.get_keypair());
+        assert!(verify_action(&forged, bob.configs.as_ref()).is_none());
+        assert!(decode_action(&encode_to_bytes(&forged), bob.configs.as_ref()).is_none());
+        assert!(decode_action(&encode_to_bytes(&action), bob.configs.as_ref()).is_none());
+    }
+}
@@ -1,6 +1,6 @@
 use openmls::ciphersuite::hash_ref::HashReference;
 use openmls::credentials::{Credential, CredentialBundle, CredentialType};
-use openmls::extensions::{Extension, LifetimeExtension};
+use openmls::extensions::{Extension, ExtensionType, LifetimeExtension};
 use openmls::key_packages::{KeyPackage, KeyPackageBundle};
 use openmls_rust_crypto::RustCrypto;
 use openmls_traits::crypto::OpenMlsCrypto;
@@ -10,6 +10,7 @@ use openmls_traits::OpenMlsCryptoProvider;
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::sync::RwLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{fmt, u8};
 use tls_codec::Serialize;
 
@@ -19,7 +20,8 @@ use tls_codec::Serialize;
 const DEFAULT_SIGNATURE_SCHEME: SignatureScheme = SignatureScheme::ED25519;
 const DEFAULT_CREDENTIAL_TYPE: CredentialType = CredentialType::Basic;
 
-const DEFAULT_LIFETIME: u64 = 60 * 60 * 24 * 90;
+/// The lifetime of the key packages generated by default: 90 days
+pub const DEFAULT_KEY_PACKAGE_LIFETIME: Duration = Duration::from_secs(60 * 60 * 24 * 90);
 
 /// Errors thrown by the key store.
 /// Credit: OpenMLS Memory Keystore
@@ -40,10 +42,22 @@ pub struct KeyStore {
 
 pub type KeyStoreType = HashMap<Vec<u8>, Vec<u8>>;
 
-#[derive(Default, Debug)]
+#[derive(Debug)]
 pub struct CryptoBackend {
     pub crypto: RustCrypto,
     pub key_store: KeyStore,
+    /// How long the generated key packages are valid for
+    pub key_package_lifetime: Duration,
+}
+
+impl Default for CryptoBackend {
+    fn default() -> Self {
+        CryptoBackend {
+            crypto: RustCrypto::default(),
+            key_store: KeyStore::default(),
+            key_package_lifetime: DEFAULT_KEY_PACKAGE_LIFETIME,
+        }
+    }
 }
 
 /// Credit: OpenMLS Memory Keystore
@@ -188,12 +202,24 @@ impl CryptoBackend {
         .expect("Cannot create new [KeyPackageBundle]")
     }
 
+    /// Generates a key package valid for the `key_package_lifetime` of the
+    /// backend, and stores its bundle
     pub fn generate_default_key_package_and_store_bundle(
         &mut self,
         credential: &Credential,
+    ) -> KeyPackage {
+        self.generate_key_package_with_lifetime(credential, self.key_package_lifetime)
+    }
+
+    /// Generates a key package valid from now on for `lifetime`, and stores
+    /// its bundle
+    pub fn generate_key_package_with_lifetime(
+        &mut self,
+        credential: &Credential,
+        lifetime: Duration,
     ) -> KeyPackage {
         let extensions = vec![Extension::LifeTime(LifetimeExtension::new(
-            DEFAULT_LIFETIME, // Maximum lifetime of 90 days, expressed in seconds
+            lifetime.as_secs(),
         ))];
 
         // Fetch the credential bundle from the key store
@@ -213,6 +239,39 @@ impl CryptoBackend {
             .hash_ref(self.crypto())
             .expect("Could not hash KeyPackage.")
     }
+
+    /// Returns the `not_before` and `not_after` bounds of the lifetime
+    /// extension of `key_package`, in seconds since the epoch, if any
+    pub fn key_package_lifetime_bounds(&self, key_package: &KeyPackage) -> Option<(u64, u64)> {
+        let lifetime = key_package
+            .extension_with_type(ExtensionType::Lifetime)
+            .and_then(|extension| extension.as_lifetime_extension().ok())?;
+        // The bounds of the extension are private, so read them from its wire
+        // encoding: `not_before` then `not_after`, in big-endian seconds
+        let encoded = match lifetime.tls_serialize_detached() {
+            Ok(encoded) if encoded.len() == 16 => encoded,
+            _ => return None,
+        };
+        Some((
+            u64::from_be_bytes(encoded[..8].try_into().unwrap()),
+            u64::from_be_bytes(encoded[8..].try_into().unwrap()),
+        ))
+    }
+
+    /// Returns whether the lifetime extension of `key_package` covers `now`,
+    /// as read from the clock of the client. A key package without a
+    /// lifetime extension is never valid.
+    pub fn key_package_is_valid_at(&self, key_package: &KeyPackage, now: SystemTime) -> bool {
+        let (not_before, not_after) = match self.key_package_lifetime_bounds(key_package) {
+            Some(bounds) => bounds,
+            None => return false,
+        };
+        let now = now
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+        not_before <= now && now <= not_after
+    }
 }
 
 // Credit: https://stackoverflow.com/questions/32710187/how-do-i-get-an-enum-as-a-string
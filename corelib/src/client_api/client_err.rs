@@ -1,13 +1,20 @@
 use std::fmt;
+use std::io;
 
 use openmls::prelude::WelcomeError;
 use thiserror::Error;
 
+use crate::client_api::actions::ActionType;
+
 #[derive(Debug, Error)]
-pub(crate) enum ClientError {
+pub enum ClientError {
     NoGroupStateAvailable,
     NoSuchInvite,
     InvalidInvite(#[from] WelcomeError),
+    Unauthorized(ActionType),
+    InvalidGovStateExport(serde_json::Error),
+    InvalidPrefs,
+    CannotCopyClientData(serde_json::Error),
 }
 
 impl fmt::Display for ClientError {
@@ -28,6 +35,34 @@ impl fmt::Display for ClientError {
                     "The latest welcome stored for this group locally is not usable"
                 )
             }
+            ClientError::Unauthorized(action_type) => {
+                write!(f, "The current role does not permit {:?}", action_type)
+            }
+            ClientError::InvalidGovStateExport(e) => {
+                write!(f, "Cannot parse the exported group state: {}", e)
+            }
+            ClientError::InvalidPrefs => {
+                write!(
+                    f,
+                    "Cannot decrypt the stored preferences. Do all devices use the same secret?"
+                )
+            }
+            ClientError::CannotCopyClientData(e) => {
+                write!(f, "Cannot copy the client data to replay on: {}", e)
+            }
         }
     }
 }
+
+/// Why a history export did not pass [crate::client_api::verify_history_export]
+#[derive(Debug, Error)]
+pub enum HistoryExportError {
+    #[error("Cannot read the history export: {0}")]
+    Io(#[from] io::Error),
+    #[error("Cannot parse the history export: {0}")]
+    Malformed(#[from] serde_json::Error),
+    #[error("The hash chain of the history export breaks at message {index}")]
+    BrokenChain { index: usize },
+    #[error("The signature on the history export does not verify")]
+    BadSignature,
+}
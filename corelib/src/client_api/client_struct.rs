@@ -6,7 +6,7 @@
 //   https://github.com/openmls/openmls/blob/83faeae98af97b517f127f9a9f54bd9a6f1140eb/memory_keystore/src/lib.rs
 // - OpenMLS Rust Crypto
 //   https://github.com/openmls/openmls/blob/83faeae98af97b517f127f9a9f54bd9a6f1140eb/openmls_rust_crypto/src/lib.rs
-use crate::client_api::actions::{ActionMsg, ActionType};
+use crate::client_api::actions::{ActionMsg, ActionType, CustomAction, CustomActionHandler};
 use crate::client_api::client_crypto_impl::BackendError;
 use crate::messages::UnorderedPrivateMessage;
 use crate::policyengine::{Policy, PolicyEngine};
@@ -18,17 +18,21 @@ use mockall::automock;
 use openmls::group::MlsGroup;
 use openmls::key_packages::KeyPackage;
 use openmls::prelude::{Credential, Welcome};
+use serde::de::Error as _;
 use serde::{Deserialize, Serialize};
 use serde_json_any_key::any_key_map;
+use sha2::{Digest, Sha256};
 use std::any::Any;
 use std::cell::RefCell;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
 use std::fmt::Debug;
 use std::rc::Rc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use std::vec;
+use uuid::Uuid;
 
-use crate::messages::OnWireMessage;
+use crate::messages::{AuthToken, OnWireMessage};
 
 use crate::client_api::VerifiableAction;
 use crate::policyengine::policy_eng_deserialize;
@@ -59,11 +63,15 @@ pub trait ClientDataProvider: Debug {
     fn remove_welcome(&mut self, comm_grp: &CommGroupId) -> Option<Welcome>;
     /// Return the user id associated with this client
     fn get_user_id(&self) -> String;
+    /// Returns the current time according to the clock of this client
+    fn now(&self) -> SystemTime;
     fn get_credential(&self) -> Credential;
     fn update_governance_state(&mut self, comm_grp: &CommGroupId, key: String, value: String);
 
     fn store_to_add_invitee_key_pack(&mut self, comm_grp: &CommGroupId, key_package: KeyPackage);
 
+    /// Removes the pre-approved key package of `invitee_name`, or of one of
+    /// their devices if `invitee_name` is a user with several devices
     fn pop_to_add_invitee_key_pack(
         &mut self,
         comm_grp: &CommGroupId,
@@ -74,6 +82,42 @@ pub trait ClientDataProvider: Debug {
 
     fn pop_to_be_removed_member(&mut self, comm_grp: &CommGroupId, member: &String) -> bool;
 
+    /// Marks the member as having left the group until they are removed
+    fn mark_member_left(&mut self, comm_grp: &CommGroupId, member: &str);
+
+    /// Forgets the role of a member once their removal was merged
+    fn forget_removed_member(&mut self, comm_grp: &CommGroupId, member: &str);
+
+    /// Bans the user from being invited to the group
+    fn ban_user(&mut self, comm_grp: &CommGroupId, user_id: String);
+
+    /// Lifts the ban of the user, if any
+    fn unban_user(&mut self, comm_grp: &CommGroupId, user_id: &str);
+
+    /// Returns whether the user is banned from the group
+    fn is_banned(&self, comm_grp: &CommGroupId, user_id: &str) -> bool;
+
+    /// Mutes the group, or only `user_id` within it if given. Local only
+    fn mute(&mut self, comm_grp: &CommGroupId, user_id: Option<&str>);
+
+    /// Unmutes the group, or only `user_id` within it if given
+    fn unmute(&mut self, comm_grp: &CommGroupId, user_id: Option<&str>);
+
+    /// Returns whether the messages of `sender` in the group are muted, as
+    /// either the group or the sender is
+    fn is_muted(&self, comm_grp: &CommGroupId, sender: &str) -> bool;
+
+    /// Returns the notification preferences of this client, shared with the
+    /// other devices of its user
+    fn get_notification_prefs(&self) -> NotificationPrefs;
+
+    /// Replaces the notification preferences of this client with `prefs`,
+    /// e.g., as stored by another device of its user
+    fn set_notification_prefs(&mut self, prefs: NotificationPrefs);
+
+    /// Caps the number of members of the group, or lifts the cap if `None`
+    fn set_max_group_size(&mut self, comm_grp: &CommGroupId, max_group_size: Option<usize>);
+
     /// A function called when the current client
     /// sending a message, specifically to store a self-sent message
     /// as DS will not relay them back
@@ -83,26 +127,39 @@ pub trait ClientDataProvider: Debug {
         private_msg: &UnorderedPrivateMessage,
     );
 
+    /// Returns the sequence number of the next message this client sends to
+    /// the group, for its receivers to store its messages in order
+    fn next_msg_seq(&mut self, comm_grp: &CommGroupId) -> u64;
+
     /// A function called to store the action corresponding to a pending
     /// commit. The merging of the pending commit occurs at the MLS layer.
     /// This function allows us to get the corresponding action at the
     /// application layer and apply it once we know our commit has been
-    /// relayed.
+    /// relayed. A commit of several actions stores each of them in order.
     fn store_pending_action(
         &mut self,
         comm_grp: &CommGroupId,
         action: crate::client_api::ActionMsg,
     );
 
-    /// A function called to retrieve and remove a pending action if any.
-    /// Currently this action is returned as a [ClientParsedMsg], however
-    /// we will soon replace this with a new Action type.
+    /// A function called to retrieve and remove the oldest pending action if any.
     fn pop_pending_action(
         &mut self,
         comm_grp: &CommGroupId,
     ) -> Option<crate::client_api::ActionMsg>;
 
-    /// A function called during parsing when received a new message from DS
+    /// A function called to retrieve and remove all pending actions, in the
+    /// order they were stored.
+    fn pop_pending_actions(&mut self, comm_grp: &CommGroupId) -> Vec<crate::client_api::ActionMsg>;
+
+    /// Returns whether the removal of `member` from `comm_grp` is already
+    /// under way: a `Kick` of them awaits its echo from the DS or a decision
+    /// of the policy engine, or they are to be removed from the MLS group
+    fn is_removal_pending(&self, comm_grp: &CommGroupId, member: &str) -> bool;
+
+    /// A function called during parsing when received a new message from DS.
+    /// Messages of a sender are stored in the order of their sequence numbers,
+    /// and skipped sequence numbers are flagged until the messages arrive
     fn store_received_msg(
         &mut self,
         comm_grp: &CommGroupId,
@@ -122,27 +179,144 @@ pub trait ClientDataProvider: Debug {
     /// Obtain the topic of the group
     fn get_group_topic(&self, comm_grp: &CommGroupId) -> &str;
 
-    /// Define a role within a group as a vector of action types
+    /// Record the `(lamport_counter, author)` of the group's current name
+    fn set_group_name_version(&mut self, comm_grp: &CommGroupId, version: (u64, String));
+
+    /// Obtain the `(lamport_counter, author)` of the group's current name
+    fn get_group_name_version(&self, comm_grp: &CommGroupId) -> (u64, String);
+
+    /// Record the `(lamport_counter, author)` of the group's current topic
+    fn set_group_topic_version(&mut self, comm_grp: &CommGroupId, version: (u64, String));
+
+    /// Obtain the `(lamport_counter, author)` of the group's current topic
+    fn get_group_topic_version(&self, comm_grp: &CommGroupId) -> (u64, String);
+
+    /// Sets whether group names must be unique within a community
+    fn set_enforce_unique_group_names(&mut self, enforce: bool);
+
+    /// Sets the roles given to the creator and to the invitees of groups this
+    /// client creates from now on, instead of `Mod` and `BaseUser`
+    fn set_default_group_roles(&mut self, creator_role: String, invitee_role: String);
+
+    /// Applies `roles`, or this client's default group roles if `None`, to a
+    /// newly created group and returns the role its creator should get
+    fn init_group_roles(
+        &mut self,
+        comm_grp: &CommGroupId,
+        roles: Option<GroupRoleConfig>,
+    ) -> String;
+
+    /// Returns the role given to users invited to the group
+    fn get_invitee_role(&self, comm_grp: &CommGroupId) -> String;
+
+    /// Returns whether unique group names are enforced and another known group
+    /// of the same community is already named `name`, ignoring case.
+    /// Groups without a name go by their group id.
+    fn is_group_name_taken(&self, comm_grp: &CommGroupId, name: &str) -> bool;
+
+    /// Sets whether ordered messages are sent without a sender field
+    fn set_sealed_sender(&mut self, sealed_sender: bool);
+
+    /// Returns whether ordered messages are sent without a sender field
+    fn is_sealed_sender(&self) -> bool;
+
+    /// Remember a message sent with a sealed sender, to recognize its echo
+    fn store_sealed_sent_msg_id(&mut self, client_msg_id: Uuid);
+
+    /// Returns whether the message was sent by this client with a sealed
+    /// sender, forgetting it
+    fn pop_sealed_sent_msg_id(&mut self, client_msg_id: &Uuid) -> bool;
+
+    /// Add a report to the group's review queue
+    fn store_report(&mut self, comm_grp: &CommGroupId, report: StoredReport);
+
+    /// Return the group's review queue, oldest report first
+    fn get_reports(&self, comm_grp: &CommGroupId) -> Vec<StoredReport>;
+
+    /// Record that `flagger` flagged the message with id `message_id`
+    fn store_flag(&mut self, comm_grp: &CommGroupId, message_id: String, flagger: String);
+
+    /// Return the flagged messages of the group, mapped to who flagged them
+    fn get_flags(&self, comm_grp: &CommGroupId) -> BTreeMap<String, BTreeSet<String>>;
+
+    /// Sets the number of flags of a message of the group after which the
+    /// member whose flag reaches it proposes a takedown. `None` never
+    /// proposes one.
+    fn set_flag_takedown_threshold(&mut self, comm_grp: &CommGroupId, threshold: Option<usize>);
+
+    /// Returns the number of flags of a message of the group after which a
+    /// takedown is proposed
+    fn get_flag_takedown_threshold(&self, comm_grp: &CommGroupId) -> Option<usize>;
+
+    /// Queues `action` to be sent to `comm_grp`, e.g., by a policy
+    /// reacting to another action
+    fn push_outbox_action(&mut self, comm_grp: &CommGroupId, action: ActionMsg);
+
+    /// Removes and returns the actions queued to be sent, oldest first
+    fn take_outbox_actions(&mut self) -> Vec<(CommGroupId, ActionMsg)>;
+
+    /// Records a group message as sent but not confirmed by the DS yet. A
+    /// message the DS already confirmed or that is recorded already is
+    /// ignored.
+    fn push_unsent_msg(&mut self, onwire_msg: OnWireMessage);
+
+    /// Returns the messages sent but not confirmed by the DS yet, oldest first
+    fn get_unsent_msgs(&self) -> Vec<OnWireMessage>;
+
+    /// Marks the group message `client_msg_id`, if recorded as unsent, as
+    /// confirmed by the DS, no longer to be resent
+    fn confirm_sent_msg(&mut self, client_msg_id: Uuid);
+
+    /// Define a role within a group as a vector of action types, inheriting
+    /// those of the given roles
     fn def_role(
         &mut self,
         comm_grp: &CommGroupId,
         role_name: String,
         action_types: Vec<crate::client_api::ActionType>,
+        inherits_from: Vec<String>,
     );
 
-    /// Assign roles to users within a gruop
+    /// Assign roles to users within a gruop, replacing the roles they held
     fn set_user_role(&mut self, comm_grp: &CommGroupId, user_id: String, role_name: String);
 
+    /// Gives a role to a user within a group, in addition to the roles they hold
+    fn add_user_role(&mut self, comm_grp: &CommGroupId, user_id: String, role_name: String);
+
+    /// Takes a role away from a user within a group
+    fn remove_user_role(&mut self, comm_grp: &CommGroupId, user_id: String, role_name: String);
+
     /// Determines if action is authorized according to the permissions
     /// in the group
     fn action_authorized(&mut self, action: &crate::client_api::ActionMsg) -> bool;
 
+    /// Returns true if the role of `actor` in the group permits actions of
+    /// `action_type`, e.g., to only offer the actions a user may take. Unlike
+    /// [Self::action_authorized], needs no action, and returns false if the
+    /// group is unknown
+    fn can_perform(&self, comm_grp: &CommGroupId, action_type: ActionType, actor: &str) -> bool;
+
     /// Return the cloned Rbac state
     fn get_roles(&self, comm_grp: &CommGroupId) -> RbacState;
 
-    /// Returns the members belonging to the specified group
+    /// Returns the members belonging to the specified group, with each device
+    /// of a user as a separate member
     fn get_group_members(&self, comm_grp: &CommGroupId) -> Vec<String>;
 
+    /// Returns true if `user_name` is a member of the specified group. Each
+    /// device of a user is a member under its own name
+    fn is_member(&self, comm_grp: &CommGroupId, user_name: &str) -> bool;
+
+    /// Returns the roles assigned to `user_name` in the specified group,
+    /// joined by `+`, or `None` if they were assigned none
+    fn member_role(&self, comm_grp: &CommGroupId, user_name: &str) -> Option<String>;
+
+    /// Returns the ids of all groups this client holds a state for
+    fn get_comm_grps(&self) -> Vec<CommGroupId>;
+
+    /// Returns the current MLS epoch of all groups this client holds a state for
+    fn get_group_epochs(&self) -> Vec<(CommGroupId, u64)>;
+
     /// Obtains a reference to the ED25519 keypair associated with this client
     fn get_keypair(&self) -> &Keypair;
 
@@ -152,6 +326,51 @@ pub trait ClientDataProvider: Debug {
     /// Sets the credential entries within the client
     fn set_credential_entries(&mut self, credentials: BTreeMap<String, CredentialEntry>);
 
+    /// Adds or updates the given credential entries. Known entries missing
+    /// from `credentials`, e.g., from a partial AS response, are kept if their
+    /// user is still a member of a group of this client, and dropped
+    /// otherwise. If `drop_revoked` is set, revoked entries of users that are
+    /// not members of any of these groups are dropped too
+    fn merge_credential_entries(
+        &mut self,
+        credentials: BTreeMap<String, CredentialEntry>,
+        drop_revoked: bool,
+    );
+
+    /// Returns whether the AS reported the credential of `user_name` as revoked
+    fn is_credential_revoked(&self, user_name: &str) -> bool;
+
+    /// Returns the display names registered with the AS by the users whose
+    /// credential was looked up, by user id. Users without one are left out
+    fn get_display_names(&self) -> BTreeMap<String, String>;
+
+    /// Pins the public key the AS signs its responses with
+    fn set_as_public_key(&mut self, public_key: PublicKey);
+
+    /// Returns the pinned public key of the AS, if any was received yet
+    fn get_as_public_key(&self) -> Option<PublicKey>;
+
+    /// Stores the latest token the AS issued to authenticate to the DS with
+    fn set_ds_token(&mut self, token: AuthToken);
+
+    /// Returns the latest token issued by the AS, if any, even if expired
+    fn get_ds_token(&self) -> Option<AuthToken>;
+
+    /// Records the number of key packages the DS reported holding for this client
+    fn set_remaining_key_packages(&mut self, remaining: usize);
+
+    /// Returns the last reported number of key packages the DS holds for
+    /// this client, or `None` if no sync has reported it yet
+    fn get_remaining_key_packages(&self) -> Option<usize>;
+
+    /// Records that the relayed messages up to the server timestamp
+    /// `relayed_until` were processed, unless later ones already were
+    fn set_relayed_until(&mut self, relayed_until: SystemTime);
+
+    /// Returns the server timestamp up to which the relayed messages were
+    /// processed, acknowledged to the DS at the next sync, if any
+    fn get_relayed_until(&self) -> Option<SystemTime>;
+
     /// Returns a serialization of the object -- note that in
     /// order to make this trait object safe, we cannot make
     /// `Serialize` a supertrait.
@@ -161,6 +380,57 @@ pub trait ClientDataProvider: Debug {
     /// assumed to be empty innitializations.
     fn set_client_policies(&mut self, policies: Vec<Box<dyn Policy>>);
 
+    /// Returns copies of the policies governing this client
+    fn get_client_policies(&self) -> Vec<Box<dyn Policy>>;
+
+    /// Records an action evaluated by the policy engine of the group.
+    /// Disappearing text messages are not recorded, so that they expire
+    fn log_policy_action(&mut self, comm_grp: &CommGroupId, action: ActionMsg);
+
+    /// Drops the actions the policy engine of the group rejected from its log
+    fn unlog_policy_actions(&mut self, comm_grp: &CommGroupId, action_ids: &[String]);
+
+    /// Returns the latest actions evaluated by the policy engine of the group
+    /// and not rejected by it, oldest first
+    fn get_policy_action_log(&self, comm_grp: &CommGroupId) -> Vec<ActionMsg>;
+
+    /// Records that a close of the vote on each of the actions `action_ids`
+    /// is queued in the group, forgetting those of other actions, e.g., of
+    /// decided ones. Returns the actions a close was not queued for before
+    fn record_queued_poll_closes(
+        &mut self,
+        comm_grp: &CommGroupId,
+        action_ids: &[String],
+    ) -> Vec<String>;
+
+    /// Keeps a snapshot of the group state of the group at its current MLS
+    /// epoch, replacing any previous one of the same epoch. Only the latest
+    /// `MAX_GOV_STATE_SNAPSHOTS` epochs are kept
+    fn store_gov_state_snapshot(&mut self, comm_grp: &CommGroupId);
+
+    /// Returns a copy of the group state snapshot kept for `epoch`, if any
+    fn get_gov_state_snapshot(
+        &self,
+        comm_grp: &CommGroupId,
+        epoch: u64,
+    ) -> Option<SharedGroupState>;
+
+    /// Returns a copy of the group state as received when joining the group,
+    /// if its snapshot is still kept. `None` for the creator of the group
+    fn get_received_gov_state(&self, comm_grp: &CommGroupId) -> Option<SharedGroupState>;
+
+    /// Registers the handler of the `CustomAction`s of the given kind,
+    /// replacing any handler previously registered for it
+    fn register_custom_action_handler(
+        &mut self,
+        kind: String,
+        handler: Box<dyn CustomActionHandler>,
+    );
+
+    /// Executes a `CustomAction` with the handler registered for its kind.
+    /// Returns false if there is none
+    fn dispatch_custom_action(&mut self, action: &CustomAction) -> bool;
+
     /// As any to allow proper downcasting
     fn as_any(&self) -> &dyn Any;
 
@@ -218,6 +488,12 @@ pub enum ClientParsedMsg {
         sender: String,
         comm_grp: CommGroupId,
     },
+    /// A member that discarded its local state of `comm_grp` asks
+    /// to be invited again
+    ReinviteRequest {
+        requester: String,
+        comm_grp: CommGroupId,
+    },
     ASFeedback {
         request_valid: bool,
         explanation: Option<String>,
@@ -233,6 +509,90 @@ pub enum ClientParsedMsg {
         retry_possible: bool,
         description: String,
     },
+    /// A message of `comm_grp` was discarded for being of MLS epoch `got`,
+    /// while the local group is at epoch `expected`. A self-sent one was
+    /// preceded by other ordered messages, and can be retried after a sync
+    EpochMismatch {
+        comm_grp: CommGroupId,
+        expected: u64,
+        got: u64,
+        self_sent: bool,
+    },
+    /// A message of `comm_grp` from another member could not be decrypted
+    DecryptionFailed { comm_grp: CommGroupId },
+}
+
+impl ClientParsedMsg {
+    /// Describes why the message could not be processed, or returns `None`
+    /// if it was
+    pub fn error_description(&self) -> Option<String> {
+        match self {
+            ClientParsedMsg::Invalid { description, .. } => Some(description.clone()),
+            ClientParsedMsg::EpochMismatch {
+                self_sent: true, ..
+            } => Some("The action you just attempted did not go through because there was other valid actions preceding it. Please try again".to_string()),
+            ClientParsedMsg::EpochMismatch {
+                comm_grp,
+                expected,
+                got,
+                self_sent: false,
+            } => Some(format!(
+                "An action by other group member in {:?} was discarded because it had Wrong Epoch {got} (local epoch {expected})",
+                comm_grp
+            )),
+            ClientParsedMsg::DecryptionFailed { comm_grp } => Some(format!(
+                "Unable to decrypt an incoming message in {:?}",
+                comm_grp
+            )),
+            ClientParsedMsg::NewInvite { .. }
+            | ClientParsedMsg::NewMsg { .. }
+            | ClientParsedMsg::NewOrdMsg { .. }
+            | ClientParsedMsg::ReinviteRequest { .. }
+            | ClientParsedMsg::ASFeedback { .. }
+            | ClientParsedMsg::DSFeedback { .. } => None,
+        }
+    }
+
+    /// Invokes the callback of `sink` matching the kind of this message
+    pub fn notify(&self, sink: &mut dyn ClientEventSink) {
+        match self {
+            ClientParsedMsg::NewMsg { .. } | ClientParsedMsg::NewOrdMsg { .. } => {
+                sink.on_new_message(self)
+            }
+            ClientParsedMsg::NewInvite { inviter, comm_grp } => sink.on_invite(inviter, comm_grp),
+            ClientParsedMsg::ReinviteRequest {
+                requester,
+                comm_grp,
+            } => sink.on_reinvite_request(requester, comm_grp),
+            ClientParsedMsg::ASFeedback { .. } | ClientParsedMsg::DSFeedback { .. } => {
+                sink.on_feedback(self)
+            }
+            ClientParsedMsg::Invalid { .. }
+            | ClientParsedMsg::EpochMismatch { .. }
+            | ClientParsedMsg::DecryptionFailed { .. } => sink.on_invalid(self),
+        }
+    }
+}
+
+/// Receives the messages parsed by
+/// [parse_incoming_onwire_msgs_with_sink](crate::client_api::parse_incoming_onwire_msgs_with_sink)
+/// as they are parsed, e.g., to update a UI. Callbacks not implemented do nothing
+pub trait ClientEventSink {
+    /// A `NewMsg` or `NewOrdMsg`
+    fn on_new_message(&mut self, _msg: &ClientParsedMsg) {}
+
+    /// An invite from `inviter` to `comm_grp`, already joined
+    fn on_invite(&mut self, _inviter: &str, _comm_grp: &CommGroupId) {}
+
+    /// A request of `requester` to be invited again to `comm_grp`
+    fn on_reinvite_request(&mut self, _requester: &str, _comm_grp: &CommGroupId) {}
+
+    /// An `ASFeedback` or `DSFeedback`
+    fn on_feedback(&mut self, _feedback: &ClientParsedMsg) {}
+
+    /// A message that could not be processed, see
+    /// [ClientParsedMsg::error_description]
+    fn on_invalid(&mut self, _invalid: &ClientParsedMsg) {}
 }
 
 impl From<BackendError> for String {
@@ -246,12 +606,10 @@ pub fn mls_group_deserialize<'a, D>(deserializer: D) -> Result<RefCell<MlsGroup>
 where
     D: serde::Deserializer<'a>,
 {
-    let v = deserializer
-        .deserialize_byte_buf(BytesVisitor)
-        .expect("Cannot get byte buf");
+    let v = deserializer.deserialize_byte_buf(BytesVisitor)?;
     match MlsGroup::load(&*v) {
         Ok(mls_group) => Ok(RefCell::new(mls_group)),
-        Err(e) => panic!("Cannot load group:{:?}", e),
+        Err(e) => Err(D::Error::custom(format!("Cannot load group:{:?}", e))),
     }
 }
 
@@ -274,6 +632,12 @@ pub struct SharedGroupState {
     pub name: String,
     /// The topic associated with the group
     pub topic: String,
+    /// The `(lamport_counter, author)` of the last accepted rename
+    #[serde(default)]
+    pub name_version: (u64, String),
+    /// The `(lamport_counter, author)` of the last accepted topic change
+    #[serde(default)]
+    pub topic_version: (u64, String),
     /// Rbac state
     pub rbac: RbacState,
 
@@ -297,17 +661,155 @@ pub struct SharedGroupState {
 
     /// A list of members pre-approved to be removed
     pub to_be_removed_members: Vec<String>,
+
+    /// Users who cannot be invited to the group
+    #[serde(default)]
+    pub banned_users: BTreeSet<String>,
+
+    /// Reports awaiting review by moderators
+    #[serde(default)]
+    pub reports: Vec<StoredReport>,
+
+    /// Flagged message ids, mapped to the members who flagged them
+    #[serde(default)]
+    pub flags: BTreeMap<String, BTreeSet<String>>,
+
+    /// The most members, counting pre-approved invitees, the group may have
+    #[serde(default)]
+    pub max_group_size: Option<usize>,
+
+    /// The number of flags of a message after which the member whose flag
+    /// reaches it proposes to take the message down
+    #[serde(default)]
+    pub flag_takedown_threshold: Option<usize>,
+}
+
+/// A report received in the group, kept for moderators to review
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct StoredReport {
+    pub reporter: String,
+    pub reason: String,
+    /// The reported verifiable action serialized as a String
+    pub reported_action: String,
+    pub received_timestamp: SystemTime,
+}
+
+/// The notification preferences the devices of a user share through the AS
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct NotificationPrefs {
+    /// The muted groups, as `(group, None)`, and members, as
+    /// `(group, Some(member))`, with groups given by [CommGroupId::get_string]
+    #[serde(default)]
+    pub muted: BTreeSet<(String, Option<String>)>,
+}
+
+/// A readable summary of the local state of a group
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct GroupInfo {
+    pub name: String,
+    pub topic: String,
+    /// The current MLS epoch of the group
+    pub epoch: u64,
+    /// The `(identity, roles)` of each member, sorted by identity, with
+    /// the roles of a member joined by `+`
+    pub members: Vec<(String, String)>,
+    /// The [crate::credential_fingerprint] of each member's credential in
+    /// the MLS group, by identity
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub key_fingerprints: BTreeMap<String, String>,
+}
+
+/// An inconsistency in the local state of a group
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum IntegrityDiscrepancy {
+    /// A member of the MLS group without a role in the RBAC state
+    MemberWithoutRole(String),
+    /// A proposed action whose signature does not verify against the
+    /// credential of its sender, or whose sender has no known credential
+    UnverifiedProposedAction { action_id: String, sender: String },
+    /// The hash of the group state as received differs from the hash stored
+    /// when receiving it
+    InitHashMismatch { stored: u64, recomputed: u64 },
+}
+
+impl fmt::Display for IntegrityDiscrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IntegrityDiscrepancy::MemberWithoutRole(member) => {
+                write!(f, "Member {} has no role", member)
+            }
+            IntegrityDiscrepancy::UnverifiedProposedAction { action_id, sender } => write!(
+                f,
+                "The signature of the proposed action {} by {} does not verify",
+                action_id, sender
+            ),
+            IntegrityDiscrepancy::InitHashMismatch { stored, recomputed } => write!(
+                f,
+                "The group state as received hashes to {} instead of the stored {}",
+                recomputed, stored
+            ),
+        }
+    }
 }
 
 // TODO: better comparison for policy engines
+// Reports and flags are unordered and timestamped on receipt, so they are not compared
 impl PartialEq for SharedGroupState {
     fn eq(&self, other: &Self) -> bool {
         self.name == other.name
             && self.topic == other.topic
+            && self.name_version == other.name_version
+            && self.topic_version == other.topic_version
             && self.rbac == other.rbac
             && self.governance_state == other.governance_state
             && self.to_add_invitees == other.to_add_invitees
             && self.to_be_removed_members == other.to_be_removed_members
+            && self.banned_users == other.banned_users
+            && self.max_group_size == other.max_group_size
+            && self.flag_takedown_threshold == other.flag_takedown_threshold
+    }
+}
+
+impl SharedGroupState {
+    /// The serialization of the fields compared by `PartialEq`, with the
+    /// entries of every collection in sorted order, so that members whose
+    /// states have the same content get the same bytes, whatever order they
+    /// added the entries in
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        let mut role_defs = self.rbac.role_defs.clone();
+        for action_types in role_defs.values_mut() {
+            action_types.sort_by_key(|action_type| format!("{:?}", action_type));
+            action_types.dedup();
+        }
+        let rbac = RbacState {
+            role_defs,
+            ..self.rbac.clone()
+        };
+        let mut to_be_removed_members = self.to_be_removed_members.clone();
+        to_be_removed_members.sort();
+        // Maps and sets are BTree ones, which serialize in key order already
+        let compared = (
+            &self.name,
+            &self.topic,
+            &self.name_version,
+            &self.topic_version,
+            &rbac,
+            &self.governance_state,
+            &self.to_add_invitees,
+            &to_be_removed_members,
+            &self.banned_users,
+            &self.max_group_size,
+            &self.flag_takedown_threshold,
+        );
+        serde_json::to_vec(&compared).expect("Cannot serialize group state")
+    }
+
+    /// A hash of the [SharedGroupState::canonical_bytes], which unlike
+    /// `DefaultHasher` is the same across runs and members, so that members
+    /// can compare their group states
+    pub fn stable_hash(&self) -> u64 {
+        let digest = Sha256::digest(self.canonical_bytes());
+        u64::from_be_bytes(digest[..8].try_into().expect("Digest too short"))
     }
 }
 
@@ -316,8 +818,80 @@ pub struct RbacState {
     /// A mapping from role names to sets of action types
     #[serde(with = "any_key_map")]
     pub role_defs: BTreeMap<String, Vec<ActionType>>,
-    #[serde(with = "any_key_map")]
-    pub user_to_role: BTreeMap<String, String>,
+    /// The roles held by each user. A user is authorized to take an action
+    /// if any of their roles permits it
+    #[serde(
+        alias = "user_to_role",
+        serialize_with = "any_key_map::serialize",
+        deserialize_with = "user_roles_deserialize"
+    )]
+    pub user_to_roles: BTreeMap<String, BTreeSet<String>>,
+    /// The role given to the creator of the group
+    #[serde(default = "default_creator_role")]
+    pub creator_role: String,
+    /// The role given to users invited to the group
+    #[serde(default = "default_invitee_role")]
+    pub invitee_role: String,
+    /// Users that left the group but were not removed from it yet, so that
+    /// they can be shown as such
+    #[serde(default)]
+    pub left_users: BTreeSet<String>,
+    /// The roles each role inherits the action types of, for roles defined
+    /// with parents
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub role_parents: BTreeMap<String, BTreeSet<String>>,
+}
+
+/// The roles given to the creator and to the invitees of a group when it is
+/// created, stored in its [RbacState]
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+pub struct GroupRoleConfig {
+    pub creator_role: String,
+    pub invitee_role: String,
+}
+
+impl Default for GroupRoleConfig {
+    fn default() -> Self {
+        GroupRoleConfig {
+            creator_role: default_creator_role(),
+            invitee_role: default_invitee_role(),
+        }
+    }
+}
+
+/// The roles of a user as stored, either as a set of roles or as the single
+/// role of states stored before users could hold several roles
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredUserRoles {
+    Many(BTreeSet<String>),
+    One(String),
+}
+
+/// Custom deserialization for [RbacState::user_to_roles], accepting states
+/// stored before users could hold several roles
+fn user_roles_deserialize<'de, D>(
+    deserializer: D,
+) -> Result<BTreeMap<String, BTreeSet<String>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let stored: BTreeMap<String, StoredUserRoles> = any_key_map::deserialize(deserializer)?;
+    Ok(stored
+        .into_iter()
+        .map(|(user_id, roles)| match roles {
+            StoredUserRoles::Many(roles) => (user_id, roles),
+            StoredUserRoles::One(role) => (user_id, BTreeSet::from([role])),
+        })
+        .collect())
+}
+
+fn default_creator_role() -> String {
+    "Mod".to_string()
+}
+
+fn default_invitee_role() -> String {
+    "BaseUser".to_string()
 }
 
 impl RbacState {
@@ -325,36 +899,188 @@ impl RbacState {
     pub fn new() -> Self {
         let mut result = RbacState {
             role_defs: BTreeMap::new(),
-            user_to_role: BTreeMap::new(),
+            user_to_roles: BTreeMap::new(),
+            creator_role: default_creator_role(),
+            invitee_role: default_invitee_role(),
+            left_users: BTreeSet::new(),
+            role_parents: BTreeMap::new(),
         };
         // Currently sets default roles
         result.set_default_roles();
         result
     }
 
-    /// Assigns a role to a user
+    /// Assigns a role to a user, replacing all the roles they held
     pub fn set_user_role(&mut self, user_id: String, role_name: String) {
-        self.user_to_role.insert(user_id, role_name);
+        self.set_multiple_roles(user_id, BTreeSet::from([role_name]));
+    }
+
+    /// Assigns a set of roles to a user, replacing all the roles they held
+    pub fn set_multiple_roles(&mut self, user_id: String, role_names: BTreeSet<String>) {
+        self.user_to_roles.insert(user_id, role_names);
     }
 
-    /// Defines a role as a vector of action types
-    pub fn def_role(&mut self, role_name: String, action_types: Vec<ActionType>) {
+    /// Gives a role to a user in addition to the roles they hold. A user
+    /// without roles yet is treated as holding `BaseUser`.
+    pub fn add_user_role(&mut self, user_id: String, role_name: String) {
+        self.user_to_roles
+            .entry(user_id)
+            .or_insert_with(|| BTreeSet::from(["BaseUser".to_string()]))
+            .insert(role_name);
+    }
+
+    /// Takes a role away from a user, keeping the other roles they hold. A
+    /// user whose last role is taken away is authorized for no action.
+    pub fn remove_user_role(&mut self, user_id: &str, role_name: &str) {
+        if let Some(roles) = self.user_to_roles.get_mut(user_id) {
+            roles.remove(role_name);
+        }
+    }
+
+    /// Marks a user as having left the group, keeping their role until they
+    /// are removed
+    pub fn mark_user_left(&mut self, user_id: String) {
+        self.left_users.insert(user_id);
+    }
+
+    /// Returns true if the user left the group but was not removed yet
+    pub fn has_user_left(&self, user_id: &str) -> bool {
+        self.left_users.contains(user_id)
+    }
+
+    /// Forgets the role and the left mark of a user removed from the group
+    pub fn forget_user(&mut self, user_id: &str) {
+        self.user_to_roles.remove(user_id);
+        self.left_users.remove(user_id);
+    }
+
+    /// Defines a role as a vector of action types, in addition to those of
+    /// the roles it inherits from. The definition is rejected if one of
+    /// these roles does not exist or if it would make a role inherit from
+    /// itself.
+    pub fn def_role(
+        &mut self,
+        role_name: String,
+        action_types: Vec<ActionType>,
+        inherits_from: Vec<String>,
+    ) -> Result<(), String> {
+        for parent in &inherits_from {
+            if !self.role_defs.contains_key(parent) {
+                return Err(format!("Role {} does not exist", parent));
+            }
+            if self.inherited_roles(parent).contains(&role_name) {
+                return Err(format!(
+                    "Role {} cannot inherit from {} as it would inherit from itself",
+                    role_name, parent
+                ));
+            }
+        }
+        let parents: BTreeSet<String> = inherits_from.into_iter().collect();
+        if parents.is_empty() {
+            self.role_parents.remove(&role_name);
+        } else {
+            self.role_parents.insert(role_name.clone(), parents);
+        }
         self.role_defs.insert(role_name, action_types);
+        Ok(())
     }
 
-    /// Returns true if the sender is authorized to perform the given action
-    /// and false otherwise.
+    /// Returns the given role together with all the roles it transitively
+    /// inherits from
+    fn inherited_roles(&self, role_name: &str) -> BTreeSet<String> {
+        let mut visited = BTreeSet::new();
+        let mut to_visit = vec![role_name.to_string()];
+        while let Some(role) = to_visit.pop() {
+            if let Some(parents) = self.role_parents.get(&role) {
+                to_visit.extend(
+                    parents
+                        .iter()
+                        .filter(|parent| !visited.contains(*parent))
+                        .cloned(),
+                );
+            }
+            visited.insert(role);
+        }
+        visited
+    }
+
+    /// Returns the action types a role permits, including those of the roles
+    /// it inherits from, or `None` if the role does not exist
+    pub fn role_action_types(&self, role_name: &str) -> Option<Vec<ActionType>> {
+        if !self.role_defs.contains_key(role_name) {
+            return None;
+        }
+        let mut result = Vec::new();
+        for role in self.inherited_roles(role_name) {
+            for action_type in self.role_defs.get(&role).into_iter().flatten() {
+                if !result.contains(action_type) {
+                    result.push(*action_type);
+                }
+            }
+        }
+        Some(result)
+    }
+
+    /// Sets the roles given to the group creator and to invitees. A role that
+    /// is not defined yet gets the action types of `Mod`, respectively `BaseUser`.
+    pub fn set_creator_and_invitee_roles(&mut self, creator_role: String, invitee_role: String) {
+        for (role, template_role) in [
+            (&creator_role, default_creator_role()),
+            (&invitee_role, default_invitee_role()),
+        ] {
+            if !self.role_defs.contains_key(role) {
+                let action_types = self
+                    .role_defs
+                    .get(&template_role)
+                    .cloned()
+                    .unwrap_or_default();
+                self.role_defs.insert(role.to_owned(), action_types);
+            }
+        }
+        self.creator_role = creator_role;
+        self.invitee_role = invitee_role;
+    }
+
+    /// Returns true if any role of the sender authorizes them to perform
+    /// the given action and false otherwise.
     pub fn action_authorized(&mut self, sender: &String, action: &ActionMsg) -> bool {
         // Insert user if they don't already exist
-        let role = self
-            .user_to_role
+        let roles = self
+            .user_to_roles
             .entry(sender.to_owned())
-            .or_insert_with(|| "BaseUser".to_string());
-        // Check if the role vector contains this action type
-        self.role_defs
-            .get(role)
-            .expect("Role does not exist")
-            .contains(&action.action_type())
+            .or_insert_with(|| BTreeSet::from(["BaseUser".to_string()]))
+            .clone();
+        // Check if the role vector of any role, or of a role it inherits
+        // from, contains this action type
+        roles.iter().any(|role| {
+            self.role_action_types(role)
+                .expect("Role does not exist")
+                .contains(&action.action_type())
+        })
+    }
+
+    /// Returns the roles of the user, sorted by name, treating unknown users
+    /// as `BaseUser`
+    pub fn get_user_roles(&self, user_id: &str) -> Vec<&str> {
+        match self.user_to_roles.get(user_id) {
+            Some(roles) => roles.iter().map(String::as_str).collect(),
+            None => vec!["BaseUser"],
+        }
+    }
+
+    /// Returns true if the user holds the given role, treating unknown users
+    /// as `BaseUser`
+    pub fn has_user_role(&self, user_id: &str, role_name: &str) -> bool {
+        self.get_user_roles(user_id).contains(&role_name)
+    }
+
+    /// Returns true if any role of the user permits the given action type,
+    /// treating unknown users as `BaseUser`
+    pub fn action_type_authorized(&self, user_id: &str, action_type: &ActionType) -> bool {
+        self.get_user_roles(user_id).into_iter().any(|role| {
+            self.role_action_types(role)
+                .map_or(false, |action_types| action_types.contains(action_type))
+        })
     }
 
     /// Initializes default roles for the group
@@ -366,6 +1092,8 @@ impl RbacState {
                 ActionType::Accept,
                 ActionType::UpdateGroupState,
                 ActionType::Report,
+                ActionType::FlagMessage,
+                ActionType::Leave,
             ],
         );
         self.role_defs.insert(
@@ -374,14 +1102,20 @@ impl RbacState {
                 ActionType::TextMsg,
                 ActionType::RenameGroup,
                 ActionType::SetTopicGroup,
+                ActionType::SetMaxGroupSize,
                 ActionType::TakedownTextMsg,
+                ActionType::FlagMessage,
                 ActionType::Invite,
                 ActionType::Kick,
+                ActionType::Ban,
+                ActionType::AdminRevert,
                 ActionType::DefRole,
                 ActionType::SetUserRole,
                 ActionType::Accept,
                 ActionType::UpdateGroupState,
                 ActionType::Report,
+                ActionType::ReviewReports,
+                ActionType::Leave,
             ],
         );
     }
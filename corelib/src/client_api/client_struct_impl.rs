@@ -3,30 +3,62 @@ use crate::messages::{welcome_deserialize, welcome_serialize};
 
 use core::cell::RefCell;
 use std::collections::btree_map::Entry;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fs;
+use std::io;
+use std::path::Path;
 use std::rc::Rc;
 use std::time::SystemTime;
 
-use crate::client_api::client_struct::{ClientDataProvider, RbacState, SharedGroupState};
-use crate::identity_to_str;
-use crate::messages::{OnWireMessage, UnorderedMsgContent, UnorderedPrivateMessage};
+use crate::client_api::client_struct::{
+    ClientDataProvider, GroupRoleConfig, NotificationPrefs, RbacState, SharedGroupState,
+    StoredReport,
+};
+use crate::clock::SharedClock;
+use crate::messages::{AuthToken, OnWireMessage, UnorderedMsgContent, UnorderedPrivateMessage};
 use crate::policyengine::{Policy, PolicyEngine};
 use crate::servers_api::as_struct::CredentialEntry;
 use crate::servers_api::mls_helpers::user_names_from_mls_group;
+use crate::state_store::{
+    decode_versioned, legacy_schema_version, StateStoreError, VersionedState,
+};
 use crate::CommGroupId;
-use ed25519_dalek::{Keypair, PublicKey};
+use crate::{hex_sha256, identity_to_str, split_device};
+use ed25519_dalek::{Keypair, PublicKey, Signature, Signer};
+use log::{debug, warn};
 use openmls::credentials::Credential;
 use openmls::key_packages::KeyPackage;
 use openmls::messages::Welcome;
 use openmls::prelude::MlsGroup;
 use serde::{Deserialize, Serialize};
 use serde_json_any_key::any_key_map;
+use serde_yaml::{Mapping, Value};
+use uuid::Uuid;
 
-use crate::client_api::actions::{Action, ActionMsg, ActionType, VerifiableAction};
+use crate::client_api::actions::{
+    Action, ActionMsg, ActionType, CustomAction, CustomActionHandler, VerifiableAction,
+};
+
+/// How many group state snapshots, one per epoch, are kept for each group
+pub const MAX_GOV_STATE_SNAPSHOTS: usize = 16;
+
+/// How many client message IDs of confirmed group messages are remembered
+pub const MAX_CONFIRMED_MSG_IDS: usize = 256;
+
+/// How many client message IDs of sealed messages awaiting their echo are
+/// remembered
+pub const MAX_SEALED_SENT_MSG_IDS: usize = 256;
+
+/// How many actions the policy action log of a group holds, for
+/// [replay_policies](crate::client_api::replay_policies)
+pub const MAX_POLICY_ACTION_LOG_LEN: usize = 1000;
 
 /// The `ClientData` struct stores all the state relevent to this client
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ClientData {
+    /// The version of the layout of the stored client data
+    #[serde(default = "legacy_schema_version")]
+    pub schema_version: u32,
     /// The user name associated with this client
     pub user_name: String,
     /// The `Credential` object for this client
@@ -45,9 +77,60 @@ pub struct ClientData {
     #[serde(with = "any_key_map")]
     pub community_general_channel_names: BTreeMap<String, String>,
     #[serde(with = "any_key_map")]
-    pub community_group_pending_actions: BTreeMap<String, BTreeMap<String, Option<ActionMsg>>>,
+    pub community_group_pending_actions: BTreeMap<String, BTreeMap<String, Vec<ActionMsg>>>,
     /// Dev only. Skip updating msg history. Shall be reset to false at start every time.
     pub skip_updating_msg_history: bool,
+    /// The number of key packages the DS held for this client as of the last sync
+    #[serde(default)]
+    pub remaining_key_packages: Option<usize>,
+    /// Whether to reject names already used by another group of the same community
+    #[serde(default)]
+    pub enforce_unique_group_names: bool,
+    /// The (creator, invitee) roles of new groups, if not `Mod` and `BaseUser`
+    #[serde(default)]
+    pub default_group_roles: Option<(String, String)>,
+    /// The public key of the AS, pinned when first received
+    #[serde(default)]
+    pub as_public_key: Option<PublicKey>,
+    /// The latest token the AS issued to authenticate to the DS with
+    #[serde(default)]
+    pub ds_token: Option<AuthToken>,
+    /// The server timestamp up to which the relayed messages were processed
+    #[serde(default)]
+    pub relayed_until: Option<SystemTime>,
+    /// Whether to send ordered messages without a sender field
+    #[serde(default)]
+    pub sealed_sender: bool,
+    /// Client message IDs of the sealed messages sent but not echoed yet,
+    /// oldest first
+    #[serde(default)]
+    pub sealed_sent_msg_ids: VecDeque<Uuid>,
+    /// Set if a group advanced its epoch in a run that did not store its
+    /// state, so that the stored group states are outdated
+    #[serde(default)]
+    pub branched_state: bool,
+    /// Actions queued by policies to be sent to their groups, oldest first
+    #[serde(default)]
+    pub outbox: Vec<(CommGroupId, ActionMsg)>,
+    /// Group messages sent but not confirmed by the DS yet, oldest first,
+    /// resent at the next start
+    #[serde(default)]
+    pub unsent_msgs: Vec<OnWireMessage>,
+    /// Client message IDs of the latest group messages the DS confirmed,
+    /// oldest first, so that they are not recorded as unsent again
+    #[serde(default)]
+    pub confirmed_msg_ids: VecDeque<Uuid>,
+    /// The muted groups, as `(group, None)`, and members, as
+    /// `(group, Some(member))`, with groups given by [CommGroupId::get_string].
+    /// Never shared with the other members
+    #[serde(default)]
+    pub muted: BTreeSet<(String, Option<String>)>,
+    /// Handlers of `CustomAction`s by kind. Registered at every start
+    #[serde(skip)]
+    pub custom_action_handlers: BTreeMap<String, Box<dyn CustomActionHandler>>,
+    /// Where timestamps are read from. The system clock when restored
+    #[serde(skip)]
+    pub clock: SharedClock,
 }
 
 // Workaround for confy's [Default] requirement on structure
@@ -102,13 +185,19 @@ impl ClientDataProvider for ClientData {
         self.community_group_pending_actions
             .entry(comm_grp.community_id())
             .or_insert_with(BTreeMap::default)
-            .insert(comm_grp.group_id(), None);
+            .insert(comm_grp.group_id(), Vec::new());
     }
 
     fn remove_group(&mut self, comm_grp: &CommGroupId) {
         if let Some(community) = self.community_states.get_mut(&*comm_grp.community_id()) {
             community.group_states.remove(&*comm_grp.group_id());
         }
+        if let Some(community) = self
+            .community_group_pending_actions
+            .get_mut(&*comm_grp.community_id())
+        {
+            community.remove(&*comm_grp.group_id());
+        }
     }
 
     fn load_shared_state(&mut self, comm_grp: &CommGroupId, shared_state: SharedGroupState) {
@@ -136,7 +225,7 @@ impl ClientDataProvider for ClientData {
 
     fn store_welcome(&mut self, comm_grp: &CommGroupId, welcome: Welcome) {
         let community_name = comm_grp.community_id();
-        let group_name = comm_grp.community_id();
+        let group_name = comm_grp.group_id();
         if let Entry::Vacant(e) = self
             .community_general_channel_names
             .entry(community_name.to_owned())
@@ -148,34 +237,29 @@ impl ClientDataProvider for ClientData {
     }
 
     fn contains_welcome(&self, comm_grp: &CommGroupId) -> bool {
-        let community_name = comm_grp.community_id();
-        let group_name = comm_grp.community_id();
-        self.pending_welcomes
-            .contains_key(&(community_name, group_name))
+        self.pending_welcome_key(comm_grp).is_some()
     }
 
     fn get_welcome_clone(&self, comm_grp: &CommGroupId) -> Option<Welcome> {
-        let community_name = comm_grp.community_id();
-        let group_name = comm_grp.community_id();
-        self.pending_welcomes
-            .get(&(community_name, group_name))
+        self.pending_welcome_key(comm_grp)
+            .and_then(|key| self.pending_welcomes.get(&key))
             .map(|local_welcome| local_welcome.welcome.clone())
     }
 
     fn remove_welcome(&mut self, comm_grp: &CommGroupId) -> Option<Welcome> {
-        let community_name = comm_grp.community_id();
-        let group_name = comm_grp.community_id();
-        if let Some(local_welcome) = self.pending_welcomes.remove(&(community_name, group_name)) {
-            Some(local_welcome.welcome)
-        } else {
-            None
-        }
+        self.pending_welcome_key(comm_grp)
+            .and_then(|key| self.pending_welcomes.remove(&key))
+            .map(|local_welcome| local_welcome.welcome)
     }
 
     fn get_user_id(&self) -> String {
         self.user_name.to_owned()
     }
 
+    fn now(&self) -> SystemTime {
+        self.clock.now()
+    }
+
     fn get_credential(&self) -> Credential {
         self.credential.clone()
     }
@@ -200,12 +284,16 @@ impl ClientDataProvider for ClientData {
         comm_grp: &CommGroupId,
         invitee_name: &str,
     ) -> Option<KeyPackage> {
-        let local_group_state = self.get_mut_group_state(comm_grp);
-
-        local_group_state
-            .shared
-            .to_add_invitees
-            .remove(invitee_name)
+        let to_add_invitees = &mut self.get_mut_group_state(comm_grp).shared.to_add_invitees;
+        if let Some(key_package) = to_add_invitees.remove(invitee_name) {
+            return Some(key_package);
+        }
+        // Otherwise, one of the devices of the invitee
+        let device_name = to_add_invitees
+            .keys()
+            .find(|device_name| split_device(device_name).0 == invitee_name)?
+            .to_string();
+        to_add_invitees.remove(&device_name)
     }
 
     fn store_to_be_removed_member(&mut self, comm_grp: &CommGroupId, member: &String) {
@@ -232,6 +320,72 @@ impl ClientDataProvider for ClientData {
         }
     }
 
+    fn mark_member_left(&mut self, comm_grp: &CommGroupId, member: &str) {
+        let local_group_state = self.get_mut_group_state(comm_grp);
+        local_group_state
+            .shared
+            .rbac
+            .mark_user_left(member.to_string());
+    }
+
+    fn forget_removed_member(&mut self, comm_grp: &CommGroupId, member: &str) {
+        let local_group_state = self.get_mut_group_state(comm_grp);
+        local_group_state.shared.rbac.forget_user(member);
+    }
+
+    fn ban_user(&mut self, comm_grp: &CommGroupId, user_id: String) {
+        let local_group_state = self.get_mut_group_state(comm_grp);
+        local_group_state.shared.banned_users.insert(user_id);
+    }
+
+    fn unban_user(&mut self, comm_grp: &CommGroupId, user_id: &str) {
+        let local_group_state = self.get_mut_group_state(comm_grp);
+        local_group_state.shared.banned_users.remove(user_id);
+    }
+
+    fn is_banned(&self, comm_grp: &CommGroupId, user_id: &str) -> bool {
+        let local_group_state = self.get_group_state(comm_grp);
+        let banned_users = &local_group_state.shared.banned_users;
+        // Banning a user bans all their devices
+        banned_users.contains(user_id) || banned_users.contains(split_device(user_id).0)
+    }
+
+    fn mute(&mut self, comm_grp: &CommGroupId, user_id: Option<&str>) {
+        self.muted
+            .insert((comm_grp.get_string(), user_id.map(str::to_string)));
+    }
+
+    fn unmute(&mut self, comm_grp: &CommGroupId, user_id: Option<&str>) {
+        self.muted
+            .remove(&(comm_grp.get_string(), user_id.map(str::to_string)));
+    }
+
+    fn is_muted(&self, comm_grp: &CommGroupId, sender: &str) -> bool {
+        let group = comm_grp.get_string();
+        // Muting a user mutes all their devices
+        [None, Some(sender), Some(split_device(sender).0)]
+            .into_iter()
+            .any(|user_id| {
+                self.muted
+                    .contains(&(group.clone(), user_id.map(str::to_string)))
+            })
+    }
+
+    fn get_notification_prefs(&self) -> NotificationPrefs {
+        NotificationPrefs {
+            muted: self.muted.clone(),
+        }
+    }
+
+    fn set_notification_prefs(&mut self, prefs: NotificationPrefs) {
+        self.muted = prefs.muted;
+    }
+
+    fn set_max_group_size(&mut self, comm_grp: &CommGroupId, max_group_size: Option<usize>) {
+        let local_group_state = self.get_mut_group_state(comm_grp);
+        local_group_state.shared.max_group_size = max_group_size;
+    }
+
     fn store_self_sent_msg(
         &mut self,
         comm_grp: &CommGroupId,
@@ -241,31 +395,53 @@ impl ClientDataProvider for ClientData {
         self.store_received_msg_w_counter(comm_grp, sender, private_msg, false)
     }
 
+    fn next_msg_seq(&mut self, comm_grp: &CommGroupId) -> u64 {
+        let local_group_state = self.get_mut_group_state(comm_grp);
+        local_group_state.sent_msg_seq += 1;
+        local_group_state.sent_msg_seq
+    }
+
     fn store_pending_action(&mut self, comm_grp: &CommGroupId, action: ActionMsg) {
-        let value = self
-            .community_group_pending_actions
-            .get_mut(&*comm_grp.community_id())
-            .expect("Community name does not exist")
-            .get_mut(&*comm_grp.group_id())
-            .expect("Group name does not exist");
-        // Make sure that we don't already have a pending action
-        assert!(value.is_none());
-        *value = Some(action);
+        if let Some(pending_actions) = self.get_mut_pending_actions(comm_grp) {
+            pending_actions.push(action);
+        }
     }
 
     fn pop_pending_action(&mut self, comm_grp: &CommGroupId) -> Option<ActionMsg> {
-        let result = self
+        let pending_actions = self.get_mut_pending_actions(comm_grp)?;
+        if pending_actions.is_empty() {
+            None
+        } else {
+            Some(pending_actions.remove(0))
+        }
+    }
+
+    fn pop_pending_actions(&mut self, comm_grp: &CommGroupId) -> Vec<ActionMsg> {
+        self.get_mut_pending_actions(comm_grp)
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    fn is_removal_pending(&self, comm_grp: &CommGroupId, member: &str) -> bool {
+        let kicks = |action: &ActionMsg| matches!(action, ActionMsg::Kick(kick) if kick.target_user_id == member);
+        let awaiting_echo = self
             .community_group_pending_actions
-            .get_mut(&*comm_grp.community_id())
-            .expect("Community name does not exist")
-            .remove(&*comm_grp.group_id())
-            .expect("Group name does not exist");
-        // Replace the removed value with None
-        self.community_group_pending_actions
-            .get_mut(&*comm_grp.community_id())
-            .unwrap()
-            .insert(comm_grp.group_id(), None);
-        result
+            .get(&*comm_grp.community_id())
+            .and_then(|community| community.get(&*comm_grp.group_id()))
+            .map(|pending_actions| pending_actions.iter().any(kicks))
+            .unwrap_or(false);
+        let shared = &self.get_group_state(comm_grp).shared;
+        awaiting_echo
+            || shared
+                .to_be_removed_members
+                .iter()
+                .any(|to_be_removed| to_be_removed == member)
+            || shared
+                .policy_engine
+                .borrow()
+                .proposed_actions
+                .iter()
+                .any(|proposed_action| kicks(&proposed_action.action))
     }
 
     fn store_received_msg(
@@ -297,25 +473,223 @@ impl ClientDataProvider for ClientData {
         &local_group_state.shared.topic
     }
 
+    fn set_group_name_version(&mut self, comm_grp: &CommGroupId, version: (u64, String)) {
+        let local_group_state = self.get_mut_group_state(comm_grp);
+        local_group_state.shared.name_version = version;
+    }
+
+    fn get_group_name_version(&self, comm_grp: &CommGroupId) -> (u64, String) {
+        let local_group_state = self.get_group_state(comm_grp);
+        local_group_state.shared.name_version.clone()
+    }
+
+    fn set_group_topic_version(&mut self, comm_grp: &CommGroupId, version: (u64, String)) {
+        let local_group_state = self.get_mut_group_state(comm_grp);
+        local_group_state.shared.topic_version = version;
+    }
+
+    fn get_group_topic_version(&self, comm_grp: &CommGroupId) -> (u64, String) {
+        let local_group_state = self.get_group_state(comm_grp);
+        local_group_state.shared.topic_version.clone()
+    }
+
+    fn set_enforce_unique_group_names(&mut self, enforce: bool) {
+        self.enforce_unique_group_names = enforce;
+    }
+
+    fn set_default_group_roles(&mut self, creator_role: String, invitee_role: String) {
+        self.default_group_roles = Some((creator_role, invitee_role));
+    }
+
+    fn init_group_roles(
+        &mut self,
+        comm_grp: &CommGroupId,
+        roles: Option<GroupRoleConfig>,
+    ) -> String {
+        let roles = roles.or_else(|| {
+            self.default_group_roles
+                .clone()
+                .map(|(creator_role, invitee_role)| GroupRoleConfig {
+                    creator_role,
+                    invitee_role,
+                })
+        });
+        let rbac = &mut self.get_mut_group_state(comm_grp).shared.rbac;
+        if let Some(roles) = roles {
+            rbac.set_creator_and_invitee_roles(roles.creator_role, roles.invitee_role);
+        }
+        rbac.creator_role.clone()
+    }
+
+    fn get_invitee_role(&self, comm_grp: &CommGroupId) -> String {
+        let local_group_state = self.get_group_state(comm_grp);
+        local_group_state.shared.rbac.invitee_role.clone()
+    }
+
+    fn is_group_name_taken(&self, comm_grp: &CommGroupId, name: &str) -> bool {
+        if !self.enforce_unique_group_names {
+            return false;
+        }
+        let name = name.to_lowercase();
+        self.community_states
+            .get(&comm_grp.community_id())
+            .map_or(false, |community| {
+                community
+                    .group_states
+                    .iter()
+                    .filter(|(group_id, _)| **group_id != comm_grp.group_id())
+                    .any(|(group_id, group_state)| {
+                        let sibling_name = if group_state.shared.name.is_empty() {
+                            group_id
+                        } else {
+                            &group_state.shared.name
+                        };
+                        sibling_name.to_lowercase() == name
+                    })
+            })
+    }
+
+    fn set_sealed_sender(&mut self, sealed_sender: bool) {
+        self.sealed_sender = sealed_sender;
+    }
+
+    fn is_sealed_sender(&self) -> bool {
+        self.sealed_sender
+    }
+
+    fn store_sealed_sent_msg_id(&mut self, client_msg_id: Uuid) {
+        self.sealed_sent_msg_ids.push_back(client_msg_id);
+        if self.sealed_sent_msg_ids.len() > MAX_SEALED_SENT_MSG_IDS {
+            self.sealed_sent_msg_ids.pop_front();
+        }
+    }
+
+    fn pop_sealed_sent_msg_id(&mut self, client_msg_id: &Uuid) -> bool {
+        let n_sent = self.sealed_sent_msg_ids.len();
+        self.sealed_sent_msg_ids
+            .retain(|sent_id| sent_id != client_msg_id);
+        self.sealed_sent_msg_ids.len() < n_sent
+    }
+
+    fn store_report(&mut self, comm_grp: &CommGroupId, report: StoredReport) {
+        let local_group_state = self.get_mut_group_state(comm_grp);
+        local_group_state.shared.reports.push(report);
+    }
+
+    fn get_reports(&self, comm_grp: &CommGroupId) -> Vec<StoredReport> {
+        let local_group_state = self.get_group_state(comm_grp);
+        local_group_state.shared.reports.clone()
+    }
+
+    fn store_flag(&mut self, comm_grp: &CommGroupId, message_id: String, flagger: String) {
+        let local_group_state = self.get_mut_group_state(comm_grp);
+        local_group_state
+            .shared
+            .flags
+            .entry(message_id)
+            .or_default()
+            .insert(flagger);
+    }
+
+    fn get_flags(&self, comm_grp: &CommGroupId) -> BTreeMap<String, BTreeSet<String>> {
+        let local_group_state = self.get_group_state(comm_grp);
+        local_group_state.shared.flags.clone()
+    }
+
+    fn set_flag_takedown_threshold(&mut self, comm_grp: &CommGroupId, threshold: Option<usize>) {
+        let local_group_state = self.get_mut_group_state(comm_grp);
+        local_group_state.shared.flag_takedown_threshold = threshold;
+    }
+
+    fn get_flag_takedown_threshold(&self, comm_grp: &CommGroupId) -> Option<usize> {
+        self.get_group_state(comm_grp)
+            .shared
+            .flag_takedown_threshold
+    }
+
+    fn push_outbox_action(&mut self, comm_grp: &CommGroupId, action: ActionMsg) {
+        self.outbox.push((comm_grp.clone(), action));
+    }
+
+    fn take_outbox_actions(&mut self) -> Vec<(CommGroupId, ActionMsg)> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    fn push_unsent_msg(&mut self, onwire_msg: OnWireMessage) {
+        let client_msg_id = match onwire_msg.sent_group_msg() {
+            Some(group_msg) => group_msg.get_client_msg_id(),
+            None => return,
+        };
+        let recorded = self.unsent_msgs.iter().any(|unsent_msg| {
+            unsent_msg.sent_group_msg().map_or(false, |group_msg| {
+                group_msg.get_client_msg_id() == client_msg_id
+            })
+        });
+        if !recorded && !self.confirmed_msg_ids.contains(&client_msg_id) {
+            self.unsent_msgs.push(onwire_msg);
+        }
+    }
+
+    fn get_unsent_msgs(&self) -> Vec<OnWireMessage> {
+        self.unsent_msgs.clone()
+    }
+
+    fn confirm_sent_msg(&mut self, client_msg_id: Uuid) {
+        let n_unsent = self.unsent_msgs.len();
+        self.unsent_msgs.retain(|unsent_msg| {
+            unsent_msg.sent_group_msg().map_or(true, |group_msg| {
+                group_msg.get_client_msg_id() != client_msg_id
+            })
+        });
+        // Only the messages of this client are remembered
+        if self.unsent_msgs.len() < n_unsent {
+            self.confirmed_msg_ids.push_back(client_msg_id);
+            if self.confirmed_msg_ids.len() > MAX_CONFIRMED_MSG_IDS {
+                self.confirmed_msg_ids.pop_front();
+            }
+        }
+    }
+
     fn def_role(
         &mut self,
         comm_grp: &CommGroupId,
         role_name: String,
         action_types: Vec<ActionType>,
+        inherits_from: Vec<String>,
     ) {
+        let local_group_state = self.get_mut_group_state(comm_grp);
+        if let Err(reason) =
+            local_group_state
+                .shared
+                .rbac
+                .def_role(role_name, action_types, inherits_from)
+        {
+            warn!("Not defining the role: {}", reason);
+        }
+    }
+
+    fn set_user_role(&mut self, comm_grp: &CommGroupId, user_id: String, role_name: String) {
         let local_group_state = self.get_mut_group_state(comm_grp);
         local_group_state
             .shared
             .rbac
-            .def_role(role_name, action_types);
+            .set_user_role(user_id, role_name);
     }
 
-    fn set_user_role(&mut self, comm_grp: &CommGroupId, user_id: String, role_name: String) {
+    fn add_user_role(&mut self, comm_grp: &CommGroupId, user_id: String, role_name: String) {
         let local_group_state = self.get_mut_group_state(comm_grp);
         local_group_state
             .shared
             .rbac
-            .set_user_role(user_id, role_name);
+            .add_user_role(user_id, role_name);
+    }
+
+    fn remove_user_role(&mut self, comm_grp: &CommGroupId, user_id: String, role_name: String) {
+        let local_group_state = self.get_mut_group_state(comm_grp);
+        local_group_state
+            .shared
+            .rbac
+            .remove_user_role(&user_id, &role_name);
     }
 
     fn action_authorized(&mut self, action: &ActionMsg) -> bool {
@@ -336,6 +710,18 @@ impl ClientDataProvider for ClientData {
             .action_authorized(&sender, action)
     }
 
+    fn can_perform(&self, comm_grp: &CommGroupId, action_type: ActionType, actor: &str) -> bool {
+        self.community_states
+            .get(&comm_grp.community_id())
+            .and_then(|community_state| community_state.group_states.get(&comm_grp.group_id()))
+            .map_or(false, |local_group_state| {
+                local_group_state
+                    .shared
+                    .rbac
+                    .action_type_authorized(actor, &action_type)
+            })
+    }
+
     fn get_roles(&self, comm_grp: &CommGroupId) -> RbacState {
         let local_group_state = self.get_group_state(comm_grp);
         local_group_state.shared.rbac.clone()
@@ -346,6 +732,49 @@ impl ClientDataProvider for ClientData {
         user_names_from_mls_group(&local_group_state.mls_state.borrow())
     }
 
+    fn is_member(&self, comm_grp: &CommGroupId, user_name: &str) -> bool {
+        self.get_group_members(comm_grp)
+            .iter()
+            .any(|member| member == user_name)
+    }
+
+    fn member_role(&self, comm_grp: &CommGroupId, user_name: &str) -> Option<String> {
+        let local_group_state = self.get_group_state(comm_grp);
+        local_group_state
+            .shared
+            .rbac
+            .user_to_roles
+            .get(user_name)
+            .map(|roles| roles.iter().cloned().collect::<Vec<_>>().join("+"))
+    }
+
+    fn get_comm_grps(&self) -> Vec<CommGroupId> {
+        self.community_states
+            .iter()
+            .flat_map(|(community_id, community)| {
+                community
+                    .group_states
+                    .keys()
+                    .map(move |group_id| CommGroupId::new(community_id, group_id))
+            })
+            .collect()
+    }
+
+    fn get_group_epochs(&self) -> Vec<(CommGroupId, u64)> {
+        self.get_comm_grps()
+            .into_iter()
+            .map(|comm_grp| {
+                let epoch = self
+                    .get_group_state(&comm_grp)
+                    .mls_state
+                    .borrow()
+                    .epoch()
+                    .as_u64();
+                (comm_grp, epoch)
+            })
+            .collect()
+    }
+
     fn get_keypair(&self) -> &Keypair {
         &self.verif_keypair
     }
@@ -360,6 +789,77 @@ impl ClientDataProvider for ClientData {
         self.credential_entries = credentials;
     }
 
+    fn merge_credential_entries(
+        &mut self,
+        credentials: BTreeMap<String, CredentialEntry>,
+        drop_revoked: bool,
+    ) {
+        let members: BTreeSet<String> = self
+            .get_comm_grps()
+            .iter()
+            .flat_map(|comm_grp| self.get_group_members(comm_grp))
+            .collect();
+        self.credential_entries
+            .retain(|user_name, _| members.contains(user_name));
+        self.credential_entries.extend(credentials);
+        if drop_revoked {
+            self.credential_entries
+                .retain(|user_name, entry| !entry.is_revoked() || members.contains(user_name));
+        }
+    }
+
+    fn is_credential_revoked(&self, user_name: &str) -> bool {
+        self.credential_entries
+            .get(user_name)
+            .map(|entry| entry.is_revoked())
+            .unwrap_or(false)
+    }
+
+    fn get_display_names(&self) -> BTreeMap<String, String> {
+        self.credential_entries
+            .iter()
+            .filter(|(_, entry)| !entry.display_name().is_empty())
+            .map(|(user_name, entry)| (user_name.to_owned(), entry.display_name().to_string()))
+            .collect()
+    }
+
+    fn set_as_public_key(&mut self, public_key: PublicKey) {
+        self.as_public_key = Some(public_key);
+    }
+
+    fn get_as_public_key(&self) -> Option<PublicKey> {
+        self.as_public_key
+    }
+
+    fn set_ds_token(&mut self, token: AuthToken) {
+        self.ds_token = Some(token);
+    }
+
+    fn get_ds_token(&self) -> Option<AuthToken> {
+        self.ds_token.clone()
+    }
+
+    fn set_remaining_key_packages(&mut self, remaining: usize) {
+        self.remaining_key_packages = Some(remaining);
+    }
+
+    fn get_remaining_key_packages(&self) -> Option<usize> {
+        self.remaining_key_packages
+    }
+
+    fn set_relayed_until(&mut self, relayed_until: SystemTime) {
+        if self
+            .relayed_until
+            .map_or(true, |until| until < relayed_until)
+        {
+            self.relayed_until = Some(relayed_until);
+        }
+    }
+
+    fn get_relayed_until(&self) -> Option<SystemTime> {
+        self.relayed_until
+    }
+
     fn to_string(&self) -> String {
         serde_json::to_string(&self).expect("Could not serialize")
     }
@@ -368,6 +868,119 @@ impl ClientDataProvider for ClientData {
         self.policies = policies;
     }
 
+    fn get_client_policies(&self) -> Vec<Box<dyn Policy>> {
+        self.policies
+            .iter()
+            .map(|policy| policy.get_policy_obj())
+            .collect()
+    }
+
+    fn log_policy_action(&mut self, comm_grp: &CommGroupId, action: ActionMsg) {
+        if matches!(&action, ActionMsg::TextMsg(text_msg) if text_msg.expires_after.is_some()) {
+            return;
+        }
+        let policy_action_log = &mut self.get_mut_group_state(comm_grp).policy_action_log;
+        if policy_action_log.len() >= MAX_POLICY_ACTION_LOG_LEN {
+            policy_action_log.pop_front();
+        }
+        policy_action_log.push_back(action);
+    }
+
+    fn unlog_policy_actions(&mut self, comm_grp: &CommGroupId, action_ids: &[String]) {
+        if action_ids.is_empty() {
+            return;
+        }
+        self.get_mut_group_state(comm_grp)
+            .policy_action_log
+            .retain(|action| !action_ids.contains(&action.get_metadata().action_id));
+    }
+
+    fn get_policy_action_log(&self, comm_grp: &CommGroupId) -> Vec<ActionMsg> {
+        self.get_group_state(comm_grp)
+            .policy_action_log
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    fn record_queued_poll_closes(
+        &mut self,
+        comm_grp: &CommGroupId,
+        action_ids: &[String],
+    ) -> Vec<String> {
+        let queued_poll_closes = &mut self.get_mut_group_state(comm_grp).queued_poll_closes;
+        let unqueued = action_ids
+            .iter()
+            .filter(|action_id| !queued_poll_closes.contains(*action_id))
+            .cloned()
+            .collect();
+        *queued_poll_closes = action_ids.iter().cloned().collect();
+        unqueued
+    }
+
+    fn store_gov_state_snapshot(&mut self, comm_grp: &CommGroupId) {
+        let local_group_state = self.get_mut_group_state(comm_grp);
+        let epoch = local_group_state.mls_state.borrow().epoch().as_u64();
+        let snapshot = local_group_state.shared.clone();
+        let snapshots = &mut local_group_state.gov_state_snapshots;
+        if matches!(snapshots.back(), Some((last_epoch, _)) if *last_epoch == epoch) {
+            snapshots.pop_back();
+        }
+        snapshots.push_back((epoch, snapshot));
+        while snapshots.len() > MAX_GOV_STATE_SNAPSHOTS {
+            snapshots.pop_front();
+        }
+    }
+
+    fn get_gov_state_snapshot(
+        &self,
+        comm_grp: &CommGroupId,
+        epoch: u64,
+    ) -> Option<SharedGroupState> {
+        self.get_group_state(comm_grp)
+            .gov_state_snapshots
+            .iter()
+            .find(|(snapshot_epoch, _)| *snapshot_epoch == epoch)
+            .map(|(_, snapshot)| snapshot.clone())
+    }
+
+    fn get_received_gov_state(&self, comm_grp: &CommGroupId) -> Option<SharedGroupState> {
+        let local_group_state = self.get_group_state(comm_grp);
+        // The creator did not receive the group state
+        if local_group_state.gov_state_init_hash.unwrap_or(0) == 0 {
+            return None;
+        }
+        // The oldest snapshot was taken on receipt, unless more snapshots
+        // than kept were taken since
+        let snapshots = &local_group_state.gov_state_snapshots;
+        if snapshots.len() >= MAX_GOV_STATE_SNAPSHOTS {
+            return None;
+        }
+        snapshots.front().map(|(_, snapshot)| snapshot.clone())
+    }
+
+    fn register_custom_action_handler(
+        &mut self,
+        kind: String,
+        handler: Box<dyn CustomActionHandler>,
+    ) {
+        self.custom_action_handlers.insert(kind, handler);
+    }
+
+    fn dispatch_custom_action(&mut self, action: &CustomAction) -> bool {
+        if let Some((kind, payload)) = action.kind_and_payload() {
+            // Taken out of the registry while it runs, as it may modify `self`
+            if let Some(handler) = self.custom_action_handlers.remove(kind) {
+                handler.handle(payload, &action.metadata, self);
+                self.custom_action_handlers
+                    .entry(kind.to_string())
+                    .or_insert(handler);
+                return true;
+            }
+        }
+        false
+    }
+
     fn as_any(&self) -> &dyn std::any::Any {
         self
     }
@@ -480,6 +1093,8 @@ impl ClientData {
         if self.skip_updating_msg_history {
             return;
         }
+        // Kept in the history, but not counted as unread
+        let increase_counter = increase_counter && !self.is_muted(comm_grp, sender);
         let local_group_state = self
             .community_states
             .get_mut(&*comm_grp.community_id())
@@ -487,18 +1102,166 @@ impl ClientData {
             .group_states
             .get_mut(&*comm_grp.group_id())
             .unwrap();
-        local_group_state.history.insert(
-            0,
-            LocalHistoryMessage::new_and_timestamp(private_msg, &sender.to_string()),
+        let mut history_msg = LocalHistoryMessage::new_and_timestamp(
+            private_msg,
+            &sender.to_string(),
+            self.clock.now(),
         );
+        let position = match private_msg.seq {
+            0 => 0,
+            seq => local_group_state.track_msg_seq(sender, seq),
+        };
+        if position > 0 {
+            // Keeps the history ordered by receipt time for `get_history_since`
+            history_msg.received_timestamp =
+                local_group_state.history[position - 1].received_timestamp;
+        }
+        local_group_state.history.insert(position, history_msg);
         if increase_counter {
             local_group_state.unread_msgs_count += 1;
         }
     }
 
+    /// Returns the messages of the group received at or after `since`, newest
+    /// first, except the expired ones. As the history is ordered from new to
+    /// old, this binary searches for the oldest such message rather than going
+    /// through the whole history.
+    pub fn get_history_since(
+        &self,
+        comm_grp: &CommGroupId,
+        since: SystemTime,
+    ) -> Vec<&LocalHistoryMessage> {
+        let history = &self.get_group_state(comm_grp).history;
+        let n_since = history.partition_point(|msg| msg.received_timestamp >= since);
+        let now = self.clock.now();
+        history[..n_since]
+            .iter()
+            .filter(|msg| !msg.is_expired(now))
+            .collect()
+    }
+
+    /// Returns the messages of the thread of `message_id`, newest first,
+    /// except the expired ones. The thread is made of the message the reply
+    /// chain of `message_id` starts from and of all the messages replying to
+    /// it, directly or not. Empty if `message_id` is not in the history.
+    pub fn get_thread(
+        &self,
+        comm_grp: &CommGroupId,
+        message_id: &str,
+    ) -> Vec<&LocalHistoryMessage> {
+        let history = &self.get_group_state(comm_grp).history;
+        let find = |id: &str| {
+            history
+                .iter()
+                .find(|msg| msg.message_id().as_deref() == Some(id))
+        };
+        if find(message_id).is_none() {
+            return vec![];
+        }
+        // Go up the reply chain to the message it starts from
+        let mut root_id = message_id.to_string();
+        let mut visited = BTreeSet::from([root_id.clone()]);
+        while let Some(parent_id) = find(&root_id).and_then(|msg| msg.in_reply_to.clone()) {
+            if find(&parent_id).is_none() || !visited.insert(parent_id.clone()) {
+                break;
+            }
+            root_id = parent_id;
+        }
+
+        // Replies are received after the messages they reply to, so going
+        // from old to new finds the replies to replies as well
+        let mut thread_ids = BTreeSet::from([root_id]);
+        let mut thread = vec![];
+        for msg in history.iter().rev() {
+            let msg_id = msg.message_id().unwrap_or_default();
+            let in_thread = thread_ids.contains(&msg_id)
+                || msg
+                    .in_reply_to
+                    .as_ref()
+                    .map_or(false, |parent_id| thread_ids.contains(parent_id));
+            if in_thread {
+                thread_ids.insert(msg_id);
+                thread.push(msg);
+            }
+        }
+        let now = self.clock.now();
+        thread.reverse();
+        thread.retain(|msg| !msg.is_expired(now));
+        thread
+    }
+
+    /// Writes the message history of the group to `out_path` as a
+    /// [HistoryExport], from old to new. Each message is chained to the one
+    /// before by a hash, and the last hash is signed with this client's
+    /// keypair, see [crate::client_api::verify_history_export]
+    pub fn export_history(
+        &self,
+        comm_grp: &CommGroupId,
+        out_path: impl AsRef<Path>,
+    ) -> io::Result<()> {
+        let mut entries = vec![];
+        let mut prev_hash = String::new();
+        for history_msg in self.get_group_state(comm_grp).history.iter().rev() {
+            let message = serde_json::to_value(history_msg)?;
+            let hash = HistoryExportEntry::chain_hash(&prev_hash, &message);
+            entries.push(HistoryExportEntry {
+                message,
+                hash: hash.clone(),
+            });
+            prev_hash = hash;
+        }
+        let export = HistoryExport {
+            comm_grp: comm_grp.clone(),
+            exporter: self.user_name.clone(),
+            verification_key: self.verif_keypair.public,
+            entries,
+            signature: self.verif_keypair.sign(prev_hash.as_bytes()),
+        };
+        fs::write(out_path, serde_json::to_string_pretty(&export)?)
+    }
+
+    /// Removes the disappearing messages of the group that expired at `now`
+    /// from the history. Returns how many were removed
+    pub fn purge_expired_msgs(&mut self, comm_grp: &CommGroupId, now: SystemTime) -> usize {
+        let user_id = self.user_name.clone();
+        let n_purged = self
+            .get_mut_group_state(comm_grp)
+            .purge_expired_msgs(&user_id, now);
+        if n_purged > 0 {
+            debug!("Purged {} expired messages", n_purged);
+        }
+        n_purged
+    }
+
+    /// The key of the welcome pending for `comm_grp`, if any.
+    /// Welcomes used to be stored under `(community_id, community_id)`, so
+    /// such an entry of a previously persisted state is used as a fallback.
+    fn pending_welcome_key(&self, comm_grp: &CommGroupId) -> Option<(String, String)> {
+        let community_name = comm_grp.community_id();
+        [
+            (community_name.clone(), comm_grp.group_id()),
+            (community_name.clone(), community_name),
+        ]
+        .into_iter()
+        .find(|key| self.pending_welcomes.contains_key(key))
+    }
+
+    /// The actions pending for `comm_grp`, if the group was not removed
+    fn get_mut_pending_actions(&mut self, comm_grp: &CommGroupId) -> Option<&mut Vec<ActionMsg>> {
+        let pending_actions = self
+            .community_group_pending_actions
+            .get_mut(&*comm_grp.community_id())
+            .and_then(|community| community.get_mut(&*comm_grp.group_id()));
+        if pending_actions.is_none() {
+            debug!("No pending actions of group {:?}, which is gone", comm_grp);
+        }
+        pending_actions
+    }
+
     pub fn new(name: String, credential: Credential, verif_keypair: Keypair) -> Self {
         assert_eq!(name, identity_to_str(credential.identity()).unwrap());
         ClientData {
+            schema_version: Self::SCHEMA_VERSION,
             user_name: name,
             credential,
             verif_keypair,
@@ -509,6 +1272,99 @@ impl ClientData {
             community_group_pending_actions: BTreeMap::new(),
             policies: Vec::new(),
             skip_updating_msg_history: false,
+            remaining_key_packages: None,
+            enforce_unique_group_names: false,
+            default_group_roles: None,
+            as_public_key: None,
+            ds_token: None,
+            relayed_until: None,
+            sealed_sender: false,
+            sealed_sent_msg_ids: VecDeque::new(),
+            branched_state: false,
+            outbox: Vec::new(),
+            unsent_msgs: Vec::new(),
+            confirmed_msg_ids: VecDeque::new(),
+            muted: BTreeSet::new(),
+            custom_action_handlers: BTreeMap::new(),
+            clock: SharedClock::default(),
+        }
+    }
+
+    /// Sets the clock timestamps are read from, e.g., a mock one in tests
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Decodes client data encoded with [ClientDataProvider::to_string],
+    /// migrating it to the current layout if stored with an earlier one
+    pub fn from_versioned_str(encoded: &str) -> Result<Self, StateStoreError> {
+        let value: Value =
+            serde_json::from_str(encoded).map_err(|e| StateStoreError::Decode(e.to_string()))?;
+        decode_versioned(value)
+    }
+}
+
+impl VersionedState for ClientData {
+    /// 2: users may hold several roles, stored under `user_to_roles`, and
+    /// several pending actions per group
+    const SCHEMA_VERSION: u32 = 2;
+
+    fn migrate(from_version: u32, value: &mut Value) -> Result<(), StateStoreError> {
+        match from_version {
+            1 => {
+                single_roles_to_role_sets(value);
+                single_pending_actions_to_lists(value);
+                Ok(())
+            }
+            _ => Err(StateStoreError::UnsupportedVersion {
+                found: from_version,
+                supported: Self::SCHEMA_VERSION,
+            }),
+        }
+    }
+}
+
+/// Replaces the single role of each user under `user_to_role`, as stored in
+/// the group states of layout 1, with a set of roles under `user_to_roles`
+fn single_roles_to_role_sets(value: &mut Value) {
+    match value {
+        Value::Mapping(fields) => {
+            if let Some(Value::Mapping(user_to_role)) = fields.remove(&Value::from("user_to_role"))
+            {
+                let user_to_roles: Mapping = user_to_role
+                    .into_iter()
+                    .map(|(user, role)| match role {
+                        Value::String(_) => (user, Value::Sequence(vec![role])),
+                        roles => (user, roles),
+                    })
+                    .collect();
+                fields.insert(Value::from("user_to_roles"), Value::Mapping(user_to_roles));
+            }
+            for (_, field) in fields.iter_mut() {
+                single_roles_to_role_sets(field);
+            }
+        }
+        Value::Sequence(values) => values.iter_mut().for_each(single_roles_to_role_sets),
+        _ => {}
+    }
+}
+
+/// Replaces the optional pending action of each group under
+/// `community_group_pending_actions`, as stored by layout 1, with a list of
+/// pending actions. States stored with lists already are kept as they are
+fn single_pending_actions_to_lists(value: &mut Value) {
+    if let Some(Value::Mapping(communities)) = value.get_mut("community_group_pending_actions") {
+        for (_, groups) in communities.iter_mut() {
+            if let Value::Mapping(groups) = groups {
+                for (_, pending_actions) in groups.iter_mut() {
+                    *pending_actions = match std::mem::replace(pending_actions, Value::Null) {
+                        Value::Null => Value::Sequence(vec![]),
+                        Value::Sequence(actions) => Value::Sequence(actions),
+                        action => Value::Sequence(vec![action]),
+                    };
+                }
+            }
         }
     }
 }
@@ -542,6 +1398,34 @@ pub struct LocalGroupState {
 
     /// Initial hash of the governance state received. 0 if user is the creator of the group.
     pub gov_state_init_hash: Option<u64>,
+
+    /// Audit log of the latest [MAX_POLICY_ACTION_LOG_LEN] actions evaluated
+    /// by the policy engine and not rejected by it, oldest first
+    #[serde(default)]
+    pub policy_action_log: VecDeque<ActionMsg>,
+
+    /// Snapshots of the group state, keyed by the MLS epoch they were taken
+    /// at, oldest first. They only cover the group state, not the MLS group
+    #[serde(default)]
+    pub gov_state_snapshots: VecDeque<(u64, SharedGroupState)>,
+
+    /// The sequence number of the last message sent to the group
+    #[serde(default)]
+    pub sent_msg_seq: u64,
+
+    /// The highest sequence number received from each sender
+    #[serde(default)]
+    pub received_msg_seqs: BTreeMap<String, u64>,
+
+    /// The sequence numbers skipped by each sender, i.e., of the messages
+    /// not received yet while later ones were
+    #[serde(default)]
+    pub msg_seq_gaps: BTreeMap<String, BTreeSet<u64>>,
+
+    /// The ids of the actions this client proposed whose vote ended, and
+    /// for which it queued a close of the vote already
+    #[serde(default)]
+    pub queued_poll_closes: BTreeSet<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -549,6 +1433,45 @@ pub struct LocalHistoryMessage {
     pub message: UnorderedPrivateMessage,
     pub sender: String,
     pub received_timestamp: SystemTime,
+    /// When the message disappears from the history, for text messages sent
+    /// with an `expires_after`
+    #[serde(default)]
+    pub expires_at: Option<SystemTime>,
+    /// The action id of the message this text message replies to, if any
+    #[serde(default)]
+    pub in_reply_to: Option<String>,
+}
+
+/// The message history of a group, written by [ClientData::export_history]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoryExport {
+    pub comm_grp: CommGroupId,
+    /// The user whose client exported the history
+    pub exporter: String,
+    /// The key the signature verifies against, to be compared with the one
+    /// the AS holds for the exporter
+    pub verification_key: PublicKey,
+    /// The messages of the history, from old to new
+    pub entries: Vec<HistoryExportEntry>,
+    /// The signature of the exporter on the hash of the last entry
+    pub signature: Signature,
+}
+
+/// A [LocalHistoryMessage] within a [HistoryExport]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoryExportEntry {
+    pub message: serde_json::Value,
+    /// The hash of the previous entry's hash followed by this message, hex
+    /// encoded. The previous hash of the first entry is empty
+    pub hash: String,
+}
+
+impl HistoryExportEntry {
+    pub(crate) fn chain_hash(prev_hash: &str, message: &serde_json::Value) -> String {
+        let mut bytes = prev_hash.as_bytes().to_vec();
+        bytes.extend(serde_json::to_vec(message).expect("Cannot serialize history message"));
+        hex_sha256(&bytes)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -559,13 +1482,45 @@ pub struct LocalWelcome {
 }
 
 impl LocalHistoryMessage {
-    pub fn new_and_timestamp(message: &UnorderedPrivateMessage, sender: &String) -> Self {
+    pub fn new_and_timestamp(
+        message: &UnorderedPrivateMessage,
+        sender: &String,
+        received_timestamp: SystemTime,
+    ) -> Self {
+        let (expires_after, in_reply_to) = match &message.content {
+            UnorderedMsgContent::TextAction { text_action } => match &text_action.action {
+                ActionMsg::TextMsg(text_msg_action) => (
+                    text_msg_action.expires_after,
+                    text_msg_action.in_reply_to.clone(),
+                ),
+                _ => (None, None),
+            },
+            _ => (None, None),
+        };
         Self {
             message: message.to_owned(),
             sender: sender.to_owned(),
-            received_timestamp: SystemTime::now(),
+            received_timestamp,
+            expires_at: expires_after.map(|expires_after| received_timestamp + expires_after),
+            in_reply_to,
+        }
+    }
+
+    /// The action id of the message, the one replies and takedowns refer
+    /// to, for messages sent as actions
+    pub fn message_id(&self) -> Option<String> {
+        match &self.message.content {
+            UnorderedMsgContent::TextAction { text_action } => {
+                Some(text_action.action.get_metadata().action_id)
+            }
+            _ => None,
         }
     }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at
+            .map_or(false, |expires_at| expires_at <= now)
+    }
 }
 
 impl LocalGroupState {
@@ -584,17 +1539,94 @@ impl LocalGroupState {
             shared: SharedGroupState {
                 name: "".to_string(),
                 topic: "".to_string(),
+                name_version: (0, "".to_string()),
+                topic_version: (0, "".to_string()),
                 rbac: RbacState::new(),
                 governance_state: BTreeMap::new(),
                 to_add_invitees: BTreeMap::new(),
                 policy_engine: Rc::new(RefCell::new(PolicyEngine::new(policy_refs))),
                 to_be_removed_members: vec![],
+                banned_users: BTreeSet::new(),
+                reports: vec![],
+                flags: BTreeMap::new(),
+                max_group_size: None,
+                flag_takedown_threshold: None,
             },
             history: vec![],
             unprocessed_messages: vec![],
             unread_msgs_count: 0,
             proposed_actions: vec![],
             gov_state_init_hash,
+            policy_action_log: VecDeque::new(),
+            gov_state_snapshots: VecDeque::new(),
+            sent_msg_seq: 0,
+            received_msg_seqs: BTreeMap::new(),
+            msg_seq_gaps: BTreeMap::new(),
+            queued_poll_closes: BTreeSet::new(),
         }
     }
+
+    /// Removes the messages of the history that expired at `now`, and no
+    /// longer counts those that were unread. Returns how many were removed
+    fn purge_expired_msgs(&mut self, user_id: &str, now: SystemTime) -> usize {
+        let mut remaining_unread = self.unread_msgs_count;
+        let mut n_purged_unread = 0;
+        let n_msgs = self.history.len();
+        // The unread messages are the newest ones, at the start of the history
+        self.history.retain(|msg| {
+            let unread = remaining_unread > 0 && msg.sender != user_id;
+            if unread {
+                remaining_unread -= 1;
+            }
+            let expired = msg.is_expired(now);
+            if expired && unread {
+                n_purged_unread += 1;
+            }
+            !expired
+        });
+        self.unread_msgs_count -= n_purged_unread;
+        n_msgs - self.history.len()
+    }
+
+    /// Records the receipt of the message `seq` of `sender`, flagging the
+    /// sequence numbers it skips, and returns where to insert the message in
+    /// the history for the messages of `sender` to stay in sequence order
+    fn track_msg_seq(&mut self, sender: &str, seq: u64) -> usize {
+        let highest = match self.received_msg_seqs.get_mut(sender) {
+            Some(highest) => highest,
+            None => {
+                // The sender may have sent messages before this client joined
+                self.received_msg_seqs.insert(sender.to_string(), seq);
+                return 0;
+            }
+        };
+        if seq > *highest {
+            if seq > *highest + 1 {
+                warn!(
+                    "Messages {} to {} of {} are missing",
+                    *highest + 1,
+                    seq - 1,
+                    sender
+                );
+                self.msg_seq_gaps
+                    .entry(sender.to_string())
+                    .or_default()
+                    .extend(*highest + 1..seq);
+            }
+            *highest = seq;
+            return 0;
+        }
+
+        if let Some(gaps) = self.msg_seq_gaps.get_mut(sender) {
+            gaps.remove(&seq);
+            if gaps.is_empty() {
+                self.msg_seq_gaps.remove(sender);
+            }
+        }
+        // Right after the oldest of the later messages of the sender
+        self.history
+            .iter()
+            .rposition(|msg| msg.sender == sender && msg.message.seq > seq)
+            .map_or(0, |i| i + 1)
+    }
 }
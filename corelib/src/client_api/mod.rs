@@ -1,45 +1,65 @@
 //! An un-networked module to creating requests in forms of OnWireMessages  while producing all
 //! related side effects
 
-use ed25519_dalek::PublicKey;
+use ed25519_dalek::{Keypair, PublicKey, Signature, Signer, Verifier};
 use log::*;
 use openmls::framing::{MlsMessageIn, MlsMessageOut, ProcessedMessage};
 use openmls::group::{GroupId, MlsGroup, MlsGroupConfig};
 use openmls::key_packages::KeyPackage;
-use openmls::prelude::{Credential, StagedCommit};
-use std::collections::hash_map::DefaultHasher;
+use openmls::prelude::{Credential, Proposal, QueuedProposal, StagedCommit};
+use openmls_traits::crypto::OpenMlsCrypto;
+use openmls_traits::random::OpenMlsRand;
+use openmls_traits::types::AeadType;
+use openmls_traits::OpenMlsCryptoProvider;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
 use std::fmt::Debug;
-use std::hash::Hash;
-use std::hash::Hasher;
+use std::fs;
+use std::io;
 use std::ops::DerefMut;
+use std::path::Path;
+use std::rc::Rc;
 use std::str::from_utf8;
-use std::time::{Instant, SystemTime};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
 use crate::client_api::actions::{
-    AcceptAction, Action, ActionMsg, DeclineAction, InviteAction, KickAction, LeaveAction,
-    SetUserRoleAction,
+    AcceptAction, Action, ActionMsg, AddUserRoleAction, AdminRevertAction, DeclineAction,
+    InviteAction, KickAction, LeaveAction, RemoveUserRoleAction, SetUserRoleAction,
+    SetUserRolesBulkAction,
 };
 use crate::client_api::client_crypto_impl::CryptoBackend;
-use crate::client_api::client_err::ClientError;
 use crate::client_api::client_err::ClientError::*;
+use crate::client_api::client_err::{ClientError, HistoryExportError};
 use crate::client_api::client_struct::ClientParsedMsg::NewMsg;
-use crate::client_api::client_struct::{ClientDataProvider, ClientParsedMsg};
+use crate::client_api::client_struct::{
+    ClientDataProvider, ClientEventSink, ClientParsedMsg, GroupInfo, GroupRoleConfig,
+    IntegrityDiscrepancy, NotificationPrefs, SharedGroupState, StoredReport,
+};
+use crate::client_api::client_struct_impl::{ClientData, HistoryExport, HistoryExportEntry};
 use crate::messages::{
-    ordered_deserialize, ordered_serialize, GroupMessage, OnWireMessage, OrderedMsgContent,
-    OrderedPrivateMessage, UnorderedMsgContent, UnorderedPrivateMessage,
+    device_endorsement_content, encode_to_bytes, ordered_deserialize, ordered_serialize,
+    prefs_request_content, rotate_key_request_content, AuthToken, DeviceEndorsement, EphemeralKind,
+    GroupMessage, OnWireMessage, OnWireMessageWithMetaData, OrderedMsgContent,
+    OrderedPrivateMessage, PendingCounts, UnorderedMsgContent, UnorderedPrivateMessage,
 };
-use crate::policyengine::ClientRef;
+use crate::policyengine::{ClientRef, PolicyDecision, PolicyEngine};
+use crate::servers_api::mls_helpers::user_names_from_mls_group;
 use crate::TimerType::{
     MlsGovPolicyEngineCheck, MlsGovRBACCheck, OpenMlsGroupOperation, OpenMlsMsgGeneration,
-    OpenMlsMsgVerifyDecryption, ParseIncomingSingleMsgNonKpFetch, SyncGeneration,
+    OpenMlsMsgVerifyDecryption, ParseIncomingMsgsKeyPackage, ParseIncomingSingleMsgNonKpFetch,
+    SyncGeneration,
+};
+use crate::{
+    credential_fingerprint, get_key_package_ref_identity, get_member_hash_ref, identity_to_str,
+    split_device, str_to_identity,
 };
-use crate::{get_key_package_ref_identity, get_member_hash_ref, identity_to_str, str_to_identity};
-use crate::{CommGroupId, SingleTimeMeasurement};
+use crate::{without_timers, CommGroupId, IdError, SingleTimeMeasurement, DM_COMMUNITY_ID};
 
 use self::actions::{
-    ActionMetadata, ActionType, GovStateAnnouncementAction, TextMsgAction, VerifiableAction,
-    VoteAction,
+    ActionMetadata, ActionType, ClosePollAction, FlagMessageAction, GovStateAnnouncementAction,
+    GovStateHashAction, MuteAction, RenameGroupAction, TakedownTextMsgAction, TextMsgAction,
+    UnmuteAction, VerifiableAction, VoteAction,
 };
 
 pub mod actions;
@@ -62,9 +82,21 @@ pub use baseline::{
 
 #[cfg(test)]
 mod action_tests;
-mod client_err;
+#[cfg(test)]
+mod client_api_tests;
+pub mod client_err;
 pub mod client_struct_impl;
 
+/// How long before it expires a token is renewed by [fresh_ds_token], so that
+/// it does not expire while the requests it is sent with are on their way
+pub const TOKEN_RENEWAL_MARGIN: Duration = Duration::from_secs(60);
+
+/// The length of the nonce the encrypted preferences of a user start with
+const PREFS_NONCE_LEN: usize = 12;
+/// Prepended to the secret [prefs_key] derives a key from, so that the key
+/// differs from other hashes of the same secret
+const PREFS_KEY_CONTEXT: &[u8] = b"MLSGov notification preferences:";
+
 /// Broadcast an action to all members of a group, with authorization and action storing
 /// Client should use this function to send an action (msg)
 /// ActionMsg-> Bytes -> MlsMessageOut -> GroupMessage -> OnWireMessage Vec
@@ -96,7 +128,8 @@ pub fn check_action_msg_and_get_mls(
             content: UnorderedMsgContent::TextAction {
                 text_action: verif_action,
             },
-            sender_timestamp: SystemTime::now(),
+            sender_timestamp: client_data.now(),
+            seq: client_data.next_msg_seq(comm_grp),
         };
         let encoded = private_message.to_bytes();
 
@@ -117,6 +150,7 @@ pub fn check_action_msg_and_get_mls(
             user_msg: private_msg,
             recipients,
             identifier: Some(comm_grp.get_string()),
+            ephemeral: None,
         }
         .to_vec()
     }
@@ -139,6 +173,7 @@ pub fn propose_vote(
             user_name,
             Uuid::new_v4().to_string(),
             comm_group_id.to_owned(),
+            client_data.now(),
         ),
     });
     let verif_action = VerifiableAction::new(action, client_data.get_keypair());
@@ -149,7 +184,8 @@ pub fn propose_vote(
         content: UnorderedMsgContent::ProposedAction {
             proposed_action: verif_action,
         },
-        sender_timestamp: SystemTime::now(),
+        sender_timestamp: client_data.now(),
+        seq: 0,
     };
     let encoded = private_message.to_bytes();
     let recipients = client_data.get_group_members(comm_group_id);
@@ -166,6 +202,49 @@ pub fn propose_vote(
         user_msg: private_msg,
         recipients,
         identifier: Some(comm_group_id.get_string()),
+        ephemeral: None,
+    }
+    .to_vec()
+}
+
+/// Send a short-lived event of `kind` (e.g. a typing indicator) to the other
+/// members of a group. The DS only delivers the latest such event per sender
+/// and kind, and receivers do not keep it in their history.
+pub fn ephemeral_msg(
+    comm_grp: &CommGroupId,
+    kind: EphemeralKind,
+    content: &str,
+    backend: &mut CryptoBackend,
+    client_data: &mut (impl ClientDataProvider + ?Sized),
+) -> Vec<OnWireMessage> {
+    let user_name = client_data.get_user_id();
+    let private_message = UnorderedPrivateMessage {
+        sender: user_name.clone(),
+        content: UnorderedMsgContent::Ephemeral {
+            kind,
+            content: content.to_owned(),
+        },
+        sender_timestamp: client_data.now(),
+        seq: 0,
+    };
+    let recipients = client_data
+        .get_group_members(comm_grp)
+        .into_iter()
+        .filter(|member| member != &user_name)
+        .collect();
+    let private_msg = bytes_to_group_message(
+        &user_name,
+        comm_grp,
+        private_message.to_bytes(),
+        false,
+        backend,
+        client_data,
+    );
+    OnWireMessage::UserStandardSend {
+        user_msg: private_msg,
+        recipients,
+        identifier: None,
+        ephemeral: Some(kind),
     }
     .to_vec()
 }
@@ -192,6 +271,37 @@ pub fn commit_proposed_votes(
     group_msg_to_ord_onwire_broadcast(group_msg, comm_group_id, client_data).to_vec()
 }
 
+/// Sign each of `actions` and broadcast them together in a single commit,
+/// so that they all take effect within the same epoch change
+#[cfg(feature = "gov")]
+pub fn commit_actions(
+    comm_grp: &CommGroupId,
+    actions: Vec<ActionMsg>,
+    backend: &mut CryptoBackend,
+    client_data: &mut (impl ClientDataProvider + ?Sized),
+) -> Vec<OnWireMessage> {
+    if actions.is_empty() {
+        return vec![];
+    }
+    let mut verif_actions = vec![];
+    for action in actions {
+        client_data.store_pending_action(comm_grp, action.clone());
+        verif_actions.push(VerifiableAction::new(action, client_data.get_keypair()));
+    }
+    let ord_priv_msg = OrderedPrivateMessage {
+        content: OrderedMsgContent::ActionBatch(verif_actions),
+    };
+    let group_msg = bytes_to_group_message(
+        &client_data.get_user_id(),
+        comm_grp,
+        ordered_serialize(&ord_priv_msg),
+        true,
+        backend,
+        client_data,
+    );
+    group_msg_to_ord_onwire_broadcast(group_msg, comm_grp, client_data).to_vec()
+}
+
 /// ActionMsg -> VerifiableAction -> Bytes -> MlsMessageOut -> GroupMessage
 #[cfg(feature = "gov")]
 pub(crate) fn action_msg_to_group_msg_unchecked(
@@ -242,12 +352,20 @@ pub(crate) fn action_msg_vec_to_group_msg_unchecked(
 /// Generate a an ordered `OnWireMessage` intending for all members of the group specified from group msg
 /// GroupMessage -> OnWireMessage
 fn group_msg_to_ord_onwire_broadcast(
-    group_msg: GroupMessage,
+    mut group_msg: GroupMessage,
     comm_grp: &CommGroupId,
-    client_data: &(impl ClientDataProvider + ?Sized),
+    client_data: &mut (impl ClientDataProvider + ?Sized),
 ) -> OnWireMessage {
+    let mut user_name = Some(client_data.get_user_id());
+    if client_data.is_sealed_sender() {
+        // Attribution relies on the signature within the MLS message instead.
+        // Remember the message to recognize it when a sync relays it back
+        client_data.store_sealed_sent_msg_id(group_msg.get_client_msg_id());
+        group_msg = group_msg.without_sender();
+        user_name = None;
+    }
     OnWireMessage::UserReliableSend {
-        user_name: client_data.get_user_id(),
+        user_name,
         user_msg: group_msg,
         //  recipients does include the sender, so and when the sender receives its own message
         // it pops from the community group locally. This way gives clients a chance to give up their
@@ -299,6 +417,7 @@ fn bytes_to_group_message(
     SingleTimeMeasurement::new(OpenMlsMsgGeneration, timestamp.elapsed());
 
     GroupMessage::from_mls(mls_out, comm_grp.clone(), Some(user_name.to_owned()))
+        .with_tag(client_data.get_keypair())
 }
 
 fn get_default_group_config() -> MlsGroupConfig {
@@ -318,17 +437,117 @@ pub fn sync_msg(
     let result = OnWireMessage::UserSync {
         user_name: config.get_user_id(),
         new_key_packages,
+        known_epochs: config.get_group_epochs(),
+        acked_until: Some(config.get_relayed_until().unwrap_or(UNIX_EPOCH)),
     };
     let _ = SingleTimeMeasurement::new(SyncGeneration, before_send.elapsed());
     result
 }
 
+/// Generates a request for how many messages the DS holds for the client,
+/// without retrieving them
+pub fn poll_msg(config: &(impl ClientDataProvider + ?Sized)) -> OnWireMessage {
+    OnWireMessage::UserPoll {
+        user_name: config.get_user_id(),
+    }
+}
+
+/// Asks the DS how many messages it holds for the client. `None` if it did
+/// not answer with counts
+pub fn poll_pending(
+    config: &(impl ClientDataProvider + ?Sized),
+    ds_transport: &mut impl OnWireTransport,
+) -> Option<PendingCounts> {
+    ds_transport
+        .request(poll_msg(config))
+        .into_iter()
+        .find_map(|msg| match msg {
+            OnWireMessage::DSPendingCounts { counts } => Some(counts),
+            _ => None,
+        })
+}
+
+/// Syncs with the DS only if [poll_pending] reports anything to retrieve,
+/// so that interactive clients may call it often. Returns the parsed
+/// messages of the sync, if any
+pub fn try_sync(
+    client_data: &mut ClientRef,
+    backend: &mut CryptoBackend,
+    ds_transport: &mut impl OnWireTransport,
+) -> Vec<ClientParsedMsg> {
+    if poll_pending(&**client_data, ds_transport).map_or(false, |counts| counts.is_empty()) {
+        return vec![];
+    }
+    let msgs = ds_transport.request(sync_msg(&**client_data, vec![]));
+    parse_incoming_onwire_msgs(msgs, client_data, backend)
+}
+
+/// Returns how many new key packages should accompany the next sync so that
+/// the DS holds `target` of them, based on the count reported by the last sync.
+/// Falls back to the full `target` if no count was reported yet.
+pub fn key_packages_to_replenish(
+    config: &(impl ClientDataProvider + ?Sized),
+    target: usize,
+) -> usize {
+    target.saturating_sub(config.get_remaining_key_packages().unwrap_or(0))
+}
+
+/// Creates a group, failing if its community or group ID is empty or not
+/// URL-safe
 pub fn create_group_msg(
     user_name: &String,
     comm_grp: &CommGroupId,
     backend: &mut CryptoBackend,
     config: &mut (impl ClientDataProvider + ?Sized),
+) -> Result<Vec<OnWireMessage>, IdError> {
+    create_group_checked(user_name, comm_grp, None, backend, config)
+}
+
+/// Like [create_group_msg], but gives the creator and the invitees of the
+/// group the roles of `roles` instead of the default roles of this client
+pub fn create_group_with_roles_msg(
+    user_name: &String,
+    comm_grp: &CommGroupId,
+    roles: GroupRoleConfig,
+    backend: &mut CryptoBackend,
+    config: &mut (impl ClientDataProvider + ?Sized),
+) -> Result<Vec<OnWireMessage>, IdError> {
+    create_group_checked(user_name, comm_grp, Some(roles), backend, config)
+}
+
+fn create_group_checked(
+    user_name: &String,
+    comm_grp: &CommGroupId,
+    roles: Option<GroupRoleConfig>,
+    backend: &mut CryptoBackend,
+    config: &mut (impl ClientDataProvider + ?Sized),
+) -> Result<Vec<OnWireMessage>, IdError> {
+    let comm_grp = CommGroupId::try_new(&comm_grp.community_id(), &comm_grp.group_id())?;
+    if comm_grp.is_dm() {
+        error!(
+            "Cannot create group {}: community {} is reserved for direct messages",
+            comm_grp.group_id(),
+            DM_COMMUNITY_ID
+        );
+        return Ok(vec![]);
+    }
+    Ok(create_group(user_name, &comm_grp, roles, backend, config))
+}
+
+fn create_group(
+    user_name: &String,
+    comm_grp: &CommGroupId,
+    roles: Option<GroupRoleConfig>,
+    backend: &mut CryptoBackend,
+    config: &mut (impl ClientDataProvider + ?Sized),
 ) -> Vec<OnWireMessage> {
+    if config.is_group_name_taken(comm_grp, &comm_grp.group_id()) {
+        error!(
+            "Cannot create group {}: another group of the community has that name",
+            comm_grp.group_id()
+        );
+        return vec![];
+    }
     let group_id = GroupId::from_slice(comm_grp.group_id().as_bytes());
     let new_key_package =
         backend.generate_default_key_package_and_store_bundle(&config.get_credential());
@@ -343,8 +562,9 @@ pub fn create_group_msg(
 
     config.store_group(comm_grp, Some(0), new_mls_group); // Empty hash "0" for newly created group
 
-    // Add creator of the group as Mod
-    config.set_user_role(comm_grp, user_name.to_string(), "Mod".to_string());
+    // Add creator of the group with the configured creator role (Mod by default)
+    let creator_role = config.init_group_roles(comm_grp, roles);
+    config.set_user_role(comm_grp, user_name.to_string(), creator_role);
 
     // OnWireMessage::UserCreate {
     //     user_name: user_name.to_owned(),
@@ -356,14 +576,235 @@ pub fn create_group_msg(
     vec![]
 }
 
+/// Returns whether `signature` is a valid signature of the AS
+/// over the credentials of a credential response
+pub fn verify_as_credentials(
+    credentials: &Vec<Credential>,
+    signature: &Signature,
+    as_public_key: &PublicKey,
+) -> bool {
+    as_public_key
+        .verify(&encode_to_bytes(credentials), signature)
+        .is_ok()
+}
+
+/// Reassembles the credentials of the chunks of a credential lookup
+/// response among `msgs`, in chunk order. Returns `None` if a chunk is
+/// missing or any chunk does not verify against `as_public_key`
+pub fn credentials_from_chunks(
+    msgs: &[OnWireMessage],
+    as_public_key: &PublicKey,
+) -> Option<Vec<Credential>> {
+    let mut chunks = vec![];
+    for msg in msgs {
+        if let OnWireMessage::ASCredentialResponse {
+            queried_user_credentials,
+            signature,
+            chunk_index,
+            total_chunks,
+        } = msg
+        {
+            if !verify_as_credentials(queried_user_credentials, signature, as_public_key) {
+                warn!("Chunk {chunk_index} of the credential response does not verify");
+                return None;
+            }
+            chunks.push((*chunk_index, *total_chunks, queried_user_credentials));
+        }
+    }
+    reassemble_chunks(chunks)
+}
+
+/// Reassembles the key packages of the chunks of a key package lookup
+/// response among `msgs`, in chunk order. Returns `None` if a chunk is missing
+pub fn key_packages_from_chunks(msgs: &[OnWireMessage]) -> Option<Vec<KeyPackage>> {
+    let chunks = msgs
+        .iter()
+        .filter_map(|msg| match msg {
+            OnWireMessage::DSKeyPackageResponse {
+                queried_user_key_packages,
+                chunk_index,
+                total_chunks,
+            } => Some((*chunk_index, *total_chunks, queried_user_key_packages)),
+            _ => None,
+        })
+        .collect();
+    reassemble_chunks(chunks)
+}
+
+/// Concatenates the results of `(chunk_index, total_chunks, results)` chunks
+/// in chunk order, if exactly the chunks `0..total_chunks` are all there
+fn reassemble_chunks<T: Clone>(mut chunks: Vec<(usize, usize, &Vec<T>)>) -> Option<Vec<T>> {
+    chunks.sort_by_key(|(chunk_index, _, _)| *chunk_index);
+    let total_chunks = chunks.first()?.1;
+    let complete = chunks.len() == total_chunks
+        && chunks
+            .iter()
+            .enumerate()
+            .all(|(i, (chunk_index, total, _))| *chunk_index == i && *total == total_chunks);
+    if !complete {
+        warn!(
+            "Received {} chunks of a lookup response of {} chunks",
+            chunks.len(),
+            total_chunks
+        );
+        return None;
+    }
+    Some(
+        chunks
+            .into_iter()
+            .flat_map(|(_, _, results)| results.iter().cloned())
+            .collect(),
+    )
+}
+
 pub fn register_msg_as(credential: Credential, verification_key: PublicKey) -> Vec<OnWireMessage> {
+    register_msg_as_with_display_name(credential, verification_key, String::new())
+}
+
+/// Registers with the AS a `display_name` to show other users instead of
+/// the identity of `credential`, which remains the unique id of the user
+pub fn register_msg_as_with_display_name(
+    credential: Credential,
+    verification_key: PublicKey,
+    display_name: String,
+) -> Vec<OnWireMessage> {
+    register_msg_as_with_endorsement(credential, verification_key, display_name, None)
+}
+
+/// Registers another device of a user with the AS, endorsed by one of the
+/// identities the user registered already
+pub fn register_msg_as_with_endorsement(
+    credential: Credential,
+    verification_key: PublicKey,
+    display_name: String,
+    endorsement: Option<DeviceEndorsement>,
+) -> Vec<OnWireMessage> {
     OnWireMessage::UserRegisterForAS {
         credential,
         verification_key,
+        display_name,
+        endorsement,
     }
     .to_vec()
 }
 
+/// Endorses a new device of the same user, which registers `credential` and
+/// `verification_key`, by signing them with the key of this client
+pub fn endorse_device(
+    credential: &Credential,
+    verification_key: &PublicKey,
+    config: &(impl ClientDataProvider + ?Sized),
+) -> DeviceEndorsement {
+    DeviceEndorsement {
+        endorser: config.get_user_id(),
+        signature: config
+            .get_keypair()
+            .sign(&device_endorsement_content(credential, verification_key)),
+    }
+}
+
+/// Asks the AS to replace the credential and the verification key this
+/// client registered with new ones, signed with both the current verification
+/// key and `new_keypair`. `key_rotations` is how many times the key was
+/// rotated so far, as found in the credential entry of this client at the AS
+pub fn rotate_key_msg(
+    new_credential: Credential,
+    new_keypair: &Keypair,
+    key_rotations: u64,
+    config: &(impl ClientDataProvider + ?Sized),
+) -> OnWireMessage {
+    let user_name = config.get_user_id();
+    let keypair = config.get_keypair();
+    let content = rotate_key_request_content(
+        &user_name,
+        &keypair.public,
+        key_rotations,
+        &new_credential,
+        &new_keypair.public,
+    );
+    OnWireMessage::UserRotateKey {
+        user_name,
+        new_credential,
+        new_verification_key: new_keypair.public,
+        signature: keypair.sign(&content),
+        new_key_signature: new_keypair.sign(&content),
+    }
+}
+
+/// Tells the DS that this client accepted the invite to `comm_grp`, so that
+/// it stops delivering the welcome again
+pub fn accepted_invite_msg(
+    comm_grp: &CommGroupId,
+    config: &(impl ClientDataProvider + ?Sized),
+) -> OnWireMessage {
+    OnWireMessage::UserAcceptedInvite {
+        user_name: config.get_user_id(),
+        comm_grp: comm_grp.clone(),
+    }
+}
+
+pub fn whoami_msg(config: &(impl ClientDataProvider + ?Sized)) -> OnWireMessage {
+    OnWireMessage::UserWhoAmI {
+        user_name: config.get_user_id(),
+    }
+}
+
+/// Asks the AS to revoke the credential of `user_name`, as an operator
+/// presenting the `admin_credential` configured at the AS
+pub fn revoke_credential_msg(admin_credential: &str, user_name: &str) -> OnWireMessage {
+    OnWireMessage::ASRevokeCredential {
+        admin_credential: admin_credential.to_string(),
+        user_name: user_name.to_string(),
+    }
+}
+
+/// Requests a token to authenticate to the DS with from the AS, signed with
+/// the verification key of this client
+pub fn token_request_msg(config: &(impl ClientDataProvider + ?Sized)) -> OnWireMessage {
+    let user_name = config.get_user_id();
+    let requested_at = config.now();
+    let signature = config
+        .get_keypair()
+        .sign(&AuthToken::request_content(&user_name, requested_at));
+    OnWireMessage::ASIssueToken {
+        user_name,
+        requested_at,
+        signature,
+    }
+}
+
+/// Returns the stored token to authenticate to the DS with, or a new one from
+/// the AS if none was stored or it expires within [TOKEN_RENEWAL_MARGIN] of `now`.
+/// `None` if the AS did not issue one
+pub fn fresh_ds_token(
+    client_data: &mut (impl ClientDataProvider + ?Sized),
+    as_transport: &mut impl OnWireTransport,
+    now: SystemTime,
+) -> Option<AuthToken> {
+    if let Some(token) = client_data.get_ds_token() {
+        if token.expires_at > now + TOKEN_RENEWAL_MARGIN {
+            return Some(token);
+        }
+    }
+    let token = as_transport
+        .request(token_request_msg(client_data))
+        .into_iter()
+        .find_map(|msg| match msg {
+            OnWireMessage::ASToken { token } => Some(token),
+            _ => None,
+        });
+    match token {
+        Some(token) => {
+            client_data.set_ds_token(token.clone());
+            Some(token)
+        }
+        None => {
+            warn!("The AS did not issue a token to authenticate to the DS with");
+            None
+        }
+    }
+}
+
 pub fn register_msg_ds(key_packages: Vec<KeyPackage>) -> Vec<OnWireMessage> {
     OnWireMessage::UserKeyPackagesForDS { key_packages }.to_vec()
 }
@@ -380,44 +821,225 @@ pub fn pre_add_invite_msg(
     backend: &mut CryptoBackend,
     client_data: &mut (impl ClientDataProvider + Debug + ?Sized),
     third_party_key_package: Vec<KeyPackage>,
-) -> Vec<OnWireMessage> {
+) -> (Vec<OnWireMessage>, Vec<String>) {
+    let (invitee_key_packages, dropped_invitees) =
+        drop_expired_key_packages(third_party_key_package, backend, client_data.now());
     let action_msg = ActionMsg::Invite(InviteAction {
         metadata: ActionMetadata::new(
             user_name.to_owned(),
             Uuid::new_v4().to_string(),
             comm_grp.clone(),
+            client_data.now(),
         ),
-        invitee_key_packages: third_party_key_package,
+        invitee_key_packages,
     });
 
     info!("Sending the Invite Action message. You still need to `Add Member` to complete the invitation process");
-    check_action_msg_and_get_mls(comm_grp, action_msg, backend, client_data)
+    (
+        check_action_msg_and_get_mls(comm_grp, action_msg, backend, client_data),
+        dropped_invitees,
+    )
+}
+
+/// Sends a request to a server and returns its responses, up to its result
+pub trait OnWireTransport {
+    fn request(&mut self, onwire_msg: OnWireMessage) -> Vec<OnWireMessage>;
+}
+
+/// Looks up the credentials and key packages of the invitees, then generates
+/// the `OnWireMessage`s that invite those whose key packages match their
+/// credentials signed by the AS. Each invitee is looked up on its own, so
+/// that the others can still be invited if one has no credential or key
+/// package available. As with [pre_add_invite_msg], the invitees must then
+/// be added with [add_msg] once the invite went through the DS.
+///
+/// Returns the messages for the DS along with the invitees that cannot be invited
+pub fn invite_users(
+    comm_grp: &CommGroupId,
+    invitee_names: &[String],
+    backend: &mut CryptoBackend,
+    client_data: &mut (impl ClientDataProvider + Debug + ?Sized),
+    as_transport: &mut impl OnWireTransport,
+    ds_transport: &mut impl OnWireTransport,
+) -> (Vec<OnWireMessage>, Vec<String>) {
+    let (key_packages, mut failed_invitees) = fetch_verified_key_packages(
+        invitee_names,
+        backend,
+        client_data,
+        as_transport,
+        ds_transport,
+    );
+    if key_packages.is_empty() {
+        return (vec![], failed_invitees);
+    }
+    let user_name = client_data.get_user_id();
+    let (msgs, dropped_invitees) =
+        pre_add_invite_msg(&user_name, comm_grp, backend, client_data, key_packages);
+    failed_invitees.extend(dropped_invitees);
+    (msgs, failed_invitees)
+}
+
+/// Returns the key packages of the invitees that match their credentials
+/// signed by the AS and are correctly signed, along with the invitees for
+/// which no such key package was found. Expired key packages are returned
+/// without verification, to be dropped by [pre_add_invite_msg].
+pub fn fetch_verified_key_packages(
+    invitee_names: &[String],
+    backend: &CryptoBackend,
+    client_data: &mut (impl ClientDataProvider + ?Sized),
+    as_transport: &mut impl OnWireTransport,
+    ds_transport: &mut impl OnWireTransport,
+) -> (Vec<KeyPackage>, Vec<String>) {
+    let as_public_key = match client_data.get_as_public_key() {
+        Some(as_public_key) => as_public_key,
+        None => {
+            let public_key = as_transport
+                .request(OnWireMessage::ASPublicKeyRequest)
+                .into_iter()
+                .find_map(|msg| match msg {
+                    OnWireMessage::ASPublicKeyResponse { public_key } => Some(public_key),
+                    _ => None,
+                });
+            match public_key {
+                Some(public_key) => {
+                    client_data.set_as_public_key(public_key);
+                    public_key
+                }
+                None => {
+                    error!("No public key response from AS");
+                    return (vec![], invitee_names.to_vec());
+                }
+            }
+        }
+    };
+
+    let user_name = client_data.get_user_id();
+    let now = client_data.now();
+    let mut verified_key_packages = vec![];
+    let mut failed_invitees = vec![];
+    for invitee_name in invitee_names {
+        let credentials = credentials_from_chunks(
+            &as_transport.request(OnWireMessage::UserCredentialLookup {
+                user_name: user_name.clone(),
+                queried_users: vec![invitee_name.to_owned()],
+            }),
+            &as_public_key,
+        );
+        let key_packages =
+            key_packages_from_chunks(&ds_transport.request(OnWireMessage::UserKeyPackageLookup {
+                user_name: user_name.clone(),
+                queried_users: vec![invitee_name.to_owned()],
+            }));
+        let (credentials, key_packages) = match (credentials, key_packages) {
+            (Some(credentials), Some(key_packages)) if !key_packages.is_empty() => {
+                (credentials, key_packages)
+            }
+            _ => {
+                warn!("Cannot find a signed credential and a key package of [{invitee_name}]");
+                failed_invitees.push(invitee_name.to_owned());
+                continue;
+            }
+        };
+
+        let verify_begin_timestamp = Instant::now();
+        let all_verified = key_packages.iter().all(|key_package| {
+            credentials.contains(key_package.credential())
+                && (!backend.key_package_is_valid_at(key_package, now)
+                    || key_package.verify(backend).is_ok())
+        });
+        SingleTimeMeasurement::new(
+            ParseIncomingMsgsKeyPackage,
+            verify_begin_timestamp.elapsed(),
+        );
+        if all_verified {
+            verified_key_packages.extend(key_packages);
+        } else {
+            warn!("The key packages of [{invitee_name}] do not match their credential");
+            failed_invitees.push(invitee_name.to_owned());
+        }
+    }
+    (verified_key_packages, failed_invitees)
+}
+
+/// Splits `key_packages` into those whose lifetime covers `now` and the
+/// identities of the expired ones, which the DS would reject
+fn drop_expired_key_packages(
+    key_packages: Vec<KeyPackage>,
+    backend: &CryptoBackend,
+    now: SystemTime,
+) -> (Vec<KeyPackage>, Vec<String>) {
+    let (valid, expired): (Vec<KeyPackage>, Vec<KeyPackage>) = key_packages
+        .into_iter()
+        .partition(|kp| backend.key_package_is_valid_at(kp, now));
+    let dropped_invitees: Vec<String> = expired
+        .iter()
+        .filter_map(|kp| identity_to_str(kp.credential().identity()))
+        .collect();
+    if !dropped_invitees.is_empty() {
+        warn!(
+            "Skipping invitees whose key package expired: {:?}",
+            dropped_invitees
+        );
+    }
+    (valid, dropped_invitees)
 }
 
 /// Generates an `OnWireMessage` that actually adds the user to the group cryptographically
 ///
-/// `user_name` is the name of the inviter
-/// `invitee_names` are the names of the user being invited to the group
+/// `pre_approved_invitees` are the names of the users being invited to the group.
+/// All pre-approved devices of a user are added, unless a single device is named.
+/// Devices whose key package expired since the invitation are skipped and
+/// returned alongside the messages. If an invitee has no pre-approved key
+/// package, nobody is added and the invitees without one are returned
 pub fn add_msg(
     comm_grp: &CommGroupId,
     pre_approved_invitees: &Vec<String>,
     client_data: &mut (impl ClientDataProvider + Debug + ?Sized),
     backend: &mut CryptoBackend,
-) -> Vec<OnWireMessage> {
-    let invitee_key_packages: Vec<KeyPackage> = pre_approved_invitees
+) -> (Vec<OnWireMessage>, Vec<String>) {
+    // All pre-approved devices of each invitee are added. They are only
+    // popped once the commit is created, so that a failed add can be retried
+    let to_add_invitees = &client_data.get_shared_state(comm_grp).to_add_invitees;
+    let mut resolved_devices: Vec<String> = vec![];
+    let mut unresolved_invitees: Vec<String> = vec![];
+    for pre_approved_invitee in pre_approved_invitees {
+        let devices: Vec<String> = to_add_invitees
+            .keys()
+            .filter(|device_name| {
+                *device_name == pre_approved_invitee
+                    || split_device(device_name).0 == pre_approved_invitee.as_str()
+            })
+            .cloned()
+            .collect();
+        if devices.is_empty() {
+            unresolved_invitees.push(pre_approved_invitee.to_string());
+        }
+        resolved_devices.extend(devices);
+    }
+    if !unresolved_invitees.is_empty() {
+        warn!(
+            "No pre-approved key package for {:?}, adding nobody",
+            unresolved_invitees
+        );
+        return (vec![], unresolved_invitees);
+    }
+    resolved_devices.sort();
+    resolved_devices.dedup();
+    let invitee_key_packages: Vec<KeyPackage> = resolved_devices
         .iter()
-        .filter_map(|pre_approved_invitee| {
-            match client_data.pop_to_add_invitee_key_pack(comm_grp, pre_approved_invitee) {
-                Some(kp) => Some(kp),
-                None => {
-                    warn!(
-                        "pop_to_add_invitee_key_pack returned None for {:?}",
-                        pre_approved_invitee
-                    );
-                    None
-                }
-            }
-        })
+        .map(|device_name| to_add_invitees[device_name].clone())
+        .collect();
+    let (invitee_key_packages, dropped_invitees) =
+        drop_expired_key_packages(invitee_key_packages, backend, client_data.now());
+    if invitee_key_packages.is_empty() {
+        // E.g., all the key packages expired since they were staged
+        warn!("No valid key package left to add, adding nobody");
+        return (vec![], dropped_invitees);
+    }
+    // The welcome goes to every added device
+    let invitee_names: Vec<String> = invitee_key_packages
+        .iter()
+        .filter_map(|kp| identity_to_str(kp.credential().identity()))
         .collect();
 
     let group = client_data
@@ -433,22 +1055,27 @@ pub fn add_msg(
 
     let _ = SingleTimeMeasurement::new(OpenMlsMsgGeneration, mls_operation_timestamp.elapsed());
 
+    for device_name in &resolved_devices {
+        client_data.pop_to_add_invitee_key_pack(comm_grp, device_name);
+    }
+
     let update_group = GroupMessage::from_mls(
         update_mls,
         comm_grp.clone(),
         Some(client_data.get_user_id()),
-    );
+    )
+    .with_tag(client_data.get_keypair());
 
     let update_onwire = group_msg_to_ord_onwire_broadcast(update_group, comm_grp, client_data);
 
     let welcome_onwire = OnWireMessage::UserInvite {
         user_name: client_data.get_user_id(),
-        invitee_names: pre_approved_invitees.to_owned(),
+        invitee_names,
         comm_grp: comm_grp.to_owned(),
         welcome,
     };
 
-    vec![update_onwire, welcome_onwire]
+    (vec![update_onwire, welcome_onwire], dropped_invitees)
 }
 
 pub fn send_text_msg_mls(
@@ -457,6 +1084,42 @@ pub fn send_text_msg_mls(
     message: String,
     backend: &mut CryptoBackend,
     client_data: &mut (impl ClientDataProvider + ?Sized),
+) -> Vec<OnWireMessage> {
+    send_disappearing_text_msg_mls(user_name, comm_grp, message, None, backend, client_data)
+}
+
+/// Sends a text message that every member, including the sender, removes
+/// from their history `expires_after` they received it, if given
+pub fn send_disappearing_text_msg_mls(
+    user_name: &String,
+    comm_grp: &CommGroupId,
+    message: String,
+    expires_after: Option<Duration>,
+    backend: &mut CryptoBackend,
+    client_data: &mut (impl ClientDataProvider + ?Sized),
+) -> Vec<OnWireMessage> {
+    send_reply_text_msg_mls(
+        user_name,
+        comm_grp,
+        message,
+        None,
+        expires_after,
+        backend,
+        client_data,
+    )
+}
+
+/// Sends a text message replying to the message with action id
+/// `in_reply_to`, if given, placing it in the thread of that message.
+/// See [send_disappearing_text_msg_mls] for `expires_after`
+pub fn send_reply_text_msg_mls(
+    user_name: &String,
+    comm_grp: &CommGroupId,
+    message: String,
+    in_reply_to: Option<String>,
+    expires_after: Option<Duration>,
+    backend: &mut CryptoBackend,
+    client_data: &mut (impl ClientDataProvider + ?Sized),
 ) -> Vec<OnWireMessage> {
     // Stop self-store message if that group does not exist
     client_data
@@ -465,16 +1128,61 @@ pub fn send_text_msg_mls(
 
     let text_msg_action = ActionMsg::TextMsg(TextMsgAction {
         msg: message,
+        expires_after,
+        in_reply_to,
         metadata: ActionMetadata::new(
             user_name.to_string(),
             Uuid::new_v4().to_string(),
             comm_grp.clone(),
+            client_data.now(),
         ),
     });
 
     check_action_msg_and_get_mls(comm_grp, text_msg_action, backend, client_data)
 }
 
+/// Creates the hidden group of the direct conversation with `peer_name` (see
+/// [CommGroupId::dm]) and invites the peer with `peer_key_packages`. As for any
+/// group, the peer is then added with [add_msg] and receives the group state
+/// from [send_group_state_update], each once the previous messages went through
+/// the DS, before accepting the invite.
+pub fn create_dm(
+    peer_name: &String,
+    peer_key_packages: Vec<KeyPackage>,
+    backend: &mut CryptoBackend,
+    client_data: &mut (impl ClientDataProvider + Debug + ?Sized),
+) -> (Vec<OnWireMessage>, Vec<String>) {
+    let user_name = client_data.get_user_id();
+    let comm_grp = CommGroupId::dm(&user_name, peer_name);
+    if client_data.get_ref_group(&comm_grp).is_some() {
+        warn!("A direct conversation with {} already exists", peer_name);
+        return (vec![], vec![]);
+    }
+    let mut msgs = create_group(&user_name, &comm_grp, None, backend, client_data);
+    let (invite_msgs, dropped_invitees) = pre_add_invite_msg(
+        &user_name,
+        &comm_grp,
+        backend,
+        client_data,
+        peer_key_packages,
+    );
+    msgs.extend(invite_msgs);
+    (msgs, dropped_invitees)
+}
+
+/// Sends `message` in the direct conversation with `peer_name`, which must
+/// have been created with [create_dm] or accepted already
+pub fn send_dm(
+    peer_name: &String,
+    message: String,
+    backend: &mut CryptoBackend,
+    client_data: &mut (impl ClientDataProvider + ?Sized),
+) -> Vec<OnWireMessage> {
+    let user_name = client_data.get_user_id();
+    let comm_grp = CommGroupId::dm(&user_name, peer_name);
+    send_text_msg_mls(&user_name, &comm_grp, message, backend, client_data)
+}
+
 /// Send an update to the shared state of the group (eventually will use
 /// app msg)
 pub fn show_group_state(
@@ -491,27 +1199,263 @@ pub fn show_group_state(
     );
 }
 
-pub fn pre_leave_msg(
+/// Summarizes the local state of a group: its name, topic and MLS epoch, and
+/// the role and credential fingerprint of each member. Returns `None` if the
+/// group is unknown.
+pub fn group_info(
     comm_grp: &CommGroupId,
-    backend: &mut CryptoBackend,
     client_data: &mut (impl ClientDataProvider + ?Sized),
-) -> Vec<OnWireMessage> {
-    let action = ActionMsg::Leave(LeaveAction {
-        metadata: ActionMetadata::new(
-            client_data.get_user_id(),
-            Uuid::new_v4().to_string(),
-            comm_grp.clone(),
-        ),
-    });
+) -> Option<GroupInfo> {
+    // Release the MLS group before reading the shared state
+    let (epoch, mut member_names, key_fingerprints) = {
+        let mls_group = client_data.get_ref_group(comm_grp)?.borrow();
+        let key_fingerprints = mls_group
+            .members()
+            .iter()
+            .filter_map(|key_package| {
+                let credential = key_package.credential();
+                identity_to_str(credential.identity())
+                    .map(|member| (member, credential_fingerprint(credential)))
+            })
+            .collect();
+        (
+            mls_group.epoch().as_u64(),
+            user_names_from_mls_group(&mls_group),
+            key_fingerprints,
+        )
+    };
+    member_names.sort();
+    let shared_state = client_data.get_shared_state(comm_grp);
+    let members = member_names
+        .into_iter()
+        .map(|member| {
+            let roles = shared_state.rbac.get_user_roles(&member).join("+");
+            (member, roles)
+        })
+        .collect();
+    Some(GroupInfo {
+        name: shared_state.name.clone(),
+        topic: shared_state.topic.clone(),
+        epoch,
+        members,
+        key_fingerprints,
+    })
+}
+
+/// Checks the local state of a group for inconsistencies: members of the MLS
+/// group without a role, proposed actions whose signature does not verify,
+/// and a group state as received that no longer hashes to the hash stored on
+/// receipt. Returns `None` if the group is unknown.
+pub fn verify_group_integrity(
+    comm_grp: &CommGroupId,
+    client_data: &mut (impl ClientDataProvider + ?Sized),
+) -> Option<Vec<IntegrityDiscrepancy>> {
+    let mut member_names =
+        user_names_from_mls_group(&client_data.get_ref_group(comm_grp)?.borrow());
+    member_names.sort();
+    let rbac = client_data.get_roles(comm_grp);
+    let mut discrepancies: Vec<IntegrityDiscrepancy> = member_names
+        .into_iter()
+        .filter(|member| !rbac.user_to_roles.contains_key(member))
+        .map(IntegrityDiscrepancy::MemberWithoutRole)
+        .collect();
+
+    for proposed_action in client_data.get_proposed_actions(comm_grp) {
+        let metadata = proposed_action.action.get_metadata();
+        let verified = client_data
+            .get_user_verify_key(&metadata.sender)
+            .map_or(false, |key| proposed_action.verify(key));
+        if !verified {
+            discrepancies.push(IntegrityDiscrepancy::UnverifiedProposedAction {
+                action_id: metadata.action_id,
+                sender: metadata.sender,
+            });
+        }
+    }
+
+    if let (Some(stored), Some(received)) = (
+        client_data.get_shared_gov_state_init_hash(comm_grp),
+        client_data.get_received_gov_state(comm_grp),
+    ) {
+        let recomputed = received.stable_hash();
+        if recomputed != stored {
+            discrepancies.push(IntegrityDiscrepancy::InitHashMismatch { stored, recomputed });
+        }
+    }
+    Some(discrepancies)
+}
+
+/// Returns the group's report review queue, if the role of this client
+/// permits reviewing reports
+pub fn list_reports(
+    comm_grp: &CommGroupId,
+    client_data: &(impl ClientDataProvider + ?Sized),
+) -> Result<Vec<StoredReport>, ClientError> {
+    if !client_data
+        .get_roles(comm_grp)
+        .action_type_authorized(&client_data.get_user_id(), &ActionType::ReviewReports)
+    {
+        return Err(Unauthorized(ActionType::ReviewReports));
+    }
+    Ok(client_data.get_reports(comm_grp))
+}
+
+/// Returns the ids of the group's flagged messages with their number of
+/// flags, most flagged first, if the role of this client permits reviewing
+/// reports
+pub fn list_flagged(
+    comm_grp: &CommGroupId,
+    client_data: &(impl ClientDataProvider + ?Sized),
+) -> Result<Vec<(String, usize)>, ClientError> {
+    if !client_data
+        .get_roles(comm_grp)
+        .action_type_authorized(&client_data.get_user_id(), &ActionType::ReviewReports)
+    {
+        return Err(Unauthorized(ActionType::ReviewReports));
+    }
+    let mut flagged: Vec<(String, usize)> = client_data
+        .get_flags(comm_grp)
+        .into_iter()
+        .map(|(message_id, flaggers)| (message_id, flaggers.len()))
+        .collect();
+    flagged.sort_by(|(_, count_a), (_, count_b)| count_b.cmp(count_a));
+    Ok(flagged)
+}
+
+/// Lists the actions of the group pending in the policy engine, each with the
+/// number of yes votes it still needs to pass (see [PolicyEngine::votes_needed])
+pub fn list_proposals(
+    comm_grp: &CommGroupId,
+    client_data: &(impl ClientDataProvider + ?Sized),
+) -> Vec<(ActionMsg, usize)> {
+    let policy_engine_ref = client_data.get_policy_engine_ref_clone(comm_grp);
+    let policy_engine = policy_engine_ref.borrow();
+    let mut proposals: Vec<(ActionMsg, usize)> = policy_engine
+        .proposed_actions
+        .iter()
+        .map(|proposed_action| {
+            let action_id = proposed_action.action.get_metadata().action_id;
+            (
+                proposed_action.action.clone(),
+                policy_engine.votes_needed(&action_id),
+            )
+        })
+        .collect();
+    // An action subject to several policies is pending in each of them
+    proposals.dedup_by_key(|(action, _)| action.get_metadata().action_id);
+    proposals
+}
+
+/// Returns the latest decisions the policy engine of the group took, oldest first
+pub fn policy_log(
+    comm_grp: &CommGroupId,
+    client_data: &(impl ClientDataProvider + ?Sized),
+) -> Vec<PolicyDecision> {
+    client_data
+        .get_policy_engine_ref_clone(comm_grp)
+        .borrow()
+        .decision_log
+        .iter()
+        .cloned()
+        .collect()
+}
+
+/// Returns how many more yes votes the proposal `proposal_id` needs to pass:
+/// 0 if it already passed (or is not decided by vote), and
+/// [crate::policyengine::VOTES_UNREACHABLE] if it can no longer pass
+pub fn votes_needed(
+    comm_grp: &CommGroupId,
+    proposal_id: &str,
+    client_data: &(impl ClientDataProvider + ?Sized),
+) -> usize {
+    client_data
+        .get_policy_engine_ref_clone(comm_grp)
+        .borrow()
+        .votes_needed(proposal_id)
+}
+
+/// Queues in the outbox a [ClosePollAction] for each pending action this
+/// client proposed whose vote ended, in every group with a governance state.
+/// Meant to run on each sync, but queues one close per action only. The vote
+/// only closes once the action is ordered, so that every member closes it
+/// after the same votes
+pub fn queue_expired_poll_closes(client_data: &mut ClientRef) {
+    let now = client_data.now();
+    let user_name = client_data.get_user_id();
+    for comm_grp in client_data.get_comm_grps() {
+        if !client_data.is_shared_gov_state_initialized(&comm_grp) {
+            continue;
+        }
+        let mut expired: Vec<(String, ActionType)> = vec![];
+        for proposed_action in client_data
+            .get_policy_engine_ref_clone(&comm_grp)
+            .borrow()
+            .proposed_actions
+            .iter()
+        {
+            let metadata = proposed_action.action.get_metadata();
+            let entry = (metadata.action_id, proposed_action.action.action_type());
+            if metadata.sender == user_name
+                && proposed_action
+                    .reevaluate_after
+                    .map_or(false, |deadline| deadline <= now)
+                && !expired.contains(&entry)
+            {
+                expired.push(entry);
+            }
+        }
+        let expired_ids: Vec<String> = expired.iter().map(|(id, _)| id.clone()).collect();
+        let unqueued_ids = client_data.record_queued_poll_closes(&comm_grp, &expired_ids);
+        for (proposed_action_id, proposed_action_type) in expired
+            .into_iter()
+            .filter(|(id, _)| unqueued_ids.contains(id))
+        {
+            let close_action = ActionMsg::ClosePoll(ClosePollAction {
+                proposed_action_id,
+                proposed_action_type,
+                metadata: ActionMetadata::new(
+                    user_name.clone(),
+                    Uuid::new_v4().to_string(),
+                    comm_grp.clone(),
+                    client_data.now(),
+                ),
+            });
+            client_data.push_outbox_action(&comm_grp, close_action);
+        }
+    }
+}
+
+pub fn pre_leave_msg(
+    comm_grp: &CommGroupId,
+    backend: &mut CryptoBackend,
+    client_data: &mut (impl ClientDataProvider + ?Sized),
+) -> Vec<OnWireMessage> {
+    let action = ActionMsg::Leave(LeaveAction {
+        metadata: ActionMetadata::new(
+            client_data.get_user_id(),
+            Uuid::new_v4().to_string(),
+            comm_grp.clone(),
+            client_data.now(),
+        ),
+    });
 
     check_action_msg_and_get_mls(comm_grp, action, backend, client_data)
 }
 
+/// Declines the invite to `comm_grp`. If the invite is only a pending
+/// welcome, i.e. the client was never added to the MLS group, the welcome is
+/// dropped locally and no message is sent
 pub fn pre_decline_msg(
     comm_grp: &CommGroupId,
     backend: &mut CryptoBackend,
     client_data: &mut ClientRef,
 ) -> Vec<OnWireMessage> {
+    if client_data.get_ref_group(comm_grp).is_none()
+        && client_data.remove_welcome(comm_grp).is_some()
+    {
+        info!("Discarded the pending welcome to {:?}", comm_grp);
+        return vec![];
+    }
     try_activate_gov_state(comm_grp, backend, client_data).expect("Cannot decline.");
 
     let action = ActionMsg::Decline(DeclineAction {
@@ -519,12 +1463,33 @@ pub fn pre_decline_msg(
             client_data.get_user_id(),
             Uuid::new_v4().to_string(),
             comm_grp.clone(),
+            client_data.now(),
         ),
     });
 
     check_action_msg_and_get_mls(comm_grp, action, backend, client_data.deref_mut())
 }
 
+/// Removes the client from `comm_grp` after its decline was committed, so a
+/// separate `Remove` (self) is not needed. If the client only holds a pending
+/// welcome to the group, the welcome is dropped locally and no message is sent
+pub fn remove_self_on_decline(
+    comm_grp: &CommGroupId,
+    backend: &mut CryptoBackend,
+    client_data: &mut ClientRef,
+) -> Vec<OnWireMessage> {
+    if client_data.get_ref_group(comm_grp).is_none() {
+        if client_data.remove_welcome(comm_grp).is_some() {
+            info!("Discarded the pending welcome to {:?}", comm_grp);
+        } else {
+            warn!("No invite to {:?} to decline", comm_grp);
+        }
+        return vec![];
+    }
+    let user_name = client_data.get_user_id();
+    remove_other_or_self_msg(comm_grp, &user_name, backend, client_data.deref_mut())
+}
+
 /// execute all unordered messages which supposedly contain `UpdateGroupStateAction`,
 /// execute all other messages (ordered),
 /// and store the group.
@@ -545,22 +1510,27 @@ fn try_activate_gov_state(
 
     // Init shared gov state by execute all non-handshake, same-epoch messages.
     for msg in &unprocessed_msgs {
-        if let OnWireMessage::DSRelayedUserMsg { user_msg, .. } = msg {
-            match user_msg {
+        if let OnWireMessage::DSRelayedUserMsg {
+            user_msg:
                 GroupMessage::AppMlsMessage {
-                    mls_msg, sender, ..
-                } => {
-                    if (!mls_msg.is_handshake_message()) && mls_msg.epoch() == welcome_epoch {
-                        parse_mls_message_out(
-                            mls_msg.clone(),
-                            comm_grp,
-                            sender.clone(),
-                            client_data,
-                            backend,
-                        );
-                        processed_msgs.push(msg.to_owned());
-                    }
-                }
+                    mls_msg,
+                    sender,
+                    tag,
+                    ..
+                },
+            ..
+        } = msg
+        {
+            if (!mls_msg.is_handshake_message()) && mls_msg.epoch() == welcome_epoch {
+                parse_mls_message_out(
+                    mls_msg.clone(),
+                    comm_grp,
+                    sender.clone(),
+                    tag.as_ref(),
+                    client_data,
+                    backend,
+                );
+                processed_msgs.push(msg.to_owned());
             }
         }
     }
@@ -598,6 +1568,174 @@ pub fn pre_kick_msg(
             client_data.get_user_id(),
             Uuid::new_v4().to_string(),
             comm_grp.clone(),
+            client_data.now(),
+        ),
+    });
+
+    check_action_msg_and_get_mls(comm_grp, action, backend, client_data)
+}
+
+/// Proposes the removal of group members whose credentials were reported as
+/// revoked by the AS. Each removal is a `Kick` and thus subject to the group's
+/// policies. At most one member is proposed per group, as a group can only hold
+/// a single pending action; the rest are proposed on later syncs. Members whose
+/// removal is already pending are skipped, so a sync does not propose the
+/// same removal again while the previous one awaits its echo or a vote.
+/// Nothing is proposed if the credential of the local user itself was revoked.
+pub fn remove_revoked_members_msgs(
+    backend: &mut CryptoBackend,
+    client_data: &mut (impl ClientDataProvider + ?Sized),
+) -> Vec<OnWireMessage> {
+    let user_name = client_data.get_user_id();
+    if client_data.is_credential_revoked(&user_name) {
+        warn!("Credential of [{user_name}] was revoked. Not proposing any removal");
+        return vec![];
+    }
+
+    let mut result = vec![];
+    for comm_grp in client_data.get_comm_grps() {
+        if !client_data.is_shared_gov_state_initialized(&comm_grp) {
+            continue;
+        }
+        let revoked_member = client_data
+            .get_group_members(&comm_grp)
+            .into_iter()
+            .find(|member| {
+                client_data.is_credential_revoked(member)
+                    && !client_data.is_removal_pending(&comm_grp, member)
+            });
+        if let Some(member) = revoked_member {
+            info!(
+                "Proposing to remove [{member}] from {:?} as their credential was revoked",
+                comm_grp
+            );
+            result.extend(pre_kick_msg(&comm_grp, &member, backend, client_data));
+        }
+    }
+    result
+}
+
+/// Returns the messages sending the actions that policies queued in the
+/// outbox, e.g., takedowns by an `AutoModerationPolicy`. The actions of
+/// groups the client left since are dropped.
+pub fn outbox_msgs(
+    backend: &mut CryptoBackend,
+    client_data: &mut (impl ClientDataProvider + ?Sized),
+) -> Vec<OnWireMessage> {
+    let mut result = vec![];
+    for (comm_grp, action) in client_data.take_outbox_actions() {
+        if client_data.get_ref_group(&comm_grp).is_none() {
+            warn!(
+                "Dropping a queued action to {:?}, no longer a group",
+                comm_grp
+            );
+            continue;
+        }
+        result.extend(check_action_msg_and_get_mls(
+            &comm_grp,
+            action,
+            backend,
+            client_data,
+        ));
+    }
+    result
+}
+
+/// Records the group messages among `onwire_msgs` as unsent until the DS
+/// confirms them. Storing the client data before sending them makes them
+/// survive a crash, to be resent from [ClientDataProvider::get_unsent_msgs]
+/// at the next start. Messages sent without an identifier are not
+/// recorded, as their `DSResult` does not tell which message it answers.
+pub fn record_unsent_msgs(
+    onwire_msgs: &[OnWireMessage],
+    client_data: &mut (impl ClientDataProvider + ?Sized),
+) {
+    for onwire_msg in onwire_msgs {
+        let confirmable = match onwire_msg {
+            OnWireMessage::UserStandardSend { identifier, .. } => identifier.is_some(),
+            OnWireMessage::UserReliableSend { .. } => true,
+            _ => false,
+        };
+        if confirmable {
+            client_data.push_unsent_msg(onwire_msg.clone());
+        }
+    }
+}
+
+/// Confirms the oldest unsent message to the group `identifier`, the one a
+/// `DSResult` echoing the identifier answers, as the DS answers in order
+fn confirm_oldest_unsent_msg(
+    identifier: &str,
+    client_data: &mut (impl ClientDataProvider + ?Sized),
+) {
+    let oldest_id = client_data.get_unsent_msgs().iter().find_map(|unsent_msg| {
+        unsent_msg
+            .sent_group_msg()
+            .filter(|group_msg| group_msg.get_group_id().get_string() == identifier)
+            .map(GroupMessage::get_client_msg_id)
+    });
+    if let Some(client_msg_id) = oldest_id {
+        client_data.confirm_sent_msg(client_msg_id);
+    }
+}
+
+/// Restores for every member the group state snapshot this client took at
+/// `target_epoch`. Nothing is sent if this client has no such snapshot.
+pub fn admin_revert_msg(
+    comm_grp: &CommGroupId,
+    target_epoch: u64,
+    backend: &mut CryptoBackend,
+    client_data: &mut (impl ClientDataProvider + ?Sized),
+) -> Vec<OnWireMessage> {
+    let target_state = match client_data.get_gov_state_snapshot(comm_grp, target_epoch) {
+        Some(target_state) => target_state,
+        None => {
+            warn!(
+                "No snapshot of the group state at epoch {}, not reverting",
+                target_epoch
+            );
+            return vec![];
+        }
+    };
+    let action = ActionMsg::AdminRevert(AdminRevertAction {
+        target_epoch,
+        target_state: Some(target_state),
+        metadata: ActionMetadata::new(
+            client_data.get_user_id(),
+            Uuid::new_v4().to_string(),
+            comm_grp.clone(),
+            client_data.now(),
+        ),
+    });
+
+    check_action_msg_and_get_mls(comm_grp, action, backend, client_data)
+}
+
+/// Renames the group for every member. With unique group names enforced,
+/// nothing is sent if another group of the community this client is in has
+/// that name. The check is only made here, as members may know of different
+/// groups of the community, and must all apply the same renames
+pub fn rename_group_msg(
+    comm_grp: &CommGroupId,
+    new_name: &str,
+    backend: &mut CryptoBackend,
+    client_data: &mut (impl ClientDataProvider + ?Sized),
+) -> Vec<OnWireMessage> {
+    if client_data.is_group_name_taken(comm_grp, new_name) {
+        error!(
+            "Cannot rename the group to {}: another group of the community has that name",
+            new_name
+        );
+        return vec![];
+    }
+    let action = ActionMsg::RenameGroup(RenameGroupAction {
+        new_name: new_name.to_owned(),
+        lamport_counter: client_data.get_group_name_version(comm_grp).0 + 1,
+        metadata: ActionMetadata::new(
+            client_data.get_user_id(),
+            Uuid::new_v4().to_string(),
+            comm_grp.clone(),
+            client_data.now(),
         ),
     });
 
@@ -618,6 +1756,72 @@ pub fn set_role_msg(
             client_data.get_user_id(),
             Uuid::new_v4().to_string(),
             comm_grp.clone(),
+            client_data.now(),
+        ),
+    });
+
+    check_action_msg_and_get_mls(comm_grp, action, backend, client_data)
+}
+
+/// Gives `role` to a member, in addition to the roles they hold
+pub fn add_role_msg(
+    comm_grp: &CommGroupId,
+    member_name: &String,
+    role: String,
+    backend: &mut CryptoBackend,
+    client_data: &mut (impl ClientDataProvider + ?Sized),
+) -> Vec<OnWireMessage> {
+    let action = ActionMsg::AddUserRole(AddUserRoleAction {
+        user_id: member_name.to_owned(),
+        role_name: role,
+        metadata: ActionMetadata::new(
+            client_data.get_user_id(),
+            Uuid::new_v4().to_string(),
+            comm_grp.clone(),
+            client_data.now(),
+        ),
+    });
+
+    check_action_msg_and_get_mls(comm_grp, action, backend, client_data)
+}
+
+/// Takes `role` away from a member, keeping the other roles they hold
+pub fn remove_role_msg(
+    comm_grp: &CommGroupId,
+    member_name: &String,
+    role: String,
+    backend: &mut CryptoBackend,
+    client_data: &mut (impl ClientDataProvider + ?Sized),
+) -> Vec<OnWireMessage> {
+    let action = ActionMsg::RemoveUserRole(RemoveUserRoleAction {
+        user_id: member_name.to_owned(),
+        role_name: role,
+        metadata: ActionMetadata::new(
+            client_data.get_user_id(),
+            Uuid::new_v4().to_string(),
+            comm_grp.clone(),
+            client_data.now(),
+        ),
+    });
+
+    check_action_msg_and_get_mls(comm_grp, action, backend, client_data)
+}
+
+/// Assigns each `(member_name, new_role)` pair in `assignments` within a
+/// single ordered action, and thus a single epoch change
+pub fn set_roles_bulk_msg(
+    comm_grp: &CommGroupId,
+    assignments: Vec<(String, String)>,
+    backend: &mut CryptoBackend,
+    client_data: &mut (impl ClientDataProvider + ?Sized),
+) -> Vec<OnWireMessage> {
+    let action = ActionMsg::SetUserRolesBulk(SetUserRolesBulkAction {
+        assignments,
+        metadata: ActionMetadata::new(
+            client_data.get_user_id(),
+            Uuid::new_v4().to_string(),
+            comm_grp.clone(),
+            client_data.now(),
         ),
     });
 
@@ -652,28 +1856,452 @@ pub fn remove_other_or_self_msg(
         }
     };
 
-    let update_group = GroupMessage::from_mls(mls, comm_grp.clone(), Some(client_user));
+    let update_group = GroupMessage::from_mls(mls, comm_grp.clone(), Some(client_user))
+        .with_tag(client_data.get_keypair());
 
     group_msg_to_ord_onwire_broadcast(update_group, comm_grp, client_data).to_vec()
 }
 
-pub fn send_group_state_update(
-    user_name: &String,
-    comm_grp: &CommGroupId,
+/// Removes all of `member_names` from the group in a single commit. Names
+/// that are not current members, or that are the client's own (see
+/// [remove_other_or_self_msg] to leave), are skipped with a warning. Returns
+/// no message if no name is left to remove.
+pub fn remove_members_msg(
+    comm_grp: &CommGroupId,
+    member_names: Vec<String>,
+    backend: &mut CryptoBackend,
+    client_data: &mut (impl ClientDataProvider + ?Sized),
+) -> Vec<OnWireMessage> {
+    let client_user = client_data.get_user_id();
+    let mut to_remove = vec![];
+    for member_name in member_names {
+        if member_name == client_user {
+            warn!("Not removing yourself along with other members. Leave the group instead");
+        } else if !client_data.is_member(comm_grp, &member_name) {
+            warn!("Not removing [{member_name}] as they are not a member");
+        } else if !to_remove.contains(&member_name) {
+            to_remove.push(member_name);
+        }
+    }
+    if to_remove.is_empty() {
+        warn!("No member to remove from {:?}", comm_grp);
+        return vec![];
+    }
+
+    let mls = {
+        let mut group = client_data
+            .get_ref_group(comm_grp)
+            .expect("Cannot find that group")
+            .borrow_mut();
+        let member_hash_refs: Vec<_> = to_remove
+            .iter()
+            .map(|member_name| {
+                get_member_hash_ref(group.members(), &str_to_identity(member_name), backend)
+            })
+            .collect();
+        group
+            .remove_members(backend, &member_hash_refs)
+            .expect("Could not generate remove members update")
+            .0 //Note: ignoring .1 because our system does not allow both add and remove at same commit
+    };
+
+    let update_group = GroupMessage::from_mls(mls, comm_grp.clone(), Some(client_user))
+        .with_tag(client_data.get_keypair());
+
+    group_msg_to_ord_onwire_broadcast(update_group, comm_grp, client_data).to_vec()
+}
+
+/// Discards the local state of `comm_grp`, e.g., because it diverged from
+/// the other members', and asks to be invited again. As the DS does not
+/// keep what external commits would need to join, the request goes to the
+/// members allowed to invite according to the discarded state, or to all
+/// other members if none is.
+pub fn rejoin_group_msg(
+    comm_grp: &CommGroupId,
+    client_data: &mut (impl ClientDataProvider + ?Sized),
+) -> Vec<OnWireMessage> {
+    let user_name = client_data.get_user_id();
+    let other_members: Vec<String> = match client_data.get_ref_group(comm_grp) {
+        Some(_) => client_data
+            .get_group_members(comm_grp)
+            .into_iter()
+            .filter(|member| member != &user_name)
+            .collect(),
+        None => vec![],
+    };
+    let mut recipients = vec![];
+    if client_data.is_shared_gov_state_initialized(comm_grp) {
+        let rbac = client_data.get_roles(comm_grp);
+        recipients = other_members
+            .iter()
+            .filter(|member| rbac.action_type_authorized(member, &ActionType::Invite))
+            .cloned()
+            .collect();
+    }
+    if recipients.is_empty() {
+        recipients = other_members;
+    }
+
+    client_data.remove_group(comm_grp);
+    info!("Discarded {:?} and requested a re-invite", comm_grp);
+
+    OnWireMessage::UserRequestReinvite {
+        user_name,
+        comm_grp: comm_grp.clone(),
+        recipients,
+    }
+    .to_vec()
+}
+
+pub fn send_group_state_update(
+    user_name: &String,
+    comm_grp: &CommGroupId,
+    backend: &mut CryptoBackend,
+    client_data: &mut (impl ClientDataProvider + ?Sized),
+) -> Vec<OnWireMessage> {
+    let update = GovStateAnnouncementAction {
+        group_state: client_data.get_shared_state(comm_grp).clone(),
+        metadata: ActionMetadata::new(
+            user_name.to_string(),
+            Uuid::new_v4().to_string(),
+            comm_grp.clone(),
+            client_data.now(),
+        ),
+    };
+
+    let action_msg = ActionMsg::GovStateAnnouncement(update);
+    check_action_msg_and_get_mls(comm_grp, action_msg, backend, client_data)
+}
+
+/// Mutes the group, or only `target_user_id` within it, for this client only:
+/// their messages are still stored, but no longer counted as unread
+pub fn mute(
+    comm_grp: &CommGroupId,
+    target_user_id: Option<String>,
+    client_data: &mut (impl ClientDataProvider + ?Sized),
+) {
+    let action = MuteAction {
+        target_user_id,
+        metadata: ActionMetadata::new(
+            client_data.get_user_id(),
+            Uuid::new_v4().to_string(),
+            comm_grp.clone(),
+            client_data.now(),
+        ),
+    };
+    action.execute(client_data);
+}
+
+/// Undoes a [mute] of the group, or of `target_user_id` within it
+pub fn unmute(
+    comm_grp: &CommGroupId,
+    target_user_id: Option<String>,
+    client_data: &mut (impl ClientDataProvider + ?Sized),
+) {
+    let action = UnmuteAction {
+        target_user_id,
+        metadata: ActionMetadata::new(
+            client_data.get_user_id(),
+            Uuid::new_v4().to_string(),
+            comm_grp.clone(),
+            client_data.now(),
+        ),
+    };
+    action.execute(client_data);
+}
+
+/// The key the devices of a user encrypt their shared notification
+/// preferences with, derived from a secret configured on each of them
+pub fn prefs_key(secret: &str) -> Vec<u8> {
+    Sha256::digest([PREFS_KEY_CONTEXT, secret.as_bytes()].concat()).to_vec()
+}
+
+/// Encrypts the notification preferences of this client with `prefs_key`,
+/// and signs them for the AS to store for the other devices of its user
+pub fn store_prefs_msg(
+    prefs_key: &[u8],
+    backend: &CryptoBackend,
+    client_data: &(impl ClientDataProvider + ?Sized),
+) -> OnWireMessage {
+    let user_name = client_data.get_user_id();
+    let plaintext = serde_json::to_vec(&client_data.get_notification_prefs())
+        .expect("Cannot serialize the preferences");
+    let nonce = backend
+        .rand()
+        .random_vec(PREFS_NONCE_LEN)
+        .expect("Cannot generate a nonce");
+    let ciphertext = backend
+        .crypto()
+        .aead_encrypt(
+            AeadType::ChaCha20Poly1305,
+            prefs_key,
+            &plaintext,
+            &nonce,
+            split_device(&user_name).0.as_bytes(),
+        )
+        .expect("Cannot encrypt the preferences");
+    let prefs = [nonce, ciphertext].concat();
+    let signature = client_data
+        .get_keypair()
+        .sign(&prefs_request_content(&user_name, &prefs));
+    OnWireMessage::UserStorePrefs {
+        user_name,
+        prefs,
+        signature,
+    }
+}
+
+/// Requests the notification preferences stored by any device of this
+/// client's user, answered with an `ASPrefsResponse`
+pub fn load_prefs_msg(client_data: &(impl ClientDataProvider + ?Sized)) -> OnWireMessage {
+    OnWireMessage::UserLoadPrefs {
+        user_name: client_data.get_user_id(),
+    }
+}
+
+/// Decrypts the preferences of an `ASPrefsResponse` with `prefs_key`, and
+/// replaces the local ones with them
+pub fn apply_loaded_prefs(
+    prefs: &[u8],
+    prefs_key: &[u8],
+    backend: &CryptoBackend,
+    client_data: &mut (impl ClientDataProvider + ?Sized),
+) -> Result<(), ClientError> {
+    if prefs.len() < PREFS_NONCE_LEN {
+        return Err(InvalidPrefs);
+    }
+    let (nonce, ciphertext) = prefs.split_at(PREFS_NONCE_LEN);
+    let user_name = client_data.get_user_id();
+    let plaintext = backend
+        .crypto()
+        .aead_decrypt(
+            AeadType::ChaCha20Poly1305,
+            prefs_key,
+            ciphertext,
+            nonce,
+            split_device(&user_name).0.as_bytes(),
+        )
+        .map_err(|_| InvalidPrefs)?;
+    let prefs: NotificationPrefs = serde_json::from_slice(&plaintext).map_err(|_| InvalidPrefs)?;
+    client_data.set_notification_prefs(prefs);
+    Ok(())
+}
+
+/// Serializes the shared governance state of the group (name, topic, roles,
+/// policies, ...) as JSON, e.g., for a backup, or to audit it
+pub fn export_gov_state(
+    comm_grp: &CommGroupId,
+    client_data: &(impl ClientDataProvider + ?Sized),
+) -> String {
+    serde_json::to_string_pretty(client_data.get_shared_state(comm_grp))
+        .expect("Cannot serialize group state")
+}
+
+/// Replaces the shared governance state of the group with one exported by
+/// [export_gov_state], and broadcasts it to the other members, if the role
+/// of this client permits restoring group states.
+/// The MLS group itself is left untouched.
+pub fn import_gov_state(
+    comm_grp: &CommGroupId,
+    exported: &str,
+    backend: &mut CryptoBackend,
+    client_data: &mut (impl ClientDataProvider + ?Sized),
+) -> Result<Vec<OnWireMessage>, ClientError> {
+    let user_name = client_data.get_user_id();
+    if !client_data
+        .get_roles(comm_grp)
+        .action_type_authorized(&user_name, &ActionType::AdminRevert)
+    {
+        return Err(Unauthorized(ActionType::AdminRevert));
+    }
+    let group_state: SharedGroupState =
+        serde_json::from_str(exported).map_err(InvalidGovStateExport)?;
+    client_data.load_shared_state(comm_grp, group_state);
+    Ok(send_group_state_update(
+        &user_name,
+        comm_grp,
+        backend,
+        client_data,
+    ))
+}
+
+/// Broadcasts the hash of the local group state of every group whose group
+/// state is initialized, for the other members to compare with their own
+#[cfg(feature = "gov")]
+pub fn gov_state_hash_msgs(
+    backend: &mut CryptoBackend,
+    client_data: &mut (impl ClientDataProvider + ?Sized),
+) -> Vec<OnWireMessage> {
+    let mut result = vec![];
+    for comm_grp in client_data.get_comm_grps() {
+        if !client_data.is_shared_gov_state_initialized(&comm_grp) {
+            continue;
+        }
+        let action_msg = ActionMsg::GovStateHash(GovStateHashAction {
+            gov_state_hash: client_data.get_shared_state(&comm_grp).stable_hash(),
+            epoch: client_data
+                .get_ref_group(&comm_grp)
+                .expect("Cannot find that group")
+                .borrow()
+                .epoch()
+                .as_u64(),
+            metadata: ActionMetadata::new(
+                client_data.get_user_id(),
+                Uuid::new_v4().to_string(),
+                comm_grp.clone(),
+                client_data.now(),
+            ),
+        });
+        result.extend(check_action_msg_and_get_mls(
+            &comm_grp,
+            action_msg,
+            backend,
+            client_data,
+        ));
+    }
+    result
+}
+
+/// Compares the group state hash announced by another member with the hash
+/// of the local group state at the same epoch. Returns an `Invalid` message,
+/// after logging a warning, when they differ.
+#[cfg(feature = "gov")]
+pub fn check_gov_state_hash(
+    hash_action: &GovStateHashAction,
+    client_data: &mut (impl ClientDataProvider + ?Sized),
+) -> Option<ClientParsedMsg> {
+    let comm_grp = &hash_action.metadata.community_group_id;
+    let sender = &hash_action.metadata.sender;
+    if !client_data.is_shared_gov_state_initialized(comm_grp) {
+        debug!("Cannot compare the group state hash from {sender} as the group state is not initialized");
+        return None;
+    }
+    let local_epoch = client_data
+        .get_ref_group(comm_grp)
+        .expect("Cannot find that group")
+        .borrow()
+        .epoch()
+        .as_u64();
+    if local_epoch != hash_action.epoch {
+        debug!(
+            "Not comparing the group state hash from {sender} of epoch {}, as the local epoch is {local_epoch}",
+            hash_action.epoch
+        );
+        return None;
+    }
+    let local_hash = client_data.get_shared_state(comm_grp).stable_hash();
+    if local_hash == hash_action.gov_state_hash {
+        debug!("The group state hash from {sender} matches the local one");
+        return None;
+    }
+    let description = format!(
+        "The group state of {:?} diverged from [{sender}]'s at epoch {local_epoch}: local hash {local_hash}, theirs {}",
+        comm_grp, hash_action.gov_state_hash
+    );
+    warn!("!!! {description} !!!");
+    Some(ClientParsedMsg::Invalid {
+        external_error: true,
+        retry_possible: false,
+        description,
+    })
+}
+
+/// Ask a single existing `member` of a group just joined for its group state,
+/// so that it does not have to be broadcast to the whole group
+#[cfg(feature = "gov")]
+pub fn request_gov_state_msg(
+    comm_grp: &CommGroupId,
+    member: &str,
+    backend: &mut CryptoBackend,
+    client_data: &mut (impl ClientDataProvider + ?Sized),
+) -> Vec<OnWireMessage> {
+    let private_message = UnorderedPrivateMessage {
+        sender: client_data.get_user_id(),
+        content: UnorderedMsgContent::GroupStateRequest,
+        sender_timestamp: client_data.now(),
+        seq: 0,
+    };
+    let private_msg = bytes_to_group_message(
+        &client_data.get_user_id(),
+        comm_grp,
+        private_message.to_bytes(),
+        false,
+        backend,
+        client_data,
+    );
+    OnWireMessage::UserStandardSend {
+        user_msg: private_msg,
+        recipients: vec![member.to_string()],
+        // No action is pending on this request
+        identifier: None,
+        ephemeral: None,
+    }
+    .to_vec()
+}
+
+/// Answer every `GroupStateRequest` among `parsed_msgs` by sending the signed
+/// group state directly to the requester
+#[cfg(feature = "gov")]
+pub fn answer_gov_state_requests(
+    parsed_msgs: &[ClientParsedMsg],
     backend: &mut CryptoBackend,
     client_data: &mut (impl ClientDataProvider + ?Sized),
 ) -> Vec<OnWireMessage> {
-    let update = GovStateAnnouncementAction {
-        group_state: client_data.get_shared_state(comm_grp).clone(),
-        metadata: ActionMetadata::new(
-            user_name.to_string(),
-            Uuid::new_v4().to_string(),
-            comm_grp.clone(),
-        ),
-    };
-
-    let action_msg = ActionMsg::GovStateAnnouncement(update);
-    check_action_msg_and_get_mls(comm_grp, action_msg, backend, client_data)
+    let mut transfer_msgs = vec![];
+    for parsed_msg in parsed_msgs {
+        if let NewMsg {
+            private_msg:
+                UnorderedPrivateMessage {
+                    content: UnorderedMsgContent::GroupStateRequest,
+                    sender,
+                    ..
+                },
+            comm_grp,
+        } = parsed_msg
+        {
+            if !client_data.is_shared_gov_state_initialized(comm_grp)
+                || !client_data.get_group_members(comm_grp).contains(sender)
+            {
+                debug!("Cannot answer the group state request from {}", sender);
+                continue;
+            }
+            let transfer = GovStateAnnouncementAction {
+                group_state: client_data.get_shared_state(comm_grp).clone(),
+                metadata: ActionMetadata::new(
+                    client_data.get_user_id(),
+                    Uuid::new_v4().to_string(),
+                    comm_grp.clone(),
+                    client_data.now(),
+                ),
+            };
+            let private_message = UnorderedPrivateMessage {
+                sender: client_data.get_user_id(),
+                content: UnorderedMsgContent::GroupState {
+                    shared: VerifiableAction::new(
+                        ActionMsg::GovStateAnnouncement(transfer),
+                        client_data.get_keypair(),
+                    ),
+                },
+                sender_timestamp: client_data.now(),
+                seq: 0,
+            };
+            let private_msg = bytes_to_group_message(
+                &client_data.get_user_id(),
+                comm_grp,
+                private_message.to_bytes(),
+                false,
+                backend,
+                client_data,
+            );
+            transfer_msgs.push(OnWireMessage::UserStandardSend {
+                user_msg: private_msg,
+                recipients: vec![sender.to_owned()],
+                identifier: None,
+                ephemeral: None,
+            });
+        }
+    }
+    transfer_msgs
 }
 
 #[cfg(feature = "gov")]
@@ -690,6 +2318,7 @@ pub fn accept_msg(
             client_data.get_user_id(),
             Uuid::new_v4().to_string(),
             comm_grp.clone(),
+            client_data.now(),
         ),
         received_gov_state_hash,
     });
@@ -697,20 +2326,113 @@ pub fn accept_msg(
     check_action_msg_and_get_mls(comm_grp, action, backend, client_data.deref_mut())
 }
 
+/// Returns the sender of a relayed group message. A message without sender
+/// is attributed to this client if it is one it sent with a sealed sender.
+fn group_msg_sender(group_msg: &GroupMessage, client_data: &mut ClientRef) -> Option<String> {
+    if let Some(sender) = group_msg.get_sender() {
+        return Some(sender);
+    }
+    if client_data.pop_sealed_sent_msg_id(&group_msg.get_client_msg_id()) {
+        Some(client_data.get_user_id())
+    } else {
+        None
+    }
+}
+
+/// Runs a recorded log of the binary websocket frames received by a client,
+/// one [OnWireMessageWithMetaData] per line, through
+/// [parse_incoming_onwire_msgs], without any network and without recording
+/// timers. Lines that cannot be decoded are reported as `Invalid`.
+pub fn replay_onwire_log(
+    path: impl AsRef<Path>,
+    client_data: &mut ClientRef,
+    backend: &mut CryptoBackend,
+) -> io::Result<Vec<ClientParsedMsg>> {
+    let log = fs::read_to_string(path)?;
+    Ok(without_timers(|| {
+        let mut parsed_msgs = vec![];
+        for line in log.lines().filter(|line| !line.trim().is_empty()) {
+            match OnWireMessageWithMetaData::decode(line.as_bytes()) {
+                Ok(msg_w_meta) => parsed_msgs.extend(parse_incoming_onwire_msgs(
+                    vec![msg_w_meta.onwire_msg],
+                    client_data,
+                    backend,
+                )),
+                Err(err) => parsed_msgs.push(ClientParsedMsg::Invalid {
+                    external_error: true,
+                    retry_possible: false,
+                    description: format!("Cannot decode a recorded message: {err}"),
+                }),
+            }
+        }
+        parsed_msgs
+    }))
+}
+
+/// Checks a history written by [ClientData::export_history]: that the hash
+/// chain over its messages is unbroken, and that the exporter signed its last
+/// hash with the verification key it carries. Returns the verified export
+pub fn verify_history_export(path: impl AsRef<Path>) -> Result<HistoryExport, HistoryExportError> {
+    let export: HistoryExport = serde_json::from_str(&fs::read_to_string(path)?)?;
+    let mut prev_hash = String::new();
+    for (index, entry) in export.entries.iter().enumerate() {
+        if HistoryExportEntry::chain_hash(&prev_hash, &entry.message) != entry.hash {
+            return Err(HistoryExportError::BrokenChain { index });
+        }
+        prev_hash = entry.hash.clone();
+    }
+    export
+        .verification_key
+        .verify(prev_hash.as_bytes(), &export.signature)
+        .map_err(|_| HistoryExportError::BadSignature)?;
+    Ok(export)
+}
+
 pub fn parse_incoming_onwire_msgs(
     msgs: Vec<OnWireMessage>,
     client_data: &mut ClientRef,
     backend: &mut CryptoBackend,
+) -> Vec<ClientParsedMsg> {
+    parse_incoming_onwire_msgs_with_sink(msgs, client_data, backend, None)
+}
+
+/// Same as [parse_incoming_onwire_msgs], but also passes the messages parsed
+/// from each incoming message to `sink`, if any, before parsing the next one
+pub fn parse_incoming_onwire_msgs_with_sink(
+    msgs: Vec<OnWireMessage>,
+    client_data: &mut ClientRef,
+    backend: &mut CryptoBackend,
+    mut sink: Option<&mut dyn ClientEventSink>,
 ) -> Vec<ClientParsedMsg> {
     let mut local_plain_msgs = vec![];
+    #[cfg(feature = "tracing")]
+    let user_name = client_data.get_user_id();
     for onwire_msg in &msgs {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!(
+            "parse_onwire_msg",
+            user = user_name.as_str(),
+            variant = onwire_msg.variant_name(),
+        )
+        .entered();
         let parse_begin_timestamp = Instant::now();
+        let parsed_before = local_plain_msgs.len();
         match onwire_msg {
-            OnWireMessage::DSRelayedUserWelcome {
-                comm_grp,
-                sender,
-                welcome,
+            OnWireMessage::DSRelayedUserMsg {
+                user_msg:
+                    GroupMessage::Welcome {
+                        comm_grp,
+                        sender,
+                        welcome,
+                        ..
+                    },
+                ..
             } => {
+                if client_data.get_ref_group(comm_grp).is_some() {
+                    // E.g., the welcome of an invite not accepted yet, delivered again
+                    debug!("Skipping a welcome to {:?}, already joined", comm_grp);
+                    continue;
+                }
                 if let Ok(mls_group) = MlsGroup::new_from_welcome(
                     backend,
                     &get_default_group_config(),
@@ -738,16 +2460,28 @@ pub fn parse_incoming_onwire_msgs(
                 user_msg,
                 server_timestamp: _,
             } => local_plain_msgs.extend(match user_msg {
+                // Handled above
+                GroupMessage::Welcome { .. } => vec![],
                 GroupMessage::AppMlsMessage {
                     mls_msg,
                     comm_grp,
-                    sender,
+                    tag,
+                    ..
                 } => {
-                    if client_data.is_shared_gov_state_initialized(comm_grp) {
+                    if client_data.get_ref_group(comm_grp).is_none() {
+                        // E.g., the local state of the group was discarded to rejoin it
+                        vec![ClientParsedMsg::Invalid {
+                            external_error: true,
+                            retry_possible: false,
+                            description: "Cannot find the group of a received message.".to_string(),
+                        }]
+                    } else if client_data.is_shared_gov_state_initialized(comm_grp) {
+                        let sender = group_msg_sender(user_msg, client_data);
                         let msgs = parse_mls_message_out(
                             mls_msg.clone(),
                             comm_grp,
-                            sender.to_owned(),
+                            sender,
+                            tag.as_ref(),
                             client_data,
                             backend,
                         );
@@ -757,7 +2491,12 @@ pub fn parse_incoming_onwire_msgs(
                                 comm_grp,
                             } = msg
                             {
-                                if private_msg.sender != client_data.get_user_id() {
+                                let is_ephemeral = matches!(
+                                    private_msg.content,
+                                    UnorderedMsgContent::Ephemeral { .. }
+                                );
+                                if private_msg.sender != client_data.get_user_id() && !is_ephemeral
+                                {
                                     client_data.store_received_msg(
                                         comm_grp,
                                         &private_msg.sender,
@@ -773,13 +2512,44 @@ pub fn parse_incoming_onwire_msgs(
                     }
                 }
             }),
+            OnWireMessage::DSRelayedReinviteRequest {
+                requester,
+                comm_grp,
+            } => local_plain_msgs.push(ClientParsedMsg::ReinviteRequest {
+                requester: requester.to_owned(),
+                comm_grp: comm_grp.to_owned(),
+            }),
             OnWireMessage::DSResult {
                 request_valid,
                 explanation,
                 identifier,
                 preceding_and_sent_ordered_msgs,
+                remaining_key_packages,
                 process_time_used,
             } => {
+                if let Some(remaining) = remaining_key_packages {
+                    client_data.set_remaining_key_packages(*remaining);
+                    // The result of a sync, received after all it relayed
+                    if let Some(relayed_until) = msgs
+                        .iter()
+                        .filter_map(|msg| match msg {
+                            OnWireMessage::DSRelayedUserMsg {
+                                server_timestamp, ..
+                            } => Some(*server_timestamp),
+                            _ => None,
+                        })
+                        .max()
+                    {
+                        client_data.set_relayed_until(relayed_until);
+                    }
+                }
+                // Answered, whether valid or not, so not to be resent
+                if let Some(group_id_str) = identifier {
+                    confirm_oldest_unsent_msg(group_id_str, client_data);
+                }
+                for group_msg in preceding_and_sent_ordered_msgs {
+                    client_data.confirm_sent_msg(group_msg.get_client_msg_id());
+                }
                 if !request_valid {
                     // DS says Invalid
                     debug!("Process received DS err: {:?}", explanation);
@@ -787,12 +2557,10 @@ pub fn parse_incoming_onwire_msgs(
                         // DS says invalid | Identifier Available
                         let comm_grp = CommGroupId::from_string(&group_id_str);
                         let clear_commit_timestamp = Instant::now();
-                        client_data
-                            .get_ref_group(&comm_grp)
-                            .unwrap()
-                            .borrow_mut()
-                            .clear_pending_commit();
-                        client_data.pop_pending_action(&comm_grp);
+                        if let Some(group) = client_data.get_ref_group(&comm_grp) {
+                            group.borrow_mut().clear_pending_commit();
+                        }
+                        client_data.pop_pending_actions(&comm_grp);
                         SingleTimeMeasurement::new(
                             OpenMlsGroupOperation,
                             clear_commit_timestamp.elapsed(),
@@ -807,29 +2575,44 @@ pub fn parse_incoming_onwire_msgs(
                         if let Some(group_id_str) = identifier.clone() {
                             // DS says valid | No message echoed | Identifier Available
                             let comm_grp = CommGroupId::from_string(&group_id_str);
-
-                            if let Some(action) = client_data.pop_pending_action(&comm_grp) {
+                            // A sealed ordered message awaits its echo by a
+                            // sync, which merges the commit and its actions
+                            let awaits_echo = client_data
+                                .get_ref_group(&comm_grp)
+                                .map_or(false, |group| group.borrow().pending_commit().is_some());
+
+                            let action = match awaits_echo {
+                                true => None,
+                                false => client_data.pop_pending_action(&comm_grp),
+                            };
+                            if let Some(action) = action {
                                 // DS says valid | No message echoed | Identifier Available | Action found
-                                policy_check_and_execute(action, &comm_grp, None, client_data);
+                                if let ActionMsg::FlagMessage(flag_action) = action {
+                                    flag_check_and_execute(flag_action, &comm_grp, client_data);
+                                } else {
+                                    policy_check_and_execute(action, &comm_grp, None, client_data);
+                                }
                             }
                         }
                     } else {
                         // DS says valid | Sent messages echoed back
                         for group_msg in preceding_and_sent_ordered_msgs {
-                            match group_msg {
-                                GroupMessage::AppMlsMessage {
+                            let sender = group_msg_sender(group_msg, client_data);
+                            if let GroupMessage::AppMlsMessage {
+                                comm_grp,
+                                mls_msg,
+                                tag,
+                                ..
+                            } = group_msg
+                            {
+                                local_plain_msgs.extend(parse_mls_message_out(
+                                    mls_msg.clone(),
                                     comm_grp,
                                     sender,
-                                    mls_msg,
-                                } => {
-                                    local_plain_msgs.extend(parse_mls_message_out(
-                                        mls_msg.clone(),
-                                        comm_grp,
-                                        sender.clone(),
-                                        client_data,
-                                        backend,
-                                    ));
-                                }
+                                    tag.as_ref(),
+                                    client_data,
+                                    backend,
+                                ));
                             }
                         }
                     }
@@ -858,35 +2641,122 @@ pub fn parse_incoming_onwire_msgs(
 
             OnWireMessage::UserKeyPackagesForDS { .. }
             | OnWireMessage::UserRegisterForAS { .. }
+            | OnWireMessage::UserRotateKey { .. }
             | OnWireMessage::UserCredentialLookup { .. }
             | OnWireMessage::UserKeyPackageLookup { .. }
             | OnWireMessage::UserSyncCredentials { .. }
+            | OnWireMessage::ASPublicKeyRequest
+            | OnWireMessage::ASIssueToken { .. }
+            | OnWireMessage::UserWhoAmI { .. }
+            | OnWireMessage::UserStorePrefs { .. }
+            | OnWireMessage::UserLoadPrefs { .. }
+            | OnWireMessage::ASRevokeCredential { .. }
             | OnWireMessage::UserSync { .. }
+            | OnWireMessage::UserPoll { .. }
             | OnWireMessage::UserInvite { .. }
             | OnWireMessage::UserStandardSend { .. }
-            | OnWireMessage::UserReliableSend { .. } => panic!("Received user requests at client"),
-            OnWireMessage::ASCredentialResponse { .. } => (),
+            | OnWireMessage::UserReliableSend { .. }
+            | OnWireMessage::UserRequestReinvite { .. }
+            | OnWireMessage::UserAcceptedInvite { .. }
+            | OnWireMessage::DSAuditQuery { .. } => {
+                panic!("Received user requests at client")
+            }
+            OnWireMessage::ASCredentialResponse { .. }
+            | OnWireMessage::ASWhoAmIResponse { .. }
+            | OnWireMessage::ASPrefsResponse { .. } => (),
+            OnWireMessage::ASPublicKeyResponse { public_key } => {
+                match client_data.get_as_public_key() {
+                    None => client_data.set_as_public_key(*public_key),
+                    Some(pinned_key) if pinned_key != *public_key => {
+                        warn!("The AS sent a public key different from the pinned one, ignoring it")
+                    }
+                    Some(_) => (),
+                }
+            }
             OnWireMessage::ASCredentialSyncResponse { credentials } => {
-                client_data.set_credential_entries(credentials.clone());
+                client_data.merge_credential_entries(credentials.clone(), false);
             }
-            OnWireMessage::DSKeyPackageResponse { .. } => (),
+            OnWireMessage::ASToken { token } => client_data.set_ds_token(token.clone()),
+            OnWireMessage::DSKeyPackageResponse { .. }
+            | OnWireMessage::DSAuditResponse { .. }
+            | OnWireMessage::DSPendingCounts { .. } => (),
         }
         SingleTimeMeasurement::new(
             ParseIncomingSingleMsgNonKpFetch,
             parse_begin_timestamp.elapsed(),
         );
+        if let Some(sink) = sink.as_deref_mut() {
+            for parsed_msg in &local_plain_msgs[parsed_before..] {
+                parsed_msg.notify(sink);
+            }
+        }
     }
     local_plain_msgs
 }
 
+/// Checks a received message against the group and sender the DS relayed
+/// it with: the MLS group of the message must be that of `comm_grp`, and
+/// its tag must be signed by `sender` over both. Sealed messages, without a
+/// sender, are only checked against the group. Untagged messages, and those
+/// from senders whose verification key is unknown, are rejected.
+pub(crate) fn check_group_msg_tag(
+    mls_msg: &MlsMessageOut,
+    comm_grp: &CommGroupId,
+    sender: Option<&String>,
+    tag: Option<&Signature>,
+    client_data: &(impl ClientDataProvider + ?Sized),
+) -> Result<(), ClientParsedMsg> {
+    let invalid = |description: String| ClientParsedMsg::Invalid {
+        external_error: true,
+        retry_possible: false,
+        description,
+    };
+    if mls_msg.group_id() != &GroupId::from_slice(comm_grp.group_id().as_bytes()) {
+        return Err(invalid(format!(
+            "A message relayed for group {} belongs to another group",
+            comm_grp.group_id()
+        )));
+    }
+    let sender = match sender {
+        Some(sender) => sender,
+        None => return Ok(()),
+    };
+    let public_key = if *sender == client_data.get_user_id() {
+        client_data.get_keypair().public
+    } else {
+        match client_data.get_user_verify_key(sender) {
+            Some(public_key) => public_key,
+            None => {
+                return Err(invalid(format!(
+                    "Public key not found for {sender}, cannot check the tag of their message"
+                )))
+            }
+        }
+    };
+    match tag {
+        Some(tag) if GroupMessage::verify_tag(comm_grp, sender, mls_msg, tag, &public_key) => {
+            Ok(())
+        }
+        _ => Err(invalid(format!(
+            "The tag of a message from {sender} does not match the group or sender it was relayed with"
+        ))),
+    }
+}
+
 #[cfg(feature = "gov")]
 fn parse_mls_message_out(
     mls_msg: MlsMessageOut,
     comm_grp: &CommGroupId,
     sender: Option<String>,
+    tag: Option<&Signature>,
     client_data: &mut ClientRef,
     backend: &mut CryptoBackend,
 ) -> Vec<ClientParsedMsg> {
+    if let Err(invalid) =
+        check_group_msg_tag(&mls_msg, comm_grp, sender.as_ref(), tag, &**client_data)
+    {
+        return vec![invalid];
+    }
     let mls_msg = MlsMessageIn::from(mls_msg);
     let mut local_plain_msgs = vec![];
     let own_user_name = client_data.get_user_id();
@@ -918,14 +2788,14 @@ fn parse_mls_message_out(
                 {
                     // Self sent message | Wrong epoch
                     debug!("Self sent message | Wrong epoch");
-                    local_plain_msgs.push(ClientParsedMsg::Invalid {
-                        external_error: false,
-                        retry_possible: true,
-                        description: "The action you just attempted did not go through because there was other valid actions preceding it. Please try again".to_string(),
+                    local_plain_msgs.push(ClientParsedMsg::EpochMismatch {
+                        comm_grp: comm_grp.clone(),
+                        expected: local_epoch.as_u64(),
+                        got: mls_msg.epoch().as_u64(),
+                        self_sent: true,
                     });
-                    //error!("The action you just attempted did not go through because there was other valid actions preceding it. Please try again");
                     mls_group_ref.borrow_mut().clear_pending_commit();
-                    client_data.pop_pending_action(comm_grp);
+                    client_data.pop_pending_actions(comm_grp);
                     SingleTimeMeasurement::new(
                         OpenMlsGroupOperation,
                         get_ref_group_timestamp.elapsed(),
@@ -933,14 +2803,21 @@ fn parse_mls_message_out(
                 } else {
                     // Self sent message | Correct epoch
                     debug!("Self sent message | Correct epoch");
-                    let action_popped = client_data.pop_pending_action(comm_grp);
+                    let actions_popped = client_data.pop_pending_actions(comm_grp);
                     let mut commit_ord_priv_msg: Option<OrderedPrivateMessage> = None;
-                    if let Some(pending_commit) = client_data
-                        .get_ref_group(comm_grp)
-                        .unwrap()
-                        .borrow_mut()
-                        .pending_commit()
-                    {
+                    let mut removed_members: Vec<String> = vec![];
+                    let group = client_data.get_ref_group(comm_grp).unwrap().borrow();
+                    if let Some(pending_commit) = group.pending_commit() {
+                        removed_members = pending_commit
+                            .remove_proposals()
+                            .filter_map(|queued_prop| {
+                                get_key_package_ref_identity(
+                                    group.members(),
+                                    queued_prop.remove_proposal().removed(),
+                                    backend,
+                                )
+                            })
+                            .collect();
                         let mut ord_priv_msgs: Vec<Option<OrderedPrivateMessage>> = pending_commit
                             .ord_app_msg_proposals()
                             .map(|queued_prop| {
@@ -951,78 +2828,91 @@ fn parse_mls_message_out(
                             commit_ord_priv_msg = ord_priv_msgs.remove(0);
                         }
                     }
-                    match action_popped {
-                        Some(action) => {
-                            // Self sent message | Correct epoch | Locally Stored Action Exists
-                            debug!(
-                                "Self sent message | Correct epoch | Locally Stored Action Exists"
-                            );
-                            if exists_pending_commit {
-                                if let Some(OrderedPrivateMessage {
+                    drop(group);
+                    if !actions_popped.is_empty() {
+                        // Self sent message | Correct epoch | Locally Stored Action Exists
+                        debug!("Self sent message | Correct epoch | Locally Stored Action Exists");
+                        if exists_pending_commit {
+                            match commit_ord_priv_msg {
+                                Some(OrderedPrivateMessage {
                                     content:
                                         OrderedMsgContent::Action(VerifiableAction {
                                             action: act_msg,
                                             signature: _,
                                         }),
-                                }) = commit_ord_priv_msg
-                                {
-                                    assert_eq!(act_msg, action, "The stored pending action was not the same as the action in the pending commit");
+                                }) => {
+                                    assert_eq!(vec![act_msg], actions_popped, "The stored pending action was not the same as the action in the pending commit");
+                                    let action = actions_popped.into_iter().next().unwrap();
                                     policy_check_and_execute(action, comm_grp, None, client_data);
                                 }
-                            } else {
-                                debug!(
-                                    "*Found locally saved action but cannot find the local commit"
-                                );
+                                Some(OrderedPrivateMessage {
+                                    content: OrderedMsgContent::ActionBatch(verif_actions),
+                                }) => {
+                                    let committed_actions: Vec<ActionMsg> = verif_actions
+                                        .iter()
+                                        .map(|verif_action| verif_action.action.clone())
+                                        .collect();
+                                    assert_eq!(committed_actions, actions_popped, "The stored pending actions were not the same as the actions in the pending commit");
+                                    batch_check_and_execute(
+                                        verif_actions,
+                                        comm_grp,
+                                        None,
+                                        client_data,
+                                    );
+                                }
+                                _ => debug!("The pending commit does not carry any action"),
                             }
+                        } else {
+                            debug!("*Found locally saved action but cannot find the local commit");
+                        }
+                    } else {
+                        // Self sent message | Correct epoch | NO Locally Stored Action
+                        debug!("Self sent message | Correct epoch | NO Locally Stored Action");
+                        // Inspect staged commit to extract ordered app messages
+                        // Clear those actions
+                        if let Some(OrderedPrivateMessage {
+                            content: OrderedMsgContent::ActionVec(proposed_actions),
+                        }) = commit_ord_priv_msg
+                        {
+                            info!("Clearing proposed actions for self-sent message");
+                            client_data.remove_proposed_actions(comm_grp, &proposed_actions);
+                            evaluate_proposed_actions(
+                                proposed_actions,
+                                comm_grp,
+                                None,
+                                client_data,
+                            );
+                        } else {
+                            debug!("No locally saved action or pending commit");
                         }
-                        None => {
-                            // Self sent message | Correct epoch | NO Locally Stored Action
-                            debug!("Self sent message | Correct epoch | NO Locally Stored Action");
-                            // Inspect staged commit to extract ordered app messages
-                            // Clear those actions
-                            if let Some(OrderedPrivateMessage {
-                                content: OrderedMsgContent::ActionVec(proposed_actions),
-                            }) = commit_ord_priv_msg
-                            {
-                                info!("Clearing proposed actions for self-sent message");
-                                client_data.remove_proposed_actions(comm_grp, &proposed_actions);
-                                evaluate_proposed_actions(
-                                    proposed_actions,
-                                    comm_grp,
-                                    None,
-                                    client_data,
-                                );
-                            } else {
-                                debug!("No locally saved action or pending commit");
-                            }
-
-                            client_data
-                                .get_ref_group(comm_grp)
-                                .unwrap()
-                                .borrow_mut()
-                                .merge_pending_commit()
-                                .expect(
-                                    "Cannot merge pending commit, and also the action is missing",
-                                );
 
-                            // TODO: check that this is right place to handle this
-                            if exists_pending_commit {
-                                let proposed_actions = client_data.get_proposed_actions(comm_grp);
-                                info!("Clearing proposed actions for self-sent message");
-                                client_data.clear_proposed_actions(comm_grp);
-                                evaluate_proposed_actions(
-                                    proposed_actions,
-                                    comm_grp,
-                                    None,
-                                    client_data,
-                                );
-                            } else {
-                                debug!("No locally saved action or pending commit");
-                            }
+                        client_data
+                            .get_ref_group(comm_grp)
+                            .unwrap()
+                            .borrow_mut()
+                            .merge_pending_commit()
+                            .expect("Cannot merge pending commit, and also the action is missing");
+                        for removed in &removed_members {
+                            client_data.forget_removed_member(comm_grp, removed);
+                        }
 
-                            warn!("(Ignore if you just sent an Add/Leave/Remove/Accept/Decline) Cannot find a saved action after DS response for that group. Merged anyway.");
+                        // TODO: check that this is right place to handle this
+                        if exists_pending_commit {
+                            let proposed_actions = client_data.get_proposed_actions(comm_grp);
+                            info!("Clearing proposed actions for self-sent message");
+                            client_data.clear_proposed_actions(comm_grp);
+                            evaluate_proposed_actions(
+                                proposed_actions,
+                                comm_grp,
+                                None,
+                                client_data,
+                            );
+                        } else {
+                            debug!("No locally saved action or pending commit");
                         }
-                    };
+
+                        warn!("(Ignore if you just sent an Add/Leave/Remove/Accept/Decline) Cannot find a saved action after DS response for that group. Merged anyway.");
+                    }
                 }
             } else {
                 // Message from other group member
@@ -1032,10 +2922,11 @@ fn parse_mls_message_out(
                 {
                     // Message from other group member | Wrong epoch
                     debug!("Message from other group member | Wrong epoch");
-                    local_plain_msgs.push(ClientParsedMsg::Invalid {
-                        external_error: true,
-                        retry_possible: false,
-                        description: "An action by other group member was discarded because it had Wrong Epoch".to_string(),
+                    local_plain_msgs.push(ClientParsedMsg::EpochMismatch {
+                        comm_grp: comm_grp.clone(),
+                        expected: local_epoch.as_u64(),
+                        got: mls_msg.epoch().as_u64(),
+                        self_sent: false,
                     });
                 } else {
                     // Message from other group member | Correct epoch
@@ -1045,10 +2936,8 @@ fn parse_mls_message_out(
                     //.expect("Cannot parse incoming MlsGroup Message");
                     let unverified = if unverified_r.is_err() {
                         debug!("Unable to decrypt a message");
-                        return vec![ClientParsedMsg::Invalid {
-                            external_error: true,
-                            retry_possible: false,
-                            description: "Unable to decrypt an incoming message".to_string(),
+                        return vec![ClientParsedMsg::DecryptionFailed {
+                            comm_grp: comm_grp.clone(),
                         }];
                     } else {
                         unverified_r.unwrap()
@@ -1097,12 +2986,29 @@ fn parse_mls_message_out(
                                     .expect("Do not have the public key locally");
                                 if text_action.verify(public_key) {
                                     info!("signature is valid");
-                                    policy_check_and_execute(
-                                        text_action.action.clone(),
-                                        comm_grp,
-                                        None,
-                                        client_data,
-                                    );
+                                    if let ActionMsg::GovStateHash(hash_action) =
+                                        &text_action.action
+                                    {
+                                        local_plain_msgs.extend(check_gov_state_hash(
+                                            hash_action,
+                                            client_data.deref_mut(),
+                                        ));
+                                    } else if let ActionMsg::FlagMessage(flag_action) =
+                                        &text_action.action
+                                    {
+                                        flag_check_and_execute(
+                                            flag_action.clone(),
+                                            comm_grp,
+                                            client_data,
+                                        );
+                                    } else {
+                                        policy_check_and_execute(
+                                            text_action.action.clone(),
+                                            comm_grp,
+                                            None,
+                                            client_data,
+                                        );
+                                    }
                                 } else {
                                     debug!("invalid signature");
                                 }
@@ -1112,11 +3018,35 @@ fn parse_mls_message_out(
                                 // TODO: filter for votes
                                 client_data
                                     .store_proposed_action(comm_grp, proposed_action.clone());
+                            } else if let UnorderedMsgContent::GroupState { shared } =
+                                &private_msg.content
+                            {
+                                load_gov_state_transfer(
+                                    shared,
+                                    &private_msg.sender,
+                                    comm_grp,
+                                    client_data,
+                                );
+                            }
+                        }
+                        ProcessedMessage::ProposalMessage(proposal_wrapped) => {
+                            match join_proposal_rejection(&proposal_wrapped, comm_grp, client_data)
+                            {
+                                Some(description) => {
+                                    info!("{}", description);
+                                    local_plain_msgs.push(ClientParsedMsg::Invalid {
+                                        external_error: true,
+                                        retry_possible: false,
+                                        description,
+                                    });
+                                }
+                                None => client_data
+                                    .get_ref_group(comm_grp)
+                                    .unwrap()
+                                    .borrow_mut()
+                                    .store_pending_proposal(*proposal_wrapped),
                             }
                         }
-                        ProcessedMessage::ProposalMessage(proposal_wrapped) => mls_group_ref
-                            .borrow_mut()
-                            .store_pending_proposal(*proposal_wrapped),
                         ProcessedMessage::StagedCommitMessage(staged_commit) => {
                             // Message from other group member | Correct epoch | Ordered
                             debug!("Message from other group member | Correct epoch | Ordered");
@@ -1188,6 +3118,10 @@ fn parse_mls_message_out(
                                         // Self was removed from the group
                                         info!("You were removed from group {:?}", comm_grp);
                                         client_data.remove_group(comm_grp);
+                                    } else {
+                                        for removed in &to_removed {
+                                            client_data.forget_removed_member(comm_grp, removed);
+                                        }
                                     }
                                 } else {
                                     debug!("An ordered message by other group member was not processed because it had no actions");
@@ -1233,16 +3167,23 @@ fn parse_mls_message_out(
                                             client_data,
                                         );
                                         break;
+                                    } else if let Some(OrderedPrivateMessage {
+                                        content: OrderedMsgContent::ActionBatch(action_vec),
+                                    }) = action_opt
+                                    {
+                                        batch_check_and_execute(
+                                            action_vec,
+                                            comm_grp,
+                                            Some(staged_commit),
+                                            client_data,
+                                        );
+                                        break;
                                     }
                                 }
                             };
-                            let mut hasher = DefaultHasher::new();
-                            serde_json::to_string(client_data.get_shared_state(comm_grp))
-                                .expect("Cannot serialize group state")
-                                .hash(&mut hasher);
                             info!(
                                 "Group state hash after the ordered update: {:?}",
-                                hasher.finish()
+                                client_data.get_shared_state(comm_grp).stable_hash()
                             );
                         }
                     }
@@ -1253,6 +3194,122 @@ fn parse_mls_message_out(
     local_plain_msgs
 }
 
+/// Why a standalone proposal to add a member must be dropped, if it must.
+/// Members add invitees within their own commits, so such a proposal is a
+/// request to join the group, e.g., sent from outside of it. The pinned
+/// OpenMLS has no dedicated external join message, so these requests are
+/// told apart by their content. The joiner must not be banned, and must have
+/// been pre-approved by an invite, which RBAC and the group's policies
+/// authorized. Other proposals are kept as they are.
+#[cfg(feature = "gov")]
+fn join_proposal_rejection(
+    proposal: &QueuedProposal,
+    comm_grp: &CommGroupId,
+    client_data: &ClientRef,
+) -> Option<String> {
+    let add_proposal = match proposal.proposal() {
+        Proposal::Add(add_proposal) => add_proposal,
+        _ => return None,
+    };
+    let joiner = match identity_to_str(add_proposal.key_package().credential().identity()) {
+        Some(joiner) => joiner,
+        None => return Some("Dropped a join request with an unreadable identity".to_string()),
+    };
+    if client_data.is_banned(comm_grp, &joiner) {
+        Some(format!(
+            "Dropped a join request of {} as they are banned from the group",
+            joiner
+        ))
+    } else if !client_data
+        .get_shared_state(comm_grp)
+        .to_add_invitees
+        .contains_key(&joiner)
+    {
+        Some(format!(
+            "Dropped a join request of {} as they were not invited to the group",
+            joiner
+        ))
+    } else {
+        None
+    }
+}
+
+/// Initialize the group state from a `GroupState` sent directly by `sender`,
+/// provided it is a group state announcement signed by that same sender
+#[cfg(feature = "gov")]
+fn load_gov_state_transfer(
+    shared: &VerifiableAction,
+    sender: &str,
+    comm_grp: &CommGroupId,
+    client_data: &mut ClientRef,
+) {
+    if client_data.is_shared_gov_state_initialized(comm_grp) {
+        debug!("Ignoring a group state from {sender} as the group state is already initialized");
+        return;
+    }
+    if !matches!(shared.action, ActionMsg::GovStateAnnouncement(_))
+        || shared.action.get_metadata().sender != sender
+    {
+        info!("Ignoring a malformed group state from {sender}");
+        return;
+    }
+    match client_data.get_user_verify_key(sender) {
+        Some(public_key) if shared.verify(public_key) => {
+            policy_check_and_execute(shared.action.clone(), comm_grp, None, client_data);
+        }
+        Some(_) => info!("Ignoring a group state from {sender} with an invalid signature"),
+        None => info!("Public key not found for {sender}, who sent a group state"),
+    }
+}
+
+/// Check authorization and execute a flag. If a flag of this client brings
+/// the message to the group's flag takedown threshold, a takedown of the
+/// message is queued in the outbox, to be signed and sent like any other
+/// action, and authorized by each member.
+fn flag_check_and_execute(
+    flag_action: FlagMessageAction,
+    comm_grp: &CommGroupId,
+    client_data: &mut ClientRef,
+) {
+    let count_flags = |client_data: &ClientRef| {
+        client_data
+            .get_flags(comm_grp)
+            .get(&flag_action.message_id)
+            .map_or(0, |flaggers| flaggers.len())
+    };
+    let flags_before = count_flags(client_data);
+    policy_check_and_execute(
+        ActionMsg::FlagMessage(flag_action.clone()),
+        comm_grp,
+        None,
+        client_data,
+    );
+    let flags_after = count_flags(client_data);
+
+    if flag_action.metadata.sender != client_data.get_user_id() {
+        return;
+    }
+    if let Some(threshold) = client_data.get_flag_takedown_threshold(comm_grp) {
+        if flags_before < threshold && flags_after >= threshold {
+            info!(
+                "Message {} got {} flags, proposing to take it down",
+                flag_action.message_id, flags_after
+            );
+            let takedown_action = ActionMsg::TakedownTextMsg(TakedownTextMsgAction {
+                message_id: flag_action.message_id.to_owned(),
+                reason: format!("Flagged by {} members", flags_after),
+                metadata: ActionMetadata::new(
+                    client_data.get_user_id(),
+                    Uuid::new_v4().to_string(),
+                    comm_grp.to_owned(),
+                    client_data.now(),
+                ),
+            });
+            client_data.push_outbox_action(comm_grp, takedown_action);
+        }
+    }
+}
+
 /// Check authorization and execute the action, then merge the (staged (hence external)) commit.
 /// if the commit is `None`, then merge the pending (hence self-init'ed) commit
 #[cfg(feature = "gov")]
@@ -1262,8 +3319,16 @@ fn policy_check_and_execute(
     commit: Option<Box<StagedCommit>>,
     client_data: &mut ClientRef,
 ) {
+    if let ActionMsg::Unknown(unknown_action) = &action {
+        // Neither authorized nor evaluated, as what it does is unknown
+        unknown_action.execute(client_data.deref_mut());
+        merge_commit_opt(client_data, comm_grp, commit);
+        return;
+    }
     let pre_auth_timestamp = Instant::now();
-    if client_data.action_authorized(&action) {
+    // Votes and the closing of votes only count towards a policy
+    let is_vote = matches!(action, ActionMsg::Vote(_) | ActionMsg::ClosePoll(_));
+    if !is_vote && client_data.action_authorized(&action) {
         SingleTimeMeasurement::new(MlsGovRBACCheck, pre_auth_timestamp.elapsed());
 
         action.execute(client_data.deref_mut());
@@ -1293,10 +3358,10 @@ fn policy_check_and_execute(
         // Evaluate this action
         let mut policy_eng_mut = policy_engine_ref.borrow_mut();
 
-        policy_eng_mut.evaluate_action(action, client_data);
+        evaluate_and_log_action(&mut policy_eng_mut, action, comm_grp, client_data);
 
         // Evaluate all proposed actions
-        policy_eng_mut.evaluate_all_proposed_actions(client_data);
+        evaluate_all_and_unlog_rejected(&mut policy_eng_mut, comm_grp, client_data);
 
         SingleTimeMeasurement::new(MlsGovPolicyEngineCheck, policy_engine_timestamp.elapsed());
 
@@ -1332,7 +3397,12 @@ fn evaluate_proposed_actions(
             client_data.get_user_verify_key(&verifiable_action.action.get_metadata().sender)
         {
             if verifiable_action.verify(public_key) {
-                policy_eng_mut.evaluate_action(verifiable_action.action, client_data);
+                evaluate_and_log_action(
+                    &mut policy_eng_mut,
+                    verifiable_action.action,
+                    comm_grp,
+                    client_data,
+                );
             }
         } else {
             info!(
@@ -1343,7 +3413,7 @@ fn evaluate_proposed_actions(
     }
 
     // Evaluate all proposed actions
-    policy_eng_mut.evaluate_all_proposed_actions(client_data);
+    evaluate_all_and_unlog_rejected(&mut policy_eng_mut, comm_grp, client_data);
 
     SingleTimeMeasurement::new(MlsGovPolicyEngineCheck, policy_engine_timestamp.elapsed());
 
@@ -1351,6 +3421,112 @@ fn evaluate_proposed_actions(
     merge_commit_opt(client_data, comm_grp, commit);
 }
 
+/// Evaluates `action` with the policy engine of the group, then records it
+/// in the policy action log if a policy decided on it and the engine did not
+/// reject it, so that [replay_policies] only audits the actions that passed
+/// or may still pass
+#[cfg(feature = "gov")]
+fn evaluate_and_log_action(
+    policy_engine: &mut PolicyEngine,
+    action: ActionMsg,
+    comm_grp: &CommGroupId,
+    client_data: &mut ClientRef,
+) {
+    let action_id = action.get_metadata().action_id;
+    // Checked before the evaluation, which may change the group state
+    let matched = policy_engine.has_matching_policy(&action, client_data);
+    let rejected_action_ids = policy_engine.evaluate_action(action.clone(), client_data);
+    if matched && !rejected_action_ids.contains(&action_id) {
+        client_data.log_policy_action(comm_grp, action);
+    }
+    client_data.unlog_policy_actions(comm_grp, &rejected_action_ids);
+}
+
+/// Evaluates all proposed actions of the group again, and drops those the
+/// policy engine rejected from the policy action log
+#[cfg(feature = "gov")]
+fn evaluate_all_and_unlog_rejected(
+    policy_engine: &mut PolicyEngine,
+    comm_grp: &CommGroupId,
+    client_data: &mut ClientRef,
+) {
+    let rejected_action_ids = policy_engine.evaluate_all_proposed_actions(client_data);
+    client_data.unlog_policy_actions(comm_grp, &rejected_action_ids);
+}
+
+/// Re-runs the policy audit log of a group through a fresh policy engine made of
+/// the client's current policies, returning the actions that would not pass under them.
+/// The replay happens on a copy of the client data, so the actual state is untouched.
+/// Policies checking the group state, e.g., roles or members, are checked
+/// against the current state rather than the state at the time of each
+/// action, as the log does not keep the latter. The log only holds the
+/// latest [MAX_POLICY_ACTION_LOG_LEN](client_struct_impl::MAX_POLICY_ACTION_LOG_LEN)
+/// actions that were not rejected, and no disappearing text messages.
+pub fn replay_policies(
+    comm_grp: &CommGroupId,
+    client_data: &(impl ClientDataProvider + ?Sized),
+) -> Result<Vec<ActionMsg>, ClientError> {
+    let scratch_data: ClientData =
+        serde_json::from_str(&client_data.to_string()).map_err(CannotCopyClientData)?;
+    let mut scratch_data = Box::new(scratch_data) as ClientRef;
+
+    let policy_refs = client_data
+        .get_client_policies()
+        .into_iter()
+        .map(|policy| Rc::new(RefCell::new(policy)))
+        .collect();
+    let mut policy_engine = PolicyEngine::new(policy_refs);
+
+    Ok(policy_engine.replay_actions(
+        client_data.get_policy_action_log(comm_grp),
+        &mut scratch_data,
+    ))
+}
+
+/// Check authorization and execute each of the actions committed together
+/// with [commit_actions], in order, handing those not authorized to the
+/// policy engine, then merge the commit once. If the commit is `None`, then
+/// merge the pending (hence self-init'ed) commit
+#[cfg(feature = "gov")]
+fn batch_check_and_execute(
+    action_vec: Vec<VerifiableAction>,
+    comm_grp: &CommGroupId,
+    commit: Option<Box<StagedCommit>>,
+    client_data: &mut ClientRef,
+) {
+    let policy_engine_ref = client_data.get_policy_engine_ref_clone(comm_grp);
+    for verifiable_action in action_vec {
+        let verified = client_data
+            .get_user_verify_key(&verifiable_action.action.get_metadata().sender)
+            .map_or(false, |public_key| verifiable_action.verify(public_key));
+        if !verified {
+            info!("An action committed along with others was invalid, so it is skipped");
+            continue;
+        }
+        let action = verifiable_action.action;
+        if let ActionMsg::Unknown(unknown_action) = &action {
+            // Neither authorized nor evaluated, as what it does is unknown
+            unknown_action.execute(client_data.deref_mut());
+            continue;
+        }
+        // Votes and the closing of votes only count towards a policy
+        let is_vote = matches!(action, ActionMsg::Vote(_) | ActionMsg::ClosePoll(_));
+        if !is_vote && client_data.action_authorized(&action) {
+            action.execute(client_data.deref_mut());
+            info!("An action of type {:?} went through", action.action_type());
+        } else {
+            evaluate_and_log_action(
+                &mut policy_engine_ref.borrow_mut(),
+                action,
+                comm_grp,
+                client_data,
+            );
+        }
+    }
+    evaluate_all_and_unlog_rejected(&mut policy_engine_ref.borrow_mut(), comm_grp, client_data);
+    merge_commit_opt(client_data, comm_grp, commit);
+}
+
 fn merge_commit_opt(
     client_data: &mut ClientRef,
     comm_grp: &CommGroupId,
@@ -1375,5 +3551,6 @@ fn merge_commit_opt(
                 .expect("Cannot merge staged commit");
         }
     }
+    client_data.store_gov_state_snapshot(comm_grp);
     SingleTimeMeasurement::new(OpenMlsGroupOperation, timestamp.elapsed());
 }
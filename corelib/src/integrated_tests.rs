@@ -79,26 +79,31 @@ mod integrated_test {
                 &comm_grp(),
                 &mut admin.backend,
                 admin.configs.deref_mut(),
-            ),
+            )
+            .unwrap(),
             client_api::pre_add_invite_msg(
                 &admin.name(),
                 &comm_grp(),
                 &mut admin.backend,
                 admin.configs.deref_mut(),
                 invitee.new_key_package(),
-            ),
+            )
+            .0,
         ]);
         admin
             .send_all_assert_ok(admin_for_ds_msgs1, &ds_state)
             .await;
 
         // Admin adds invitee
-        let admin_for_ds_msgs2 = flatten(vec![client_api::add_msg(
-            &comm_grp(),
-            &invitee.name(),
-            admin.configs.deref_mut(),
-            &mut admin.backend,
-        )]);
+        let admin_for_ds_msgs2 = flatten(vec![
+            client_api::add_msg(
+                &comm_grp(),
+                &invitee.name(),
+                admin.configs.deref_mut(),
+                &mut admin.backend,
+            )
+            .0,
+        ]);
         admin
             .send_all_assert_ok(admin_for_ds_msgs2, &ds_state)
             .await;
@@ -251,14 +256,16 @@ mod integrated_test {
                 &comm_grp(),
                 &mut admin.backend,
                 admin.configs.deref_mut(),
-            ),
+            )
+            .unwrap(),
             client_api::pre_add_invite_msg(
                 &admin.name(),
                 &comm_grp(),
                 &mut admin.backend,
                 admin.configs.deref_mut(),
                 invitee.new_key_package(),
-            ),
+            )
+            .0,
             sync_msg(admin.name(), admin.new_kps(5)),
         ]);
 
@@ -276,12 +283,15 @@ mod integrated_test {
         admin.sync_as_assert_ok(&as_state).await;
         invitee.sync_as_assert_ok(&as_state).await;
 
-        let admin_for_ds_msgs2 = flatten(vec![client_api::add_msg(
-            &comm_grp(),
-            &invitee.name(),
-            admin.configs.deref_mut(),
-            &mut admin.backend,
-        )]);
+        let admin_for_ds_msgs2 = flatten(vec![
+            client_api::add_msg(
+                &comm_grp(),
+                &invitee.name(),
+                admin.configs.deref_mut(),
+                &mut admin.backend,
+            )
+            .0,
+        ]);
         admin
             .send_all_assert_ok(admin_for_ds_msgs2, &ds_state)
             .await;
@@ -389,10 +399,7 @@ mod integrated_test {
             .await;
 
         // Assert the invitee's name is removed in admin's member list.
-        assert!(!admin
-            .configs
-            .get_group_members(&comm_grp())
-            .contains(&invitee.name));
+        assert!(!admin.configs.is_member(&comm_grp(), &invitee.name));
 
         let admin_for_ds_msgs5 = client_api::send_text_msg_mls(
             &admin.name(),
@@ -489,14 +496,16 @@ mod integrated_test {
                 &comm_grp(),
                 &mut admin.backend,
                 admin.configs.deref_mut(),
-            ),
+            )
+            .unwrap(),
             client_api::pre_add_invite_msg(
                 &admin.name(),
                 &comm_grp(),
                 &mut admin.backend,
                 admin.configs.deref_mut(),
                 invitee.new_key_package(),
-            ),
+            )
+            .0,
             sync_msg(admin.name(), admin.new_kps(1)),
         ]);
 
@@ -513,12 +522,15 @@ mod integrated_test {
         invitee.sync_as_assert_ok(&as_state).await;
         invitee2.sync_as_assert_ok(&as_state).await;
 
-        let admin_for_ds_msgs2 = flatten(vec![client_api::add_msg(
-            &comm_grp(),
-            &invitee.name(),
-            admin.configs.deref_mut(),
-            &mut admin.backend,
-        )]);
+        let admin_for_ds_msgs2 = flatten(vec![
+            client_api::add_msg(
+                &comm_grp(),
+                &invitee.name(),
+                admin.configs.deref_mut(),
+                &mut admin.backend,
+            )
+            .0,
+        ]);
         admin
             .send_all_assert_ok(admin_for_ds_msgs2, &ds_state)
             .await;
@@ -547,24 +559,30 @@ mod integrated_test {
             .send_all_assert_ok(invitees_accept_admin_invite_msgs, &ds_state)
             .await;
 
-        let admin_invites_invitee2_msgs = flatten(vec![client_api::pre_add_invite_msg(
-            &admin.name(),
-            &comm_grp(),
-            &mut admin.backend,
-            admin.configs.deref_mut(),
-            invitee2.new_key_package(),
-        )]);
+        let admin_invites_invitee2_msgs = flatten(vec![
+            client_api::pre_add_invite_msg(
+                &admin.name(),
+                &comm_grp(),
+                &mut admin.backend,
+                admin.configs.deref_mut(),
+                invitee2.new_key_package(),
+            )
+            .0,
+        ]);
         admin
             .send_all_assert_ok(admin_invites_invitee2_msgs, &ds_state)
             .await;
 
-        let invitee_invites_invitee2_msgs = flatten(vec![client_api::pre_add_invite_msg(
-            &invitee.name(),
-            &comm_grp(),
-            &mut invitee.backend,
-            invitee.configs.deref_mut(),
-            invitee2.new_key_package(),
-        )]);
+        let invitee_invites_invitee2_msgs = flatten(vec![
+            client_api::pre_add_invite_msg(
+                &invitee.name(),
+                &comm_grp(),
+                &mut invitee.backend,
+                invitee.configs.deref_mut(),
+                invitee2.new_key_package(),
+            )
+            .0,
+        ]);
         // Would not pass because conflict. Assert will panic
         for msg in invitee_invites_invitee2_msgs {
             invitee.send_and_parse(msg, &ds_state).await;
@@ -573,12 +591,15 @@ mod integrated_test {
         admin.sync_ds_assert_ok(&ds_state).await;
         invitee.sync_ds_assert_ok(&ds_state).await;
 
-        let admin_for_ds_msgs2 = flatten(vec![client_api::add_msg(
-            &comm_grp(),
-            &invitee2.name(),
-            admin.configs.deref_mut(),
-            &mut admin.backend,
-        )]);
+        let admin_for_ds_msgs2 = flatten(vec![
+            client_api::add_msg(
+                &comm_grp(),
+                &invitee2.name(),
+                admin.configs.deref_mut(),
+                &mut admin.backend,
+            )
+            .0,
+        ]);
         admin
             .send_all_assert_ok(admin_for_ds_msgs2, &ds_state)
             .await;
@@ -662,14 +683,16 @@ mod integrated_test {
                 &comm_grp(),
                 &mut admin.backend,
                 admin.configs.deref_mut(),
-            ),
+            )
+            .unwrap(),
             client_api::pre_add_invite_msg(
                 &admin.name(),
                 &comm_grp(),
                 &mut admin.backend,
                 admin.configs.deref_mut(),
                 invitee.new_key_package(),
-            ),
+            )
+            .0,
             sync_msg(admin.name(), admin.new_kps(1)),
         ]);
 
@@ -677,12 +700,15 @@ mod integrated_test {
             .send_all_assert_ok(admin_creates_and_invites_msgs, &ds_state)
             .await;
 
-        let admin_for_ds_msgs2 = flatten(vec![client_api::add_msg(
-            &comm_grp(),
-            &invitee.name(),
-            admin.configs.deref_mut(),
-            &mut admin.backend,
-        )]);
+        let admin_for_ds_msgs2 = flatten(vec![
+            client_api::add_msg(
+                &comm_grp(),
+                &invitee.name(),
+                admin.configs.deref_mut(),
+                &mut admin.backend,
+            )
+            .0,
+        ]);
 
         admin
             .send_all_assert_ok(admin_for_ds_msgs2, &ds_state)
@@ -731,7 +757,13 @@ mod integrated_test {
             &comm_grp(),
             ActionMsg::RenameGroup(RenameGroupAction {
                 new_name: "invitee_changed".to_string(),
-                metadata: ActionMetadata::new(invitee.name(), "".to_string(), comm_grp()),
+                lamport_counter: 1,
+                metadata: ActionMetadata::new(
+                    invitee.name(),
+                    "".to_string(),
+                    comm_grp(),
+                    invitee.configs.now(),
+                ),
             }),
             &mut invitee.backend,
             invitee.configs.deref_mut(),
@@ -749,7 +781,13 @@ mod integrated_test {
             &comm_grp(),
             ActionMsg::RenameGroup(RenameGroupAction {
                 new_name: "admin_changed".to_string(),
-                metadata: ActionMetadata::new(admin.name(), "".to_string(), comm_grp()),
+                lamport_counter: 1,
+                metadata: ActionMetadata::new(
+                    admin.name(),
+                    "".to_string(),
+                    comm_grp(),
+                    admin.configs.now(),
+                ),
             }),
             &mut admin.backend,
             admin.configs.deref_mut(),
@@ -1,25 +1,36 @@
 extern crate core;
 
+use std::cell::Cell;
 use std::fmt;
 use std::str::from_utf8;
 use std::time::Duration;
 
-use ed25519_dalek::Keypair;
-use log::debug;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use ed25519_dalek::{Keypair, PublicKey};
+use log::{debug, warn};
+use openmls::credentials::Credential;
 use openmls::key_packages::KeyPackage;
 use openmls::prelude::KeyPackageRef;
 use openmls_traits::OpenMlsCryptoProvider;
 use rand_07::rngs::OsRng;
 use serde::de::Visitor;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tls_codec::Serialize as _;
 
 use crate::client_api::client_crypto_impl::CryptoBackend;
 
 pub mod client_api;
+pub mod clock;
 mod integrated_tests;
 pub mod messages;
+mod messages_tests;
+#[cfg(feature = "metrics")]
+pub mod metrics;
 pub mod policyengine;
 pub mod servers_api;
+pub mod state_store;
 pub mod test_helpers;
 
 pub struct BytesVisitor;
@@ -44,6 +55,14 @@ impl<'de> Visitor<'de> for BytesVisitor {
     }
 }
 
+/// Separates the user name from the device name in the identity of one of
+/// the user's devices, e.g., `alice#phone`
+pub const DEVICE_SEPARATOR: char = '#';
+
+/// The community reserved for the hidden groups of direct messages
+pub const DM_COMMUNITY_ID: &str = "__dm__";
+
+/// Decodes an identity, keeping its device suffix, if any
 pub fn identity_to_str(identity: &[u8]) -> Option<String> {
     match from_utf8(identity) {
         Ok(name) => Some(name.to_string()),
@@ -54,6 +73,23 @@ pub fn identity_to_str(identity: &[u8]) -> Option<String> {
     }
 }
 
+/// Decodes an identity into the user name and the device name, if any
+pub fn identity_to_user_device(identity: &[u8]) -> Option<(String, Option<String>)> {
+    identity_to_str(identity).map(|name| {
+        let (user, device) = split_device(&name);
+        (user.to_string(), device.map(str::to_string))
+    })
+}
+
+/// Splits `alice#phone` into `("alice", Some("phone"))`, and `alice` into
+/// `("alice", None)`
+pub fn split_device(name: &str) -> (&str, Option<&str>) {
+    match name.split_once(DEVICE_SEPARATOR) {
+        Some((user, device)) => (user, Some(device)),
+        None => (name, None),
+    }
+}
+
 pub fn str_to_identity(name: &String) -> Vec<u8> {
     name.to_owned().into_bytes()
 }
@@ -102,6 +138,122 @@ pub fn generate_verification_key() -> Keypair {
     Keypair::generate(&mut csprng)
 }
 
+/// A short, human-comparable fingerprint of a verification key: the
+/// hex-encoded SHA-256 digest of its bytes
+pub fn verification_key_fingerprint(verification_key: &PublicKey) -> String {
+    hex_sha256(verification_key.as_bytes())
+}
+
+/// The fingerprint of the signature key of an MLS credential, in the same
+/// format as [verification_key_fingerprint], for members to compare out of
+/// band
+pub fn credential_fingerprint(credential: &Credential) -> String {
+    hex_sha256(
+        &credential
+            .signature_key()
+            .tls_serialize_detached()
+            .expect("Error serializing signature key."),
+    )
+}
+
+pub(crate) fn hex_sha256(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Encodes a public key as base64, e.g., for the AS to print the key the DS
+/// is configured with
+pub fn encode_public_key(public_key: &PublicKey) -> String {
+    BASE64.encode(public_key.as_bytes())
+}
+
+/// Decodes a public key encoded by [encode_public_key]
+pub fn decode_public_key(encoded: &str) -> Option<PublicKey> {
+    let bytes = BASE64.decode(encoded.trim()).ok()?;
+    PublicKey::from_bytes(&bytes).ok()
+}
+
+/// Why a community or group ID was rejected by [CommGroupId::try_new]
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum IdError {
+    #[error("The ID is empty")]
+    Empty,
+    #[error("The ID contains {0:?}, only ASCII letters, digits, and - _ . ~ + are allowed")]
+    UnsafeCharacter(char),
+}
+
+/// Why the config of a client or a server was rejected
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ConfigError {
+    #[error("`{field}` is not a valid URL ({value:?}): {reason}")]
+    InvalidUrl {
+        field: &'static str,
+        value: String,
+        reason: String,
+    },
+    #[error("`{field}` is not a valid IPv4 address ({value:?})")]
+    InvalidIpAddress { field: &'static str, value: String },
+    #[error("`{field}` must be greater than 0")]
+    NotPositive { field: &'static str },
+    #[error("`{field}` is not a base64-encoded public key ({value:?})")]
+    InvalidPublicKey { field: &'static str, value: String },
+    #[error("`{field}` is not a writable path ({path:?}): {reason}")]
+    UnwritablePath {
+        field: &'static str,
+        path: String,
+        reason: String,
+    },
+}
+
+/// Checks that the file at `path` can be written: either the file exists and
+/// is not read-only, or its directory does and is not read-only. Empty paths
+/// are left unchecked, as they mean the file is not used
+pub fn check_writable_path(field: &'static str, path: &str) -> Result<(), ConfigError> {
+    if path.is_empty() {
+        return Ok(());
+    }
+    let unwritable = |reason: String| ConfigError::UnwritablePath {
+        field,
+        path: path.to_string(),
+        reason,
+    };
+    let file_path = std::path::Path::new(path);
+    let checked_path = if file_path.exists() {
+        file_path
+    } else {
+        match file_path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => std::path::Path::new("."),
+        }
+    };
+    let metadata = std::fs::metadata(checked_path)
+        .map_err(|e| unwritable(format!("{}: {}", checked_path.display(), e)))?;
+    if metadata.permissions().readonly() {
+        return Err(unwritable(format!(
+            "{} is read-only",
+            checked_path.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Checks that an ID is not empty and only made of URL-safe characters. `+`
+/// is allowed as well for the group IDs of direct conversations
+fn validate_id(id: &str) -> Result<(), IdError> {
+    if id.is_empty() {
+        return Err(IdError::Empty);
+    }
+    match id
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || "-_.~+".contains(*c)))
+    {
+        Some(c) => Err(IdError::UnsafeCharacter(c)),
+        None => Ok(()),
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct CommGroupId {
     community_id: String,
@@ -125,16 +277,64 @@ impl CommGroupId {
         self.group_id.to_string()
     }
 
+    /// Like [Self::try_new], but keeps invalid IDs with a warning, as they
+    /// may come from states stored before IDs were validated. Panics on
+    /// invalid IDs in tests instead.
     pub fn new(community_id: &String, group_id: &String) -> Self {
-        Self {
+        match Self::try_new(community_id, group_id) {
+            Ok(comm_grp) => comm_grp,
+            Err(err) => {
+                if cfg!(test) {
+                    panic!("Invalid ID in {community_id}/{group_id}: {err}");
+                }
+                warn!("Invalid ID in {community_id}/{group_id}: {err}");
+                Self {
+                    community_id: community_id.to_string(),
+                    group_id: group_id.to_string(),
+                }
+            }
+        }
+    }
+
+    /// Returns the ID of the group, if both IDs are non-empty and URL-safe
+    pub fn try_new(community_id: &str, group_id: &str) -> Result<Self, IdError> {
+        validate_id(community_id)?;
+        validate_id(group_id)?;
+        Ok(Self {
             community_id: community_id.to_string(),
             group_id: group_id.to_string(),
-        }
+        })
     }
 
     pub fn default() -> Self {
         panic!("No name")
     }
+
+    /// The hidden group holding the direct messages between two users. Both
+    /// users name it the same, whichever of them created it
+    pub fn dm(user_name: &str, peer_name: &str) -> Self {
+        let mut names = [user_name, peer_name];
+        names.sort();
+        Self::new(&DM_COMMUNITY_ID.to_string(), &names.join("+"))
+    }
+
+    /// Whether this is the hidden group of a direct conversation
+    pub fn is_dm(&self) -> bool {
+        self.community_id == DM_COMMUNITY_ID
+    }
+}
+
+thread_local! {
+    static TIMERS_MUTED: Cell<bool> = Cell::new(false);
+}
+
+/// Runs `f` without recording any [SingleTimeMeasurement], e.g., when
+/// replaying recorded messages, whose timings would not mean anything
+pub fn without_timers<T>(f: impl FnOnce() -> T) -> T {
+    let was_muted = TIMERS_MUTED.with(|muted| muted.replace(true));
+    let result = f();
+    TIMERS_MUTED.with(|muted| muted.set(was_muted));
+    result
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -149,9 +349,20 @@ impl SingleTimeMeasurement {
             description: format!("{:?}", des),
             nanoseconds: dur.as_nanos(),
         };
-        debug!("[Timer-JSON]{}", serde_json::to_string(&new_self).unwrap());
+        new_self.record();
         new_self
     }
+
+    /// Logs the measurement, and with the `metrics` feature, also adds it to
+    /// the global registry
+    pub fn record(&self) {
+        if TIMERS_MUTED.with(Cell::get) {
+            return;
+        }
+        debug!("[Timer-JSON]{}", serde_json::to_string(self).unwrap());
+        #[cfg(feature = "metrics")]
+        metrics::timer_histogram(&self.description).observe(self.nanoseconds as f64);
+    }
 }
 
 #[derive(Debug)]
@@ -184,6 +395,10 @@ pub enum TimerType {
 pub struct SingleMsgSizeMeasurement {
     description: String,
     num_bytes: usize,
+    /// The size the message would have had without compression, if it was
+    /// compressed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    uncompressed_num_bytes: Option<usize>,
 }
 
 #[derive(Debug)]
@@ -194,14 +409,33 @@ pub enum MsgSizeType {
 
 impl SingleMsgSizeMeasurement {
     pub fn new(des: MsgSizeType, size: usize) -> Self {
-        let new_self = Self {
+        Self::recorded(Self {
             description: format!("{:?}", des),
             num_bytes: size,
-        };
-        debug!(
-            "[Bandwidth-JSON]{}",
-            serde_json::to_string(&new_self).unwrap()
-        );
+            uncompressed_num_bytes: None,
+        })
+    }
+
+    /// Measures a compressed message of `size` bytes, `uncompressed_size`
+    /// bytes without compression
+    pub fn new_compressed(des: MsgSizeType, size: usize, uncompressed_size: usize) -> Self {
+        Self::recorded(Self {
+            description: format!("{:?}", des),
+            num_bytes: size,
+            uncompressed_num_bytes: Some(uncompressed_size),
+        })
+    }
+
+    fn recorded(new_self: Self) -> Self {
+        new_self.record();
         new_self
     }
+
+    /// Logs the measurement, and with the `metrics` feature, also adds the
+    /// bytes to the global registry
+    pub fn record(&self) {
+        debug!("[Bandwidth-JSON]{}", serde_json::to_string(self).unwrap());
+        #[cfg(feature = "metrics")]
+        metrics::msg_bytes_counter(&self.description).inc_by(self.num_bytes as u64);
+    }
 }
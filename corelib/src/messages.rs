@@ -1,19 +1,29 @@
 use std::collections::BTreeMap;
+use std::io::{Read, Write};
 use std::time::{Duration, SystemTime};
 use std::{fmt, u8};
 
-use ed25519_dalek::PublicKey;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use ed25519_dalek::{Keypair, PublicKey, Signature, Signer, Verifier};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
 use log::*;
 use openmls::credentials::Credential;
 use openmls::framing::MlsMessageOut;
 use openmls::key_packages::KeyPackage;
 use openmls::messages::Welcome;
 use serde;
+use serde::de::Error as _;
+use sha2::{Digest, Sha256};
 use tls_codec::Serialize;
 use tls_codec::{self, Deserialize};
+use uuid::Uuid;
+use versions::Versioning;
 
 use crate::client_api::actions::{ActionMsg, VerifiableAction};
 use crate::servers_api::as_struct::CredentialEntry;
+use crate::servers_api::ds_structs::AuditEntry;
 use crate::{BytesVisitor, CommGroupId};
 
 /// One Vec of Bytes in an Ordered Message commit
@@ -26,6 +36,10 @@ pub type OrderedPreSerializationType = OrderedPrivateMessage;
 /// should be serialized to
 pub type UnorderedPreSerializationType = UnorderedPrivateMessage;
 
+/// The largest size, in bytes, a compressed message may decompress to;
+/// larger messages are rejected rather than fully decompressed
+pub const MAX_DECOMPRESSED_LEN: u64 = 16 * 1024 * 1024;
+
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 /// A version-controlled, all-encompassing message structure used for information exchange
 /// between clients and the platform (authentic services, delivery services, etc.)
@@ -33,6 +47,203 @@ pub struct OnWireMessageWithMetaData {
     pub onwire_msg: OnWireMessage,
     pub sender_timestamp: SystemTime,
     pub version: String,
+    /// Whether `onwire_msg` is compressed on the wire. Messages of versions
+    /// without this flag are never compressed.
+    #[serde(default)]
+    pub compress: bool,
+    /// The token the AS issued to the sender, which the DS may require to
+    /// process requests naming a user
+    #[serde(default)]
+    pub token: Option<AuthToken>,
+}
+
+/// How an [OnWireMessageWithMetaData] is encoded on the wire: exactly one of
+/// `onwire_msg` and `compressed_onwire_msg` is set, depending on `compress`.
+/// An uncompressed message is encoded as before compression was supported.
+#[derive(serde::Deserialize, serde::Serialize)]
+struct EncodedOnWireMessageWithMetaData {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    onwire_msg: Option<OnWireMessage>,
+    /// The base64 of the deflated JSON encoding of the message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    compressed_onwire_msg: Option<String>,
+    sender_timestamp: SystemTime,
+    version: String,
+    #[serde(default)]
+    compress: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<AuthToken>,
+}
+
+impl OnWireMessageWithMetaData {
+    pub fn new(onwire_msg: OnWireMessage, compress: bool) -> Self {
+        OnWireMessageWithMetaData {
+            onwire_msg,
+            sender_timestamp: SystemTime::now(),
+            version: Versioning::new("0.3.0").unwrap().to_string(),
+            compress,
+            token: None,
+        }
+    }
+
+    /// Attaches `token` to the message, if any
+    pub fn with_token(mut self, token: Option<AuthToken>) -> Self {
+        self.token = token;
+        self
+    }
+
+    /// Encodes the message to be sent as a binary websocket message,
+    /// compressing the inner message if `compress` is set
+    pub fn encode(&self) -> Vec<u8> {
+        let (onwire_msg, compressed_onwire_msg) = if self.compress {
+            let uncompressed = serde_json::to_vec(&self.onwire_msg).expect("Cannot encode app msg");
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+            encoder
+                .write_all(&uncompressed)
+                .expect("Cannot compress app msg");
+            let compressed = encoder.finish().expect("Cannot compress app msg");
+            (None, Some(BASE64.encode(compressed)))
+        } else {
+            (Some(self.onwire_msg.clone()), None)
+        };
+        let encoded = EncodedOnWireMessageWithMetaData {
+            onwire_msg,
+            compressed_onwire_msg,
+            sender_timestamp: self.sender_timestamp,
+            version: self.version.clone(),
+            compress: self.compress,
+            token: self.token.clone(),
+        };
+        serde_json::to_vec(&encoded).expect("Cannot encode app msg")
+    }
+
+    /// Decodes a message encoded by [Self::encode], decompressing the inner
+    /// message only if the `compress` flag is set
+    pub fn decode(encoded: &[u8]) -> serde_json::Result<Self> {
+        let encoded: EncodedOnWireMessageWithMetaData = serde_json::from_slice(encoded)?;
+        let onwire_msg = match (encoded.compress, encoded.onwire_msg) {
+            (false, Some(onwire_msg)) => onwire_msg,
+            (true, _) => {
+                let compressed = BASE64
+                    .decode(encoded.compressed_onwire_msg.unwrap_or_default())
+                    .map_err(serde_json::Error::custom)?;
+                let mut uncompressed = vec![];
+                DeflateDecoder::new(compressed.as_slice())
+                    .take(MAX_DECOMPRESSED_LEN + 1)
+                    .read_to_end(&mut uncompressed)
+                    .map_err(serde_json::Error::custom)?;
+                if uncompressed.len() as u64 > MAX_DECOMPRESSED_LEN {
+                    return Err(serde_json::Error::custom(format!(
+                        "compressed message exceeds {MAX_DECOMPRESSED_LEN} bytes"
+                    )));
+                }
+                serde_json::from_slice(&uncompressed)?
+            }
+            (false, None) => return Err(serde_json::Error::missing_field("onwire_msg")),
+        };
+        Ok(OnWireMessageWithMetaData {
+            onwire_msg,
+            sender_timestamp: encoded.sender_timestamp,
+            version: encoded.version,
+            compress: encoded.compress,
+            token: encoded.token,
+        })
+    }
+
+    /// The size the message would take on the wire without compression
+    pub fn uncompressed_len(&self) -> usize {
+        let mut uncompressed = self.clone();
+        uncompressed.compress = false;
+        uncompressed.encode().len()
+    }
+}
+
+/// The names, or parts of names, of the fields whose values
+/// [redact_key_material] replaces
+const REDACTED_FIELDS: [&str; 4] = ["key", "signature", "secret", "token"];
+
+/// A frame of a dump of websocket messages, as decoded by [decode_wire_dump]
+#[derive(Debug)]
+pub enum DumpedFrame {
+    Decoded {
+        /// The offset of the frame in the dump, in bytes
+        offset: usize,
+        msg: OnWireMessageWithMetaData,
+    },
+    Malformed {
+        offset: usize,
+        error: String,
+    },
+}
+
+/// Decodes a dump of encoded [OnWireMessageWithMetaData]s, either one per
+/// line, or each prefixed by its length as a big-endian `u32` if the dump
+/// does not start with a JSON object. Malformed frames are reported and
+/// skipped; a truncated length-prefixed frame ends the dump.
+pub fn decode_wire_dump(dump: &[u8]) -> Vec<DumpedFrame> {
+    let decode_frame = |offset: usize, frame: &[u8]| match OnWireMessageWithMetaData::decode(frame)
+    {
+        Ok(msg) => DumpedFrame::Decoded { offset, msg },
+        Err(err) => DumpedFrame::Malformed {
+            offset,
+            error: err.to_string(),
+        },
+    };
+    let mut frames = vec![];
+    let mut offset = 0;
+    let is_blank = |bytes: &[u8]| bytes.iter().all(u8::is_ascii_whitespace);
+    let first_byte = dump.iter().find(|byte| !byte.is_ascii_whitespace());
+    if first_byte == Some(&b'{') {
+        for line in dump.split(|byte| *byte == b'\n') {
+            if !is_blank(line) {
+                frames.push(decode_frame(offset, line));
+            }
+            offset += line.len() + 1;
+        }
+        return frames;
+    }
+    while offset < dump.len() {
+        if dump.len() - offset < 4 {
+            frames.push(DumpedFrame::Malformed {
+                offset,
+                error: "Truncated length prefix".to_string(),
+            });
+            break;
+        }
+        let len = u32::from_be_bytes(dump[offset..offset + 4].try_into().unwrap()) as usize;
+        if dump.len() - offset - 4 < len {
+            frames.push(DumpedFrame::Malformed {
+                offset,
+                error: format!(
+                    "Truncated frame of {} bytes, {} left",
+                    len,
+                    dump.len() - offset - 4
+                ),
+            });
+            break;
+        }
+        frames.push(decode_frame(offset, &dump[offset + 4..offset + 4 + len]));
+        offset += 4 + len;
+    }
+    frames
+}
+
+/// Replaces the values of the fields holding key material, such as keys,
+/// signatures and tokens, so that a decoded message can be shared
+pub fn redact_key_material(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(fields) => {
+            for (name, field) in fields.iter_mut() {
+                if REDACTED_FIELDS.iter().any(|part| name.contains(part)) {
+                    *field = serde_json::Value::String("<redacted>".to_string());
+                } else {
+                    redact_key_material(field);
+                }
+            }
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(redact_key_material),
+        _ => (),
+    }
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize, PartialEq, Clone)]
@@ -46,12 +257,64 @@ pub enum OnWireMessage {
     UserRegisterForAS {
         credential: Credential,
         verification_key: PublicKey,
+        /// The name to show other users instead of the identity of the
+        /// credential, if not empty
+        #[serde(default)]
+        display_name: String,
+        /// Required for a device of a user who already registered another
+        /// identity, e.g., `alice#laptop` once `alice#phone` is registered
+        #[serde(default)]
+        endorsement: Option<DeviceEndorsement>,
+    },
+    /// Replaces the credential and the verification key `user_name`
+    /// registered, e.g., to rotate a key. Signed with the verification key
+    /// registered so far, and with the new one to prove its possession, see
+    /// [rotate_key_request_content]
+    UserRotateKey {
+        user_name: String,
+        new_credential: Credential,
+        new_verification_key: PublicKey,
+        signature: Signature,
+        new_key_signature: Signature,
     },
     UserCredentialLookup {
         user_name: String,
         queried_users: Vec<String>,
     },
     UserSyncCredentials,
+    /// A request for the public key the AS signs its responses with
+    ASPublicKeyRequest,
+    /// A request for a token to authenticate to the DS with, signed with the
+    /// verification key `user_name` registered. See [AuthToken::request_content]
+    ASIssueToken {
+        user_name: String,
+        requested_at: SystemTime,
+        signature: Signature,
+    },
+    /// A request for whether the AS stores a credential for `user_name`
+    UserWhoAmI {
+        user_name: String,
+    },
+    /// Stores the notification preferences of the user of the device
+    /// `user_name` at the AS, for their other devices to load. `prefs` is
+    /// encrypted by the client and opaque to the AS. Signed with the
+    /// verification key `user_name` registered, see [prefs_request_content]
+    UserStorePrefs {
+        user_name: String,
+        prefs: Vec<u8>,
+        signature: Signature,
+    },
+    /// A request for the notification preferences stored by any device of
+    /// the user of the device `user_name`
+    UserLoadPrefs {
+        user_name: String,
+    },
+    /// A request from an operator to revoke the credential of `user_name`,
+    /// only carried out if `admin_credential` is the one configured at the AS
+    ASRevokeCredential {
+        admin_credential: String,
+        user_name: String,
+    },
     UserKeyPackageLookup {
         user_name: String,
         queried_users: Vec<String>,
@@ -67,6 +330,21 @@ pub enum OnWireMessage {
     UserSync {
         user_name: String,
         new_key_packages: Vec<KeyPackage>,
+        /// The MLS epoch of each group of the user, so that the DS does not
+        /// relay ordered messages of earlier epochs, already merged
+        #[serde(default)]
+        known_epochs: Vec<(CommGroupId, u64)>,
+        /// The latest server timestamp of the relayed messages the client
+        /// processed. If set, the DS relays the messages of each sync again
+        /// at later syncs until they are acknowledged this way, rather than
+        /// dropping them once relayed
+        #[serde(default)]
+        acked_until: Option<SystemTime>,
+    },
+    /// A request for how many messages the DS holds for `user_name`, which
+    /// are left queued, to decide whether a `UserSync` is worthwhile
+    UserPoll {
+        user_name: String,
     },
     /// A invite new group member request from client
     UserInvite {
@@ -82,12 +360,39 @@ pub enum OnWireMessage {
         recipients: Vec<String>,
         identifier: Option<String>,
         user_msg: GroupMessage,
+        /// Set for high-frequency events the DS may coalesce
+        #[serde(default)]
+        ephemeral: Option<EphemeralKind>,
     },
+    /// An ordered message, routed to `recipients` only. `user_name` is used
+    /// to echo back the sender's preceding ordered messages, and is never
+    /// relayed. Sealed messages omit it, along with the sender of
+    /// `user_msg`, and their sender retrieves the echo with its next sync.
     UserReliableSend {
-        user_name: String,
+        #[serde(default)]
+        user_name: Option<String>,
         recipients: Vec<String>,
         user_msg: GroupMessage,
     },
+    /// A request from a client that discarded its local state of `comm_grp`
+    /// to be invited again, relayed to `recipients`
+    UserRequestReinvite {
+        user_name: String,
+        comm_grp: CommGroupId,
+        recipients: Vec<String>,
+    },
+    /// A notice from a client that accepted the invite to `comm_grp`, so that
+    /// the DS no longer delivers the welcome of the invite again
+    UserAcceptedInvite {
+        user_name: String,
+        comm_grp: CommGroupId,
+    },
+    /// A request from an operator for the commands `user_name` issued to
+    /// the DS, only answered if `admin_credential` is the one configured
+    DSAuditQuery {
+        admin_credential: String,
+        user_name: String,
+    },
 
     /// A result message from delivery service,
     /// indicating whether the request was valid
@@ -101,23 +406,22 @@ pub enum OnWireMessage {
         /// When request was an Ordered Message, the reply includes all sender's unseen ordered msg
         /// to help enforce ordering
         preceding_and_sent_ordered_msgs: Vec<GroupMessage>,
+        /// When request was a `UserSync`, the number of key packages the DS
+        /// holds for the sender after the sync
+        #[serde(default)]
+        remaining_key_packages: Option<usize>,
         process_time_used: Duration,
     },
-    /// A relayed (single) user (group) non-membership-related
-    /// message from delivery service
+    /// A relayed (single) user (group) message from delivery service,
+    /// including the welcomes of invites
     DSRelayedUserMsg {
         user_msg: GroupMessage,
         server_timestamp: SystemTime,
     },
-    /// A relayed (single) user (group) non-membership-related
-    /// message from delivery service
-    DSRelayedUserWelcome {
+    /// A relayed request from `requester` to be invited again to `comm_grp`
+    DSRelayedReinviteRequest {
+        requester: String,
         comm_grp: CommGroupId,
-        sender: String,
-
-        #[serde(serialize_with = "welcome_serialize")]
-        #[serde(deserialize_with = "welcome_deserialize")]
-        welcome: Welcome,
     },
     /// A result message from authentication service,
     /// indicating whether the request was valid
@@ -126,15 +430,60 @@ pub enum OnWireMessage {
         explanation: Option<String>,
         process_time_used: Duration,
     },
-    /// A response with queried Credential from authentication service
+    /// A response with queried Credential from authentication service, sent
+    /// in `total_chunks` chunks of at most `MAX_LOOKUP_RESULTS_PER_CHUNK`
+    /// credentials each
     ASCredentialResponse {
         queried_user_credentials: Vec<Credential>,
+        /// The AS signature over the serialized `queried_user_credentials`
+        /// of this chunk
+        signature: Signature,
+        #[serde(default)]
+        chunk_index: usize,
+        #[serde(default = "single_chunk")]
+        total_chunks: usize,
+    },
+    /// The public key the AS signs its responses with
+    ASPublicKeyResponse {
+        public_key: PublicKey,
+    },
+    /// Whether the AS stores a credential for `user_name`, and the
+    /// fingerprint of the verification key registered with it
+    ASWhoAmIResponse {
+        user_name: String,
+        registered: bool,
+        revoked: bool,
+        verification_key_fingerprint: Option<String>,
     },
     ASCredentialSyncResponse {
         credentials: BTreeMap<String, CredentialEntry>,
     },
+    /// The encrypted notification preferences of a user, if any was stored
+    ASPrefsResponse {
+        prefs: Option<Vec<u8>>,
+    },
+    /// The token issued in response to an `ASIssueToken`
+    ASToken {
+        token: AuthToken,
+    },
+    /// A response with queried key packages from delivery service, sent in
+    /// `total_chunks` chunks of at most `MAX_LOOKUP_RESULTS_PER_CHUNK` key
+    /// packages each
     DSKeyPackageResponse {
         queried_user_key_packages: Vec<KeyPackage>,
+        #[serde(default)]
+        chunk_index: usize,
+        #[serde(default = "single_chunk")]
+        total_chunks: usize,
+    },
+    /// The commands `user_name` issued to the DS, oldest first
+    DSAuditResponse {
+        user_name: String,
+        entries: Vec<AuditEntry>,
+    },
+    /// The response to a `UserPoll`
+    DSPendingCounts {
+        counts: PendingCounts,
     },
 }
 
@@ -146,6 +495,26 @@ pub enum GroupMessage {
         #[serde(serialize_with = "mls_msg_serialize")]
         #[serde(deserialize_with = "mls_msg_deserialize")]
         mls_msg: MlsMessageOut,
+        /// Generated by the sender, so that the DS can recognize a resent
+        /// copy of a message it already delivered
+        #[serde(default = "Uuid::new_v4")]
+        client_msg_id: Uuid,
+        /// The sender's signature over [GroupMessage::tag_content], so that
+        /// recipients can detect the DS altering `comm_grp` or `sender`.
+        /// Absent without a sender
+        #[serde(default)]
+        tag: Option<Signature>,
+    },
+    /// The welcome of an invite of the recipients to `comm_grp`, relayed
+    /// like any other unordered message
+    Welcome {
+        comm_grp: CommGroupId,
+        sender: String,
+        #[serde(serialize_with = "welcome_serialize")]
+        #[serde(deserialize_with = "welcome_deserialize")]
+        welcome: Welcome,
+        #[serde(default = "Uuid::new_v4")]
+        client_msg_id: Uuid,
     },
 }
 
@@ -155,6 +524,11 @@ pub struct UnorderedPrivateMessage {
     pub content: UnorderedMsgContent,
     pub sender_timestamp: SystemTime,
     pub sender: String,
+    /// The position of the message among those the sender sent to the group,
+    /// starting at 1, so that receivers can store them in order. 0 for
+    /// messages that are not kept in order, e.g., requests
+    #[serde(default)]
+    pub seq: u64,
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize, Clone)]
@@ -166,22 +540,41 @@ pub enum UnorderedMsgContent {
     TextAction { text_action: VerifiableAction },
     /// A group state udpate
     GroupState { shared: VerifiableAction },
+    /// A request for the receiving member to send its group state back
+    /// directly, as a `GroupState`
+    GroupStateRequest,
     /// An action without any signature
     UnsignedAction { action: ActionMsg },
     /// A ProposedAction contains an action that has yet to be committed
     ProposedAction { proposed_action: VerifiableAction },
+    /// A short-lived event (e.g. typing) that is not kept in the history
+    Ephemeral {
+        kind: EphemeralKind,
+        content: String,
+    },
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize, Clone)]
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+/// Kinds of high-frequency events. For each recipient, the DS only keeps the
+/// latest queued event per (sender, group, kind).
+pub enum EphemeralKind {
+    Typing,
+    Reaction,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, PartialEq)]
 
 pub struct OrderedPrivateMessage {
     pub content: OrderedMsgContent,
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize, Clone)]
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, PartialEq)]
 pub enum OrderedMsgContent {
     Action(VerifiableAction),
     ActionVec(Vec<VerifiableAction>),
+    /// Actions committed together with [crate::client_api::commit_actions],
+    /// each executed like an action committed on its own
+    ActionBatch(Vec<VerifiableAction>),
 }
 
 impl OnWireMessage {
@@ -189,27 +582,216 @@ impl OnWireMessage {
         match self {
             OnWireMessage::UserKeyPackagesForDS { .. }
             | OnWireMessage::UserRegisterForAS { .. }
+            | OnWireMessage::UserRotateKey { .. }
             | OnWireMessage::UserCredentialLookup { .. }
             | OnWireMessage::UserKeyPackageLookup { .. }
             | OnWireMessage::UserSyncCredentials { .. }
+            | OnWireMessage::ASPublicKeyRequest
+            | OnWireMessage::ASIssueToken { .. }
+            | OnWireMessage::UserWhoAmI { .. }
+            | OnWireMessage::UserStorePrefs { .. }
+            | OnWireMessage::UserLoadPrefs { .. }
+            | OnWireMessage::ASRevokeCredential { .. }
             | OnWireMessage::UserSync { .. }
+            | OnWireMessage::UserPoll { .. }
             | OnWireMessage::UserInvite { .. }
             | OnWireMessage::UserReliableSend { .. }
-            | OnWireMessage::UserStandardSend { .. } => true,
+            | OnWireMessage::UserStandardSend { .. }
+            | OnWireMessage::UserRequestReinvite { .. }
+            | OnWireMessage::UserAcceptedInvite { .. }
+            | OnWireMessage::DSAuditQuery { .. } => true,
 
             OnWireMessage::DSResult { .. }
             | OnWireMessage::DSRelayedUserMsg { .. }
             | OnWireMessage::DSKeyPackageResponse { .. }
-            | OnWireMessage::DSRelayedUserWelcome { .. }
+            | OnWireMessage::DSAuditResponse { .. }
+            | OnWireMessage::DSPendingCounts { .. }
+            | OnWireMessage::DSRelayedReinviteRequest { .. }
             | OnWireMessage::ASResult { .. }
             | OnWireMessage::ASCredentialSyncResponse { .. }
-            | OnWireMessage::ASCredentialResponse { .. } => false,
+            | OnWireMessage::ASPrefsResponse { .. }
+            | OnWireMessage::ASCredentialResponse { .. }
+            | OnWireMessage::ASPublicKeyResponse { .. }
+            | OnWireMessage::ASWhoAmIResponse { .. }
+            | OnWireMessage::ASToken { .. } => false,
         }
     }
 
     pub fn to_vec(self) -> Vec<Self> {
         vec![self]
     }
+
+    /// The group message sent by a `UserStandardSend` or a
+    /// `UserReliableSend`, if the message is one
+    pub fn sent_group_msg(&self) -> Option<&GroupMessage> {
+        match self {
+            OnWireMessage::UserStandardSend { user_msg, .. }
+            | OnWireMessage::UserReliableSend { user_msg, .. } => Some(user_msg),
+            _ => None,
+        }
+    }
+
+    /// The name of the variant, e.g., to record the commands of a user
+    pub fn variant_name(&self) -> &'static str {
+        match self {
+            OnWireMessage::UserKeyPackagesForDS { .. } => "UserKeyPackagesForDS",
+            OnWireMessage::UserRegisterForAS { .. } => "UserRegisterForAS",
+            OnWireMessage::UserRotateKey { .. } => "UserRotateKey",
+            OnWireMessage::UserCredentialLookup { .. } => "UserCredentialLookup",
+            OnWireMessage::UserSyncCredentials => "UserSyncCredentials",
+            OnWireMessage::ASPublicKeyRequest => "ASPublicKeyRequest",
+            OnWireMessage::ASIssueToken { .. } => "ASIssueToken",
+            OnWireMessage::UserWhoAmI { .. } => "UserWhoAmI",
+            OnWireMessage::UserStorePrefs { .. } => "UserStorePrefs",
+            OnWireMessage::UserLoadPrefs { .. } => "UserLoadPrefs",
+            OnWireMessage::ASRevokeCredential { .. } => "ASRevokeCredential",
+            OnWireMessage::UserKeyPackageLookup { .. } => "UserKeyPackageLookup",
+            OnWireMessage::UserSync { .. } => "UserSync",
+            OnWireMessage::UserPoll { .. } => "UserPoll",
+            OnWireMessage::UserInvite { .. } => "UserInvite",
+            OnWireMessage::UserStandardSend { .. } => "UserStandardSend",
+            OnWireMessage::UserReliableSend { .. } => "UserReliableSend",
+            OnWireMessage::UserRequestReinvite { .. } => "UserRequestReinvite",
+            OnWireMessage::UserAcceptedInvite { .. } => "UserAcceptedInvite",
+            OnWireMessage::DSAuditQuery { .. } => "DSAuditQuery",
+            OnWireMessage::DSResult { .. } => "DSResult",
+            OnWireMessage::DSRelayedUserMsg { .. } => "DSRelayedUserMsg",
+            OnWireMessage::DSRelayedReinviteRequest { .. } => "DSRelayedReinviteRequest",
+            OnWireMessage::ASResult { .. } => "ASResult",
+            OnWireMessage::ASCredentialResponse { .. } => "ASCredentialResponse",
+            OnWireMessage::ASPublicKeyResponse { .. } => "ASPublicKeyResponse",
+            OnWireMessage::ASWhoAmIResponse { .. } => "ASWhoAmIResponse",
+            OnWireMessage::ASCredentialSyncResponse { .. } => "ASCredentialSyncResponse",
+            OnWireMessage::ASPrefsResponse { .. } => "ASPrefsResponse",
+            OnWireMessage::ASToken { .. } => "ASToken",
+            OnWireMessage::DSKeyPackageResponse { .. } => "DSKeyPackageResponse",
+            OnWireMessage::DSAuditResponse { .. } => "DSAuditResponse",
+            OnWireMessage::DSPendingCounts { .. } => "DSPendingCounts",
+        }
+    }
+}
+
+/// How many messages the DS holds for a user, as reported to a `UserPoll`
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, PartialEq, Default)]
+pub struct PendingCounts {
+    /// Welcomes of invites, including those delivered again until accepted
+    pub invites: usize,
+    /// Other unordered messages, including re-invite requests
+    pub unordered: usize,
+    /// Ordered messages, by group. Groups without any are left out
+    pub ordered: Vec<(CommGroupId, usize)>,
+}
+
+impl PendingCounts {
+    /// Whether a `UserSync` would retrieve nothing
+    pub fn is_empty(&self) -> bool {
+        self.invites == 0 && self.unordered == 0 && self.ordered.is_empty()
+    }
+}
+
+/// A short-lived proof, signed by the AS, that whoever presents it registered
+/// as `user_name`, so that the DS can check the user requests name their
+/// sender
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, PartialEq)]
+pub struct AuthToken {
+    pub user_name: String,
+    pub expires_at: SystemTime,
+    /// The AS signature over [Self::signed_content]
+    pub signature: Signature,
+}
+
+impl AuthToken {
+    /// What the AS signs to issue a token for `user_name`
+    pub fn signed_content(user_name: &str, expires_at: SystemTime) -> Vec<u8> {
+        encode_to_bytes(&(user_name, expires_at))
+    }
+
+    /// What a user signs to request a token, as sent in an `ASIssueToken`
+    pub fn request_content(user_name: &str, requested_at: SystemTime) -> Vec<u8> {
+        encode_to_bytes(&("ASIssueToken", user_name, requested_at))
+    }
+
+    /// Checks that the token was issued by the AS to `user_name`, and has
+    /// yet to expire at `now`
+    pub fn verify(
+        &self,
+        as_public_key: &PublicKey,
+        user_name: &str,
+        now: SystemTime,
+    ) -> Result<(), UserRequestErrors> {
+        let signed_content = Self::signed_content(&self.user_name, self.expires_at);
+        if as_public_key
+            .verify(&signed_content, &self.signature)
+            .is_err()
+            || self.user_name != user_name
+        {
+            return Err(UserRequestErrors::InvalidToken);
+        }
+        if self.expires_at <= now {
+            return Err(UserRequestErrors::ExpiredToken);
+        }
+        Ok(())
+    }
+}
+
+/// What a user signs with both their registered verification key and the new
+/// one to replace the former, and their credential, as sent in a
+/// `UserRotateKey`. `key_rotations` is how many times the AS rotated the key of
+/// the user so far, so that a captured rotation cannot be replayed once the
+/// key is rotated back to `verification_key`
+pub fn rotate_key_request_content(
+    user_name: &str,
+    verification_key: &PublicKey,
+    key_rotations: u64,
+    new_credential: &Credential,
+    new_verification_key: &PublicKey,
+) -> Vec<u8> {
+    encode_to_bytes(&(
+        "UserRotateKey",
+        user_name,
+        verification_key,
+        key_rotations,
+        new_credential,
+        new_verification_key,
+    ))
+}
+
+/// A registered identity of a user vouching for a new device of the same
+/// user, signing the [device_endorsement_content] of the new device
+#[derive(Debug, serde::Serialize, serde::Deserialize, PartialEq, Clone)]
+pub struct DeviceEndorsement {
+    /// The registered identity that signed, e.g., `alice#phone`
+    pub endorser: String,
+    pub signature: Signature,
+}
+
+/// What a new device of a user hands to a registered identity of the user to
+/// be endorsed by it
+#[derive(Debug, serde::Serialize, serde::Deserialize, PartialEq, Clone)]
+pub struct DeviceEndorsementRequest {
+    pub credential: Credential,
+    pub verification_key: PublicKey,
+}
+
+/// What a registered identity of a user signs with its verification key to
+/// let a new device of the user register with `credential` and
+/// `verification_key`
+pub fn device_endorsement_content(
+    credential: &Credential,
+    verification_key: &PublicKey,
+) -> Vec<u8> {
+    encode_to_bytes(&("UserRegisterForAS", credential, verification_key))
+}
+
+/// What a device signs to store the notification preferences of its user,
+/// as sent in a `UserStorePrefs`
+pub fn prefs_request_content(user_name: &str, prefs: &[u8]) -> Vec<u8> {
+    encode_to_bytes(&("UserStorePrefs", user_name, prefs))
+}
+
+/// The number of chunks of responses sent before they could be chunked
+fn single_chunk() -> usize {
+    1
 }
 
 impl GroupMessage {
@@ -223,14 +805,123 @@ impl GroupMessage {
             comm_grp,
             mls_msg: msg,
             sender,
+            client_msg_id: Uuid::new_v4(),
+            tag: None,
+        }
+    }
+
+    /// What the sender of an `AppMlsMessage` signs: its routing metadata and
+    /// the hash of its MLS message
+    pub fn tag_content(comm_grp: &CommGroupId, sender: &str, mls_msg: &MlsMessageOut) -> Vec<u8> {
+        let mls_msg_hash = Sha256::digest(
+            mls_msg
+                .tls_serialize_detached()
+                .expect("Cannot serialize MlsMessageOut"),
+        );
+        encode_to_bytes(&(comm_grp, sender, mls_msg_hash.as_slice()))
+    }
+
+    /// Tags an `AppMlsMessage` with the signature of its sender. Messages
+    /// without a sender, and welcomes, are left as is
+    pub fn with_tag(self, keypair: &Keypair) -> Self {
+        match self {
+            GroupMessage::AppMlsMessage {
+                comm_grp,
+                sender: Some(sender),
+                mls_msg,
+                client_msg_id,
+                ..
+            } => {
+                let tag = keypair.sign(&Self::tag_content(&comm_grp, &sender, &mls_msg));
+                GroupMessage::AppMlsMessage {
+                    comm_grp,
+                    sender: Some(sender),
+                    mls_msg,
+                    client_msg_id,
+                    tag: Some(tag),
+                }
+            }
+            other => other,
+        }
+    }
+
+    /// Checks that `tag` was signed with `public_key` over the routing
+    /// metadata the message was received with
+    pub fn verify_tag(
+        comm_grp: &CommGroupId,
+        sender: &str,
+        mls_msg: &MlsMessageOut,
+        tag: &Signature,
+        public_key: &PublicKey,
+    ) -> bool {
+        public_key
+            .verify(&Self::tag_content(comm_grp, sender, mls_msg), tag)
+            .is_ok()
+    }
+
+    /// The welcome of an invite from `sender` to `comm_grp`
+    pub fn from_welcome(welcome: Welcome, comm_grp: CommGroupId, sender: String) -> Self {
+        Self::Welcome {
+            comm_grp,
+            sender,
+            welcome,
+            client_msg_id: Uuid::new_v4(),
         }
     }
 
     pub fn get_group_id(&self) -> CommGroupId {
         match self {
-            GroupMessage::AppMlsMessage { comm_grp, .. } => comm_grp.clone(),
+            GroupMessage::AppMlsMessage { comm_grp, .. }
+            | GroupMessage::Welcome { comm_grp, .. } => comm_grp.clone(),
+        }
+    }
+
+    pub fn get_sender(&self) -> Option<String> {
+        match self {
+            GroupMessage::AppMlsMessage { sender, .. } => sender.clone(),
+            GroupMessage::Welcome { sender, .. } => Some(sender.clone()),
+        }
+    }
+
+    /// Strips the sender, and the tag that would reveal it, leaving it to be
+    /// established from the MLS message. Welcomes keep theirs, as the invitee cannot establish it otherwise
+    pub fn without_sender(self) -> Self {
+        match self {
+            GroupMessage::AppMlsMessage {
+                comm_grp,
+                mls_msg,
+                client_msg_id,
+                ..
+            } => GroupMessage::AppMlsMessage {
+                comm_grp,
+                sender: None,
+                mls_msg,
+                client_msg_id,
+                tag: None,
+            },
+            welcome @ GroupMessage::Welcome { .. } => welcome,
+        }
+    }
+
+    pub fn get_client_msg_id(&self) -> Uuid {
+        match self {
+            GroupMessage::AppMlsMessage { client_msg_id, .. }
+            | GroupMessage::Welcome { client_msg_id, .. } => *client_msg_id,
         }
     }
+
+    /// The MLS epoch the message was sent in, readable in plaintext. 0 for
+    /// welcomes, whose epoch is encrypted
+    pub fn get_epoch(&self) -> u64 {
+        match self {
+            GroupMessage::AppMlsMessage { mls_msg, .. } => mls_msg.epoch().as_u64(),
+            GroupMessage::Welcome { .. } => 0,
+        }
+    }
+
+    pub fn is_welcome(&self) -> bool {
+        matches!(self, GroupMessage::Welcome { .. })
+    }
 }
 
 /// Custom serialization for [MlsMessageOut], to be used with `serde_with`
@@ -247,11 +938,9 @@ pub fn mls_msg_deserialize<'a, D>(deserializer: D) -> Result<MlsMessageOut, D::E
 where
     D: serde::Deserializer<'a>,
 {
-    let v = deserializer
-        .deserialize_byte_buf(BytesVisitor)
-        .expect("Cannot get byte buf");
-    let decoded: MlsMessageOut = MlsMessageOut::tls_deserialize(&mut &*v).unwrap();
-    Ok(decoded)
+    let v = deserializer.deserialize_byte_buf(BytesVisitor)?;
+    MlsMessageOut::tls_deserialize(&mut &*v)
+        .map_err(|e| D::Error::custom(format!("Cannot decode MLS message: {:?}", e)))
 }
 
 /// Custom serialization for [Welcome], to be used with `serde_with`
@@ -268,12 +957,9 @@ pub fn welcome_deserialize<'a, D>(deserializer: D) -> Result<Welcome, D::Error>
 where
     D: serde::Deserializer<'a>,
 {
-    let v = deserializer
-        .deserialize_byte_buf(BytesVisitor)
-        .expect("Cannot get byte buf");
-    let mut s = &*v;
-    let decoded: Welcome = Welcome::tls_deserialize(&mut s).unwrap();
-    Ok(decoded)
+    let v = deserializer.deserialize_byte_buf(BytesVisitor)?;
+    Welcome::tls_deserialize(&mut &*v)
+        .map_err(|e| D::Error::custom(format!("Cannot decode welcome: {:?}", e)))
 }
 
 impl UnorderedPrivateMessage {
@@ -336,6 +1022,15 @@ pub enum UserRequestErrors {
     CannotReinvite,
     CannotDecodeIdentity,
     IncompatibleEpochNumber,
+    TooManyRecipients,
+    NotAdmin,
+    MissingToken,
+    InvalidToken,
+    ExpiredToken,
+    UnsupportedCredentialType,
+    InvalidPrefsSignature,
+    InvalidKeyRotation,
+    InvalidDeviceEndorsement,
 }
 
 impl fmt::Display for UserRequestErrors {
@@ -383,6 +1078,57 @@ impl fmt::Display for UserRequestErrors {
             UserRequestErrors::CannotKickYourself => {
                 write!(f, "Do not use Kick to remove yourself. Instead use Leave!")
             }
+            UserRequestErrors::TooManyRecipients => {
+                write!(f, "Too many recipients for a single message.")
+            }
+            UserRequestErrors::NotAdmin => {
+                write!(f, "Not authorized. Invalid admin credential.")
+            }
+            UserRequestErrors::MissingToken => {
+                write!(f, "Not authenticated. Request a token from the AS first.")
+            }
+            UserRequestErrors::InvalidToken => {
+                write!(
+                    f,
+                    "Not authenticated. The token was not issued to you by the AS."
+                )
+            }
+            UserRequestErrors::ExpiredToken => {
+                write!(
+                    f,
+                    "Not authenticated. The token expired, request a new one from the AS."
+                )
+            }
+            UserRequestErrors::UnsupportedCredentialType => {
+                write!(
+                    f,
+                    "The credential type or signature scheme is not allowed by the AS."
+                )
+            }
+            UserRequestErrors::InvalidPrefsSignature => {
+                write!(
+                    f,
+                    "The preferences are not signed with the key of a registered, unrevoked device."
+                )
+            }
+            UserRequestErrors::IdentityAlreadyExist => {
+                write!(
+                    f,
+                    "This identity is already registered. To replace its key, rotate it with a request signed by the registered key."
+                )
+            }
+            UserRequestErrors::InvalidDeviceEndorsement => {
+                write!(
+                    f,
+                    "Another identity of this user is registered, so a new device must be endorsed by an unrevoked one of them."
+                )
+            }
+            UserRequestErrors::InvalidKeyRotation => {
+                write!(
+                    f,
+                    "The key rotation is not signed with the registered and the new key of an unrevoked user, or is for another identity."
+                )
+            }
             _ => write!(f, "{:?}", self),
         }
     }
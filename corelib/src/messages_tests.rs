@@ -0,0 +1,270 @@
+#[cfg(test)]
+mod messages_tests {
+    use std::time::{Duration, SystemTime};
+
+    use ed25519_dalek::{Keypair, Signer};
+    use proptest::prelude::*;
+    use rand_07::rngs::OsRng;
+
+    use crate::client_api::actions::{
+        ActionMetadata, ActionMsg, ActionType, CustomAction, KickAction, RenameGroupAction,
+        TakedownTextMsgAction, TextMsgAction, VerifiableAction, VoteAction,
+    };
+    use crate::messages::{
+        decode_from_bytes, decode_wire_dump, encode_to_bytes, ordered_deserialize,
+        ordered_serialize, redact_key_material, unordered_deserialize, DumpedFrame, OnWireMessage,
+        OnWireMessageWithMetaData, OrderedMsgContent, OrderedPrivateMessage, MAX_DECOMPRESSED_LEN,
+    };
+    use crate::CommGroupId;
+
+    fn metadata_strategy() -> impl Strategy<Value = ActionMetadata> {
+        (
+            ".*",
+            ".*",
+            "[a-zA-Z0-9_.~+-]{1,12}",
+            "[a-zA-Z0-9_.~+-]{1,12}",
+            ".*",
+            proptest::option::of((any::<u32>(), 0..1_000_000_000u32)),
+        )
+            .prop_map(
+                |(sender, action_id, community_id, group_id, data, sent_at)| ActionMetadata {
+                    sender,
+                    action_id,
+                    community_group_id: CommGroupId::new(&community_id, &group_id),
+                    data,
+                    sent_at: sent_at.map(|(secs, nanos)| {
+                        SystemTime::UNIX_EPOCH + Duration::new(secs as u64, nanos)
+                    }),
+                },
+            )
+    }
+
+    fn action_strategy() -> impl Strategy<Value = ActionMsg> {
+        prop_oneof![
+            (
+                ".*",
+                proptest::option::of(any::<u32>()),
+                proptest::option::of(".*"),
+                metadata_strategy()
+            )
+                .prop_map(|(msg, expires_after, in_reply_to, metadata)| {
+                    ActionMsg::TextMsg(TextMsgAction {
+                        msg,
+                        expires_after: expires_after.map(|secs| Duration::from_secs(secs as u64)),
+                        in_reply_to,
+                        metadata,
+                    })
+                }),
+            (".*", any::<u64>(), metadata_strategy()).prop_map(
+                |(new_name, lamport_counter, metadata)| {
+                    ActionMsg::RenameGroup(RenameGroupAction {
+                        new_name,
+                        lamport_counter,
+                        metadata,
+                    })
+                }
+            ),
+            (".*", metadata_strategy()).prop_map(|(target_user_id, metadata)| {
+                ActionMsg::Kick(KickAction {
+                    target_user_id,
+                    metadata,
+                })
+            }),
+            (
+                ".*",
+                ".*",
+                prop_oneof![
+                    Just(ActionType::Kick),
+                    Just(ActionType::RenameGroup),
+                    Just(ActionType::TextMsg)
+                ],
+                metadata_strategy()
+            )
+                .prop_map(
+                    |(vote_value, proposed_action_id, proposed_action_type, metadata)| {
+                        ActionMsg::Vote(VoteAction {
+                            vote_value,
+                            proposed_action_id,
+                            proposed_action_type,
+                            metadata,
+                        })
+                    }
+                ),
+            (".*", ".*", metadata_strategy()).prop_map(|(message_id, reason, metadata)| {
+                ActionMsg::TakedownTextMsg(TakedownTextMsgAction {
+                    message_id,
+                    reason,
+                    metadata,
+                })
+            }),
+            (".*", metadata_strategy())
+                .prop_map(|(data, metadata)| ActionMsg::Custom(CustomAction { data, metadata })),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        /// Encoding then decoding an action should give it back
+        fn action_round_trip(action in action_strategy()) {
+            let decoded: Option<ActionMsg> = decode_from_bytes(&encode_to_bytes(&action));
+            prop_assert_eq!(decoded, Some(action));
+        }
+
+        #[test]
+        /// Serializing then deserializing an ordered message, of one or
+        /// several signed actions, should give it back
+        fn ordered_msg_round_trip(actions in proptest::collection::vec(action_strategy(), 1..4)) {
+            let keypair = Keypair::generate(&mut OsRng {});
+            let mut verif_actions: Vec<VerifiableAction> = actions
+                .into_iter()
+                .map(|action| VerifiableAction::new(action, &keypair))
+                .collect();
+            let content = if verif_actions.len() == 1 {
+                OrderedMsgContent::Action(verif_actions.remove(0))
+            } else {
+                OrderedMsgContent::ActionVec(verif_actions)
+            };
+            let msg = OrderedPrivateMessage { content };
+            prop_assert_eq!(ordered_deserialize(&ordered_serialize(&msg)), Some(msg));
+        }
+
+        #[test]
+        /// Deserializing arbitrary bytes should fail without panicking
+        fn arbitrary_bytes_not_deserialized(bytes in proptest::collection::vec(any::<u8>(), 0..256)) {
+            prop_assert!(ordered_deserialize(&bytes).is_none());
+            prop_assert!(unordered_deserialize(&bytes).is_none());
+        }
+    }
+
+    #[test]
+    /// Malformed MLS messages and group states nested in well-formed JSON
+    /// should fail to decode rather than panic
+    fn malformed_nested_fields_not_deserialized() {
+        assert!(decode_from_bytes::<crate::messages::GroupMessage>(
+            br#"{"AppMlsMessage":{"mls_msg":[1,2,3],"comm_grp":{"community_id":"c","group_id":"g"},"sender":null,"client_msg_id":"00000000-0000-0000-0000-000000000000"}}"#
+        )
+        .is_none());
+        assert!(decode_from_bytes::<crate::messages::GroupMessage>(
+            br#"{"AppMlsMessage":{"mls_msg":"not bytes","comm_grp":{"community_id":"c","group_id":"g"},"sender":null,"client_msg_id":"00000000-0000-0000-0000-000000000000"}}"#
+        )
+        .is_none());
+    }
+
+    #[test]
+    /// A dump of two messages around a malformed frame should decode both
+    /// messages, one per line or length-prefixed, and report the bad frame
+    fn wire_dump_skips_malformed_frame() {
+        let keypair = Keypair::generate(&mut OsRng {});
+        let frames = vec![
+            OnWireMessageWithMetaData::new(
+                OnWireMessage::UserStorePrefs {
+                    user_name: "bob".to_string(),
+                    prefs: vec![1, 2, 3],
+                    signature: keypair.sign(b"prefs"),
+                },
+                false,
+            )
+            .encode(),
+            b"{\"onwire_msg\": garbage".to_vec(),
+            OnWireMessageWithMetaData::new(
+                OnWireMessage::UserLoadPrefs {
+                    user_name: "bob".to_string(),
+                },
+                true,
+            )
+            .encode(),
+        ];
+        let newline_dump = frames.join(&b'\n');
+        let mut prefixed_dump = vec![];
+        for frame in frames.iter() {
+            prefixed_dump.extend((frame.len() as u32).to_be_bytes());
+            prefixed_dump.extend(frame);
+        }
+        // A frame cut short by the end of the capture
+        prefixed_dump.extend(100u32.to_be_bytes());
+        prefixed_dump.extend(b"{}");
+
+        // The separator is a newline, or the length prefix of the next frame
+        for (dump, n_frames, separator_len) in [(newline_dump, 3, 1), (prefixed_dump, 4, 4)] {
+            let decoded = decode_wire_dump(&dump);
+            assert_eq!(decoded.len(), n_frames);
+            assert!(matches!(
+                &decoded[0],
+                DumpedFrame::Decoded { offset: 0, msg }
+                    if matches!(msg.onwire_msg, OnWireMessage::UserStorePrefs { .. })
+            ));
+            assert!(matches!(
+                decoded[1],
+                DumpedFrame::Malformed { offset, .. } if offset == frames[0].len() + separator_len
+            ));
+            assert!(matches!(
+                &decoded[2],
+                DumpedFrame::Decoded { msg, .. }
+                    if matches!(msg.onwire_msg, OnWireMessage::UserLoadPrefs { .. })
+            ));
+        }
+
+        let decoded = OnWireMessageWithMetaData::decode(&frames[0]).unwrap();
+        let mut value = serde_json::to_value(&decoded.onwire_msg).unwrap();
+        redact_key_material(&mut value);
+        assert_eq!(value["UserStorePrefs"]["signature"], "<redacted>");
+        assert_eq!(value["UserStorePrefs"]["user_name"], "bob");
+    }
+
+    #[test]
+    /// A compressed message should decode to the same message, and be smaller
+    /// than uncompressed; uncompressed messages should still decode without
+    /// the compression flag
+    fn compressed_onwire_msg_round_trip() {
+        let keypair = Keypair::generate(&mut OsRng {});
+        let msg = OnWireMessage::UserStorePrefs {
+            user_name: "bob".to_string(),
+            prefs: vec![7; 4096],
+            signature: keypair.sign(b"prefs"),
+        };
+
+        let uncompressed = OnWireMessageWithMetaData::new(msg.clone(), false).encode();
+        let compressed_msg_w_meta = OnWireMessageWithMetaData::new(msg.clone(), true);
+        let compressed = compressed_msg_w_meta.encode();
+        assert!(compressed.len() < uncompressed.len());
+        assert_eq!(compressed_msg_w_meta.uncompressed_len(), uncompressed.len());
+
+        let decoded = OnWireMessageWithMetaData::decode(&compressed).unwrap();
+        assert!(decoded.compress);
+        assert_eq!(decoded.onwire_msg, msg);
+        let decoded = OnWireMessageWithMetaData::decode(&uncompressed).unwrap();
+        assert!(!decoded.compress);
+        assert_eq!(decoded.onwire_msg, msg);
+
+        // As sent by versions without compression
+        let mut legacy: serde_json::Value = serde_json::from_slice(&uncompressed).unwrap();
+        legacy.as_object_mut().unwrap().remove("compress");
+        let decoded =
+            OnWireMessageWithMetaData::decode(&serde_json::to_vec(&legacy).unwrap()).unwrap();
+        assert_eq!(decoded.onwire_msg, msg);
+    }
+
+    #[test]
+    /// A compressed message that decompresses past the size limit should be
+    /// rejected without being fully decompressed
+    fn compressed_onwire_msg_size_limit() {
+        use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+        use flate2::write::DeflateEncoder;
+        use flate2::Compression;
+        use std::io::Write;
+
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+        let zeros = vec![0u8; 1024 * 1024];
+        for _ in 0..=MAX_DECOMPRESSED_LEN / zeros.len() as u64 {
+            encoder.write_all(&zeros).unwrap();
+        }
+        let bomb = encoder.finish().unwrap();
+
+        let mut encoded: serde_json::Value = serde_json::from_slice(
+            &OnWireMessageWithMetaData::new(OnWireMessage::UserSyncCredentials, true).encode(),
+        )
+        .unwrap();
+        encoded["compressed_onwire_msg"] = serde_json::Value::String(BASE64.encode(bomb));
+        assert!(OnWireMessageWithMetaData::decode(&serde_json::to_vec(&encoded).unwrap()).is_err());
+    }
+}
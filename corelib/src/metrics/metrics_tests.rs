@@ -0,0 +1,58 @@
+#[cfg(test)]
+mod metrics_tests {
+    use std::time::Duration;
+
+    use crate::metrics::*;
+    use crate::servers_api::ds_structs::QueueMetrics;
+    use crate::MsgSizeType::{IncomingMsg, OutgoingMsg};
+    use crate::TimerType::{MlsGovRBACCheck, TotalEndToEnd};
+    use crate::{SingleMsgSizeMeasurement, SingleTimeMeasurement};
+
+    #[test]
+    fn test_metric_names() {
+        assert_eq!(
+            timer_metric_name(&format!("{:?}", MlsGovRBACCheck)),
+            "mlsgov_timer_mls_gov_rbac_check_nanos"
+        );
+        assert_eq!(
+            msg_bytes_metric_name(&format!("{:?}", IncomingMsg)),
+            "mlsgov_msg_bytes_incoming_msg"
+        );
+    }
+
+    #[test]
+    fn test_recording_feeds_the_registry() {
+        // Other tests may record concurrently, so only the differences count
+        let timer = timer_histogram("TotalEndToEnd");
+        let bytes = msg_bytes_counter("OutgoingMsg");
+        let (count_before, sum_before) = (timer.get_sample_count(), timer.get_sample_sum());
+        let bytes_before = bytes.get();
+
+        SingleTimeMeasurement::new(TotalEndToEnd, Duration::from_nanos(3_000));
+        SingleTimeMeasurement::new(TotalEndToEnd, Duration::from_nanos(5_000));
+        SingleMsgSizeMeasurement::new(OutgoingMsg, 100);
+        SingleMsgSizeMeasurement::new_compressed(OutgoingMsg, 40, 200);
+
+        assert_eq!(timer.get_sample_count() - count_before, 2);
+        assert_eq!(timer.get_sample_sum() - sum_before, 8_000.0);
+        assert_eq!(bytes.get() - bytes_before, 140);
+
+        let text = gather_text();
+        assert!(text.contains("mlsgov_timer_total_end_to_end_nanos"));
+        assert!(text.contains("mlsgov_msg_bytes_outgoing_msg"));
+    }
+
+    #[test]
+    fn test_ds_queues_feed_the_registry() {
+        record_ds_queues(&QueueMetrics::default());
+        let latency = delivery_latency_histogram();
+        let count_before = latency.get_sample_count();
+        latency.observe(0.5);
+        assert_eq!(latency.get_sample_count() - count_before, 1);
+
+        let text = gather_text();
+        assert!(text.contains("mlsgov_ds_queued_messages"));
+        assert!(text.contains("mlsgov_ds_max_queue_depth"));
+        assert!(text.contains("mlsgov_ds_delivery_latency_seconds"));
+    }
+}
@@ -0,0 +1,178 @@
+//! Accumulates the `SingleTimeMeasurement`s and `SingleMsgSizeMeasurement`s
+//! into a global Prometheus registry, so that servers can expose them for
+//! scraping instead of having them parsed out of the logs.
+//!
+//! Timers are histograms named `mlsgov_timer_<variant>_nanos` and message
+//! sizes are counters named `mlsgov_msg_bytes_<variant>`, where `<variant>`
+//! is the snake-cased `TimerType` or `MsgSizeType`.
+//!
+//! The DS also reports the depth of its message queues, as the gauges
+//! `mlsgov_ds_queued_messages` and `mlsgov_ds_max_queue_depth`, and how long
+//! messages wait before being retrieved, as the histogram
+//! `mlsgov_ds_delivery_latency_seconds`.
+
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+
+use log::warn;
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, IntCounter, IntGauge, Registry,
+};
+
+use crate::servers_api::ds_structs::QueueMetrics;
+
+mod metrics_tests;
+
+struct Metrics {
+    registry: Registry,
+    timers: Mutex<HashMap<String, Histogram>>,
+    msg_bytes: Mutex<HashMap<String, IntCounter>>,
+}
+
+fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(|| Metrics {
+        registry: Registry::new(),
+        timers: Mutex::new(HashMap::new()),
+        msg_bytes: Mutex::new(HashMap::new()),
+    })
+}
+
+/// The global registry all measurements are recorded into
+pub fn registry() -> &'static Registry {
+    &metrics().registry
+}
+
+/// Returns all recorded metrics in the Prometheus text format
+pub fn gather_text() -> String {
+    let mut buffer = vec![];
+    if let Err(e) = prometheus::TextEncoder::new().encode(&registry().gather(), &mut buffer) {
+        warn!("Cannot encode the metrics: {:?}", e);
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+pub fn timer_metric_name(description: &str) -> String {
+    format!("mlsgov_timer_{}_nanos", to_snake_case(description))
+}
+
+pub fn msg_bytes_metric_name(description: &str) -> String {
+    format!("mlsgov_msg_bytes_{}", to_snake_case(description))
+}
+
+/// Returns the histogram of the timer described by `description`, registering
+/// it at first use
+pub(crate) fn timer_histogram(description: &str) -> Histogram {
+    let mut timers = metrics().timers.lock().unwrap();
+    timers
+        .entry(description.to_string())
+        .or_insert_with(|| {
+            // From 1 microsecond to 10 seconds
+            let opts = HistogramOpts::new(
+                timer_metric_name(description),
+                format!("Duration of {} in nanoseconds", description),
+            )
+            .buckets(exponential_buckets(1_000.0, 10.0, 8).unwrap());
+            let histogram = Histogram::with_opts(opts).unwrap();
+            register(Box::new(histogram.clone()));
+            histogram
+        })
+        .clone()
+}
+
+/// Returns the counter of the message sizes described by `description`,
+/// registering it at first use
+pub(crate) fn msg_bytes_counter(description: &str) -> IntCounter {
+    let mut msg_bytes = metrics().msg_bytes.lock().unwrap();
+    msg_bytes
+        .entry(description.to_string())
+        .or_insert_with(|| {
+            let counter = IntCounter::new(
+                msg_bytes_metric_name(description),
+                format!("Total bytes of {} messages", description),
+            )
+            .unwrap();
+            register(Box::new(counter.clone()));
+            counter
+        })
+        .clone()
+}
+
+struct DsQueueMetrics {
+    queued_messages: IntGauge,
+    max_queue_depth: IntGauge,
+    delivery_latency: Histogram,
+}
+
+fn ds_queue_metrics() -> &'static DsQueueMetrics {
+    static DS_QUEUE_METRICS: OnceLock<DsQueueMetrics> = OnceLock::new();
+    DS_QUEUE_METRICS.get_or_init(|| {
+        let queued_messages = IntGauge::new(
+            "mlsgov_ds_queued_messages",
+            "Messages yet to be retrieved, counted once per recipient",
+        )
+        .unwrap();
+        let max_queue_depth = IntGauge::new(
+            "mlsgov_ds_max_queue_depth",
+            "Messages yet to be retrieved by the user with the most",
+        )
+        .unwrap();
+        // From 10 milliseconds to about a day
+        let opts = HistogramOpts::new(
+            "mlsgov_ds_delivery_latency_seconds",
+            "Time from the arrival of a message at the DS to its retrieval, in seconds",
+        )
+        .buckets(exponential_buckets(0.01, 10.0, 8).unwrap());
+        let delivery_latency = Histogram::with_opts(opts).unwrap();
+        register(Box::new(queued_messages.clone()));
+        register(Box::new(max_queue_depth.clone()));
+        register(Box::new(delivery_latency.clone()));
+        DsQueueMetrics {
+            queued_messages,
+            max_queue_depth,
+            delivery_latency,
+        }
+    })
+}
+
+/// Sets the gauges of the DS queues to the depths in `queue_metrics`, e.g.,
+/// before gathering the metrics
+pub fn record_ds_queues(queue_metrics: &QueueMetrics) {
+    let metrics = ds_queue_metrics();
+    metrics
+        .queued_messages
+        .set(queue_metrics.total_queued() as i64);
+    metrics
+        .max_queue_depth
+        .set(queue_metrics.max_queue_depth() as i64);
+}
+
+/// Returns the histogram of the time messages wait at the DS before being
+/// retrieved
+pub(crate) fn delivery_latency_histogram() -> &'static Histogram {
+    &ds_queue_metrics().delivery_latency
+}
+
+fn register(collector: Box<dyn prometheus::core::Collector>) {
+    if let Err(e) = registry().register(collector) {
+        warn!("Cannot register a metric: {:?}", e);
+    }
+}
+
+/// Turns a `Debug`-formatted variant such as `MlsGovRBACCheck` into
+/// `mls_gov_rbac_check`
+fn to_snake_case(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut result = String::new();
+    for (i, c) in chars.iter().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            let prev_lower = chars[i - 1].is_lowercase();
+            let next_lower = chars.get(i + 1).map_or(false, |next| next.is_lowercase());
+            if prev_lower || (chars[i - 1].is_uppercase() && next_lower) {
+                result.push('_');
+            }
+        }
+        result.extend(c.to_lowercase());
+    }
+    result
+}
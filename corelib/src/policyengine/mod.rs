@@ -4,12 +4,16 @@
 //!
 //! This module is inspired by [policykit](https://github.com/policykit/policykit).
 
-use std::ops::Deref;
+use std::collections::{BTreeSet, VecDeque};
+use std::ops::{Deref, Range};
+use std::time::SystemTime;
 use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
 
+use serde::de::Error as _;
 use serde::{Deserialize, Serialize};
 
-use crate::client_api::{actions::ActionMsg, client_struct::ClientDataProvider};
+use crate::client_api::actions::{Action, ActionMsg};
+use crate::client_api::client_struct::ClientDataProvider;
 use crate::messages::{decode_from_bytes, encode_to_bytes};
 use crate::BytesVisitor;
 
@@ -26,6 +30,14 @@ mod policyengine_tests;
 type PolicyRef = Rc<RefCell<Box<dyn Policy>>>;
 pub type ClientRef = Box<dyn ClientDataProvider>;
 
+/// Returned as the number of votes needed by a proposed action that can no
+/// longer pass, whichever way the remaining members vote
+pub const VOTES_UNREACHABLE: usize = usize::MAX;
+
+/// How many decisions the policy engine keeps in its log, the oldest being
+/// dropped first
+pub const MAX_POLICY_DECISIONS: usize = 100;
+
 /// The `Policy` trait defines the core interface a developer-defined
 /// policy must provide.
 #[typetag::serde(tag = "policy_type")]
@@ -51,12 +63,37 @@ pub trait Policy {
     fn get_policy_obj(&self) -> Box<dyn Policy>;
     /// Returns the name of the policy to aid with debugging
     fn get_policy_name(&self) -> &str;
+    /// Returns how many more yes votes the proposed action `action_id` needs
+    /// to pass, or `None` if this policy does not decide it by vote
+    fn votes_needed(&self, _action_id: &str) -> Option<usize> {
+        None
+    }
+    /// Specifies what should happen if the proposed action passed, but is
+    /// executed by another policy it matched (see [PolicyMatchMode::AllMatch])
+    fn release(&mut self, _action: &mut ProposedAction, _client_data: &mut ClientRef) {}
+}
+
+/// Which policies an action is subject to, among those whose filter passes
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum PolicyMatchMode {
+    /// Only the first policy whose filter passes, in the order of the policies
+    #[default]
+    FirstMatch,
+    /// All the policies whose filter passes, which must all pass the action.
+    /// The first of them executes it.
+    AllMatch,
 }
 
 // Drawing on https://github.com/policykit/policykit/blob/6729fa82/policykit/policyengine/engine.py
 pub struct PolicyEngine {
+    /// The policies, in the order actions are matched against them
     pub policies: Vec<PolicyRef>,
+    pub match_mode: PolicyMatchMode,
     pub proposed_actions: Vec<ProposedAction>,
+    /// The latest decisions taken on actions, oldest first
+    pub decision_log: VecDeque<PolicyDecision>,
+    /// The ids of the actions a policy BLOCKED, which are never evaluated again
+    pub blocked_action_ids: BTreeSet<String>,
 }
 
 /// When debugging the PolicyEngine, show the contents of the SerPolicyEngine
@@ -85,58 +122,169 @@ impl PolicyEngine {
     pub fn new(policies: Vec<PolicyRef>) -> Self {
         PolicyEngine {
             policies,
+            match_mode: PolicyMatchMode::default(),
             proposed_actions: Vec::new(),
+            decision_log: VecDeque::new(),
+            blocked_action_ids: BTreeSet::new(),
+        }
+    }
+
+    /// Subjects actions to the policies according to `match_mode`
+    pub fn with_match_mode(mut self, match_mode: PolicyMatchMode) -> Self {
+        self.match_mode = match_mode;
+        self
+    }
+
+    /// Appends `decision` to the decision log, dropping the oldest decision
+    /// if the log holds [MAX_POLICY_DECISIONS] already
+    fn log_decision(&mut self, decision: Option<PolicyDecision>) {
+        if let Some(decision) = decision {
+            if self.decision_log.len() >= MAX_POLICY_DECISIONS {
+                self.decision_log.pop_front();
+            }
+            self.decision_log.push_back(decision);
+        }
+    }
+
+    /// Appends `decisions` to the decision log, and returns the ids of the
+    /// actions they FAILED or BLOCKED
+    fn log_decisions(&mut self, decisions: Vec<Option<PolicyDecision>>) -> Vec<String> {
+        let mut rejected_action_ids = vec![];
+        for decision in decisions.into_iter().flatten() {
+            if matches!(
+                decision.status,
+                ProposedActionStatus::FAILED | ProposedActionStatus::BLOCKED
+            ) && !rejected_action_ids.contains(&decision.action_id)
+            {
+                rejected_action_ids.push(decision.action_id.clone());
+            }
+            self.log_decision(Some(decision));
+        }
+        rejected_action_ids
+    }
+
+    /// Returns how many more yes votes the pending action `action_id` needs to
+    /// pass: 0 if it is not pending or not decided by vote, and
+    /// [VOTES_UNREACHABLE] if it can no longer pass. Of several policies
+    /// deciding it by vote, the one needing the most votes counts.
+    pub fn votes_needed(&self, action_id: &str) -> usize {
+        self.proposed_actions
+            .iter()
+            .filter(|proposed_action| proposed_action.action.get_metadata().action_id == action_id)
+            .filter_map(|proposed_action| proposed_action.policy.borrow().votes_needed(action_id))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Records the proposed actions that were BLOCKED, and retains only the
+    /// proposed actions of the actions that are still pending, i.e., with a
+    /// proposed action in a PROPOSED state
+    fn retain_proposed(&mut self) {
+        let mut pending_action_ids = BTreeSet::new();
+        for proposed_action in self.proposed_actions.iter() {
+            let action_id = proposed_action.action.get_metadata().action_id;
+            match proposed_action.status {
+                ProposedActionStatus::PROPOSED => {
+                    pending_action_ids.insert(action_id);
+                }
+                ProposedActionStatus::BLOCKED => {
+                    self.blocked_action_ids.insert(action_id);
+                }
+                ProposedActionStatus::PASSED | ProposedActionStatus::FAILED => (),
+            }
         }
+        self.proposed_actions.retain(|proposed_action| {
+            pending_action_ids.contains(&proposed_action.action.get_metadata().action_id)
+        })
     }
 
-    /// Called the first time the action is evaluated
-    pub fn evaluate_action(&mut self, action: ActionMsg, client_data: &mut ClientRef) {
-        let proposed_action_opt = self.create_prefiltered_proposed_actions(action, client_data);
-        if let Some(mut proposed_action) = proposed_action_opt {
-            Self::evaluate_proposed_action(&mut proposed_action, client_data, true);
-            if proposed_action.status == ProposedActionStatus::PROPOSED {
-                self.proposed_actions.push(proposed_action);
+    /// Returns the ranges of `proposed_actions` holding the proposed actions of
+    /// a same action, which are always next to each other
+    fn action_groups(proposed_actions: &[ProposedAction]) -> Vec<Range<usize>> {
+        let mut groups: Vec<Range<usize>> = vec![];
+        for (i, proposed_action) in proposed_actions.iter().enumerate() {
+            match groups.last_mut() {
+                Some(group)
+                    if proposed_actions[group.start]
+                        .action
+                        .get_metadata()
+                        .action_id
+                        == proposed_action.action.get_metadata().action_id =>
+                {
+                    group.end = i + 1
+                }
+                _ => groups.push(i..i + 1),
             }
         }
+        groups
     }
 
-    /// Evaluates the given action against the list of policies and returns
-    /// ProposedActionS for those that pass the filter. This will generate
-    /// multiple copies of the same action -- one for each proposal.
-    pub fn create_prefiltered_proposed_actions<'a>(
+    /// Called the first time the action is evaluated. Actions that were
+    /// BLOCKED before are ignored. Returns the ids of the actions that FAILED
+    /// or were BLOCKED, including this one if it was BLOCKED before
+    pub fn evaluate_action(
+        &mut self,
+        action: ActionMsg,
+        client_data: &mut ClientRef,
+    ) -> Vec<String> {
+        let action_id = action.get_metadata().action_id;
+        if self.blocked_action_ids.contains(&action_id) {
+            return vec![action_id];
+        }
+        let mut proposed_actions = self.create_prefiltered_proposed_actions(action, client_data);
+        let decisions = Self::evaluate_action_group(&mut proposed_actions, client_data, true);
+        let rejected_action_ids = self.log_decisions(decisions);
+        self.proposed_actions.extend(proposed_actions);
+        self.retain_proposed();
+        rejected_action_ids
+    }
+
+    /// Evaluates the given action against the list of policies, in order, and
+    /// returns a ProposedAction for the first one whose filter passes, or for
+    /// each of them in [PolicyMatchMode::AllMatch]
+    pub fn create_prefiltered_proposed_actions(
         &self,
         action: ActionMsg,
         client_data: &mut ClientRef,
-    ) -> Option<ProposedAction> {
-        let mut proposed_actions = None;
+    ) -> Vec<ProposedAction> {
+        let mut proposed_actions = vec![];
         for policy in self.policies.iter() {
-            // Check if filter passes, the first policy whose filter
-            // passes will be assigned to this action
             if policy.borrow().filter(&action, client_data) {
-                proposed_actions = Some(ProposedAction::new(
-                    action,
+                proposed_actions.push(ProposedAction::new(
+                    action.clone(),
                     policy.clone(),
                     ProposedActionStatus::PROPOSED,
                 ));
-                break;
+                if self.match_mode == PolicyMatchMode::FirstMatch {
+                    break;
+                }
             }
         }
 
         proposed_actions
     }
 
-    /// Called repeatedly until a proposed action reaches a state of
-    /// PASSED, FAILED, or BLOCKED. This function will invoke a check.
-    /// If the result is PASSED, the code in `pass` runs. If the result
-    /// is FAILED or BLOCKED, then the code in `fail` runs. If the result
-    /// is PROPOSED, the proposed action is retained.
-    /// The argument `first_eval` specifies if this is the first time the
-    /// proposed action is being evaluated
-    pub fn evaluate_proposed_action(
+    /// Whether the filter of any policy passes `action`, i.e., whether the
+    /// engine decides on it at all
+    pub fn has_matching_policy(&self, action: &ActionMsg, client_data: &mut ClientRef) -> bool {
+        self.policies
+            .iter()
+            .any(|policy| policy.borrow().filter(action, client_data))
+    }
+
+    /// Checks a proposed action against its policy, and sets its status to
+    /// the result, without running `pass` or `fail`. A BLOCKED proposed
+    /// action is never checked again.
+    /// Returns the decision taken if this is the first evaluation or the
+    /// status changed, to be recorded in the decision log
+    fn check_proposed_action(
         proposed_action: &mut ProposedAction,
         client_data: &mut ClientRef,
         first_eval: bool,
-    ) {
+    ) -> Option<PolicyDecision> {
+        if proposed_action.status == ProposedActionStatus::BLOCKED {
+            return None;
+        }
         let policy_clone = proposed_action.policy.clone();
         let mut policy_ref = policy_clone.borrow_mut();
 
@@ -144,29 +292,166 @@ impl PolicyEngine {
             policy_ref.init(proposed_action, client_data);
         }
 
+        let previous_status = proposed_action.status;
         let check_result = policy_ref.check(proposed_action, client_data);
         proposed_action.status = check_result;
-        match check_result {
+
+        if first_eval || check_result != previous_status {
+            Some(PolicyDecision::new(proposed_action, &**policy_ref))
+        } else {
+            None
+        }
+    }
+
+    /// Called repeatedly until a proposed action reaches a state of
+    /// PASSED, FAILED, or BLOCKED. This function will invoke a check.
+    /// If the result is PASSED, the code in `pass` runs. If the result
+    /// is FAILED or BLOCKED, then the code in `fail` runs. If the result
+    /// is PROPOSED, the proposed action is retained. A BLOCKED proposed
+    /// action is never checked again.
+    /// The argument `first_eval` specifies if this is the first time the
+    /// proposed action is being evaluated.
+    /// Returns the decision taken if this is the first evaluation or the
+    /// status changed, to be recorded in the decision log
+    pub fn evaluate_proposed_action(
+        proposed_action: &mut ProposedAction,
+        client_data: &mut ClientRef,
+        first_eval: bool,
+    ) -> Option<PolicyDecision> {
+        let was_blocked = proposed_action.status == ProposedActionStatus::BLOCKED;
+        let decision = Self::check_proposed_action(proposed_action, client_data, first_eval);
+        if was_blocked {
+            return decision;
+        }
+        let policy_clone = proposed_action.policy.clone();
+        let mut policy_ref = policy_clone.borrow_mut();
+        match proposed_action.status {
             ProposedActionStatus::PROPOSED => (),
             ProposedActionStatus::PASSED => {
                 policy_ref.pass(proposed_action, client_data);
             }
-            ProposedActionStatus::FAILED => {
+            ProposedActionStatus::FAILED | ProposedActionStatus::BLOCKED => {
                 policy_ref.fail(proposed_action, client_data);
             }
         }
+        decision
     }
 
-    /// Evaluates all currently proposed actions and retains only those that
-    /// are still in a PROPOSED state
-    pub fn evaluate_all_proposed_actions(&mut self, client_data: &mut ClientRef) {
-        self.proposed_actions
+    /// Evaluates the proposed actions of a same action, one per policy it
+    /// matched. A single proposed action is evaluated on its own. Otherwise,
+    /// the action is BLOCKED or FAILED as soon as one of its policies blocks
+    /// or fails it, in which case `fail` runs for each policy, and it PASSED
+    /// once all of its policies passed it, in which case `pass` runs for the
+    /// first policy only, which executes it, and `release` for the others.
+    /// Returns the decisions taken, to be recorded in the decision log
+    fn evaluate_action_group(
+        group: &mut [ProposedAction],
+        client_data: &mut ClientRef,
+        first_eval: bool,
+    ) -> Vec<Option<PolicyDecision>> {
+        if let [proposed_action] = group {
+            return vec![Self::evaluate_proposed_action(
+                proposed_action,
+                client_data,
+                first_eval,
+            )];
+        }
+        let decisions = group
             .iter_mut()
-            .for_each(|proposed_action| {
-                PolicyEngine::evaluate_proposed_action(proposed_action, client_data, false)
-            });
-        self.proposed_actions
-            .retain(|proposed_action| proposed_action.status == ProposedActionStatus::PROPOSED)
+            .filter(|proposed_action| proposed_action.status == ProposedActionStatus::PROPOSED)
+            .map(|proposed_action| {
+                Self::check_proposed_action(proposed_action, client_data, first_eval)
+            })
+            .collect();
+        let statuses: Vec<_> = group
+            .iter()
+            .map(|proposed_action| proposed_action.status)
+            .collect();
+        let outcome = if statuses.contains(&ProposedActionStatus::BLOCKED) {
+            ProposedActionStatus::BLOCKED
+        } else if statuses.contains(&ProposedActionStatus::FAILED) {
+            ProposedActionStatus::FAILED
+        } else if statuses.contains(&ProposedActionStatus::PROPOSED) {
+            return decisions;
+        } else {
+            ProposedActionStatus::PASSED
+        };
+        for (i, proposed_action) in group.iter_mut().enumerate() {
+            let policy_clone = proposed_action.policy.clone();
+            let mut policy_ref = policy_clone.borrow_mut();
+            match outcome {
+                ProposedActionStatus::PASSED if i == 0 => {
+                    policy_ref.pass(proposed_action, client_data)
+                }
+                ProposedActionStatus::PASSED => policy_ref.release(proposed_action, client_data),
+                _ => policy_ref.fail(proposed_action, client_data),
+            }
+            proposed_action.status = outcome;
+        }
+        decisions
+    }
+
+    /// Evaluates all currently proposed actions and retains only those that
+    /// are still in a PROPOSED state, recording the BLOCKED ones.
+    /// Returns the ids of the actions that FAILED or were BLOCKED
+    pub fn evaluate_all_proposed_actions(&mut self, client_data: &mut ClientRef) -> Vec<String> {
+        let mut decisions = vec![];
+        for group in Self::action_groups(&self.proposed_actions) {
+            decisions.extend(PolicyEngine::evaluate_action_group(
+                &mut self.proposed_actions[group],
+                client_data,
+                false,
+            ));
+        }
+        let rejected_action_ids = self.log_decisions(decisions);
+        self.retain_proposed();
+        rejected_action_ids
+    }
+
+    /// Evaluates `actions` in order, the same way as received actions are
+    /// evaluated, and returns the actions that did not end up PASSED.
+    /// Intended to be used on a fresh engine to audit past actions.
+    pub fn replay_actions(
+        &mut self,
+        actions: Vec<ActionMsg>,
+        client_data: &mut ClientRef,
+    ) -> Vec<ActionMsg> {
+        let all_passed = |group: &[ProposedAction]| {
+            group
+                .iter()
+                .all(|proposed_action| proposed_action.status == ProposedActionStatus::PASSED)
+        };
+        let mut passed_action_ids = BTreeSet::new();
+        for action in actions.iter() {
+            if self
+                .blocked_action_ids
+                .contains(&action.get_metadata().action_id)
+            {
+                continue;
+            }
+            let mut proposed_actions =
+                self.create_prefiltered_proposed_actions(action.clone(), client_data);
+            if !proposed_actions.is_empty() {
+                Self::evaluate_action_group(&mut proposed_actions, client_data, true);
+                if all_passed(&proposed_actions) {
+                    passed_action_ids.insert(action.get_metadata().action_id);
+                }
+                self.proposed_actions.extend(proposed_actions);
+                self.retain_proposed();
+            }
+            for group in Self::action_groups(&self.proposed_actions) {
+                let group = &mut self.proposed_actions[group];
+                Self::evaluate_action_group(group, client_data, false);
+                if all_passed(group) {
+                    passed_action_ids.insert(group[0].action.get_metadata().action_id);
+                }
+            }
+            self.retain_proposed();
+        }
+        actions
+            .into_iter()
+            .filter(|action| !passed_action_ids.contains(&action.get_metadata().action_id))
+            .collect()
     }
 }
 
@@ -180,6 +465,9 @@ pub enum ProposedActionStatus {
     FAILED,
     /// The action has been approved
     PASSED,
+    /// The action has been rejected for good: unlike a FAILED one, it is
+    /// never evaluated again, even if it is received again
+    BLOCKED,
 }
 
 /// `ProposedAction` here serves the purpose of a `Proposal` in the PolicyKit
@@ -190,6 +478,10 @@ pub struct ProposedAction {
     pub policy: PolicyRef,
     pub status: ProposedActionStatus,
     pub data: String,
+    /// When the policy expects the action to be decided, e.g., once the
+    /// vote on it closes. Only shown to users; the action is only checked
+    /// again when an action arrives
+    pub reevaluate_after: Option<SystemTime>,
 }
 
 impl ProposedAction {
@@ -199,6 +491,47 @@ impl ProposedAction {
             policy,
             status,
             data: "".to_string(),
+            reevaluate_after: None,
+        }
+    }
+}
+
+/// A decision the policy engine took on an action, kept in its decision log so
+/// that users can understand what happened to their actions
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct PolicyDecision {
+    pub action_id: String,
+    /// The name of the policy whose filter the action passed
+    pub policy_name: String,
+    pub status: ProposedActionStatus,
+    /// Why the action has this status, as far as the engine can tell
+    pub reason: String,
+}
+
+impl PolicyDecision {
+    fn new(proposed_action: &ProposedAction, policy: &dyn Policy) -> Self {
+        let action_id = proposed_action.action.get_metadata().action_id;
+        let reason = match proposed_action.status {
+            ProposedActionStatus::PASSED => "Approved by the policy".to_string(),
+            ProposedActionStatus::FAILED => "Rejected by the policy".to_string(),
+            ProposedActionStatus::BLOCKED => "Blocked by the policy for good".to_string(),
+            ProposedActionStatus::PROPOSED => match (
+                policy.votes_needed(&action_id),
+                proposed_action.reevaluate_after,
+            ) {
+                (Some(VOTES_UNREACHABLE), _) => "Pending, but can no longer pass".to_string(),
+                (Some(votes_needed), _) => {
+                    format!("Pending until {} more yes vote(s)", votes_needed)
+                }
+                (None, Some(deadline)) => format!("Pending until {:?}", deadline),
+                (None, None) => "Pending".to_string(),
+            },
+        };
+        PolicyDecision {
+            action_id,
+            policy_name: policy.get_policy_name().to_string(),
+            status: proposed_action.status,
+            reason,
         }
     }
 }
@@ -211,6 +544,8 @@ struct SerProposedAction {
     pub policy_idx: usize,
     pub status: ProposedActionStatus,
     pub data: String,
+    #[serde(default)]
+    pub reevaluate_after: Option<SystemTime>,
 }
 
 /// A serializable version of `PolicyEngine` that is used in our serialization
@@ -218,7 +553,13 @@ struct SerProposedAction {
 #[derive(Serialize, Deserialize, Debug)]
 struct SerPolicyEngine {
     pub policies: Vec<Box<dyn Policy>>,
+    #[serde(default)]
+    pub match_mode: PolicyMatchMode,
     pub proposed_actions: Vec<SerProposedAction>,
+    #[serde(default)]
+    pub decision_log: VecDeque<PolicyDecision>,
+    #[serde(default)]
+    pub blocked_action_ids: BTreeSet<String>,
 }
 
 impl From<SerPolicyEngine> for PolicyEngine {
@@ -236,11 +577,15 @@ impl From<SerPolicyEngine> for PolicyEngine {
                 policy: policies[proposed_action.policy_idx].clone(),
                 status: proposed_action.status,
                 data: proposed_action.data,
+                reevaluate_after: proposed_action.reevaluate_after,
             })
             .collect();
         PolicyEngine {
             policies,
+            match_mode: ser_policy_engine.match_mode,
             proposed_actions,
+            decision_log: ser_policy_engine.decision_log,
+            blocked_action_ids: ser_policy_engine.blocked_action_ids,
         }
     }
 }
@@ -268,13 +613,17 @@ impl From<&PolicyEngine> for SerPolicyEngine {
                     .unwrap(),
                 status: proposed_action.status,
                 data: proposed_action.data.clone(),
+                reevaluate_after: proposed_action.reevaluate_after,
             })
             .collect();
 
         // Construct the SerPolicyEngine
         SerPolicyEngine {
             policies: ser_policies,
+            match_mode: engine.match_mode,
             proposed_actions: ser_prop_actions,
+            decision_log: engine.decision_log.clone(),
+            blocked_action_ids: engine.blocked_action_ids.clone(),
         }
     }
 }
@@ -298,7 +647,7 @@ where
     D: serde::Deserializer<'a>,
 {
     let result = deserializer.deserialize_byte_buf(BytesVisitor)?;
-    let ser_policy_engine: SerPolicyEngine =
-        decode_from_bytes(&result).expect("could not decode policy engine");
+    let ser_policy_engine: SerPolicyEngine = decode_from_bytes(&result)
+        .ok_or_else(|| D::Error::custom("could not decode policy engine"))?;
     Ok(Rc::new(RefCell::new(ser_policy_engine.into())))
 }
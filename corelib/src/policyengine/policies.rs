@@ -1,11 +1,18 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::ops::DerefMut;
+use std::time::{Duration, SystemTime};
 
-use log::info;
+use log::{info, warn};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
-use crate::client_api::actions::{Action, ActionMsg, ActionType};
-use crate::policyengine::{ClientRef, Policy, ProposedAction, ProposedActionStatus};
+use crate::client_api::actions::{
+    Action, ActionMetadata, ActionMsg, ActionType, TakedownTextMsgAction,
+};
+use crate::policyengine::{
+    ClientRef, Policy, ProposedAction, ProposedActionStatus, VOTES_UNREACHABLE,
+};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct PassAllPolicy {}
@@ -102,6 +109,21 @@ impl Poll {
     pub fn get_num_eligible_voters(&self) -> usize {
         self.member_to_vote.len()
     }
+
+    /// Returns how many of the members yet to vote must vote yes for the poll
+    /// to pass, i.e., for yes votes to at least match no votes once everyone
+    /// voted, or [VOTES_UNREACHABLE] if that is no longer possible
+    pub fn get_yes_votes_needed(&self) -> usize {
+        let yes_votes = self.get_yes_votes();
+        let no_votes = self.get_no_votes();
+        let remaining_voters = self.get_num_eligible_voters() - yes_votes - no_votes;
+        let needed = ((no_votes + remaining_voters).saturating_sub(yes_votes) + 1) / 2;
+        if needed > remaining_voters {
+            VOTES_UNREACHABLE
+        } else {
+            needed
+        }
+    }
 }
 
 impl VoteOnNameChangePolicy {
@@ -206,6 +228,10 @@ impl Policy for VoteOnNameChangePolicy {
         }
     }
 
+    fn release(&mut self, action: &mut ProposedAction, client_data: &mut ClientRef) {
+        self.fail(action, client_data)
+    }
+
     fn get_policy_obj(&self) -> Box<dyn Policy> {
         Box::new(self.clone())
     }
@@ -213,6 +239,12 @@ impl Policy for VoteOnNameChangePolicy {
     fn get_policy_name(&self) -> &str {
         "VoteOnNameChangePolicy"
     }
+
+    fn votes_needed(&self, action_id: &str) -> Option<usize> {
+        self.action_id_to_poll
+            .get(action_id)
+            .map(Poll::get_yes_votes_needed)
+    }
 }
 
 /// A policy that keeps track of user reputation via a custom action message
@@ -356,7 +388,7 @@ impl Policy for WordFilterPolicy {
                 let community_group_id = metadata.community_group_id;
                 let rbac = client_data.get_roles(&community_group_id);
                 // Check if the sender of the action has a Mod role
-                if rbac.user_to_role.get(&sender) == Some(&"Mod".to_string()) {
+                if rbac.has_user_role(&sender, "Mod") {
                     ProposedActionStatus::PASSED
                 } else {
                     ProposedActionStatus::FAILED
@@ -398,3 +430,536 @@ impl Policy for WordFilterPolicy {
         "WordFilterPolicy"
     }
 }
+
+/// A policy that fails the actions of a sender who already sent
+/// `max_actions` of the filtered action types within the last `window`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RateLimitPolicy {
+    /// The action types to rate limit. All action types if empty
+    action_types: Vec<ActionType>,
+    /// How many actions a sender may send within a window
+    max_actions: usize,
+    /// The length of the sliding window
+    window: Duration,
+    /// When the passed actions of each sender were sent, oldest first
+    sender_to_sent_at: BTreeMap<String, VecDeque<SystemTime>>,
+}
+
+impl RateLimitPolicy {
+    pub fn new(action_types: Vec<ActionType>, max_actions: usize, window: Duration) -> Self {
+        RateLimitPolicy {
+            action_types,
+            max_actions,
+            window,
+            sender_to_sent_at: BTreeMap::new(),
+        }
+    }
+}
+
+#[typetag::serde]
+impl Policy for RateLimitPolicy {
+    fn filter(&self, action: &ActionMsg, _client_data: &mut ClientRef) -> bool {
+        self.action_types.is_empty() || self.action_types.contains(&action.action_type())
+    }
+
+    fn init(&mut self, _action: &mut ProposedAction, _client_data: &mut ClientRef) {}
+
+    fn check(
+        &mut self,
+        action: &mut ProposedAction,
+        client_data: &mut ClientRef,
+    ) -> ProposedActionStatus {
+        let action_metadata = action.action.get_metadata();
+        // Actions from older clients carry no timestamp; count them as of now
+        let sent_at = action_metadata.sent_at.unwrap_or_else(|| client_data.now());
+        let sent_ats = self
+            .sender_to_sent_at
+            .entry(action_metadata.sender)
+            .or_default();
+        // Forget the actions that left the window
+        while let Some(oldest) = sent_ats.front() {
+            match sent_at.duration_since(*oldest) {
+                Ok(elapsed) if elapsed >= self.window => {
+                    sent_ats.pop_front();
+                }
+                _ => break,
+            }
+        }
+        if sent_ats.len() >= self.max_actions {
+            info!(
+                "Rate limited action {}: its sender sent {} actions within {:?}",
+                action.action.get_metadata().action_id,
+                sent_ats.len(),
+                self.window
+            );
+            ProposedActionStatus::FAILED
+        } else {
+            sent_ats.push_back(sent_at);
+            ProposedActionStatus::PASSED
+        }
+    }
+
+    fn pass(&mut self, action: &mut ProposedAction, client_data: &mut ClientRef) {
+        action.action.execute(client_data.deref_mut());
+    }
+
+    fn fail(&mut self, _action: &mut ProposedAction, _client_data: &mut ClientRef) {}
+
+    fn get_policy_obj(&self) -> Box<dyn Policy> {
+        Box::new(self.clone())
+    }
+
+    fn get_policy_name(&self) -> &str {
+        "RateLimitPolicy"
+    }
+}
+
+/// A voting policy for the filtered action types whose vote is open for
+/// `voting_period` since the action was sent. Votes sent after that do not
+/// count. The vote closes on a [crate::client_api::actions::ClosePollAction]
+/// sent after that, so that every member closes it after the same votes. The
+/// action passes if a majority of the votes cast by then are yes, or as soon
+/// as every member voted.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TimedVotePolicy {
+    /// The action types that are put to a vote
+    action_types: Vec<ActionType>,
+    /// How long the vote stays open
+    voting_period: Duration,
+    /// Maps action ids to polls
+    action_id_to_poll: BTreeMap<String, Poll>,
+    /// Maps action ids to when their vote ends
+    #[serde(default)]
+    action_id_to_deadline: BTreeMap<String, SystemTime>,
+    /// The ids of the actions whose vote was closed
+    #[serde(default)]
+    closed_action_ids: BTreeSet<String>,
+}
+
+impl TimedVotePolicy {
+    pub fn new(action_types: Vec<ActionType>, voting_period: Duration) -> Self {
+        TimedVotePolicy {
+            action_types,
+            voting_period,
+            action_id_to_poll: BTreeMap::new(),
+            action_id_to_deadline: BTreeMap::new(),
+            closed_action_ids: BTreeSet::new(),
+        }
+    }
+
+    /// Whether a vote sent at `sent_at` comes after the vote on `action_id`
+    /// ended. Votes from older clients carry no timestamp, and still count
+    fn vote_too_late(&self, action_id: &str, sent_at: Option<SystemTime>) -> bool {
+        match (self.action_id_to_deadline.get(action_id), sent_at) {
+            (Some(deadline), Some(sent_at)) => sent_at > *deadline,
+            _ => false,
+        }
+    }
+
+    fn forget(&mut self, action_id: &str) {
+        self.action_id_to_poll.remove(action_id);
+        self.action_id_to_deadline.remove(action_id);
+        self.closed_action_ids.remove(action_id);
+    }
+}
+
+#[typetag::serde]
+impl Policy for TimedVotePolicy {
+    /// Allow the filtered action types, and the votes on them and their closing
+    fn filter(&self, action: &ActionMsg, _client_data: &mut ClientRef) -> bool {
+        match action {
+            ActionMsg::Vote(vote_action) => self
+                .action_types
+                .contains(&vote_action.proposed_action_type),
+            ActionMsg::ClosePoll(close_action) => self
+                .action_types
+                .contains(&close_action.proposed_action_type),
+            _ => self.action_types.contains(&action.action_type()),
+        }
+    }
+
+    /// Open a poll for the proposed action until the end of its voting period
+    fn init(&mut self, action: &mut ProposedAction, client_data: &mut ClientRef) {
+        if let ActionMsg::Vote(_) | ActionMsg::ClosePoll(_) = &action.action {
+            return;
+        }
+        let action_metadata = action.action.get_metadata();
+        self.action_id_to_poll.insert(
+            action_metadata.action_id.clone(),
+            Poll::new(&client_data.get_group_members(&action_metadata.community_group_id)),
+        );
+        // Actions from older clients carry no timestamp, and every member
+        // must agree on the deadline, so their vote may be closed right away
+        let sent_at = action_metadata.sent_at.unwrap_or(SystemTime::UNIX_EPOCH);
+        let deadline = sent_at + self.voting_period;
+        self.action_id_to_deadline
+            .insert(action_metadata.action_id.clone(), deadline);
+        action.reevaluate_after = Some(deadline);
+        info!(
+            "Voting is open until {:?} for action ID: {}",
+            deadline, action_metadata.action_id
+        );
+    }
+
+    fn check(
+        &mut self,
+        action: &mut ProposedAction,
+        _client_data: &mut ClientRef,
+    ) -> ProposedActionStatus {
+        match &action.action {
+            ActionMsg::Vote(vote_action) => {
+                if self.vote_too_late(
+                    &vote_action.proposed_action_id,
+                    vote_action.metadata.sent_at,
+                ) {
+                    info!("vote sent after the deadline: {:?}", vote_action);
+                    return ProposedActionStatus::FAILED;
+                }
+                match self
+                    .action_id_to_poll
+                    .get_mut(&vote_action.proposed_action_id)
+                {
+                    Some(poll) => {
+                        if !poll.record_vote(
+                            &vote_action.get_metadata().sender,
+                            &vote_action.vote_value,
+                        ) {
+                            info!("vote not registered: {:?}", vote_action);
+                        }
+                        ProposedActionStatus::PASSED
+                    }
+                    // The vote already closed, or never opened
+                    None => ProposedActionStatus::FAILED,
+                }
+            }
+            ActionMsg::ClosePoll(close_action) => {
+                let proposed_action_id = &close_action.proposed_action_id;
+                let deadline_passed = matches!(
+                    (
+                        self.action_id_to_deadline.get(proposed_action_id),
+                        close_action.metadata.sent_at,
+                    ),
+                    (Some(deadline), Some(sent_at)) if sent_at >= *deadline
+                );
+                if deadline_passed && self.action_id_to_poll.contains_key(proposed_action_id) {
+                    self.closed_action_ids.insert(proposed_action_id.clone());
+                    ProposedActionStatus::PASSED
+                } else {
+                    // Too early, or the vote already closed or never opened
+                    ProposedActionStatus::FAILED
+                }
+            }
+            _ => {
+                let poll = match self
+                    .action_id_to_poll
+                    .get(&action.action.get_metadata().action_id)
+                {
+                    Some(poll) => poll,
+                    None => return ProposedActionStatus::FAILED,
+                };
+                let yes_votes = poll.get_yes_votes();
+                let no_votes = poll.get_no_votes();
+                let everyone_voted = yes_votes + no_votes >= poll.get_num_eligible_voters();
+                let closed = self
+                    .closed_action_ids
+                    .contains(&action.action.get_metadata().action_id);
+                if !everyone_voted && !closed {
+                    ProposedActionStatus::PROPOSED
+                } else if yes_votes > no_votes {
+                    ProposedActionStatus::PASSED
+                } else {
+                    ProposedActionStatus::FAILED
+                }
+            }
+        }
+    }
+
+    fn pass(&mut self, action: &mut ProposedAction, client_data: &mut ClientRef) {
+        if let ActionMsg::Vote(_) | ActionMsg::ClosePoll(_) = &action.action {
+            return;
+        }
+        action.action.execute(client_data.deref_mut());
+        self.forget(&action.action.get_metadata().action_id);
+    }
+
+    fn fail(&mut self, action: &mut ProposedAction, _client_data: &mut ClientRef) {
+        if let ActionMsg::Vote(_) | ActionMsg::ClosePoll(_) = &action.action {
+            return;
+        }
+        self.forget(&action.action.get_metadata().action_id);
+    }
+
+    fn release(&mut self, action: &mut ProposedAction, client_data: &mut ClientRef) {
+        self.fail(action, client_data)
+    }
+
+    fn get_policy_obj(&self) -> Box<dyn Policy> {
+        Box::new(self.clone())
+    }
+
+    fn get_policy_name(&self) -> &str {
+        "TimedVotePolicy"
+    }
+
+    fn votes_needed(&self, action_id: &str) -> Option<usize> {
+        self.action_id_to_poll.get(action_id).map(|poll| {
+            let remaining_voters =
+                poll.get_num_eligible_voters() - poll.get_yes_votes() - poll.get_no_votes();
+            let needed = (poll.get_no_votes() + 1).saturating_sub(poll.get_yes_votes());
+            if needed > remaining_voters {
+                VOTES_UNREACHABLE
+            } else {
+                needed
+            }
+        })
+    }
+}
+
+/// A voting policy for the actions of type `target_action_type`, which pass
+/// as soon as `yes_votes_required` members voted yes on them. Each member
+/// eligible at the time of the proposal counts at most once. The action
+/// fails once too few members are left to vote for it to pass.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ThresholdVotePolicy {
+    /// The action type that is put to a vote
+    target_action_type: ActionType,
+    /// How many yes votes pass the action
+    yes_votes_required: usize,
+    /// Maps action ids to polls
+    action_id_to_poll: BTreeMap<String, Poll>,
+}
+
+impl ThresholdVotePolicy {
+    pub fn new(target_action_type: ActionType, yes_votes_required: usize) -> Self {
+        ThresholdVotePolicy {
+            target_action_type,
+            yes_votes_required,
+            action_id_to_poll: BTreeMap::new(),
+        }
+    }
+
+    /// Returns how many more yes votes `poll` needs to pass, or
+    /// [VOTES_UNREACHABLE] if too few members are left to vote
+    fn yes_votes_needed(&self, poll: &Poll) -> usize {
+        let remaining_voters =
+            poll.get_num_eligible_voters() - poll.get_yes_votes() - poll.get_no_votes();
+        let needed = self.yes_votes_required.saturating_sub(poll.get_yes_votes());
+        if needed > remaining_voters {
+            VOTES_UNREACHABLE
+        } else {
+            needed
+        }
+    }
+}
+
+#[typetag::serde]
+impl Policy for ThresholdVotePolicy {
+    /// Allow the target action type, and the votes on it
+    fn filter(&self, action: &ActionMsg, _client_data: &mut ClientRef) -> bool {
+        match action {
+            ActionMsg::Vote(vote_action) => {
+                vote_action.proposed_action_type == self.target_action_type
+            }
+            _ => action.action_type() == self.target_action_type,
+        }
+    }
+
+    /// Open a poll for the proposed action -- only the members present at
+    /// its proposal are eligible to vote
+    fn init(&mut self, action: &mut ProposedAction, client_data: &mut ClientRef) {
+        if let ActionMsg::Vote(_) = &action.action {
+            return;
+        }
+        let action_metadata = action.action.get_metadata();
+        self.action_id_to_poll.insert(
+            action_metadata.action_id.clone(),
+            Poll::new(&client_data.get_group_members(&action_metadata.community_group_id)),
+        );
+        info!(
+            "Voting is happening for action ID: {}",
+            action_metadata.action_id
+        );
+    }
+
+    fn check(
+        &mut self,
+        action: &mut ProposedAction,
+        _client_data: &mut ClientRef,
+    ) -> ProposedActionStatus {
+        match &action.action {
+            ActionMsg::Vote(vote_action) => {
+                match self
+                    .action_id_to_poll
+                    .get_mut(&vote_action.proposed_action_id)
+                {
+                    Some(poll) => {
+                        // A member who already voted is not counted again
+                        if !poll.record_vote(
+                            &vote_action.get_metadata().sender,
+                            &vote_action.vote_value,
+                        ) {
+                            info!("vote not registered: {:?}", vote_action);
+                        }
+                        ProposedActionStatus::PASSED
+                    }
+                    // The vote already closed, or never opened
+                    None => ProposedActionStatus::FAILED,
+                }
+            }
+            _ => {
+                let poll = match self
+                    .action_id_to_poll
+                    .get(&action.action.get_metadata().action_id)
+                {
+                    Some(poll) => poll,
+                    None => return ProposedActionStatus::FAILED,
+                };
+                match self.yes_votes_needed(poll) {
+                    0 => ProposedActionStatus::PASSED,
+                    VOTES_UNREACHABLE => ProposedActionStatus::FAILED,
+                    _ => ProposedActionStatus::PROPOSED,
+                }
+            }
+        }
+    }
+
+    fn pass(&mut self, action: &mut ProposedAction, client_data: &mut ClientRef) {
+        if let ActionMsg::Vote(_) = &action.action {
+            return;
+        }
+        action.action.execute(client_data.deref_mut());
+        self.action_id_to_poll
+            .remove(&action.action.get_metadata().action_id);
+    }
+
+    fn fail(&mut self, action: &mut ProposedAction, _client_data: &mut ClientRef) {
+        if let ActionMsg::Vote(_) = &action.action {
+            return;
+        }
+        self.action_id_to_poll
+            .remove(&action.action.get_metadata().action_id);
+    }
+
+    fn release(&mut self, action: &mut ProposedAction, client_data: &mut ClientRef) {
+        self.fail(action, client_data)
+    }
+
+    fn get_policy_obj(&self) -> Box<dyn Policy> {
+        Box::new(self.clone())
+    }
+
+    fn get_policy_name(&self) -> &str {
+        "ThresholdVotePolicy"
+    }
+
+    fn votes_needed(&self, action_id: &str) -> Option<usize> {
+        self.action_id_to_poll
+            .get(action_id)
+            .map(|poll| self.yes_votes_needed(poll))
+    }
+}
+
+/// A policy that lets text messages through, but takes down those matching
+/// any of the `blocked_patterns` regexes. Only one member sends the takedown:
+/// the first member, by name, allowed to take down messages. If that is the
+/// local user, the takedown is queued in the client's outbox.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AutoModerationPolicy {
+    /// The regexes a text message must not match. Invalid ones are ignored
+    blocked_patterns: Vec<String>,
+    /// `blocked_patterns` compiled, once they are first needed
+    #[serde(skip)]
+    compiled_patterns: Option<Vec<(String, Regex)>>,
+}
+
+impl AutoModerationPolicy {
+    pub fn new(blocked_patterns: Vec<String>) -> Self {
+        AutoModerationPolicy {
+            blocked_patterns,
+            compiled_patterns: None,
+        }
+    }
+
+    /// Returns the first blocked pattern matched by `text`, if any
+    fn matched_pattern(&mut self, text: &str) -> Option<&String> {
+        let blocked_patterns = &self.blocked_patterns;
+        self.compiled_patterns
+            .get_or_insert_with(|| {
+                blocked_patterns
+                    .iter()
+                    .filter_map(|pattern| match Regex::new(pattern) {
+                        Ok(regex) => Some((pattern.to_owned(), regex)),
+                        Err(e) => {
+                            warn!("Ignoring the invalid blocked pattern {}: {}", pattern, e);
+                            None
+                        }
+                    })
+                    .collect()
+            })
+            .iter()
+            .find(|(_, regex)| regex.is_match(text))
+            .map(|(pattern, _)| pattern)
+    }
+}
+
+#[typetag::serde]
+impl Policy for AutoModerationPolicy {
+    fn filter(&self, action: &ActionMsg, _client_data: &mut ClientRef) -> bool {
+        matches!(action, ActionMsg::TextMsg(_))
+    }
+
+    fn init(&mut self, _action: &mut ProposedAction, _client_data: &mut ClientRef) {}
+
+    fn check(
+        &mut self,
+        _action: &mut ProposedAction,
+        _client_data: &mut ClientRef,
+    ) -> ProposedActionStatus {
+        ProposedActionStatus::PASSED
+    }
+
+    fn pass(&mut self, action: &mut ProposedAction, client_data: &mut ClientRef) {
+        if let ActionMsg::TextMsg(text_action) = &action.action {
+            text_action.execute(client_data.deref_mut());
+            let pattern = match self.matched_pattern(&text_action.msg) {
+                Some(pattern) => pattern.to_owned(),
+                None => return,
+            };
+            let metadata = text_action.get_metadata();
+            let comm_grp = &metadata.community_group_id;
+            let mut members = client_data.get_group_members(comm_grp);
+            members.sort();
+            let moderator = members.into_iter().find(|member| {
+                client_data.can_perform(comm_grp, ActionType::TakedownTextMsg, member)
+            });
+            if moderator != Some(client_data.get_user_id()) {
+                return;
+            }
+            info!(
+                "Message {} matches the blocked pattern {}, taking it down",
+                metadata.action_id, pattern
+            );
+            let takedown_action = ActionMsg::TakedownTextMsg(TakedownTextMsgAction {
+                message_id: metadata.action_id.to_owned(),
+                reason: format!("Matches the blocked pattern {}", pattern),
+                metadata: ActionMetadata::new(
+                    client_data.get_user_id(),
+                    Uuid::new_v4().to_string(),
+                    comm_grp.to_owned(),
+                    client_data.now(),
+                ),
+            });
+            client_data.push_outbox_action(comm_grp, takedown_action);
+        }
+    }
+
+    fn fail(&mut self, _action: &mut ProposedAction, _client_data: &mut ClientRef) {}
+
+    fn get_policy_obj(&self) -> Box<dyn Policy> {
+        Box::new(self.clone())
+    }
+
+    fn get_policy_name(&self) -> &str {
+        "AutoModerationPolicy"
+    }
+}
@@ -2,17 +2,64 @@
 mod policyengine_tests {
     use std::cell::RefCell;
     use std::rc::Rc;
+    use std::time::{Duration, SystemTime};
+
+    use serde::{Deserialize, Serialize};
 
     use crate::client_api::actions::{
-        ActionMetadata, ActionMsg, ActionType, CustomAction, RenameGroupAction, VoteAction,
+        ActionMetadata, ActionMsg, ActionType, ClosePollAction, CustomAction, KickAction,
+        RenameGroupAction, TextMsgAction, VoteAction,
     };
     use crate::client_api::client_struct::{ClientDataProvider, MockClientDataProvider};
     use crate::policyengine::policies::{
-        PassAllPolicy, ReputationChangeAction, ReputationNameChangePolicy, VoteOnNameChangePolicy,
+        PassAllPolicy, RateLimitPolicy, ReputationChangeAction, ReputationNameChangePolicy,
+        ThresholdVotePolicy, TimedVotePolicy, VoteOnNameChangePolicy,
+    };
+    use crate::policyengine::{
+        ClientRef, Policy, PolicyDecision, PolicyEngine, PolicyMatchMode, ProposedAction,
+        ProposedActionStatus,
     };
-    use crate::policyengine::{Policy, PolicyEngine};
     use crate::CommGroupId;
 
+    /// Fails text messages, and blocks them instead if `block` is set
+    #[derive(Serialize, Deserialize)]
+    struct RejectTextPolicy {
+        block: bool,
+    }
+
+    #[typetag::serde]
+    impl Policy for RejectTextPolicy {
+        fn filter(&self, action: &ActionMsg, _client_data: &mut ClientRef) -> bool {
+            matches!(action, ActionMsg::TextMsg(_))
+        }
+
+        fn init(&mut self, _action: &mut ProposedAction, _client_data: &mut ClientRef) {}
+
+        fn check(
+            &mut self,
+            _action: &mut ProposedAction,
+            _client_data: &mut ClientRef,
+        ) -> ProposedActionStatus {
+            if self.block {
+                ProposedActionStatus::BLOCKED
+            } else {
+                ProposedActionStatus::FAILED
+            }
+        }
+
+        fn pass(&mut self, _action: &mut ProposedAction, _client_data: &mut ClientRef) {}
+
+        fn fail(&mut self, _action: &mut ProposedAction, _client_data: &mut ClientRef) {}
+
+        fn get_policy_obj(&self) -> Box<dyn Policy> {
+            Box::new(RejectTextPolicy { block: self.block })
+        }
+
+        fn get_policy_name(&self) -> &str {
+            "RejectTextPolicy"
+        }
+    }
+
     #[test]
     fn test_pass_all_actions() {
         // Initialize a mock client
@@ -26,6 +73,11 @@ mod policyengine_tests {
             })
             .times(1)
             .returning(|_, _| ());
+        mock_client
+            .expect_get_group_name_version()
+            .return_const((0, "".to_string()));
+        mock_client.expect_set_group_name_version().return_const(());
+        mock_client.expect_is_group_name_taken().return_const(false);
         let mut mock_client_ref = Box::new(mock_client) as Box<dyn ClientDataProvider>;
 
         // Initialize a new policy
@@ -37,6 +89,7 @@ mod policyengine_tests {
         // Generate a test action
         let rename_action = ActionMsg::RenameGroup(RenameGroupAction {
             new_name: "new name".to_string(),
+            lamport_counter: 1,
             metadata: ActionMetadata {
                 sender: "sender".to_string(),
                 action_id: "rename_action_id".to_string(),
@@ -45,6 +98,7 @@ mod policyengine_tests {
                     &"test_group".to_string(),
                 ),
                 data: "".to_string(),
+                sent_at: None,
             },
         });
 
@@ -76,6 +130,11 @@ mod policyengine_tests {
             })
             .times(1)
             .returning(|_, _| ());
+        mock_client
+            .expect_get_group_name_version()
+            .return_const((0, "".to_string()));
+        mock_client.expect_set_group_name_version().return_const(());
+        mock_client.expect_is_group_name_taken().return_const(false);
         let mut mock_client_ref = Box::new(mock_client) as Box<dyn ClientDataProvider>;
 
         let vote_policy = VoteOnNameChangePolicy::new();
@@ -84,6 +143,7 @@ mod policyengine_tests {
         // Generate a test action
         let rename_action = ActionMsg::RenameGroup(RenameGroupAction {
             new_name: "new name".to_string(),
+            lamport_counter: 1,
             metadata: ActionMetadata {
                 sender: "sender".to_string(),
                 action_id: "rename_action_id".to_string(),
@@ -92,6 +152,7 @@ mod policyengine_tests {
                     &"test_group".to_string(),
                 ),
                 data: "".to_string(),
+                sent_at: None,
             },
         });
 
@@ -113,6 +174,7 @@ mod policyengine_tests {
                     &"test_group".to_string(),
                 ),
                 data: "".to_string(),
+                sent_at: None,
             },
         });
 
@@ -128,6 +190,7 @@ mod policyengine_tests {
                     &"test_group".to_string(),
                 ),
                 data: "".to_string(),
+                sent_at: None,
             },
         });
 
@@ -143,6 +206,7 @@ mod policyengine_tests {
                     &"test_group".to_string(),
                 ),
                 data: "".to_string(),
+                sent_at: None,
             },
         });
 
@@ -172,6 +236,11 @@ mod policyengine_tests {
             })
             .times(1)
             .returning(|_, _| ());
+        mock_client
+            .expect_get_group_name_version()
+            .return_const((0, "".to_string()));
+        mock_client.expect_set_group_name_version().return_const(());
+        mock_client.expect_is_group_name_taken().return_const(false);
 
         let mut mock_client_ref = Box::new(mock_client) as Box<dyn ClientDataProvider>;
         let alice_rep_action = ActionMsg::Custom(CustomAction {
@@ -188,6 +257,7 @@ mod policyengine_tests {
                     &"test_group".to_string(),
                 ),
                 data: "".to_string(),
+                sent_at: None,
             },
         });
 
@@ -205,6 +275,7 @@ mod policyengine_tests {
                     &"test_group".to_string(),
                 ),
                 data: "".to_string(),
+                sent_at: None,
             },
         });
 
@@ -222,11 +293,13 @@ mod policyengine_tests {
                     &"test_group".to_string(),
                 ),
                 data: "".to_string(),
+                sent_at: None,
             },
         });
 
         let diane_rename_action = ActionMsg::RenameGroup(RenameGroupAction {
             new_name: "new name".to_string(),
+            lamport_counter: 1,
             metadata: ActionMetadata {
                 sender: "diane".to_string(),
                 action_id: "diane_rename_id".to_string(),
@@ -235,6 +308,7 @@ mod policyengine_tests {
                     &"test_group".to_string(),
                 ),
                 data: "".to_string(),
+                sent_at: None,
             },
         });
 
@@ -247,4 +321,488 @@ mod policyengine_tests {
         policy_engine.evaluate_action(charlie_rep_action, &mut mock_client_ref);
         policy_engine.evaluate_action(diane_rename_action, &mut mock_client_ref);
     }
+
+    /// 5 text messages within a second should pass, the 6th fail, and the
+    /// sender should be able to send again once the window moved on
+    #[test]
+    fn test_rate_limit_policy() {
+        let mut mock_client_ref =
+            Box::new(MockClientDataProvider::new()) as Box<dyn ClientDataProvider>;
+        let rate_limit = RateLimitPolicy::new(vec![ActionType::TextMsg], 5, Duration::from_secs(1));
+        let rate_limit_ref = Rc::new(RefCell::new(Box::new(rate_limit) as Box<dyn Policy>));
+
+        let start = SystemTime::now();
+        let mut check_text_msg = |sender: &str, millis_after_start: u64| {
+            let text_action = ActionMsg::TextMsg(TextMsgAction {
+                msg: "spam".to_string(),
+                expires_after: None,
+                in_reply_to: None,
+                metadata: ActionMetadata {
+                    sender: sender.to_string(),
+                    action_id: format!("{}_{}", sender, millis_after_start),
+                    community_group_id: CommGroupId::new(
+                        &"test_community".to_string(),
+                        &"test_group".to_string(),
+                    ),
+                    data: "".to_string(),
+                    sent_at: Some(start + Duration::from_millis(millis_after_start)),
+                },
+            });
+            assert!(rate_limit_ref
+                .borrow()
+                .filter(&text_action, &mut mock_client_ref));
+            let mut proposed_action = ProposedAction::new(
+                text_action,
+                rate_limit_ref.clone(),
+                ProposedActionStatus::PROPOSED,
+            );
+            rate_limit_ref
+                .borrow_mut()
+                .check(&mut proposed_action, &mut mock_client_ref)
+        };
+
+        for i in 0..5 {
+            assert_eq!(
+                check_text_msg("spammer", i * 150),
+                ProposedActionStatus::PASSED
+            );
+        }
+        assert_eq!(check_text_msg("spammer", 800), ProposedActionStatus::FAILED);
+        // Other senders have their own window
+        assert_eq!(check_text_msg("bob", 850), ProposedActionStatus::PASSED);
+        // The first message left the window
+        assert_eq!(
+            check_text_msg("spammer", 1000),
+            ProposedActionStatus::PASSED
+        );
+        assert_eq!(
+            check_text_msg("spammer", 1100),
+            ProposedActionStatus::FAILED
+        );
+        assert_eq!(
+            check_text_msg("spammer", 2500),
+            ProposedActionStatus::PASSED
+        );
+    }
+
+    /// A timed vote should stay open until closed by an action sent past its
+    /// deadline, even with a majority, and not count the votes sent past it
+    #[test]
+    fn test_timed_vote_closes_on_close_poll() {
+        let mut mock_client = MockClientDataProvider::new();
+        mock_client.expect_get_group_members().return_const(vec![
+            "alice".to_string(),
+            "bob".to_string(),
+            "charlie".to_string(),
+        ]);
+        mock_client
+            .expect_set_group_name()
+            .withf(|_: &CommGroupId, new_name: &String| new_name.eq("new name"))
+            .times(1)
+            .returning(|_, _| ());
+        mock_client
+            .expect_get_group_name_version()
+            .return_const((0, "".to_string()));
+        mock_client.expect_set_group_name_version().return_const(());
+        mock_client.expect_is_group_name_taken().return_const(false);
+        let mut mock_client_ref = Box::new(mock_client) as Box<dyn ClientDataProvider>;
+
+        let timed_vote =
+            TimedVotePolicy::new(vec![ActionType::RenameGroup], Duration::from_secs(3600));
+        let timed_vote_ref = Rc::new(RefCell::new(Box::new(timed_vote) as Box<dyn Policy>));
+        let mut policy_engine = PolicyEngine::new(vec![timed_vote_ref]);
+
+        let start = SystemTime::now();
+        let metadata = |sender: &str, action_id: &str, after_secs: u64| ActionMetadata {
+            sent_at: Some(start + Duration::from_secs(after_secs)),
+            ..test_metadata(sender, action_id)
+        };
+        let vote = |voter: &str, vote_value: &str, after_secs: u64| {
+            ActionMsg::Vote(VoteAction {
+                vote_value: vote_value.to_string(),
+                proposed_action_id: "rename_action_id".to_string(),
+                proposed_action_type: ActionType::RenameGroup,
+                metadata: metadata(voter, &format!("{}_vote_id", voter), after_secs),
+            })
+        };
+        let close_poll = |after_secs: u64| {
+            ActionMsg::ClosePoll(ClosePollAction {
+                proposed_action_id: "rename_action_id".to_string(),
+                proposed_action_type: ActionType::RenameGroup,
+                metadata: metadata("sender", &format!("close_{}", after_secs), after_secs),
+            })
+        };
+        policy_engine.evaluate_action(
+            ActionMsg::RenameGroup(RenameGroupAction {
+                new_name: "new name".to_string(),
+                lamport_counter: 1,
+                metadata: metadata("sender", "rename_action_id", 0),
+            }),
+            &mut mock_client_ref,
+        );
+        policy_engine.evaluate_action(vote("alice", "yes", 0), &mut mock_client_ref);
+        policy_engine.evaluate_all_proposed_actions(&mut mock_client_ref);
+        assert_eq!(
+            policy_engine.proposed_actions[0].reevaluate_after,
+            Some(start + Duration::from_secs(3600))
+        );
+
+        // Before the deadline, closing changes nothing
+        policy_engine.evaluate_action(close_poll(1800), &mut mock_client_ref);
+        policy_engine.evaluate_all_proposed_actions(&mut mock_client_ref);
+        assert_eq!(policy_engine.proposed_actions.len(), 1);
+
+        // A vote sent past the deadline does not count, even before the close
+        policy_engine.evaluate_action(vote("bob", "no", 3601), &mut mock_client_ref);
+        policy_engine.evaluate_all_proposed_actions(&mut mock_client_ref);
+        assert_eq!(policy_engine.votes_needed("rename_action_id"), 0);
+
+        // Past the deadline, the votes cast in time decide
+        policy_engine.evaluate_action(close_poll(3601), &mut mock_client_ref);
+        policy_engine.evaluate_all_proposed_actions(&mut mock_client_ref);
+        assert!(policy_engine.proposed_actions.is_empty());
+    }
+
+    /// Returns metadata of an action sent by `sender` to the test group
+    fn test_metadata(sender: &str, action_id: &str) -> ActionMetadata {
+        ActionMetadata {
+            sender: sender.to_string(),
+            action_id: action_id.to_string(),
+            community_group_id: CommGroupId::new(
+                &"test_community".to_string(),
+                &"test_group".to_string(),
+            ),
+            data: "".to_string(),
+            sent_at: None,
+        }
+    }
+
+    /// Returns a vote of `voter` on the action `proposed_action_id`
+    fn test_vote(
+        voter: &str,
+        vote_id: &str,
+        proposed_action_id: &str,
+        proposed_action_type: ActionType,
+    ) -> ActionMsg {
+        ActionMsg::Vote(VoteAction {
+            vote_value: "yes".to_string(),
+            proposed_action_id: proposed_action_id.to_string(),
+            proposed_action_type,
+            metadata: test_metadata(voter, vote_id),
+        })
+    }
+
+    /// A kick needing 2 yes votes should not pass on a repeated vote of the
+    /// same member, only once another member votes yes
+    #[test]
+    fn test_threshold_vote_on_kick() {
+        let mut mock_client = MockClientDataProvider::new();
+        mock_client.expect_get_group_members().return_const(vec![
+            "alice".to_string(),
+            "bob".to_string(),
+            "charlie".to_string(),
+            "diane".to_string(),
+        ]);
+        mock_client
+            .expect_store_to_be_removed_member()
+            .withf(|_: &CommGroupId, member: &String| member == "diane")
+            .times(1)
+            .return_const(());
+        mock_client
+            .expect_get_user_id()
+            .return_const("alice".to_string());
+        let mut mock_client_ref = Box::new(mock_client) as Box<dyn ClientDataProvider>;
+        let threshold_vote_ref = Rc::new(RefCell::new(Box::new(ThresholdVotePolicy::new(
+            ActionType::Kick,
+            2,
+        )) as Box<dyn Policy>));
+        let mut policy_engine = PolicyEngine::new(vec![threshold_vote_ref]);
+
+        policy_engine.evaluate_action(
+            ActionMsg::Kick(KickAction {
+                target_user_id: "diane".to_string(),
+                metadata: test_metadata("alice", "kick_action_id"),
+            }),
+            &mut mock_client_ref,
+        );
+        for vote_id in ["bob_vote_id", "bob_vote_again_id"] {
+            policy_engine.evaluate_action(
+                test_vote("bob", vote_id, "kick_action_id", ActionType::Kick),
+                &mut mock_client_ref,
+            );
+        }
+        policy_engine.evaluate_all_proposed_actions(&mut mock_client_ref);
+        assert_eq!(policy_engine.votes_needed("kick_action_id"), 1);
+
+        policy_engine.evaluate_action(
+            test_vote(
+                "charlie",
+                "charlie_vote_id",
+                "kick_action_id",
+                ActionType::Kick,
+            ),
+            &mut mock_client_ref,
+        );
+        policy_engine.evaluate_all_proposed_actions(&mut mock_client_ref);
+        assert!(policy_engine.proposed_actions.is_empty());
+    }
+
+    /// A rename needing a single yes vote should pass on the first one, and
+    /// votes on other action types should not count toward it
+    #[test]
+    fn test_threshold_vote_on_rename() {
+        let mut mock_client = MockClientDataProvider::new();
+        mock_client.expect_get_group_members().return_const(vec![
+            "alice".to_string(),
+            "bob".to_string(),
+            "charlie".to_string(),
+        ]);
+        mock_client
+            .expect_set_group_name()
+            .withf(|_: &CommGroupId, new_name: &String| new_name.eq("new name"))
+            .times(1)
+            .returning(|_, _| ());
+        mock_client
+            .expect_get_group_name_version()
+            .return_const((0, "".to_string()));
+        mock_client.expect_set_group_name_version().return_const(());
+        mock_client.expect_is_group_name_taken().return_const(false);
+        let mut mock_client_ref = Box::new(mock_client) as Box<dyn ClientDataProvider>;
+        let threshold_vote_ref = Rc::new(RefCell::new(Box::new(ThresholdVotePolicy::new(
+            ActionType::RenameGroup,
+            1,
+        )) as Box<dyn Policy>));
+        let mut policy_engine = PolicyEngine::new(vec![threshold_vote_ref]);
+
+        policy_engine.evaluate_action(
+            ActionMsg::RenameGroup(RenameGroupAction {
+                new_name: "new name".to_string(),
+                lamport_counter: 1,
+                metadata: test_metadata("alice", "rename_action_id"),
+            }),
+            &mut mock_client_ref,
+        );
+        // Not a vote on a rename, so not for this policy to count
+        policy_engine.evaluate_action(
+            test_vote("bob", "bob_vote_id", "rename_action_id", ActionType::Kick),
+            &mut mock_client_ref,
+        );
+        policy_engine.evaluate_all_proposed_actions(&mut mock_client_ref);
+        assert_eq!(policy_engine.votes_needed("rename_action_id"), 1);
+
+        policy_engine.evaluate_action(
+            test_vote(
+                "charlie",
+                "charlie_vote_id",
+                "rename_action_id",
+                ActionType::RenameGroup,
+            ),
+            &mut mock_client_ref,
+        );
+        policy_engine.evaluate_all_proposed_actions(&mut mock_client_ref);
+        assert!(policy_engine.proposed_actions.is_empty());
+    }
+
+    /// An action put to a vote should be logged as pending under the voting
+    /// policy, along with the votes it needs
+    #[test]
+    fn test_proposed_action_decision_logged() {
+        let mut mock_client = MockClientDataProvider::new();
+        mock_client.expect_get_group_members().return_const(vec![
+            "alice".to_string(),
+            "bob".to_string(),
+            "charlie".to_string(),
+        ]);
+        let mut mock_client_ref = Box::new(mock_client) as Box<dyn ClientDataProvider>;
+        let vote_policy_ref = Rc::new(RefCell::new(
+            Box::new(VoteOnNameChangePolicy::new()) as Box<dyn Policy>
+        ));
+        let mut policy_engine = PolicyEngine::new(vec![vote_policy_ref]);
+
+        policy_engine.evaluate_action(
+            ActionMsg::RenameGroup(RenameGroupAction {
+                new_name: "new name".to_string(),
+                lamport_counter: 1,
+                metadata: ActionMetadata {
+                    sender: "sender".to_string(),
+                    action_id: "rename_action_id".to_string(),
+                    community_group_id: CommGroupId::new(
+                        &"test_community".to_string(),
+                        &"test_group".to_string(),
+                    ),
+                    data: "".to_string(),
+                    sent_at: None,
+                },
+            }),
+            &mut mock_client_ref,
+        );
+        // Re-evaluating a still pending action logs nothing new
+        policy_engine.evaluate_all_proposed_actions(&mut mock_client_ref);
+
+        assert_eq!(
+            policy_engine.decision_log.iter().collect::<Vec<_>>(),
+            vec![&PolicyDecision {
+                action_id: "rename_action_id".to_string(),
+                policy_name: "VoteOnNameChangePolicy".to_string(),
+                status: ProposedActionStatus::PROPOSED,
+                reason: "Pending until 2 more yes vote(s)".to_string(),
+            }]
+        );
+    }
+
+    /// A blocked action should be dropped and logged as blocked, and never be
+    /// evaluated again, unlike a failed one
+    #[test]
+    fn test_blocked_action_not_reevaluated() {
+        let mut mock_client_ref =
+            Box::new(MockClientDataProvider::new()) as Box<dyn ClientDataProvider>;
+        let text_msg = |action_id: &str| {
+            ActionMsg::TextMsg(TextMsgAction {
+                msg: "spam".to_string(),
+                expires_after: None,
+                in_reply_to: None,
+                metadata: test_metadata("spammer", action_id),
+            })
+        };
+        let reject_policy = Rc::new(RefCell::new(
+            Box::new(RejectTextPolicy { block: false }) as Box<dyn Policy>
+        ));
+        let block_policy = Rc::new(RefCell::new(
+            Box::new(RejectTextPolicy { block: true }) as Box<dyn Policy>
+        ));
+        let mut rejecting_engine = PolicyEngine::new(vec![reject_policy]);
+        let mut blocking_engine = PolicyEngine::new(vec![block_policy]);
+
+        for _ in 0..2 {
+            rejecting_engine.evaluate_action(text_msg("failed_id"), &mut mock_client_ref);
+            blocking_engine.evaluate_action(text_msg("blocked_id"), &mut mock_client_ref);
+        }
+
+        assert!(rejecting_engine.proposed_actions.is_empty());
+        assert!(blocking_engine.proposed_actions.is_empty());
+        // The failed action is evaluated each time it is received
+        assert_eq!(rejecting_engine.decision_log.len(), 2);
+        assert!(rejecting_engine.blocked_action_ids.is_empty());
+        assert_eq!(
+            blocking_engine.decision_log.iter().collect::<Vec<_>>(),
+            vec![&PolicyDecision {
+                action_id: "blocked_id".to_string(),
+                policy_name: "RejectTextPolicy".to_string(),
+                status: ProposedActionStatus::BLOCKED,
+                reason: "Blocked by the policy for good".to_string(),
+            }]
+        );
+        // The block survives serialization
+        assert!(blocking_engine
+            .clone()
+            .blocked_action_ids
+            .contains("blocked_id"));
+        assert_eq!(
+            blocking_engine.replay_actions(vec![text_msg("blocked_id")], &mut mock_client_ref),
+            vec![text_msg("blocked_id")]
+        );
+        assert_eq!(blocking_engine.decision_log.len(), 1);
+    }
+
+    /// Returns a client of a group of three members, expecting the group to
+    /// be renamed exactly once
+    fn renamed_once_client() -> ClientRef {
+        let mut mock_client = MockClientDataProvider::new();
+        mock_client.expect_get_group_members().return_const(vec![
+            "alice".to_string(),
+            "bob".to_string(),
+            "charlie".to_string(),
+        ]);
+        mock_client
+            .expect_set_group_name()
+            .times(1)
+            .returning(|_, _| ());
+        mock_client
+            .expect_get_group_name_version()
+            .return_const((0, "".to_string()));
+        mock_client.expect_set_group_name_version().return_const(());
+        mock_client.expect_is_group_name_taken().return_const(false);
+        Box::new(mock_client)
+    }
+
+    /// Returns a rename policy that passes everything, followed by one that
+    /// puts renames to a vote
+    fn overlapping_policies() -> Vec<Rc<RefCell<Box<dyn Policy>>>> {
+        vec![
+            Rc::new(RefCell::new(Box::new(PassAllPolicy {}) as Box<dyn Policy>)),
+            Rc::new(RefCell::new(
+                Box::new(VoteOnNameChangePolicy::new()) as Box<dyn Policy>
+            )),
+        ]
+    }
+
+    fn test_rename() -> ActionMsg {
+        ActionMsg::RenameGroup(RenameGroupAction {
+            new_name: "new name".to_string(),
+            lamport_counter: 1,
+            metadata: test_metadata("sender", "rename_action_id"),
+        })
+    }
+
+    /// In the default mode, a rename should only be subject to the first of
+    /// two matching policies
+    #[test]
+    fn test_first_match_mode() {
+        let mut mock_client_ref = renamed_once_client();
+        let mut policy_engine = PolicyEngine::new(overlapping_policies());
+        assert_eq!(policy_engine.match_mode, PolicyMatchMode::FirstMatch);
+
+        policy_engine.evaluate_action(test_rename(), &mut mock_client_ref);
+
+        assert!(policy_engine.proposed_actions.is_empty());
+        assert_eq!(policy_engine.decision_log.len(), 1);
+        assert_eq!(policy_engine.decision_log[0].policy_name, "PassAllPolicy");
+    }
+
+    /// With all policies matching, a rename that one policy passes right away
+    /// should wait for the vote of the other, and then be executed once
+    #[test]
+    fn test_all_match_mode() {
+        let mut mock_client_ref = renamed_once_client();
+        let mut policy_engine =
+            PolicyEngine::new(overlapping_policies()).with_match_mode(PolicyMatchMode::AllMatch);
+
+        policy_engine.evaluate_action(test_rename(), &mut mock_client_ref);
+        assert_eq!(policy_engine.proposed_actions.len(), 2);
+        assert_eq!(
+            policy_engine
+                .proposed_actions
+                .iter()
+                .map(|proposed_action| proposed_action.status)
+                .collect::<Vec<_>>(),
+            vec![ProposedActionStatus::PASSED, ProposedActionStatus::PROPOSED]
+        );
+
+        for voter in ["alice", "bob"] {
+            policy_engine.evaluate_action(
+                test_vote(
+                    voter,
+                    &format!("{}_vote_id", voter),
+                    "rename_action_id",
+                    ActionType::RenameGroup,
+                ),
+                &mut mock_client_ref,
+            );
+        }
+        policy_engine.evaluate_all_proposed_actions(&mut mock_client_ref);
+        assert_eq!(policy_engine.proposed_actions.len(), 2);
+
+        policy_engine.evaluate_action(
+            test_vote(
+                "charlie",
+                "charlie_vote_id",
+                "rename_action_id",
+                ActionType::RenameGroup,
+            ),
+            &mut mock_client_ref,
+        );
+        policy_engine.evaluate_all_proposed_actions(&mut mock_client_ref);
+        assert!(policy_engine.proposed_actions.is_empty());
+        assert_eq!(policy_engine.votes_needed("rename_action_id"), 0);
+    }
 }
@@ -1,11 +1,27 @@
 //! A module to declare all authentication server data structures,
 //! and their helper functions
 use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
 
 use dashmap::DashMap;
-use ed25519_dalek::PublicKey;
+use ed25519_dalek::{Keypair, PublicKey, Signature, Signer, Verifier};
 use openmls::credentials::Credential;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::clock::SharedClock;
+use crate::messages::{
+    device_endorsement_content, encode_to_bytes, prefs_request_content, rotate_key_request_content,
+    AuthToken, DeviceEndorsement, UserRequestErrors,
+};
+use crate::state_store::{legacy_schema_version, StateStoreError, VersionedState};
+use crate::{generate_verification_key, identity_to_str, split_device};
+
+/// How long a token issued by the AS is accepted by the DS
+pub const TOKEN_LIFETIME: Duration = Duration::from_secs(15 * 60);
+/// How far the time a token was requested at may be from the time of the AS,
+/// so that a captured request cannot be replayed later
+pub const TOKEN_REQUEST_MAX_SKEW: Duration = Duration::from_secs(5 * 60);
 
 #[derive(Default, Debug, Clone)]
 pub struct AuthServiceParam {
@@ -19,25 +35,328 @@ pub struct AuthServiceParam {
 pub struct CredentialEntry {
     pub(crate) credential: Credential,
     pub(crate) verification_key: PublicKey,
+    /// Whether the AS has revoked this credential
+    #[serde(default)]
+    pub(crate) revoked: bool,
+    /// The name shown to other users, which unlike the identity of the
+    /// credential need not be unique. Empty if none was registered
+    #[serde(default)]
+    pub(crate) display_name: String,
+    /// How many times the key was rotated, which a key rotation signs so that
+    /// it cannot be replayed
+    #[serde(default)]
+    pub(crate) key_rotations: u64,
 }
 
-#[derive(Default, Debug, Serialize, Deserialize, Clone)]
+impl CredentialEntry {
+    pub fn is_revoked(&self) -> bool {
+        self.revoked
+    }
+
+    pub fn key_rotations(&self) -> u64 {
+        self.key_rotations
+    }
+
+    pub fn display_name(&self) -> &str {
+        &self.display_name
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct AuthServiceState {
+    /// The version of the layout of the stored state
+    #[serde(default = "legacy_schema_version")]
+    pub schema_version: u32,
     // Maps from User IDs to their public credential
     //#[serde(with = "any_key_map")]
     pub credential_entries: DashMap<String, CredentialEntry>,
+    /// The ED25519 keypair the AS signs its credential responses with
+    #[serde(default = "generate_verification_key")]
+    keypair: Keypair,
+    /// The credential types, by their `Debug` names such as `Basic`, that
+    /// users may register with. Any if not set. Taken from the AS config
+    #[serde(skip)]
+    pub allowed_credential_types: Option<Vec<String>>,
+    /// The signature schemes, by their `Debug` names such as `ED25519`, that
+    /// users may register with. Any if not set. Taken from the AS config
+    #[serde(skip)]
+    pub allowed_signature_schemes: Option<Vec<String>>,
+    /// The encrypted notification preferences shared by the devices of each
+    /// user, by user name without device. Opaque to the AS
+    #[serde(default)]
+    pub user_prefs: DashMap<String, Vec<u8>>,
+    /// Where timestamps are read from. The system clock when restored
+    #[serde(skip)]
+    pub clock: SharedClock,
+    /// The credential operators present to revoke the credentials of users.
+    /// Taken from the AS config rather than stored. Nobody may revoke any if
+    /// not set
+    #[serde(skip)]
+    pub admin_credential: Option<String>,
+}
+
+impl Default for AuthServiceState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clone for AuthServiceState {
+    fn clone(&self) -> Self {
+        AuthServiceState {
+            schema_version: self.schema_version,
+            credential_entries: self.credential_entries.clone(),
+            keypair: Keypair::from_bytes(&self.keypair.to_bytes()).expect("Cannot copy keypair"),
+            allowed_credential_types: self.allowed_credential_types.clone(),
+            allowed_signature_schemes: self.allowed_signature_schemes.clone(),
+            user_prefs: self.user_prefs.clone(),
+            clock: self.clock.clone(),
+            admin_credential: self.admin_credential.clone(),
+        }
+    }
+}
+
+impl VersionedState for AuthServiceState {
+    const SCHEMA_VERSION: u32 = 1;
+
+    /// No earlier layout to migrate from yet
+    fn migrate(from_version: u32, _value: &mut serde_yaml::Value) -> Result<(), StateStoreError> {
+        Err(StateStoreError::UnsupportedVersion {
+            found: from_version,
+            supported: Self::SCHEMA_VERSION,
+        })
+    }
 }
 
 impl AuthServiceState {
     pub fn new() -> AuthServiceState {
         AuthServiceState {
+            schema_version: Self::SCHEMA_VERSION,
             credential_entries: DashMap::new(),
+            keypair: generate_verification_key(),
+            allowed_credential_types: None,
+            allowed_signature_schemes: None,
+            user_prefs: DashMap::new(),
+            clock: SharedClock::default(),
+            admin_credential: None,
+        }
+    }
+
+    /// Sets the clock timestamps are read from, e.g., a mock one in tests
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Checks that the type and the signature scheme of `credential` are
+    /// among the allowed ones, so that users cannot register weak credentials
+    pub fn check_credential_allowed(
+        &self,
+        credential: &Credential,
+    ) -> Result<(), UserRequestErrors> {
+        let is_allowed = |allowed: &Option<Vec<String>>, name: String| {
+            allowed
+                .as_ref()
+                .map_or(true, |allowed| allowed.contains(&name))
+        };
+        if is_allowed(
+            &self.allowed_credential_types,
+            format!("{:?}", credential.credential_type()),
+        ) && is_allowed(
+            &self.allowed_signature_schemes,
+            format!("{:?}", credential.signature_scheme()),
+        ) {
+            Ok(())
+        } else {
+            Err(UserRequestErrors::UnsupportedCredentialType)
         }
     }
+
+    /// Checks that a device registering as `name` is endorsed by a registered,
+    /// unrevoked identity of the same user, if the user registered any yet.
+    /// The first identity of a user registers without an endorsement
+    pub fn check_device_endorsement(
+        &self,
+        name: &str,
+        credential: &Credential,
+        verification_key: &PublicKey,
+        endorsement: Option<&DeviceEndorsement>,
+    ) -> Result<(), UserRequestErrors> {
+        let user = split_device(name).0;
+        let has_other_identity = self
+            .credential_entries
+            .iter()
+            .any(|entry| entry.key() != name && split_device(entry.key()).0 == user);
+        if !has_other_identity {
+            return Ok(());
+        }
+        let endorsement = endorsement.ok_or(UserRequestErrors::InvalidDeviceEndorsement)?;
+        match self.credential_entries.get(&endorsement.endorser) {
+            Some(endorser)
+                if split_device(&endorsement.endorser).0 == user
+                    && !endorser.revoked
+                    && endorser
+                        .verification_key
+                        .verify(
+                            &device_endorsement_content(credential, verification_key),
+                            &endorsement.signature,
+                        )
+                        .is_ok() =>
+            {
+                Ok(())
+            }
+            _ => Err(UserRequestErrors::InvalidDeviceEndorsement),
+        }
+    }
+
+    /// Returns the public key clients verify AS signatures with
+    pub fn public_key(&self) -> PublicKey {
+        self.keypair.public
+    }
+
+    /// Signs the serialization of `credentials`, as sent in a credential response
+    pub fn sign_credentials(&self, credentials: &Vec<Credential>) -> Signature {
+        self.keypair.sign(&encode_to_bytes(credentials))
+    }
+
+    /// Issues a token for `user_name` if the request was signed with their
+    /// registered verification key at most [TOKEN_REQUEST_MAX_SKEW] from `now`,
+    /// and their credential is not revoked
+    pub fn issue_token(
+        &self,
+        user_name: &str,
+        requested_at: SystemTime,
+        signature: &Signature,
+        now: SystemTime,
+    ) -> Result<AuthToken, UserRequestErrors> {
+        let entry = self
+            .credential_entries
+            .get(user_name)
+            .ok_or(UserRequestErrors::UnknownUser)?;
+        let skew = match now.duration_since(requested_at) {
+            Ok(elapsed) => elapsed,
+            Err(e) => e.duration(),
+        };
+        if entry.revoked
+            || skew > TOKEN_REQUEST_MAX_SKEW
+            || entry
+                .verification_key
+                .verify(
+                    &AuthToken::request_content(user_name, requested_at),
+                    signature,
+                )
+                .is_err()
+        {
+            return Err(UserRequestErrors::InvalidToken);
+        }
+        let expires_at = now + TOKEN_LIFETIME;
+        Ok(AuthToken {
+            user_name: user_name.to_string(),
+            expires_at,
+            signature: self
+                .keypair
+                .sign(&AuthToken::signed_content(user_name, expires_at)),
+        })
+    }
+
+    /// Replaces the credential and the verification key of `user_name`, if
+    /// signed with both their registered verification key and the new one
+    /// over the current rotation count, their credential is not revoked, and
+    /// the new credential is allowed and for the same identity
+    pub fn rotate_key(
+        &self,
+        user_name: &str,
+        new_credential: Credential,
+        new_verification_key: PublicKey,
+        signature: &Signature,
+        new_key_signature: &Signature,
+    ) -> Result<(), UserRequestErrors> {
+        self.check_credential_allowed(&new_credential)?;
+        let mut entry = self
+            .credential_entries
+            .get_mut(user_name)
+            .ok_or(UserRequestErrors::UnknownUser)?;
+        let content = rotate_key_request_content(
+            user_name,
+            &entry.verification_key,
+            entry.key_rotations,
+            &new_credential,
+            &new_verification_key,
+        );
+        if entry.revoked
+            || identity_to_str(new_credential.identity()).as_deref() != Some(user_name)
+            || entry.verification_key.verify(&content, signature).is_err()
+            || new_verification_key
+                .verify(&content, new_key_signature)
+                .is_err()
+        {
+            return Err(UserRequestErrors::InvalidKeyRotation);
+        }
+        entry.credential = new_credential;
+        entry.verification_key = new_verification_key;
+        entry.key_rotations += 1;
+        Ok(())
+    }
+
+    /// Stores `prefs` for the user of the device `user_name`, replacing the
+    /// ones stored by any of their devices, if signed with the registered
+    /// verification key of that device and its credential is not revoked
+    pub fn store_prefs(
+        &self,
+        user_name: &str,
+        prefs: Vec<u8>,
+        signature: &Signature,
+    ) -> Result<(), UserRequestErrors> {
+        let entry = self
+            .credential_entries
+            .get(user_name)
+            .ok_or(UserRequestErrors::UnknownUser)?;
+        if entry.revoked
+            || entry
+                .verification_key
+                .verify(&prefs_request_content(user_name, &prefs), signature)
+                .is_err()
+        {
+            return Err(UserRequestErrors::InvalidPrefsSignature);
+        }
+        self.user_prefs
+            .insert(split_device(user_name).0.to_string(), prefs);
+        Ok(())
+    }
+
+    /// The preferences stored by any device of the user of `user_name`
+    pub fn load_prefs(&self, user_name: &str) -> Option<Vec<u8>> {
+        self.user_prefs
+            .get(split_device(user_name).0)
+            .map(|prefs| prefs.clone())
+    }
+
     pub fn get_credential_copy(&self, user: String) -> Option<Credential> {
         Some(self.credential_entries.get(&user)?.credential.to_owned())
     }
 
+    /// Returns true if `credential` is the configured admin credential. Their
+    /// hashes are compared, as by the DS
+    pub fn is_admin_credential(&self, credential: &str) -> bool {
+        match &self.admin_credential {
+            Some(admin_credential) => {
+                Sha256::digest(admin_credential.as_bytes()) == Sha256::digest(credential.as_bytes())
+            }
+            None => false,
+        }
+    }
+
+    /// Marks the credential of `user` as revoked. Returns false if no such user
+    pub fn revoke_credential(&self, user: &str) -> bool {
+        match self.credential_entries.get_mut(user) {
+            Some(mut entry) => {
+                entry.revoked = true;
+                true
+            }
+            None => false,
+        }
+    }
+
     pub fn get_all_credentials_copy(&self) -> BTreeMap<String, CredentialEntry> {
         let mut btree_map = BTreeMap::new();
         for (k, v) in self.credential_entries.clone().into_iter() {
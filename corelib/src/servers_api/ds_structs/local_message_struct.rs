@@ -2,7 +2,7 @@ use std::time::SystemTime;
 
 use openmls::messages::Welcome;
 
-use crate::messages::GroupMessage;
+use crate::messages::{EphemeralKind, GroupMessage};
 use crate::CommGroupId;
 
 #[derive(Debug, serde_derive::Serialize, serde_derive::Deserialize, PartialEq, Clone)]
@@ -14,6 +14,9 @@ pub struct ProtectedMessageWithMetaData {
     pub community_group_id: CommGroupId,
     /// For ordered messages, server timestamp must be in the same order with group messages.
     pub server_timestamp: SystemTime,
+    /// Set if only the latest message of this kind needs to be relayed
+    #[serde(default)]
+    pub ephemeral: Option<EphemeralKind>,
 }
 
 #[derive(Debug, serde_derive::Serialize, serde_derive::Deserialize, Clone)]
@@ -24,6 +27,10 @@ pub struct Invite {
     pub invitee: String,
     pub inviter: String,
     pub comm_grp: CommGroupId,
+    /// When the DS received the invite. Invites restored from a state
+    /// stored without it count as received at restore time
+    #[serde(default = "SystemTime::now")]
+    pub created_at: SystemTime,
 }
 
 impl ProtectedMessageWithMetaData {
@@ -34,15 +41,31 @@ impl ProtectedMessageWithMetaData {
             community_group_id,
             ordered,
             server_timestamp,
+            ephemeral: None,
         }
     }
 
+    pub fn with_ephemeral(mut self, ephemeral: Option<EphemeralKind>) -> Self {
+        self.ephemeral = ephemeral;
+        self
+    }
+
+    /// Whether `newer` makes this message obsolete, i.e., both are ephemeral
+    /// messages of the same kind from the same sender in the same group
+    pub fn is_superseded_by(&self, newer: &ProtectedMessageWithMetaData) -> bool {
+        self.ephemeral.is_some()
+            && self.ephemeral == newer.ephemeral
+            && self.community_group_id == newer.community_group_id
+            && self.protected_msg.get_sender().is_some()
+            && self.protected_msg.get_sender() == newer.protected_msg.get_sender()
+    }
+
     pub fn is_ordered(&self) -> bool {
         self.ordered
     }
 
-    pub fn update_timestamp(&mut self) {
-        self.server_timestamp = SystemTime::now();
+    pub fn update_timestamp(&mut self, now: SystemTime) {
+        self.server_timestamp = now;
     }
 }
 
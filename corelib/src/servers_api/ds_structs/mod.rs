@@ -1,21 +1,111 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
 use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::time::{Duration, SystemTime};
 
 use dashmap::DashMap;
+use ed25519_dalek::PublicKey;
+use log::*;
 use openmls::key_packages::KeyPackage;
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
 use local_message_struct::{Invite, ProtectedMessageWithMetaData};
 
-use crate::messages::GroupMessage;
-use crate::{identity_to_str, CommGroupId};
+use crate::clock::SharedClock;
+use crate::messages::{GroupMessage, PendingCounts, UserRequestErrors};
+use crate::state_store::{legacy_schema_version, StateStoreError, VersionedState};
+use crate::{identity_to_str, split_device, CommGroupId};
 
 pub(crate) mod local_message_struct;
 
 pub type MessageID = String;
 
-#[derive(Default, Debug, serde_derive::Serialize, serde_derive::Deserialize, Clone)]
+/// How many client message IDs the DS remembers per group to recognize
+/// retried messages
+pub const MAX_SEEN_CLIENT_MSG_IDS: usize = 1024;
+
+/// How many of the latest commands of each user the DS records for audit
+pub const MAX_AUDIT_ENTRIES_PER_USER: usize = 1000;
+
+/// An operation on the message queues of the DS, as appended to its
+/// write-ahead log
+#[derive(Debug, serde_derive::Serialize, serde_derive::Deserialize, Clone)]
+pub enum WalOp {
+    /// The message `msg_w_meta` was stored as `message_id` for `recipients`
+    Deliver {
+        message_id: MessageID,
+        recipients: Vec<String>,
+        msg_w_meta: ProtectedMessageWithMetaData,
+    },
+    /// The message `message_id` was retrieved by `user`
+    Pop { message_id: MessageID, user: String },
+    /// The messages `message_ids` were removed as stale
+    Gc { message_ids: Vec<MessageID> },
+}
+
+/// The write-ahead log of the DS, appended to at `path`
+#[derive(Debug)]
+pub struct WalFile {
+    path: PathBuf,
+    file: File,
+}
+
+/// A command issued to the DS, as recorded for audit
+#[derive(Debug, serde_derive::Serialize, serde_derive::Deserialize, Clone, PartialEq)]
+pub struct AuditEntry {
+    /// When the DS received the command
+    pub timestamp: SystemTime,
+    /// The name of the [crate::messages::OnWireMessage] variant of the command
+    pub command: String,
+}
+
+/// The depth of the message queues of the DS, kept up to date as messages
+/// are delivered and retrieved
+#[derive(Debug, Default, Clone)]
+pub struct QueueMetrics {
+    /// Maps from User IDs to the number of messages they have yet to retrieve
+    queue_depths: DashMap<String, usize>,
+}
+
+impl QueueMetrics {
+    /// The number of messages yet to be retrieved, counted once per recipient
+    pub fn total_queued(&self) -> usize {
+        self.queue_depths.iter().map(|depth| *depth.value()).sum()
+    }
+
+    /// The number of messages yet to be retrieved by the user with the most
+    pub fn max_queue_depth(&self) -> usize {
+        self.queue_depths
+            .iter()
+            .map(|depth| *depth.value())
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn enqueued(&self, recipients: &[String]) {
+        for recipient in recipients {
+            *self.queue_depths.entry(recipient.to_owned()).or_default() += 1;
+        }
+    }
+
+    fn dequeued(&self, user: &str) {
+        if let Some(mut depth) = self.queue_depths.get_mut(user) {
+            *depth = depth.saturating_sub(1);
+        }
+        self.queue_depths.remove_if(user, |_, depth| *depth == 0);
+    }
+}
+
+#[derive(Debug, serde_derive::Serialize, serde_derive::Deserialize, Clone)]
 pub struct DeliveryServiceState {
+    /// The version of the layout of the stored state
+    #[serde(default = "legacy_schema_version")]
+    pub schema_version: u32,
+
     /// Maps from User IDs to their unretrieved messages IDs
     pub unordered_message_indvl_queues: DashMap<String, Vec<MessageID>>,
 
@@ -26,15 +116,78 @@ pub struct DeliveryServiceState {
     // Maps from GroupID to known recipients and (existing) ordered message IDs.
     pub groups_to_ordered_messages: DashMap<CommGroupId, (HashSet<String>, Vec<MessageID>)>,
 
-    /// Maps from User IDs to their unretrieved messages
-    pub invite_indvl_queues: DashMap<String, Vec<Invite>>,
-
     /// Maps from user ID to their initial key packages at sign-up
     pub user_key_packages: DashMap<String, Vec<KeyPackage>>,
 
     /// Maps from message IDs to (unretrieved recipients, message).
     /// an empty recipient list means the message can be and will be deleted from DS.
     pub message_id_to_message: DashMap<MessageID, (HashSet<String>, ProtectedMessageWithMetaData)>,
+
+    /// Maps from GroupID to the most recent client message IDs sent to the
+    /// group, oldest first, at most `MAX_SEEN_CLIENT_MSG_IDS` of them
+    #[serde(default)]
+    pub seen_client_msg_ids: DashMap<CommGroupId, (HashSet<Uuid>, VecDeque<Uuid>)>,
+
+    /// Maps from User IDs to their unretrieved re-invite requests,
+    /// as (requester, group) pairs
+    #[serde(default)]
+    pub reinvite_request_indvl_queues: DashMap<String, Vec<(String, CommGroupId)>>,
+
+    /// Maps from User IDs to the latest invite to each group they have yet
+    /// to accept, whose welcome is delivered again at every sync
+    #[serde(default)]
+    pub retained_welcome_indvl_queues: DashMap<String, Vec<Invite>>,
+
+    /// Maps from User IDs to the messages relayed at their syncs that they
+    /// have yet to acknowledge, relayed again at every sync until then
+    #[serde(default)]
+    pub unacked_indvl_msgs: DashMap<String, Vec<ProtectedMessageWithMetaData>>,
+
+    /// Maps from User IDs to the latest commands they issued, at most
+    /// [MAX_AUDIT_ENTRIES_PER_USER], oldest first
+    #[serde(default)]
+    pub command_audit: DashMap<String, VecDeque<AuditEntry>>,
+
+    /// The credential operators present to query [Self::command_audit].
+    /// Taken from the DS config rather than stored. Nobody may query if
+    /// not set
+    #[serde(skip)]
+    pub admin_credential: Option<String>,
+
+    /// The public key of the AS, which the tokens of requests naming a user
+    /// are verified against. No token is required if not set. Taken from the
+    /// DS config
+    #[serde(skip)]
+    pub as_public_key: Option<PublicKey>,
+
+    /// How long the welcome of an invite is delivered again until accepted.
+    /// Not delivered again if not set. Taken from the DS config
+    #[serde(skip)]
+    pub welcome_retention: Option<Duration>,
+
+    /// Where timestamps are read from. The system clock when restored
+    #[serde(skip)]
+    pub clock: SharedClock,
+
+    /// The most recipients a single send may list. Unlimited if not set.
+    /// Taken from the DS config rather than stored
+    #[serde(skip)]
+    pub max_recipients_per_send: Option<usize>,
+
+    /// Append-only file every delivery and retrieval is logged to, so that
+    /// queued messages survive a crash. Not logged if not set
+    #[serde(skip)]
+    pub wal: Option<Arc<Mutex<WalFile>>>,
+
+    /// Held (shared) while an operation is logged to the WAL and applied,
+    /// and held (exclusively) while a snapshot of the state is taken
+    #[serde(skip)]
+    pub wal_checkpoint: Arc<RwLock<()>>,
+
+    /// The number of messages each user has yet to retrieve. Recounted from
+    /// the stored messages rather than stored
+    #[serde(skip)]
+    pub queue_metrics: QueueMetrics,
 }
 
 #[derive(Default, Debug, Clone)]
@@ -47,36 +200,284 @@ pub struct DeliveryServiceParam {
 
 pub type SharedDeliverServiceState = DeliveryServiceState;
 
+impl Default for DeliveryServiceState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VersionedState for DeliveryServiceState {
+    /// 2: invites are queued as welcome messages, no longer under
+    /// `invite_indvl_queues`
+    const SCHEMA_VERSION: u32 = 2;
+
+    fn migrate(from_version: u32, value: &mut serde_yaml::Value) -> Result<(), StateStoreError> {
+        match from_version {
+            1 => invites_to_welcome_msgs(value),
+            _ => Err(StateStoreError::UnsupportedVersion {
+                found: from_version,
+                supported: Self::SCHEMA_VERSION,
+            }),
+        }
+    }
+}
+
+/// Moves the invites queued under `invite_indvl_queues`, as stored in
+/// layout 1, to the message queues as welcome messages, so that invitees
+/// still retrieve them
+fn invites_to_welcome_msgs(value: &mut serde_yaml::Value) -> Result<(), StateStoreError> {
+    use serde_yaml::{Mapping, Value};
+
+    let decode_err = |e: serde_yaml::Error| StateStoreError::Decode(e.to_string());
+    let fields = value
+        .as_mapping_mut()
+        .ok_or_else(|| StateStoreError::Decode("Not a map of fields".to_string()))?;
+    let invite_queues = match fields.remove(&Value::from("invite_indvl_queues")) {
+        Some(invite_queues) => invite_queues,
+        None => return Ok(()),
+    };
+    let invite_queues: BTreeMap<String, Vec<Invite>> =
+        serde_yaml::from_value(invite_queues).map_err(decode_err)?;
+
+    let mut messages = match fields.remove(&Value::from("message_id_to_message")) {
+        Some(Value::Mapping(messages)) => messages,
+        _ => Mapping::new(),
+    };
+    let mut queues = match fields.remove(&Value::from("unordered_message_indvl_queues")) {
+        Some(Value::Mapping(queues)) => queues,
+        _ => Mapping::new(),
+    };
+    for (invitee, invites) in invite_queues {
+        for invite in invites {
+            let mut message_id = Uuid::new_v4().to_string();
+            while messages.contains_key(&Value::from(message_id.as_str())) {
+                message_id = Uuid::new_v4().to_string();
+            }
+            let msg_w_meta = ProtectedMessageWithMetaData::new(
+                GroupMessage::from_welcome(invite.welcome_obj, invite.comm_grp, invite.inviter),
+                false,
+                invite.created_at,
+            );
+            messages.insert(
+                Value::from(message_id.as_str()),
+                serde_yaml::to_value((vec![invitee.clone()], msg_w_meta)).map_err(decode_err)?,
+            );
+            let queue_key = Value::from(invitee.as_str());
+            match queues.get_mut(&queue_key) {
+                Some(Value::Sequence(queue)) => queue.push(Value::from(message_id)),
+                _ => {
+                    queues.insert(queue_key, Value::Sequence(vec![Value::from(message_id)]));
+                }
+            }
+        }
+    }
+    fields.insert(
+        Value::from("message_id_to_message"),
+        Value::Mapping(messages),
+    );
+    fields.insert(
+        Value::from("unordered_message_indvl_queues"),
+        Value::Mapping(queues),
+    );
+    Ok(())
+}
+
 impl DeliveryServiceState {
     pub fn new() -> DeliveryServiceState {
         DeliveryServiceState {
+            schema_version: Self::SCHEMA_VERSION,
             unordered_message_indvl_queues: DashMap::new(),
             indvl_groups: DashMap::new(),
             groups_to_ordered_messages: DashMap::new(),
-            invite_indvl_queues: DashMap::new(),
             user_key_packages: DashMap::new(),
             message_id_to_message: DashMap::new(),
+            seen_client_msg_ids: DashMap::new(),
+            reinvite_request_indvl_queues: DashMap::new(),
+            retained_welcome_indvl_queues: DashMap::new(),
+            unacked_indvl_msgs: DashMap::new(),
+            command_audit: DashMap::new(),
+            admin_credential: None,
+            as_public_key: None,
+            welcome_retention: None,
+            clock: SharedClock::default(),
+            max_recipients_per_send: None,
+            wal: None,
+            wal_checkpoint: Arc::new(RwLock::new(())),
+            queue_metrics: QueueMetrics::default(),
+        }
+    }
+
+    /// Records that `user_name` issued `command` now, dropping their oldest
+    /// command if [MAX_AUDIT_ENTRIES_PER_USER] are recorded already
+    pub fn record_command(&self, user_name: &str, command: &str) {
+        let mut entries = self.command_audit.entry(user_name.to_string()).or_default();
+        if entries.len() >= MAX_AUDIT_ENTRIES_PER_USER {
+            entries.pop_front();
+        }
+        entries.push_back(AuditEntry {
+            timestamp: self.clock.now(),
+            command: command.to_string(),
+        });
+    }
+
+    /// Returns true if `credential` is the configured admin credential.
+    /// Their hashes are compared, so that the time taken does not reveal how
+    /// much of the credential matched
+    pub fn is_admin_credential(&self, credential: &str) -> bool {
+        match &self.admin_credential {
+            Some(admin_credential) => {
+                Sha256::digest(admin_credential.as_bytes()) == Sha256::digest(credential.as_bytes())
+            }
+            None => false,
+        }
+    }
+
+    /// Sets the clock timestamps are read from, e.g., a mock one in tests
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Keeps the welcome of `invite` to be delivered again, replacing that of
+    /// any earlier invite of the invitee to the same group
+    pub fn retain_welcome(&self, invite: &Invite) {
+        if self.welcome_retention.is_none() {
+            return;
+        }
+        let mut retained = self
+            .retained_welcome_indvl_queues
+            .entry(invite.invitee.to_string())
+            .or_default();
+        retained.retain(|retained_invite| retained_invite.comm_grp != invite.comm_grp);
+        retained.push(invite.clone());
+    }
+
+    /// Returns the invites of `user` whose welcome is still to be delivered
+    /// again, dropping those retained for longer than `welcome_retention`
+    pub fn retained_welcomes(&self, user: &str) -> Vec<Invite> {
+        let retention = match self.welcome_retention {
+            Some(retention) => retention,
+            None => return vec![],
+        };
+        let now = self.clock.now();
+        match self.retained_welcome_indvl_queues.get_mut(user) {
+            Some(mut retained) => {
+                retained.retain(|invite| {
+                    now.duration_since(invite.created_at)
+                        .map(|age| age <= retention)
+                        .unwrap_or(true)
+                });
+                retained.clone()
+            }
+            None => vec![],
+        }
+    }
+
+    /// Stops delivering again the welcome of the invite of `user` to
+    /// `comm_grp`
+    pub fn forget_retained_welcome(&self, user: &str, comm_grp: &CommGroupId) {
+        if let Some(mut retained) = self.retained_welcome_indvl_queues.get_mut(user) {
+            retained.retain(|invite| &invite.comm_grp != comm_grp);
+        }
+        self.retained_welcome_indvl_queues
+            .remove_if(user, |_, retained| retained.is_empty());
+    }
+
+    /// Drops the relayed messages of `user` acknowledged as processed up to
+    /// `acked_until`, and those of the epochs of `known_epochs` the user
+    /// already merged, then returns the remaining ones to be relayed again
+    pub fn take_unacked_msgs(
+        &self,
+        user: &str,
+        acked_until: SystemTime,
+        known_epochs: &[(CommGroupId, u64)],
+    ) -> Vec<ProtectedMessageWithMetaData> {
+        let mut unacked = self
+            .unacked_indvl_msgs
+            .remove(user)
+            .map(|(_, unacked)| unacked)
+            .unwrap_or_default();
+        unacked.retain(|msg_w_meta| {
+            let merged = msg_w_meta.ordered
+                && known_epochs.iter().any(|(comm_grp, epoch)| {
+                    comm_grp == &msg_w_meta.community_group_id
+                        && msg_w_meta.protected_msg.get_epoch() < *epoch
+                });
+            msg_w_meta.server_timestamp > acked_until && !merged
+        });
+        unacked
+    }
+
+    /// Keeps the messages relayed to `user` at a sync to be relayed again
+    /// until acknowledged
+    pub fn keep_unacked_msgs(&self, user: &str, relayed: Vec<ProtectedMessageWithMetaData>) {
+        if !relayed.is_empty() {
+            self.unacked_indvl_msgs.insert(user.to_string(), relayed);
+        }
+    }
+
+    /// Checks that a send to `recipients` lists at most
+    /// `max_recipients_per_send` recipients, all of them known to the DS
+    pub fn check_recipients(&self, recipients: &[String]) -> Result<(), UserRequestErrors> {
+        if let Some(max_recipients) = self.max_recipients_per_send {
+            if recipients.len() > max_recipients {
+                return Err(UserRequestErrors::TooManyRecipients);
+            }
         }
+        if recipients
+            .iter()
+            .any(|recipient| !self.user_key_packages.contains_key(recipient))
+        {
+            return Err(UserRequestErrors::NoSuchQueriedUser);
+        }
+        Ok(())
     }
 
     /// Places the message `msg_w_meta` in the receiving inbox
     /// queues for all members in `recipients`.
+    /// Returns false if the message is a retried copy of a recently
+    /// delivered one, in which case it is dropped.
     pub fn delivery_to_recipients(
         &self,
         recipients: &Vec<String>,
-        mut msg_w_meta: ProtectedMessageWithMetaData,
-    ) {
+        msg_w_meta: ProtectedMessageWithMetaData,
+    ) -> bool {
         if recipients.is_empty() {
-            return;
+            return true;
         }
         let msg_com_grp = msg_w_meta.community_group_id.to_owned();
+        if !self.mark_client_msg_id_seen(&msg_com_grp, msg_w_meta.protected_msg.get_client_msg_id())
+        {
+            return false;
+        }
 
         // Generate a message id and store the message
         let mut message_id = Uuid::new_v4().to_string();
         while self.message_id_to_message.get(&message_id).is_some() {
             message_id = Uuid::new_v4().to_string()
         }
+        self.store_delivery(message_id, recipients, msg_w_meta, false);
+        true
+    }
 
+    /// Stores the message `msg_w_meta` as `message_id` in the queues of
+    /// `recipients`, after logging it to the WAL, if any. A message
+    /// `replayed` from the WAL keeps its logged server timestamp
+    fn store_delivery(
+        &self,
+        message_id: MessageID,
+        recipients: &[String],
+        mut msg_w_meta: ProtectedMessageWithMetaData,
+        replayed: bool,
+    ) {
+        if !msg_w_meta.ordered && msg_w_meta.ephemeral.is_some() {
+            for recipient in recipients {
+                self.drop_superseded_msgs(recipient, &msg_w_meta);
+            }
+        }
+        // Taken before any lock on the maps, as checkpoints read all of them
+        let _applying = self.wal_guard();
+        let msg_com_grp = msg_w_meta.community_group_id.to_owned();
         if msg_w_meta.ordered {
             let community_group_id = msg_com_grp.clone();
             let default_values = (HashSet::new(), Vec::new());
@@ -97,17 +498,32 @@ impl DeliveryServiceState {
                     known_recipients.insert(recipient.to_owned());
                 }
             }
-            msg_w_meta.update_timestamp(); // Ensure "group lock" obtained before finalizing timestamp
+            if !replayed {
+                msg_w_meta.update_timestamp(self.clock.now()); // Ensure "group lock" obtained before finalizing timestamp
+            }
+            // Logged under the "group lock" to keep the order of the group
+            self.log_to_wal(|| WalOp::Deliver {
+                message_id: message_id.clone(),
+                recipients: recipients.to_vec(),
+                msg_w_meta: msg_w_meta.clone(),
+            });
             self.message_id_to_message.insert(
                 message_id.clone(),
                 (HashSet::from_iter(recipients.iter().cloned()), msg_w_meta),
             );
+            self.queue_metrics.enqueued(recipients);
             group_messages.push(message_id);
         } else {
+            self.log_to_wal(|| WalOp::Deliver {
+                message_id: message_id.clone(),
+                recipients: recipients.to_vec(),
+                msg_w_meta: msg_w_meta.clone(),
+            });
             self.message_id_to_message.insert(
                 message_id.clone(),
                 (HashSet::from_iter(recipients.iter().cloned()), msg_w_meta),
             );
+            self.queue_metrics.enqueued(recipients);
             for recipient in recipients {
                 self.unordered_message_indvl_queues
                     .entry(recipient.to_string())
@@ -117,29 +533,86 @@ impl DeliveryServiceState {
         }
     }
 
+    /// Records `client_msg_id` as seen in `comm_grp`, forgetting the oldest
+    /// ID beyond `MAX_SEEN_CLIENT_MSG_IDS`.
+    /// Returns false if it was already seen.
+    fn mark_client_msg_id_seen(&self, comm_grp: &CommGroupId, client_msg_id: Uuid) -> bool {
+        let mut seen_entry = self
+            .seen_client_msg_ids
+            .entry(comm_grp.clone())
+            .or_insert((HashSet::new(), VecDeque::new()));
+        let (seen_set, seen_order) = seen_entry.deref_mut();
+        if !seen_set.insert(client_msg_id) {
+            return false;
+        }
+        seen_order.push_back(client_msg_id);
+        if seen_order.len() > MAX_SEEN_CLIENT_MSG_IDS {
+            if let Some(oldest) = seen_order.pop_front() {
+                seen_set.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    /// Removes the unretrieved messages of `recipient` that are made obsolete
+    /// by the ephemeral message `newer`
+    fn drop_superseded_msgs(&self, recipient: &String, newer: &ProtectedMessageWithMetaData) {
+        let superseded: Vec<MessageID> =
+            match self.unordered_message_indvl_queues.get_mut(recipient) {
+                Some(mut queue) => {
+                    let superseded: Vec<MessageID> = queue
+                        .iter()
+                        .filter(|msg_id| {
+                            self.message_id_to_message
+                                .get(*msg_id)
+                                .map(|entry_ref| entry_ref.1.is_superseded_by(newer))
+                                .unwrap_or(false)
+                        })
+                        .cloned()
+                        .collect();
+                    queue.retain(|msg_id| !superseded.contains(msg_id));
+                    superseded
+                }
+                None => return,
+            };
+        for msg_id in superseded {
+            let _applying = self.wal_guard();
+            let _ = self.pop_logged_message(&msg_id, recipient, false);
+        }
+    }
+
     pub fn pop_all_ordered_msg(
         &self,
         user: &String,
         comm_group_id: &CommGroupId,
     ) -> Vec<GroupMessage> {
-        self.pop_all_ordered_msg_w_meta(user, comm_group_id)
+        self.pop_all_ordered_msg_w_meta(user, comm_group_id, None)
             .into_iter()
             .map(|msg_w_meta| msg_w_meta.protected_msg)
             .collect()
     }
 
+    /// Pops the ordered messages of `comm_group_id` that `user` has yet to
+    /// retrieve. Those of an MLS epoch below `since_epoch`, if given, are
+    /// popped but not returned, as the user already merged them
     pub fn pop_all_ordered_msg_w_meta(
         &self,
         user: &String,
         comm_group_id: &CommGroupId,
+        since_epoch: Option<u64>,
     ) -> Vec<ProtectedMessageWithMetaData> {
+        let _applying = self.wal_guard();
         if let Some(mut ref_mut) = self.groups_to_ordered_messages.get_mut(comm_group_id) {
             let (known_user, msg_ids) = ref_mut.deref_mut();
             if known_user.contains(user) {
                 known_user.remove(user);
                 return msg_ids
                     .iter()
-                    .filter_map(|msg_id| self.pop_message_by_id(msg_id, user))
+                    .filter_map(|msg_id| self.pop_logged_message(msg_id, user, true))
+                    .filter(|msg_w_meta| {
+                        since_epoch
+                            .map_or(true, |epoch| msg_w_meta.protected_msg.get_epoch() >= epoch)
+                    })
                     .collect();
             }
         }
@@ -150,6 +623,20 @@ impl DeliveryServiceState {
         &self,
         message_id: &String,
         user: &String,
+    ) -> Option<ProtectedMessageWithMetaData> {
+        let _applying = self.wal_guard();
+        self.pop_logged_message(message_id, user, true)
+    }
+
+    /// Pops the message `message_id` for `user`, after logging it to the
+    /// WAL. Only messages `retrieved` by the user, rather than, e.g.,
+    /// superseded or replayed, count towards the delivery latency. The
+    /// caller holds [Self::wal_guard]
+    fn pop_logged_message(
+        &self,
+        message_id: &String,
+        user: &String,
+        retrieved: bool,
     ) -> Option<ProtectedMessageWithMetaData> {
         let mut is_intended_recipient = false;
         let mut remove_msg = false;
@@ -162,6 +649,10 @@ impl DeliveryServiceState {
         if !is_intended_recipient {
             return None;
         }
+        self.log_to_wal(|| WalOp::Pop {
+            message_id: message_id.clone(),
+            user: user.clone(),
+        });
 
         // Write Lock
         let result = self
@@ -178,9 +669,31 @@ impl DeliveryServiceState {
             self.message_id_to_message.remove(message_id);
         };
 
+        if result.is_some() {
+            self.queue_metrics.dequeued(user);
+        }
+        #[cfg(not(feature = "metrics"))]
+        let _ = retrieved;
+        #[cfg(feature = "metrics")]
+        if let (Some(msg), true) = (&result, retrieved) {
+            if let Ok(latency) = self.clock.now().duration_since(msg.server_timestamp) {
+                crate::metrics::delivery_latency_histogram().observe(latency.as_secs_f64());
+            }
+        }
+
         result
     }
 
+    /// Counts again the messages each user has yet to retrieve, e.g., once
+    /// the state is restored
+    pub fn recount_queue_metrics(&self) {
+        self.queue_metrics.queue_depths.clear();
+        for entry_ref in self.message_id_to_message.iter() {
+            let recipients: Vec<String> = entry_ref.value().0.iter().cloned().collect();
+            self.queue_metrics.enqueued(&recipients);
+        }
+    }
+
     pub fn add_key_packages(
         &self,
         mut new_key_packages: Vec<KeyPackage>,
@@ -199,4 +712,325 @@ impl DeliveryServiceState {
             false
         }
     }
+
+    /// Returns the names the devices of `name` registered their key packages
+    /// under: `name` itself if it names a single device (e.g., `alice#phone`),
+    /// else `name` and every `name#<device>`
+    pub fn device_names(&self, name: &str) -> Vec<String> {
+        if split_device(name).1.is_some() {
+            return match self.user_key_packages.contains_key(name) {
+                true => vec![name.to_string()],
+                false => vec![],
+            };
+        }
+        let mut names: Vec<String> = self
+            .user_key_packages
+            .iter()
+            .map(|entry| entry.key().to_string())
+            .filter(|device_name| split_device(device_name).0 == name)
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Returns the number of key packages currently stored for `user_name`
+    pub fn count_key_packages(&self, user_name: &String) -> usize {
+        self.user_key_packages
+            .get(user_name)
+            .map(|key_packages| key_packages.len())
+            .unwrap_or(0)
+    }
+
+    /// Counts the messages a `UserSync` of `user` would retrieve, without
+    /// dequeuing any of them. Ordered messages of epochs the user already
+    /// merged are counted, as a sync would only skip them
+    pub fn pending_counts(&self, user: &String) -> PendingCounts {
+        let is_unretrieved = |msg_id: &MessageID| {
+            self.message_id_to_message
+                .get(msg_id)
+                .map(|entry_ref| entry_ref.0.contains(user))
+                .unwrap_or(false)
+        };
+        let mut counts = PendingCounts::default();
+        let mut welcome_grps = vec![];
+        if let Some(queue) = self.unordered_message_indvl_queues.get(user) {
+            for msg_id in queue.iter() {
+                match self.message_id_to_message.get(msg_id) {
+                    Some(entry_ref) if entry_ref.1.protected_msg.is_welcome() => {
+                        counts.invites += 1;
+                        welcome_grps.push(entry_ref.1.community_group_id.clone());
+                    }
+                    Some(_) => counts.unordered += 1,
+                    None => (),
+                }
+            }
+        }
+        counts.invites += self
+            .retained_welcomes(user)
+            .iter()
+            .filter(|retained| !welcome_grps.contains(&retained.comm_grp))
+            .count();
+        counts.unordered += self
+            .reinvite_request_indvl_queues
+            .get(user)
+            .map_or(0, |queue| queue.len());
+
+        let comm_grps = self
+            .indvl_groups
+            .get(user)
+            .map(|comm_grps| comm_grps.clone())
+            .unwrap_or_default();
+        for comm_grp in comm_grps {
+            let n_ordered = match self.groups_to_ordered_messages.get(&comm_grp) {
+                Some(entry_ref) if entry_ref.0.contains(user) => entry_ref
+                    .1
+                    .iter()
+                    .filter(|msg_id| is_unretrieved(*msg_id))
+                    .count(),
+                _ => 0,
+            };
+            if n_ordered > 0 {
+                counts.ordered.push((comm_grp, n_ordered));
+            }
+        }
+        counts
+    }
+
+    /// Queues the re-invite request of `requester` to `comm_grp` for each
+    /// of the `recipients`, replacing an identical unretrieved request
+    pub fn store_reinvite_request(
+        &self,
+        requester: &str,
+        comm_grp: &CommGroupId,
+        recipients: &[String],
+    ) {
+        let request = (requester.to_string(), comm_grp.clone());
+        for recipient in recipients {
+            let mut queue = self
+                .reinvite_request_indvl_queues
+                .entry(recipient.to_owned())
+                .or_default();
+            if !queue.contains(&request) {
+                queue.push(request.clone());
+            }
+        }
+    }
+
+    /// Removes the messages stored for longer than `max_age`, e.g., because
+    /// their recipients never came back online, along with the references
+    /// to them in the user queues. Returns the number of (welcomes of
+    /// invites, other messages) removed.
+    pub fn gc(&self, max_age: Duration) -> (usize, usize) {
+        let now = self.clock.now();
+        let is_stale = |stored_at: &SystemTime| {
+            now.duration_since(*stored_at)
+                .map(|age| age > max_age)
+                .unwrap_or(false)
+        };
+
+        // Messages are stored when received, or when ordered
+        let mut n_welcomes = 0;
+        let stale_msg_ids: HashSet<MessageID> = self
+            .message_id_to_message
+            .iter()
+            .filter(|entry_ref| is_stale(&entry_ref.value().1.server_timestamp))
+            .map(|entry_ref| {
+                if entry_ref.value().1.protected_msg.is_welcome() {
+                    n_welcomes += 1;
+                }
+                entry_ref.key().clone()
+            })
+            .collect();
+        for mut unacked in self.unacked_indvl_msgs.iter_mut() {
+            unacked.retain(|msg_w_meta| !is_stale(&msg_w_meta.server_timestamp));
+        }
+        self.unacked_indvl_msgs
+            .retain(|_, unacked| !unacked.is_empty());
+        if stale_msg_ids.is_empty() {
+            return (0, 0);
+        }
+        let applying = self.wal_guard();
+        self.log_to_wal(|| WalOp::Gc {
+            message_ids: stale_msg_ids.iter().cloned().collect(),
+        });
+        self.remove_msgs(&stale_msg_ids);
+        drop(applying);
+        self.recount_queue_metrics();
+        (n_welcomes, stale_msg_ids.len() - n_welcomes)
+    }
+
+    /// Removes the messages `msg_ids`, along with the references to them in
+    /// the user queues
+    fn remove_msgs(&self, msg_ids: &HashSet<MessageID>) {
+        for msg_id in msg_ids.iter() {
+            self.message_id_to_message.remove(msg_id);
+        }
+        for mut queue in self.unordered_message_indvl_queues.iter_mut() {
+            queue.retain(|msg_id| !msg_ids.contains(msg_id));
+        }
+        for mut group_entry in self.groups_to_ordered_messages.iter_mut() {
+            let (_, msg_ids_of_group) = group_entry.deref_mut();
+            msg_ids_of_group.retain(|msg_id| !msg_ids.contains(msg_id));
+        }
+    }
+
+    /// Blocks checkpoints while an operation is logged to the WAL and applied
+    fn wal_guard(&self) -> RwLockReadGuard<'_, ()> {
+        self.wal_checkpoint
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Appends the operation built by `op` to the WAL, if any
+    fn log_to_wal(&self, op: impl FnOnce() -> WalOp) {
+        let wal = match &self.wal {
+            Some(wal) => wal,
+            None => return,
+        };
+        let line = match serde_json::to_string(&op()) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("in encoding a WAL operation: {:?}", e);
+                return;
+            }
+        };
+        let mut wal = wal.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        // Synced before the operation is applied, and so before it is answered
+        if let Err(e) = writeln!(wal.file, "{}", line).and_then(|_| wal.file.sync_data()) {
+            error!("in appending to the WAL: {:?}", e);
+        }
+    }
+
+    /// Applies the operations logged to the WAL at `path`, if it exists, then
+    /// logs all further deliveries and retrievals to it.
+    /// Returns the number of operations applied
+    pub fn open_wal(&mut self, path: impl AsRef<Path>) -> io::Result<usize> {
+        let n_ops = self.replay_wal(&path)?;
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        self.wal = Some(Arc::new(Mutex::new(WalFile {
+            path: path.as_ref().to_path_buf(),
+            file,
+        })));
+        Ok(n_ops)
+    }
+
+    /// Applies the operations logged to the WAL at `path`, if it exists.
+    /// Deliveries of messages already stored are skipped, and replaying
+    /// stops at the first operation that cannot be decoded, e.g., one
+    /// partially written before a crash.
+    /// Returns the number of operations applied
+    pub fn replay_wal(&self, path: impl AsRef<Path>) -> io::Result<usize> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        let mut n_ops = 0;
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let op: WalOp = match serde_json::from_str(&line) {
+                Ok(op) => op,
+                Err(e) => {
+                    warn!("Stopped replaying the WAL at an invalid operation: {:?}", e);
+                    break;
+                }
+            };
+            match op {
+                WalOp::Deliver {
+                    message_id,
+                    recipients,
+                    msg_w_meta,
+                } => {
+                    if self.message_id_to_message.contains_key(&message_id) {
+                        continue;
+                    }
+                    self.mark_client_msg_id_seen(
+                        &msg_w_meta.community_group_id,
+                        msg_w_meta.protected_msg.get_client_msg_id(),
+                    );
+                    self.store_delivery(message_id, &recipients, msg_w_meta, true);
+                }
+                WalOp::Pop { message_id, user } => {
+                    let applying = self.wal_guard();
+                    let _ = self.pop_logged_message(&message_id, &user, false);
+                    drop(applying);
+                    if let Some(mut queue) = self.unordered_message_indvl_queues.get_mut(&user) {
+                        queue.retain(|queued_id| queued_id != &message_id);
+                    }
+                    self.unordered_message_indvl_queues
+                        .remove_if(&user, |_, queue| queue.is_empty());
+                }
+                WalOp::Gc { message_ids } => {
+                    self.remove_msgs(&message_ids.into_iter().collect());
+                    self.recount_queue_metrics();
+                }
+            }
+            n_ops += 1;
+        }
+        Ok(n_ops)
+    }
+
+    /// Stores a snapshot of the whole state with `store`, then drops the
+    /// operations the snapshot includes from the WAL, so that the WAL only
+    /// holds the operations applied after the stored state. Operations are
+    /// only kept from being logged and applied while the snapshot is taken,
+    /// not while it is stored
+    pub fn checkpoint(
+        &self,
+        store: impl FnOnce(&Self) -> Result<(), StateStoreError>,
+    ) -> Result<(), StateStoreError> {
+        let to_access_err = |e: io::Error| StateStoreError::Access(e.to_string());
+        let (snapshot, logged_len) = {
+            let _checkpointing = self
+                .wal_checkpoint
+                .write()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            (self.clone(), self.wal_len().map_err(to_access_err)?)
+        };
+        store(&snapshot)?;
+        self.drop_logged_ops(logged_len).map_err(to_access_err)
+    }
+
+    /// The number of bytes logged to the WAL, if any
+    fn wal_len(&self) -> io::Result<u64> {
+        match &self.wal {
+            Some(wal) => Ok(wal
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .file
+                .metadata()?
+                .len()),
+            None => Ok(0),
+        }
+    }
+
+    /// Drops the first `logged_len` bytes of the WAL, i.e., the operations a
+    /// stored snapshot includes, keeping those logged since. These are
+    /// written to a new file replacing the WAL, so that a crash in between
+    /// leaves one of the two complete
+    fn drop_logged_ops(&self, logged_len: u64) -> io::Result<()> {
+        let wal = match &self.wal {
+            Some(wal) => wal,
+            None => return Ok(()),
+        };
+        let mut wal = wal.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut logged_since = Vec::new();
+        let mut reader = File::open(&wal.path)?;
+        reader.seek(SeekFrom::Start(logged_len))?;
+        reader.read_to_end(&mut logged_since)?;
+        if logged_since.is_empty() {
+            return wal.file.set_len(0);
+        }
+        let mut new_path = wal.path.clone().into_os_string();
+        new_path.push(".new");
+        let mut new_file = File::create(&new_path)?;
+        new_file.write_all(&logged_since)?;
+        new_file.sync_data()?;
+        std::fs::rename(&new_path, &wal.path)?;
+        wal.file = OpenOptions::new().append(true).open(&wal.path)?;
+        Ok(())
+    }
 }
@@ -15,11 +15,11 @@ use openmls::prelude::KeyPackage;
 use ds_structs::local_message_struct::{Invite, ProtectedMessageWithMetaData};
 use ds_structs::SharedDeliverServiceState;
 
-use crate::identity_to_str;
-use crate::messages::GroupMessage;
 use crate::messages::UserRequestErrors::*;
-use crate::messages::{OnWireMessage, UserRequestErrors};
+use crate::messages::{AuthToken, OnWireMessage, UserRequestErrors};
+use crate::messages::{EphemeralKind, GroupMessage};
 use crate::CommGroupId;
+use crate::{identity_to_str, verification_key_fingerprint};
 
 use self::as_struct::CredentialEntry;
 use self::as_struct::SharedAuthServiceState;
@@ -28,19 +28,75 @@ pub mod as_struct;
 pub mod ds_structs;
 pub mod mls_helpers;
 pub mod network_helpers;
+#[cfg(test)]
+mod servers_api_tests;
 
 const MAX_KEY_PACKAGES_PER_USER: usize = 20;
+/// The most credentials or key packages sent in a single lookup response.
+/// Larger results are split into several responses
+pub const MAX_LOOKUP_RESULTS_PER_CHUNK: usize = 16;
 
 pub async fn handle_onwire_msg_ds_local(
     onwire_msg: OnWireMessage,
     shared_state: &Arc<SharedDeliverServiceState>,
+) -> Vec<OnWireMessage> {
+    handle_onwire_msg_w_token_ds_local(onwire_msg, None, shared_state).await
+}
+
+/// Like [handle_onwire_msg_ds_local], for a request sent along with `token`,
+/// which the DS requires if it knows the public key of the AS
+pub async fn handle_onwire_msg_w_token_ds_local(
+    onwire_msg: OnWireMessage,
+    token: Option<AuthToken>,
+    shared_state: &Arc<SharedDeliverServiceState>,
+) -> Vec<OnWireMessage> {
+    #[cfg(feature = "tracing")]
+    let span = request_span("ds", &onwire_msg);
+    let handling = handle_ds_request(onwire_msg, token, shared_state);
+    #[cfg(feature = "tracing")]
+    let handling = tracing::Instrument::instrument(handling, span);
+    handling.await
+}
+
+pub async fn handle_onwire_msg_as_local(
+    onwire_msg: OnWireMessage,
+    shared_state: &Arc<SharedAuthServiceState>,
+) -> Vec<OnWireMessage> {
+    #[cfg(feature = "tracing")]
+    let span = request_span("as", &onwire_msg);
+    let handling = handle_as_request(onwire_msg, shared_state);
+    #[cfg(feature = "tracing")]
+    let handling = tracing::Instrument::instrument(handling, span);
+    handling.await
+}
+
+/// The span a request is handled within, so that its logs can be told
+/// apart from those of other requests
+#[cfg(feature = "tracing")]
+fn request_span(service: &'static str, onwire_msg: &OnWireMessage) -> tracing::Span {
+    tracing::info_span!(
+        "onwire_request",
+        service,
+        user = command_issuer(onwire_msg).unwrap_or_default(),
+        variant = onwire_msg.variant_name(),
+    )
+}
+
+async fn handle_ds_request(
+    onwire_msg: OnWireMessage,
+    token: Option<AuthToken>,
+    shared_state: &Arc<SharedDeliverServiceState>,
 ) -> Vec<OnWireMessage> {
     let begin_timestamp = Instant::now();
     // Pre-process
-    if let Some(msgs) = pre_process_onwire_msgs_ds(&onwire_msg, shared_state, begin_timestamp).await
+    if let Some(msgs) =
+        pre_process_onwire_msgs_ds(&onwire_msg, token.as_ref(), shared_state, begin_timestamp).await
     {
         return msgs;
     };
+    if let Some(user_name) = command_issuer(&onwire_msg) {
+        shared_state.record_command(user_name, onwire_msg.variant_name());
+    }
     // Execute commands
     match onwire_msg {
         OnWireMessage::UserKeyPackagesForDS { key_packages } => {
@@ -49,7 +105,25 @@ pub async fn handle_onwire_msg_ds_local(
         OnWireMessage::UserSync {
             user_name,
             new_key_packages,
-        } => user_sync(&user_name, shared_state, new_key_packages, begin_timestamp).await,
+            known_epochs,
+            acked_until,
+        } => {
+            user_sync(
+                &user_name,
+                shared_state,
+                new_key_packages,
+                known_epochs,
+                acked_until,
+                begin_timestamp,
+            )
+            .await
+        }
+        OnWireMessage::UserPoll { user_name } => vec![
+            OnWireMessage::DSPendingCounts {
+                counts: shared_state.pending_counts(&user_name),
+            },
+            feedback_ds_msg("Pending messages counted", true, begin_timestamp),
+        ],
         OnWireMessage::UserInvite {
             user_name,
             invitee_names,
@@ -70,12 +144,14 @@ pub async fn handle_onwire_msg_ds_local(
             identifier,
             user_msg,
             recipients,
+            ephemeral,
         } => {
             user_send_standard(
                 recipients,
                 &user_msg,
                 shared_state,
                 identifier,
+                ephemeral,
                 begin_timestamp,
             )
             .await
@@ -96,6 +172,38 @@ pub async fn handle_onwire_msg_ds_local(
             .await
         }
 
+        OnWireMessage::UserRequestReinvite {
+            user_name,
+            comm_grp,
+            recipients,
+        } => {
+            user_request_reinvite(
+                &user_name,
+                &comm_grp,
+                recipients,
+                shared_state,
+                begin_timestamp,
+            )
+            .await
+        }
+
+        OnWireMessage::UserAcceptedInvite {
+            user_name,
+            comm_grp,
+        } => user_accepted_invite(&user_name, &comm_grp, shared_state, begin_timestamp).await,
+
+        OnWireMessage::DSAuditQuery { user_name, .. } => {
+            let entries = shared_state
+                .command_audit
+                .get(&user_name)
+                .map(|entries| entries.iter().cloned().collect())
+                .unwrap_or_default();
+            vec![
+                OnWireMessage::DSAuditResponse { user_name, entries },
+                feedback_ds_msg("Audit entries found", true, begin_timestamp),
+            ]
+        }
+
         OnWireMessage::UserKeyPackageLookup {
             user_name,
             queried_users,
@@ -110,25 +218,38 @@ pub async fn handle_onwire_msg_ds_local(
         }
 
         OnWireMessage::UserRegisterForAS { .. }
+        | OnWireMessage::UserRotateKey { .. }
         | OnWireMessage::UserCredentialLookup { .. }
-        | OnWireMessage::UserSyncCredentials { .. } => {
+        | OnWireMessage::UserSyncCredentials { .. }
+        | OnWireMessage::ASPublicKeyRequest
+        | OnWireMessage::ASIssueToken { .. }
+        | OnWireMessage::UserWhoAmI { .. }
+        | OnWireMessage::UserStorePrefs { .. }
+        | OnWireMessage::UserLoadPrefs { .. }
+        | OnWireMessage::ASRevokeCredential { .. } => {
             error!("Received requests intended for AS rather than for DS");
             vec![]
         }
         OnWireMessage::DSResult { .. }
         | OnWireMessage::DSRelayedUserMsg { .. }
-        | OnWireMessage::DSRelayedUserWelcome { .. }
+        | OnWireMessage::DSRelayedReinviteRequest { .. }
         | OnWireMessage::ASResult { .. }
         | OnWireMessage::ASCredentialResponse { .. }
         | OnWireMessage::ASCredentialSyncResponse { .. }
-        | OnWireMessage::DSKeyPackageResponse { .. } => {
+        | OnWireMessage::ASPrefsResponse { .. }
+        | OnWireMessage::ASPublicKeyResponse { .. }
+        | OnWireMessage::ASWhoAmIResponse { .. }
+        | OnWireMessage::ASToken { .. }
+        | OnWireMessage::DSKeyPackageResponse { .. }
+        | OnWireMessage::DSAuditResponse { .. }
+        | OnWireMessage::DSPendingCounts { .. } => {
             error!("Received requests intended for clients rather than for DS");
             vec![]
         }
     }
 }
 
-pub async fn handle_onwire_msg_as_local(
+async fn handle_as_request(
     onwire_msg: OnWireMessage,
     shared_state: &Arc<SharedAuthServiceState>,
 ) -> Vec<OnWireMessage> {
@@ -137,16 +258,44 @@ pub async fn handle_onwire_msg_as_local(
         OnWireMessage::UserRegisterForAS {
             credential,
             verification_key,
+            display_name,
+            endorsement,
         } => {
             let state = shared_state;
             match identity_to_str(credential.identity()) {
                 None => feedback_as_msg(CannotDecodeIdentity.to_string(), false, begin_timestamp)
                     .to_vec(),
+                Some(_) if state.check_credential_allowed(&credential).is_err() => feedback_as_msg(
+                    UnsupportedCredentialType.to_string(),
+                    false,
+                    begin_timestamp,
+                )
+                .to_vec(),
+                Some(name) if state.credential_entries.contains_key(&name) => {
+                    feedback_as_msg(IdentityAlreadyExist.to_string(), false, begin_timestamp)
+                        .to_vec()
+                }
+                Some(name)
+                    if state
+                        .check_device_endorsement(
+                            &name,
+                            &credential,
+                            &verification_key,
+                            endorsement.as_ref(),
+                        )
+                        .is_err() =>
+                {
+                    feedback_as_msg(InvalidDeviceEndorsement.to_string(), false, begin_timestamp)
+                        .to_vec()
+                }
                 Some(name) => match state.credential_entries.entry(name) {
                     Vacant(e) => {
                         e.insert(CredentialEntry {
                             credential,
                             verification_key,
+                            revoked: false,
+                            display_name,
+                            key_rotations: 0,
                         });
                         feedback_as_msg("New Identity OK".to_string(), true, begin_timestamp)
                             .to_vec()
@@ -158,6 +307,22 @@ pub async fn handle_onwire_msg_as_local(
                 },
             }
         }
+        OnWireMessage::UserRotateKey {
+            user_name,
+            new_credential,
+            new_verification_key,
+            signature,
+            new_key_signature,
+        } => match shared_state.rotate_key(
+            &user_name,
+            new_credential,
+            new_verification_key,
+            &signature,
+            &new_key_signature,
+        ) {
+            Ok(()) => feedback_as_msg("Key rotated".to_string(), true, begin_timestamp).to_vec(),
+            Err(err) => feedback_as_msg(err.to_string(), false, begin_timestamp).to_vec(),
+        },
         OnWireMessage::UserCredentialLookup {
             user_name: _user_name,
             queried_users,
@@ -172,12 +337,26 @@ pub async fn handle_onwire_msg_as_local(
                         .to_vec();
                 }
             }
-            vec![
-                OnWireMessage::ASCredentialResponse {
-                    queried_user_credentials: found_credentials,
-                },
-                feedback_as_msg("Credential found".to_string(), true, begin_timestamp),
-            ]
+            let chunks = into_chunks(found_credentials);
+            let total_chunks = chunks.len();
+            let mut responses: Vec<OnWireMessage> = chunks
+                .into_iter()
+                .enumerate()
+                .map(
+                    |(chunk_index, credentials)| OnWireMessage::ASCredentialResponse {
+                        signature: state.sign_credentials(&credentials),
+                        queried_user_credentials: credentials,
+                        chunk_index,
+                        total_chunks,
+                    },
+                )
+                .collect();
+            responses.push(feedback_as_msg(
+                "Credential found".to_string(),
+                true,
+                begin_timestamp,
+            ));
+            responses
         }
         OnWireMessage::UserSyncCredentials => {
             let state = shared_state;
@@ -188,6 +367,79 @@ pub async fn handle_onwire_msg_as_local(
                 feedback_as_msg("Credentials retrieved".to_string(), true, begin_timestamp),
             ]
         }
+        OnWireMessage::ASPublicKeyRequest => vec![
+            OnWireMessage::ASPublicKeyResponse {
+                public_key: shared_state.public_key(),
+            },
+            feedback_as_msg("Public key retrieved".to_string(), true, begin_timestamp),
+        ],
+        OnWireMessage::ASIssueToken {
+            user_name,
+            requested_at,
+            signature,
+        } => {
+            match shared_state.issue_token(
+                &user_name,
+                requested_at,
+                &signature,
+                shared_state.clock.now(),
+            ) {
+                Ok(token) => vec![
+                    OnWireMessage::ASToken { token },
+                    feedback_as_msg("Token issued".to_string(), true, begin_timestamp),
+                ],
+                Err(err) => feedback_as_msg(err.to_string(), false, begin_timestamp).to_vec(),
+            }
+        }
+        OnWireMessage::UserWhoAmI { user_name } => {
+            let (registered, revoked, verification_key_fingerprint) =
+                match shared_state.credential_entries.get(&user_name) {
+                    Some(entry) => (
+                        true,
+                        entry.is_revoked(),
+                        Some(verification_key_fingerprint(&entry.verification_key)),
+                    ),
+                    None => (false, false, None),
+                };
+            vec![
+                OnWireMessage::ASWhoAmIResponse {
+                    user_name,
+                    registered,
+                    revoked,
+                    verification_key_fingerprint,
+                },
+                feedback_as_msg("Registration looked up".to_string(), true, begin_timestamp),
+            ]
+        }
+        OnWireMessage::UserStorePrefs {
+            user_name,
+            prefs,
+            signature,
+        } => match shared_state.store_prefs(&user_name, prefs, &signature) {
+            Ok(()) => {
+                feedback_as_msg("Preferences stored".to_string(), true, begin_timestamp).to_vec()
+            }
+            Err(err) => feedback_as_msg(err.to_string(), false, begin_timestamp).to_vec(),
+        },
+        OnWireMessage::UserLoadPrefs { user_name } => vec![
+            OnWireMessage::ASPrefsResponse {
+                prefs: shared_state.load_prefs(&user_name),
+            },
+            feedback_as_msg("Preferences looked up".to_string(), true, begin_timestamp),
+        ],
+        OnWireMessage::ASRevokeCredential {
+            admin_credential,
+            user_name,
+        } => {
+            if !shared_state.is_admin_credential(&admin_credential) {
+                feedback_as_msg(NotAdmin.to_string(), false, begin_timestamp).to_vec()
+            } else if shared_state.revoke_credential(&user_name) {
+                info!("Revoked the credential of [{user_name}]");
+                feedback_as_msg("Credential revoked".to_string(), true, begin_timestamp).to_vec()
+            } else {
+                feedback_as_msg(NoSuchQueriedUser.to_string(), false, begin_timestamp).to_vec()
+            }
+        }
         _ => {
             error!(
                 "Unacceptable OnWireMessage for AS received: {:?}",
@@ -198,13 +450,32 @@ pub async fn handle_onwire_msg_as_local(
     }
 }
 
-/// Preprocess app message. Currently, only checks if the user is registered.
+/// Preprocess app message. Checks that requests naming a user come with a
+/// valid token issued to them, if the DS knows the public key of the AS, and
+/// that the user is registered.
 /// If preprocessing succeeds, then it returns `None`, else returns `Some(Vec<OnWireMessage>)`.
 async fn pre_process_onwire_msgs_ds(
     onwire_msg: &OnWireMessage,
+    token: Option<&AuthToken>,
     shared_state: &Arc<SharedDeliverServiceState>,
     timestamp: Instant,
 ) -> Option<Vec<OnWireMessage>> {
+    if let (Some(as_public_key), Some(holder)) =
+        (&shared_state.as_public_key, token_holder(onwire_msg))
+    {
+        let verified = match (token, holder) {
+            (Some(token), TokenHolder::User(user_name)) => {
+                token.verify(as_public_key, &user_name, shared_state.clock.now())
+            }
+            (Some(token), TokenHolder::AnyUser) => {
+                token.verify(as_public_key, &token.user_name, shared_state.clock.now())
+            }
+            (None, _) => Err(MissingToken),
+        };
+        if let Err(err) = verified {
+            return Some(feedback_ds_err(err, timestamp).to_vec());
+        }
+    }
     // Check whether non-registration requests are initialized by  known users
     match onwire_msg {
         OnWireMessage::UserKeyPackagesForDS { .. }
@@ -215,23 +486,106 @@ async fn pre_process_onwire_msgs_ds(
         | OnWireMessage::UserKeyPackageLookup { user_name, .. }
         // | OnWireMessage::UserCreate { user_name, .. }
         | OnWireMessage::UserSync { user_name, .. }
-        | OnWireMessage::UserInvite { user_name, .. } => {
+        | OnWireMessage::UserPoll { user_name }
+        | OnWireMessage::UserInvite { user_name, .. }
+        | OnWireMessage::UserRequestReinvite { user_name, .. }
+        | OnWireMessage::UserAcceptedInvite { user_name, .. } => {
             if shared_state.user_key_packages.contains_key(user_name) {
                 None
             } else {
                 Some(feedback_ds_msg("Unknown user. Did you register (with DS via KeyPackage)?", false, timestamp).to_vec())
             }
         }
+        OnWireMessage::DSAuditQuery { admin_credential, .. } => {
+            if shared_state.is_admin_credential(admin_credential) {
+                None
+            } else {
+                Some(feedback_ds_err(NotAdmin, timestamp).to_vec())
+            }
+        }
         OnWireMessage::DSResult { .. }
         | OnWireMessage::DSRelayedUserMsg { .. }
-        | OnWireMessage::DSRelayedUserWelcome { .. }
+        | OnWireMessage::DSRelayedReinviteRequest { .. }
         | OnWireMessage::ASResult { .. }
+        | OnWireMessage::UserRotateKey { .. }
         | OnWireMessage::UserSyncCredentials { .. }
+        | OnWireMessage::ASPublicKeyRequest
+        | OnWireMessage::ASIssueToken { .. }
+        | OnWireMessage::UserWhoAmI { .. }
+        | OnWireMessage::UserStorePrefs { .. }
+        | OnWireMessage::UserLoadPrefs { .. }
+        | OnWireMessage::ASRevokeCredential { .. }
+        | OnWireMessage::ASToken { .. }
         | OnWireMessage::ASCredentialSyncResponse { .. }
+        | OnWireMessage::ASPrefsResponse { .. }
         | OnWireMessage::ASCredentialResponse { .. }
-        | OnWireMessage::DSKeyPackageResponse { .. } => {
-            panic!("Unacceptable message types received by DS")
+        | OnWireMessage::ASPublicKeyResponse { .. }
+        | OnWireMessage::ASWhoAmIResponse { .. }
+        | OnWireMessage::DSKeyPackageResponse { .. }
+        | OnWireMessage::DSAuditResponse { .. }
+        | OnWireMessage::DSPendingCounts { .. } => Some(
+            feedback_ds_msg("Unacceptable message type for the DS", false, timestamp).to_vec(),
+        ),
+    }
+}
+
+/// Whose token a request to the DS must come with, if the DS knows the
+/// public key of the AS
+enum TokenHolder {
+    /// The user the request names
+    User(String),
+    /// Any user, for sealed sends, which name nobody
+    AnyUser,
+}
+
+/// Whose token a request to the DS must come with. `None` for requests that
+/// are not sent by users
+fn token_holder(onwire_msg: &OnWireMessage) -> Option<TokenHolder> {
+    match onwire_msg {
+        // Registered under the identity of their key packages
+        OnWireMessage::UserKeyPackagesForDS { key_packages } => Some(TokenHolder::User(
+            key_packages
+                .first()
+                .and_then(|key_package| identity_to_str(key_package.credential().identity()))
+                .unwrap_or_default(),
+        )),
+        OnWireMessage::UserStandardSend { .. } | OnWireMessage::UserReliableSend { .. } => {
+            Some(match command_issuer(onwire_msg) {
+                Some(user_name) => TokenHolder::User(user_name.to_string()),
+                None => TokenHolder::AnyUser,
+            })
         }
+        // Authenticated by the admin credential instead
+        OnWireMessage::DSAuditQuery { .. } => None,
+        _ => command_issuer(onwire_msg).map(|user_name| TokenHolder::User(user_name.to_string())),
+    }
+}
+
+/// The user issuing a request to the DS or the AS, if the request names them
+fn command_issuer(onwire_msg: &OnWireMessage) -> Option<&str> {
+    match onwire_msg {
+        OnWireMessage::UserCredentialLookup { user_name, .. }
+        | OnWireMessage::UserKeyPackageLookup { user_name, .. }
+        | OnWireMessage::UserSync { user_name, .. }
+        | OnWireMessage::UserPoll { user_name }
+        | OnWireMessage::UserInvite { user_name, .. }
+        | OnWireMessage::UserRequestReinvite { user_name, .. }
+        | OnWireMessage::UserAcceptedInvite { user_name, .. }
+        | OnWireMessage::ASIssueToken { user_name, .. }
+        | OnWireMessage::UserRotateKey { user_name, .. }
+        | OnWireMessage::UserStorePrefs { user_name, .. }
+        | OnWireMessage::UserLoadPrefs { user_name }
+        | OnWireMessage::UserWhoAmI { user_name } => Some(user_name),
+        OnWireMessage::UserReliableSend { user_name, .. } => user_name.as_deref(),
+        OnWireMessage::UserStandardSend {
+            user_msg: GroupMessage::AppMlsMessage { sender, .. },
+            ..
+        } => sender.as_deref(),
+        OnWireMessage::UserStandardSend {
+            user_msg: GroupMessage::Welcome { sender, .. },
+            ..
+        } => Some(sender),
+        _ => None,
     }
 }
 
@@ -239,6 +593,8 @@ async fn user_sync(
     user_name: &String,
     shared_state: &Arc<SharedDeliverServiceState>,
     new_key_packages: Vec<KeyPackage>,
+    known_epochs: Vec<(CommGroupId, u64)>,
+    acked_until: Option<SystemTime>,
     begin_timestamp: Instant,
 ) -> Vec<OnWireMessage> {
     let state = shared_state;
@@ -246,37 +602,37 @@ async fn user_sync(
 
     let n_key_packages = new_key_packages.len();
     let _ = state.add_key_packages(new_key_packages, MAX_KEY_PACKAGES_PER_USER);
+    let remaining_key_packages = state.count_key_packages(user_name);
 
-    let invite_queue = state
-        .invite_indvl_queues
+    let reinvite_requests = state
+        .reinvite_request_indvl_queues
         .remove(user_name)
         .unwrap_or_default()
         .1;
-
-    // Return invites first, as invites should be processed first in clients
-    // to avoid client drops messages because no associated invite was ever received
-    for invite in invite_queue {
-        return_onwire_msg_list.push(OnWireMessage::DSRelayedUserWelcome {
-            comm_grp: invite.comm_grp.to_owned(),
-            sender: invite.inviter.to_owned(),
-            welcome: invite.welcome_obj.to_owned(),
+    for (requester, comm_grp) in reinvite_requests {
+        return_onwire_msg_list.push(OnWireMessage::DSRelayedReinviteRequest {
+            requester,
+            comm_grp,
         });
     }
 
+    // Relay again what the last syncs relayed and the client did not acknowledge
+    let mut unread_messages = match acked_until {
+        Some(acked_until) => state.take_unacked_msgs(user_name, acked_until, &known_epochs),
+        None => vec![],
+    };
+
     // Find unordered message, if any
     let unordered_message_queue = state
         .unordered_message_indvl_queues
         .remove(user_name)
         .unwrap_or_default()
         .1;
-    let mut unread_messages: Vec<ProtectedMessageWithMetaData> = unordered_message_queue
-        .iter()
-        .map(|message_id| {
-            state
-                .pop_message_by_id(&message_id.to_owned(), user_name)
-                .expect("Cannot find corresponding message id")
-        })
-        .collect();
+    unread_messages.extend(unordered_message_queue.iter().map(|message_id| {
+        state
+            .pop_message_by_id(&message_id.to_owned(), user_name)
+            .expect("Cannot find corresponding message id")
+    }));
 
     // Find ordered message, if any
     let comm_group_ids = if let Some((_, group_ids)) = state.indvl_groups.remove(user_name) {
@@ -287,13 +643,44 @@ async fn user_sync(
     let ordered_messages: Vec<ProtectedMessageWithMetaData> = comm_group_ids
         .iter()
         .flat_map(|comm_group_id| {
+            let since_epoch = known_epochs
+                .iter()
+                .find(|(known_comm_grp, _)| known_comm_grp == comm_group_id)
+                .map(|(_, epoch)| *epoch);
             state
-                .pop_all_ordered_msg_w_meta(user_name, comm_group_id)
+                .pop_all_ordered_msg_w_meta(user_name, comm_group_id, since_epoch)
                 .into_iter()
         })
         .collect();
     unread_messages.extend(ordered_messages);
-    unread_messages.sort_by(|msg1, msg2| msg1.server_timestamp.cmp(&msg2.server_timestamp));
+    if acked_until.is_some() {
+        state.keep_unacked_msgs(user_name, unread_messages.clone());
+    }
+    // Deliver again the welcomes of invites not accepted yet, unless queued
+    for retained in state.retained_welcomes(user_name) {
+        if !unread_messages.iter().any(|msg_w_meta| {
+            msg_w_meta.protected_msg.is_welcome()
+                && msg_w_meta.community_group_id == retained.comm_grp
+        }) {
+            unread_messages.push(ProtectedMessageWithMetaData::new(
+                GroupMessage::from_welcome(
+                    retained.welcome_obj,
+                    retained.comm_grp,
+                    retained.inviter,
+                ),
+                false,
+                retained.created_at,
+            ));
+        }
+    }
+    // Return welcomes first, as invites should be processed first in clients
+    // to avoid client drops messages because no associated invite was ever received
+    unread_messages.sort_by_key(|msg_w_meta| {
+        (
+            !msg_w_meta.protected_msg.is_welcome(),
+            msg_w_meta.server_timestamp,
+        )
+    });
     let unread_count = unread_messages.len();
 
     for protected_msg_w_meta in unread_messages {
@@ -308,15 +695,62 @@ async fn user_sync(
         user_name, unread_count, n_key_packages,
     );
 
-    return_onwire_msg_list.push(feedback_ds_msg(&explanation, true, begin_timestamp));
+    return_onwire_msg_list.push(feedback_ds_sync_msg(
+        explanation,
+        remaining_key_packages,
+        begin_timestamp,
+    ));
     return_onwire_msg_list
 }
 
-/// The `user_invite` function accepts a request to invite a new user to
-/// an existing group. The server places the welcome object from the invite
-/// in the invitee's incoming message queue and the group update object in
-/// the incoming message queues of the other existing group members. Finally,
-/// the function produces a response to the sender of the invite.
+/// Stops delivering again the welcome of the invite of `user_name` to
+/// `comm_grp`, which they accepted
+async fn user_accepted_invite(
+    user_name: &String,
+    comm_grp: &CommGroupId,
+    shared_state: &Arc<SharedDeliverServiceState>,
+    begin_timestamp: Instant,
+) -> Vec<OnWireMessage> {
+    shared_state.forget_retained_welcome(user_name, comm_grp);
+    feedback_ds_msg_w_identifier(
+        "Invite acceptance recorded".to_string(),
+        true,
+        Some(comm_grp.get_string()),
+        vec![],
+        begin_timestamp,
+    )
+    .to_vec()
+}
+
+/// Queues the request of `user_name` to be invited again to `comm_grp`
+/// for each of the `recipients`, who receive it on their next sync.
+async fn user_request_reinvite(
+    user_name: &str,
+    comm_grp: &CommGroupId,
+    recipients: Vec<String>,
+    shared_state: &Arc<SharedDeliverServiceState>,
+    begin_timestamp: Instant,
+) -> Vec<OnWireMessage> {
+    if recipients.is_empty() {
+        return feedback_ds_msg(
+            "No recipient for the re-invite request",
+            false,
+            begin_timestamp,
+        )
+        .to_vec();
+    }
+    if let Err(err) = shared_state.check_recipients(&recipients) {
+        return feedback_ds_err(err, begin_timestamp).to_vec();
+    }
+    shared_state.store_reinvite_request(user_name, comm_grp, &recipients);
+    feedback_ds_msg("Re-invite request relayed", true, begin_timestamp).to_vec()
+}
+
+/// The `user_invite` function accepts a request to invite new users to
+/// an existing group. The server delivers the welcome object from the invite
+/// to the invitees like any other unordered message, while the group update
+/// object is sent to the existing group members separately. Finally, the
+/// function produces a response to the sender of the invite.
 async fn user_invite(
     user_name: &String,
     comm_grp: &CommGroupId,
@@ -326,32 +760,28 @@ async fn user_invite(
     begin_timestamp: Instant,
 ) -> Vec<OnWireMessage> {
     let state = shared_state;
-    let comm_group_id = comm_grp;
-
-    // if !state.invite_indvl_queues.contains_key(invitee_name) {
-    //     state
-    //         .invite_indvl_queues
-    //         .insert(invitee_name.to_string(), Vec::new());
-    // }
-    // place welcome message in invitee queue
-    for invitee_name in invitee_names {
-        state
-            .invite_indvl_queues
-            .entry(invitee_name.to_string())
-            .or_default()
-            .push(Invite {
-                welcome_obj: welcome_obj.clone(),
-                invitee: invitee_name,
-                inviter: user_name.to_string(),
-                comm_grp: comm_grp.to_owned(),
-            });
+    let created_at = state.clock.now();
+
+    for invitee_name in invitee_names.iter() {
+        state.retain_welcome(&Invite {
+            welcome_obj: welcome_obj.clone(),
+            invitee: invitee_name.to_string(),
+            inviter: user_name.to_string(),
+            comm_grp: comm_grp.to_owned(),
+            created_at,
+        });
     }
+    let welcome_msg =
+        GroupMessage::from_welcome(welcome_obj, comm_grp.to_owned(), user_name.to_string());
+    state.delivery_to_recipients(
+        &invitee_names,
+        ProtectedMessageWithMetaData::new(welcome_msg, false, created_at),
+    );
 
     feedback_ds_msg_w_identifier(
         "Invite has been sent".to_string(),
         true,
-        Some(comm_group_id.get_string()),
-        //state.pop_all_ordered_msg(user_name),
+        Some(comm_grp.get_string()),
         vec![],
         begin_timestamp,
     )
@@ -359,31 +789,35 @@ async fn user_invite(
 }
 
 async fn user_reliable_send(
-    user_name: String,
+    user_name: Option<String>,
     recipients: Vec<String>,
     protected_message: &GroupMessage,
     shared_state: &Arc<SharedDeliverServiceState>,
     begin_timestamp: Instant,
 ) -> Vec<OnWireMessage> {
     let state = shared_state;
+    if let Err(err) = state.check_recipients(&recipients) {
+        return feedback_ds_err(err, begin_timestamp).to_vec();
+    }
     let msg_w_meta =
-        ProtectedMessageWithMetaData::new(protected_message.to_owned(), true, SystemTime::now());
-    state.delivery_to_recipients(&recipients, msg_w_meta);
-    let explanation = "A user sent an ordered message.".to_string();
-
-    let comm_group_id = match &protected_message {
-        GroupMessage::AppMlsMessage {
-            comm_grp,
-            mls_msg: _,
-            sender: _,
-        } => comm_grp,
+        ProtectedMessageWithMetaData::new(protected_message.to_owned(), true, state.clock.now());
+    let explanation = if state.delivery_to_recipients(&recipients, msg_w_meta) {
+        "A user sent an ordered message."
+    } else {
+        "A user resent an already delivered ordered message."
+    }
+    .to_string();
+    // A sealed sender retrieves its echo, in order, with its next sync
+    let preceding_and_sent_ordered_msgs = match user_name {
+        Some(user_name) => state.pop_all_ordered_msg(&user_name, &protected_message.get_group_id()),
+        None => vec![],
     };
 
     feedback_ds_msg_w_identifier(
         explanation,
         true,
-        Some(comm_group_id.get_string()),
-        state.pop_all_ordered_msg(&user_name, &protected_message.get_group_id()),
+        Some(protected_message.get_group_id().get_string()),
+        preceding_and_sent_ordered_msgs,
         begin_timestamp,
     )
     .to_vec()
@@ -394,12 +828,31 @@ async fn user_send_standard(
     protected_message: &GroupMessage,
     shared_state: &Arc<SharedDeliverServiceState>,
     identifier: Option<String>,
+    ephemeral: Option<EphemeralKind>,
     begin_timestamp: Instant,
 ) -> Vec<OnWireMessage> {
-    let msg_w_meta =
-        ProtectedMessageWithMetaData::new(protected_message.to_owned(), false, SystemTime::now());
-    shared_state.delivery_to_recipients(&recipients, msg_w_meta);
-    let explanation = "A user sent a message.".to_string();
+    if let Err(err) = shared_state.check_recipients(&recipients) {
+        return feedback_ds_msg_w_identifier(
+            err.to_string(),
+            false,
+            identifier,
+            vec![],
+            begin_timestamp,
+        )
+        .to_vec();
+    }
+    let msg_w_meta = ProtectedMessageWithMetaData::new(
+        protected_message.to_owned(),
+        false,
+        shared_state.clock.now(),
+    )
+    .with_ephemeral(ephemeral);
+    let explanation = if shared_state.delivery_to_recipients(&recipients, msg_w_meta) {
+        "A user sent a message."
+    } else {
+        "A user resent an already delivered message."
+    }
+    .to_string();
     feedback_ds_msg_w_identifier(explanation, true, identifier, vec![], begin_timestamp).to_vec()
 }
 
@@ -434,27 +887,73 @@ async fn user_request_key_package(
     shared_state: &Arc<SharedDeliverServiceState>,
     begin_timestamp: Instant,
 ) -> Vec<OnWireMessage> {
-    let mut found_packages = Vec::new();
+    // One key package for each device of each queried user, resolved before
+    // any is popped so that a failed lookup consumes none
+    let mut all_device_names = Vec::new();
     for queried_name in queried_names {
-        let key_packages = shared_state.user_key_packages.get_mut(&*queried_name);
-
-        match key_packages {
-            Some(mut key_packages) => {
-                if let Some(key_package) = key_packages.pop() {
-                    found_packages.push(key_package);
-                } else {
-                    return feedback_ds_err(NoAvailableUserKeyPackage, begin_timestamp).to_vec();
+        let device_names = shared_state.device_names(&queried_name);
+        if device_names.is_empty() {
+            return feedback_ds_err(NoSuchQueriedUser, begin_timestamp).to_vec();
+        }
+        all_device_names.extend(device_names);
+    }
+    let mut popped: Vec<(String, KeyPackage)> = Vec::new();
+    for device_name in all_device_names {
+        let key_package = shared_state
+            .user_key_packages
+            .get_mut(&device_name)
+            .and_then(|mut key_packages| key_packages.pop());
+        match key_package {
+            Some(key_package) => popped.push((device_name, key_package)),
+            None => {
+                // Puts back the packages popped for the other devices
+                for (device_name, key_package) in popped {
+                    shared_state
+                        .user_key_packages
+                        .entry(device_name)
+                        .or_insert_with(Vec::new)
+                        .push(key_package);
                 }
+                return feedback_ds_err(NoAvailableUserKeyPackage, begin_timestamp).to_vec();
             }
-            None => return feedback_ds_err(NoSuchQueriedUser, begin_timestamp).to_vec(),
         }
     }
-    vec![
-        OnWireMessage::DSKeyPackageResponse {
-            queried_user_key_packages: found_packages,
-        },
-        feedback_ds_msg("Found queried user's keypackage", true, begin_timestamp),
-    ]
+    let found_packages: Vec<KeyPackage> = popped
+        .into_iter()
+        .map(|(_, key_package)| key_package)
+        .collect();
+    let chunks = into_chunks(found_packages);
+    let total_chunks = chunks.len();
+    let mut responses: Vec<OnWireMessage> = chunks
+        .into_iter()
+        .enumerate()
+        .map(
+            |(chunk_index, key_packages)| OnWireMessage::DSKeyPackageResponse {
+                queried_user_key_packages: key_packages,
+                chunk_index,
+                total_chunks,
+            },
+        )
+        .collect();
+    responses.push(feedback_ds_msg(
+        "Found queried user's keypackage",
+        true,
+        begin_timestamp,
+    ));
+    responses
+}
+
+/// Splits lookup results into chunks of at most
+/// [MAX_LOOKUP_RESULTS_PER_CHUNK] results, keeping their order. No results
+/// make a single empty chunk
+fn into_chunks<T: Clone>(results: Vec<T>) -> Vec<Vec<T>> {
+    if results.is_empty() {
+        return vec![vec![]];
+    }
+    results
+        .chunks(MAX_LOOKUP_RESULTS_PER_CHUNK)
+        .map(<[T]>::to_vec)
+        .collect()
 }
 
 fn feedback_ds_err(err: UserRequestErrors, begin_timestamp: Instant) -> OnWireMessage {
@@ -494,6 +993,24 @@ fn feedback_ds_msg_w_identifier(
         explanation: Some(explanation),
         identifier,
         preceding_and_sent_ordered_msgs: preceding_ordered_msgs,
+        remaining_key_packages: None,
+        process_time_used: begin_timestamp.elapsed(),
+    }
+}
+
+/// Feedback to a successful `UserSync`, reporting how many key packages
+/// the DS still holds for the user so that the client can top up
+fn feedback_ds_sync_msg(
+    explanation: String,
+    remaining_key_packages: usize,
+    begin_timestamp: Instant,
+) -> OnWireMessage {
+    OnWireMessage::DSResult {
+        request_valid: true,
+        explanation: Some(explanation),
+        identifier: None,
+        preceding_and_sent_ordered_msgs: vec![],
+        remaining_key_packages: Some(remaining_key_packages),
         process_time_used: begin_timestamp.elapsed(),
     }
 }
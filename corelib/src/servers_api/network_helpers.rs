@@ -1,28 +1,64 @@
-use std::time::SystemTime;
+use std::time::Duration;
 
-use axum::extract::ws::{Message, WebSocket};
+use actix_rt::time::timeout;
+use axum::extract::ws::Message;
 use futures::lock::Mutex;
-use futures::sink::SinkExt;
-use futures::stream::SplitSink;
+use futures::sink::{Sink, SinkExt};
 use log::*;
-use versions::Versioning;
+use thiserror::Error;
 
 use crate::messages::{OnWireMessage, OnWireMessageWithMetaData};
 
-pub async fn send_enum_app_message(
+/// How long a reply may wait for a client that does not read its messages
+/// fast enough, after which servers drop the connection
+pub const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Why a reply could not be sent to a client
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum SendError {
+    #[error("The client did not read its messages within {0:?}")]
+    Timeout(Duration),
+    #[error("The connection to the client is closed")]
+    Closed,
+}
+
+/// Sends the message to a client, compressed if `compress` is set, which
+/// servers only do for clients that compress their own messages. Waits at
+/// most [SEND_TIMEOUT] for a slow client, see [send_ws_message]
+pub async fn send_enum_app_message<S>(
     app_msg: &OnWireMessage,
-    sender: &mut Mutex<SplitSink<WebSocket, Message>>,
-) {
-    let server_reply_msg = OnWireMessageWithMetaData {
-        onwire_msg: app_msg.to_owned(),
-        sender_timestamp: SystemTime::now(),
-        version: Versioning::new("0.3.0").unwrap().to_string(),
-    };
-    let encoded_reply_msg = serde_json::to_vec(&server_reply_msg).unwrap();
-    let _ = sender
-        .get_mut()
-        .send(Message::Binary(encoded_reply_msg))
-        .await;
+    compress: bool,
+    sender: &mut Mutex<S>,
+) -> Result<(), SendError>
+where
+    S: Sink<Message> + Unpin,
+{
+    let server_reply_msg = OnWireMessageWithMetaData::new(app_msg.to_owned(), compress);
+    let encoded_reply_msg = server_reply_msg.encode();
+    send_ws_message(
+        Message::Binary(encoded_reply_msg),
+        sender.get_mut(),
+        SEND_TIMEOUT,
+    )
+    .await
+}
+
+/// Sends the message and flushes it. While the buffer of `sink` is full,
+/// i.e., the client is not reading, this yields until it has room again, for
+/// at most `send_timeout`
+pub async fn send_ws_message<S>(
+    ws_msg: Message,
+    sink: &mut S,
+    send_timeout: Duration,
+) -> Result<(), SendError>
+where
+    S: Sink<Message> + Unpin,
+{
+    match timeout(send_timeout, sink.send(ws_msg)).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(_)) => Err(SendError::Closed),
+        Err(_) => Err(SendError::Timeout(send_timeout)),
+    }
 }
 
 pub async fn parse_wrapped_ws_msg(
@@ -35,9 +71,8 @@ pub async fn parse_wrapped_ws_msg(
             // Try to retrieve binary from the websocket package
             match ws_msg {
                 Message::Binary(ws_binary) => {
-                    let parsed_app_msg_w_meta: OnWireMessageWithMetaData =
-                        serde_json::from_slice(&ws_binary)
-                            .expect("Cannot deserialize received binary");
+                    let parsed_app_msg_w_meta = OnWireMessageWithMetaData::decode(&ws_binary)
+                        .expect("Cannot deserialize received binary");
                     Some(parsed_app_msg_w_meta)
                 }
                 Message::Close(_) => None,
@@ -0,0 +1,464 @@
+#[cfg(all(test, feature = "gov"))]
+mod servers_api_tests {
+    use std::collections::HashSet;
+    use std::ops::DerefMut;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    use openmls::prelude::KeyPackage;
+
+    use crate::client_api::{
+        create_group_msg, pre_add_invite_msg, register_msg_ds, send_text_msg_mls, sync_msg,
+        token_request_msg,
+    };
+    use crate::clock::{Clock, MockClock, SharedClock};
+    use crate::messages::{AuthToken, GroupMessage, OnWireMessage};
+    use crate::servers_api::as_struct::{AuthServiceState, TOKEN_LIFETIME};
+    use crate::servers_api::ds_structs::local_message_struct::ProtectedMessageWithMetaData;
+    use crate::servers_api::ds_structs::{DeliveryServiceState, MAX_AUDIT_ENTRIES_PER_USER};
+    use crate::servers_api::{
+        handle_onwire_msg_as_local, handle_onwire_msg_ds_local, handle_onwire_msg_w_token_ds_local,
+    };
+    use crate::test_helpers::*;
+
+    #[actix_rt::test]
+    /// The commands a user issued to the DS should be returned in order to
+    /// operators presenting the admin credential, and to nobody else
+    async fn test_ds_command_audit_query() {
+        let mut ds_state = DeliveryServiceState::new();
+        ds_state.admin_credential = Some("secret".to_string());
+        ds_state.user_key_packages.insert("bob".to_string(), vec![]);
+        let ds_state = Arc::new(ds_state);
+        let mut admin = TestClientBundle::new("admin");
+        let _ = create_group_msg(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        let mls_msg = admin
+            .configs
+            .get_ref_group(&comm_grp())
+            .unwrap()
+            .borrow_mut()
+            .create_message(&admin.backend, b"hello")
+            .unwrap();
+        let send = OnWireMessage::UserStandardSend {
+            recipients: vec![admin.name()],
+            identifier: None,
+            user_msg: GroupMessage::from_mls(mls_msg, comm_grp(), Some("bob".to_string())),
+            ephemeral: None,
+        };
+        let sync = OnWireMessage::UserSync {
+            user_name: "bob".to_string(),
+            new_key_packages: vec![],
+            known_epochs: vec![],
+            acked_until: None,
+        };
+        for msg in [send, sync] {
+            handle_onwire_msg_ds_local(msg, &ds_state).await;
+        }
+
+        let query = |admin_credential: &str| OnWireMessage::DSAuditQuery {
+            admin_credential: admin_credential.to_string(),
+            user_name: "bob".to_string(),
+        };
+        let replies = handle_onwire_msg_ds_local(query("guess"), &ds_state).await;
+        assert!(matches!(
+            replies[..],
+            [OnWireMessage::DSResult {
+                request_valid: false,
+                ..
+            }]
+        ));
+
+        let replies = handle_onwire_msg_ds_local(query("secret"), &ds_state).await;
+        let entries = match &replies[0] {
+            OnWireMessage::DSAuditResponse { entries, .. } => entries.clone(),
+            other => panic!("Unexpected reply {:?}", other),
+        };
+        let commands: Vec<&str> = entries.iter().map(|entry| entry.command.as_str()).collect();
+        assert_eq!(commands, ["UserStandardSend", "UserSync"]);
+        assert!(entries[0].timestamp <= entries[1].timestamp);
+
+        // Only the latest commands are kept
+        for _ in 0..MAX_AUDIT_ENTRIES_PER_USER {
+            ds_state.record_command("bob", "UserPoll");
+        }
+        let entries = ds_state.command_audit.get("bob").unwrap().clone();
+        assert_eq!(entries.len(), MAX_AUDIT_ENTRIES_PER_USER);
+        assert!(entries.iter().all(|entry| entry.command == "UserPoll"));
+    }
+
+    #[actix_rt::test]
+    /// A DS that knows the public key of the AS should only process requests
+    /// naming a user along with an unexpired token the AS issued to them
+    async fn test_ds_checks_tokens_issued_by_as() {
+        let as_state = Arc::new(AuthServiceState::new());
+        let clock = MockClock::new(SystemTime::now());
+        let ds_state = Arc::new(DeliveryServiceState {
+            as_public_key: Some(as_state.public_key()),
+            clock: SharedClock::new(clock.clone()),
+            ..DeliveryServiceState::new()
+        });
+        let mut alice = TestClientBundle::new("alice");
+        let mut bob = TestClientBundle::new("bob");
+        alice.register_assert_ok(&as_state, &ds_state).await;
+        bob.register_assert_ok(&as_state, &ds_state).await;
+        for client in [&mut alice, &mut bob] {
+            let msgs =
+                handle_onwire_msg_as_local(token_request_msg(&*client.configs), &as_state).await;
+            assert_all_feedback_ok(&client.parse_msgs(&msgs));
+        }
+        let alice_token = alice.configs.get_ds_token().unwrap();
+        let bob_token = bob.configs.get_ds_token().unwrap();
+        assert_eq!(alice_token.user_name, "alice");
+
+        let sync_valid = |token: Option<AuthToken>| {
+            let ds_state = ds_state.clone();
+            let sync = sync_msg(&*alice.configs, vec![]);
+            async move {
+                match &handle_onwire_msg_w_token_ds_local(sync, token, &ds_state).await[..] {
+                    [.., OnWireMessage::DSResult { request_valid, .. }] => *request_valid,
+                    other => panic!("Unexpected replies {:?}", other),
+                }
+            }
+        };
+        assert!(sync_valid(Some(alice_token.clone())).await);
+        assert!(!sync_valid(None).await);
+        assert!(!sync_valid(Some(bob_token.clone())).await);
+
+        // Registering key packages takes a token of the user they belong to
+        let register_valid = |kps: Vec<KeyPackage>, token: Option<AuthToken>| {
+            let ds_state = ds_state.clone();
+            async move {
+                match &handle_onwire_msg_w_token_ds_local(
+                    register_msg_ds(kps).remove(0),
+                    token,
+                    &ds_state,
+                )
+                .await[..]
+                {
+                    [.., OnWireMessage::DSResult { request_valid, .. }] => *request_valid,
+                    other => panic!("Unexpected replies {:?}", other),
+                }
+            }
+        };
+        assert!(!register_valid(alice.new_kps(1), None).await);
+        assert!(!register_valid(alice.new_kps(1), Some(bob_token)).await);
+        assert!(register_valid(alice.new_kps(1), Some(alice_token.clone())).await);
+
+        clock.advance(TOKEN_LIFETIME + Duration::from_secs(1));
+        assert!(!sync_valid(Some(alice_token)).await);
+    }
+
+    #[actix_rt::test]
+    /// Garbage collection on the DS should remove the invites and messages
+    /// older than the threshold, and only those
+    async fn test_ds_gc_removes_stale_invites_and_msgs() {
+        let (as_state, ds_state, [mut admin, bob, charlie, dave]) =
+            register_clients_assert_ok(["admin", "bob", "charlie", "dave"]).await;
+        let mut members = vec![bob];
+        let mut invitees = vec![charlie, dave];
+        setup_group_assert_ok(&mut admin, &mut members, &as_state, &ds_state).await;
+        admin.sync_as_assert_ok(&as_state).await;
+        let max_age = Duration::from_secs(3600);
+        let long_ago = SystemTime::now() - 2 * max_age;
+
+        let text_msgs = send_text_msg_mls(
+            &admin.name(),
+            &comm_grp(),
+            "hello".to_string(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        admin.send_all_assert_ok(text_msgs, &ds_state).await;
+        let stale_msg_id = ds_state.unordered_message_indvl_queues.get("bob").unwrap()[0].clone();
+        ds_state
+            .message_id_to_message
+            .get_mut(&stale_msg_id)
+            .unwrap()
+            .1
+            .server_timestamp = long_ago;
+
+        for invitee in invitees.iter_mut() {
+            let (invite_msgs, _) = pre_add_invite_msg(
+                &admin.name(),
+                &comm_grp(),
+                &mut admin.backend,
+                admin.configs.deref_mut(),
+                vec![invitee.new_key_package()],
+            );
+            admin.send_all_assert_ok(invite_msgs, &ds_state).await;
+        }
+        let stale_welcome_id = ds_state
+            .unordered_message_indvl_queues
+            .get("charlie")
+            .unwrap()[0]
+            .clone();
+        ds_state
+            .message_id_to_message
+            .get_mut(&stale_welcome_id)
+            .unwrap()
+            .1
+            .server_timestamp = long_ago;
+
+        assert_eq!(ds_state.gc(max_age), (1, 1));
+        assert!(ds_state
+            .unordered_message_indvl_queues
+            .get("charlie")
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            ds_state
+                .unordered_message_indvl_queues
+                .get("dave")
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(ds_state.message_id_to_message.get(&stale_msg_id).is_none());
+        assert!(ds_state
+            .unordered_message_indvl_queues
+            .get("bob")
+            .unwrap()
+            .is_empty());
+        assert_eq!(ds_state.gc(max_age), (0, 0));
+    }
+
+    #[actix_rt::test]
+    /// A DS restarted from its WAL should hold the messages its recipients
+    /// have yet to retrieve, and only those
+    async fn test_ds_state_recovered_from_wal() {
+        let wal_path =
+            std::env::temp_dir().join(format!("mlsgov-ds-wal-{}.log", uuid::Uuid::new_v4()));
+        let mut ds_state = DeliveryServiceState::new();
+        ds_state.open_wal(&wal_path).unwrap();
+        let mut admin = TestClientBundle::new("admin");
+        let _ = create_group_msg(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        let mls_msg = admin
+            .configs
+            .get_ref_group(&comm_grp())
+            .unwrap()
+            .borrow_mut()
+            .create_message(&admin.backend, b"hello")
+            .unwrap();
+        let group_msg = GroupMessage::from_mls(mls_msg, comm_grp(), Some(admin.name()));
+        assert!(ds_state.delivery_to_recipients(
+            &vec!["bob".to_string(), "charlie".to_string()],
+            ProtectedMessageWithMetaData::new(group_msg, false, SystemTime::now()),
+        ));
+
+        // Only bob retrieves the message before the DS crashes
+        ds_state.user_key_packages.insert("bob".to_string(), vec![]);
+        let sync = OnWireMessage::UserSync {
+            user_name: "bob".to_string(),
+            new_key_packages: vec![],
+            known_epochs: vec![],
+            acked_until: None,
+        };
+        let ds_state = Arc::new(ds_state);
+        let relayed = handle_onwire_msg_ds_local(sync, &ds_state).await;
+        assert!(relayed
+            .iter()
+            .any(|reply| matches!(reply, OnWireMessage::DSRelayedUserMsg { .. })));
+
+        let mut recovered_state = DeliveryServiceState::new();
+        assert_eq!(recovered_state.open_wal(&wal_path).unwrap(), 2);
+        std::fs::remove_file(&wal_path).unwrap();
+        assert!(!recovered_state
+            .unordered_message_indvl_queues
+            .contains_key("bob"));
+        let charlie_queue = recovered_state
+            .unordered_message_indvl_queues
+            .get("charlie")
+            .unwrap()
+            .clone();
+        assert_eq!(charlie_queue.len(), 1);
+        let (unretrieved_recipients, msg_w_meta) = recovered_state
+            .message_id_to_message
+            .get(&charlie_queue[0])
+            .unwrap()
+            .clone();
+        assert_eq!(
+            unretrieved_recipients,
+            HashSet::from(["charlie".to_string()])
+        );
+        assert_eq!(
+            msg_w_meta,
+            ds_state
+                .message_id_to_message
+                .get(&charlie_queue[0])
+                .unwrap()
+                .1
+        );
+    }
+
+    #[test]
+    /// Messages removed as stale should stay removed once a DS restarts from
+    /// its WAL, and a checkpoint should only leave the operations applied
+    /// since its snapshot in the WAL
+    fn test_ds_gc_and_checkpoint_logged_to_wal() {
+        let wal_path =
+            std::env::temp_dir().join(format!("mlsgov-ds-wal-{}.log", uuid::Uuid::new_v4()));
+        let mock_clock =
+            MockClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        let mut ds_state =
+            DeliveryServiceState::new().with_clock(SharedClock::new(mock_clock.clone()));
+        ds_state.open_wal(&wal_path).unwrap();
+        let mut admin = TestClientBundle::new("admin");
+        let _ = create_group_msg(
+            &admin.name(),
+            &comm_grp(),
+            &mut admin.backend,
+            admin.configs.deref_mut(),
+        );
+        let mut deliver = |text: &str| {
+            let mls_msg = admin
+                .configs
+                .get_ref_group(&comm_grp())
+                .unwrap()
+                .borrow_mut()
+                .create_message(&admin.backend, text.as_bytes())
+                .unwrap();
+            let group_msg = GroupMessage::from_mls(mls_msg, comm_grp(), Some(admin.name()));
+            assert!(ds_state.delivery_to_recipients(
+                &vec!["charlie".to_string()],
+                ProtectedMessageWithMetaData::new(group_msg, false, mock_clock.now()),
+            ));
+        };
+        deliver("stale");
+        mock_clock.advance(Duration::from_secs(7200));
+        assert_eq!(ds_state.gc(Duration::from_secs(3600)), (0, 1));
+
+        let mut recovered_state = DeliveryServiceState::new();
+        assert_eq!(recovered_state.open_wal(&wal_path).unwrap(), 2);
+        assert!(recovered_state.message_id_to_message.is_empty());
+        assert!(recovered_state
+            .unordered_message_indvl_queues
+            .get("charlie")
+            .map_or(true, |queue| queue.is_empty()));
+
+        deliver("fresh");
+        let mut stored = String::new();
+        ds_state
+            .checkpoint(|state| {
+                stored = serde_yaml::to_string(state).unwrap();
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(std::fs::metadata(&wal_path).unwrap().len(), 0);
+        let stored_state: DeliveryServiceState = serde_yaml::from_str(&stored).unwrap();
+        assert_eq!(stored_state.message_id_to_message.len(), 1);
+
+        // Delivered while the snapshot is stored, so only kept by the WAL
+        ds_state
+            .checkpoint(|state| {
+                deliver("during");
+                stored = serde_yaml::to_string(state).unwrap();
+                Ok(())
+            })
+            .unwrap();
+        let stored_state: DeliveryServiceState = serde_yaml::from_str(&stored).unwrap();
+        assert_eq!(stored_state.message_id_to_message.len(), 1);
+        assert_eq!(stored_state.replay_wal(&wal_path).unwrap(), 1);
+        assert_eq!(stored_state.message_id_to_message.len(), 2);
+        std::fs::remove_file(&wal_path).unwrap();
+    }
+
+    #[actix_rt::test]
+    /// A key package lookup that fails for one device should not consume the
+    /// key packages of the other devices and users
+    async fn test_ds_failed_key_package_lookup_keeps_packages() {
+        let ds_state = Arc::new(DeliveryServiceState::new());
+        let mut alice = TestClientBundle::new("alice");
+        let mut laptop = TestClientBundle::new("bob#laptop");
+        ds_state
+            .user_key_packages
+            .insert(alice.name(), alice.new_kps(2));
+        ds_state
+            .user_key_packages
+            .insert(laptop.name(), laptop.new_kps(2));
+        ds_state
+            .user_key_packages
+            .insert("bob#phone".to_string(), vec![]);
+
+        let replies = handle_onwire_msg_ds_local(
+            OnWireMessage::UserKeyPackageLookup {
+                user_name: alice.name(),
+                queried_users: vec![alice.name(), "bob".to_string()],
+            },
+            &ds_state,
+        )
+        .await;
+        assert!(matches!(
+            replies[..],
+            [OnWireMessage::DSResult {
+                request_valid: false,
+                ..
+            }]
+        ));
+        assert_eq!(ds_state.count_key_packages(&alice.name()), 2);
+        assert_eq!(ds_state.count_key_packages(&laptop.name()), 2);
+    }
+
+    #[actix_rt::test]
+    /// Messages meant for the AS or for clients should be rejected by the DS
+    /// rather than crash it
+    async fn test_ds_rejects_messages_not_meant_for_it() {
+        let ds_state = Arc::new(DeliveryServiceState::new());
+        let replies = handle_onwire_msg_ds_local(
+            OnWireMessage::UserWhoAmI {
+                user_name: "alice".to_string(),
+            },
+            &ds_state,
+        )
+        .await;
+        assert!(matches!(
+            replies[..],
+            [OnWireMessage::DSResult {
+                request_valid: false,
+                ..
+            }]
+        ));
+    }
+
+    #[actix_rt::test]
+    /// Re-invite requests should be rejected, and relayed to nobody, if they
+    /// name unknown users or more recipients than the DS accepts per send
+    async fn test_ds_rejects_reinvite_requests_to_invalid_recipients() {
+        let ds_state = Arc::new(DeliveryServiceState {
+            max_recipients_per_send: Some(2),
+            ..DeliveryServiceState::new()
+        });
+        for name in ["alice", "bob", "charlie", "dave"] {
+            ds_state.user_key_packages.insert(name.to_string(), vec![]);
+        }
+
+        let reinvite_valid = |recipients: &[&str]| {
+            let ds_state = ds_state.clone();
+            let request = OnWireMessage::UserRequestReinvite {
+                user_name: "alice".to_string(),
+                comm_grp: comm_grp(),
+                recipients: recipients.iter().map(|name| name.to_string()).collect(),
+            };
+            async move {
+                match &handle_onwire_msg_ds_local(request, &ds_state).await[..] {
+                    [OnWireMessage::DSResult { request_valid, .. }] => *request_valid,
+                    other => panic!("Unexpected replies {:?}", other),
+                }
+            }
+        };
+        assert!(!reinvite_valid(&["bob", "mallory"]).await);
+        assert!(!reinvite_valid(&["bob", "charlie", "dave"]).await);
+        assert!(ds_state.reinvite_request_indvl_queues.is_empty());
+
+        assert!(reinvite_valid(&["bob", "charlie"]).await);
+        assert!(ds_state.reinvite_request_indvl_queues.contains_key("bob"));
+    }
+}
@@ -0,0 +1,146 @@
+//! Persistence of server and client states behind a swappable [StateStore],
+//! so that states do not have to live in local files
+
+use std::fs;
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_yaml::Value;
+use thiserror::Error;
+
+mod state_store_tests;
+
+#[derive(Debug, Error)]
+pub enum StateStoreError {
+    #[error("Cannot access the stored state: {0}")]
+    Access(String),
+    #[error("Cannot decode the stored state: {0}")]
+    Decode(String),
+    #[error(
+        "The stored state has layout version {found}, only versions up to {supported} can be read"
+    )]
+    UnsupportedVersion { found: u32, supported: u32 },
+}
+
+/// Why a stored state could not be restored, e.g., at startup
+#[derive(Debug, Error)]
+#[error("Cannot restore the state at {key}: {source}. Run with --fresh-start to discard it")]
+pub struct RestoreError {
+    pub key: String,
+    pub source: StateStoreError,
+}
+
+/// The field of a [VersionedState] holding the version of its layout
+pub const SCHEMA_VERSION_FIELD: &str = "schema_version";
+/// The layout version of states stored before versions were recorded
+pub const LEGACY_SCHEMA_VERSION: u32 = 1;
+
+/// The layout version of a state stored without one
+pub fn legacy_schema_version() -> u32 {
+    LEGACY_SCHEMA_VERSION
+}
+
+/// A state stored along with the version of its layout, so that a state
+/// stored with an earlier layout is migrated rather than discarded
+pub trait VersionedState: DeserializeOwned {
+    /// The version of the layout of the states stored now
+    const SCHEMA_VERSION: u32;
+
+    /// Upgrades `value`, a state encoded with the layout `from_version`, to
+    /// the next layout. Only called for versions below [Self::SCHEMA_VERSION]
+    fn migrate(from_version: u32, value: &mut Value) -> Result<(), StateStoreError>;
+}
+
+/// Decodes the state `value`, after migrating it to the current layout if
+/// it was stored with an earlier one. States stored with a later layout,
+/// e.g., by a newer version, are refused
+pub fn decode_versioned<T: VersionedState>(mut value: Value) -> Result<T, StateStoreError> {
+    let found = match value.get(SCHEMA_VERSION_FIELD) {
+        None => LEGACY_SCHEMA_VERSION,
+        Some(version) => version
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .ok_or_else(|| {
+                StateStoreError::Decode(format!("Invalid layout version {:?}", version))
+            })?,
+    };
+    if !(LEGACY_SCHEMA_VERSION..=T::SCHEMA_VERSION).contains(&found) {
+        return Err(StateStoreError::UnsupportedVersion {
+            found,
+            supported: T::SCHEMA_VERSION,
+        });
+    }
+    for version in found..T::SCHEMA_VERSION {
+        T::migrate(version, &mut value)?;
+    }
+    match value.as_mapping_mut() {
+        Some(fields) => {
+            fields.insert(
+                Value::from(SCHEMA_VERSION_FIELD),
+                Value::Number(T::SCHEMA_VERSION.into()),
+            );
+        }
+        None => return Err(StateStoreError::Decode("Not a map of fields".to_string())),
+    }
+    serde_yaml::from_value(value).map_err(|e| StateStoreError::Decode(e.to_string()))
+}
+
+/// A key-value store for (serializable) states
+pub trait StateStore {
+    /// Returns the state stored under `key`, or `None` if nothing was stored yet
+    fn load<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, StateStoreError>;
+
+    /// Stores `state` under `key`, replacing any state stored there before
+    fn store<T: Serialize>(&self, key: &str, state: &T) -> Result<(), StateStoreError>;
+
+    /// Returns the state stored under `key` migrated to the current layout,
+    /// or `None` if nothing was stored yet
+    fn load_versioned<T: VersionedState>(&self, key: &str) -> Result<Option<T>, StateStoreError> {
+        match self.load::<Value>(key)? {
+            Some(value) => decode_versioned(value).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Returns the state stored under `key` in `store`, decoded with `decode`,
+/// or `None` if nothing was stored yet. A state that cannot be read or
+/// decoded is an error rather than `None`, so that it is not overwritten by
+/// a fresh state
+pub fn restore_state<S: DeserializeOwned, T>(
+    store: &impl StateStore,
+    key: &str,
+    decode: impl FnOnce(S) -> Result<T, StateStoreError>,
+) -> Result<Option<T>, RestoreError> {
+    let to_restore_err = |source| RestoreError {
+        key: key.to_string(),
+        source,
+    };
+    match store.load::<S>(key).map_err(to_restore_err)? {
+        Some(stored) => decode(stored).map(Some).map_err(to_restore_err),
+        None => Ok(None),
+    }
+}
+
+/// The default [StateStore], keeping each state in a YAML file (using confy)
+/// whose path is the key
+#[derive(Default, Debug, Clone)]
+pub struct FileStore;
+
+impl StateStore for FileStore {
+    fn load<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, StateStoreError> {
+        if !Path::new(key).exists() {
+            return Ok(None);
+        }
+        let content =
+            fs::read_to_string(key).map_err(|e| StateStoreError::Access(e.to_string()))?;
+        serde_yaml::from_str(&content)
+            .map(Some)
+            .map_err(|e| StateStoreError::Decode(e.to_string()))
+    }
+
+    fn store<T: Serialize>(&self, key: &str, state: &T) -> Result<(), StateStoreError> {
+        confy::store_path(key, state).map_err(|e| StateStoreError::Access(e.to_string()))
+    }
+}
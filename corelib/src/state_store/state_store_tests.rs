@@ -0,0 +1,221 @@
+#[cfg(test)]
+mod state_store_tests {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+
+    #[cfg(feature = "gov")]
+    use std::ops::DerefMut;
+
+    #[cfg(feature = "gov")]
+    use crate::client_api::actions::{ActionMetadata, ActionMsg, RenameGroupAction};
+    #[cfg(feature = "gov")]
+    use crate::client_api::client_struct::ClientDataProvider;
+    #[cfg(feature = "gov")]
+    use crate::client_api::client_struct_impl::ClientData;
+    #[cfg(feature = "gov")]
+    use crate::client_api::create_group_msg;
+    use crate::servers_api::ds_structs::DeliveryServiceState;
+    use crate::state_store::{
+        decode_versioned, restore_state, StateStore, StateStoreError, VersionedState,
+    };
+    use crate::test_helpers::*;
+
+    /// A [StateStore] keeping JSON-encoded states in memory
+    #[derive(Default)]
+    struct MemoryStore {
+        entries: Mutex<HashMap<String, String>>,
+    }
+
+    impl StateStore for MemoryStore {
+        fn load<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, StateStoreError> {
+            match self.entries.lock().unwrap().get(key) {
+                Some(encoded) => serde_json::from_str(encoded)
+                    .map(Some)
+                    .map_err(|e| StateStoreError::Decode(e.to_string())),
+                None => Ok(None),
+            }
+        }
+
+        fn store<T: Serialize>(&self, key: &str, state: &T) -> Result<(), StateStoreError> {
+            let encoded =
+                serde_json::to_string(state).map_err(|e| StateStoreError::Access(e.to_string()))?;
+            self.entries
+                .lock()
+                .unwrap()
+                .insert(key.to_string(), encoded);
+            Ok(())
+        }
+    }
+
+    #[test]
+    /// A delivery service state should come back from the store as it was stored
+    fn test_ds_state_round_trip() {
+        let store = MemoryStore::default();
+        let mut alice = TestClientBundle::new("alice");
+        let state = DeliveryServiceState::new();
+        assert!(state.add_key_packages(alice.new_kps(3), 20));
+        state.indvl_groups.insert(alice.name(), vec![comm_grp()]);
+
+        assert!(store
+            .load::<DeliveryServiceState>("ds_state")
+            .unwrap()
+            .is_none());
+        store.store("ds_state", &state).unwrap();
+        let loaded: DeliveryServiceState = store.load("ds_state").unwrap().unwrap();
+
+        assert_eq!(loaded.count_key_packages(&alice.name()), 3);
+        assert_eq!(
+            loaded.user_key_packages.get(&alice.name()).unwrap().clone(),
+            state.user_key_packages.get(&alice.name()).unwrap().clone()
+        );
+        assert_eq!(
+            loaded.indvl_groups.get(&alice.name()).unwrap().clone(),
+            vec![comm_grp()]
+        );
+        assert!(loaded.message_id_to_message.is_empty());
+    }
+
+    /// Turns encoded client data back into layout 1, without a version and
+    /// with a single role and at most one pending action per user
+    #[cfg(feature = "gov")]
+    fn to_layout_1(value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::Object(fields) => {
+                fields.remove("schema_version");
+                if let Some(serde_json::Value::Object(communities)) =
+                    fields.get_mut("community_group_pending_actions")
+                {
+                    for groups in communities.values_mut() {
+                        for pending_actions in groups.as_object_mut().unwrap().values_mut() {
+                            *pending_actions = pending_actions[0].clone();
+                        }
+                    }
+                }
+                if let Some(serde_json::Value::Object(user_to_roles)) =
+                    fields.remove("user_to_roles")
+                {
+                    let user_to_role = user_to_roles
+                        .into_iter()
+                        .map(|(user, roles)| (user, roles[0].clone()))
+                        .collect();
+                    fields.insert(
+                        "user_to_role".to_string(),
+                        serde_json::Value::Object(user_to_role),
+                    );
+                }
+                fields.values_mut().for_each(to_layout_1);
+            }
+            serde_json::Value::Array(values) => values.iter_mut().for_each(to_layout_1),
+            _ => {}
+        }
+    }
+
+    #[cfg(feature = "gov")]
+    #[test]
+    /// Client data stored with layout 1 should be migrated to the current
+    /// layout rather than discarded
+    fn test_client_data_migrated_from_v1() {
+        let mut alice = TestClientBundle::new("alice");
+        create_group_msg(
+            &alice.name(),
+            &comm_grp(),
+            &mut alice.backend,
+            alice.configs.deref_mut(),
+        )
+        .unwrap();
+        let role = alice.configs.member_role(&comm_grp(), &alice.name());
+        assert!(role.is_some());
+        let rename = ActionMsg::RenameGroup(RenameGroupAction {
+            new_name: "renamed".to_string(),
+            lamport_counter: 1,
+            metadata: ActionMetadata::new(
+                alice.name(),
+                "renamed".to_string(),
+                comm_grp(),
+                alice.configs.now(),
+            ),
+        });
+        alice
+            .configs
+            .store_pending_action(&comm_grp(), rename.clone());
+
+        let mut v1_value: serde_json::Value =
+            serde_json::from_str(&alice.configs.to_string()).unwrap();
+        to_layout_1(&mut v1_value);
+        let v1_blob = v1_value.to_string();
+        assert!(v1_blob.contains("\"user_to_role\""));
+        assert!(!v1_blob.contains("schema_version"));
+
+        let loaded = ClientData::from_versioned_str(&v1_blob).unwrap();
+        assert_eq!(loaded.schema_version, ClientData::SCHEMA_VERSION);
+        assert_eq!(loaded.user_name, alice.name());
+        assert_eq!(loaded.member_role(&comm_grp(), &alice.name()), role);
+        let mut loaded = loaded;
+        assert_eq!(loaded.pop_pending_actions(&comm_grp()), vec![rename]);
+
+        // A group without a pending action
+        let mut bob = TestClientBundle::new("bob");
+        create_group_msg(
+            &bob.name(),
+            &comm_grp(),
+            &mut bob.backend,
+            bob.configs.deref_mut(),
+        )
+        .unwrap();
+        let mut v1_value: serde_json::Value =
+            serde_json::from_str(&bob.configs.to_string()).unwrap();
+        to_layout_1(&mut v1_value);
+        let mut loaded = ClientData::from_versioned_str(&v1_value.to_string()).unwrap();
+        assert!(loaded.pop_pending_actions(&comm_grp()).is_empty());
+    }
+
+    #[cfg(feature = "gov")]
+    #[test]
+    /// States stored with a later layout than known should be refused
+    /// rather than read as some other layout
+    fn test_later_layout_refused() {
+        let alice = TestClientBundle::new("alice");
+        let mut value: serde_json::Value =
+            serde_json::from_str(&alice.configs.to_string()).unwrap();
+        value["schema_version"] = serde_json::Value::from(ClientData::SCHEMA_VERSION + 1);
+        assert!(matches!(
+            ClientData::from_versioned_str(&value.to_string()),
+            Err(StateStoreError::UnsupportedVersion { found, supported })
+                if found == ClientData::SCHEMA_VERSION + 1 && supported == ClientData::SCHEMA_VERSION
+        ));
+
+        let store = MemoryStore::default();
+        let mut ds_value = serde_json::to_value(DeliveryServiceState::new()).unwrap();
+        ds_value["schema_version"] =
+            serde_json::Value::from(DeliveryServiceState::SCHEMA_VERSION + 1);
+        store.store("ds_state", &ds_value).unwrap();
+        assert!(matches!(
+            store.load_versioned::<DeliveryServiceState>("ds_state"),
+            Err(StateStoreError::UnsupportedVersion { .. })
+        ));
+    }
+
+    #[test]
+    /// A stored state that cannot be decoded should be an error naming where
+    /// it is stored, rather than be taken for a missing one
+    fn test_undecodable_state_not_restored() {
+        let store = MemoryStore::default();
+        assert!(matches!(
+            restore_state(&store, "ds_state", decode_versioned::<DeliveryServiceState>),
+            Ok(None)
+        ));
+
+        let undecodable = serde_json::json!({
+            "schema_version": DeliveryServiceState::SCHEMA_VERSION,
+            "user_key_packages": "none",
+        });
+        store.store("ds_state", &undecodable).unwrap();
+        let err = restore_state(&store, "ds_state", decode_versioned::<DeliveryServiceState>)
+            .unwrap_err();
+        assert_eq!(err.key, "ds_state");
+        assert!(matches!(err.source, StateStoreError::Decode(_)));
+    }
+}
@@ -1,24 +1,32 @@
 use core::fmt::Debug;
 use core::marker::Sized;
 use core::option::Option::None;
+#[cfg(feature = "gov")]
+use std::ops::DerefMut;
 use std::sync::Arc;
-use std::time::SystemTime;
 
+use futures::executor::block_on;
 use openmls::credentials::{Credential, CredentialBundle};
 use openmls::key_packages::KeyPackage;
-use versions::Versioning;
 
+#[cfg(feature = "gov")]
+use crate::client_api;
 use crate::client_api::actions::ActionMsg;
 use crate::client_api::client_crypto_impl::CryptoBackend;
 use crate::client_api::client_struct::{ClientDataProvider, ClientParsedMsg};
 use crate::client_api::client_struct_impl::ClientData;
-use crate::client_api::parse_incoming_onwire_msgs;
+use crate::client_api::{
+    endorse_device, parse_incoming_onwire_msgs, register_msg_as_with_endorsement, register_msg_ds,
+    token_request_msg, OnWireTransport,
+};
+use crate::clock::SharedClock;
 use crate::messages::{
-    OnWireMessage, OnWireMessageWithMetaData, UnorderedMsgContent, UnorderedPrivateMessage,
+    DeviceEndorsement, OnWireMessage, OnWireMessageWithMetaData, UnorderedMsgContent,
+    UnorderedPrivateMessage,
 };
 use crate::policyengine::ClientRef;
-use crate::servers_api::as_struct::SharedAuthServiceState;
-use crate::servers_api::ds_structs::SharedDeliverServiceState;
+use crate::servers_api::as_struct::{AuthServiceState, SharedAuthServiceState};
+use crate::servers_api::ds_structs::{DeliveryServiceState, SharedDeliverServiceState};
 use crate::{generate_verification_key, servers_api, CommGroupId};
 
 #[derive(Debug)]
@@ -52,6 +60,16 @@ impl TestClientBundle {
         }
     }
 
+    /// Sets the clock the client reads timestamps from
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.configs
+            .as_any_mut()
+            .downcast_mut::<ClientData>()
+            .unwrap()
+            .clock = clock;
+        self
+    }
+
     pub fn name(&self) -> String {
         self.name.clone()
     }
@@ -126,6 +144,75 @@ impl TestClientBundle {
         .await;
     }
 
+    /// Registers this client with both the AS and the DS
+    pub async fn register_assert_ok(
+        &mut self,
+        as_state: &Arc<SharedAuthServiceState>,
+        ds_state: &Arc<SharedDeliverServiceState>,
+    ) {
+        self.register_endorsed_assert_ok(None, as_state, ds_state)
+            .await;
+    }
+
+    /// Registers this client as another device of the user of `endorser`,
+    /// which registered already
+    pub async fn register_device_assert_ok(
+        &mut self,
+        endorser: &TestClientBundle,
+        as_state: &Arc<SharedAuthServiceState>,
+        ds_state: &Arc<SharedDeliverServiceState>,
+    ) {
+        let endorsement = self.endorsement_by(endorser);
+        self.register_endorsed_assert_ok(Some(endorsement), as_state, ds_state)
+            .await;
+    }
+
+    /// The endorsement of this client by `endorser`
+    pub fn endorsement_by(&self, endorser: &TestClientBundle) -> DeviceEndorsement {
+        endorse_device(
+            self.credential(),
+            &self.configs.get_keypair().public_key(),
+            endorser.configs.as_ref(),
+        )
+    }
+
+    async fn register_endorsed_assert_ok(
+        &mut self,
+        endorsement: Option<DeviceEndorsement>,
+        as_state: &Arc<SharedAuthServiceState>,
+        ds_state: &Arc<SharedDeliverServiceState>,
+    ) {
+        let as_msgs = register_msg_as_with_endorsement(
+            self.credential().to_owned(),
+            self.configs.get_keypair().public_key(),
+            String::new(),
+            endorsement,
+        );
+        for as_msg in as_msgs {
+            let msgs = servers_api::handle_onwire_msg_as_local(as_msg, as_state).await;
+            assert_all_feedback_ok(&self.parse_msgs(&msgs));
+        }
+        if ds_state.as_public_key.is_some() {
+            // Required by the DS even to register
+            let msgs = servers_api::handle_onwire_msg_as_local(
+                token_request_msg(&*self.configs),
+                as_state,
+            )
+            .await;
+            assert_all_feedback_ok(&self.parse_msgs(&msgs));
+        }
+        let kps = self.new_kps(5);
+        for ds_msg in register_msg_ds(kps) {
+            let msgs = servers_api::handle_onwire_msg_w_token_ds_local(
+                ds_msg,
+                self.configs.get_ds_token(),
+                ds_state,
+            )
+            .await;
+            assert_all_feedback_ok(&self.parse_msgs(&msgs));
+        }
+    }
+
     pub async fn sync_as_credentials_responses(
         &mut self,
         as_state: &Arc<SharedAuthServiceState>,
@@ -144,10 +231,156 @@ impl TestClientBundle {
     }
 }
 
+/// New AS and DS states with a client for each of `names` registered on both
+pub async fn register_clients_assert_ok<const N: usize>(
+    names: [&str; N],
+) -> (
+    Arc<SharedAuthServiceState>,
+    Arc<SharedDeliverServiceState>,
+    [TestClientBundle; N],
+) {
+    let as_state = Arc::new(AuthServiceState::new());
+    let ds_state = Arc::new(DeliveryServiceState::new());
+    let mut clients = names.map(TestClientBundle::new);
+    for client in clients.iter_mut() {
+        client.register_assert_ok(&as_state, &ds_state).await;
+    }
+    (as_state, ds_state, clients)
+}
+
+/// New AS and DS states with the registered clients of `admin_name` and
+/// `member_names` in the test group, see [setup_group_assert_ok]
+#[cfg(feature = "gov")]
+pub async fn setup_registered_group_assert_ok(
+    admin_name: &str,
+    member_names: &[&str],
+) -> (
+    Arc<SharedAuthServiceState>,
+    Arc<SharedDeliverServiceState>,
+    TestClientBundle,
+    Vec<TestClientBundle>,
+) {
+    setup_registered_group_with_assert_ok(
+        AuthServiceState::new(),
+        DeliveryServiceState::new(),
+        TestClientBundle::new(admin_name),
+        member_names
+            .iter()
+            .map(|name| TestClientBundle::new(name))
+            .collect(),
+    )
+    .await
+}
+
+/// Registers `admin` and `members` with `as_state` and `ds_state`, then
+/// sets up the test group of them with [setup_group_assert_ok]
+#[cfg(feature = "gov")]
+pub async fn setup_registered_group_with_assert_ok(
+    as_state: AuthServiceState,
+    ds_state: DeliveryServiceState,
+    mut admin: TestClientBundle,
+    mut members: Vec<TestClientBundle>,
+) -> (
+    Arc<SharedAuthServiceState>,
+    Arc<SharedDeliverServiceState>,
+    TestClientBundle,
+    Vec<TestClientBundle>,
+) {
+    let as_state = Arc::new(as_state);
+    let ds_state = Arc::new(ds_state);
+    admin.register_assert_ok(&as_state, &ds_state).await;
+    for member in members.iter_mut() {
+        member.register_assert_ok(&as_state, &ds_state).await;
+    }
+    setup_group_assert_ok(&mut admin, &mut members, &as_state, &ds_state).await;
+    (as_state, ds_state, admin, members)
+}
+
+/// Lets the registered `admin` create the test group and add all of the
+/// registered `members`, who then accept the invite
+#[cfg(feature = "gov")]
+pub async fn setup_group_assert_ok(
+    admin: &mut TestClientBundle,
+    members: &mut [TestClientBundle],
+    as_state: &Arc<SharedAuthServiceState>,
+    ds_state: &Arc<SharedDeliverServiceState>,
+) {
+    admin.sync_as_assert_ok(as_state).await;
+    for member in members.iter_mut() {
+        member.sync_as_assert_ok(as_state).await;
+    }
+
+    let _ = client_api::create_group_msg(
+        &admin.name(),
+        &comm_grp(),
+        &mut admin.backend,
+        admin.configs.deref_mut(),
+    );
+    let member_kps = members
+        .iter_mut()
+        .map(|member| member.new_key_package())
+        .collect();
+    let member_names: Vec<String> = members.iter().map(|member| member.name()).collect();
+    let (invite_msgs, _) = client_api::pre_add_invite_msg(
+        &admin.name(),
+        &comm_grp(),
+        &mut admin.backend,
+        admin.configs.deref_mut(),
+        member_kps,
+    );
+    admin.send_all_assert_ok(invite_msgs, ds_state).await;
+    let (add_msgs, _) = client_api::add_msg(
+        &comm_grp(),
+        &member_names,
+        admin.configs.deref_mut(),
+        &mut admin.backend,
+    );
+    admin.send_all_assert_ok(add_msgs, ds_state).await;
+    let state_msgs = client_api::send_group_state_update(
+        &admin.name(),
+        &comm_grp(),
+        &mut admin.backend,
+        admin.configs.deref_mut(),
+    );
+    admin.send_all_assert_ok(state_msgs, ds_state).await;
+
+    for member in members.iter_mut() {
+        member.sync_ds_assert_ok(ds_state).await;
+        let accept_msgs =
+            client_api::accept_msg(&comm_grp(), &mut member.backend, &mut member.configs);
+        member.send_all_assert_ok(accept_msgs, ds_state).await;
+    }
+
+    admin.sync_ds_assert_ok(ds_state).await;
+    for member in members.iter_mut() {
+        member.sync_ds_assert_ok(ds_state).await;
+    }
+}
+
+/// Hands requests straight to the local state of a DS
+pub struct LocalDsTransport<'a>(pub &'a Arc<SharedDeliverServiceState>);
+
+impl OnWireTransport for LocalDsTransport<'_> {
+    fn request(&mut self, onwire_msg: OnWireMessage) -> Vec<OnWireMessage> {
+        block_on(servers_api::handle_onwire_msg_ds_local(onwire_msg, self.0))
+    }
+}
+
+/// Hands requests straight to the local state of an AS
+pub struct LocalAsTransport<'a>(pub &'a Arc<SharedAuthServiceState>);
+
+impl OnWireTransport for LocalAsTransport<'_> {
+    fn request(&mut self, onwire_msg: OnWireMessage) -> Vec<OnWireMessage> {
+        block_on(servers_api::handle_onwire_msg_as_local(onwire_msg, self.0))
+    }
+}
+
 pub fn sync_msg(user_name: String, key_packages: Vec<KeyPackage>) -> Vec<OnWireMessage> {
     OnWireMessage::UserSync {
         user_name,
         new_key_packages: key_packages,
+        known_epochs: vec![],
+        acked_until: None,
     }
     .to_vec()
 }
@@ -162,6 +395,7 @@ pub fn concat_string_in_decrypted_msgs(local_msgs: Vec<ClientParsedMsg>) -> Stri
     for local_msg in local_msgs {
         match local_msg {
             ClientParsedMsg::NewInvite { .. } => {}
+            ClientParsedMsg::ReinviteRequest { .. } => {}
             ClientParsedMsg::NewMsg {
                 private_msg,
                 comm_grp: _,
@@ -187,7 +421,11 @@ pub fn concat_string_in_decrypted_msgs(local_msgs: Vec<ClientParsedMsg>) -> Stri
             } => result_str.push_str(&private_msg),
             ClientParsedMsg::ASFeedback { .. } => {}
             ClientParsedMsg::DSFeedback { .. } => {}
-            ClientParsedMsg::Invalid { description, .. } => result_str.push_str(&*description),
+            ClientParsedMsg::Invalid { .. }
+            | ClientParsedMsg::EpochMismatch { .. }
+            | ClientParsedMsg::DecryptionFailed { .. } => {
+                result_str.push_str(&local_msg.error_description().unwrap_or_default())
+            }
         }
     }
 
@@ -199,6 +437,7 @@ pub fn assert_all_feedback_ok(local_msgs: &Vec<ClientParsedMsg>) {
         for local_msg in local_msgs {
             match local_msg {
                 ClientParsedMsg::NewInvite { .. } => {}
+                ClientParsedMsg::ReinviteRequest { .. } => {}
                 ClientParsedMsg::NewMsg { .. } => {}
                 ClientParsedMsg::NewOrdMsg { .. } => {}
                 ClientParsedMsg::ASFeedback {
@@ -216,7 +455,9 @@ pub fn assert_all_feedback_ok(local_msgs: &Vec<ClientParsedMsg>) {
                         .unwrap_or_else(|| "Invalid request".to_string());
                     assert!(request_valid, "{expl}")
                 }
-                ClientParsedMsg::Invalid { .. } => {}
+                ClientParsedMsg::Invalid { .. }
+                | ClientParsedMsg::EpochMismatch { .. }
+                | ClientParsedMsg::DecryptionFailed { .. } => {}
             }
         }
     }
@@ -269,13 +510,9 @@ pub fn flatten<T>(vec_of_vec: Vec<Vec<T>>) -> Vec<T> {
 
 /// Computes the bandwidth for sending the specified message
 pub fn onwire_msg_bandwidth(msg: &OnWireMessage) -> usize {
-    let app_msg_w_meta = OnWireMessageWithMetaData {
-        onwire_msg: msg.to_owned(),
-        sender_timestamp: SystemTime::now(),
-        version: Versioning::new("0.3.0").unwrap().to_string(),
-    };
-    let encoded = serde_json::to_vec(&app_msg_w_meta).expect("Cannot encode app msg");
-    encoded.len()
+    OnWireMessageWithMetaData::new(msg.to_owned(), false)
+        .encode()
+        .len()
 }
 
 pub fn onwire_msgs_bandwidth(msgs: &Vec<OnWireMessage>) -> usize {
@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use clap::Parser;
 
 #[derive(Parser, Debug, Default)]
@@ -14,6 +16,10 @@ pub(crate) struct CliDS {
     #[clap(short, long)]
     /// Skip storing persistent states entirely
     pub non_persistent: bool,
+
+    /// Path to the config
+    #[clap(long, default_value = "./DeliveryServiceConfig.yaml")]
+    pub config: PathBuf,
 }
 
 impl CliDS {}
@@ -1,3 +1,8 @@
+use std::net::Ipv4Addr;
+use std::path::Path;
+
+use corelib::{check_writable_path, decode_public_key, ConfigError};
+
 use serde_derive::{Deserialize, Serialize};
 
 /// This struct contains information pertaining to the delivery
@@ -7,4 +12,99 @@ pub(crate) struct DeliveryServiceConfig {
     pub data_path: String,
     pub ip_address: String,
     pub port: u16,
+    /// Invites and messages stored for longer than this are removed.
+    /// Never removed if not set
+    #[serde(default)]
+    pub gc_max_age_secs: Option<u64>,
+    /// How often stale invites and messages are looked for
+    #[serde(default = "default_gc_interval_secs")]
+    pub gc_interval_secs: u64,
+    /// The most recipients a single send may list
+    #[serde(default = "default_max_recipients_per_send")]
+    pub max_recipients_per_send: usize,
+    /// How long the welcome of an invite is delivered again at every sync of
+    /// the invitee, until they accept it. Never delivered again if 0
+    #[serde(default = "default_welcome_retention_secs")]
+    pub welcome_retention_secs: u64,
+    /// Where every delivery and retrieval is logged, to recover the queued
+    /// messages after a crash. Not logged if not set
+    #[serde(default)]
+    pub wal_path: Option<String>,
+    /// How often the whole state is stored and the WAL emptied, besides
+    /// when a connection closes. Only on connection close if 0
+    #[serde(default = "default_checkpoint_interval_secs")]
+    pub checkpoint_interval_secs: u64,
+    /// The credential operators present to query the commands a user
+    /// issued. Nobody may query them if not set
+    #[serde(default)]
+    pub admin_credential: Option<String>,
+    /// The base64-encoded public key the AS logs at startup. If set, requests
+    /// naming a user must come with a token the AS issued to them
+    #[serde(default)]
+    pub as_public_key: Option<String>,
+}
+
+fn default_gc_interval_secs() -> u64 {
+    3600
+}
+
+fn default_checkpoint_interval_secs() -> u64 {
+    300
+}
+
+fn default_max_recipients_per_send() -> usize {
+    1000
+}
+
+fn default_welcome_retention_secs() -> u64 {
+    7 * 24 * 3600
+}
+
+impl DeliveryServiceConfig {
+    /// Loads the config at `path`, with a relative data path taken relative
+    /// to the directory of the config
+    pub(crate) fn load(path: &Path) -> Result<Self, confy::ConfyError> {
+        let mut config: Self = confy::load_path(path)?;
+        if let Some(config_dir) = path.parent() {
+            if !config.data_path.is_empty() && Path::new(&config.data_path).is_relative() {
+                config.data_path = config_dir
+                    .join(&config.data_path)
+                    .to_string_lossy()
+                    .to_string();
+            }
+            if let Some(wal_path) = config.wal_path.as_mut() {
+                if Path::new(wal_path.as_str()).is_relative() {
+                    *wal_path = config_dir
+                        .join(wal_path.as_str())
+                        .to_string_lossy()
+                        .to_string();
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    /// Checks that the IP address and the AS public key parse, and that the
+    /// data and write-ahead log files can be written
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        self.ip_address
+            .parse::<Ipv4Addr>()
+            .map_err(|_| ConfigError::InvalidIpAddress {
+                field: "ip_address",
+                value: self.ip_address.clone(),
+            })?;
+        if let Some(as_public_key) = &self.as_public_key {
+            if decode_public_key(as_public_key).is_none() {
+                return Err(ConfigError::InvalidPublicKey {
+                    field: "as_public_key",
+                    value: as_public_key.clone(),
+                });
+            }
+        }
+        check_writable_path("data_path", &self.data_path)?;
+        match &self.wal_path {
+            Some(wal_path) => check_writable_path("wal_path", wal_path),
+            None => Ok(()),
+        }
+    }
 }
@@ -1,23 +1,29 @@
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::ops::Deref;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
-use axum::{extract::Extension, response::IntoResponse, routing::get, Router};
+use axum::{extract::Extension, response::IntoResponse, routing::get, Json, Router};
 use axum_macros::debug_handler;
 use clap::Parser;
 use futures::lock::Mutex;
 use futures::stream::SplitSink;
 use futures::stream::StreamExt;
 use log::*;
+use serde_derive::Serialize;
 
 use config::DeliveryServiceConfig;
-use corelib::messages::OnWireMessage;
+use corelib::messages::{AuthToken, OnWireMessage};
 use corelib::servers_api::ds_structs::{
     DeliveryServiceParam, DeliveryServiceState, SharedDeliverServiceState,
 };
-use corelib::servers_api::handle_onwire_msg_ds_local;
-use corelib::servers_api::network_helpers::{parse_wrapped_ws_msg, send_enum_app_message};
+use corelib::servers_api::handle_onwire_msg_w_token_ds_local;
+use corelib::servers_api::network_helpers::{
+    parse_wrapped_ws_msg, send_enum_app_message, SendError,
+};
+use corelib::state_store::{decode_versioned, restore_state, FileStore, StateStore};
+use corelib::TimerType::SingleUserRequestDSProcessTime;
+use corelib::{decode_public_key, SingleTimeMeasurement};
 
 use crate::cli_struct::CliDS;
 
@@ -33,31 +39,70 @@ async fn main() {
         persistent_state: !local_cli_param.non_persistent,
     };
 
-    let ds_config: DeliveryServiceConfig =
-        confy::load_path("./DeliveryServiceConfig.yaml").expect("Could not parse DS config.");
+    let ds_config = match DeliveryServiceConfig::load(&local_cli_param.config) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Could not parse DS config: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = ds_config.validate() {
+        eprintln!("Invalid DS config: {}", e);
+        std::process::exit(1);
+    }
 
-    let ip_addr: Ipv4Addr = ds_config
-        .ip_address
-        .parse()
-        .expect("Could not parse IP address.");
+    let ip_addr: Ipv4Addr = ds_config.ip_address.parse().expect("Validated IP address");
     let port: u16 = ds_config.port;
 
     let sock_addr = SocketAddr::from((IpAddr::V4(ip_addr), port));
 
-    let mut logger = env_logger::Builder::new();
-    match local_cli_param.verbose {
-        0 => logger.filter_level(LevelFilter::Info),
-        1 => logger.filter_level(LevelFilter::Debug),
-        _ => logger.filter_level(LevelFilter::Trace),
-    };
-    logger.init();
+    #[cfg(not(feature = "request-spans"))]
+    {
+        let mut logger = env_logger::Builder::new();
+        match local_cli_param.verbose {
+            0 => logger.filter_level(LevelFilter::Info),
+            1 => logger.filter_level(LevelFilter::Debug),
+            _ => logger.filter_level(LevelFilter::Trace),
+        };
+        logger.init();
+    }
+    // Also turns log records into events within the span of their request
+    #[cfg(feature = "request-spans")]
+    tracing_subscriber::fmt()
+        .with_max_level(match local_cli_param.verbose {
+            0 => tracing::Level::INFO,
+            1 => tracing::Level::DEBUG,
+            _ => tracing::Level::TRACE,
+        })
+        .init();
 
     let server_state: Arc<SharedDeliverServiceState> =
         recover_state(&local_cli_param, &ds_config).await;
 
+    if let Some(max_age_secs) = ds_config.gc_max_age_secs {
+        tokio::spawn(gc_periodically(
+            Arc::clone(&server_state),
+            Duration::from_secs(max_age_secs),
+            Duration::from_secs(ds_config.gc_interval_secs),
+        ));
+    }
+
+    if ds_param.persistent_state && ds_config.checkpoint_interval_secs > 0 {
+        tokio::spawn(checkpoint_periodically(
+            Arc::clone(&server_state),
+            ds_config.clone(),
+            Duration::from_secs(ds_config.checkpoint_interval_secs),
+        ));
+    }
+
     let app: Router = Router::new()
         .route("/", get(websocket_handler))
-        .layer(Extension((server_state, ds_config, ds_param)));
+        .route("/health", get(health_handler));
+    #[cfg(feature = "metrics")]
+    let app = app.route("/metrics", get(metrics_handler));
+    let app = app
+        .layer(Extension((server_state, ds_config, ds_param)))
+        .layer(Extension(Instant::now()));
 
     tracing::debug!("listening on {}", sock_addr);
     axum::Server::bind(&sock_addr)
@@ -78,6 +123,46 @@ async fn websocket_handler(
     ws.on_upgrade(|socket| websocket(socket, state, config, param))
 }
 
+/// Reported by `GET /health`
+#[derive(Serialize, Debug)]
+struct HealthReport {
+    uptime_secs: u64,
+    n_users: usize,
+    n_groups: usize,
+}
+
+/// Reports the uptime and the number of known users and groups, without a
+/// websocket upgrade
+#[debug_handler]
+async fn health_handler(
+    Extension((state, _, _)): Extension<(
+        Arc<SharedDeliverServiceState>,
+        DeliveryServiceConfig,
+        DeliveryServiceParam,
+    )>,
+    Extension(started_at): Extension<Instant>,
+) -> Json<HealthReport> {
+    Json(HealthReport {
+        uptime_secs: started_at.elapsed().as_secs(),
+        n_users: state.user_key_packages.len(),
+        n_groups: state.groups_to_ordered_messages.len(),
+    })
+}
+
+/// Reports the recorded measurements and the depth of the message queues in
+/// the Prometheus text format
+#[cfg(feature = "metrics")]
+async fn metrics_handler(
+    Extension((state, _, _)): Extension<(
+        Arc<SharedDeliverServiceState>,
+        DeliveryServiceConfig,
+        DeliveryServiceParam,
+    )>,
+) -> String {
+    corelib::metrics::record_ds_queues(&state.queue_metrics);
+    corelib::metrics::gather_text()
+}
+
 async fn websocket(
     stream: WebSocket,
     state: Arc<SharedDeliverServiceState>,
@@ -93,21 +178,76 @@ async fn websocket(
         match parse_wrapped_ws_msg(received_message).await {
             None => (),
             Some(onwire_msg_w_data) => {
-                respond_onwire_msg(onwire_msg_w_data.onwire_msg, &mut shared_sender, &state).await;
+                if let Err(e) = respond_onwire_msg(
+                    onwire_msg_w_data.onwire_msg,
+                    onwire_msg_w_data.token,
+                    onwire_msg_w_data.compress,
+                    &mut shared_sender,
+                    &state,
+                )
+                .await
+                {
+                    // Dropping the connection rather than letting a client
+                    // that does not read hold this loop indefinitely
+                    warn!("Dropping the connection: {}", e);
+                    break;
+                }
             }
         }
     }
     if param.persistent_state {
         // Save all data of the delivery_service
-        store_state(Arc::clone(&state).deref().clone(), &config).await;
-        let _ = state; // Prolong lifetime of state
+        store_state(state, &config).await;
+    }
+}
+
+/// Removes the invites and messages older than `max_age` every `period`
+async fn gc_periodically(
+    state: Arc<SharedDeliverServiceState>,
+    max_age: Duration,
+    period: Duration,
+) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        let (n_invites, n_msgs) = state.gc(max_age);
+        if n_invites > 0 || n_msgs > 0 {
+            info!(
+                "Removed {} stale invites and {} stale messages",
+                n_invites, n_msgs
+            );
+        }
+    }
+}
+
+/// Stores the whole state every `period`, so that the WAL does not grow
+/// while connections stay open
+async fn checkpoint_periodically(
+    state: Arc<SharedDeliverServiceState>,
+    config: DeliveryServiceConfig,
+    period: Duration,
+) {
+    let mut interval = tokio::time::interval(period);
+    // The first tick completes immediately
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        store_state(Arc::clone(&state), &config).await;
     }
 }
 
-async fn store_state(state: DeliveryServiceState, config: &DeliveryServiceConfig) {
-    match confy::store_path(&config.data_path, state) {
-        Ok(_) => {
-            info!("Server state save successfully\n");
+async fn store_state(state: Arc<SharedDeliverServiceState>, config: &DeliveryServiceConfig) {
+    let data_path = config.data_path.clone();
+    // Writing and syncing the file blocks, so it is kept off the async
+    // workers. The WAL then only keeps the operations logged since
+    let stored = tokio::task::spawn_blocking(move || {
+        state.checkpoint(|snapshot| FileStore.store(&data_path, snapshot))
+    })
+    .await;
+    match stored {
+        Ok(Ok(_)) => info!("Server state save successfully\n"),
+        Ok(Err(e)) => {
+            error!("in Server state saving: {:?}", e);
         }
         Err(e) => {
             error!("in Server state saving: {:?}", e);
@@ -119,37 +259,184 @@ async fn recover_state(
     local_cli_param: &CliDS,
     config: &DeliveryServiceConfig,
 ) -> Arc<SharedDeliverServiceState> {
-    Arc::new(match &local_cli_param.fresh_start {
-        false => match confy::load_path(&config.data_path) {
-            Ok(state) => {
+    let mut state = match &local_cli_param.fresh_start {
+        false => match restore_state(&FileStore, &config.data_path, decode_versioned) {
+            Ok(Some(state)) => {
                 debug!("Restore states successfully");
                 state
             }
-            Err(_) => {
-                warn!("Starting fresh (no local record found or was incompatible)");
+            Ok(None) => {
+                warn!("Starting fresh (no local record found)");
                 DeliveryServiceState::new()
             }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
         },
 
         true => {
             println!("Starting fresh as requested");
             // Remove cached result
-            store_state(DeliveryServiceState::new(), config).await;
+            store_state(Arc::new(DeliveryServiceState::new()), config).await;
+            if let Some(wal_path) = &config.wal_path {
+                let _ = std::fs::remove_file(wal_path);
+            }
             // Return a fresh result
             DeliveryServiceState::new()
         }
-    })
+    };
+    state.recount_queue_metrics();
+    state.max_recipients_per_send = Some(config.max_recipients_per_send);
+    state.admin_credential = config.admin_credential.clone();
+    state.as_public_key = config.as_public_key.as_deref().and_then(decode_public_key);
+    state.welcome_retention = match config.welcome_retention_secs {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    if let Some(wal_path) = &config.wal_path {
+        match state.open_wal(wal_path) {
+            Ok(n_ops) => debug!("Replayed {} operations from the WAL", n_ops),
+            Err(e) => error!("in opening the WAL at {}: {:?}", wal_path, e),
+        }
+    }
+    Arc::new(state)
 }
 
+/// Replies are compressed if `compress` is set, i.e., if the request was
+/// compressed itself
 async fn respond_onwire_msg(
     onwire_msg: OnWireMessage,
+    token: Option<AuthToken>,
+    compress: bool,
     sender: &mut Mutex<SplitSink<WebSocket, Message>>,
     shared_state: &Arc<SharedDeliverServiceState>,
-) {
+) -> Result<(), SendError> {
     trace!("Decoded: {:?}", onwire_msg);
 
-    let reply_msg_queue = handle_onwire_msg_ds_local(onwire_msg, shared_state).await;
+    let begin_timestamp = Instant::now();
+    let reply_msg_queue = handle_onwire_msg_w_token_ds_local(onwire_msg, token, shared_state).await;
+    SingleTimeMeasurement::new(SingleUserRequestDSProcessTime, begin_timestamp.elapsed());
     for reply_msg in reply_msg_queue {
-        send_enum_app_message(&reply_msg, sender).await;
+        send_enum_app_message(&reply_msg, compress, sender).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use corelib::servers_api::as_struct::AuthServiceState;
+    use corelib::servers_api::network_helpers::send_ws_message;
+    use corelib::test_helpers::{setup_group_assert_ok, TestClientBundle};
+    use futures::Sink;
+
+    use super::*;
+
+    /// A sink whose buffer is full for its first `n_full_polls` polls, or
+    /// for good if `None`, like the sink of a client that reads slowly
+    struct BackpressuredSink {
+        n_full_polls: Option<usize>,
+        sent: Vec<Message>,
+        flushed: bool,
+    }
+
+    impl BackpressuredSink {
+        fn new(n_full_polls: Option<usize>) -> Self {
+            BackpressuredSink {
+                n_full_polls,
+                sent: vec![],
+                flushed: false,
+            }
+        }
+    }
+
+    impl Sink<Message> for BackpressuredSink {
+        type Error = ();
+
+        fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            match self.n_full_polls {
+                Some(0) => Poll::Ready(Ok(())),
+                Some(n) => {
+                    self.n_full_polls = Some(n - 1);
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }
+                None => Poll::Pending,
+            }
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), ()> {
+            self.sent.push(item);
+            self.flushed = false;
+            Ok(())
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            self.flushed = true;
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_send_waits_out_backpressure() {
+        let reply = OnWireMessage::DSResult {
+            request_valid: true,
+            explanation: None,
+            identifier: None,
+            preceding_and_sent_ordered_msgs: vec![],
+            remaining_key_packages: None,
+            process_time_used: Duration::ZERO,
+        };
+        let mut sender = Mutex::new(BackpressuredSink::new(Some(3)));
+        assert_eq!(
+            send_enum_app_message(&reply, false, &mut sender).await,
+            Ok(())
+        );
+        let sink = sender.into_inner();
+        assert_eq!(sink.n_full_polls, Some(0));
+        assert_eq!(sink.sent.len(), 1);
+        assert!(sink.flushed);
+    }
+
+    #[actix_rt::test]
+    async fn test_send_times_out_on_full_buffer() {
+        let send_timeout = Duration::from_millis(50);
+        let mut sink = BackpressuredSink::new(None);
+        assert_eq!(
+            send_ws_message(Message::Binary(vec![1]), &mut sink, send_timeout).await,
+            Err(SendError::Timeout(send_timeout))
+        );
+        assert!(sink.sent.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_health_reports_seeded_state() {
+        let as_state = Arc::new(AuthServiceState::new());
+        let ds_state = Arc::new(DeliveryServiceState::new());
+
+        let mut alice = TestClientBundle::new("alice");
+        let mut bob = TestClientBundle::new("bob");
+        alice.register_assert_ok(&as_state, &ds_state).await;
+        bob.register_assert_ok(&as_state, &ds_state).await;
+        setup_group_assert_ok(&mut alice, &mut [bob], &as_state, &ds_state).await;
+
+        let Json(report) = health_handler(
+            Extension((
+                ds_state,
+                DeliveryServiceConfig::default(),
+                DeliveryServiceParam::default(),
+            )),
+            Extension(Instant::now()),
+        )
+        .await;
+        assert_eq!(report.n_users, 2);
+        assert_eq!(report.n_groups, 1);
     }
 }